@@ -0,0 +1,132 @@
+// A switch-style workload, where every time round the loop a br_table picks one of several
+// cases, and the executor finds the target in the branch table decoded when the function
+// was compiled. It reports how long each dispatch takes. It also compares finding a target
+// straight from the instruction bytes by collecting the whole table into a vector, as
+// get_block_table_targets does, with stepping through the targets until the chosen one.
+// The process fails if stepping through stops being the faster way.
+use std::hint::black_box;
+use std::process;
+use std::time::{Duration, Instant};
+
+use wasm::core::{
+    resolve_raw_module, EmptyResolver, Export, ExportDesc, Expr, Func, FuncType, Instance, Locals,
+    RawModule, Value, ValueType,
+};
+use wasm::parser::InstructionSource;
+
+const ITERATIONS: i32 = 200_000;
+const LOOKUPS_PER_SAMPLE: usize = 100_000;
+const TABLE_SIZE: u8 = 64;
+const SAMPLES: usize = 9;
+
+// Allow some noise before deciding that iterating has lost
+const TOLERANCE: f64 = 1.05;
+
+// Counts its parameter down to zero, choosing a case from its bottom two bits each time
+fn make_switch_module() -> RawModule {
+    let body = vec![
+        0x03, 0x40, // loop
+        0x02, 0x40, 0x02, 0x40, 0x02, 0x40, 0x02, 0x40, // four blocks
+        0x20, 0x00, 0x41, 0x03, 0x71, // local.get 0, i32.const 3, i32.and
+        0x0E, 0x03, 0x00, 0x01, 0x02, 0x03, // br_table 0 1 2 3
+        0x0B, // end of the first case's block
+        0x20, 0x01, 0x41, 0x01, 0x6A, 0x21, 0x01, 0x0C, 0x02, // acc += 1, br 2
+        0x0B, // end of the second case's block
+        0x20, 0x01, 0x41, 0x03, 0x6A, 0x21, 0x01, 0x0C, 0x01, // acc += 3, br 1
+        0x0B, // end of the third case's block
+        0x20, 0x01, 0x20, 0x00, 0x73, 0x21, 0x01, 0x0C, 0x00, // acc ^= n, br 0
+        0x0B, // the fourth case does nothing
+        0x20, 0x00, 0x41, 0x01, 0x6B, 0x22, 0x00, 0x0D, 0x00, // n -= 1, br_if 0
+        0x0B, // end of the loop
+        0x20, 0x01, 0x0B, // local.get 1, end
+    ];
+
+    RawModule::new(
+        vec![FuncType::new(vec![ValueType::I32], vec![ValueType::I32])],
+        vec![0],
+        vec![Func::new(
+            vec![Locals::new(1, ValueType::I32)],
+            Expr::new(body),
+        )],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        None,
+        vec![],
+        vec![Export::new("switch".to_string(), ExportDesc::Func(0))],
+    )
+}
+
+fn fastest_sample(mut sample: impl FnMut()) -> Duration {
+    // Take the fastest sample, as that is the one least disturbed by everything else
+    // running on the machine
+    (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            sample();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn measure_dispatch() -> Duration {
+    let instance =
+        Instance::new(resolve_raw_module(make_switch_module(), EmptyResolver::instance()).unwrap());
+    fastest_sample(|| {
+        black_box(
+            instance
+                .invoke("switch", &[Value::I32(ITERATIONS)])
+                .unwrap(),
+        );
+    })
+}
+
+fn large_branch_table() -> Vec<u8> {
+    let mut expr = vec![0x0E, TABLE_SIZE];
+    expr.extend(0..=TABLE_SIZE);
+    expr.push(0x0B);
+    expr
+}
+
+fn main() {
+    let dispatch = measure_dispatch();
+    println!(
+        "{:<16} {:>10.2?} ({:.2} ns per dispatch)",
+        "dispatch",
+        dispatch,
+        dispatch.as_nanos() as f64 / f64::from(ITERATIONS)
+    );
+
+    let expr = large_branch_table();
+    let instruction = InstructionSource::iter(&expr).next().unwrap().unwrap();
+    let selectors = (0..LOOKUPS_PER_SAMPLE).map(|i| i % (usize::from(TABLE_SIZE) + 1));
+    let collected = fastest_sample(|| {
+        for selector in selectors.clone() {
+            let targets = black_box(&instruction).get_block_table_targets();
+            black_box(targets[selector]);
+        }
+    });
+    let iterated = fastest_sample(|| {
+        for selector in selectors.clone() {
+            let mut targets = black_box(&instruction).block_table_targets();
+            black_box(targets.nth(selector).unwrap());
+        }
+    });
+
+    for (name, duration) in &[("collected", collected), ("iterated", iterated)] {
+        println!(
+            "{:<16} {:>10.2?} ({:.2} ns per lookup)",
+            name,
+            duration,
+            duration.as_nanos() as f64 / LOOKUPS_PER_SAMPLE as f64
+        );
+    }
+
+    if iterated.as_secs_f64() > collected.as_secs_f64() * TOLERANCE {
+        eprintln!("Stepping through branch table targets is no longer faster than collecting them");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,101 @@
+// Compares the bounds check strategies available for `Memory` on a kernel that does
+// nothing but small loads and stores, which is where the check dominates. The process
+// fails if the cached length strategy, which is the default, stops being the faster one.
+// The same kernel working straight on the slice of the memory is reported alongside, as
+// the cost of the accesses without going through `get_data` and `set_data`.
+use std::hint::black_box;
+use std::process;
+use std::time::{Duration, Instant};
+
+use wasm::core::{
+    memory_page::WASM_PAGE_SIZE_IN_BYTES, BoundsCheck, CachedLengthBoundsCheck, Memory,
+    PageLookupBoundsCheck,
+};
+
+const MEMORY_PAGES: usize = 16;
+const PASSES_PER_SAMPLE: usize = 4;
+const SAMPLES: usize = 9;
+
+// Allow some noise before deciding that the default strategy has lost
+const TOLERANCE: f64 = 1.05;
+
+// Adds each word of memory into the next one, the way a compiled prefix sum loop would
+fn load_store_kernel<Check: BoundsCheck>(memory: &mut Memory<Check>) {
+    let length = MEMORY_PAGES * WASM_PAGE_SIZE_IN_BYTES;
+    let mut buf = [0u8; 4];
+
+    for address in (0..length - 4).step_by(4) {
+        memory.get_data(black_box(address), &mut buf).unwrap();
+        let value = u32::from_le_bytes(buf);
+        memory.get_data(black_box(address + 4), &mut buf).unwrap();
+        let value = value.wrapping_add(u32::from_le_bytes(buf));
+        memory
+            .set_data(black_box(address + 4), &value.to_le_bytes())
+            .unwrap();
+    }
+}
+
+// The kernel as the executor could write it, checking each access itself and then copying
+fn slice_kernel<Check: BoundsCheck>(memory: &mut Memory<Check>) {
+    let bytes = memory
+        .as_mut_slice()
+        .expect("The benchmark memories aren't shared");
+    let length = bytes.len();
+
+    for address in (0..length - 4).step_by(4) {
+        let address = black_box(address);
+        assert!(address + 8 <= length);
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&bytes[address..address + 4]);
+        let value = u32::from_le_bytes(buf);
+        buf.copy_from_slice(&bytes[address + 4..address + 8]);
+        let value = value.wrapping_add(u32::from_le_bytes(buf));
+        bytes[address + 4..address + 8].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn accesses_per_sample() -> usize {
+    PASSES_PER_SAMPLE * 3 * (MEMORY_PAGES * WASM_PAGE_SIZE_IN_BYTES / 4 - 1)
+}
+
+fn measure<Check: BoundsCheck>(kernel: fn(&mut Memory<Check>)) -> Duration {
+    let mut memory = Memory::<Check>::new_from_bounds(MEMORY_PAGES, None);
+    memory.set_data(0, &[1, 0, 0, 0]).unwrap();
+
+    // Take the fastest sample, as that is the one least disturbed by everything else
+    // running on the machine
+    (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..PASSES_PER_SAMPLE {
+                kernel(&mut memory);
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, duration: Duration) {
+    println!(
+        "{:<16} {:>10.2?} ({:.2} ns per access)",
+        name,
+        duration,
+        duration.as_nanos() as f64 / accesses_per_sample() as f64
+    );
+}
+
+fn main() {
+    let cached_length = measure::<CachedLengthBoundsCheck>(load_store_kernel);
+    let page_lookup = measure::<PageLookupBoundsCheck>(load_store_kernel);
+    let slice = measure::<CachedLengthBoundsCheck>(slice_kernel);
+
+    report("cached length", cached_length);
+    report("page lookup", page_lookup);
+    report("slice", slice);
+
+    if cached_length.as_secs_f64() > page_lookup.as_secs_f64() * TOLERANCE {
+        eprintln!("The cached length bounds check is no longer the fastest strategy");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,242 @@
+//! Counts of the heap allocations made by the interpreter, so that the heap of a small
+//! device can be sized from what a real module needs rather than guessed. Install
+//! `AccountingAllocator` as the global allocator to collect them:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: AccountingAllocator<System> = AccountingAllocator::new(System);
+//! ```
+//!
+//! Each allocation is charged to the category of the work that made it, such as reading a
+//! module or growing a memory. Anything else the program allocates is charged to `Other`.
+//! Frees are charged to the category of the allocation, whichever thread makes them.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AllocCategory {
+    /// Reading, validating and compiling modules
+    Module,
+    /// The pages of linear memories
+    Memory,
+    /// The value, frame and label stacks used while executing
+    Stack,
+    Other,
+}
+
+impl AllocCategory {
+    pub const ALL: [AllocCategory; 4] = [
+        AllocCategory::Module,
+        AllocCategory::Memory,
+        AllocCategory::Stack,
+        AllocCategory::Other,
+    ];
+
+    fn from_u8(value: u8) -> Self {
+        Self::ALL
+            .get(usize::from(value))
+            .copied()
+            .unwrap_or(AllocCategory::Other)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AllocStats {
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub current_bytes: usize,
+    /// The most bytes allocated at once since the last `reset_peaks`
+    pub peak_bytes: usize,
+    /// The biggest single allocation since the last `reset_peaks`. A heap which is
+    /// fragmented can fail to find room for this even when it has enough free space.
+    pub largest_allocation: usize,
+}
+
+impl AllocStats {
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations - self.deallocations
+    }
+}
+
+struct Counters {
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    current_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    largest_allocation: AtomicUsize,
+}
+
+impl Counters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Counters = Counters {
+        allocations: AtomicU64::new(0),
+        deallocations: AtomicU64::new(0),
+        current_bytes: AtomicUsize::new(0),
+        peak_bytes: AtomicUsize::new(0),
+        largest_allocation: AtomicUsize::new(0),
+    };
+
+    fn allocated(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.largest_allocation.fetch_max(size, Ordering::Relaxed);
+        self.grown(size);
+    }
+
+    fn deallocated(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.current_bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn grown(&self, size: usize) {
+        let current = self.current_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_bytes.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> AllocStats {
+        AllocStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            current_bytes: self.current_bytes.load(Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+            largest_allocation: self.largest_allocation.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset_peaks(&self) {
+        self.peak_bytes.store(
+            self.current_bytes.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.largest_allocation.store(0, Ordering::Relaxed);
+    }
+}
+
+// One for each category, then one for the total
+static COUNTERS: [Counters; AllocCategory::ALL.len() + 1] =
+    [Counters::NEW; AllocCategory::ALL.len() + 1];
+const TOTAL: usize = AllocCategory::ALL.len();
+
+thread_local! {
+    static CURRENT_CATEGORY: Cell<AllocCategory> = const { Cell::new(AllocCategory::Other) };
+}
+
+fn current_category() -> AllocCategory {
+    // Allocations made while a thread is being torn down can't see its category
+    CURRENT_CATEGORY
+        .try_with(Cell::get)
+        .unwrap_or(AllocCategory::Other)
+}
+
+pub fn stats(category: AllocCategory) -> AllocStats {
+    COUNTERS[category as usize].stats()
+}
+
+/// The allocations of every category together. The peak is the peak of the total, which
+/// can be less than the sum of the peaks of the categories.
+pub fn total_stats() -> AllocStats {
+    COUNTERS[TOTAL].stats()
+}
+
+/// Start measuring the peaks again from the current usage, so that the peak of one phase
+/// of a program, such as running a single call, can be seen on its own
+pub fn reset_peaks() {
+    COUNTERS.iter().for_each(Counters::reset_peaks);
+}
+
+/// Charges the allocations made on this thread to a category until it is dropped
+#[must_use]
+pub struct AllocScope {
+    previous: AllocCategory,
+}
+
+impl AllocScope {
+    pub fn enter(category: AllocCategory) -> Self {
+        let previous = current_category();
+        let _ = CURRENT_CATEGORY.try_with(|current| current.set(category));
+        Self { previous }
+    }
+}
+
+impl Drop for AllocScope {
+    fn drop(&mut self) {
+        let _ = CURRENT_CATEGORY.try_with(|current| current.set(self.previous));
+    }
+}
+
+/// Wraps another allocator to count what is allocated with it
+pub struct AccountingAllocator<A> {
+    inner: A,
+}
+
+impl<A> AccountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+// Each allocation has room in front of it for the category it is charged to. Using the
+// alignment as the size of the header keeps the allocation itself aligned.
+fn with_header(layout: Layout) -> Option<(Layout, usize)> {
+    let header = layout.align();
+    let size = layout.size().checked_add(header)?;
+    Layout::from_size_align(size, layout.align())
+        .ok()
+        .map(|layout| (layout, header))
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for AccountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, header) = match with_header(layout) {
+            Some(outer) => outer,
+            None => return std::ptr::null_mut(),
+        };
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let category = current_category();
+        let ptr = base.add(header);
+        *ptr.sub(1) = category as u8;
+        COUNTERS[category as usize].allocated(layout.size());
+        COUNTERS[TOTAL].allocated(layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, header) = with_header(layout).unwrap();
+        let category = AllocCategory::from_u8(*ptr.sub(1));
+        COUNTERS[category as usize].deallocated(layout.size());
+        COUNTERS[TOTAL].deallocated(layout.size());
+        self.inner.dealloc(ptr.sub(header), outer);
+    }
+
+    // A resized allocation stays in the category it was made in
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let (outer, header) = with_header(layout).unwrap();
+        let new_outer_size = match new_size.checked_add(header) {
+            Some(size) => size,
+            None => return std::ptr::null_mut(),
+        };
+        let category = AllocCategory::from_u8(*ptr.sub(1));
+        let base = self.inner.realloc(ptr.sub(header), outer, new_outer_size);
+        if base.is_null() {
+            return base;
+        }
+
+        for counters in &[&COUNTERS[category as usize], &COUNTERS[TOTAL]] {
+            counters
+                .current_bytes
+                .fetch_sub(layout.size(), Ordering::Relaxed);
+            counters
+                .largest_allocation
+                .fetch_max(new_size, Ordering::Relaxed);
+            counters.grown(new_size);
+        }
+        base.add(header)
+    }
+}
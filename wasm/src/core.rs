@@ -1,24 +1,73 @@
+mod bounds_check;
 mod callable;
+pub mod compiled;
 mod core_types;
+mod debugger;
+mod error;
 mod executor;
 mod global;
+mod handle;
+mod instance;
+mod interrupt;
+mod lint;
 mod memory;
+mod memory_monitor;
 pub mod memory_page;
+mod memory_view;
 mod module;
+mod module_diff;
+mod observer;
+mod profiler;
+mod requirements;
 mod resolver;
 mod section;
 mod stack;
 pub mod stack_entry;
+mod store;
 mod table;
+#[cfg(test)]
+pub(crate) mod test_module;
+mod time_travel;
+mod value;
 
-pub use callable::{Callable, WasmExprCallable};
+pub use bounds_check::{BoundsCheck, CachedLengthBoundsCheck, PageLookupBoundsCheck};
+pub use callable::{
+    Callable, HostFunc, HostFuncCallable, HostFuncError, HostFuncResult, InstanceId, Provenance,
+    StubCallable, WasmExprCallable,
+};
 pub use core_types::*;
-pub use executor::{evaluate_constant_expression, execute_expression, store_access};
+pub use debugger::{CodeLocation, DebugSession, DebugStop};
+pub use error::{trap, ParseError, TrapKind, WasmError};
+pub use executor::{
+    evaluate_constant_expression, execute_compiled, execute_expression,
+    memory_access::LEByteConvert, store_access, ReadOnlyDataStore,
+};
 pub use global::Global;
-pub use memory::Memory;
-pub use module::{load_module_from_path, ExportValue, RawModule};
-pub use resolver::{EmptyResolver, Resolver};
+pub use handle::{handle, Handle, Lock, LockRef, LockRefMut, MaybeSendSync, Shared};
+pub use instance::{Execution, ExecutionConfig, Instance, Invocation, SavedExecution};
+pub use interrupt::InterruptHandle;
+pub use lint::{lint_module, lint_module_from_path, LintFinding, LintKind};
+pub use memory::{Memory, SharedMemory};
+pub use memory_monitor::{MemoryMonitor, MemorySnapshot};
+pub use memory_view::MemoryView;
+#[cfg(feature = "mmap")]
+pub use module::load_module_from_mmap;
+pub use module::{
+    load_module_from_bytes, load_module_from_path, load_module_from_reader, resolve_raw_module,
+    validate_raw_module, ExportInfo, ExportKind, ExportType, ExportValue, Exports, IndexSpace,
+    InstantiateConfig, LoadedModule, RawModule, StartFunction, ValidatedModule,
+};
+pub use module_diff::{diff_modules, diff_modules_from_paths, ChangeKind, ModuleChange};
+pub use observer::ExecutionObserver;
+pub use profiler::{FunctionProfile, Profile};
+pub use requirements::{required_resources, ImportedResource, ResourceRequirements};
+pub use resolver::{
+    EmptyResolver, GrowingResolver, HostObjectResolver, ImportNotFound, Resolver, StubResolver,
+};
 pub use section::SectionType;
-pub use stack::Stack;
+pub use stack::{Stack, StackLimits};
+pub use store::{Incompatibility, ReloadError, Store};
 pub use store_access::{ConstantDataStore, DataStore, FunctionStore};
-pub use table::Table;
+pub use table::{Table, TableElement};
+pub use time_travel::{TimeTravelConfig, TimeTravelSession};
+pub use value::Value;
@@ -0,0 +1,94 @@
+use std::fmt;
+
+use crate::core::memory_page::{split_page_from_address, WASM_PAGE_SIZE_IN_BYTES};
+use crate::core::{trap, TrapKind};
+use anyhow::Result;
+
+/// The strategy a `Memory` uses to validate every access before it touches a page.
+/// Strategies are chosen at compile time through the type of the memory, so there is
+/// no dispatch cost on the access path. Every access still goes through the check, so
+/// the choice of strategy only affects speed and never safety.
+pub trait BoundsCheck: fmt::Debug + Default {
+    /// Called whenever the number of pages in the memory changes
+    fn set_page_count(&mut self, page_count: usize);
+
+    fn check(&self, page_count: usize, offset: usize, length: usize) -> Result<()>;
+}
+
+fn overflow_error() -> anyhow::Error {
+    trap(TrapKind::MemoryOutOfBounds).context("Length overflow when accessing memory")
+}
+
+fn out_of_bounds_error() -> anyhow::Error {
+    trap(TrapKind::MemoryOutOfBounds).context("Attempting to access outside allocated memory")
+}
+
+/// Keeps the size of the memory in bytes up to date as it grows, so a check is a single
+/// addition and comparison.
+#[derive(Debug, Default)]
+pub struct CachedLengthBoundsCheck {
+    byte_length: usize,
+}
+
+impl BoundsCheck for CachedLengthBoundsCheck {
+    fn set_page_count(&mut self, page_count: usize) {
+        self.byte_length = page_count.saturating_mul(WASM_PAGE_SIZE_IN_BYTES);
+    }
+
+    fn check(&self, _page_count: usize, offset: usize, length: usize) -> Result<()> {
+        match offset.checked_add(length) {
+            None => Err(overflow_error()),
+            Some(end) if end > self.byte_length => Err(out_of_bounds_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Works out which page holds the last byte of the access and checks that the page exists.
+#[derive(Debug, Default)]
+pub struct PageLookupBoundsCheck {}
+
+impl BoundsCheck for PageLookupBoundsCheck {
+    fn set_page_count(&mut self, _page_count: usize) {}
+
+    fn check(&self, page_count: usize, offset: usize, length: usize) -> Result<()> {
+        match offset.checked_add(length) {
+            None => Err(overflow_error()),
+            Some(0) => Ok(()),
+            Some(end) => {
+                let (last_page, _) = split_page_from_address(end - 1);
+                if last_page < page_count {
+                    Ok(())
+                } else {
+                    Err(out_of_bounds_error())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_all<Check: BoundsCheck>(check: &mut Check) {
+        check.set_page_count(0);
+        assert!(check.check(0, 0, 0).is_ok());
+        assert!(check.check(0, 0, 1).is_err());
+
+        check.set_page_count(2);
+        let size = 2 * WASM_PAGE_SIZE_IN_BYTES;
+        assert!(check.check(2, 0, size).is_ok());
+        assert!(check.check(2, size, 0).is_ok());
+        assert!(check.check(2, size - 4, 4).is_ok());
+        assert!(check.check(2, size - 3, 4).is_err());
+        assert!(check.check(2, size, 1).is_err());
+        assert!(check.check(2, usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn test_bounds_check_strategies() {
+        check_all(&mut CachedLengthBoundsCheck::default());
+        check_all(&mut PageLookupBoundsCheck::default());
+    }
+}
@@ -1,68 +1,467 @@
 use crate::core::{
-    execute_expression, DataStore, Expr, Func, FuncType, FunctionStore, Locals, Stack,
+    compiled::{CompiledFunction, ModuleContext},
+    executor::execute_core::execute_body,
+    trap, DataStore, Expr, Func, FuncType, FunctionStore, Locals, Lock, MaybeSendSync, Shared,
+    Stack, TrapKind, Value, WasmError,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_INSTANCE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies an instantiated module, so that functions shared between instances can say
+/// where they came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InstanceId(u64);
+
+impl InstanceId {
+    pub(crate) fn next() -> Self {
+        Self(NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instance {}", self.0)
+    }
+}
+
+/// Where a function came from, as the instance which defined it, or which first put it in
+/// a table if it came from the host, and its index in that instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Provenance {
+    instance_id: InstanceId,
+    func_idx: usize,
+}
+
+impl Provenance {
+    pub fn new(instance_id: InstanceId, func_idx: usize) -> Self {
+        Self {
+            instance_id,
+            func_idx,
+        }
+    }
+
+    pub fn instance_id(&self) -> InstanceId {
+        self.instance_id
+    }
+
+    pub fn func_idx(&self) -> usize {
+        self.func_idx
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "function {} of {}", self.func_idx, self.instance_id)
+    }
+}
 
 #[derive(Debug)]
 pub struct WasmExprCallable {
     func_type: FuncType,
+    provenance: Option<Provenance>,
     locals: Vec<Locals>,
-    expr: Expr,
+    local_count: usize,
+    body: FunctionBody,
+}
+
+#[derive(Debug)]
+enum FunctionBody {
+    Compiled(Shared<CompiledFunction>),
+    /// Compiled when the function is first called. The module's types and what else the
+    /// module defines are kept for checking the body then.
+    Lazy {
+        expr: Expr,
+        types: Shared<[FuncType]>,
+        context: Shared<ModuleContext>,
+        compiled: Lock<Option<Shared<CompiledFunction>>>,
+    },
+}
+
+#[derive(Debug)]
+pub struct StubCallable {
+    func_type: FuncType,
+    provenance: Option<Provenance>,
+    mod_name: String,
+    name: String,
+}
+
+/// The ways a host function can fail. They are told apart in the error that ends the
+/// call, so an embedder can decide whether the guest or the host was at fault.
+#[derive(Debug)]
+pub enum HostFuncError {
+    /// The guest did something the host won't allow, so execution traps just as if the
+    /// guest had trapped itself
+    Trap(TrapKind),
+    /// The host failed for reasons of its own. The call is abandoned, and the error comes
+    /// back marked as `WasmError::HostError` rather than as a trap.
+    Host(anyhow::Error),
+    /// Not a failure - the results aren't ready yet. Execution is suspended until the host
+    /// supplies them through `Execution::resume_with`, which is only possible in a call
+    /// started with `Instance::invoke_resumable`.
+    Pending,
+}
+
+impl From<anyhow::Error> for HostFuncError {
+    fn from(error: anyhow::Error) -> Self {
+        HostFuncError::Host(error)
+    }
+}
+
+impl HostFuncError {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            HostFuncError::Trap(kind) => trap(kind),
+            // Errors which are already classified, such as a trap from touching memory out
+            // of bounds on the guest's behalf, keep their kind. The original error is kept
+            // underneath, so embedders can still downcast to their own error types.
+            HostFuncError::Host(error) if error.downcast_ref::<WasmError>().is_some() => error,
+            HostFuncError::Host(error) => error.context(WasmError::HostError),
+            HostFuncError::Pending => pending_outside_resumable_call(),
+        }
+    }
+}
+
+pub type HostFuncResult = std::result::Result<Vec<Value>, HostFuncError>;
+
+#[cfg(not(feature = "sync"))]
+pub type HostFunc = dyn Fn(&[Value]) -> HostFuncResult;
+/// Host functions go into instances, which can be sent to other threads
+#[cfg(feature = "sync")]
+pub type HostFunc = dyn Fn(&[Value]) -> HostFuncResult + Send + Sync;
+
+pub struct HostFuncCallable {
+    func_type: FuncType,
+    provenance: Option<Provenance>,
+    func: Box<HostFunc>,
+}
+
+impl fmt::Debug for HostFuncCallable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostFuncCallable")
+            .field("func_type", &self.func_type)
+            .field("provenance", &self.provenance)
+            .finish()
+    }
 }
 
 #[derive(Debug)]
 pub enum Callable {
     WasmExpr(WasmExprCallable),
+    Stub(StubCallable),
+    HostFunc(HostFuncCallable),
+}
+
+impl From<StubCallable> for Callable {
+    fn from(callable: StubCallable) -> Self {
+        Callable::Stub(callable)
+    }
+}
+
+impl From<HostFuncCallable> for Callable {
+    fn from(callable: HostFuncCallable) -> Self {
+        Callable::HostFunc(callable)
+    }
+}
+
+/// How far a call got when it was entered
+pub(crate) enum CallEntry {
+    /// The function's frame has been pushed, and its body is ready for the executor to run.
+    /// The function's index is there for the execution observer.
+    Body(Shared<CompiledFunction>, Option<usize>),
+    /// The call has finished and its results are on the stack
+    Complete,
+    /// A host function of this type is waiting for its results. Its arguments are still
+    /// on the stack.
+    Pending(FuncType),
 }
 
 impl Callable {
+    /// Call the function and run it to completion
     pub fn call(
         &self,
         stack: &mut Stack,
         function_store: &impl FunctionStore,
         data_store: &mut impl DataStore,
     ) -> Result<()> {
+        let mark = stack.mark();
+        match self.enter(stack)? {
+            CallEntry::Body(body, func_idx) => {
+                // A trap abandons the whole call, and can happen with any number of values
+                // on the stack, so the results are only checked if the body completed. The
+                // function's own frame goes along with its arguments.
+                if let Err(error) = execute_body(body, func_idx, stack, function_store, data_store)
+                {
+                    stack.unwind_to(mark);
+                    return Err(error);
+                }
+                stack.pop_typed_frame()
+            }
+            CallEntry::Complete => Ok(()),
+            CallEntry::Pending(_) => Err(pending_outside_resumable_call()),
+        }
+    }
+
+    /// Start a call without running any wasm code. This lets the executor run the body
+    /// itself, rather than recursing on the host's stack.
+    pub(crate) fn enter(&self, stack: &mut Stack) -> Result<CallEntry> {
         match &self {
-            Callable::WasmExpr(e) => e.call(stack, function_store, data_store),
+            Callable::WasmExpr(e) => e.enter(stack),
+            Callable::Stub(s) => s.call().map(|_| CallEntry::Complete),
+            Callable::HostFunc(h) => h.call(stack),
         }
     }
 
     pub fn func_type(&self) -> &FuncType {
         match &self {
             Callable::WasmExpr(e) => &e.func_type,
+            Callable::Stub(s) => &s.func_type,
+            Callable::HostFunc(h) => &h.func_type,
+        }
+    }
+
+    /// Where the function came from, if it has been defined by or put in a table by an
+    /// instance
+    pub fn provenance(&self) -> Option<Provenance> {
+        match &self {
+            Callable::WasmExpr(e) => e.provenance,
+            Callable::Stub(s) => s.provenance,
+            Callable::HostFunc(h) => h.provenance,
+        }
+    }
+
+    // The first instance to record itself is kept, because a function is only defined once
+    // but can be imported and put in tables by any number of instances
+    pub(crate) fn record_provenance(&mut self, provenance: Provenance) {
+        let slot = match self {
+            Callable::WasmExpr(e) => &mut e.provenance,
+            Callable::Stub(s) => &mut s.provenance,
+            Callable::HostFunc(h) => &mut h.provenance,
+        };
+        slot.get_or_insert(provenance);
+    }
+
+    /// The locals of a function defined in wasm, beyond its parameters
+    pub(crate) fn locals(&self) -> &[Locals] {
+        match self {
+            Callable::WasmExpr(e) => &e.locals,
+            _ => &[],
+        }
+    }
+
+    /// The compiled body of a function defined in wasm, compiling it first if it is lazy
+    pub(crate) fn compiled_body(&self) -> Result<Option<Shared<CompiledFunction>>> {
+        match self {
+            Callable::WasmExpr(e) => e.body().map(Some),
+            _ => Ok(None),
         }
     }
 }
 
 impl WasmExprCallable {
-    pub fn new(func_type: FuncType, func: Func) -> Callable {
-        Self::new_base(func_type, func.locals().clone(), func.expr().clone())
+    pub fn new(func_type: FuncType, func: Func, types: &[FuncType]) -> Result<Callable> {
+        Self::from_parts(
+            func_type,
+            func.locals().clone(),
+            func.local_count(),
+            func.expr(),
+            types,
+        )
     }
 
-    pub fn new_base(func_type: FuncType, locals: Vec<Locals>, expr: Expr) -> Callable {
-        Callable::WasmExpr(Self {
+    /// The body is compiled straight away, so a malformed one is reported here rather than
+    /// when the function is first called. The module's types are needed to resolve any
+    /// blocks whose type is a type index.
+    pub fn new_base(
+        func_type: FuncType,
+        locals: Vec<Locals>,
+        expr: &Expr,
+        types: &[FuncType],
+    ) -> Result<Callable> {
+        let local_count = Locals::total_count(&locals)?;
+        Self::from_parts(func_type, locals, local_count, expr, types)
+    }
+
+    fn from_parts(
+        func_type: FuncType,
+        locals: Vec<Locals>,
+        local_count: u32,
+        expr: &Expr,
+        types: &[FuncType],
+    ) -> Result<Callable> {
+        Ok(Callable::WasmExpr(Self {
             func_type,
+            provenance: None,
             locals,
-            expr,
-        })
+            local_count: usize::try_from(local_count)?,
+            body: FunctionBody::Compiled(Shared::new(CompiledFunction::new(expr, types)?)),
+        }))
     }
 
-    fn call(
-        &self,
-        stack: &mut Stack,
-        function_store: &impl FunctionStore,
-        data_store: &mut impl DataStore,
-    ) -> Result<()> {
-        // Create the call frame for the function on the stack
-        stack.push_typed_frame(&self.func_type, &self.locals)?;
+    /// A function whose body isn't compiled until it is first called, so that a module
+    /// with many functions can be instantiated without compiling the ones it never uses
+    pub(crate) fn new_lazy(
+        func_type: FuncType,
+        func: Func,
+        types: Shared<[FuncType]>,
+        context: Shared<ModuleContext>,
+    ) -> Result<Callable> {
+        Ok(Callable::WasmExpr(Self {
+            func_type,
+            provenance: None,
+            locals: func.locals().clone(),
+            local_count: usize::try_from(func.local_count())?,
+            body: FunctionBody::Lazy {
+                expr: func.expr().clone(),
+                types,
+                context,
+                compiled: Lock::new(None),
+            },
+        }))
+    }
 
-        // Now execute the function on the stack
-        let result = execute_expression(&self.expr, stack, function_store, data_store);
+    fn body(&self) -> Result<Shared<CompiledFunction>> {
+        match &self.body {
+            FunctionBody::Compiled(body) => Ok(body.clone()),
+            FunctionBody::Lazy {
+                expr,
+                types,
+                context,
+                compiled,
+            } => {
+                if let Some(body) = &*compiled.borrow() {
+                    return Ok(body.clone());
+                }
 
-        // Pop the function frame off the stack
-        stack.pop_typed_frame()?;
+                let body = CompiledFunction::new(expr, types)
+                    .and_then(|body| {
+                        body.check_module_references(context, &self.func_type, &self.locals)?;
+                        Ok(Shared::new(body))
+                    })
+                    .with_context(|| match self.provenance {
+                        Some(provenance) => format!("In {}", provenance),
+                        None => "In a function compiled on its first call".to_string(),
+                    })?;
+                *compiled.borrow_mut() = Some(body.clone());
+                Ok(body)
+            }
+        }
+    }
 
-        // And we're done
-        result
+    fn enter(&self, stack: &mut Stack) -> Result<CallEntry> {
+        let body = self.body()?;
+
+        // Create the call frame for the function on the stack, ready for the body
+        stack.push_typed_frame(&self.func_type, &self.locals, self.local_count)?;
+        Ok(CallEntry::Body(
+            body,
+            self.provenance.map(|provenance| provenance.func_idx()),
+        ))
     }
 }
+
+impl StubCallable {
+    pub fn new(func_type: FuncType, mod_name: &str, name: &str) -> Self {
+        Self {
+            func_type,
+            provenance: None,
+            mod_name: mod_name.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    fn call(&self) -> Result<()> {
+        Err(anyhow!(
+            "Called imported function {}:{} which was stubbed out because it could not be resolved",
+            self.mod_name,
+            self.name
+        ))
+    }
+}
+
+impl HostFuncCallable {
+    pub fn new(
+        func_type: FuncType,
+        func: impl Fn(&[Value]) -> HostFuncResult + MaybeSendSync + 'static,
+    ) -> Self {
+        Self {
+            func_type,
+            provenance: None,
+            func: Box::new(func),
+        }
+    }
+
+    fn call(&self, stack: &mut Stack) -> Result<CallEntry> {
+        let arg_types = self.func_type.arg_types();
+        if arg_types.len() > stack.working_count() {
+            return Err(anyhow!("Not enough arguments on working stack"));
+        }
+        let args: Vec<Value> = stack
+            .working_top(arg_types.len())
+            .iter()
+            .map(|entry| Value::from(*entry))
+            .collect();
+
+        for (idx, (arg, arg_type)) in args.iter().zip(arg_types).enumerate() {
+            if arg.value_type() != *arg_type {
+                return Err(anyhow!(
+                    "Argument {} to host function is {:?} but {:?} was expected",
+                    idx,
+                    arg.value_type(),
+                    arg_type
+                ));
+            }
+        }
+
+        match (self.func)(&args) {
+            Ok(results) => {
+                complete_host_call(&self.func_type, results, stack)?;
+                Ok(CallEntry::Complete)
+            }
+            Err(HostFuncError::Pending) => Ok(CallEntry::Pending(self.func_type.clone())),
+            Err(error) => Err(error.into_error()),
+        }
+    }
+}
+
+pub(crate) fn pending_outside_resumable_call() -> anyhow::Error {
+    anyhow!("Host functions can only wait for their results in a resumable call")
+}
+
+/// Replace the arguments of a host function call with its results
+pub(crate) fn complete_host_call(
+    func_type: &FuncType,
+    results: Vec<Value>,
+    stack: &mut Stack,
+) -> Result<()> {
+    // The results are outside of our control, so check that they match the signature
+    // before anything is pushed onto the stack
+    let return_types = func_type.return_types();
+    if results.len() != return_types.len() {
+        return Err(anyhow!(
+            "Host function returned {} results but {} were expected",
+            results.len(),
+            return_types.len()
+        ));
+    }
+    for (idx, (result, return_type)) in results.iter().zip(return_types).enumerate() {
+        if result.value_type() != *return_type {
+            return Err(anyhow!(
+                "Result {} of host function is {:?} but {:?} was expected",
+                idx,
+                result.value_type(),
+                return_type
+            ));
+        }
+    }
+
+    stack.pop_n(func_type.arg_types().len());
+    for result in results {
+        stack.push(result.into());
+    }
+
+    Ok(())
+}
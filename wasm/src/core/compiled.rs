@@ -0,0 +1,980 @@
+//! Function bodies decoded once, when they are loaded, into a flat list of instructions.
+//! Immediates are decoded up front, and every branch knows where it goes, so the executor
+//! never has to look at the encoded bytes or scan for the end of a block.
+
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+
+use crate::core::{BlockType, FuncType, GlobalType, Locals, ValueType};
+use crate::parser::{
+    lookup_extension, AtomicOpcode, Instruction, InstructionImmediates, InstructionIterator,
+    InstructionSource, MemArg, MiscOpcode, Opcode,
+};
+
+/// Where a branch leaves execution, worked out when the function is compiled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BranchTarget {
+    /// How many labels out the branch goes, where 0 is the innermost
+    depth: usize,
+    /// The next instruction to execute, or `None` if the branch targets the function body
+    /// itself, which makes it behave like a return
+    destination: Option<usize>,
+    /// Branches to a loop go back to its start, and need the loop's label back again
+    is_loop: bool,
+    /// The number of values the branch carries to its label. Branches out of the function
+    /// body leave the results to the call frame, so this is zero for them.
+    arity: usize,
+}
+
+impl BranchTarget {
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn destination(&self) -> Option<usize> {
+        self.destination
+    }
+
+    pub fn is_loop(&self) -> bool {
+        self.is_loop
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+}
+
+/// The targets of a br_table. Compilers often repeat a target many times in a row, as
+/// when most cases of a switch share the default, so each run of the same target is
+/// stored once along with the first selector which picks it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchTable {
+    /// One for each run, with the run holding the default target last
+    targets: Box<[BranchTarget]>,
+    /// The first selector of each run, or `None` when no target is repeated, so that the
+    /// target can be looked up directly
+    run_starts: Option<Box<[u32]>>,
+}
+
+impl BranchTable {
+    /// The target picked by a selector. Selectors past the end pick the default.
+    pub fn target(&self, selector: u32) -> &BranchTarget {
+        let run = match &self.run_starts {
+            Some(run_starts) => run_starts.partition_point(|start| *start <= selector) - 1,
+            None => std::cmp::min(selector as usize, self.targets.len() - 1),
+        };
+        &self.targets[run]
+    }
+
+    /// The distinct runs of targets, in order
+    pub fn targets(&self) -> &[BranchTarget] {
+        &self.targets
+    }
+}
+
+/// The shape of a block, resolved from its block type when the function is compiled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockSignature {
+    block_type: BlockType,
+    /// The number of values the block takes from the stack when it is entered
+    param_count: usize,
+    /// The number of values the block leaves on the stack when it ends
+    result_count: usize,
+    /// The number of values a branch to the block carries, which is the parameters for a
+    /// loop, because the branch starts it again, and the results for anything else
+    label_arity: usize,
+}
+
+impl BlockSignature {
+    fn new(block_type: BlockType, is_loop: bool, types: &[FuncType]) -> Result<Self> {
+        let func_type = block_type.func_type(types)?;
+        let param_count = func_type.arg_types().len();
+        let result_count = func_type.return_types().len();
+        Ok(Self {
+            block_type,
+            param_count,
+            result_count,
+            label_arity: if is_loop { param_count } else { result_count },
+        })
+    }
+
+    pub fn block_type(&self) -> BlockType {
+        self.block_type
+    }
+
+    pub fn param_count(&self) -> usize {
+        self.param_count
+    }
+
+    pub fn result_count(&self) -> usize {
+        self.result_count
+    }
+
+    pub fn label_arity(&self) -> usize {
+        self.label_arity
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompiledImmediates {
+    None,
+    Index(u32),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Pair(u32, u32),
+    MemArg(MemArg),
+    /// The type chosen between by a typed select
+    ValueType(ValueType),
+    Block(BlockSignature),
+    /// Where the else branch starts, if there is one, and the position just after the end
+    /// of the whole if
+    If {
+        signature: BlockSignature,
+        else_start: Option<usize>,
+        end: usize,
+    },
+    /// Reaching the else means the then branch has finished, so it skips to the end
+    Else {
+        end: usize,
+    },
+    /// The end of a block, which keeps the block's results and drops anything beneath them
+    End {
+        result_count: usize,
+    },
+    Branch(BranchTarget),
+    BranchTable(BranchTable),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledInstruction {
+    opcode: Opcode,
+    misc_opcode: Option<MiscOpcode>,
+    atomic_opcode: Option<AtomicOpcode>,
+    extension_opcode: Option<u32>,
+    immediates: CompiledImmediates,
+}
+
+impl CompiledInstruction {
+    fn new(opcode: Opcode, immediates: CompiledImmediates) -> Self {
+        Self {
+            opcode,
+            misc_opcode: None,
+            atomic_opcode: None,
+            extension_opcode: None,
+            immediates,
+        }
+    }
+
+    /// Decode an instruction which doesn't affect control flow. Blocks and branches depend
+    /// on what surrounds them, so they can only be compiled as part of a whole function.
+    pub fn decode(instruction: &Instruction) -> Result<Self> {
+        let immediates = match instruction.immediates() {
+            InstructionImmediates::None => CompiledImmediates::None,
+            InstructionImmediates::Index(index) => CompiledImmediates::Index(index),
+            InstructionImmediates::I32(value) => CompiledImmediates::I32(value),
+            InstructionImmediates::I64(value) => CompiledImmediates::I64(value),
+            InstructionImmediates::F32(value) => CompiledImmediates::F32(value),
+            InstructionImmediates::F64(value) => CompiledImmediates::F64(value),
+            InstructionImmediates::Pair(first, second) => CompiledImmediates::Pair(first, second),
+            InstructionImmediates::MemArg(mem_arg) => CompiledImmediates::MemArg(mem_arg),
+            InstructionImmediates::BranchTable(_) | InstructionImmediates::Block { .. } => {
+                return Err(anyhow!(
+                    "{:?} can only be compiled as part of a function",
+                    instruction.opcode()
+                ))
+            }
+        };
+
+        let immediates = match (instruction.opcode(), immediates) {
+            (Opcode::SelectTyped, CompiledImmediates::Pair(count, value_type)) => {
+                CompiledImmediates::ValueType(select_type(count, value_type)?)
+            }
+            (_, immediates) => immediates,
+        };
+
+        if let CompiledImmediates::MemArg(mem_arg) = immediates {
+            // The hint may be smaller than the size of the access, but never larger. Atomic
+            // accesses have to be naturally aligned, so their hint has to be exactly that.
+            let atomic_opcode = instruction.try_atomic_opcode();
+            let natural_alignment = match atomic_opcode {
+                Some(atomic_opcode) => atomic_opcode.natural_alignment(),
+                None => instruction.opcode().natural_alignment(),
+            };
+            if let Some(natural_alignment) = natural_alignment {
+                if let Some(atomic_opcode) = atomic_opcode {
+                    if mem_arg.align() != natural_alignment {
+                        return Err(anyhow!(
+                            "Alignment 2^{} of {:?} isn't its natural alignment 2^{}",
+                            mem_arg.align(),
+                            atomic_opcode,
+                            natural_alignment
+                        ));
+                    }
+                } else if mem_arg.align() > natural_alignment {
+                    return Err(anyhow!(
+                        "Alignment 2^{} of {:?} is larger than its natural alignment 2^{}",
+                        mem_arg.align(),
+                        instruction.opcode(),
+                        natural_alignment
+                    ));
+                }
+            }
+        }
+
+        if let Some(sub_opcode) = instruction.try_extension_opcode() {
+            lookup_extension(sub_opcode)?.validate(sub_opcode, &immediates)?;
+        }
+
+        Ok(Self {
+            opcode: instruction.opcode(),
+            misc_opcode: instruction.try_misc_opcode(),
+            atomic_opcode: instruction.try_atomic_opcode(),
+            extension_opcode: instruction.try_extension_opcode(),
+            immediates,
+        })
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    pub fn misc_opcode(&self) -> MiscOpcode {
+        match self.misc_opcode {
+            Some(misc_opcode) => misc_opcode,
+            _ => panic!("Not a prefixed instruction"),
+        }
+    }
+
+    pub fn atomic_opcode(&self) -> AtomicOpcode {
+        match self.atomic_opcode {
+            Some(atomic_opcode) => atomic_opcode,
+            _ => panic!("Not an atomic instruction"),
+        }
+    }
+
+    pub fn extension_opcode(&self) -> u32 {
+        match self.extension_opcode {
+            Some(extension_opcode) => extension_opcode,
+            _ => panic!("Not an extension instruction"),
+        }
+    }
+
+    pub fn immediates(&self) -> &CompiledImmediates {
+        &self.immediates
+    }
+
+    /// The name of the instruction, which is the sub opcode for prefixed instructions
+    pub fn name(&self) -> String {
+        match (self.misc_opcode, self.atomic_opcode, self.extension_opcode) {
+            (Some(misc_opcode), _, _) => format!("{:?}", misc_opcode),
+            (_, Some(atomic_opcode), _) => format!("{:?}", atomic_opcode),
+            (_, _, Some(extension_opcode)) => format!("{:?} {}", self.opcode, extension_opcode),
+            _ => format!("{:?}", self.opcode),
+        }
+    }
+
+    // Like the accessors on Instruction, these panic if the instruction doesn't have the
+    // immediate asked for, because the executor only asks for what the opcode implies
+
+    pub fn get_single_u32_arg(&self) -> u32 {
+        match self.immediates {
+            CompiledImmediates::Index(index) => index,
+            _ => panic!("{:?} has no index", self.opcode),
+        }
+    }
+
+    pub fn get_single_u32_as_usize_arg(&self) -> usize {
+        self.get_single_u32_arg() as usize
+    }
+
+    pub fn get_single_i32_arg(&self) -> i32 {
+        match self.immediates {
+            CompiledImmediates::I32(value) => value,
+            _ => panic!("{:?} has no i32 immediate", self.opcode),
+        }
+    }
+
+    pub fn get_single_i64_arg(&self) -> i64 {
+        match self.immediates {
+            CompiledImmediates::I64(value) => value,
+            _ => panic!("{:?} has no i64 immediate", self.opcode),
+        }
+    }
+
+    pub fn get_single_f32_arg(&self) -> f32 {
+        match self.immediates {
+            CompiledImmediates::F32(value) => value,
+            _ => panic!("{:?} has no f32 immediate", self.opcode),
+        }
+    }
+
+    pub fn get_single_f64_arg(&self) -> f64 {
+        match self.immediates {
+            CompiledImmediates::F64(value) => value,
+            _ => panic!("{:?} has no f64 immediate", self.opcode),
+        }
+    }
+
+    pub fn get_pair_u32_as_usize_arg(&self) -> (usize, usize) {
+        match self.immediates {
+            CompiledImmediates::Pair(first, second) => (first as usize, second as usize),
+            _ => panic!("{:?} has no pair of immediates", self.opcode),
+        }
+    }
+
+    pub fn get_value_type(&self) -> ValueType {
+        match &self.immediates {
+            CompiledImmediates::ValueType(value_type) => value_type.clone(),
+            _ => panic!("{:?} has no value type", self.opcode),
+        }
+    }
+
+    pub fn get_mem_arg(&self) -> MemArg {
+        match self.immediates {
+            CompiledImmediates::MemArg(mem_arg) => mem_arg,
+            _ => panic!("{:?} has no memory argument", self.opcode),
+        }
+    }
+
+    // The memories the instruction touches, so they can be checked against the module
+    fn memory_indices(&self) -> Vec<u32> {
+        match (&self.immediates, self.opcode, self.misc_opcode) {
+            (CompiledImmediates::MemArg(mem_arg), _, _) => vec![mem_arg.memory()],
+            (CompiledImmediates::Index(mem_idx), Opcode::MemorySize, _)
+            | (CompiledImmediates::Index(mem_idx), Opcode::MemoryGrow, _)
+            | (CompiledImmediates::Index(mem_idx), _, Some(MiscOpcode::MemoryFill)) => {
+                vec![*mem_idx]
+            }
+            (CompiledImmediates::Pair(_, mem_idx), _, Some(MiscOpcode::MemoryInit)) => {
+                vec![*mem_idx]
+            }
+            (CompiledImmediates::Pair(dst_idx, src_idx), _, Some(MiscOpcode::MemoryCopy)) => {
+                vec![*dst_idx, *src_idx]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // The type of the value the instruction gives, where that doesn't depend on its
+    // operands, so that the instruction which takes it can be checked before it runs
+    fn result_type(
+        &self,
+        context: &ModuleContext,
+        func_type: &FuncType,
+        locals: &[Locals],
+    ) -> Option<ValueType> {
+        match self.opcode {
+            Opcode::I32Const => Some(ValueType::I32),
+            Opcode::I64Const => Some(ValueType::I64),
+            Opcode::F32Const => Some(ValueType::F32),
+            Opcode::F64Const => Some(ValueType::F64),
+            Opcode::GlobalGet => context
+                .globals
+                .get(self.get_single_u32_as_usize_arg())
+                .map(|global_type| global_type.value_type().clone()),
+            Opcode::LocalGet | Opcode::LocalTee => {
+                local_type(func_type, locals, self.get_single_u32_as_usize_arg())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_block_signature(&self) -> &BlockSignature {
+        match &self.immediates {
+            CompiledImmediates::Block(signature) | CompiledImmediates::If { signature, .. } => {
+                signature
+            }
+            _ => panic!("{:?} is not a block", self.opcode),
+        }
+    }
+
+    pub fn get_block_type(&self) -> BlockType {
+        self.get_block_signature().block_type()
+    }
+
+    // Fill in a destination which wasn't known until the end of its block was reached
+    fn set_destination(&mut self, slot: usize, destination: usize) {
+        match &mut self.immediates {
+            CompiledImmediates::Branch(target) => target.destination = Some(destination),
+            CompiledImmediates::BranchTable(table) => {
+                table.targets[slot].destination = Some(destination)
+            }
+            CompiledImmediates::If { end, .. } | CompiledImmediates::Else { end } => {
+                *end = destination
+            }
+            _ => panic!("{:?} has no destination", self.opcode),
+        }
+    }
+}
+
+// Without an else, an if whose condition is false passes its parameters straight through
+// as its results, so they have to be the same
+fn check_if_without_else(block_type: BlockType, types: &[FuncType]) -> Result<()> {
+    let func_type = block_type.func_type(types)?;
+    if func_type.arg_types() != func_type.return_types() {
+        return Err(anyhow!(
+            "If of type {:?} has no else, so it cannot turn {:?} into {:?}",
+            block_type,
+            func_type.arg_types(),
+            func_type.return_types()
+        ));
+    }
+    Ok(())
+}
+
+// The vector of types of a typed select, which has to hold exactly one type
+fn select_type(count: u32, value_type: u32) -> Result<ValueType> {
+    if count != 1 {
+        return Err(anyhow!(
+            "Typed select must have exactly one type, not {}",
+            count
+        ));
+    }
+
+    u8::try_from(value_type)
+        .map_err(|_| anyhow!("Invalid value type 0x{:x} in typed select", value_type))
+        .and_then(ValueType::from_byte)
+}
+
+// A block which is still being compiled
+struct OpenBlock<'a> {
+    instructions: InstructionIterator<'a, [u8]>,
+    // The position of the block instruction itself, or None for the function body
+    start: Option<usize>,
+    is_loop: bool,
+    // The block's own signature, or None for the function body
+    signature: Option<BlockSignature>,
+    else_block: Option<&'a [u8]>,
+    // Instructions which need to know where the end of the block is, along with which of
+    // their destinations it is
+    forward_branches: Vec<(usize, usize)>,
+}
+
+impl<'a> OpenBlock<'a> {
+    fn new(
+        body: &'a [u8],
+        start: Option<usize>,
+        is_loop: bool,
+        signature: Option<BlockSignature>,
+    ) -> Self {
+        Self {
+            instructions: InstructionIterator::new(body),
+            start,
+            is_loop,
+            signature,
+            else_block: None,
+            forward_branches: Vec::new(),
+        }
+    }
+}
+
+/// A function body, ready to execute
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledFunction {
+    instructions: Vec<CompiledInstruction>,
+    /// Where each instruction came from in the body, with one more entry for the body's
+    /// final end
+    byte_offsets: Vec<usize>,
+}
+
+impl CompiledFunction {
+    /// Compile an expression. Nested blocks are handled with an explicit stack rather than
+    /// by recursing, so deep nesting can't exhaust the host's stack. The module's types are
+    /// needed for blocks whose type is a type index.
+    pub fn new(expr: &(impl InstructionSource + ?Sized), types: &[FuncType]) -> Result<Self> {
+        let body = expr.get_instruction_bytes();
+        let mut instructions = Vec::new();
+        let mut byte_offsets = Vec::new();
+        let mut open_blocks = vec![OpenBlock::new(body, None, false, None)];
+
+        while let Some(block) = open_blocks.last_mut() {
+            let instruction = match block.instructions.next() {
+                Some(instruction) => instruction?,
+                None => {
+                    Self::close_block(&mut instructions, &mut byte_offsets, &mut open_blocks, body);
+                    continue;
+                }
+            };
+
+            let position = instructions.len();
+            let compiled = match instruction.opcode() {
+                opcode @ Opcode::Block | opcode @ Opcode::Loop | opcode @ Opcode::If => {
+                    let is_loop = opcode == Opcode::Loop;
+                    let signature =
+                        BlockSignature::new(instruction.get_block_type(), is_loop, types)?;
+                    let mut nested = OpenBlock::new(
+                        instruction.get_block(),
+                        Some(position),
+                        is_loop,
+                        Some(signature),
+                    );
+                    let immediates = if opcode == Opcode::If {
+                        nested.else_block = instruction.try_get_else_block();
+                        if nested.else_block.is_none() {
+                            check_if_without_else(instruction.get_block_type(), types)?;
+                        }
+                        nested.forward_branches.push((position, 0));
+                        CompiledImmediates::If {
+                            signature,
+                            else_start: None,
+                            end: 0,
+                        }
+                    } else {
+                        CompiledImmediates::Block(signature)
+                    };
+
+                    open_blocks.push(nested);
+                    CompiledInstruction::new(opcode, immediates)
+                }
+                opcode @ Opcode::Br | opcode @ Opcode::BrIf => {
+                    let depth = instruction.get_single_u32_as_usize_arg();
+                    let target = Self::resolve_branch(&mut open_blocks, depth, position, 0);
+                    CompiledInstruction::new(opcode, CompiledImmediates::Branch(target))
+                }
+                Opcode::BrTable => {
+                    let table =
+                        Self::compile_branch_table(&instruction, &mut open_blocks, position);
+                    CompiledInstruction::new(
+                        Opcode::BrTable,
+                        CompiledImmediates::BranchTable(table),
+                    )
+                }
+                _ => CompiledInstruction::decode(&instruction)?,
+            };
+            instructions.push(compiled);
+            byte_offsets.push(offset_within(body, instruction.bytes()));
+        }
+
+        byte_offsets.push(body.len().saturating_sub(1));
+        Ok(Self {
+            instructions,
+            byte_offsets,
+        })
+    }
+
+    fn compile_branch_table(
+        instruction: &Instruction,
+        open_blocks: &mut [OpenBlock],
+        position: usize,
+    ) -> BranchTable {
+        let depths = instruction.block_table_targets();
+        let target_count = depths.len();
+        let mut targets = Vec::new();
+        let mut run_starts = Vec::new();
+        let mut previous_depth = None;
+
+        // Each run is only resolved once, so it is also only patched once when the end of
+        // its block is reached
+        for (selector, depth) in depths.enumerate() {
+            if previous_depth != Some(depth) {
+                run_starts.push(u32::try_from(selector).unwrap());
+                targets.push(Self::resolve_branch(
+                    open_blocks,
+                    depth,
+                    position,
+                    targets.len(),
+                ));
+                previous_depth = Some(depth);
+            }
+        }
+
+        BranchTable {
+            targets: targets.into_boxed_slice(),
+            run_starts: (run_starts.len() < target_count).then(|| run_starts.into_boxed_slice()),
+        }
+    }
+
+    fn resolve_branch(
+        open_blocks: &mut [OpenBlock],
+        depth: usize,
+        position: usize,
+        slot: usize,
+    ) -> BranchTarget {
+        // The outermost entry is the function body, which has no label
+        let label_count = open_blocks.len() - 1;
+        if depth >= label_count {
+            return BranchTarget {
+                depth,
+                destination: None,
+                is_loop: false,
+                arity: 0,
+            };
+        }
+
+        let block = &mut open_blocks[label_count - depth];
+        let arity = block
+            .signature
+            .map_or(0, |signature| signature.label_arity());
+        if block.is_loop {
+            // Loops start again from the instruction after the loop itself
+            BranchTarget {
+                depth,
+                destination: block.start.map(|start| start + 1),
+                is_loop: true,
+                arity,
+            }
+        } else {
+            // Blocks are exited, but their end isn't known yet
+            block.forward_branches.push((position, slot));
+            BranchTarget {
+                depth,
+                destination: Some(0),
+                is_loop: false,
+                arity,
+            }
+        }
+    }
+
+    fn close_block(
+        instructions: &mut Vec<CompiledInstruction>,
+        byte_offsets: &mut Vec<usize>,
+        open_blocks: &mut Vec<OpenBlock>,
+        body: &[u8],
+    ) {
+        let block = open_blocks.last_mut().unwrap();
+        // Blocks don't include the else or end which finishes them, so that comes next
+        let block_bytes = block.instructions.get_instruction_bytes();
+        let end_offset = offset_within(body, block_bytes) + block_bytes.len();
+        let (start, signature) = match (block.start, block.signature) {
+            (Some(start), Some(signature)) => (start, signature),
+            _ => {
+                // The function body has finished
+                open_blocks.pop();
+                return;
+            }
+        };
+
+        if let Some(else_block) = block.else_block.take() {
+            // The then branch has finished, so carry on with the else branch
+            block.forward_branches.push((instructions.len(), 0));
+            instructions.push(CompiledInstruction::new(
+                Opcode::Else,
+                CompiledImmediates::Else { end: 0 },
+            ));
+            byte_offsets.push(end_offset);
+            let else_position = instructions.len();
+            if let CompiledImmediates::If { else_start, .. } = &mut instructions[start].immediates {
+                *else_start = Some(else_position);
+            }
+            block.instructions = InstructionIterator::new(else_block);
+            return;
+        }
+
+        instructions.push(CompiledInstruction::new(
+            Opcode::End,
+            CompiledImmediates::End {
+                result_count: signature.result_count(),
+            },
+        ));
+        byte_offsets.push(end_offset);
+        let end = instructions.len();
+        for (position, slot) in open_blocks.pop().unwrap().forward_branches {
+            instructions[position].set_destination(slot, end);
+        }
+    }
+
+    pub fn instructions(&self) -> &[CompiledInstruction] {
+        &self.instructions
+    }
+
+    /// Where the instruction at `position` starts in the function's body. The position
+    /// after the last instruction is the end of the body.
+    pub fn byte_offset(&self, position: usize) -> usize {
+        self.byte_offsets[position]
+    }
+
+    /// The position of the instruction which starts at `byte_offset` in the function's body
+    pub fn position_at(&self, byte_offset: usize) -> Option<usize> {
+        self.byte_offsets.binary_search(&byte_offset).ok()
+    }
+
+    /// The arities of the labels of the blocks which enclose the instruction at `position`,
+    /// outermost first. These are the labels on the stack when execution reaches it.
+    pub(crate) fn label_arities(&self, position: usize) -> Vec<usize> {
+        let mut arities = Vec::new();
+        for instruction in &self.instructions[..position] {
+            match instruction.immediates() {
+                CompiledImmediates::Block(signature) | CompiledImmediates::If { signature, .. } => {
+                    arities.push(signature.label_arity())
+                }
+                CompiledImmediates::End { .. } => {
+                    arities.pop();
+                }
+                _ => {}
+            }
+        }
+        arities
+    }
+
+    /// Make sure that every memory and global the function uses exists, and that every
+    /// global it sets is mutable. The type of the value a global is set to is only checked
+    /// when it comes straight from a constant, a global or a local, and anything else is
+    /// left to the check when the value is set. Memories and globals can be imported, so
+    /// this can't happen until the module's imports are known.
+    pub fn check_module_references(
+        &self,
+        context: &ModuleContext,
+        func_type: &FuncType,
+        locals: &[Locals],
+    ) -> Result<()> {
+        // Only the instruction just before a global.set is looked at. When that is one of
+        // the instructions above, the value it pushes is the global.set's operand, because
+        // branches only ever land just after a block, an else or an end, which are
+        // compiled instructions too.
+        let mut previous: Option<&CompiledInstruction> = None;
+        for instruction in &self.instructions {
+            for mem_idx in instruction.memory_indices() {
+                if mem_idx as usize >= context.memory_count {
+                    return Err(anyhow!(
+                        "{:?} uses memory {} but there are only {} memories",
+                        instruction.opcode(),
+                        mem_idx,
+                        context.memory_count
+                    ));
+                }
+            }
+
+            if let Opcode::GlobalGet | Opcode::GlobalSet = instruction.opcode() {
+                let global_idx = instruction.get_single_u32_as_usize_arg();
+                let global_type = context.globals.get(global_idx).ok_or_else(|| {
+                    anyhow!(
+                        "{:?} uses global {} but there are only {} globals",
+                        instruction.opcode(),
+                        global_idx,
+                        context.globals.len()
+                    )
+                })?;
+                if instruction.opcode() == Opcode::GlobalSet && !global_type.is_mutable() {
+                    return Err(anyhow!(
+                        "GlobalSet of global {} of type {:?}, which is immutable",
+                        global_idx,
+                        global_type.value_type()
+                    ));
+                }
+                let operand_type =
+                    previous.and_then(|previous| previous.result_type(context, func_type, locals));
+                if instruction.opcode() == Opcode::GlobalSet {
+                    if let Some(operand_type) = operand_type {
+                        if operand_type != *global_type.value_type() {
+                            return Err(anyhow!(
+                                "GlobalSet of global {} of type {:?} given an operand of type {:?}",
+                                global_idx,
+                                global_type.value_type(),
+                                operand_type
+                            ));
+                        }
+                    }
+                }
+            }
+            previous = Some(instruction);
+        }
+        Ok(())
+    }
+}
+
+/// What a function body can refer to outside itself, for checking the body against the
+/// module it belongs to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModuleContext {
+    memory_count: usize,
+    /// The types of all of the module's globals, imported ones first
+    globals: Vec<GlobalType>,
+}
+
+impl ModuleContext {
+    pub fn new(memory_count: usize, globals: Vec<GlobalType>) -> Self {
+        Self {
+            memory_count,
+            globals,
+        }
+    }
+}
+
+// The type of a local, counting the function's parameters first
+fn local_type(func_type: &FuncType, locals: &[Locals], local_idx: usize) -> Option<ValueType> {
+    let params = func_type.arg_types();
+    if let Some(param_type) = params.get(local_idx) {
+        return Some(param_type.clone());
+    }
+    let mut remaining = local_idx - params.len();
+    for locals in locals {
+        let count = locals.count() as usize;
+        if remaining < count {
+            return Some(locals.value_type());
+        }
+        remaining -= count;
+    }
+    None
+}
+
+// The bytes of nested blocks are slices of the body's bytes
+fn offset_within(body: &[u8], bytes: &[u8]) -> usize {
+    bytes.as_ptr() as usize - body.as_ptr() as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::Expr;
+
+    #[test]
+    fn test_branch_destinations() {
+        // (loop (result i32) (block (result i32) i32.const 1 br 0) br_if 0)
+        let function = CompiledFunction::new(
+            &Expr::new(vec![
+                0x03, 0x7F, 0x02, 0x7F, 0x41, 0x01, 0x0C, 0x00, 0x0B, 0x0D, 0x00, 0x0B, 0x0B,
+            ]),
+            &[],
+        )
+        .unwrap();
+        let instructions = function.instructions();
+
+        assert_eq!(instructions.len(), 7);
+        assert_eq!(instructions[0].get_block_signature().label_arity(), 0);
+        assert_eq!(instructions[1].get_block_signature().label_arity(), 1);
+
+        // The branch out of the block goes just past its end
+        assert_eq!(
+            instructions[3].immediates(),
+            &CompiledImmediates::Branch(BranchTarget {
+                depth: 0,
+                destination: Some(5),
+                is_loop: false,
+                arity: 1,
+            })
+        );
+
+        // The branch to the loop goes back to the instruction after the loop
+        assert_eq!(
+            instructions[5].immediates(),
+            &CompiledImmediates::Branch(BranchTarget {
+                depth: 0,
+                destination: Some(1),
+                is_loop: true,
+                arity: 0,
+            })
+        );
+
+        // The ends of blocks are at the ends of their bytes
+        let offsets: Vec<usize> = (0..=7).map(|pc| function.byte_offset(pc)).collect();
+        assert_eq!(offsets, vec![0, 2, 4, 6, 8, 9, 11, 12]);
+        assert_eq!(function.position_at(9), Some(5));
+        assert_eq!(function.position_at(5), None);
+    }
+
+    #[test]
+    fn test_branch_table_runs() {
+        // (block (block local.get 0 (br_table 0 0 0 1 1 0 1) ))
+        let body = vec![
+            0x02, 0x40, 0x02, 0x40, 0x20, 0x00, 0x0E, 0x06, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00,
+            0x01, 0x0B, 0x0B, 0x0B,
+        ];
+        let function = CompiledFunction::new(&Expr::new(body), &[]).unwrap();
+        let table = match function.instructions()[3].immediates() {
+            CompiledImmediates::BranchTable(table) => table,
+            immediates => panic!("Expected a branch table but found {:?}", immediates),
+        };
+
+        // Four runs are kept for the seven targets
+        let depths: Vec<usize> = table.targets().iter().map(BranchTarget::depth).collect();
+        assert_eq!(depths, vec![0, 1, 0, 1]);
+
+        // Every run still goes past the end of its own block
+        let picked = |selector| {
+            let target = table.target(selector);
+            (target.depth(), target.destination())
+        };
+        for selector in 0..3 {
+            assert_eq!(picked(selector), (0, Some(5)));
+        }
+        assert_eq!(picked(3), (1, Some(6)));
+        assert_eq!(picked(4), (1, Some(6)));
+        assert_eq!(picked(5), (0, Some(5)));
+        assert_eq!(picked(6), (1, Some(6)));
+        assert_eq!(picked(u32::MAX), (1, Some(6)));
+
+        // A table without any repeats is looked up directly
+        let body = vec![0x02, 0x40, 0x41, 0x00, 0x0E, 0x01, 0x00, 0x01, 0x0B, 0x0B];
+        let function = CompiledFunction::new(&Expr::new(body), &[]).unwrap();
+        match function.instructions()[2].immediates() {
+            CompiledImmediates::BranchTable(table) => {
+                assert!(table.run_starts.is_none());
+                assert_eq!(table.target(0).depth(), 0);
+                assert_eq!(table.target(7).depth(), 1);
+            }
+            immediates => panic!("Expected a branch table but found {:?}", immediates),
+        }
+    }
+
+    #[test]
+    fn test_alignment_validation() {
+        let compile = |body: Vec<u8>| CompiledFunction::new(&Expr::new(body), &[]);
+
+        // i32.load with alignments 2^2 and 2^0 are fine, but 2^3 is too large
+        assert!(compile(vec![0x41, 0x00, 0x28, 0x02, 0x00, 0x1A, 0x0B]).is_ok());
+        assert!(compile(vec![0x41, 0x00, 0x28, 0x00, 0x00, 0x1A, 0x0B]).is_ok());
+        let err = compile(vec![0x41, 0x00, 0x28, 0x03, 0x00, 0x1A, 0x0B]).unwrap_err();
+        assert!(err.to_string().contains("I32Load"), "{}", err);
+
+        // i64.store8 only allows byte alignment
+        assert!(compile(vec![0x41, 0x00, 0x42, 0x00, 0x3C, 0x00, 0x00, 0x0B]).is_ok());
+        assert!(compile(vec![0x41, 0x00, 0x42, 0x00, 0x3C, 0x01, 0x00, 0x0B]).is_err());
+
+        // i64.atomic.rmw16.add_u has an alignment of 2^1
+        assert!(compile(vec![
+            0x41, 0x00, 0x42, 0x00, 0xFE, 0x23, 0x01, 0x00, 0x1A, 0x0B
+        ])
+        .is_ok());
+        let err = compile(vec![
+            0x41, 0x00, 0x42, 0x00, 0xFE, 0x23, 0x02, 0x00, 0x1A, 0x0B,
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("I64AtomicRmw16AddU"), "{}", err);
+
+        // An atomic access can't be any less aligned either, so i32.atomic.load needs 2^2
+        assert!(compile(vec![0x41, 0x00, 0xFE, 0x10, 0x02, 0x00, 0x1A, 0x0B]).is_ok());
+        let err = compile(vec![0x41, 0x00, 0xFE, 0x10, 0x01, 0x00, 0x1A, 0x0B]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Alignment 2^1 of I32AtomicLoad isn't its natural alignment 2^2"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_if_without_else_validation() {
+        // Type 0 takes an i32 and gives it back, and type 1 turns an i32 into an i64
+        let types = [
+            FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            FuncType::new(vec![ValueType::I32], vec![ValueType::I64]),
+        ];
+        let compile = |body: Vec<u8>| CompiledFunction::new(&Expr::new(body), &types);
+
+        // (if (local.get 0) (then nop)) and the same with an i32 parameter passed through
+        assert!(compile(vec![0x20, 0x00, 0x04, 0x40, 0x01, 0x0B, 0x0B]).is_ok());
+        assert!(compile(vec![0x41, 0x01, 0x20, 0x00, 0x04, 0x00, 0x0B, 0x0B]).is_ok());
+
+        // A result can't come from nowhere, nor can a parameter change its type
+        let err = compile(vec![0x20, 0x00, 0x04, 0x7F, 0x41, 0x01, 0x0B, 0x0B]).unwrap_err();
+        assert!(
+            err.to_string().contains("If of type I32 has no else"),
+            "{}",
+            err
+        );
+        let err = compile(vec![0x41, 0x01, 0x20, 0x00, 0x04, 0x01, 0x0B, 0x0B]).unwrap_err();
+        assert!(err.to_string().contains("[I32] into [I64]"), "{}", err);
+
+        // Both are fine with an else
+        assert!(compile(vec![
+            0x20, 0x00, 0x04, 0x7F, 0x41, 0x01, 0x05, 0x41, 0x02, 0x0B, 0x0B
+        ])
+        .is_ok());
+        assert!(compile(vec![
+            0x41, 0x01, 0x20, 0x00, 0x04, 0x01, 0xAC, 0x05, 0xAD, 0x0B, 0x0B
+        ])
+        .is_ok());
+    }
+}
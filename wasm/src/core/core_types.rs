@@ -1,11 +1,17 @@
+use crate::core::{MaybeSendSync, Shared};
 use crate::parser::InstructionSource;
 use anyhow::{anyhow, Result};
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use num_enum::TryFromPrimitive;
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::ops::Range;
 
 #[derive(Debug, Clone, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ValueType {
+    ExternRef = 0x6F,
+    FuncRef = 0x70,
     F64 = 0x7C,
     F32 = 0x7D,
     I64 = 0x7E,
@@ -14,7 +20,6 @@ pub enum ValueType {
 
 impl ValueType {
     pub fn from_byte(byte: u8) -> Result<Self> {
-        // actual values are offset by 0x7C [cb]
         match byte.try_into() {
             Ok(v) => Ok(v),
             _ => Err(anyhow!("Invalid value type byte 0x{:02x}", byte)),
@@ -22,21 +27,60 @@ impl ValueType {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, TryFromPrimitive, IntoPrimitive)]
-#[repr(u8)]
+/// The type of a block. The empty type and single value types are encoded as one byte,
+/// and blocks which take parameters or produce several values refer to a function type.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum BlockType {
     None,
-    F64 = 0x7C,
-    F32 = 0x7D,
-    I64 = 0x7E,
-    I32 = 0x7F,
+    ExternRef,
+    FuncRef,
+    F64,
+    F32,
+    I64,
+    I32,
+    TypeIdx(u32),
 }
 
 impl BlockType {
-    pub fn from_byte(byte: u8) -> Result<Self> {
-        match byte.try_into() {
-            Ok(v) => Ok(v),
-            _ => Err(anyhow!("Invalid block type byte 0x{:02x}", byte)),
+    /// Block types are encoded as a signed 33 bit LEB, so that the single byte forms are
+    /// negative and can't be confused with a type index.
+    pub fn from_s33(value: i64) -> Result<Self> {
+        match value {
+            -0x40 => Ok(BlockType::None),
+            -0x11 => Ok(BlockType::ExternRef),
+            -0x10 => Ok(BlockType::FuncRef),
+            -0x04 => Ok(BlockType::F64),
+            -0x03 => Ok(BlockType::F32),
+            -0x02 => Ok(BlockType::I64),
+            -0x01 => Ok(BlockType::I32),
+            0..=0xFFFF_FFFF => Ok(BlockType::TypeIdx(value as u32)),
+            _ => Err(anyhow!("Invalid block type {}", value)),
+        }
+    }
+
+    pub fn to_s33(self) -> i64 {
+        match self {
+            BlockType::None => -0x40,
+            BlockType::ExternRef => -0x11,
+            BlockType::FuncRef => -0x10,
+            BlockType::F64 => -0x04,
+            BlockType::F32 => -0x03,
+            BlockType::I64 => -0x02,
+            BlockType::I32 => -0x01,
+            BlockType::TypeIdx(idx) => i64::from(idx),
+        }
+    }
+
+    /// The parameters and results of a block of this type. Only type indices have
+    /// parameters, and they need the module's types to resolve.
+    pub fn func_type(self, types: &[FuncType]) -> Result<FuncType> {
+        match self {
+            BlockType::None => Ok(FuncType::new(vec![], vec![])),
+            BlockType::TypeIdx(idx) => types
+                .get(idx as usize)
+                .cloned()
+                .ok_or_else(|| anyhow!("Block type refers to type {} which does not exist", idx)),
+            value_type => Ok(FuncType::new(vec![], vec![value_type.try_into()?])),
         }
     }
 }
@@ -44,6 +88,8 @@ impl BlockType {
 impl From<ValueType> for BlockType {
     fn from(val: ValueType) -> BlockType {
         match val {
+            ValueType::ExternRef => BlockType::ExternRef,
+            ValueType::FuncRef => BlockType::FuncRef,
             ValueType::F64 => BlockType::F64,
             ValueType::F32 => BlockType::F32,
             ValueType::I64 => BlockType::I64,
@@ -57,6 +103,8 @@ impl TryFrom<BlockType> for ValueType {
 
     fn try_from(block_type: BlockType) -> Result<ValueType> {
         match block_type {
+            BlockType::ExternRef => Ok(ValueType::ExternRef),
+            BlockType::FuncRef => Ok(ValueType::FuncRef),
             BlockType::F64 => Ok(ValueType::F64),
             BlockType::F32 => Ok(ValueType::F32),
             BlockType::I64 => Ok(ValueType::I64),
@@ -71,6 +119,7 @@ impl TryFrom<BlockType> for ValueType {
 }
 
 #[derive(Debug, Clone, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum MutableType {
     Const,
@@ -87,27 +136,54 @@ impl MutableType {
 }
 
 #[derive(Debug, Clone, PartialEq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum ElemType {
     FuncRef = 0x70,
+    ExternRef = 0x6F,
 }
 
 impl ElemType {
     pub fn from_byte(byte: u8) -> Result<Self> {
         match byte.try_into() {
             Ok(s) => Ok(s),
-            _ => Err(anyhow!("Unknown funcref type")),
+            _ => Err(anyhow!("Unknown reference type 0x{:02x}", byte)),
+        }
+    }
+
+    /// The type of the values the table's entries are read and written as
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            ElemType::FuncRef => ValueType::FuncRef,
+            ElemType::ExternRef => ValueType::ExternRef,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Limits {
     Unbounded(usize),
     Bounded(usize, usize),
 }
 
-#[derive(Debug, Clone)]
+impl Limits {
+    pub fn minimum(&self) -> usize {
+        match self {
+            Limits::Unbounded(minimum) | Limits::Bounded(minimum, _) => *minimum,
+        }
+    }
+
+    pub fn maximum(&self) -> Option<usize> {
+        match self {
+            Limits::Unbounded(_) => None,
+            Limits::Bounded(_, maximum) => Some(*maximum),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TableType {
     et: ElemType,
     lim: Limits,
@@ -127,22 +203,40 @@ impl TableType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemType {
     limits: Limits,
+    #[cfg_attr(feature = "serde", serde(default))]
+    shared: bool,
 }
 
 impl MemType {
     pub fn new(limits: Limits) -> Self {
-        Self { limits }
+        Self {
+            limits,
+            shared: false,
+        }
+    }
+
+    pub fn new_shared(limits: Limits) -> Self {
+        Self {
+            limits,
+            shared: true,
+        }
     }
 
     pub fn limits(&self) -> &Limits {
         &self.limits
     }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GlobalType {
     t: ValueType,
     m: MutableType,
@@ -166,6 +260,7 @@ impl GlobalType {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FuncType {
     arg_types: Vec<ValueType>,
     ret_types: Vec<ValueType>,
@@ -189,6 +284,7 @@ impl FuncType {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ImportDesc {
     TypeIdx(usize),
     TableType(TableType),
@@ -197,6 +293,7 @@ pub enum ImportDesc {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Import {
     mod_name: String,
     name: String,
@@ -225,21 +322,61 @@ impl Import {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Bytes which expressions can share without copying them, such as a code section, or a
+/// whole module file mapped into memory
+pub trait SharedBytes: MaybeSendSync {
+    fn bytes(&self) -> &[u8];
+}
+
+impl<T: AsRef<[u8]> + MaybeSendSync> SharedBytes for T {
+    fn bytes(&self) -> &[u8] {
+        self.as_ref()
+    }
+}
+
+impl fmt::Debug for dyn SharedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes>", self.bytes().len())
+    }
+}
+
+#[derive(Clone)]
 pub struct Expr {
-    // So, a basic expr is just the bytes that make up the expression
-    instr: Vec<u8>,
+    // So, a basic expr is just the bytes that make up the expression. They can be part of
+    // a bigger buffer, such as a code section which was read without decoding its bodies.
+    instr: Shared<dyn SharedBytes>,
+    range: Range<usize>,
 }
 
 impl Expr {
     pub fn new(instr: Vec<u8>) -> Self {
-        Self { instr }
+        let range = 0..instr.len();
+        Self {
+            instr: Shared::new(instr),
+            range,
+        }
+    }
+
+    /// An expression made of some of the bytes of a shared buffer
+    pub fn from_shared(instr: Shared<dyn SharedBytes>, range: Range<usize>) -> Result<Self> {
+        if range.start > range.end || range.end > instr.bytes().len() {
+            return Err(anyhow!("Expression is outside of its buffer"));
+        }
+        Ok(Self { instr, range })
     }
 }
 
 impl InstructionSource for Expr {
     fn get_instruction_bytes(&self) -> &[u8] {
-        &self.instr
+        &self.instr.bytes()[self.range.clone()]
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Expr")
+            .field("instr", &self.get_instruction_bytes())
+            .finish()
     }
 }
 
@@ -264,6 +401,7 @@ impl GlobalDef {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ExportDesc {
     Func(usize),
     Table(usize),
@@ -272,6 +410,7 @@ pub enum ExportDesc {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Export {
     pub nm: String,
     pub d: ExportDesc,
@@ -283,28 +422,62 @@ impl Export {
     }
 }
 
+#[derive(Debug)]
+pub enum ElementMode {
+    Active(usize, Expr),
+    Passive,
+    /// Only declares the functions that ref.func may refer to, so the segment is never
+    /// available to table.init
+    Declarative,
+}
+
+/// The entries of an element segment
+#[derive(Debug)]
+pub enum ElementItems {
+    /// Functions given by their indices, as the MVP encoding gives them
+    Functions(Vec<usize>),
+    /// A constant expression for each entry, each of which gives a reference of the type.
+    /// Unlike function indices these can give null references.
+    Expressions(ElemType, Vec<Expr>),
+}
+
 #[derive(Debug)]
 pub struct Element {
-    x: usize,
-    e: Expr,
-    y: Vec<usize>,
+    m: ElementMode,
+    items: ElementItems,
 }
 
 impl Element {
     pub fn new(x: usize, e: Expr, y: Vec<usize>) -> Self {
-        Self { x, e, y }
+        Self::with_items(ElementMode::Active(x, e), ElementItems::Functions(y))
     }
 
-    pub fn table_idx(&self) -> usize {
-        self.x
+    pub fn new_passive(y: Vec<usize>) -> Self {
+        Self::with_items(ElementMode::Passive, ElementItems::Functions(y))
     }
 
-    pub fn func_indices(&self) -> &[usize] {
-        &self.y
+    pub fn new_declarative(y: Vec<usize>) -> Self {
+        Self::with_items(ElementMode::Declarative, ElementItems::Functions(y))
     }
 
-    pub fn expr(&self) -> &Expr {
-        &self.e
+    pub fn with_items(m: ElementMode, items: ElementItems) -> Self {
+        Self { m, items }
+    }
+
+    pub fn mode(&self) -> &ElementMode {
+        &self.m
+    }
+
+    pub fn items(&self) -> &ElementItems {
+        &self.items
+    }
+
+    /// The type of reference the segment holds
+    pub fn elem_type(&self) -> ElemType {
+        match &self.items {
+            ElementItems::Functions(_) => ElemType::FuncRef,
+            ElementItems::Expressions(elem_type, _) => elem_type.clone(),
+        }
     }
 }
 
@@ -326,49 +499,106 @@ impl Locals {
     pub fn value_type(&self) -> ValueType {
         self.t.clone()
     }
+
+    /// The number of locals a function's declarations add up to, which the spec limits to
+    /// what fits in a u32
+    pub fn total_count(locals: &[Locals]) -> Result<u32> {
+        locals
+            .iter()
+            .try_fold(0_u32, |total, locals| total.checked_add(locals.count()))
+            .ok_or_else(|| anyhow!("Function declares more than {} locals", u32::MAX))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Func {
     locals: Vec<Locals>,
+    local_count: u32,
     e: Expr,
+    lazy: bool,
 }
 
 impl Func {
+    /// Panics if the locals add up to more than a u32 can hold. Use `try_new` for locals
+    /// which haven't been checked.
     pub fn new(locals: Vec<Locals>, e: Expr) -> Self {
-        Self { locals, e }
+        Self::try_new(locals, e).expect("Too many locals")
+    }
+
+    pub fn try_new(locals: Vec<Locals>, e: Expr) -> Result<Self> {
+        let local_count = Locals::total_count(&locals)?;
+        Ok(Self {
+            locals,
+            local_count,
+            e,
+            lazy: false,
+        })
+    }
+
+    /// A function whose instructions haven't been checked yet. Its body is compiled, and
+    /// any problems with it reported, when it is first called rather than when it is
+    /// instantiated.
+    pub fn try_new_lazy(locals: Vec<Locals>, e: Expr) -> Result<Self> {
+        Ok(Self {
+            lazy: true,
+            ..Self::try_new(locals, e)?
+        })
+    }
+
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
     }
 
     pub fn locals(&self) -> &Vec<Locals> {
         &self.locals
     }
 
+    /// The number of locals, not counting the parameters
+    pub fn local_count(&self) -> u32 {
+        self.local_count
+    }
+
     pub fn expr(&self) -> &Expr {
         &self.e
     }
 }
 
+#[derive(Debug)]
+pub enum DataMode {
+    Active(usize, Expr),
+    Passive,
+}
+
 #[derive(Debug)]
 pub struct Data {
-    x: usize,
-    e: Expr,
+    m: DataMode,
     b: Vec<u8>,
 }
 
 impl Data {
     pub fn new(x: usize, e: Expr, b: Vec<u8>) -> Self {
-        Self { x, e, b }
+        Self {
+            m: DataMode::Active(x, e),
+            b,
+        }
     }
 
-    pub fn mem_idx(&self) -> usize {
-        self.x
+    pub fn new_passive(b: Vec<u8>) -> Self {
+        Self {
+            m: DataMode::Passive,
+            b,
+        }
     }
 
-    pub fn expr(&self) -> &Expr {
-        &self.e
+    pub fn mode(&self) -> &DataMode {
+        &self.m
     }
 
     pub fn bytes(&self) -> &[u8] {
         &self.b
     }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.b
+    }
 }
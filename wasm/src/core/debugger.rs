@@ -0,0 +1,372 @@
+//! Stopping a call at chosen instructions to look at it. Breakpoints are set at byte
+//! offsets within function bodies, counted from the first byte after the locals, which is
+//! how a disassembly of the body numbers its instructions. While the session is stopped,
+//! its locals and operand stack can be read from it, and memories, tables and globals from
+//! the instance.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use crate::core::{
+    callable::CallEntry,
+    compiled::CompiledImmediates,
+    executor::execute_core::{CallStack, RunStatus},
+    instance::read_results,
+    FunctionStore, Instance, Stack, Value,
+};
+
+/// An instruction in a function defined by the instance
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CodeLocation {
+    /// `None` for function bodies which aren't part of any instance
+    pub func_idx: Option<usize>,
+    pub byte_offset: usize,
+}
+
+/// Why a debug session stopped. It always stops before executing the instruction at the
+/// location.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugStop {
+    Breakpoint(CodeLocation),
+    Step(CodeLocation),
+    /// The call has finished, with these results
+    Complete(Vec<Value>),
+}
+
+/// A call into an instance which stops at breakpoints, and can be stepped an instruction
+/// at a time. Nothing is executed until the session is first resumed or stepped, so
+/// breakpoints can be set before then.
+pub struct DebugSession<'a> {
+    instance: &'a Instance,
+    stack: Stack,
+    /// The wasm functions being executed, or `None` once the call has finished or trapped
+    call_stack: Option<CallStack>,
+    result_count: usize,
+    results: Option<Vec<Value>>,
+    /// Whether the session has stopped anywhere yet
+    started: bool,
+}
+
+impl Instance {
+    /// Start calling the exported function `name` under a debugger, without executing any
+    /// of it yet
+    pub fn debug(&self, name: &str, args: &[Value]) -> Result<DebugSession<'_>> {
+        let mut stack = self.make_stack();
+        let callable = self.push_args(name, args, &mut stack)?;
+        let callable = callable.borrow();
+        let result_count = callable.func_type().return_types().len();
+
+        let (call_stack, results) = match callable.enter(&mut stack)? {
+            CallEntry::Body(body, func_idx) => (Some(CallStack::new(body, func_idx)), None),
+            CallEntry::Complete => (None, Some(read_results(&stack, result_count))),
+            CallEntry::Pending(_) => return Err(pending_while_debugging()),
+        };
+
+        Ok(DebugSession {
+            instance: self,
+            stack,
+            call_stack,
+            result_count,
+            results,
+            started: false,
+        })
+    }
+}
+
+fn pending_while_debugging() -> anyhow::Error {
+    anyhow!("Host functions cannot wait for their results in a debug session")
+}
+
+impl<'a> DebugSession<'a> {
+    pub fn instance(&self) -> &'a Instance {
+        self.instance
+    }
+
+    /// Stop before executing the instruction at the byte offset in the function with the
+    /// index. The function must be defined in wasm by the session's instance, and the
+    /// offset must be the start of an instruction other than an `else` or `end`, since
+    /// those aren't executed on their own.
+    pub fn set_breakpoint(&mut self, func_idx: usize, byte_offset: usize) -> Result<()> {
+        let callable = self.instance.function_module().get_function(func_idx)?;
+        let callable = callable.borrow();
+        let body = match (callable.compiled_body()?, callable.provenance()) {
+            (Some(body), Some(provenance))
+                if provenance.instance_id() == self.instance.id()
+                    && provenance.func_idx() == func_idx =>
+            {
+                body
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Function {} is not defined in wasm by this instance",
+                    func_idx
+                ))
+            }
+        };
+
+        let immediates = body
+            .position_at(byte_offset)
+            .and_then(|position| body.instructions().get(position))
+            .map(|instruction| instruction.immediates());
+        match immediates {
+            None | Some(CompiledImmediates::Else { .. }) | Some(CompiledImmediates::End { .. }) => {
+                Err(anyhow!(
+                    "Offset {} of function {} isn't the start of an instruction to stop at",
+                    byte_offset,
+                    func_idx
+                ))
+            }
+            Some(_) => {
+                self.stack.breakpoints_mut().insert((func_idx, byte_offset));
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns whether there was a breakpoint to remove
+    pub fn clear_breakpoint(&mut self, func_idx: usize, byte_offset: usize) -> bool {
+        self.stack
+            .breakpoints_mut()
+            .remove(&(func_idx, byte_offset))
+    }
+
+    /// The instruction which will be executed next, or `None` once the call has finished
+    pub fn location(&self) -> Option<CodeLocation> {
+        self.call_stack.as_ref().map(|call_stack| {
+            let (func_idx, byte_offset) = call_stack.location();
+            CodeLocation {
+                func_idx,
+                byte_offset,
+            }
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.results.is_some()
+    }
+
+    /// The number of wasm functions being executed, which is zero once the call has
+    /// finished
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.as_ref().map_or(0, CallStack::depth)
+    }
+
+    /// The parameters and locals of the innermost function being executed
+    pub fn locals(&self) -> Vec<Value> {
+        match self.call_stack {
+            Some(_) => self
+                .stack
+                .local()
+                .iter()
+                .copied()
+                .map(Value::from)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The values the innermost function is working on, with the top of the stack last
+    pub fn operand_stack(&self) -> Vec<Value> {
+        match self.call_stack {
+            Some(_) => self
+                .stack
+                .working_top(self.stack.working_count())
+                .iter()
+                .copied()
+                .map(Value::from)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Execute until a breakpoint is reached or the call finishes. The instruction the
+    /// session is stopped at is executed first, even if it has a breakpoint, so that
+    /// resuming from a breakpoint doesn't stop at it again straight away.
+    pub fn resume(&mut self) -> Result<DebugStop> {
+        if self.started {
+            if let Some(stop) = self.advance_one()? {
+                return Ok(stop);
+            }
+        }
+        self.started = true;
+
+        Ok(match self.advance(None)? {
+            Some(stop) => stop,
+            None => DebugStop::Breakpoint(self.location().unwrap()),
+        })
+    }
+
+    /// Execute the next instruction, following calls into the functions they call
+    pub fn step(&mut self) -> Result<DebugStop> {
+        self.started = true;
+        Ok(match self.advance_one()? {
+            Some(stop) => stop,
+            None => DebugStop::Step(self.location().unwrap()),
+        })
+    }
+
+    // Execute one instruction, whether or not it has a breakpoint
+    fn advance_one(&mut self) -> Result<Option<DebugStop>> {
+        let breakpoints = std::mem::take(self.stack.breakpoints_mut());
+        let stepped = self.advance(Some(1));
+        *self.stack.breakpoints_mut() = breakpoints;
+        stepped
+    }
+
+    // Returns `None` if execution paused part way through the call
+    fn advance(&mut self, steps: Option<u64>) -> Result<Option<DebugStop>> {
+        let call_stack = match &mut self.call_stack {
+            Some(call_stack) => call_stack,
+            None => {
+                return match &self.results {
+                    Some(results) => Ok(Some(DebugStop::Complete(results.clone()))),
+                    None => Err(anyhow!("The call has already trapped")),
+                }
+            }
+        };
+
+        self.stack.set_step_limit(steps);
+        let status = call_stack.run(
+            &mut self.stack,
+            self.instance.function_module(),
+            &mut *self.instance.data_module().borrow_mut(),
+        );
+        self.stack.set_step_limit(None);
+
+        // A trap leaves the call stack part way through an instruction, so it can't be
+        // carried on with or looked at any more
+        match status {
+            Err(e) => {
+                self.call_stack = None;
+                Err(e)
+            }
+            Ok(RunStatus::Paused) => Ok(None),
+            Ok(RunStatus::Complete) => {
+                self.stack.pop_typed_frame()?;
+                let results = read_results(&self.stack, self.result_count);
+                self.results = Some(results.clone());
+                self.call_stack = None;
+                Ok(Some(DebugStop::Complete(results)))
+            }
+            Ok(RunStatus::Pending(_)) => {
+                self.call_stack = None;
+                Err(pending_while_debugging())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for DebugSession<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DebugSession")
+            .field("location", &self.location())
+            .field("results", &self.results)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        resolve_raw_module, test_module::TestModule, EmptyResolver, ExportDesc, FuncType, Limits,
+        MemType, ValueType,
+    };
+
+    fn make_instance() -> Instance {
+        let func_type = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+        let module = TestModule::new()
+            // Adds one to what the second function returns
+            .func(
+                func_type.clone(),
+                &[0x20, 0x00, 0x10, 0x01, 0x41, 0x01, 0x6A, 0x0B],
+            )
+            // Stores its parameter at the start of the memory, and returns double it
+            .func(
+                func_type.clone(),
+                &[
+                    0x41, 0x00, 0x20, 0x00, 0x36, 0x02, 0x00, 0x20, 0x00, 0x20, 0x00, 0x6A, 0x0B,
+                ],
+            )
+            .func(func_type, &[0x41, 0x01, 0x41, 0x00, 0x6D, 0x0B])
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .export("outer", ExportDesc::Func(0))
+            .export("divide_by_zero", ExportDesc::Func(2))
+            .build();
+
+        Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap())
+    }
+
+    fn at(func_idx: usize, byte_offset: usize) -> CodeLocation {
+        CodeLocation {
+            func_idx: Some(func_idx),
+            byte_offset,
+        }
+    }
+
+    #[test]
+    fn test_breakpoints_and_steps() {
+        let instance = make_instance();
+        let mut session = instance.debug("outer", &[Value::I32(5)]).unwrap();
+        assert_eq!(session.location(), Some(at(0, 0)));
+
+        session.set_breakpoint(1, 7).unwrap();
+        session.set_breakpoint(0, 4).unwrap();
+        // The middle of an instruction, the end of the body, and a function which isn't there
+        assert!(session.set_breakpoint(1, 5).is_err());
+        assert!(session.set_breakpoint(0, 7).is_err());
+        assert!(session.set_breakpoint(3, 0).is_err());
+
+        assert_eq!(session.resume().unwrap(), DebugStop::Breakpoint(at(1, 7)));
+        assert_eq!(session.call_depth(), 2);
+        assert_eq!(session.locals(), vec![Value::I32(5)]);
+        assert!(session.operand_stack().is_empty());
+        assert_eq!(instance.memory(0).unwrap().get::<u32>(0).unwrap(), 5);
+
+        assert_eq!(session.step().unwrap(), DebugStop::Step(at(1, 9)));
+        assert_eq!(session.operand_stack(), vec![Value::I32(5)]);
+        session.step().unwrap();
+
+        // Stepping off the end of a function goes back to its caller
+        assert_eq!(session.step().unwrap(), DebugStop::Step(at(0, 4)));
+        assert_eq!(session.call_depth(), 1);
+        assert_eq!(session.operand_stack(), vec![Value::I32(10)]);
+
+        // The breakpoint which was stepped onto isn't stopped at again
+        assert!(session.clear_breakpoint(1, 7));
+        assert!(!session.clear_breakpoint(1, 7));
+        assert_eq!(
+            session.resume().unwrap(),
+            DebugStop::Complete(vec![Value::I32(11)])
+        );
+        assert!(session.is_complete());
+        assert_eq!(session.location(), None);
+        assert_eq!(
+            session.step().unwrap(),
+            DebugStop::Complete(vec![Value::I32(11)])
+        );
+    }
+
+    #[test]
+    fn test_breakpoint_on_first_instruction() {
+        let instance = make_instance();
+        let mut session = instance.debug("outer", &[Value::I32(1)]).unwrap();
+        session.set_breakpoint(0, 0).unwrap();
+
+        assert_eq!(session.resume().unwrap(), DebugStop::Breakpoint(at(0, 0)));
+        assert_eq!(
+            session.resume().unwrap(),
+            DebugStop::Complete(vec![Value::I32(3)])
+        );
+    }
+
+    #[test]
+    fn test_trap_ends_session() {
+        let instance = make_instance();
+        let mut session = instance.debug("divide_by_zero", &[Value::I32(0)]).unwrap();
+
+        assert!(session.resume().is_err());
+        assert_eq!(session.location(), None);
+        assert!(session.step().is_err());
+    }
+}
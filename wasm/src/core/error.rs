@@ -0,0 +1,142 @@
+use crate::core::SectionType;
+use std::fmt;
+
+/// The reasons the spec gives for execution to trap.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TrapKind {
+    Unreachable,
+    MemoryOutOfBounds,
+    IntegerDivideByZero,
+    IntegerOverflow,
+    InvalidConversionToInteger,
+    IndirectCallTypeMismatch,
+    UndefinedElement,
+    UninitializedElement,
+    CallStackExhausted,
+    TableOutOfBounds,
+    OutOfFuel,
+    Interrupted,
+    UnalignedAtomic,
+    ExpectedSharedMemory,
+    /// Not one the spec gives, because validation rules it out. A function body which
+    /// wasn't validated tried to use more values than its block has on the stack.
+    StackUnderflow,
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            TrapKind::Unreachable => "unreachable",
+            TrapKind::MemoryOutOfBounds => "out of bounds memory access",
+            TrapKind::IntegerDivideByZero => "integer divide by zero",
+            TrapKind::IntegerOverflow => "integer overflow",
+            TrapKind::InvalidConversionToInteger => "invalid conversion to integer",
+            TrapKind::IndirectCallTypeMismatch => "indirect call type mismatch",
+            TrapKind::UndefinedElement => "undefined element",
+            TrapKind::UninitializedElement => "uninitialized element",
+            TrapKind::CallStackExhausted => "call stack exhausted",
+            TrapKind::TableOutOfBounds => "out of bounds table access",
+            TrapKind::OutOfFuel => "out of fuel",
+            TrapKind::Interrupted => "interrupted",
+            TrapKind::UnalignedAtomic => "unaligned atomic",
+            TrapKind::ExpectedSharedMemory => "expected shared memory",
+            TrapKind::StackUnderflow => "stack underflow",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Where reading a malformed module failed
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParseError {
+    /// The section which was being read, or `None` for the module header and section headers
+    pub section: Option<SectionType>,
+    /// How many bytes of the module had been read when the failure was found
+    pub offset: u64,
+    /// What was wrong, with the context the reader added on the way out
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at offset {:#x}", self.message, self.offset)?;
+        if let Some(section) = self.section {
+            write!(f, " in the {:?}", section)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The kinds of failure an embedder may want to handle differently. These are carried
+/// inside the `anyhow::Error`s returned from the crate, so use `downcast_ref::<WasmError>()`
+/// to find out which one occurred.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", content = "detail"))]
+pub enum WasmError {
+    /// The binary module is malformed
+    ParseError(ParseError),
+    /// The module is well formed, but is not a valid module
+    ValidationError(String),
+    /// Execution trapped
+    Trap(TrapKind),
+    /// An import could not be resolved
+    LinkError(String),
+    /// Execution ran out of a resource, such as stack space
+    ExhaustionError(String),
+    /// A host function failed for reasons that aren't the guest's fault. This is attached
+    /// to the host's own error, which follows it in the error chain.
+    HostError,
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmError::ParseError(error) => write!(f, "{}", error),
+            WasmError::ValidationError(message)
+            | WasmError::LinkError(message)
+            | WasmError::ExhaustionError(message) => write!(f, "{}", message),
+            WasmError::Trap(kind) => write!(f, "Trap: {}", kind),
+            WasmError::HostError => write!(f, "Host function failed"),
+        }
+    }
+}
+
+impl std::error::Error for WasmError {}
+
+impl WasmError {
+    /// Attach a classification to an error which doesn't already have one. Errors which
+    /// are already a `WasmError` keep their original kind, so a trap in a start function
+    /// remains a trap however far it propagates.
+    pub fn classify(
+        error: anyhow::Error,
+        make_error: impl FnOnce(String) -> WasmError,
+    ) -> anyhow::Error {
+        if error.downcast_ref::<WasmError>().is_some() {
+            error
+        } else {
+            make_error(format!("{:#}", error)).into()
+        }
+    }
+
+    pub fn trap_kind(error: &anyhow::Error) -> Option<TrapKind> {
+        match error.downcast_ref::<WasmError>() {
+            Some(WasmError::Trap(kind)) => Some(*kind),
+            _ => None,
+        }
+    }
+
+    pub fn is_host_error(error: &anyhow::Error) -> bool {
+        matches!(
+            error.downcast_ref::<WasmError>(),
+            Some(WasmError::HostError)
+        )
+    }
+}
+
+pub fn trap(kind: TrapKind) -> anyhow::Error {
+    WasmError::Trap(kind).into()
+}
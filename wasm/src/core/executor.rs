@@ -1,11 +1,16 @@
+mod active_memory;
+pub mod atomic_access;
 pub mod execute_core;
 pub mod memory_access;
+pub mod read_only_store;
 pub mod stack_ops;
 pub mod store_access;
+pub mod table_access;
 
 pub use execute_core::{
-    evaluate_constant_expression, execute_constant_expression, execute_expression,
+    evaluate_constant_expression, execute_compiled, execute_constant_expression, execute_expression,
 };
+pub use read_only_store::ReadOnlyDataStore;
 pub use store_access::{ConstantDataStore, DataStore, FunctionStore};
 
 #[cfg(test)]
@@ -13,7 +18,10 @@ mod test {
     #[macro_use]
     mod instruction_test_helpers;
     mod control_instruction_tests;
+    mod differential;
     mod instruction_generator;
     mod instruction_tests;
+    mod opcode_coverage;
+    mod recursive_executor;
     mod test_store;
 }
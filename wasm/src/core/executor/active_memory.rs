@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use crate::core::{stack_entry::StackEntry, Handle, Lock, LockRefMut, Memory};
+use anyhow::Result;
+
+use super::memory_access::LEByteConvert;
+use super::store_access::{ConstantDataStore, DataStore};
+
+/// Finds memory 0 in the store once, when a function starts running, so that loads and
+/// stores go straight to it instead of looking up the memory and borrowing it every time.
+/// The memory stays borrowed while the function runs, and is only let go for the
+/// operations which need the rest of the store as well, such as `memory.init`. Calls leave
+/// the function, which lets the memory go before host functions can get at it.
+pub(crate) struct ActiveMemoryStore<'a, Inner: DataStore> {
+    inner: &'a mut Inner,
+    cell: Option<&'a Lock<Memory>>,
+    memory: Option<LockRefMut<'a, Memory>>,
+}
+
+impl<'a, Inner: DataStore> ActiveMemoryStore<'a, Inner> {
+    pub(crate) fn new(inner: &'a mut Inner, cell: Option<&'a Handle<Memory>>) -> Self {
+        let cell = cell.map(|cell| cell.as_ref());
+        Self {
+            inner,
+            cell,
+            memory: cell.map(Lock::borrow_mut),
+        }
+    }
+
+    fn active(&self, mem_idx: usize) -> Option<&Memory> {
+        match mem_idx {
+            0 => self.memory.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn active_mut(&mut self, mem_idx: usize) -> Option<&mut Memory> {
+        match mem_idx {
+            0 => self.memory.as_deref_mut(),
+            _ => None,
+        }
+    }
+
+    // Let go of the memory while the inner store does something with it
+    fn release_for<R>(&mut self, operation: impl FnOnce(&mut Inner) -> R) -> R {
+        self.memory = None;
+        let result = operation(self.inner);
+        self.memory = self.cell.map(Lock::borrow_mut);
+        result
+    }
+}
+
+impl<'a, Inner: DataStore> ConstantDataStore for ActiveMemoryStore<'a, Inner> {
+    fn get_global_value(&self, idx: usize) -> Result<StackEntry> {
+        self.inner.get_global_value(idx)
+    }
+
+    fn is_constant_global(&self, idx: usize) -> bool {
+        self.inner.is_constant_global(idx)
+    }
+}
+
+impl<'a, Inner: DataStore> DataStore for ActiveMemoryStore<'a, Inner> {
+    fn set_global_value(&mut self, idx: usize, value: StackEntry) -> Result<()> {
+        self.inner.set_global_value(idx, value)
+    }
+
+    fn cached_memory(&self) -> Option<&Handle<Memory>> {
+        None
+    }
+
+    fn read_data(&self, mem_idx: usize, offset: usize, data: &mut [u8]) -> Result<()> {
+        match self.active(mem_idx) {
+            Some(memory) => memory.get_data(offset, data),
+            None => self.inner.read_data(mem_idx, offset, data),
+        }
+    }
+
+    fn write_data(&mut self, mem_idx: usize, offset: usize, data: &[u8]) -> Result<()> {
+        match self.active_mut(mem_idx) {
+            Some(memory) => memory.set_data(offset, data),
+            None => self.inner.write_data(mem_idx, offset, data),
+        }
+    }
+
+    fn load_data<T: LEByteConvert>(&self, mem_idx: usize, offset: usize) -> Result<T> {
+        match self.active(mem_idx) {
+            Some(memory) => memory.load(offset),
+            None => self.inner.load_data(mem_idx, offset),
+        }
+    }
+
+    fn store_data<T: LEByteConvert>(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: T,
+    ) -> Result<()> {
+        match self.active_mut(mem_idx) {
+            Some(memory) => memory.store(offset, value),
+            None => self.inner.store_data(mem_idx, offset, value),
+        }
+    }
+
+    fn get_memory_size(&self, mem_idx: usize) -> Result<usize> {
+        match self.active(mem_idx) {
+            Some(memory) => Ok(memory.current_size()),
+            None => self.inner.get_memory_size(mem_idx),
+        }
+    }
+
+    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<bool> {
+        match self.active_mut(mem_idx) {
+            Some(memory) => Ok(memory.grow_by(grow_by).is_ok()),
+            None => self.inner.grow_memory_by(mem_idx, grow_by),
+        }
+    }
+
+    fn copy_memory(
+        &mut self,
+        dst_mem_idx: usize,
+        dst_offset: usize,
+        src_mem_idx: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        match self.active_mut(dst_mem_idx) {
+            Some(memory) if src_mem_idx == dst_mem_idx => {
+                memory.copy_within(dst_offset, src_offset, length)
+            }
+            _ => self.release_for(|inner| {
+                inner.copy_memory(dst_mem_idx, dst_offset, src_mem_idx, src_offset, length)
+            }),
+        }
+    }
+
+    fn fill_memory(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: u8,
+        length: usize,
+    ) -> Result<()> {
+        match self.active_mut(mem_idx) {
+            Some(memory) => memory.fill(offset, value, length),
+            None => self.inner.fill_memory(mem_idx, offset, value, length),
+        }
+    }
+
+    fn init_memory(
+        &mut self,
+        mem_idx: usize,
+        data_idx: usize,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        self.release_for(|inner| {
+            inner.init_memory(mem_idx, data_idx, dst_offset, src_offset, length)
+        })
+    }
+
+    fn drop_data(&mut self, data_idx: usize) -> Result<()> {
+        self.inner.drop_data(data_idx)
+    }
+
+    fn update_data(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        length: usize,
+        update: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<()> {
+        match self.active_mut(mem_idx) {
+            Some(memory) => memory.update_data(offset, length, update),
+            None => self.inner.update_data(mem_idx, offset, length, update),
+        }
+    }
+
+    fn wait_memory(
+        &self,
+        mem_idx: usize,
+        offset: usize,
+        expected: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<u32> {
+        match self.active(mem_idx) {
+            Some(memory) => memory.wait(offset, expected, timeout),
+            None => self.inner.wait_memory(mem_idx, offset, expected, timeout),
+        }
+    }
+
+    fn notify_memory(&self, mem_idx: usize, offset: usize, count: u32) -> Result<u32> {
+        match self.active(mem_idx) {
+            Some(memory) => memory.notify(offset, count),
+            None => self.inner.notify_memory(mem_idx, offset, count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{handle, module::DataModule};
+
+    #[test]
+    fn test_active_memory_store() {
+        let mut module = DataModule::new();
+        module
+            .memories
+            .define(handle(Memory::new_from_bounds(1, Some(2))));
+        module
+            .memories
+            .define(handle(Memory::new_from_bounds(1, None)));
+        let memories = module.memories.clone();
+
+        {
+            let memory = module.cached_memory().cloned();
+            let mut store = ActiveMemoryStore::new(&mut module, memory.as_ref());
+            store.store_data(0, 8, 0x1234_u32).unwrap();
+            store.store_data(1, 8, 0x5678_u32).unwrap();
+            assert!(store.grow_memory_by(0, 1).unwrap());
+            assert_eq!(store.get_memory_size(0).unwrap(), 2);
+
+            // Memory 0 is held for as long as the store is, but the others aren't
+            assert!(memories[0].try_borrow().is_err());
+            assert!(memories[1].try_borrow().is_ok());
+
+            // Going through the module lets go of memory 0 and takes it back afterwards
+            store.copy_memory(1, 0, 0, 8, 4).unwrap();
+            assert!(store.init_memory(0, 0, 0, 0, 0).is_err());
+            assert!(memories[0].try_borrow().is_err());
+            assert_eq!(store.load_data::<u32>(0, 8).unwrap(), 0x1234);
+        }
+
+        assert_eq!(memories[0].borrow().current_size(), 2);
+        assert_eq!(memories[1].borrow().load::<u32>(0).unwrap(), 0x1234);
+        assert_eq!(memories[1].borrow().load::<u32>(8).unwrap(), 0x5678);
+    }
+}
@@ -0,0 +1,229 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use crate::core::{compiled::CompiledInstruction, trap, Stack, TrapKind};
+use crate::parser::AtomicOpcode;
+use anyhow::Result;
+
+use super::memory_access::pop_effective_address;
+use super::stack_ops::get_stack_top;
+use super::store_access::DataStore;
+
+// The loads, the stores and each kind of read-modify-write come in groups of seven, which
+// always list the value types and access sizes in this order
+const GROUP_SIZE: u32 = 7;
+const GROUP_SHAPES: [(bool, usize); GROUP_SIZE as usize] = [
+    (false, 4),
+    (true, 8),
+    (false, 1),
+    (false, 2),
+    (true, 1),
+    (true, 2),
+    (true, 4),
+];
+
+const FIRST_LOAD: u32 = AtomicOpcode::I32AtomicLoad as u32;
+const FIRST_STORE: u32 = AtomicOpcode::I32AtomicStore as u32;
+const FIRST_RMW: u32 = AtomicOpcode::I32AtomicRmwAdd as u32;
+
+#[derive(Debug, Clone, Copy)]
+enum RmwOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Xchg,
+    Cmpxchg,
+}
+
+const RMW_OPS: [RmwOp; 7] = [
+    RmwOp::Add,
+    RmwOp::Sub,
+    RmwOp::And,
+    RmwOp::Or,
+    RmwOp::Xor,
+    RmwOp::Xchg,
+    RmwOp::Cmpxchg,
+];
+
+// Whether the instruction works on i64 values, and how many bytes of memory it accesses
+fn group_shape(sub_opcode: u32, first_in_group: u32) -> (bool, usize) {
+    GROUP_SHAPES[((sub_opcode - first_in_group) % GROUP_SIZE) as usize]
+}
+
+fn width_mask(width: usize) -> u64 {
+    match width {
+        8 => u64::MAX,
+        _ => (1 << (width * 8)) - 1,
+    }
+}
+
+fn pop_value(stack: &mut Stack, is_64: bool) -> Result<u64> {
+    let entry = get_stack_top(stack, 1)?[0];
+    let value = if is_64 {
+        u64::try_from(entry)?
+    } else {
+        u64::from(u32::try_from(entry)?)
+    };
+    stack.pop();
+    Ok(value)
+}
+
+fn push_value(stack: &mut Stack, is_64: bool, value: u64) {
+    if is_64 {
+        stack.push(value.into());
+    } else {
+        stack.push((value as u32).into());
+    }
+}
+
+// Atomic accesses have to be naturally aligned, whatever the alignment hint says
+fn pop_address(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    width: usize,
+) -> Result<(usize, usize)> {
+    let (mem_idx, address) = pop_effective_address(instruction, stack)?;
+    if address % width != 0 {
+        return Err(trap(TrapKind::UnalignedAtomic).context(format!(
+            "Address {} is not aligned to {} bytes",
+            address, width
+        )));
+    }
+    Ok((mem_idx, address))
+}
+
+fn read_value(store: &impl DataStore, mem_idx: usize, address: usize, width: usize) -> Result<u64> {
+    let mut bytes = [0; 8];
+    store.read_data(mem_idx, address, &mut bytes[..width])?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn atomic_load(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    store: &mut impl DataStore,
+    (is_64, width): (bool, usize),
+) -> Result<()> {
+    let (mem_idx, address) = pop_address(instruction, stack, width)?;
+    let value = read_value(store, mem_idx, address, width)?;
+    push_value(stack, is_64, value);
+    Ok(())
+}
+
+fn atomic_store(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    store: &mut impl DataStore,
+    (is_64, width): (bool, usize),
+) -> Result<()> {
+    let value = pop_value(stack, is_64)?;
+    let (mem_idx, address) = pop_address(instruction, stack, width)?;
+    store.write_data(mem_idx, address, &value.to_le_bytes()[..width])
+}
+
+fn atomic_rmw(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    store: &mut impl DataStore,
+    op: RmwOp,
+    (is_64, width): (bool, usize),
+) -> Result<()> {
+    let operand = pop_value(stack, is_64)?;
+    // Compare and exchange takes the expected value as well as the replacement
+    let expected = match op {
+        RmwOp::Cmpxchg => pop_value(stack, is_64)? & width_mask(width),
+        _ => 0,
+    };
+    let (mem_idx, address) = pop_address(instruction, stack, width)?;
+
+    let mut original = 0;
+    store.update_data(mem_idx, address, width, &mut |bytes| {
+        let mut current = [0; 8];
+        current[..width].copy_from_slice(bytes);
+        original = u64::from_le_bytes(current);
+
+        let replacement = match op {
+            RmwOp::Add => original.wrapping_add(operand),
+            RmwOp::Sub => original.wrapping_sub(operand),
+            RmwOp::And => original & operand,
+            RmwOp::Or => original | operand,
+            RmwOp::Xor => original ^ operand,
+            RmwOp::Xchg => operand,
+            RmwOp::Cmpxchg if original == expected => operand,
+            RmwOp::Cmpxchg => original,
+        };
+        bytes.copy_from_slice(&replacement.to_le_bytes()[..width]);
+    })?;
+
+    push_value(stack, is_64, original);
+    Ok(())
+}
+
+fn atomic_wait(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    store: &mut impl DataStore,
+    (is_64, width): (bool, usize),
+) -> Result<()> {
+    let timeout = pop_value(stack, true)? as i64;
+    let expected = pop_value(stack, is_64)?;
+    let (mem_idx, address) = pop_address(instruction, stack, width)?;
+
+    // A negative timeout waits forever
+    let timeout = u64::try_from(timeout).ok().map(Duration::from_nanos);
+    let result = store.wait_memory(mem_idx, address, &expected.to_le_bytes()[..width], timeout)?;
+    stack.push(result.into());
+    Ok(())
+}
+
+fn atomic_notify(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    store: &mut impl DataStore,
+) -> Result<()> {
+    let count = pop_value(stack, false)? as u32;
+    let (mem_idx, address) = pop_address(instruction, stack, 4)?;
+
+    let woken = store.notify_memory(mem_idx, address, count)?;
+    stack.push(woken.into());
+    Ok(())
+}
+
+pub fn execute_atomic_instruction(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    store: &mut impl DataStore,
+) -> Result<()> {
+    let sub_opcode = u32::from(instruction.atomic_opcode());
+    match instruction.atomic_opcode() {
+        AtomicOpcode::MemoryAtomicNotify => atomic_notify(instruction, stack, store),
+        AtomicOpcode::MemoryAtomicWait32 => atomic_wait(instruction, stack, store, (false, 4)),
+        AtomicOpcode::MemoryAtomicWait64 => atomic_wait(instruction, stack, store, (true, 8)),
+        // Every access is sequentially consistent already, so there is nothing to fence
+        AtomicOpcode::AtomicFence => Ok(()),
+        _ if sub_opcode < FIRST_STORE => atomic_load(
+            instruction,
+            stack,
+            store,
+            group_shape(sub_opcode, FIRST_LOAD),
+        ),
+        _ if sub_opcode < FIRST_RMW => atomic_store(
+            instruction,
+            stack,
+            store,
+            group_shape(sub_opcode, FIRST_STORE),
+        ),
+        _ => {
+            let op = RMW_OPS[((sub_opcode - FIRST_RMW) / GROUP_SIZE) as usize];
+            atomic_rmw(
+                instruction,
+                stack,
+                store,
+                op,
+                group_shape(sub_opcode, FIRST_RMW),
+            )
+        }
+    }
+}
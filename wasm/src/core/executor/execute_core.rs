@@ -1,11 +1,27 @@
 use std::convert::TryFrom;
 
-use crate::core::{stack_entry::StackEntry, BlockType, Stack};
-use crate::parser::{Instruction, InstructionSource, Opcode};
+use crate::core::callable::{complete_host_call, pending_outside_resumable_call, CallEntry};
+use crate::core::compiled::{
+    BranchTable, BranchTarget, CompiledFunction, CompiledImmediates, CompiledInstruction,
+};
+use crate::core::{
+    stack_entry::StackEntry, trap, Callable, FuncType, Handle, Shared, Stack, TrapKind, Value,
+};
+use crate::parser::{lookup_extension, Instruction, InstructionSource, MiscOpcode, Opcode};
 use anyhow::{anyhow, Result};
+use tracing::{event, Level};
 
+use super::active_memory::ActiveMemoryStore;
+use super::atomic_access::execute_atomic_instruction;
 use super::memory_access::{mem_load, mem_store};
-use super::stack_ops::{binary_boolean_op, binary_op, get_stack_top, unary_boolean_op, unary_op};
+use super::stack_ops::{
+    binary_boolean_op, binary_op, binary_trapping_op, get_stack_top, unary_boolean_op, unary_op,
+    unary_trapping_op, WasmShift,
+};
+use super::table_access::{
+    ref_func, ref_is_null, ref_null, table_copy, table_fill, table_get, table_grow, table_init,
+    table_set, table_size,
+};
 
 pub use super::store_access::{ConstantDataStore, DataStore, FunctionStore};
 
@@ -30,9 +46,35 @@ fn execute_single_constant_instruction(
         }
 
         Opcode::GlobalGet => {
-            stack.push(store.get_global_value(instruction.get_single_u32_as_usize_arg())?);
+            let idx = instruction.get_single_u32_as_usize_arg();
+            if !store.is_constant_global(idx) {
+                return Err(anyhow!(
+                    "Constant expressions can only read imported immutable globals, not global {}",
+                    idx
+                ));
+            }
+            stack.push(store.get_global_value(idx)?);
         }
 
+        Opcode::RefNull => {
+            let compiled = CompiledInstruction::decode(&instruction)?;
+            ref_null(&compiled, stack)?;
+        }
+        Opcode::RefFunc => {
+            stack.push(StackEntry::FuncRefEntry(Some(
+                instruction.get_single_u32_arg(),
+            )));
+        }
+
+        // The extended-const proposal adds integer arithmetic, so that offsets can be
+        // computed from imported globals
+        Opcode::I32Add => binary_op(stack, |a: u32, b| a.wrapping_add(b))?,
+        Opcode::I32Sub => binary_op(stack, |a: u32, b| a.wrapping_sub(b))?,
+        Opcode::I32Mul => binary_op(stack, |a: u32, b| a.wrapping_mul(b))?,
+        Opcode::I64Add => binary_op(stack, |a: u64, b| a.wrapping_add(b))?,
+        Opcode::I64Sub => binary_op(stack, |a: u64, b| a.wrapping_sub(b))?,
+        Opcode::I64Mul => binary_op(stack, |a: u64, b| a.wrapping_mul(b))?,
+
         o => {
             return Err(anyhow!(
                 "Opcode {:?} is not valid in constant expression",
@@ -44,8 +86,8 @@ fn execute_single_constant_instruction(
     Ok(())
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum InstructionResult {
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(super) enum InstructionResult {
     Block,
     Loop,
     If,
@@ -55,21 +97,111 @@ enum InstructionResult {
     Return,
     Call,
     CallIndirect,
+    ReturnCall,
+    ReturnCallIndirect,
+}
+
+// Replace the two values on top of the stack with the first if the selector is non-zero, or
+// the second if it is zero
+fn select(stack: &mut Stack, selector: i32) {
+    let arguments = stack.working_top(2);
+    let chosen = if selector == 0 {
+        arguments[1]
+    } else {
+        arguments[0]
+    };
+    stack.pop_n(2);
+    stack.push(chosen);
+}
+
+fn check_divisor(is_zero: bool) -> Result<()> {
+    if is_zero {
+        Err(trap(TrapKind::IntegerDivideByZero))
+    } else {
+        Ok(())
+    }
+}
+
+/// Truncate a float towards zero, trapping if the result can't be represented in an integer
+/// whose range is `[min, max)`. Every integer bound we need is exactly representable as f64.
+fn checked_trunc(a: f64, min: f64, max: f64) -> Result<f64> {
+    if a.is_nan() {
+        return Err(trap(TrapKind::InvalidConversionToInteger));
+    }
+
+    let truncated = a.trunc();
+    if truncated >= min && truncated < max {
+        Ok(truncated)
+    } else {
+        Err(trap(TrapKind::IntegerOverflow))
+    }
+}
+
+// Halfway cases go to the even neighbour, where round() would take them away from zero
+fn nearest_f32(a: f32) -> f32 {
+    a.round_ties_even()
+}
+
+fn nearest_f64(a: f64) -> f64 {
+    a.round_ties_even()
+}
+
+// Unlike f32::min these return NaN when either operand is NaN, and order -0 below +0.
+// Equal operands can only differ in sign, so combining their bits picks the right zero.
+fn min_f32(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        f32::from_bits(a.to_bits() | b.to_bits())
+    } else {
+        a.min(b)
+    }
+}
+
+fn max_f32(a: f32, b: f32) -> f32 {
+    if a.is_nan() || b.is_nan() {
+        f32::NAN
+    } else if a == b {
+        f32::from_bits(a.to_bits() & b.to_bits())
+    } else {
+        a.max(b)
+    }
+}
+
+fn min_f64(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        f64::from_bits(a.to_bits() | b.to_bits())
+    } else {
+        a.min(b)
+    }
+}
+
+fn max_f64(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        f64::from_bits(a.to_bits() & b.to_bits())
+    } else {
+        a.max(b)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-enum SingleInstructionResult {
+pub(super) enum SingleInstructionResult {
     Done,
     ControlInstruction(InstructionResult),
 }
 
-fn execute_single_instruction(
-    instruction: &Instruction,
+pub(super) fn execute_single_instruction(
+    instruction: &CompiledInstruction,
     stack: &mut Stack,
+    function_store: &impl FunctionStore,
     data_store: &mut impl DataStore,
 ) -> Result<SingleInstructionResult> {
     match instruction.opcode() {
-        Opcode::Unreachable => return Err(anyhow!("Unreachable opcode")),
+        Opcode::Unreachable => return Err(trap(TrapKind::Unreachable)),
         Opcode::Nop => {}
         Opcode::Block => {
             return Ok(SingleInstructionResult::ControlInstruction(
@@ -86,8 +218,8 @@ fn execute_single_instruction(
                 InstructionResult::If,
             ))
         }
-        Opcode::Else => panic!("Else opcode should not pass through opcode iterator"),
-        Opcode::End => panic!("End opcode should not pass through opcode iterator"),
+        Opcode::Else => panic!("Else is handled by the control flow of the executor"),
+        Opcode::End => panic!("End is handled by the control flow of the executor"),
         Opcode::Br => {
             return Ok(SingleInstructionResult::ControlInstruction(
                 InstructionResult::Br,
@@ -118,6 +250,16 @@ fn execute_single_instruction(
                 InstructionResult::CallIndirect,
             ))
         }
+        Opcode::ReturnCall => {
+            return Ok(SingleInstructionResult::ControlInstruction(
+                InstructionResult::ReturnCall,
+            ))
+        }
+        Opcode::ReturnCallIndirect => {
+            return Ok(SingleInstructionResult::ControlInstruction(
+                InstructionResult::ReturnCallIndirect,
+            ))
+        }
 
         Opcode::Drop => {
             // Probe the stack top to make sure there is a value there. We don't care what it is.
@@ -133,14 +275,34 @@ fn execute_single_instruction(
             if !arguments[0].is_same_type(&arguments[1]) {
                 return Err(anyhow!("Select types do not match"));
             }
-            let arguments = [arguments[0], arguments[1]];
-            stack.pop_n(2);
+            // References can only be selected between by typed select
+            if arguments[0].is_null_ref().is_some() {
+                return Err(anyhow!(
+                    "Select without a type cannot choose between {:?} values",
+                    arguments[0].value_type()
+                ));
+            }
+            select(stack, selector);
+        }
+        Opcode::SelectTyped => {
+            let selector = get_stack_top(stack, 1)?[0];
+            let selector = i32::try_from(selector)?;
+            stack.pop();
 
-            if selector == 0 {
-                stack.push(arguments[1]);
-            } else {
-                stack.push(arguments[0]);
+            let value_type = instruction.get_value_type();
+            let arguments = get_stack_top(stack, 2)?;
+            if arguments
+                .iter()
+                .any(|argument| argument.value_type() != value_type)
+            {
+                return Err(anyhow!(
+                    "Select of {:?} given {:?} and {:?}",
+                    value_type,
+                    arguments[0].value_type(),
+                    arguments[1].value_type()
+                ));
             }
+            select(stack, selector);
         }
 
         Opcode::I32Load => {
@@ -240,7 +402,11 @@ fn execute_single_instruction(
             let grow_by = usize::try_from(grow_by).unwrap();
             stack.pop();
 
-            if data_store.grow_memory_by(memory_idx, grow_by).is_ok() {
+            if data_store.grow_memory_by(memory_idx, grow_by)? {
+                if let Some(observer) = stack.observer() {
+                    let original_size = original_size as usize;
+                    observer.on_memory_grow(memory_idx, original_size, original_size + grow_by);
+                }
                 stack.push(original_size.into());
             } else {
                 stack.push(StackEntry::from(-1i32));
@@ -257,13 +423,26 @@ fn execute_single_instruction(
         }
         opcode @ Opcode::LocalSet | opcode @ Opcode::LocalTee => {
             let arg = get_stack_top(stack, 1)?[0];
-            stack.pop();
 
             let local_idx = instruction.get_single_u32_as_usize_arg();
             if local_idx >= stack.parameter_count() + stack.local_count() {
                 return Err(anyhow!("Local index out of range"));
             }
 
+            // Locals start out with a value of their declared type, and only ever get
+            // values of the same type, so the value already there says what type it is
+            let local = stack.local()[local_idx];
+            if !local.is_same_type(&arg) {
+                return Err(anyhow!(
+                    "{:?} of local {} of type {:?} given {:?}",
+                    opcode,
+                    local_idx,
+                    local.value_type(),
+                    arg.value_type()
+                ));
+            }
+
+            stack.pop();
             stack.local_mut()[local_idx] = arg;
 
             if opcode == Opcode::LocalTee {
@@ -338,18 +517,31 @@ fn execute_single_instruction(
         Opcode::I32Add => binary_op(stack, |a: u32, b| a.wrapping_add(b))?,
         Opcode::I32Sub => binary_op(stack, |a: u32, b| a.wrapping_sub(b))?,
         Opcode::I32Mul => binary_op(stack, |a: u32, b| a.wrapping_mul(b))?,
-        Opcode::I32DivS => binary_op(stack, |a: i32, b| a.wrapping_div(b))?,
-        Opcode::I32DivU => binary_op(stack, |a: u32, b| a.wrapping_div(b))?,
-        Opcode::I32RemS => binary_op(stack, |a: i32, b| a.wrapping_rem(b))?,
-        Opcode::I32RemU => binary_op(stack, |a: u32, b| a.wrapping_rem(b))?,
+        Opcode::I32DivS => binary_trapping_op(stack, |a: i32, b| {
+            check_divisor(b == 0)?;
+            a.checked_div(b)
+                .ok_or_else(|| trap(TrapKind::IntegerOverflow))
+        })?,
+        Opcode::I32DivU => binary_trapping_op(stack, |a: u32, b| {
+            check_divisor(b == 0)?;
+            Ok(a / b)
+        })?,
+        Opcode::I32RemS => binary_trapping_op(stack, |a: i32, b| {
+            check_divisor(b == 0)?;
+            Ok(a.wrapping_rem(b))
+        })?,
+        Opcode::I32RemU => binary_trapping_op(stack, |a: u32, b| {
+            check_divisor(b == 0)?;
+            Ok(a % b)
+        })?,
         Opcode::I32And => binary_op(stack, |a: u32, b: u32| a & b)?,
         Opcode::I32Or => binary_op(stack, |a: u32, b: u32| a | b)?,
         Opcode::I32Xor => binary_op(stack, |a: u32, b: u32| a ^ b)?,
-        Opcode::I32Shl => binary_op(stack, |a: u32, b: u32| a << (b % 32))?,
-        Opcode::I32ShrS => binary_op(stack, |a: i32, b: i32| a >> (b % 32))?,
-        Opcode::I32ShrU => binary_op(stack, |a: u32, b: u32| a >> (b % 32))?,
-        Opcode::I32Rotl => binary_op(stack, |a: u32, b: u32| a.rotate_left(b % 32))?,
-        Opcode::I32Rotr => binary_op(stack, |a: u32, b: u32| a.rotate_right(b % 32))?,
+        Opcode::I32Shl => binary_op(stack, u32::wasm_shl)?,
+        Opcode::I32ShrS => binary_op(stack, u32::wasm_shr_s)?,
+        Opcode::I32ShrU => binary_op(stack, u32::wasm_shr_u)?,
+        Opcode::I32Rotl => binary_op(stack, u32::wasm_rotl)?,
+        Opcode::I32Rotr => binary_op(stack, u32::wasm_rotr)?,
 
         Opcode::I64Clz => unary_op(stack, |a: u64| u64::from(a.leading_zeros()))?,
         Opcode::I64Ctz => unary_op(stack, |a: u64| u64::from(a.trailing_zeros()))?,
@@ -357,36 +549,45 @@ fn execute_single_instruction(
         Opcode::I64Add => binary_op(stack, |a: u64, b| a.wrapping_add(b))?,
         Opcode::I64Sub => binary_op(stack, |a: u64, b| a.wrapping_sub(b))?,
         Opcode::I64Mul => binary_op(stack, |a: u64, b| a.wrapping_mul(b))?,
-        Opcode::I64DivS => binary_op(stack, |a: i64, b| a.wrapping_div(b))?,
-        Opcode::I64DivU => binary_op(stack, |a: u64, b| a.wrapping_div(b))?,
-        Opcode::I64RemS => binary_op(stack, |a: i64, b| a.wrapping_rem(b))?,
-        Opcode::I64RemU => binary_op(stack, |a: u64, b| a.wrapping_rem(b))?,
+        Opcode::I64DivS => binary_trapping_op(stack, |a: i64, b| {
+            check_divisor(b == 0)?;
+            a.checked_div(b)
+                .ok_or_else(|| trap(TrapKind::IntegerOverflow))
+        })?,
+        Opcode::I64DivU => binary_trapping_op(stack, |a: u64, b| {
+            check_divisor(b == 0)?;
+            Ok(a / b)
+        })?,
+        Opcode::I64RemS => binary_trapping_op(stack, |a: i64, b| {
+            check_divisor(b == 0)?;
+            Ok(a.wrapping_rem(b))
+        })?,
+        Opcode::I64RemU => binary_trapping_op(stack, |a: u64, b| {
+            check_divisor(b == 0)?;
+            Ok(a % b)
+        })?,
         Opcode::I64And => binary_op(stack, |a: u64, b: u64| a & b)?,
         Opcode::I64Or => binary_op(stack, |a: u64, b: u64| a | b)?,
         Opcode::I64Xor => binary_op(stack, |a: u64, b: u64| a ^ b)?,
-        Opcode::I64Shl => binary_op(stack, |a: u64, b: u64| a << (b % 32))?,
-        Opcode::I64ShrS => binary_op(stack, |a: i64, b: i64| a >> (b % 32))?,
-        Opcode::I64ShrU => binary_op(stack, |a: u64, b: u64| a >> (b % 32))?,
-        Opcode::I64Rotl => binary_op(stack, |a: u64, b: u64| {
-            a.rotate_left(u32::try_from(b % 32).unwrap())
-        })?,
-        Opcode::I64Rotr => binary_op(stack, |a: u64, b: u64| {
-            a.rotate_right(u32::try_from(b % 32).unwrap())
-        })?,
+        Opcode::I64Shl => binary_op(stack, u64::wasm_shl)?,
+        Opcode::I64ShrS => binary_op(stack, u64::wasm_shr_s)?,
+        Opcode::I64ShrU => binary_op(stack, u64::wasm_shr_u)?,
+        Opcode::I64Rotl => binary_op(stack, u64::wasm_rotl)?,
+        Opcode::I64Rotr => binary_op(stack, u64::wasm_rotr)?,
 
         Opcode::F32Abs => unary_op(stack, |a: f32| a.abs())?,
         Opcode::F32Neg => unary_op(stack, |a: f32| -a)?,
         Opcode::F32Ceil => unary_op(stack, |a: f32| a.ceil())?,
         Opcode::F32Floor => unary_op(stack, |a: f32| a.floor())?,
         Opcode::F32Trunc => unary_op(stack, |a: f32| a.trunc())?,
-        Opcode::F32Nearest => unary_op(stack, |a: f32| a.round())?,
+        Opcode::F32Nearest => unary_op(stack, nearest_f32)?,
         Opcode::F32Sqrt => unary_op(stack, |a: f32| a.sqrt())?,
         Opcode::F32Add => binary_op(stack, |a: f32, b: f32| a + b)?,
         Opcode::F32Sub => binary_op(stack, |a: f32, b: f32| a - b)?,
         Opcode::F32Mul => binary_op(stack, |a: f32, b: f32| a * b)?,
         Opcode::F32Div => binary_op(stack, |a: f32, b: f32| a / b)?,
-        Opcode::F32Min => binary_op(stack, |a: f32, b: f32| a.min(b))?,
-        Opcode::F32Max => binary_op(stack, |a: f32, b: f32| a.max(b))?,
+        Opcode::F32Min => binary_op(stack, min_f32)?,
+        Opcode::F32Max => binary_op(stack, max_f32)?,
         Opcode::F32CopySign => binary_op(stack, |a: f32, b: f32| a.copysign(b))?,
 
         Opcode::F64Abs => unary_op(stack, |a: f64| a.abs())?,
@@ -394,27 +595,43 @@ fn execute_single_instruction(
         Opcode::F64Ceil => unary_op(stack, |a: f64| a.ceil())?,
         Opcode::F64Floor => unary_op(stack, |a: f64| a.floor())?,
         Opcode::F64Trunc => unary_op(stack, |a: f64| a.trunc())?,
-        Opcode::F64Nearest => unary_op(stack, |a: f64| a.round())?,
+        Opcode::F64Nearest => unary_op(stack, nearest_f64)?,
         Opcode::F64Sqrt => unary_op(stack, |a: f64| a.sqrt())?,
         Opcode::F64Add => binary_op(stack, |a: f64, b: f64| a + b)?,
         Opcode::F64Sub => binary_op(stack, |a: f64, b: f64| a - b)?,
         Opcode::F64Mul => binary_op(stack, |a: f64, b: f64| a * b)?,
         Opcode::F64Div => binary_op(stack, |a: f64, b: f64| a / b)?,
-        Opcode::F64Min => binary_op(stack, |a: f64, b: f64| a.min(b))?,
-        Opcode::F64Max => binary_op(stack, |a: f64, b: f64| a.max(b))?,
+        Opcode::F64Min => binary_op(stack, min_f64)?,
+        Opcode::F64Max => binary_op(stack, max_f64)?,
         Opcode::F64CopySign => binary_op(stack, |a: f64, b: f64| a.copysign(b))?,
 
         Opcode::I32WrapI64 => unary_op(stack, |a: u64| a as u32)?,
-        Opcode::I32TruncF32S => unary_op(stack, |a: f32| a as i32)?,
-        Opcode::I32TruncF32U => unary_op(stack, |a: f32| a as u32)?,
-        Opcode::I32TruncF64S => unary_op(stack, |a: f64| a as i32)?,
-        Opcode::I32TruncF64U => unary_op(stack, |a: f64| a as u32)?,
+        Opcode::I32TruncF32S => unary_trapping_op(stack, |a: f32| {
+            Ok(checked_trunc(f64::from(a), -2147483648.0, 2147483648.0)? as i32)
+        })?,
+        Opcode::I32TruncF32U => unary_trapping_op(stack, |a: f32| {
+            Ok(checked_trunc(f64::from(a), 0.0, 4294967296.0)? as u32)
+        })?,
+        Opcode::I32TruncF64S => unary_trapping_op(stack, |a: f64| {
+            Ok(checked_trunc(a, -2147483648.0, 2147483648.0)? as i32)
+        })?,
+        Opcode::I32TruncF64U => unary_trapping_op(stack, |a: f64| {
+            Ok(checked_trunc(a, 0.0, 4294967296.0)? as u32)
+        })?,
         Opcode::I64ExtendI32S => unary_op(stack, |a: i32| a as i64)?,
         Opcode::I64ExtendI32U => unary_op(stack, |a: u32| a as u64)?,
-        Opcode::I64TruncF32S => unary_op(stack, |a: f32| a as i64)?,
-        Opcode::I64TruncF32U => unary_op(stack, |a: f32| a as u64)?,
-        Opcode::I64TruncF64S => unary_op(stack, |a: f64| a as i64)?,
-        Opcode::I64TruncF64U => unary_op(stack, |a: f64| a as u64)?,
+        Opcode::I64TruncF32S => unary_trapping_op(stack, |a: f32| {
+            Ok(checked_trunc(f64::from(a), -9223372036854775808.0, 9223372036854775808.0)? as i64)
+        })?,
+        Opcode::I64TruncF32U => unary_trapping_op(stack, |a: f32| {
+            Ok(checked_trunc(f64::from(a), 0.0, 18446744073709551616.0)? as u64)
+        })?,
+        Opcode::I64TruncF64S => unary_trapping_op(stack, |a: f64| {
+            Ok(checked_trunc(a, -9223372036854775808.0, 9223372036854775808.0)? as i64)
+        })?,
+        Opcode::I64TruncF64U => unary_trapping_op(stack, |a: f64| {
+            Ok(checked_trunc(a, 0.0, 18446744073709551616.0)? as u64)
+        })?,
         Opcode::F32ConvertI32S => unary_op(stack, |a: i32| a as f32)?,
         Opcode::F32ConvertI32U => unary_op(stack, |a: u32| a as f32)?,
         Opcode::F32ConvertI64S => unary_op(stack, |a: i64| a as f32)?,
@@ -437,11 +654,106 @@ fn execute_single_instruction(
         Opcode::F64ReinterpretI64 => {
             unary_op(stack, |a: i64| -> f64 { unsafe { std::mem::transmute(a) } })?
         }
+
+        Opcode::I32Extend8S => unary_op(stack, |a: i32| a as i8 as i32)?,
+        Opcode::I32Extend16S => unary_op(stack, |a: i32| a as i16 as i32)?,
+        Opcode::I64Extend8S => unary_op(stack, |a: i64| a as i8 as i64)?,
+        Opcode::I64Extend16S => unary_op(stack, |a: i64| a as i16 as i64)?,
+        Opcode::I64Extend32S => unary_op(stack, |a: i64| a as i32 as i64)?,
+
+        Opcode::TableGet => table_get(instruction, stack, function_store)?,
+        Opcode::TableSet => table_set(instruction, stack, function_store)?,
+
+        Opcode::RefNull => ref_null(instruction, stack)?,
+        Opcode::RefIsNull => ref_is_null(stack)?,
+        Opcode::RefFunc => ref_func(instruction, stack),
+
+        Opcode::MiscPrefix => {
+            execute_misc_instruction(instruction, stack, function_store, data_store)?
+        }
+        Opcode::AtomicPrefix => execute_atomic_instruction(instruction, stack, data_store)?,
+        Opcode::ExtensionPrefix => {
+            let sub_opcode = instruction.extension_opcode();
+            lookup_extension(sub_opcode)?.execute(
+                sub_opcode,
+                instruction.immediates(),
+                stack,
+                data_store,
+            )?
+        }
     }
 
     Ok(SingleInstructionResult::Done)
 }
 
+// The bulk memory instructions all take three i32 arguments, which are used as offsets
+// and lengths
+fn pop_bulk_memory_args(stack: &mut Stack) -> Result<(usize, usize, usize)> {
+    let args = get_stack_top(stack, 3)?;
+    let (a, b, c) = (
+        u32::try_from(args[0])?,
+        u32::try_from(args[1])?,
+        u32::try_from(args[2])?,
+    );
+    stack.pop_n(3);
+
+    Ok((
+        usize::try_from(a).unwrap(),
+        usize::try_from(b).unwrap(),
+        usize::try_from(c).unwrap(),
+    ))
+}
+
+fn execute_misc_instruction(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<()> {
+    match instruction.misc_opcode() {
+        // Float to integer casts in Rust saturate at the bounds of the target type and
+        // convert NaN to zero, which is exactly what the saturating truncations require
+        MiscOpcode::I32TruncSatF32S => unary_op(stack, |a: f32| a as i32)?,
+        MiscOpcode::I32TruncSatF32U => unary_op(stack, |a: f32| a as u32)?,
+        MiscOpcode::I32TruncSatF64S => unary_op(stack, |a: f64| a as i32)?,
+        MiscOpcode::I32TruncSatF64U => unary_op(stack, |a: f64| a as u32)?,
+        MiscOpcode::I64TruncSatF32S => unary_op(stack, |a: f32| a as i64)?,
+        MiscOpcode::I64TruncSatF32U => unary_op(stack, |a: f32| a as u64)?,
+        MiscOpcode::I64TruncSatF64S => unary_op(stack, |a: f64| a as i64)?,
+        MiscOpcode::I64TruncSatF64U => unary_op(stack, |a: f64| a as u64)?,
+
+        MiscOpcode::MemoryInit => {
+            let (data_idx, mem_idx) = instruction.get_pair_u32_as_usize_arg();
+            let (dst_offset, src_offset, length) = pop_bulk_memory_args(stack)?;
+            data_store.init_memory(mem_idx, data_idx, dst_offset, src_offset, length)?;
+        }
+        MiscOpcode::DataDrop => {
+            data_store.drop_data(instruction.get_single_u32_as_usize_arg())?;
+        }
+        MiscOpcode::MemoryCopy => {
+            let (dst_mem_idx, src_mem_idx) = instruction.get_pair_u32_as_usize_arg();
+            let (dst_offset, src_offset, length) = pop_bulk_memory_args(stack)?;
+            data_store.copy_memory(dst_mem_idx, dst_offset, src_mem_idx, src_offset, length)?;
+        }
+        MiscOpcode::MemoryFill => {
+            let mem_idx = instruction.get_single_u32_as_usize_arg();
+            let (offset, value, length) = pop_bulk_memory_args(stack)?;
+            data_store.fill_memory(mem_idx, offset, value as u8, length)?;
+        }
+
+        MiscOpcode::TableInit => table_init(instruction, stack, function_store)?,
+        MiscOpcode::ElemDrop => {
+            function_store.drop_elements(instruction.get_single_u32_as_usize_arg())?;
+        }
+        MiscOpcode::TableCopy => table_copy(instruction, stack, function_store)?,
+        MiscOpcode::TableGrow => table_grow(instruction, stack, function_store)?,
+        MiscOpcode::TableSize => table_size(instruction, stack, function_store)?,
+        MiscOpcode::TableFill => table_fill(instruction, stack, function_store)?,
+    }
+
+    Ok(())
+}
+
 pub fn execute_constant_expression(
     expr: &impl InstructionSource,
     stack: &mut Stack,
@@ -469,327 +781,544 @@ pub fn evaluate_constant_expression(
     Ok(stack.frame()[stack.working_limit() - arity..stack.working_limit()].to_vec())
 }
 
-fn execute_inner_loop<'a>(
-    iter: &'_ mut impl Iterator<Item = Result<Instruction<'a>>>,
-    stack: &'_ mut Stack,
-    data_store: &'_ mut impl DataStore,
-) -> Option<Result<(InstructionResult, Instruction<'a>)>> {
-    loop {
-        match iter.next() {
-            None => {
-                return None;
-            }
-            Some(Err(e)) => {
-                return Some(Err(e));
-            }
-            Some(Ok(instruction)) => {
-                match execute_single_instruction(&instruction, stack, data_store) {
-                    Ok(SingleInstructionResult::Done) => {} // Normal instruction executed normally
-                    Ok(SingleInstructionResult::ControlInstruction(ir)) => {
-                        return Some(Ok((ir, instruction)));
-                    }
-                    Err(e) => {
-                        return Some(Err(e));
-                    }
-                }
-            }
-        }
+/// Take a branch, returning the next instruction to execute, or `None` if the branch
+/// targets the function body itself, which behaves like a return.
+fn take_branch(stack: &mut Stack, target: &BranchTarget) -> Result<Option<usize>> {
+    let destination = match target.destination() {
+        Some(destination) => destination,
+        None => return Ok(None),
+    };
+
+    // Walk all of the labels off the stack, including the one we're going to, keeping
+    // only the values that the target block produces
+    stack.pop_n_labels(target.depth() + 1)?;
+
+    // Loops go round again, which means entering them afresh with the values the branch
+    // carried as their parameters. The label we popped was pushed for this same loop, so
+    // there is room for it.
+    if target.is_loop() {
+        stack
+            .push_label(target.arity(), target.arity())
+            .expect("Re-entering a loop cannot exceed the control depth");
     }
+
+    Ok(Some(destination))
 }
 
-enum BranchControl {
-    NoBranch,
-    Branch { label_idx: usize, label_cnt: usize },
-    Return,
+fn execute_br_table<'a>(table: &'a BranchTable, stack: &mut Stack) -> Result<&'a BranchTarget> {
+    let selector = u32::try_from(get_stack_top(stack, 1)?[0])?;
+    stack.pop();
+
+    Ok(table.target(selector))
 }
 
-impl BranchControl {
-    pub fn no_branch() -> Self {
-        BranchControl::NoBranch
-    }
+fn get_indirect_callable(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<Handle<Callable>> {
+    let (func_type_idx, table_idx) = instruction.get_pair_u32_as_usize_arg();
 
-    pub fn branch_target(label_idx: usize) -> Self {
-        BranchControl::Branch {
-            label_idx,
-            label_cnt: label_idx,
-        }
-    }
+    let elem_idx = u32::try_from(get_stack_top(stack, 1)?[0])? as usize;
+    stack.pop();
 
-    pub fn do_return() -> Self {
-        BranchControl::Return
-    }
+    function_store.get_indirect_function(func_type_idx, table_idx, elem_idx)
+}
 
-    pub fn is_branch(&self) -> bool {
-        match self {
-            BranchControl::NoBranch => false,
-            _ => true,
-        }
-    }
+fn pop_condition(stack: &mut Stack) -> Result<bool> {
+    let condition = u32::try_from(get_stack_top(stack, 1)?[0])?;
+    stack.pop();
+    Ok(condition != 0)
 }
 
-fn execute_block_expression(
-    block_type: BlockType,
-    is_loop: bool,
-    expr: &(impl InstructionSource + ?Sized),
+/// Why a function body stopped executing
+enum FrameExit {
+    /// The body ran off its end, returned, or branched out of the function
+    Finished,
+    /// The body called this function, and carries on from the next instruction once it
+    /// has returned
+    Call(Handle<Callable>),
+    /// The body called this function, which returns straight to the body's caller
+    TailCall(Handle<Callable>),
+    /// The stack's step limit or one of its breakpoints was reached, and the body carries
+    /// on from the same instruction when it is run again
+    Paused,
+}
+
+/// Run a function body from `pc` until it finishes or calls another function. Blocks don't
+/// recurse - entering one pushes a label and branches jump straight to the destination
+/// worked out at compile time, so the depth of nesting is limited only by the stack's
+/// configured control depth and not by the host's stack.
+fn execute_frame(
+    function: &CompiledFunction,
+    func_idx: Option<usize>,
+    pc: &mut usize,
     stack: &mut Stack,
     function_store: &impl FunctionStore,
     data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    loop {
-        // Push a label on to the stack. This is mainly used as a stack guard, since we will probably
-        // end up using the rust stack to handle actual branching. Loops ignore the return type, even
-        // though it is present in the grammar
-        let block_arity = if is_loop || block_type == BlockType::None {
-            0
-        } else {
-            1
-        };
-        stack.push_label(block_arity);
-
-        // Now execute the expression
-        let branch_control = execute_expression_internal(expr, stack, function_store, data_store)?;
-        match branch_control {
-            BranchControl::Return => {
-                // For returns, leave the stack alone to be cleaned up when we get back to the call frame
-                return Ok(BranchControl::do_return());
+) -> Result<FrameExit> {
+    let instructions = function.instructions();
+    let memory = data_store.cached_memory().cloned();
+    let data_store = &mut ActiveMemoryStore::new(data_store, memory.as_ref());
+
+    while let Some(instruction) = instructions.get(*pc) {
+        *pc += 1;
+
+        // The ends of blocks aren't instructions in their own right, so they cost no fuel
+        match instruction.immediates() {
+            CompiledImmediates::Else { end } => {
+                stack.pop_n_labels(1)?;
+                *pc = *end;
+                continue;
             }
+            CompiledImmediates::End { result_count } => {
+                // Remove the block's label, keeping the values the block produced
+                stack.end_label(*result_count)?;
+                continue;
+            }
+            _ => {}
+        }
 
-            BranchControl::Branch {
-                label_idx,
-                label_cnt,
-            } if label_idx > 0 => {
-                // We're part way through the branch, so decrement the label index and propagate
-                // the branch up
-                return Ok(BranchControl::Branch {
-                    label_idx: label_idx - 1,
-                    label_cnt,
-                });
+        if (stack.has_breakpoints() && stack.is_breakpoint(func_idx, function.byte_offset(*pc - 1)))
+            || !stack.take_step()
+        {
+            *pc -= 1;
+            return Ok(FrameExit::Paused);
+        }
+        stack.consume_fuel()?;
+        stack.check_interrupt()?;
+        if let Some(memory_monitor) = stack.take_memory_request() {
+            memory_monitor.serve(data_store);
+        }
+        if let Some(observer) = stack.observer() {
+            observer.before_instruction(func_idx, *pc - 1, instruction, stack.working_count());
+        }
+        event!(
+            target: "wasm_execute",
+            Level::TRACE,
+            "{} at {}",
+            instruction.name(),
+            *pc - 1
+        );
+
+        let ir = match execute_single_instruction(instruction, stack, function_store, data_store)? {
+            SingleInstructionResult::Done => continue,
+            SingleInstructionResult::ControlInstruction(ir) => ir,
+        };
+
+        let branch_target = match (ir, instruction.immediates()) {
+            (InstructionResult::Block, _) | (InstructionResult::Loop, _) => {
+                let signature = instruction.get_block_signature();
+                stack.push_label(signature.param_count(), signature.label_arity())?;
+                None
+            }
+            (
+                InstructionResult::If,
+                CompiledImmediates::If {
+                    signature,
+                    else_start,
+                    end,
+                },
+            ) => {
+                if pop_condition(stack)? {
+                    stack.push_label(signature.param_count(), signature.label_arity())?;
+                } else if let Some(else_start) = else_start {
+                    stack.push_label(signature.param_count(), signature.label_arity())?;
+                    *pc = *else_start;
+                } else {
+                    // Without an else, the parameters pass straight through as the results,
+                    // which compiling the function made sure is possible
+                    *pc = *end;
+                }
+                None
             }
 
-            branch_control => {
-                // Either this isn't a branch, or this is a branch to here. We have to
-                // distinguish between no branch, and branch to zero because of the loop
-                // instruction where we behave differently.
-                let (is_branch, label_cnt) = match branch_control {
-                    BranchControl::Branch { label_cnt, .. } => (true, label_cnt),
-                    _ => (false, 0),
-                };
-
-                // Walk all of the labels back off the stack. We add one to account for the lable we're
-                // going to
-                stack.pop_n_labels(label_cnt + 1);
-
-                // If this is not a loop, then return no branch to indicate we're done, otherwise go around
-                // the loop again
-                if !is_branch || !is_loop {
-                    return Ok(BranchControl::no_branch());
+            (InstructionResult::Br, CompiledImmediates::Branch(target)) => Some(target),
+            (InstructionResult::BrIf, CompiledImmediates::Branch(target)) => {
+                if pop_condition(stack)? {
+                    Some(target)
+                } else {
+                    None
                 }
             }
+            (InstructionResult::BrTable, CompiledImmediates::BranchTable(table)) => {
+                Some(execute_br_table(table, stack)?)
+            }
+
+            (InstructionResult::Call, _) => {
+                let callable =
+                    function_store.get_function(instruction.get_single_u32_as_usize_arg())?;
+                return Ok(FrameExit::Call(callable));
+            }
+            (InstructionResult::CallIndirect, _) => {
+                let callable = get_indirect_callable(instruction, stack, function_store)?;
+                return Ok(FrameExit::Call(callable));
+            }
+            (InstructionResult::ReturnCall, _) => {
+                let callable =
+                    function_store.get_function(instruction.get_single_u32_as_usize_arg())?;
+                return Ok(FrameExit::TailCall(callable));
+            }
+            (InstructionResult::ReturnCallIndirect, _) => {
+                let callable = get_indirect_callable(instruction, stack, function_store)?;
+                return Ok(FrameExit::TailCall(callable));
+            }
+
+            // For returns, leave the stack alone to be cleaned up when we get back to the
+            // call frame
+            (InstructionResult::Return, _) => return Ok(FrameExit::Finished),
+
+            (ir, immediates) => panic!("{:?} was compiled with {:?}", ir, immediates),
+        };
+
+        if let Some(target) = branch_target {
+            match take_branch(stack, target)? {
+                Some(destination) => *pc = destination,
+                None => return Ok(FrameExit::Finished),
+            }
         }
     }
+
+    Ok(FrameExit::Finished)
 }
 
-fn execute_if<'a>(
-    instruction: &'a Instruction<'a>,
-    stack: &mut Stack,
-    function_store: &impl FunctionStore,
-    data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    let condition = u32::try_from(get_stack_top(stack, 1)?[0])?;
-    stack.pop();
+/// A function body part way through execution
+#[derive(Clone)]
+struct CallFrame {
+    function: Shared<CompiledFunction>,
+    func_idx: Option<usize>,
+    pc: usize,
+}
 
-    if condition != 0 {
-        execute_block_expression(
-            instruction.get_block_type(),
-            false,
-            instruction.get_block(),
-            stack,
-            function_store,
-            data_store,
-        )
-    } else if instruction.has_else_block() {
-        execute_block_expression(
-            instruction.get_block_type(),
-            false,
-            instruction.get_else_block(),
-            stack,
-            function_store,
-            data_store,
-        )
-    } else if instruction.get_block_type() != BlockType::None {
-        Err(anyhow!("If instruction with block type other than none should have an else block (shouldn't it?)"))
-    } else {
-        Ok(BranchControl::no_branch())
+impl CallFrame {
+    fn new(function: Shared<CompiledFunction>, func_idx: Option<usize>) -> Self {
+        Self {
+            function,
+            func_idx,
+            pc: 0,
+        }
     }
 }
 
-fn execute_block<'a>(
-    instruction: &'a Instruction<'a>,
-    stack: &mut Stack,
-    function_store: &impl FunctionStore,
-    data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    execute_block_expression(
-        instruction.get_block_type(),
-        instruction.opcode() == Opcode::Loop,
-        instruction.get_block(),
-        stack,
-        function_store,
-        data_store,
-    )
+/// Where a call stack stopped running
+pub(crate) enum RunStatus {
+    /// The outermost function has finished, leaving its results on the stack
+    Complete,
+    /// A host function of this type is waiting for its results
+    Pending(FuncType),
+    /// The stack's step limit or one of its breakpoints was reached
+    Paused,
 }
 
-fn execute_br(
-    label: usize,
-    _stack: &mut Stack,
-    _function_store: &impl FunctionStore,
-    _data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    // Taking the branch is pretty easy
-    Ok(BranchControl::branch_target(label))
+/// The chain of wasm function bodies being executed. Calls from one wasm function to
+/// another push a frame here rather than recursing on the host's stack, which means the
+/// whole execution can be put to one side while a host function waits for its results.
+///
+/// The stack frame for the outermost function belongs to whoever created the call stack,
+/// but the frames for every function called from it are pushed and popped here.
+#[derive(Clone)]
+pub(crate) struct CallStack {
+    current: CallFrame,
+    callers: Vec<CallFrame>,
+    // Whether the observer has been told about the outermost function yet
+    started: bool,
+    // The index of the host function waiting for its results
+    waiting_func_idx: Option<usize>,
 }
 
-fn execute_br_if(
-    label: usize,
-    stack: &mut Stack,
-    function_store: &impl FunctionStore,
-    data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    let condition = u32::try_from(get_stack_top(stack, 1)?[0])?;
-    stack.pop();
+impl CallStack {
+    pub(crate) fn new(function: Shared<CompiledFunction>, func_idx: Option<usize>) -> Self {
+        Self {
+            current: CallFrame::new(function, func_idx),
+            callers: Vec::new(),
+            started: false,
+            waiting_func_idx: None,
+        }
+    }
 
-    if condition != 0 {
-        Ok(execute_br(label, stack, function_store, data_store)?)
-    } else {
-        Ok(BranchControl::no_branch())
+    /// The number of wasm functions being executed
+    pub(crate) fn depth(&self) -> usize {
+        self.callers.len() + 1
     }
-}
 
-fn execute_br_table(
-    labels: &[usize],
-    stack: &mut Stack,
-    function_store: &impl FunctionStore,
-    data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    assert!(labels.len() > 0);
+    /// Record each function by its index, so that the call stack can be saved outside the
+    /// process. Every function must be the one with that index in the function store.
+    pub(crate) fn save(&self, function_store: &impl FunctionStore) -> Result<SavedCallStack> {
+        let frames = self
+            .callers
+            .iter()
+            .chain(std::iter::once(&self.current))
+            .map(|frame| {
+                let func_idx = frame
+                    .func_idx
+                    .ok_or_else(|| anyhow!("Functions from outside an instance can't be saved"))?;
+                if !Shared::ptr_eq(&function_body(function_store, func_idx)?, &frame.function) {
+                    return Err(anyhow!(
+                        "Function {} belongs to another instance, so can't be saved",
+                        func_idx
+                    ));
+                }
+                Ok((func_idx, frame.pc))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(SavedCallStack {
+            frames,
+            started: self.started,
+            waiting_func_idx: self.waiting_func_idx,
+        })
+    }
 
-    let index = u32::try_from(get_stack_top(stack, 1)?[0])?;
-    let index = usize::try_from(index).unwrap();
-    stack.pop();
+    pub(crate) fn restore(
+        saved: SavedCallStack,
+        function_store: &impl FunctionStore,
+    ) -> Result<Self> {
+        let mut frames = saved
+            .frames
+            .into_iter()
+            .map(|(func_idx, pc)| {
+                let function = function_body(function_store, func_idx)?;
+                if pc > function.instructions().len() {
+                    return Err(anyhow!("Function {} has no instruction {}", func_idx, pc));
+                }
+                Ok(CallFrame {
+                    function,
+                    func_idx: Some(func_idx),
+                    pc,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let current = frames
+            .pop()
+            .ok_or_else(|| anyhow!("A saved call stack has no functions"))?;
+        Ok(Self {
+            current,
+            callers: frames,
+            started: saved.started,
+            waiting_func_idx: saved.waiting_func_idx,
+        })
+    }
+
+    /// Check that each of the stack's frames belongs to the function at the same depth of
+    /// the call stack, when the two have been restored from outside the process
+    pub(crate) fn check_stack(
+        &self,
+        stack: &Stack,
+        function_store: &impl FunctionStore,
+    ) -> Result<()> {
+        let frames = self.callers.iter().chain(std::iter::once(&self.current));
+        for (frame_idx, frame) in frames.enumerate() {
+            // Restored frames always have an index, as that is how they were found
+            let callable = match frame.func_idx {
+                Some(func_idx) => function_store.get_function(func_idx)?,
+                None => continue,
+            };
+            let callable = callable.borrow();
+            stack.check_frame(
+                frame_idx,
+                callable.func_type(),
+                callable.locals(),
+                &frame.function.label_arities(frame.pc),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The index of the innermost function, and the byte offset of the instruction it
+    /// executes next
+    pub(crate) fn location(&self) -> (Option<usize>, usize) {
+        let current = &self.current;
+        (current.func_idx, current.function.byte_offset(current.pc))
+    }
+
+    pub(crate) fn run(
+        &mut self,
+        stack: &mut Stack,
+        function_store: &impl FunctionStore,
+        data_store: &mut impl DataStore,
+    ) -> Result<RunStatus> {
+        if !self.started {
+            self.started = true;
+            if let Some(observer) = stack.observer() {
+                observer.on_call(self.current.func_idx);
+            }
+        }
+
+        loop {
+            let function = self.current.function.clone();
+            match execute_frame(
+                &function,
+                self.current.func_idx,
+                &mut self.current.pc,
+                stack,
+                function_store,
+                data_store,
+            )? {
+                FrameExit::Finished => {
+                    if let Some(observer) = stack.observer() {
+                        observer.on_return(self.current.func_idx);
+                    }
+                    match self.callers.pop() {
+                        Some(caller) => {
+                            stack.pop_typed_frame()?;
+                            self.current = caller;
+                        }
+                        None => return Ok(RunStatus::Complete),
+                    }
+                }
+                FrameExit::Paused => return Ok(RunStatus::Paused),
+                FrameExit::Call(callable) => {
+                    let callable = callable.borrow();
+                    let func_idx = callable.provenance().map(|p| p.func_idx());
+                    if let Some(observer) = stack.observer() {
+                        observer.on_call(func_idx);
+                    }
+                    match callable.enter(stack)? {
+                        CallEntry::Body(body, func_idx) => {
+                            let callee = CallFrame::new(body, func_idx);
+                            let caller = std::mem::replace(&mut self.current, callee);
+                            self.callers.push(caller);
+                        }
+                        CallEntry::Complete => Self::host_returned(stack, func_idx),
+                        CallEntry::Pending(func_type) => {
+                            self.waiting_func_idx = func_idx;
+                            return Ok(RunStatus::Pending(func_type));
+                        }
+                    }
+                }
+                FrameExit::TailCall(callable) => {
+                    let callable = callable.borrow();
+                    let func_idx = callable.provenance().map(|p| p.func_idx());
+                    if callable.compiled_body()?.is_some() {
+                        // The callee takes over the frame, so tail calls don't get any deeper
+                        if let Some(observer) = stack.observer() {
+                            observer.on_return(self.current.func_idx);
+                            observer.on_call(func_idx);
+                        }
+                        stack.pop_frame_for_tail_call(callable.func_type())?;
+                        match callable.enter(stack)? {
+                            CallEntry::Body(body, func_idx) => {
+                                self.current = CallFrame::new(body, func_idx)
+                            }
+                            _ => unreachable!("Functions with a body are entered as a body"),
+                        }
+                    } else {
+                        // Functions from outside wasm don't have a frame of their own to
+                        // replace this one with, so call them as usual and return as soon as
+                        // they have finished
+                        self.current.pc = function.instructions().len();
+                        if let Some(observer) = stack.observer() {
+                            observer.on_call(func_idx);
+                        }
+                        match callable.enter(stack)? {
+                            CallEntry::Pending(func_type) => {
+                                self.waiting_func_idx = func_idx;
+                                return Ok(RunStatus::Pending(func_type));
+                            }
+                            _ => Self::host_returned(stack, func_idx),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn host_returned(stack: &Stack, func_idx: Option<usize>) {
+        if let Some(observer) = stack.observer() {
+            observer.on_return(func_idx);
+        }
+    }
 
-    let index = std::cmp::min(index, labels.len() - 1);
-    execute_br(labels[index], stack, function_store, data_store)
+    /// Hand a waiting host function its results, and carry on from where it was called
+    pub(crate) fn resume_with(
+        &mut self,
+        func_type: &FuncType,
+        results: Vec<Value>,
+        stack: &mut Stack,
+        function_store: &impl FunctionStore,
+        data_store: &mut impl DataStore,
+    ) -> Result<RunStatus> {
+        complete_host_call(func_type, results, stack)?;
+        Self::host_returned(stack, self.waiting_func_idx.take());
+        self.run(stack, function_store, data_store)
+    }
 }
 
-fn execute_call(
-    idx: usize,
-    stack: &mut Stack,
+/// A call stack with each function recorded by its index and the position it has reached,
+/// outermost first
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct SavedCallStack {
+    frames: Vec<(usize, usize)>,
+    started: bool,
+    waiting_func_idx: Option<usize>,
+}
+
+impl SavedCallStack {
+    pub(crate) fn depth(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+fn function_body(
     function_store: &impl FunctionStore,
-    data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    function_store.execute_function(idx, stack, data_store)?;
-    Ok(BranchControl::no_branch())
+    func_idx: usize,
+) -> Result<Shared<CompiledFunction>> {
+    let callable = function_store.get_function(func_idx)?;
+    let body = callable.borrow().compiled_body()?;
+    body.ok_or_else(|| anyhow!("Function {} is not defined in wasm", func_idx))
 }
 
-fn execute_call_indirect<'a>(
-    instruction: &'a Instruction<'a>,
+/// Execute a compiled function body to completion. Calls to other wasm functions don't
+/// recurse on the host's stack, so the depth of calls is limited only by the stack's
+/// configured call depth.
+pub fn execute_compiled(
+    function: &Shared<CompiledFunction>,
     stack: &mut Stack,
     function_store: &impl FunctionStore,
     data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    let (func_type_idx, table_idx) = instruction.get_pair_u32_as_usize_arg();
-
-    let elem_idx = u32::try_from(get_stack_top(stack, 1)?[0])? as usize;
-    stack.pop();
-
-    function_store.execute_indirect_function(
-        func_type_idx,
-        table_idx,
-        elem_idx,
-        stack,
-        data_store,
-    )?;
-    Ok(BranchControl::no_branch())
-}
-
-fn execute_return(
-    _stack: &mut Stack,
-    _function_store: &impl FunctionStore,
-    _data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    Ok(BranchControl::do_return())
+) -> Result<()> {
+    execute_body(function.clone(), None, stack, function_store, data_store)
 }
 
-fn execute_expression_internal(
-    expr: &(impl InstructionSource + ?Sized),
+/// Execute the body of the function with the index, which is reported to the observer
+pub(crate) fn execute_body(
+    function: Shared<CompiledFunction>,
+    func_idx: Option<usize>,
     stack: &mut Stack,
     function_store: &impl FunctionStore,
     data_store: &mut impl DataStore,
-) -> Result<BranchControl> {
-    let mut iter = expr.iter();
-    loop {
-        let branch_control = match execute_inner_loop(&mut iter, stack, data_store) {
-            None => {
-                return Ok(BranchControl::no_branch());
-            }
-            Some(Err(e)) => {
-                return Err(e);
-            }
-
-            Some(Ok((InstructionResult::If, instruction))) => {
-                execute_if(&instruction, stack, function_store, data_store)?
-            }
-            Some(Ok((InstructionResult::Block, instruction)))
-            | Some(Ok((InstructionResult::Loop, instruction))) => {
-                execute_block(&instruction, stack, function_store, data_store)?
-            }
-
-            Some(Ok((InstructionResult::Br, instruction))) => execute_br(
-                instruction.get_single_u32_as_usize_arg(),
-                stack,
-                function_store,
-                data_store,
-            )?,
-            Some(Ok((InstructionResult::BrIf, instruction))) => execute_br_if(
-                instruction.get_single_u32_as_usize_arg(),
-                stack,
-                function_store,
-                data_store,
-            )?,
-            Some(Ok((InstructionResult::BrTable, instruction))) => execute_br_table(
-                &instruction.get_block_table_targets(),
-                stack,
-                function_store,
-                data_store,
-            )?,
-
-            Some(Ok((InstructionResult::Call, instruction))) => execute_call(
-                instruction.get_single_u32_as_usize_arg(),
-                stack,
-                function_store,
-                data_store,
-            )?,
-            Some(Ok((InstructionResult::CallIndirect, instruction))) => {
-                execute_call_indirect(&instruction, stack, function_store, data_store)?
-            }
-            Some(Ok((InstructionResult::Return, _))) => {
-                execute_return(stack, function_store, data_store)?
-            }
-        };
-
-        // If we're branching, then propagate the branch to the caller
-        if branch_control.is_branch() {
-            return Ok(branch_control);
+) -> Result<()> {
+    // A trap leaves the frames and labels of the functions and blocks it abandoned behind,
+    // so they are thrown away to get back to where the body was started
+    let mark = stack.mark();
+    let status = CallStack::new(function, func_idx)
+        .run(stack, function_store, data_store)
+        .inspect_err(|_| stack.unwind_to(mark))?;
+
+    match status {
+        RunStatus::Complete => Ok(()),
+        RunStatus::Pending(_) => Err(pending_outside_resumable_call()),
+        RunStatus::Paused => {
+            unreachable!("Only a stack with a step limit or breakpoints can pause")
         }
     }
 }
 
+/// Compile an expression and execute it. Functions that will be called repeatedly should be
+/// compiled once with `CompiledFunction::new` and run with `execute_compiled` instead. There
+/// are no module types to hand, so blocks whose type is a type index can't be compiled.
 pub fn execute_expression(
     expr: &(impl InstructionSource + ?Sized),
     stack: &mut Stack,
     function_store: &impl FunctionStore,
     data_store: &mut impl DataStore,
 ) -> Result<()> {
-    execute_expression_internal(expr, stack, function_store, data_store)?;
-    Ok(())
+    execute_compiled(
+        &Shared::new(CompiledFunction::new(expr, &[])?),
+        stack,
+        function_store,
+        data_store,
+    )
 }
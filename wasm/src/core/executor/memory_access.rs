@@ -1,7 +1,6 @@
 use std::convert::TryFrom;
 
-use crate::core::{stack_entry::StackEntry, Stack};
-use crate::parser::Instruction;
+use crate::core::{compiled::CompiledInstruction, stack_entry::StackEntry, trap, Stack, TrapKind};
 use anyhow::Result;
 use generic_array::typenum::consts::{U1, U2, U4, U8};
 use generic_array::{ArrayLength, GenericArray};
@@ -124,32 +123,43 @@ impl LEByteConvert for f64 {
     }
 }
 
+/// Pop the base address of a memory access and add the offset to it, giving the index of
+/// the memory and the address accessed. Both parts are 32 bits, so the sum can need 33,
+/// which doesn't fit in a usize on every target. An address that doesn't fit is past the
+/// end of any memory, so it traps in the same way.
+pub(super) fn pop_effective_address(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+) -> Result<(usize, usize)> {
+    let mem_arg = instruction.get_mem_arg();
+    let mem_idx = usize::try_from(mem_arg.memory()).unwrap();
+
+    let base_address = get_stack_top(stack, 1)?[0];
+    let base_address = u32::try_from(base_address)?;
+    stack.pop();
+
+    let address = u64::from(base_address) + u64::from(mem_arg.offset());
+    let address = usize::try_from(address).map_err(|_| {
+        trap(TrapKind::MemoryOutOfBounds)
+            .context(format!("Address {} is beyond the address space", address))
+    })?;
+    Ok((mem_idx, address))
+}
+
 pub fn mem_load<
     ValueType: Sized + Into<StackEntry>,
     IntType: Sized + LEByteConvert,
     FuncType: Fn(IntType) -> ValueType,
     Store: DataStore,
 >(
-    instruction: &Instruction,
+    instruction: &CompiledInstruction,
     stack: &mut Stack,
     store: &mut Store,
     func: FuncType,
 ) -> Result<()> {
-    let (mem_idx, offset) = instruction.get_pair_u32_as_usize_arg();
-
-    let base_address = get_stack_top(stack, 1)?[0];
-    let base_address = usize::try_from(u32::try_from(base_address)?).unwrap();
-    stack.pop();
-
-    let final_address = base_address + offset;
-
-    // A limitaton of the rust syntax here means you can't make the array the correct
-    // size. Which is a bit annoying, but not very.
-    let mut bytes: GenericArray<u8, IntType::ArrayLength> =
-        unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-    store.read_data(mem_idx, final_address, &mut bytes)?;
+    let (mem_idx, address) = pop_effective_address(instruction, stack)?;
 
-    let int_value = IntType::from_bytes(bytes);
+    let int_value = store.load_data::<IntType>(mem_idx, address)?;
     let ret_value = func(int_value);
 
     stack.push(ret_value.into());
@@ -163,25 +173,18 @@ pub fn mem_store<
     FuncType: Fn(ValueType) -> IntType,
     Store: DataStore,
 >(
-    instruction: &Instruction,
+    instruction: &CompiledInstruction,
     stack: &mut Stack,
     store: &mut Store,
     func: FuncType,
 ) -> Result<()> {
-    let (mem_idx, offset) = instruction.get_pair_u32_as_usize_arg();
-
     let value = get_stack_top(stack, 1)?[0];
     let value = ValueType::try_from(value)?;
     stack.pop();
 
-    let base_address = get_stack_top(stack, 1)?[0];
-    let base_address = usize::try_from(u32::try_from(base_address)?).unwrap();
-    stack.pop();
-
-    let final_address = base_address + offset;
+    let (mem_idx, address) = pop_effective_address(instruction, stack)?;
 
-    let bytes = func(value).to_bytes();
-    store.write_data(mem_idx, final_address, &bytes)?;
+    store.store_data(mem_idx, address, func(value))?;
 
     Ok(())
 }
@@ -0,0 +1,131 @@
+use crate::core::{stack_entry::StackEntry, ConstantDataStore, DataStore, LEByteConvert};
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+/// A data store which passes reads through to another store but fails every attempt to
+/// modify it. Running a function against this guarantees that the instructions of the
+/// function cannot change the state of the instance, although host functions it calls
+/// are still free to modify anything they have a reference to.
+#[derive(Debug)]
+pub struct ReadOnlyDataStore<'a, Inner: DataStore> {
+    inner: &'a Inner,
+}
+
+impl<'a, Inner: DataStore> ReadOnlyDataStore<'a, Inner> {
+    pub fn new(inner: &'a Inner) -> Self {
+        Self { inner }
+    }
+}
+
+fn read_only_error<T>(operation: &str) -> Result<T> {
+    Err(anyhow!(
+        "Attempted to {} during read-only execution",
+        operation
+    ))
+}
+
+impl<'a, Inner: DataStore> ConstantDataStore for ReadOnlyDataStore<'a, Inner> {
+    fn get_global_value(&self, idx: usize) -> Result<StackEntry> {
+        self.inner.get_global_value(idx)
+    }
+
+    fn is_constant_global(&self, idx: usize) -> bool {
+        self.inner.is_constant_global(idx)
+    }
+}
+
+impl<'a, Inner: DataStore> DataStore for ReadOnlyDataStore<'a, Inner> {
+    fn set_global_value(&mut self, _idx: usize, _value: StackEntry) -> Result<()> {
+        read_only_error("set a global")
+    }
+
+    fn read_data(&self, mem_idx: usize, offset: usize, data: &mut [u8]) -> Result<()> {
+        self.inner.read_data(mem_idx, offset, data)
+    }
+
+    fn write_data(&mut self, _mem_idx: usize, _offset: usize, _data: &[u8]) -> Result<()> {
+        read_only_error("write to memory")
+    }
+
+    fn load_data<T: LEByteConvert>(&self, mem_idx: usize, offset: usize) -> Result<T> {
+        self.inner.load_data(mem_idx, offset)
+    }
+
+    fn store_data<T: LEByteConvert>(
+        &mut self,
+        _mem_idx: usize,
+        _offset: usize,
+        _value: T,
+    ) -> Result<()> {
+        read_only_error("write to memory")
+    }
+
+    fn get_memory_size(&self, mem_idx: usize) -> Result<usize> {
+        self.inner.get_memory_size(mem_idx)
+    }
+
+    fn grow_memory_by(&mut self, _mem_idx: usize, _grow_by: usize) -> Result<bool> {
+        read_only_error("grow memory")
+    }
+
+    fn copy_memory(
+        &mut self,
+        _dst_mem_idx: usize,
+        _dst_offset: usize,
+        _src_mem_idx: usize,
+        _src_offset: usize,
+        _length: usize,
+    ) -> Result<()> {
+        read_only_error("copy memory")
+    }
+
+    fn fill_memory(
+        &mut self,
+        _mem_idx: usize,
+        _offset: usize,
+        _value: u8,
+        _length: usize,
+    ) -> Result<()> {
+        read_only_error("fill memory")
+    }
+
+    fn init_memory(
+        &mut self,
+        _mem_idx: usize,
+        _data_idx: usize,
+        _dst_offset: usize,
+        _src_offset: usize,
+        _length: usize,
+    ) -> Result<()> {
+        read_only_error("initialize memory")
+    }
+
+    fn drop_data(&mut self, _data_idx: usize) -> Result<()> {
+        read_only_error("drop a data segment")
+    }
+
+    fn update_data(
+        &mut self,
+        _mem_idx: usize,
+        _offset: usize,
+        _length: usize,
+        _update: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<()> {
+        read_only_error("write to memory")
+    }
+
+    // Waiting and notifying don't change memory, but they do affect other threads
+    fn wait_memory(
+        &self,
+        _mem_idx: usize,
+        _offset: usize,
+        _expected: &[u8],
+        _timeout: Option<Duration>,
+    ) -> Result<u32> {
+        read_only_error("wait on memory")
+    }
+
+    fn notify_memory(&self, _mem_idx: usize, _offset: usize, _count: u32) -> Result<u32> {
+        read_only_error("notify waiters on memory")
+    }
+}
@@ -1,11 +1,15 @@
 use std::convert::{TryFrom, TryInto};
 
-use crate::core::{stack_entry::StackEntry, Stack};
-use anyhow::{anyhow, Result};
+use crate::core::{stack_entry::StackEntry, trap, Stack, TrapKind};
+use anyhow::Result;
 
 pub fn get_stack_top(stack: &mut Stack, n: usize) -> Result<&[StackEntry]> {
     if stack.working_count() < n {
-        Err(anyhow!("Not enough values on stack"))
+        Err(trap(TrapKind::StackUnderflow).context(format!(
+            "Needed {} values but the block has {}",
+            n,
+            stack.working_count()
+        )))
     } else {
         Ok(stack.working_top(n))
     }
@@ -18,11 +22,24 @@ pub fn unary_op<
 >(
     stack: &mut Stack,
     func: Func,
+) -> Result<()> {
+    unary_trapping_op(stack, |p| Ok(func(p)))
+}
+
+/// Like `unary_op`, but for operations which can trap. The argument is consumed even if
+/// the operation fails.
+pub fn unary_trapping_op<
+    ParamType: Sized + TryFrom<StackEntry, Error = anyhow::Error>,
+    RetType: Into<StackEntry>,
+    Func: Fn(ParamType) -> Result<RetType>,
+>(
+    stack: &mut Stack,
+    func: Func,
 ) -> Result<()> {
     let arg = get_stack_top(stack, 1)?[0];
     stack.pop();
 
-    let ret = func(arg.try_into()?);
+    let ret = func(arg.try_into()?)?;
     stack.push(ret.into());
     Ok(())
 }
@@ -44,12 +61,25 @@ pub fn binary_op<
 >(
     stack: &mut Stack,
     func: Func,
+) -> Result<()> {
+    binary_trapping_op(stack, |p1, p2| Ok(func(p1, p2)))
+}
+
+/// Like `binary_op`, but for operations which can trap. The arguments are consumed even if
+/// the operation fails.
+pub fn binary_trapping_op<
+    ParamType: Sized + TryFrom<StackEntry, Error = anyhow::Error>,
+    RetType: Into<StackEntry>,
+    Func: Fn(ParamType, ParamType) -> Result<RetType>,
+>(
+    stack: &mut Stack,
+    func: Func,
 ) -> Result<()> {
     let args = get_stack_top(stack, 2)?;
     let args = [args[0], args[1]];
     stack.pop_n(2);
 
-    let ret = func(args[0].try_into()?, args[1].try_into()?);
+    let ret = func(args[0].try_into()?, args[1].try_into()?)?;
     stack.push(ret.into());
     Ok(())
 }
@@ -66,3 +96,159 @@ pub fn binary_boolean_op<
         |p1: ParamType, p2: ParamType| if func(p1, p2) { 1u32 } else { 0u32 },
     )
 }
+
+/// Shifts and rotates as wasm defines them. Only the low bits of the count are used, just
+/// enough to count up to the width of the value, so shifting an i64 by 65 shifts it by 1.
+/// The signed shift treats the value as signed, whatever type it is held in.
+pub trait WasmShift: Copy {
+    fn wasm_shl(self, count: Self) -> Self;
+    fn wasm_shr_s(self, count: Self) -> Self;
+    fn wasm_shr_u(self, count: Self) -> Self;
+    fn wasm_rotl(self, count: Self) -> Self;
+    fn wasm_rotr(self, count: Self) -> Self;
+}
+
+macro_rules! shift_count {
+    ($count:expr, $unsigned:ty) => {
+        ($count % <$unsigned>::BITS as $unsigned) as u32
+    };
+}
+
+macro_rules! impl_wasm_shift {
+    ($unsigned:ty, $signed:ty) => {
+        impl WasmShift for $unsigned {
+            fn wasm_shl(self, count: Self) -> Self {
+                self << shift_count!(count, $unsigned)
+            }
+
+            fn wasm_shr_s(self, count: Self) -> Self {
+                ((self as $signed) >> shift_count!(count, $unsigned)) as $unsigned
+            }
+
+            fn wasm_shr_u(self, count: Self) -> Self {
+                self >> shift_count!(count, $unsigned)
+            }
+
+            fn wasm_rotl(self, count: Self) -> Self {
+                self.rotate_left(shift_count!(count, $unsigned))
+            }
+
+            fn wasm_rotr(self, count: Self) -> Self {
+                self.rotate_right(shift_count!(count, $unsigned))
+            }
+        }
+    };
+}
+
+impl_wasm_shift!(u32, i32);
+impl_wasm_shift!(u64, i64);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shift_counts_32() {
+        let value = 0x8000_0001u32;
+        let cases = [
+            // count, shl, shr_s, shr_u, rotl, rotr
+            (
+                31,
+                0x8000_0000,
+                0xFFFF_FFFF,
+                0x0000_0001,
+                0xC000_0000,
+                0x0000_0003,
+            ),
+            (32, value, value, value, value, value),
+            (
+                63,
+                0x8000_0000,
+                0xFFFF_FFFF,
+                0x0000_0001,
+                0xC000_0000,
+                0x0000_0003,
+            ),
+            (64, value, value, value, value, value),
+            (
+                65,
+                0x0000_0002,
+                0xC000_0000,
+                0x4000_0000,
+                0x0000_0003,
+                0xC000_0000,
+            ),
+            (
+                u32::MAX,
+                0x8000_0000,
+                0xFFFF_FFFF,
+                0x0000_0001,
+                0xC000_0000,
+                0x0000_0003,
+            ),
+        ];
+        for (count, shl, shr_s, shr_u, rotl, rotr) in cases {
+            assert_eq!(value.wasm_shl(count), shl, "shl {}", count);
+            assert_eq!(value.wasm_shr_s(count), shr_s, "shr_s {}", count);
+            assert_eq!(value.wasm_shr_u(count), shr_u, "shr_u {}", count);
+            assert_eq!(value.wasm_rotl(count), rotl, "rotl {}", count);
+            assert_eq!(value.wasm_rotr(count), rotr, "rotr {}", count);
+        }
+    }
+
+    #[test]
+    fn test_shift_counts_64() {
+        let value = 0x8000_0000_0000_0001u64;
+        let cases = [
+            // count, shl, shr_s, shr_u, rotl, rotr
+            (
+                31,
+                0x0000_0000_8000_0000,
+                0xFFFF_FFFF_0000_0000,
+                0x0000_0001_0000_0000,
+                0x0000_0000_C000_0000,
+                0x0000_0003_0000_0000,
+            ),
+            (
+                32,
+                0x0000_0001_0000_0000,
+                0xFFFF_FFFF_8000_0000,
+                0x0000_0000_8000_0000,
+                0x0000_0001_8000_0000,
+                0x0000_0001_8000_0000,
+            ),
+            (
+                63,
+                0x8000_0000_0000_0000,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0x0000_0000_0000_0001,
+                0xC000_0000_0000_0000,
+                0x0000_0000_0000_0003,
+            ),
+            (64, value, value, value, value, value),
+            (
+                65,
+                0x0000_0000_0000_0002,
+                0xC000_0000_0000_0000,
+                0x4000_0000_0000_0000,
+                0x0000_0000_0000_0003,
+                0xC000_0000_0000_0000,
+            ),
+            (
+                u64::MAX,
+                0x8000_0000_0000_0000,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0x0000_0000_0000_0001,
+                0xC000_0000_0000_0000,
+                0x0000_0000_0000_0003,
+            ),
+        ];
+        for (count, shl, shr_s, shr_u, rotl, rotr) in cases {
+            assert_eq!(value.wasm_shl(count), shl, "shl {}", count);
+            assert_eq!(value.wasm_shr_s(count), shr_s, "shr_s {}", count);
+            assert_eq!(value.wasm_shr_u(count), shr_u, "shr_u {}", count);
+            assert_eq!(value.wasm_rotl(count), rotl, "rotl {}", count);
+            assert_eq!(value.wasm_rotr(count), rotr, "rotr {}", count);
+        }
+    }
+}
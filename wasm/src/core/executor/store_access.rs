@@ -1,25 +1,145 @@
-use crate::core::{stack_entry::StackEntry, Stack};
+use crate::core::{stack_entry::StackEntry, Callable, Handle, Memory, Stack, Table};
 use anyhow::Result;
+use generic_array::GenericArray;
+
+use super::memory_access::LEByteConvert;
+use std::time::Duration;
 
 pub trait ConstantDataStore {
     fn get_global_value(&self, idx: usize) -> Result<StackEntry>;
+
+    /// Whether a constant expression may read the global. The spec only allows imported
+    /// globals which are immutable, whose values are known before any of the module's own
+    /// globals are initialized. Stores which don't know where their globals came from allow
+    /// all of them.
+    fn is_constant_global(&self, _idx: usize) -> bool {
+        true
+    }
 }
 
 pub trait DataStore: ConstantDataStore {
     fn set_global_value(&mut self, idx: usize, value: StackEntry) -> Result<()>;
+
+    /// Memory 0, for the executor to hold on to while a function runs. Stores which
+    /// restrict what can be done to their memories must leave this as `None`, because the
+    /// executor goes straight to the memory it is given.
+    fn cached_memory(&self) -> Option<&Handle<Memory>> {
+        None
+    }
     fn read_data(&self, mem_idx: usize, offset: usize, data: &mut [u8]) -> Result<()>;
     fn write_data(&mut self, mem_idx: usize, offset: usize, data: &[u8]) -> Result<()>;
+
+    /// Load a value for a load instruction. Stores which own their memories should go
+    /// straight to `Memory::load`, which saves the copy through a buffer.
+    fn load_data<T: LEByteConvert>(&self, mem_idx: usize, offset: usize) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let mut bytes = GenericArray::default();
+        self.read_data(mem_idx, offset, &mut bytes)?;
+        Ok(T::from_bytes(bytes))
+    }
+
+    fn store_data<T: LEByteConvert>(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: T,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.write_data(mem_idx, offset, &value.to_bytes())
+    }
+
     fn get_memory_size(&self, mem_idx: usize) -> Result<usize>;
-    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<()>;
+    /// Returns false if the memory could not grow, which is not an error as far as the
+    /// executing code is concerned.
+    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<bool>;
+
+    fn copy_memory(
+        &mut self,
+        dst_mem_idx: usize,
+        dst_offset: usize,
+        src_mem_idx: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()>;
+    fn fill_memory(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: u8,
+        length: usize,
+    ) -> Result<()>;
+    fn init_memory(
+        &mut self,
+        mem_idx: usize,
+        data_idx: usize,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()>;
+    fn drop_data(&mut self, data_idx: usize) -> Result<()>;
+
+    /// Read `length` bytes, change them and write them back as a single atomic access
+    fn update_data(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        length: usize,
+        update: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<()>;
+    /// Returns one of the `memory.atomic.wait` results
+    fn wait_memory(
+        &self,
+        mem_idx: usize,
+        offset: usize,
+        expected: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<u32>;
+    /// Returns the number of waiting threads which were woken
+    fn notify_memory(&self, mem_idx: usize, offset: usize, count: u32) -> Result<u32>;
 }
 
 pub trait FunctionStore {
+    fn get_function(&self, fn_idx: usize) -> Result<Handle<Callable>>;
+    /// Look up the function in a table entry, checking that it has the expected type
+    fn get_indirect_function(
+        &self,
+        func_type_idx: usize,
+        table_idx: usize,
+        elem_idx: usize,
+    ) -> Result<Handle<Callable>>;
+    /// The index of a function in the function index space, or `None` if it doesn't have
+    /// one, which can happen when a table shared with another module refers to it
+    fn get_function_index(&self, callable: &Handle<Callable>) -> Option<usize>;
+
+    fn get_table(&self, table_idx: usize) -> Result<Handle<Table>>;
+    fn init_table(
+        &self,
+        table_idx: usize,
+        elem_idx: usize,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()>;
+    fn drop_elements(&self, elem_idx: usize) -> Result<()>;
+
     fn execute_function(
         &self,
         fn_idx: usize,
         stack: &mut Stack,
         data_store: &mut impl DataStore,
-    ) -> Result<()>;
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.get_function(fn_idx)?
+            .borrow()
+            .call(stack, self, data_store)
+    }
+
     fn execute_indirect_function(
         &self,
         func_type_idx: usize,
@@ -27,5 +147,12 @@ pub trait FunctionStore {
         elem_idx: usize,
         stack: &mut Stack,
         data_store: &mut impl DataStore,
-    ) -> Result<()>;
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.get_indirect_function(func_type_idx, table_idx, elem_idx)?
+            .borrow()
+            .call(stack, self, data_store)
+    }
 }
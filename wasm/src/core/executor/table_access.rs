@@ -0,0 +1,195 @@
+use std::convert::TryFrom;
+
+use crate::core::{
+    compiled::CompiledInstruction, stack_entry::StackEntry, Shared, Stack, TableElement, ValueType,
+};
+use anyhow::{anyhow, Result};
+
+use super::stack_ops::get_stack_top;
+use super::store_access::FunctionStore;
+
+fn pop_u32_as_usize(stack: &mut Stack) -> Result<usize> {
+    let value = u32::try_from(get_stack_top(stack, 1)?[0])?;
+    stack.pop();
+    Ok(usize::try_from(value).unwrap())
+}
+
+// Tables hold the functions themselves rather than their indices, so references have to
+// be translated on the way in and out. Whether the reference suits the table is up to the
+// table.
+fn pop_table_entry(stack: &mut Stack, function_store: &impl FunctionStore) -> Result<TableElement> {
+    let entry = match get_stack_top(stack, 1)?[0] {
+        StackEntry::FuncRefEntry(None) => TableElement::FuncRef(None),
+        StackEntry::FuncRefEntry(Some(idx)) => {
+            TableElement::FuncRef(Some(function_store.get_function(idx as usize)?))
+        }
+        StackEntry::ExternRefEntry(extern_ref) => TableElement::ExternRef(extern_ref),
+        entry => return Err(anyhow!("Cannot store {:?} in a table", entry.value_type())),
+    };
+    stack.pop();
+    Ok(entry)
+}
+
+fn table_entry_to_ref(
+    entry: TableElement,
+    function_store: &impl FunctionStore,
+) -> Result<StackEntry> {
+    match entry {
+        TableElement::FuncRef(None) => Ok(StackEntry::FuncRefEntry(None)),
+        TableElement::FuncRef(Some(callable)) => {
+            match function_store.get_function_index(&callable) {
+                Some(idx) => Ok(StackEntry::FuncRefEntry(Some(idx as u32))),
+                None => Err(anyhow!(
+                    "Table entry refers to a function which is not in this module"
+                )),
+            }
+        }
+        TableElement::ExternRef(extern_ref) => Ok(StackEntry::ExternRefEntry(extern_ref)),
+    }
+}
+
+pub fn ref_null(instruction: &CompiledInstruction, stack: &mut Stack) -> Result<()> {
+    let ref_type = u8::try_from(instruction.get_single_u32_arg())
+        .ok()
+        .and_then(|byte| ValueType::from_byte(byte).ok());
+    match ref_type {
+        Some(ref_type @ ValueType::FuncRef) | Some(ref_type @ ValueType::ExternRef) => {
+            stack.push(StackEntry::zero_value(ref_type))
+        }
+        _ => {
+            return Err(anyhow!(
+                "Invalid reference type {} for ref.null",
+                instruction.get_single_u32_arg()
+            ))
+        }
+    }
+    Ok(())
+}
+
+pub fn ref_is_null(stack: &mut Stack) -> Result<()> {
+    let entry = get_stack_top(stack, 1)?[0];
+    let is_null = entry
+        .is_null_ref()
+        .ok_or_else(|| anyhow!("ref.is_null expects a reference, not {:?}", entry))?;
+    stack.pop();
+    stack.push(u32::from(is_null).into());
+    Ok(())
+}
+
+pub fn ref_func(instruction: &CompiledInstruction, stack: &mut Stack) {
+    stack.push(StackEntry::FuncRefEntry(Some(
+        instruction.get_single_u32_arg(),
+    )));
+}
+
+pub fn table_get(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<()> {
+    let table = function_store.get_table(instruction.get_single_u32_as_usize_arg())?;
+    let idx = pop_u32_as_usize(stack)?;
+
+    let entry = table.borrow().get(idx)?;
+    stack.push(table_entry_to_ref(entry, function_store)?);
+    Ok(())
+}
+
+pub fn table_set(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<()> {
+    let table = function_store.get_table(instruction.get_single_u32_as_usize_arg())?;
+    let entry = pop_table_entry(stack, function_store)?;
+    let idx = pop_u32_as_usize(stack)?;
+
+    table.borrow_mut().set(idx, entry)?;
+    Ok(())
+}
+
+pub fn table_size(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<()> {
+    let table = function_store.get_table(instruction.get_single_u32_as_usize_arg())?;
+    let size = table.borrow().current_size() as u32;
+    stack.push(size.into());
+    Ok(())
+}
+
+pub fn table_grow(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<()> {
+    let table = function_store.get_table(instruction.get_single_u32_as_usize_arg())?;
+    let grow_by = pop_u32_as_usize(stack)?;
+    let entry = pop_table_entry(stack, function_store)?;
+
+    let mut table = table.borrow_mut();
+    let original_size = table.current_size() as u32;
+    // Failing to grow is not an error as far as the executing code is concerned
+    if table.grow_by(grow_by, entry).is_ok() {
+        stack.push(original_size.into());
+    } else {
+        stack.push(StackEntry::from(-1i32));
+    }
+    Ok(())
+}
+
+pub fn table_fill(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<()> {
+    let table = function_store.get_table(instruction.get_single_u32_as_usize_arg())?;
+    let length = pop_u32_as_usize(stack)?;
+    let entry = pop_table_entry(stack, function_store)?;
+    let offset = pop_u32_as_usize(stack)?;
+
+    table.borrow_mut().fill(offset, entry, length)?;
+    Ok(())
+}
+
+// table.copy and table.init both take a destination, a source and a length
+fn pop_copy_args(stack: &mut Stack) -> Result<(usize, usize, usize)> {
+    let length = pop_u32_as_usize(stack)?;
+    let src_offset = pop_u32_as_usize(stack)?;
+    let dst_offset = pop_u32_as_usize(stack)?;
+    Ok((dst_offset, src_offset, length))
+}
+
+pub fn table_copy(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<()> {
+    let (dst_table_idx, src_table_idx) = instruction.get_pair_u32_as_usize_arg();
+    let dst_table = function_store.get_table(dst_table_idx)?;
+    let src_table = function_store.get_table(src_table_idx)?;
+    let (dst_offset, src_offset, length) = pop_copy_args(stack)?;
+
+    // The tables may be the same one, in which case it can only be borrowed once
+    if Shared::ptr_eq(&dst_table, &src_table) {
+        dst_table
+            .borrow_mut()
+            .copy_within(dst_offset, src_offset, length)
+    } else {
+        dst_table
+            .borrow_mut()
+            .copy_from(dst_offset, &src_table.borrow(), src_offset, length)
+    }
+}
+
+pub fn table_init(
+    instruction: &CompiledInstruction,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+) -> Result<()> {
+    let (elem_idx, table_idx) = instruction.get_pair_u32_as_usize_arg();
+    let (dst_offset, src_offset, length) = pop_copy_args(stack)?;
+
+    function_store.init_table(table_idx, elem_idx, dst_offset, src_offset, length)
+}
@@ -1,9 +1,11 @@
+use crate::core::handle;
 use crate::core::{
-    executor::execute_expression, stack_entry::StackEntry, BlockType, FuncType, Locals, Stack,
-    Table, ValueType, WasmExprCallable,
+    executor::execute_expression, stack_entry::StackEntry, BlockType, FuncType, InterruptHandle,
+    Locals, Stack, StackLimits, Table, TrapKind, ValueType, WasmError, WasmExprCallable,
 };
 use crate::parser::{InstructionSource, Opcode};
 
+use super::super::FunctionStore;
 use super::instruction_generator::*;
 use super::instruction_test_helpers::*;
 use super::test_store::*;
@@ -47,11 +49,12 @@ fn test_block_block_no_branches() {
 #[test]
 fn test_loop_block_no_branches() {
     let expr = make_expression_writer();
-    let mut block_expr = expr.write_block_instruction(Opcode::Loop, BlockType::I32); // The block type should be ignored for loops
+    let mut block_expr = expr.write_block_instruction(Opcode::Loop, BlockType::I32);
     block_expr.write_const_instruction(1_u32);
     let expr = block_expr.do_end();
 
-    test_no_return_expression!(expr);
+    // Falling out of the end of a loop produces its results, just like any other block
+    test_single_return_expression!(expr, 1_u32);
 }
 
 fn write_local_value(
@@ -114,8 +117,11 @@ fn test_loop_block() {
     let mut stack = Stack::new();
     let (function_store, mut data_store) = make_test_store();
 
-    // We push a frame onto the stack with the one local we use
-    assert!(stack.push_test_frame(1).is_ok());
+    // We push a frame onto the stack with the one i64 local we use
+    let locals = vec![Locals::new(1, ValueType::I64)];
+    assert!(stack
+        .push_typed_frame(&FuncType::new(vec![], vec![]), &locals, 1)
+        .is_ok());
 
     assert!(execute_expression(&expr, &mut stack, &function_store, &mut data_store).is_ok());
     assert_eq!(stack.working_count(), 1);
@@ -207,6 +213,145 @@ fn test_call() {
     assert_eq!(stack.working_top(1)[0], 43_i32.into());
 }
 
+#[test]
+fn test_trap_unwinds_nested_calls() {
+    let (mut function_store, mut data_store) = make_test_store();
+    let func_type = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+
+    // Function 0 traps, function 1 calls it and function 2 calls function 1. Each of them
+    // leaves something on its own working stack first.
+    let mut func_writer = make_expression_writer();
+    func_writer.write_const_instruction(0_i32);
+    func_writer.write_single_byte_instruction(Opcode::Unreachable);
+    function_store.add_function(func_writer, func_type.clone(), vec![]);
+    for callee in 0..2 {
+        let mut func_writer = make_expression_writer();
+        func_writer.write_const_instruction(callee as i32 + 1);
+        func_writer.write_single_leb_instruction(Opcode::LocalGet, 0);
+        func_writer.write_single_leb_instruction(Opcode::Call, callee);
+        function_store.add_function(func_writer, func_type.clone(), vec![]);
+    }
+
+    // All three frames go, along with the argument to the outermost call, leaving what was
+    // there before it
+    let mut test_writer = make_expression_writer();
+    test_writer.write_const_instruction(42_i32);
+    test_writer.write_const_instruction(7_i32);
+    test_writer.write_single_leb_instruction(Opcode::Call, 2);
+
+    let mut stack = Stack::new();
+    let mark = stack.mark();
+    let error = execute_expression(&test_writer, &mut stack, &function_store, &mut data_store)
+        .err()
+        .unwrap();
+    assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Unreachable));
+    assert_eq!(stack.mark(), mark);
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 42_i32.into());
+
+    // Calling the function directly leaves the caller's frame as it was too
+    assert!(stack.push_test_frame(0).is_ok());
+    let mark = stack.mark();
+    let height = stack.height();
+    stack.push(7_i32.into());
+    let callable = function_store.get_function(2).unwrap();
+    let error = callable
+        .borrow()
+        .call(&mut stack, &function_store, &mut data_store)
+        .err()
+        .unwrap();
+    assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Unreachable));
+    assert_eq!(stack.mark(), mark);
+    assert_eq!(stack.working_count(), 0);
+    assert_eq!(stack.height(), height);
+}
+
+#[test]
+fn test_stack_is_usable_after_trap_in_block() {
+    let (mut function_store, mut data_store) = make_test_store();
+
+    let mut func_writer = make_expression_writer();
+    func_writer.write_single_leb_instruction(Opcode::LocalGet, 0);
+    func_writer.write_const_instruction(1_i32);
+    func_writer.write_single_byte_instruction(Opcode::I32Add);
+    function_store.add_function(
+        func_writer,
+        FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+        vec![],
+    );
+
+    // Trap two blocks deep in the frame the expression runs in, with values in each block
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(1_i32);
+    let mut outer = expr.write_block_instruction(Opcode::Block, BlockType::None);
+    outer.write_const_instruction(2_i32);
+    let mut inner = outer.write_block_instruction(Opcode::Block, BlockType::None);
+    inner.write_const_instruction(3_i32);
+    inner.write_single_byte_instruction(Opcode::Unreachable);
+    let expr = inner.do_end().do_end();
+
+    let mut stack = Stack::new();
+    assert!(stack.push_test_frame(0).is_ok());
+    let mark = stack.mark();
+    let error = execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+        .err()
+        .unwrap();
+    assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Unreachable));
+
+    // The blocks are gone, leaving what was pushed before them
+    assert_eq!(stack.mark(), mark);
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 1_i32.into());
+
+    let mut expr = make_expression_writer();
+    expr.write_single_leb_instruction(Opcode::Call, 0);
+    assert!(execute_expression(&expr, &mut stack, &function_store, &mut data_store).is_ok());
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 2_i32.into());
+}
+
+#[test]
+fn test_interrupted_call_leaves_no_frame() {
+    let (mut function_store, mut data_store) = make_test_store();
+    let mut func_writer = make_expression_writer();
+    func_writer.write_single_leb_instruction(Opcode::LocalGet, 0);
+    func_writer.write_const_instruction(1_i32);
+    func_writer.write_single_byte_instruction(Opcode::I32Add);
+    function_store.add_function(
+        func_writer,
+        FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+        vec![],
+    );
+    let callable = function_store.get_function(0).unwrap();
+
+    // The interrupt stops the call as soon as the body starts, and the call's frame goes
+    // along with its argument
+    let interrupt = InterruptHandle::new();
+    let mut stack = Stack::new();
+    stack.set_interrupt_handle(Some(interrupt.clone()));
+    assert!(stack.push_test_frame(0).is_ok());
+    let mark = stack.mark();
+    let height = stack.height();
+    interrupt.interrupt();
+    stack.push(7_i32.into());
+    let error = callable
+        .borrow()
+        .call(&mut stack, &function_store, &mut data_store)
+        .err()
+        .unwrap();
+    assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Interrupted));
+    assert_eq!(stack.mark(), mark);
+    assert_eq!(stack.height(), height);
+
+    // So the same stack can make the call again
+    stack.push(7_i32.into());
+    assert!(callable
+        .borrow()
+        .call(&mut stack, &function_store, &mut data_store)
+        .is_ok());
+    assert_eq!(stack.working_top(1)[0], 8_i32.into());
+}
+
 #[test]
 fn test_indirect_call() {
     let mut stack = Stack::new();
@@ -236,16 +381,14 @@ fn test_indirect_call() {
             expr.write_const_instruction(i);
             expr.write_single_byte_instruction(Opcode::I32Add);
 
-            use std::{cell::RefCell, rc::Rc};
-            Rc::new(RefCell::new(WasmExprCallable::new_base(
-                func_type.clone(),
-                vec![],
-                expr.as_expr(),
-            )))
+            handle(
+                WasmExprCallable::new_base(func_type.clone(), vec![], &expr.as_expr(), &[])
+                    .unwrap(),
+            )
         })
         .collect();
 
-    table.set_entries(0, &functions);
+    table.set_entries(0, &functions).unwrap();
 
     function_store.set_func_types(vec![func_type]);
     function_store.set_table(table);
@@ -275,3 +418,325 @@ fn test_indirect_call() {
         }
     }
 }
+
+fn write_nested_blocks(depth: usize) -> ExpressionWriter {
+    let mut expr = make_expression_writer();
+    for _ in 0..depth {
+        expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    }
+    expr.write_const_instruction(42u32);
+    for _ in 0..depth {
+        expr = expr.do_end();
+    }
+    expr
+}
+
+#[test]
+fn test_deeply_nested_blocks() {
+    // Deeper than the host stack would allow if each block recursed
+    let expr = write_nested_blocks(2_000);
+    let mut stack = Stack::with_limits(StackLimits {
+        max_control_depth: 2_000,
+        ..StackLimits::default()
+    });
+    let (function_store, mut data_store) = make_test_store();
+    assert!(stack.push_test_frame(0).is_ok());
+
+    assert!(execute_expression(&expr, &mut stack, &function_store, &mut data_store).is_ok());
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 42u32.into());
+}
+
+#[test]
+fn test_control_depth_limit() {
+    let expr = write_nested_blocks(11);
+    let mut stack = Stack::with_limits(StackLimits {
+        max_control_depth: 10,
+        ..StackLimits::default()
+    });
+    let (function_store, mut data_store) = make_test_store();
+    assert!(stack.push_test_frame(0).is_ok());
+
+    let error = execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+        .err()
+        .unwrap();
+    assert!(matches!(
+        error.downcast_ref::<WasmError>(),
+        Some(WasmError::ExhaustionError(_))
+    ));
+}
+
+#[test]
+fn test_fuel_stops_infinite_loop() {
+    let mut expr = make_expression_writer();
+    let mut loop_expr = expr.write_block_instruction(Opcode::Loop, BlockType::None);
+    loop_expr.write_single_leb_instruction(Opcode::Br, 0);
+    expr = loop_expr.do_end();
+
+    let mut stack = Stack::new();
+    let (function_store, mut data_store) = make_test_store();
+    assert!(stack.push_test_frame(0).is_ok());
+    stack.set_fuel(Some(1000));
+
+    let error = execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+        .err()
+        .unwrap();
+    assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::OutOfFuel));
+    assert_eq!(stack.remaining_fuel(), Some(0));
+}
+
+// Globals used by the nested branch tests to record how control moved
+const OUTER_ENTRIES: u64 = 0;
+const INNER_ENTRIES: u64 = 1;
+const LEFT_INNER: u64 = 2;
+const LEFT_OUTER: u64 = 3;
+
+fn increment_global(expr: &mut ExpressionWriter, global_index: u64) {
+    expr.write_single_leb_instruction(Opcode::GlobalGet, global_index);
+    expr.write_const_instruction(1_u32);
+    expr.write_single_byte_instruction(Opcode::I32Add);
+    expr.write_single_leb_instruction(Opcode::GlobalSet, global_index);
+}
+
+// Two nested blocks, each of which is a block or a loop, with a branch in the inner one.
+// The branch is only taken for the first two times through the inner block, so that
+// branches back to a loop eventually stop.
+fn write_nested_branch(
+    outer: Opcode,
+    inner: Opcode,
+    branch: Opcode,
+    depth: u64,
+) -> ExpressionWriter {
+    let expr = make_expression_writer();
+    let mut outer_expr = expr.write_block_instruction(outer, BlockType::None);
+    increment_global(&mut outer_expr, OUTER_ENTRIES);
+
+    let mut inner_expr = outer_expr.write_block_instruction(inner, BlockType::None);
+    increment_global(&mut inner_expr, INNER_ENTRIES);
+
+    inner_expr.write_single_leb_instruction(Opcode::GlobalGet, INNER_ENTRIES);
+    inner_expr.write_const_instruction(3_u32);
+    inner_expr.write_single_byte_instruction(Opcode::I32LtU);
+    if branch == Opcode::BrIf {
+        inner_expr.write_single_leb_instruction(Opcode::BrIf, depth);
+    } else {
+        // The if adds a label of its own, so the branch has to go one further
+        let mut if_expr = inner_expr.write_block_instruction(Opcode::If, BlockType::None);
+        if_expr.write_single_leb_instruction(Opcode::Br, depth + 1);
+        inner_expr = if_expr.do_end();
+    }
+
+    let mut outer_expr = inner_expr.do_end();
+    increment_global(&mut outer_expr, LEFT_INNER);
+
+    let mut expr = outer_expr.do_end();
+    increment_global(&mut expr, LEFT_OUTER);
+    expr
+}
+
+#[test]
+fn test_nested_branches() {
+    // The counts of outer entries, inner entries, falls out of the inner block and falls
+    // out of the outer block for each combination. A branch to a block leaves it, and a
+    // branch to a loop goes round it again. A branch to depth 2 returns from the function.
+    let expectations: &[(Opcode, Opcode, u64, [u32; 4])] = &[
+        (Opcode::Block, Opcode::Block, 0, [1, 1, 1, 1]),
+        (Opcode::Block, Opcode::Loop, 0, [1, 3, 1, 1]),
+        (Opcode::Loop, Opcode::Block, 0, [1, 1, 1, 1]),
+        (Opcode::Loop, Opcode::Loop, 0, [1, 3, 1, 1]),
+        (Opcode::Block, Opcode::Block, 1, [1, 1, 0, 1]),
+        (Opcode::Block, Opcode::Loop, 1, [1, 1, 0, 1]),
+        (Opcode::Loop, Opcode::Block, 1, [3, 3, 1, 1]),
+        (Opcode::Loop, Opcode::Loop, 1, [3, 3, 1, 1]),
+        (Opcode::Block, Opcode::Block, 2, [1, 1, 0, 0]),
+        (Opcode::Block, Opcode::Loop, 2, [1, 1, 0, 0]),
+        (Opcode::Loop, Opcode::Block, 2, [1, 1, 0, 0]),
+        (Opcode::Loop, Opcode::Loop, 2, [1, 1, 0, 0]),
+    ];
+
+    for &(outer, inner, depth, expected) in expectations {
+        for &branch in &[Opcode::Br, Opcode::BrIf] {
+            let expr = write_nested_branch(outer, inner, branch, depth);
+
+            let mut stack = Stack::new();
+            let (function_store, mut data_store) = make_test_store();
+            for _ in 0..4 {
+                data_store.add_global(0_u32.into());
+            }
+            assert!(stack.push_test_frame(0).is_ok());
+
+            assert!(
+                execute_expression(&expr, &mut stack, &function_store, &mut data_store).is_ok()
+            );
+            let expected: Vec<StackEntry> = expected.iter().map(|&count| count.into()).collect();
+            assert_eq!(
+                data_store.globals(),
+                &expected[..],
+                "{:?} to depth {} from {:?} in {:?}",
+                branch,
+                depth,
+                inner,
+                outer
+            );
+            assert_eq!(stack.working_count(), 0);
+        }
+    }
+}
+
+#[test]
+fn test_branch_out_of_loop_carries_value() {
+    // (block (result i32)
+    //   (loop
+    //     counter += 1
+    //     (br_if 1 (i32.const 7) (i32.ge_u (counter) (i32.const 3)))
+    //     drop
+    //     br 0)
+    //   i32.const 0)
+    let expr = make_expression_writer();
+    let block_expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    let mut loop_expr = block_expr.write_block_instruction(Opcode::Loop, BlockType::None);
+    increment_global(&mut loop_expr, 0);
+    loop_expr.write_const_instruction(7_u32);
+    loop_expr.write_single_leb_instruction(Opcode::GlobalGet, 0);
+    loop_expr.write_const_instruction(3_u32);
+    loop_expr.write_single_byte_instruction(Opcode::I32GeU);
+    loop_expr.write_single_leb_instruction(Opcode::BrIf, 1);
+    loop_expr.write_single_byte_instruction(Opcode::Drop);
+    loop_expr.write_single_leb_instruction(Opcode::Br, 0);
+    let mut block_expr = loop_expr.do_end();
+    block_expr.write_const_instruction(0_u32);
+    let expr = block_expr.do_end();
+
+    let mut stack = Stack::new();
+    let (function_store, mut data_store) = make_test_store();
+    data_store.add_global(0_u32.into());
+    assert!(stack.push_test_frame(0).is_ok());
+
+    assert!(execute_expression(&expr, &mut stack, &function_store, &mut data_store).is_ok());
+    assert_eq!(stack.working_count(), 1);
+    assert_eq!(stack.working_top(1)[0], 7_u32.into());
+    assert_eq!(data_store.globals(), &[3_u32.into()]);
+}
+
+#[test]
+fn test_multi_value_block() {
+    let mut stack = Stack::new();
+    let (mut function_store, mut data_store) = make_test_store();
+    let pair = vec![ValueType::I32, ValueType::I32];
+    function_store.set_func_types(vec![FuncType::new(pair.clone(), pair.clone())]);
+
+    // (block (param i32 i32) (result i32 i32) i32.add i32.const 10)
+    let mut func_writer = make_expression_writer();
+    func_writer.write_const_instruction(3_i32);
+    func_writer.write_const_instruction(4_i32);
+    let mut block_writer =
+        func_writer.write_block_instruction(Opcode::Block, BlockType::TypeIdx(0));
+    block_writer.write_single_byte_instruction(Opcode::I32Add);
+    block_writer.write_const_instruction(10_i32);
+    let func_writer = block_writer.do_end();
+
+    function_store.add_function(func_writer, FuncType::new(vec![], pair), vec![]);
+
+    let mut test_writer = make_expression_writer();
+    test_writer.write_single_leb_instruction(Opcode::Call, 0);
+
+    assert!(execute_expression(&test_writer, &mut stack, &function_store, &mut data_store).is_ok());
+    assert_eq!(stack.working_count(), 2);
+    assert_eq!(stack.working_top(2), &[7_i32.into(), 10_i32.into()]);
+}
+
+#[test]
+fn test_loop_with_parameter() {
+    let mut stack = Stack::new();
+    let (mut function_store, mut data_store) = make_test_store();
+    data_store.add_global(0_u32.into());
+    function_store.set_func_types(vec![FuncType::new(
+        vec![ValueType::I32],
+        vec![ValueType::I32],
+    )]);
+
+    // Count down from 5, passing the count back round the loop as its parameter, with
+    // a value beneath the loop which it mustn't disturb
+    let mut func_writer = make_expression_writer();
+    func_writer.write_const_instruction(99_i32);
+    func_writer.write_const_instruction(5_i32);
+    let mut loop_writer = func_writer.write_block_instruction(Opcode::Loop, BlockType::TypeIdx(0));
+    increment_global(&mut loop_writer, 0);
+    loop_writer.write_const_instruction(1_i32);
+    loop_writer.write_single_byte_instruction(Opcode::I32Sub);
+    loop_writer.write_single_leb_instruction(Opcode::LocalTee, 0);
+    loop_writer.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_writer.write_single_leb_instruction(Opcode::BrIf, 0);
+    let func_writer = loop_writer.do_end();
+
+    function_store.add_function(
+        func_writer,
+        FuncType::new(vec![], vec![ValueType::I32, ValueType::I32]),
+        vec![Locals::new(1, ValueType::I32)],
+    );
+
+    let mut test_writer = make_expression_writer();
+    test_writer.write_single_leb_instruction(Opcode::Call, 0);
+
+    assert!(execute_expression(&test_writer, &mut stack, &function_store, &mut data_store).is_ok());
+    assert_eq!(stack.working_top(2), &[99_i32.into(), 0_i32.into()]);
+    assert_eq!(data_store.globals(), &[5_u32.into()]);
+}
+
+// These bodies would fail validation, but the interpreter must still trap rather than
+// reach past a label or panic when it is handed them
+fn assert_stack_underflow(expr: ExpressionWriter) {
+    let mut stack = Stack::new();
+    let (function_store, mut data_store) = make_test_store();
+    assert!(stack.push_test_frame(0).is_ok());
+    stack.push(7_u32.into());
+
+    let error = execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+        .err()
+        .unwrap();
+    assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::StackUnderflow));
+}
+
+#[test]
+fn test_block_cannot_consume_values_below_its_label() {
+    let expr = make_expression_writer();
+    let mut block_expr = expr.write_block_instruction(Opcode::Block, BlockType::None);
+    block_expr.write_single_byte_instruction(Opcode::Drop);
+    assert_stack_underflow(block_expr.do_end());
+
+    let expr = make_expression_writer();
+    let mut loop_expr = expr.write_block_instruction(Opcode::Loop, BlockType::None);
+    loop_expr.write_const_instruction(1_u32);
+    loop_expr.write_single_byte_instruction(Opcode::I32Add);
+    assert_stack_underflow(loop_expr.do_end());
+}
+
+#[test]
+fn test_block_without_its_results() {
+    // Reaching the end without the result
+    let expr = make_expression_writer();
+    let block_expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    assert_stack_underflow(block_expr.do_end());
+
+    // Branching out without the result
+    let expr = make_expression_writer();
+    let mut block_expr = expr.write_block_instruction(Opcode::Block, BlockType::I32);
+    block_expr.write_single_leb_instruction(Opcode::Br, 0);
+    assert_stack_underflow(block_expr.do_end());
+
+    // Reaching the else without the result
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(1_u32);
+    let if_expr = expr.write_block_instruction(Opcode::If, BlockType::I32);
+    let mut else_expr = if_expr.do_else();
+    else_expr.write_const_instruction(2_u32);
+    assert_stack_underflow(else_expr.do_end());
+
+    // Reaching the end of the else without the result
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(0_u32);
+    let mut if_expr = expr.write_block_instruction(Opcode::If, BlockType::I32);
+    if_expr.write_const_instruction(1_u32);
+    let else_expr = if_expr.do_else();
+    assert_stack_underflow(else_expr.do_end());
+}
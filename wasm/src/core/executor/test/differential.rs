@@ -0,0 +1,294 @@
+//! Differential testing for executor changes. Each program is run on two engines against
+//! identical stores, and everything observable at the function boundary has to match:
+//! how execution finished, the values left on the stack, memory and globals. Swap a new
+//! engine in as the candidate to check it against the current one while it is migrated.
+
+use anyhow::Result;
+
+use crate::core::{stack_entry::StackEntry, BlockType, FuncType, Locals, Stack, TrapKind};
+use crate::core::{ValueType, WasmError};
+use crate::parser::Opcode;
+
+use crate::core::{compiled::CompiledFunction, Shared};
+
+use super::super::execute_core::execute_compiled;
+use super::instruction_generator::{make_expression_writer, ExpressionWriter};
+use super::recursive_executor::execute_expression_recursive;
+use super::test_store::*;
+
+pub type Engine = fn(
+    &ExpressionWriter,
+    &[FuncType],
+    &mut Stack,
+    &TestFunctionStore,
+    &mut TestDataStore,
+) -> Result<()>;
+
+const REFERENCE_ENGINE: Engine = execute_expression_recursive;
+const CANDIDATE_ENGINE: Engine = execute_expression_compiled;
+
+fn execute_expression_compiled(
+    expr: &ExpressionWriter,
+    types: &[FuncType],
+    stack: &mut Stack,
+    function_store: &TestFunctionStore,
+    data_store: &mut TestDataStore,
+) -> Result<()> {
+    let function = Shared::new(CompiledFunction::new(expr, types)?);
+    execute_compiled(&function, stack, function_store, data_store)
+}
+
+#[derive(Debug, PartialEq)]
+enum Completion {
+    // Floats are compared by bit pattern so that NaNs compare equal to themselves
+    Returned(Vec<(u8, u64)>),
+    Trapped(TrapKind),
+    Failed,
+}
+
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    completion: Completion,
+    globals: Vec<(u8, u64)>,
+    memory: Vec<u8>,
+}
+
+fn entry_bits(entry: &StackEntry) -> (u8, u64) {
+    match entry {
+        StackEntry::I32Entry(i) => (0, u64::from(*i)),
+        StackEntry::I64Entry(i) => (1, *i),
+        StackEntry::F32Entry(f) => (2, u64::from(f.to_bits())),
+        StackEntry::F64Entry(f) => (3, f.to_bits()),
+        StackEntry::FuncRefEntry(r) => (4, r.map_or(u64::MAX, u64::from)),
+        StackEntry::ExternRefEntry(r) => (5, r.map_or(u64::MAX, u64::from)),
+    }
+}
+
+fn run_engine(
+    engine: Engine,
+    expr: &ExpressionWriter,
+    types: &[FuncType],
+    local_count: u32,
+    setup: &dyn Fn(&mut TestFunctionStore, &mut TestDataStore),
+) -> Outcome {
+    let mut stack = Stack::new();
+    let (mut function_store, mut data_store) = make_test_store();
+    setup(&mut function_store, &mut data_store);
+    assert!(stack.push_test_frame(local_count).is_ok());
+
+    let completion = match engine(expr, types, &mut stack, &function_store, &mut data_store) {
+        Ok(()) => Completion::Returned(
+            stack
+                .working_top(stack.working_count())
+                .iter()
+                .map(entry_bits)
+                .collect(),
+        ),
+        Err(e) => match e.downcast_ref::<WasmError>() {
+            Some(WasmError::Trap(kind)) => Completion::Trapped(*kind),
+            _ => Completion::Failed,
+        },
+    };
+
+    Outcome {
+        completion,
+        globals: data_store.globals().iter().map(entry_bits).collect(),
+        memory: data_store.memory_contents(),
+    }
+}
+
+fn assert_engines_agree(
+    expr: &ExpressionWriter,
+    local_count: u32,
+    setup: &dyn Fn(&mut TestFunctionStore, &mut TestDataStore),
+) {
+    assert_engines_agree_with_types(expr, &[], local_count, setup)
+}
+
+// For programs with blocks whose type is a type index
+fn assert_engines_agree_with_types(
+    expr: &ExpressionWriter,
+    types: &[FuncType],
+    local_count: u32,
+    setup: &dyn Fn(&mut TestFunctionStore, &mut TestDataStore),
+) {
+    let reference = run_engine(REFERENCE_ENGINE, expr, types, local_count, setup);
+    let candidate = run_engine(CANDIDATE_ENGINE, expr, types, local_count, setup);
+
+    // A program the reference engine can't run at all doesn't tell us anything
+    assert_ne!(reference.completion, Completion::Failed);
+
+    // Comparing the whole memory makes for unreadable failures, so check the rest first
+    assert_eq!(reference.completion, candidate.completion);
+    assert_eq!(reference.globals, candidate.globals);
+    assert!(
+        reference.memory == candidate.memory,
+        "Memory contents differ"
+    );
+}
+
+fn no_setup(_function_store: &mut TestFunctionStore, _data_store: &mut TestDataStore) {}
+
+fn memory_setup(_function_store: &mut TestFunctionStore, data_store: &mut TestDataStore) {
+    data_store.enable_memory();
+    data_store.add_global(0u32.into());
+}
+
+#[test]
+fn test_engines_agree_on_loops() {
+    // Sum the numbers from 10 down to 1 into global 0, storing each partial sum in memory
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(10u32);
+    expr.write_single_leb_instruction(Opcode::LocalSet, 0);
+
+    let mut loop_expr = expr.write_block_instruction(Opcode::Loop, BlockType::None);
+    loop_expr.write_single_leb_instruction(Opcode::GlobalGet, 0);
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_single_byte_instruction(Opcode::I32Add);
+    loop_expr.write_single_leb_instruction(Opcode::GlobalSet, 0);
+
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_const_instruction(4u32);
+    loop_expr.write_single_byte_instruction(Opcode::I32Mul);
+    loop_expr.write_single_leb_instruction(Opcode::GlobalGet, 0);
+    loop_expr.write_memory_instruction(Opcode::I32Store, 0, 0);
+
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_const_instruction(1u32);
+    loop_expr.write_single_byte_instruction(Opcode::I32Sub);
+    loop_expr.write_single_leb_instruction(Opcode::LocalTee, 0);
+    loop_expr.write_single_leb_instruction(Opcode::BrIf, 0);
+    let mut expr = loop_expr.do_end();
+    expr.write_single_leb_instruction(Opcode::GlobalGet, 0);
+
+    assert_engines_agree(&expr, 1, &memory_setup);
+}
+
+#[test]
+fn test_engines_agree_on_branches() {
+    // Nested blocks exited with br_table, for each possible target and one out of range
+    for target in 0..4u32 {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction(1u64);
+        let mut outer = expr.write_block_instruction(Opcode::Block, BlockType::None);
+        let mut inner = outer.write_block_instruction(Opcode::Block, BlockType::None);
+        let mut innermost = inner.write_block_instruction(Opcode::Block, BlockType::None);
+        innermost.write_const_instruction(target);
+        innermost.write_branch_table(Opcode::BrTable, &[0, 1, 2]);
+        inner = innermost.do_end();
+        inner.write_const_instruction(2u32);
+        inner.write_single_leb_instruction(Opcode::LocalSet, 0);
+        outer = inner.do_end();
+        outer.write_const_instruction(3u32);
+        outer.write_single_leb_instruction(Opcode::LocalSet, 0);
+        expr = outer.do_end();
+        expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+
+        assert_engines_agree(&expr, 1, &no_setup);
+    }
+}
+
+#[test]
+fn test_engines_agree_on_calls() {
+    let setup = |function_store: &mut TestFunctionStore, _data_store: &mut TestDataStore| {
+        let mut func = make_expression_writer();
+        func.write_single_leb_instruction(Opcode::LocalGet, 0);
+        func.write_single_leb_instruction(Opcode::LocalGet, 1);
+        func.write_single_byte_instruction(Opcode::F64Div);
+        func.write_single_byte_instruction(Opcode::Return);
+        func.write_const_instruction(0f64);
+        function_store.add_function(
+            func,
+            FuncType::new(vec![ValueType::F64, ValueType::F64], vec![ValueType::F64]),
+            vec![Locals::new(0, ValueType::I32)],
+        );
+    };
+
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(1f64);
+    expr.write_const_instruction(3f64);
+    expr.write_single_leb_instruction(Opcode::Call, 0);
+    expr.write_const_instruction(0f64);
+    expr.write_const_instruction(0f64);
+    expr.write_single_leb_instruction(Opcode::Call, 0);
+
+    assert_engines_agree(&expr, 0, &setup);
+}
+
+#[test]
+fn test_engines_agree_on_traps() {
+    // The store happens before the trap, so it must be visible in both memories
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(8u32);
+    expr.write_const_instruction(0x1234_5678u32);
+    expr.write_memory_instruction(Opcode::I32Store, 0, 0);
+    expr.write_const_instruction(1u32);
+    expr.write_const_instruction(0u32);
+    expr.write_single_byte_instruction(Opcode::I32DivU);
+
+    assert_engines_agree(&expr, 0, &memory_setup);
+
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(0x10000u32);
+    expr.write_memory_instruction(Opcode::I64Load, 0, 0);
+
+    assert_engines_agree(&expr, 0, &memory_setup);
+}
+
+#[test]
+fn test_engines_agree_on_branch_arity() {
+    // Branches out of nested blocks keep only the values the target's label carries, and
+    // drop whatever else the blocks left beneath them
+    for target in 0..3u32 {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction(1u32);
+        let mut outer = expr.write_block_instruction(Opcode::Block, BlockType::I64);
+        outer.write_const_instruction(2u32);
+        let mut inner = outer.write_block_instruction(Opcode::Block, BlockType::I64);
+        inner.write_const_instruction(3u32);
+        inner.write_const_instruction(4u64);
+        inner.write_const_instruction(target);
+        inner.write_branch_table(Opcode::BrTable, &[0, 1]);
+        outer = inner.do_end();
+        outer.write_const_instruction(5u64);
+        outer.write_single_byte_instruction(Opcode::I64Add);
+        expr = outer.do_end();
+
+        assert_engines_agree(&expr, 0, &no_setup);
+    }
+
+    // Type 0 takes an i32 and gives back an i64 and an i32, and type 1 takes an i32 and
+    // gives nothing back
+    let types = [
+        FuncType::new(vec![ValueType::I32], vec![ValueType::I64, ValueType::I32]),
+        FuncType::new(vec![ValueType::I32], vec![]),
+    ];
+
+    // A loop with a parameter counts it down to zero, with each branch back to the start
+    // carrying the new count. The block around it gives back two results.
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(5u32);
+    let mut block = expr.write_block_instruction(Opcode::Block, BlockType::TypeIdx(0));
+    let mut loop_expr = block.write_block_instruction(Opcode::Loop, BlockType::TypeIdx(1));
+    loop_expr.write_single_leb_instruction(Opcode::LocalTee, 0);
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_single_byte_instruction(Opcode::I32Eqz);
+    let mut done = loop_expr.write_block_instruction(Opcode::If, BlockType::None);
+    done.write_const_instruction(7u64);
+    done.write_single_leb_instruction(Opcode::LocalGet, 0);
+    done.write_single_leb_instruction(Opcode::Br, 2);
+    loop_expr = done.do_end();
+    loop_expr.write_single_leb_instruction(Opcode::LocalGet, 0);
+    loop_expr.write_const_instruction(1u32);
+    loop_expr.write_single_byte_instruction(Opcode::I32Sub);
+    loop_expr.write_single_leb_instruction(Opcode::Br, 0);
+    block = loop_expr.do_end();
+    block.write_single_byte_instruction(Opcode::Unreachable);
+    expr = block.do_end();
+
+    assert_engines_agree_with_types(&expr, &types, 1, &no_setup);
+    assert_eq!(
+        run_engine(CANDIDATE_ENGINE, &expr, &types, 1, &no_setup).completion,
+        Completion::Returned(vec![(1, 7), (0, 0)])
+    );
+}
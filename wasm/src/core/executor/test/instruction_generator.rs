@@ -1,9 +1,11 @@
-use crate::core::{stack_entry::StackEntry, BlockType};
-use crate::parser::{InstructionCategory, InstructionSource, Opcode};
+use crate::core::{stack_entry::StackEntry, BlockType, ValueType};
+use crate::parser::{AtomicOpcode, InstructionCategory, InstructionSource, MiscOpcode, Opcode};
 
 use std::convert::TryInto;
 
 fn write_leb(expr_bytes: &mut Vec<u8>, val: u64, signed: bool) {
+    let is_positive = !signed || 0 == (val & 0x8000000000000000);
+
     let mut encoded_bytes: [u8; 10] = [
         (0x80 | (val & 0x7f)).try_into().unwrap(),
         (0x80 | ((val >> 7) & 0x7f)).try_into().unwrap(),
@@ -14,11 +16,14 @@ fn write_leb(expr_bytes: &mut Vec<u8>, val: u64, signed: bool) {
         (0x80 | ((val >> 42) & 0x7f)).try_into().unwrap(),
         (0x80 | ((val >> 49) & 0x7f)).try_into().unwrap(),
         (0x80 | ((val >> 56) & 0x7f)).try_into().unwrap(),
-        ((val >> 63) & 0x7f).try_into().unwrap(),
+        // The last byte holds the top bit, and the rest of it is sign extension
+        if is_positive {
+            0x01 & (val >> 63) as u8
+        } else {
+            0x7F
+        },
     ];
 
-    let is_positive = !signed || 0 == (val & 0x8000000000000000);
-
     let mut required_length: usize = 10;
     while required_length > 1 {
         let last_byte = encoded_bytes[required_length - 1];
@@ -33,10 +38,6 @@ fn write_leb(expr_bytes: &mut Vec<u8>, val: u64, signed: bool) {
         // when it gets sign extended it will go wrong
         let can_drop_byte = if is_positive {
             last_byte == 0x00 && (penultimate_byte & 0xC0) == 0x80
-        } else if required_length == 10 {
-            // We have a special case for the highest byte of a negative number just to cope with the
-            // single set bit
-            last_byte == 0x01 && (penultimate_byte & 0xC0) == 0xC0
         } else {
             last_byte == 0x7F && (penultimate_byte & 0xC0) == 0xC0
         };
@@ -98,8 +99,9 @@ fn write_opcode(expr_bytes: &mut ExpressionWriter, opcode: Opcode) {
 fn write_const_instruction(expr_bytes: &mut ExpressionWriter, val: StackEntry) {
     match val {
         StackEntry::I32Entry(i) => {
+            // The immediate is a signed 32 bit LEB, so it is sign extended to be written
             expr_bytes.append_byte(Opcode::I32Const.into());
-            write_leb(&mut expr_bytes.bytes, i.into(), true);
+            write_leb(&mut expr_bytes.bytes, i as i32 as i64 as u64, true);
         }
         StackEntry::I64Entry(i) => {
             expr_bytes.append_byte(Opcode::I64Const.into());
@@ -113,6 +115,21 @@ fn write_const_instruction(expr_bytes: &mut ExpressionWriter, val: StackEntry) {
             expr_bytes.append_byte(Opcode::F64Const.into());
             expr_bytes.append_bytes(&i.to_le_bytes());
         }
+        StackEntry::FuncRefEntry(Some(idx)) => {
+            expr_bytes.append_byte(Opcode::RefFunc.into());
+            write_leb(&mut expr_bytes.bytes, idx.into(), false);
+        }
+        StackEntry::FuncRefEntry(None) => {
+            expr_bytes.append_byte(Opcode::RefNull.into());
+            expr_bytes.append_byte(ValueType::FuncRef as u8);
+        }
+        StackEntry::ExternRefEntry(None) => {
+            expr_bytes.append_byte(Opcode::RefNull.into());
+            expr_bytes.append_byte(ValueType::ExternRef as u8);
+        }
+        StackEntry::ExternRefEntry(Some(_)) => {
+            panic!("Only the host can create non-null external references")
+        }
     }
 }
 
@@ -170,6 +187,51 @@ impl ExpressionWriter {
         write_leb(&mut self.bytes, val2, false);
     }
 
+    // The memory index is only written for memories other than the first, which is how an
+    // MVP module would encode the same access
+    pub fn write_memory_instruction(&mut self, opcode: Opcode, memory: u32, offset: u32) {
+        assert!(InstructionCategory::from_opcode(opcode) == InstructionCategory::MemArg);
+        write_opcode(self, opcode);
+        if memory == 0 {
+            write_leb(&mut self.bytes, 0, false);
+        } else {
+            write_leb(&mut self.bytes, 0x40, false);
+            write_leb(&mut self.bytes, memory.into(), false);
+        }
+        write_leb(&mut self.bytes, offset.into(), false);
+    }
+
+    pub fn write_misc_instruction(&mut self, opcode: MiscOpcode, args: &[u64]) {
+        let expected_args = match InstructionCategory::from_misc_opcode(opcode) {
+            InstructionCategory::SingleByte => 0,
+            InstructionCategory::SingleLebInteger => 1,
+            InstructionCategory::TwoLebInteger => 2,
+            _ => panic!("Invalid instruction category for prefixed instruction"),
+        };
+        assert_eq!(args.len(), expected_args);
+
+        write_opcode(self, Opcode::MiscPrefix);
+        write_leb(&mut self.bytes, u32::from(opcode).into(), false);
+        for arg in args {
+            write_leb(&mut self.bytes, *arg, false);
+        }
+    }
+
+    // Atomic accesses have to be naturally aligned, so the alignment hint is always that
+    pub fn write_atomic_instruction(&mut self, opcode: AtomicOpcode, offset: u32) {
+        write_opcode(self, Opcode::AtomicPrefix);
+        write_leb(&mut self.bytes, u32::from(opcode).into(), false);
+        match InstructionCategory::from_atomic_opcode(opcode) {
+            InstructionCategory::SingleLebInteger => write_leb(&mut self.bytes, 0, false),
+            InstructionCategory::MemArg => {
+                let alignment = opcode.natural_alignment().unwrap_or(0);
+                write_leb(&mut self.bytes, alignment.into(), false);
+                write_leb(&mut self.bytes, offset.into(), false);
+            }
+            _ => panic!("Invalid instruction category for atomic instruction"),
+        }
+    }
+
     pub fn write_branch_table(&mut self, opcode: Opcode, table: &[u64]) {
         assert!(InstructionCategory::from_opcode(opcode) == InstructionCategory::BranchTable);
         assert!(table.len() > 0);
@@ -184,10 +246,13 @@ impl ExpressionWriter {
     pub fn write_block_instruction(mut self, opcode: Opcode, block_type: BlockType) -> Self {
         match InstructionCategory::from_opcode(opcode) {
             InstructionCategory::Block(allow_else) => {
-                let require_else = allow_else && block_type != BlockType::None;
+                // An if which produces a value needs an else to produce it when the condition
+                // is false. Blocks with a type index might not, depending on the type.
+                let require_else =
+                    allow_else && !matches!(block_type, BlockType::None | BlockType::TypeIdx(_));
 
                 write_opcode(&mut self, opcode);
-                self.append_byte(block_type.into());
+                write_leb(&mut self.bytes, block_type.to_s33() as u64, true);
 
                 self.state_stack.push(ExpressionWriterStateStack {
                     allow_else,
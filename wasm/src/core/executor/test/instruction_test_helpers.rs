@@ -1,8 +1,8 @@
 use std::convert::TryInto;
 
 use super::super::store_access::{DataStore, FunctionStore};
-use crate::core::{stack_entry::StackEntry, Stack};
-use crate::parser::{InstructionSource, Opcode};
+use crate::core::{stack_entry::StackEntry, Stack, TrapKind, WasmError};
+use crate::parser::{InstructionSource, MiscOpcode, Opcode};
 
 use super::instruction_generator::make_expression_writer;
 use super::test_store::*;
@@ -106,6 +106,24 @@ macro_rules! test_unary_opcode {
     };
 }
 
+pub fn test_misc_unary_opcode_impl(
+    p1: impl Into<StackEntry>,
+    opcode: MiscOpcode,
+) -> Option<StackEntry> {
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(p1.into());
+    expr.write_misc_instruction(opcode, &[]);
+
+    test_single_return_expression_impl(expr)
+}
+
+#[macro_export]
+macro_rules! test_misc_unary_opcode {
+    ($p1:expr, $opcode:expr, $r:expr) => {
+        assert_eq!(test_misc_unary_opcode_impl($p1, $opcode), Some($r.into()));
+    };
+}
+
 pub fn test_binary_opcode_impl(
     p1: impl Into<StackEntry>,
     p2: impl Into<StackEntry>,
@@ -127,6 +145,38 @@ macro_rules! test_binary_opcode {
     };
 }
 
+pub fn test_trap_expression_impl(expr: impl InstructionSource) -> Option<TrapKind> {
+    let mut stack = Stack::new();
+    let (function_store, mut data_store) = make_test_store();
+    assert!(stack.push_test_frame(0).is_ok());
+
+    match execute_expression(&expr, &mut stack, &function_store, &mut data_store) {
+        Err(e) => WasmError::trap_kind(&e),
+        Ok(_) => None,
+    }
+}
+
+#[macro_export]
+macro_rules! test_unary_opcode_traps {
+    ($p1:expr, $opcode:expr, $kind:expr) => {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction($p1);
+        expr.write_single_byte_instruction($opcode);
+        assert_eq!(test_trap_expression_impl(expr), Some($kind));
+    };
+}
+
+#[macro_export]
+macro_rules! test_binary_opcode_traps {
+    ($p1:expr, $p2:expr, $opcode:expr, $kind:expr) => {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction($p1);
+        expr.write_const_instruction($p2);
+        expr.write_single_byte_instruction($opcode);
+        assert_eq!(test_trap_expression_impl(expr), Some($kind));
+    };
+}
+
 fn memory_load_expression(
     opcode: Opcode,
     address: u32,
@@ -135,7 +185,7 @@ fn memory_load_expression(
 ) -> impl InstructionSource {
     let mut expr = make_expression_writer();
     expr.write_const_instruction(address);
-    expr.write_two_leb_instruction(opcode, mem_idx.try_into().unwrap(), offset.into());
+    expr.write_memory_instruction(opcode, mem_idx.try_into().unwrap(), offset);
     expr
 }
 
@@ -190,7 +240,7 @@ fn memory_store_expression(
     let mut expr = make_expression_writer();
     expr.write_const_instruction(address);
     expr.write_const_instruction(value);
-    expr.write_two_leb_instruction(opcode, mem_idx.try_into().unwrap(), offset.into());
+    expr.write_memory_instruction(opcode, mem_idx.try_into().unwrap(), offset);
     expr
 }
 
@@ -1,8 +1,12 @@
-use crate::core::{executor::execute_expression, stack_entry::StackEntry, Stack};
-use crate::parser::Opcode;
+use crate::core::{
+    executor::{evaluate_constant_expression, execute_expression},
+    stack_entry::StackEntry,
+    FuncType, SharedMemory, Stack, Table, TrapKind, ValueType, WasmError,
+};
+use crate::parser::{AtomicOpcode, MiscOpcode, Opcode};
 
 use super::super::store_access::{DataStore, FunctionStore};
-use super::instruction_generator::make_expression_writer;
+use super::instruction_generator::{make_expression_writer, ExpressionWriter};
 use super::instruction_test_helpers::*;
 use super::test_store::*;
 
@@ -71,6 +75,76 @@ fn test_select_op() {
     assert_eq!(stack.working_top(1)[0], 69i32.into());
 }
 
+#[test]
+fn test_typed_select_op() {
+    let run = |write: &dyn Fn(&mut ExpressionWriter)| {
+        let mut expr = make_expression_writer();
+        write(&mut expr);
+        let mut stack = Stack::new();
+        let (function_store, mut data_store) = make_test_store();
+        execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+            .map(|_| stack.working_top(stack.working_count()).to_vec())
+            .map_err(|e| format!("{:#}", e))
+    };
+
+    // Typed select chooses between references, which plain select refuses
+    let select_refs = |opcode: Opcode, selector: i32| {
+        move |expr: &mut ExpressionWriter| {
+            expr.write_single_leb_instruction(Opcode::RefFunc, 3);
+            expr.write_single_leb_instruction(Opcode::RefNull, ValueType::FuncRef as u64);
+            expr.write_const_instruction(selector);
+            if opcode == Opcode::SelectTyped {
+                expr.write_two_leb_instruction(opcode, 1, ValueType::FuncRef as u64);
+            } else {
+                expr.write_single_byte_instruction(opcode);
+            }
+        }
+    };
+    assert_eq!(
+        run(&select_refs(Opcode::SelectTyped, 1)),
+        Ok(vec![StackEntry::FuncRefEntry(Some(3))])
+    );
+    assert_eq!(
+        run(&select_refs(Opcode::SelectTyped, 0)),
+        Ok(vec![StackEntry::FuncRefEntry(None)])
+    );
+    assert!(run(&select_refs(Opcode::Select, 1))
+        .unwrap_err()
+        .contains("Select without a type"));
+
+    // It works for numbers too, as long as they are the type it names
+    let select_numbers = |value_type: ValueType| {
+        move |expr: &mut ExpressionWriter| {
+            expr.write_const_instruction(1.5f64);
+            expr.write_const_instruction(2.5f64);
+            expr.write_const_instruction(0i32);
+            expr.write_two_leb_instruction(Opcode::SelectTyped, 1, value_type.clone() as u64);
+        }
+    };
+    assert_eq!(
+        run(&select_numbers(ValueType::F64)),
+        Ok(vec![2.5f64.into()])
+    );
+    assert!(run(&select_numbers(ValueType::I64))
+        .unwrap_err()
+        .contains("Select of I64 given F64 and F64"));
+
+    // The vector of types must hold exactly one valid type
+    for (count, value_type) in [
+        (0, ValueType::I32 as u64),
+        (2, ValueType::I32 as u64),
+        (1, 0x40),
+    ] {
+        assert!(run(&|expr: &mut ExpressionWriter| {
+            expr.write_const_instruction(1i32);
+            expr.write_const_instruction(2i32);
+            expr.write_const_instruction(0i32);
+            expr.write_two_leb_instruction(Opcode::SelectTyped, count, value_type);
+        })
+        .is_err());
+    }
+}
+
 #[test]
 fn test_basic_ops() {
     test_constant_opcode!(0i32);
@@ -87,9 +161,20 @@ fn test_basic_ops() {
     test_constant_opcode!(0u64);
     test_constant_opcode!(0.0f32);
     test_constant_opcode!(0.0f64);
-    // TODOTODOTODO - should test for integers that are too big for the opcodes to make sure they're handled properly.
-    // I haven't written that test because currently it panics in the instruction accumulator which is obviously not the
-    // right thing to do.
+
+    // Integers which are too big for the opcode, or encoded with too many bytes, are errors
+    for bytes in [
+        &[0x41, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F][..],
+        &[0x41, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00],
+        &[
+            0x42, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01,
+        ],
+        &[0x20, 0x80, 0x80, 0x80, 0x80, 0x10],
+    ] {
+        let mut stack = Stack::new();
+        let (function_store, mut data_store) = make_test_store();
+        assert!(execute_expression(&bytes, &mut stack, &function_store, &mut data_store).is_err());
+    }
 
     test_unary_opcode!(7i32, Opcode::I32Eqz, 0u32);
     test_unary_opcode!(0i32, Opcode::I32Eqz, 1u32);
@@ -230,6 +315,17 @@ fn test_basic_ops() {
         0x8000000000000000u64
     );
 
+    // Counts of 32 and more shift the whole 64 bits
+    test_binary_opcode!(1u64, 33u64, Opcode::I64Shl, 0x0000000200000000u64);
+    test_binary_opcode!(
+        0x8000000000000000u64,
+        33u64,
+        Opcode::I64ShrS,
+        0xFFFFFFFFC0000000u64
+    );
+    test_binary_opcode!(1u64, 65u64, Opcode::I64Rotr, 0x8000000000000000u64);
+    test_binary_opcode!(0x80000000u32, -1i32, Opcode::I32ShrS, 0xFFFFFFFFu32);
+
     test_unary_opcode!(7.0f32, Opcode::F32Abs, 7.0f32);
     test_unary_opcode!(-7.0f32, Opcode::F32Abs, 7.0f32);
     test_unary_opcode!(7.0f32, Opcode::F32Neg, -7.0f32);
@@ -240,6 +336,11 @@ fn test_basic_ops() {
     test_unary_opcode!(-7.1f32, Opcode::F32Floor, -8.0f32);
     test_unary_opcode!(7.1f32, Opcode::F32Nearest, 7.0f32);
     test_unary_opcode!(-7.1f32, Opcode::F32Nearest, -7.0f32);
+    test_unary_opcode!(0.5f32, Opcode::F32Nearest, 0.0f32);
+    test_unary_opcode!(2.5f32, Opcode::F32Nearest, 2.0f32);
+    test_unary_opcode!(3.5f32, Opcode::F32Nearest, 4.0f32);
+    test_unary_opcode!(-0.5f32, Opcode::F32Nearest, -0.0f32);
+    test_unary_opcode!(-2.5f32, Opcode::F32Nearest, -2.0f32);
     test_unary_opcode!(64.0f32, Opcode::F32Sqrt, 8.0f32);
     test_binary_opcode!(7.0f32, 8.0f32, Opcode::F32Add, 15.0f32);
     test_binary_opcode!(7.0f32, -1.0f32, Opcode::F32Add, 6.0f32);
@@ -265,6 +366,11 @@ fn test_basic_ops() {
     test_unary_opcode!(-7.1f64, Opcode::F64Floor, -8.0f64);
     test_unary_opcode!(7.1f64, Opcode::F64Nearest, 7.0f64);
     test_unary_opcode!(-7.1f64, Opcode::F64Nearest, -7.0f64);
+    test_unary_opcode!(0.5f64, Opcode::F64Nearest, 0.0f64);
+    test_unary_opcode!(2.5f64, Opcode::F64Nearest, 2.0f64);
+    test_unary_opcode!(3.5f64, Opcode::F64Nearest, 4.0f64);
+    test_unary_opcode!(-0.5f64, Opcode::F64Nearest, -0.0f64);
+    test_unary_opcode!(-2.5f64, Opcode::F64Nearest, -2.0f64);
     test_unary_opcode!(64.0f64, Opcode::F64Sqrt, 8.0f64);
     test_binary_opcode!(7.0f64, 8.0f64, Opcode::F64Add, 15.0f64);
     test_binary_opcode!(7.0f64, -1.0f64, Opcode::F64Add, 6.0f64);
@@ -305,6 +411,166 @@ fn test_basic_ops() {
     test_unary_opcode!(-1.0f64, Opcode::I64ReinterpretF64, 0xbff0000000000000u64);
     test_unary_opcode!(0xbf800000u32, Opcode::F32ReinterpretI32, -1.0f32);
     test_unary_opcode!(0xbff0000000000000u64, Opcode::F64ReinterpretI64, -1.0f64);
+
+    test_unary_opcode!(0x7Fu32, Opcode::I32Extend8S, 0x7Fu32);
+    test_unary_opcode!(0x12345680u32, Opcode::I32Extend8S, 0xFFFFFF80u32);
+    test_unary_opcode!(0x7FFFu32, Opcode::I32Extend16S, 0x7FFFu32);
+    test_unary_opcode!(0x12348000u32, Opcode::I32Extend16S, 0xFFFF8000u32);
+    test_unary_opcode!(0x7Fu64, Opcode::I64Extend8S, 0x7Fu64);
+    test_unary_opcode!(
+        0x1234567890ABCDEFu64,
+        Opcode::I64Extend8S,
+        0xFFFFFFFFFFFFFFEFu64
+    );
+    test_unary_opcode!(0x1234567890AB7FFFu64, Opcode::I64Extend16S, 0x7FFFu64);
+    test_unary_opcode!(
+        0x1234567890ABCDEFu64,
+        Opcode::I64Extend16S,
+        0xFFFFFFFFFFFFCDEFu64
+    );
+    test_unary_opcode!(0x1234567870ABCDEFu64, Opcode::I64Extend32S, 0x70ABCDEFu64);
+    test_unary_opcode!(
+        0x1234567890ABCDEFu64,
+        Opcode::I64Extend32S,
+        0xFFFFFFFF90ABCDEFu64
+    );
+}
+
+#[test]
+fn test_extended_constant_expressions() {
+    let mut store = TestDataStore::new();
+    let global = store.add_global(1000i32.into()) as u64;
+
+    // (global.get 0 + 24) * 2 - 8
+    let mut expr = make_expression_writer();
+    expr.write_single_leb_instruction(Opcode::GlobalGet, global);
+    expr.write_const_instruction(24i32);
+    expr.write_single_byte_instruction(Opcode::I32Add);
+    expr.write_const_instruction(2i32);
+    expr.write_single_byte_instruction(Opcode::I32Mul);
+    expr.write_const_instruction(8i32);
+    expr.write_single_byte_instruction(Opcode::I32Sub);
+    assert_eq!(
+        evaluate_constant_expression(&expr, &store, 1).unwrap(),
+        vec![2040i32.into()]
+    );
+
+    // The 64 bit versions wrap just like they do in a function
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(i64::MAX);
+    expr.write_const_instruction(1i64);
+    expr.write_single_byte_instruction(Opcode::I64Add);
+    expr.write_const_instruction(3i64);
+    expr.write_single_byte_instruction(Opcode::I64Mul);
+    expr.write_const_instruction(1i64);
+    expr.write_single_byte_instruction(Opcode::I64Sub);
+    assert_eq!(
+        evaluate_constant_expression(&expr, &store, 1).unwrap(),
+        vec![i64::MIN.wrapping_mul(3).wrapping_sub(1).into()]
+    );
+
+    // Anything else is still refused
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(6i32);
+    expr.write_const_instruction(3i32);
+    expr.write_single_byte_instruction(Opcode::I32DivS);
+    assert!(evaluate_constant_expression(&expr, &store, 1).is_err());
+}
+
+#[test]
+fn test_nearest_keeps_sign_of_zero() {
+    // Equality can't tell the zeros apart, so look at the sign directly
+    match test_unary_opcode_impl(-0.5f32, Opcode::F32Nearest) {
+        Some(StackEntry::F32Entry(result)) => assert!(result == 0.0 && result.is_sign_negative()),
+        other => panic!("Unexpected result {:?}", other),
+    }
+    match test_unary_opcode_impl(-0.5f64, Opcode::F64Nearest) {
+        Some(StackEntry::F64Entry(result)) => assert!(result == 0.0 && result.is_sign_negative()),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_min_max_nan_and_signed_zero() {
+    fn f32_result(entry: Option<StackEntry>) -> f32 {
+        match entry {
+            Some(StackEntry::F32Entry(result)) => result,
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+    fn f64_result(entry: Option<StackEntry>) -> f64 {
+        match entry {
+            Some(StackEntry::F64Entry(result)) => result,
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    for opcode in [Opcode::F32Min, Opcode::F32Max] {
+        for (a, b) in [(f32::NAN, 1.0), (1.0, f32::NAN), (f32::NAN, f32::NAN)] {
+            assert!(f32_result(test_binary_opcode_impl(a, b, opcode)).is_nan());
+        }
+    }
+    for opcode in [Opcode::F64Min, Opcode::F64Max] {
+        for (a, b) in [(f64::NAN, 1.0), (1.0, f64::NAN), (f64::NAN, f64::NAN)] {
+            assert!(f64_result(test_binary_opcode_impl(a, b, opcode)).is_nan());
+        }
+    }
+
+    // Equality can't tell the zeros apart, so compare the bits
+    for (a, b) in [(-0.0f32, 0.0f32), (0.0, -0.0)] {
+        let min = f32_result(test_binary_opcode_impl(a, b, Opcode::F32Min));
+        assert_eq!(min.to_bits(), (-0.0f32).to_bits());
+        let max = f32_result(test_binary_opcode_impl(a, b, Opcode::F32Max));
+        assert_eq!(max.to_bits(), 0.0f32.to_bits());
+    }
+    for (a, b) in [(-0.0f64, 0.0f64), (0.0, -0.0)] {
+        let min = f64_result(test_binary_opcode_impl(a, b, Opcode::F64Min));
+        assert_eq!(min.to_bits(), (-0.0f64).to_bits());
+        let max = f64_result(test_binary_opcode_impl(a, b, Opcode::F64Max));
+        assert_eq!(max.to_bits(), 0.0f64.to_bits());
+    }
+
+    test_binary_opcode!(
+        f32::NEG_INFINITY,
+        -1.0f32,
+        Opcode::F32Min,
+        f32::NEG_INFINITY
+    );
+    test_binary_opcode!(-1.0f64, f64::INFINITY, Opcode::F64Max, f64::INFINITY);
+}
+
+#[test]
+fn test_saturating_truncation_ops() {
+    test_misc_unary_opcode!(-7.5f32, MiscOpcode::I32TruncSatF32S, -7i32);
+    test_misc_unary_opcode!(3e9f32, MiscOpcode::I32TruncSatF32S, i32::MAX);
+    test_misc_unary_opcode!(-3e9f32, MiscOpcode::I32TruncSatF32S, i32::MIN);
+    test_misc_unary_opcode!(f32::NAN, MiscOpcode::I32TruncSatF32S, 0i32);
+    test_misc_unary_opcode!(3e9f32, MiscOpcode::I32TruncSatF32U, 3000000000u32);
+    test_misc_unary_opcode!(-1.5f32, MiscOpcode::I32TruncSatF32U, 0u32);
+    test_misc_unary_opcode!(f32::INFINITY, MiscOpcode::I32TruncSatF32U, u32::MAX);
+    test_misc_unary_opcode!(f32::NAN, MiscOpcode::I32TruncSatF32U, 0u32);
+
+    test_misc_unary_opcode!(-7.5f64, MiscOpcode::I32TruncSatF64S, -7i32);
+    test_misc_unary_opcode!(1e10f64, MiscOpcode::I32TruncSatF64S, i32::MAX);
+    test_misc_unary_opcode!(f64::NEG_INFINITY, MiscOpcode::I32TruncSatF64S, i32::MIN);
+    test_misc_unary_opcode!(f64::NAN, MiscOpcode::I32TruncSatF64S, 0i32);
+    test_misc_unary_opcode!(4294967295.9f64, MiscOpcode::I32TruncSatF64U, u32::MAX);
+    test_misc_unary_opcode!(1e10f64, MiscOpcode::I32TruncSatF64U, u32::MAX);
+    test_misc_unary_opcode!(-1e10f64, MiscOpcode::I32TruncSatF64U, 0u32);
+
+    test_misc_unary_opcode!(-7.5f32, MiscOpcode::I64TruncSatF32S, -7i64);
+    test_misc_unary_opcode!(1e19f32, MiscOpcode::I64TruncSatF32S, i64::MAX);
+    test_misc_unary_opcode!(-1e19f32, MiscOpcode::I64TruncSatF32S, i64::MIN);
+    test_misc_unary_opcode!(f32::NAN, MiscOpcode::I64TruncSatF32U, 0u64);
+    test_misc_unary_opcode!(1e20f32, MiscOpcode::I64TruncSatF32U, u64::MAX);
+    test_misc_unary_opcode!(-1e20f32, MiscOpcode::I64TruncSatF32U, 0u64);
+
+    test_misc_unary_opcode!(-7.5f64, MiscOpcode::I64TruncSatF64S, -7i64);
+    test_misc_unary_opcode!(f64::INFINITY, MiscOpcode::I64TruncSatF64S, i64::MAX);
+    test_misc_unary_opcode!(f64::NAN, MiscOpcode::I64TruncSatF64S, 0i64);
+    test_misc_unary_opcode!(3e9f64, MiscOpcode::I64TruncSatF64U, 3000000000u64);
+    test_misc_unary_opcode!(1e20f64, MiscOpcode::I64TruncSatF64U, u64::MAX);
+    test_misc_unary_opcode!(f64::NEG_INFINITY, MiscOpcode::I64TruncSatF64U, 0u64);
 }
 
 fn do_local_get(
@@ -381,6 +647,60 @@ fn do_local_tee(
     }
 }
 
+#[test]
+fn test_trapping_ops() {
+    test_binary_opcode_traps!(7i32, 0i32, Opcode::I32DivS, TrapKind::IntegerDivideByZero);
+    test_binary_opcode_traps!(7i32, 0i32, Opcode::I32DivU, TrapKind::IntegerDivideByZero);
+    test_binary_opcode_traps!(7i32, 0i32, Opcode::I32RemS, TrapKind::IntegerDivideByZero);
+    test_binary_opcode_traps!(7i32, 0i32, Opcode::I32RemU, TrapKind::IntegerDivideByZero);
+    test_binary_opcode_traps!(7i64, 0i64, Opcode::I64DivS, TrapKind::IntegerDivideByZero);
+    test_binary_opcode_traps!(7i64, 0i64, Opcode::I64DivU, TrapKind::IntegerDivideByZero);
+    test_binary_opcode_traps!(7i64, 0i64, Opcode::I64RemS, TrapKind::IntegerDivideByZero);
+    test_binary_opcode_traps!(7i64, 0i64, Opcode::I64RemU, TrapKind::IntegerDivideByZero);
+
+    // Signed division overflows, but the matching remainder is defined to be zero
+    test_binary_opcode_traps!(i32::MIN, -1i32, Opcode::I32DivS, TrapKind::IntegerOverflow);
+    test_binary_opcode_traps!(i64::MIN, -1i64, Opcode::I64DivS, TrapKind::IntegerOverflow);
+    test_binary_opcode!(i32::MIN, -1i32, Opcode::I32RemS, 0i32);
+    test_binary_opcode!(i64::MIN, -1i64, Opcode::I64RemS, 0i64);
+
+    test_unary_opcode_traps!(
+        f32::NAN,
+        Opcode::I32TruncF32S,
+        TrapKind::InvalidConversionToInteger
+    );
+    test_unary_opcode_traps!(
+        f64::NAN,
+        Opcode::I64TruncF64U,
+        TrapKind::InvalidConversionToInteger
+    );
+    test_unary_opcode_traps!(
+        2147483648.0f32,
+        Opcode::I32TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_traps!(-1.0f64, Opcode::I32TruncF64U, TrapKind::IntegerOverflow);
+    test_unary_opcode_traps!(
+        f32::INFINITY,
+        Opcode::I64TruncF32S,
+        TrapKind::IntegerOverflow
+    );
+    test_unary_opcode_traps!(
+        1.8446744073709552e19f64,
+        Opcode::I64TruncF64U,
+        TrapKind::IntegerOverflow
+    );
+
+    // The edges of the valid range still convert
+    test_unary_opcode!(-2147483648.9f64, Opcode::I32TruncF64S, i32::MIN);
+    test_unary_opcode!(-0.9f64, Opcode::I32TruncF64U, 0u32);
+    test_unary_opcode!(4294967295.5f64, Opcode::I32TruncF64U, u32::MAX);
+
+    let mut expr = make_expression_writer();
+    expr.write_single_byte_instruction(Opcode::Unreachable);
+    assert_eq!(test_trap_expression_impl(expr), Some(TrapKind::Unreachable));
+}
+
 #[test]
 fn test_locals_ops() {
     let mut stack = Stack::new();
@@ -467,6 +787,37 @@ fn test_locals_ops() {
         Some(42i32.into())
     );
 
+    // Values of other types can't go into a local, and leave it as it was
+    assert_eq!(
+        do_local_set(
+            &mut stack,
+            &function_store,
+            &mut data_store,
+            1,
+            1.5f64.into()
+        ),
+        None
+    );
+    stack.pop();
+    assert_eq!(
+        do_local_tee(&mut stack, &function_store, &mut data_store, 1, 7i64.into()),
+        None
+    );
+    stack.pop();
+    assert_eq!(
+        do_local_get(&mut stack, &function_store, &mut data_store, 1),
+        Some(StackEntry::I32Entry(0))
+    );
+
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(1.5f32);
+    expr.write_single_leb_instruction(Opcode::LocalSet, 2);
+    let error = execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "LocalSet of local 2 of type I32 given F32");
+    stack.pop();
+
     // Check that locals still work as expected when there is a working value on the stack
     stack.push(42.0f32.into());
     assert_eq!(
@@ -699,3 +1050,590 @@ fn test_memory_ops() {
 
     assert_eq!(data_store.get_memory_size(0).ok(), Some(2));
 }
+
+#[test]
+fn test_memory_address_overflow() {
+    let run = |expr: ExpressionWriter| {
+        let mut stack = Stack::new();
+        let (function_store, mut data_store) = make_test_store();
+        data_store.enable_memory();
+        assert!(stack.push_test_frame(0).is_ok());
+        execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+            .map_err(|e| WasmError::trap_kind(&e))
+    };
+
+    // The base address and offset add up to more than 32 bits, which must not wrap
+    // around to the start of memory
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(0xFFFF_FFF0u32);
+    expr.write_memory_instruction(Opcode::I64Load, 0, 0x20);
+    assert_eq!(run(expr), Err(Some(TrapKind::MemoryOutOfBounds)));
+
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(u32::MAX);
+    expr.write_const_instruction(1u32);
+    expr.write_memory_instruction(Opcode::I32Store8, 0, u32::MAX);
+    assert_eq!(run(expr), Err(Some(TrapKind::MemoryOutOfBounds)));
+
+    let mut expr = make_expression_writer();
+    expr.write_const_instruction(u32::MAX);
+    expr.write_atomic_instruction(AtomicOpcode::I32AtomicLoad8U, u32::MAX);
+    assert_eq!(run(expr), Err(Some(TrapKind::MemoryOutOfBounds)));
+}
+
+fn do_bulk_memory_op(
+    data_store: &mut TestDataStore,
+    opcode: MiscOpcode,
+    immediates: &[u64],
+    args: &[u32],
+) -> Option<()> {
+    let mut stack = Stack::new();
+    let function_store = TestFunctionStore::new();
+
+    let mut expr = make_expression_writer();
+    for arg in args {
+        expr.write_const_instruction(*arg);
+    }
+    expr.write_misc_instruction(opcode, immediates);
+
+    if execute_expression(&expr, &mut stack, &function_store, data_store).is_ok()
+        && stack.working_count() == 0
+    {
+        Some(())
+    } else {
+        None
+    }
+}
+
+fn read_memory(data_store: &TestDataStore, offset: usize, length: usize) -> Vec<u8> {
+    let mut buf = vec![0; length];
+    data_store.read_data(0, offset, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn test_multiple_memories() {
+    let mut stack = Stack::new();
+    let (function_store, mut data_store) = make_test_store();
+
+    data_store.enable_memory();
+    let second_memory = data_store.add_memory();
+
+    data_store
+        .write_data(0, 16, &0x11111111_u32.to_le_bytes())
+        .unwrap();
+    data_store
+        .write_data(second_memory, 16, &0x22222222_u32.to_le_bytes())
+        .unwrap();
+
+    // The same address reads differently from each memory
+    test_memory_load!(
+        Opcode::I32Load,
+        8,
+        0,
+        8,
+        &mut stack,
+        &function_store,
+        &mut data_store,
+        0x11111111_u32
+    );
+    test_memory_load!(
+        Opcode::I32Load,
+        8,
+        second_memory,
+        8,
+        &mut stack,
+        &function_store,
+        &mut data_store,
+        0x22222222_u32
+    );
+
+    // A store only changes the memory it names
+    test_memory_store!(
+        Opcode::I64Store,
+        32,
+        second_memory,
+        0,
+        -1_i64,
+        &mut stack,
+        &function_store,
+        &mut data_store
+    );
+    test_memory_load!(
+        Opcode::I64Load,
+        32,
+        0,
+        0,
+        &mut stack,
+        &function_store,
+        &mut data_store,
+        0_u64
+    );
+    test_memory_load!(
+        Opcode::I64Load,
+        32,
+        second_memory,
+        0,
+        &mut stack,
+        &function_store,
+        &mut data_store,
+        -1_i64
+    );
+
+    // Memories which don't exist can't be used
+    assert_eq!(
+        test_memory_load_impl(
+            Opcode::I32Load,
+            0,
+            second_memory + 1,
+            0,
+            &mut stack,
+            &function_store,
+            &mut data_store
+        ),
+        None
+    );
+}
+#[test]
+fn test_bulk_memory_ops() {
+    let (_, mut data_store) = make_test_store();
+    data_store.enable_memory();
+    data_store.add_data_segment(&[1, 2, 3, 4, 5, 6]);
+
+    // memory.fill only uses the bottom byte of the value
+    assert_eq!(
+        do_bulk_memory_op(
+            &mut data_store,
+            MiscOpcode::MemoryFill,
+            &[0],
+            &[8, 0x1AB, 4]
+        ),
+        Some(())
+    );
+    assert_eq!(
+        read_memory(&data_store, 7, 6),
+        [0, 0xAB, 0xAB, 0xAB, 0xAB, 0]
+    );
+
+    // memory.init copies part of the data segment
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::MemoryInit, &[0, 0], &[0, 1, 4]),
+        Some(())
+    );
+    assert_eq!(read_memory(&data_store, 0, 5), [2, 3, 4, 5, 0]);
+
+    // memory.copy must cope with overlapping ranges in both directions
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::MemoryCopy, &[0, 0], &[1, 0, 4]),
+        Some(())
+    );
+    assert_eq!(read_memory(&data_store, 0, 5), [2, 2, 3, 4, 5]);
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::MemoryCopy, &[0, 0], &[0, 1, 4]),
+        Some(())
+    );
+    assert_eq!(read_memory(&data_store, 0, 5), [2, 3, 4, 5, 5]);
+
+    // Copies that cross page boundaries
+    assert_eq!(
+        do_bulk_memory_op(
+            &mut data_store,
+            MiscOpcode::MemoryCopy,
+            &[0, 0],
+            &[65534, 0, 4]
+        ),
+        None
+    );
+    data_store.grow_memory_by(0, 1).unwrap();
+    assert_eq!(
+        do_bulk_memory_op(
+            &mut data_store,
+            MiscOpcode::MemoryCopy,
+            &[0, 0],
+            &[65534, 0, 4]
+        ),
+        Some(())
+    );
+    assert_eq!(read_memory(&data_store, 65534, 4), [2, 3, 4, 5]);
+
+    // Out of bounds accesses trap, including reading past the end of the data segment
+    assert_eq!(
+        do_bulk_memory_op(
+            &mut data_store,
+            MiscOpcode::MemoryFill,
+            &[0],
+            &[131070, 0, 3]
+        ),
+        None
+    );
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::MemoryInit, &[0, 0], &[0, 4, 3]),
+        None
+    );
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::MemoryInit, &[1, 0], &[0, 0, 0]),
+        None
+    );
+
+    // Once the segment is dropped, only empty copies from it are allowed
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::DataDrop, &[0], &[]),
+        Some(())
+    );
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::MemoryInit, &[0, 0], &[0, 0, 1]),
+        None
+    );
+    assert_eq!(
+        do_bulk_memory_op(&mut data_store, MiscOpcode::MemoryInit, &[0, 0], &[0, 0, 0]),
+        Some(())
+    );
+}
+
+#[test]
+fn test_reference_ops() {
+    let run = |write: &dyn Fn(&mut ExpressionWriter)| {
+        let mut expr = make_expression_writer();
+        write(&mut expr);
+
+        let mut stack = Stack::new();
+        let (function_store, mut data_store) = make_test_store();
+        execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+            .map(|_| stack.working_top(stack.working_count()).to_vec())
+    };
+
+    assert_eq!(
+        run(&|expr| {
+            expr.write_single_leb_instruction(Opcode::RefNull, ValueType::FuncRef as u64);
+            expr.write_single_leb_instruction(Opcode::RefNull, ValueType::ExternRef as u64);
+            expr.write_single_leb_instruction(Opcode::RefFunc, 3);
+        })
+        .unwrap(),
+        [
+            StackEntry::FuncRefEntry(None),
+            StackEntry::ExternRefEntry(None),
+            StackEntry::FuncRefEntry(Some(3))
+        ]
+    );
+
+    assert_eq!(
+        run(&|expr| {
+            expr.write_single_leb_instruction(Opcode::RefNull, ValueType::ExternRef as u64);
+            expr.write_single_byte_instruction(Opcode::RefIsNull);
+            expr.write_single_leb_instruction(Opcode::RefFunc, 0);
+            expr.write_single_byte_instruction(Opcode::RefIsNull);
+        })
+        .unwrap(),
+        [StackEntry::I32Entry(1), StackEntry::I32Entry(0)]
+    );
+
+    // Only reference types can be null
+    assert!(run(&|expr| {
+        expr.write_single_leb_instruction(Opcode::RefNull, ValueType::I32 as u64)
+    })
+    .is_err());
+    assert!(run(&|expr| {
+        expr.write_const_instruction(0i32);
+        expr.write_single_byte_instruction(Opcode::RefIsNull);
+    })
+    .is_err());
+}
+
+#[test]
+fn test_table_ops() {
+    let (mut function_store, mut data_store) = make_test_store();
+    for i in 0..2_u32 {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction(i);
+        function_store.add_function(expr, FuncType::new(vec![], vec![ValueType::I32]), vec![]);
+    }
+    function_store.set_table(Table::new_from_bounds(2, Some(4)));
+    function_store.add_element_segment(&[1, 0]);
+
+    let mut run = |write: &dyn Fn(&mut ExpressionWriter)| {
+        let mut expr = make_expression_writer();
+        write(&mut expr);
+
+        let mut stack = Stack::new();
+        execute_expression(&expr, &mut stack, &function_store, &mut data_store)
+            .map(|_| stack.working_top(stack.working_count()).to_vec())
+    };
+    let get_all = |expr: &mut ExpressionWriter| {
+        expr.write_misc_instruction(MiscOpcode::TableSize, &[0]);
+        for idx in 0..3_u32 {
+            expr.write_const_instruction(idx);
+            expr.write_single_leb_instruction(Opcode::TableGet, 0);
+        }
+    };
+    let null = StackEntry::FuncRefEntry(None);
+    let func = |idx| StackEntry::FuncRefEntry(Some(idx));
+    let trap_kind = |result: Result<_, anyhow::Error>| WasmError::trap_kind(&result.unwrap_err());
+
+    // Tables start out full of nulls, and reading past the end traps
+    assert_eq!(trap_kind(run(&get_all)), Some(TrapKind::TableOutOfBounds));
+
+    // table.grow returns the old size, or -1 if the table can't grow that far
+    assert_eq!(
+        run(&|expr| {
+            expr.write_single_leb_instruction(Opcode::RefFunc, 1);
+            expr.write_const_instruction(1i32);
+            expr.write_misc_instruction(MiscOpcode::TableGrow, &[0]);
+            expr.write_single_leb_instruction(Opcode::RefNull, ValueType::FuncRef as u64);
+            expr.write_const_instruction(2i32);
+            expr.write_misc_instruction(MiscOpcode::TableGrow, &[0]);
+            expr.write_const_instruction(0i32);
+            expr.write_single_leb_instruction(Opcode::RefFunc, 0);
+            expr.write_single_leb_instruction(Opcode::TableSet, 0);
+            get_all(expr);
+        })
+        .unwrap(),
+        [
+            2i32.into(),
+            (-1i32).into(),
+            3u32.into(),
+            func(0),
+            null,
+            func(1)
+        ]
+    );
+
+    // table.fill, checking the whole range before changing anything
+    assert_eq!(
+        trap_kind(run(&|expr| {
+            expr.write_const_instruction(1i32);
+            expr.write_single_leb_instruction(Opcode::RefFunc, 0);
+            expr.write_const_instruction(3i32);
+            expr.write_misc_instruction(MiscOpcode::TableFill, &[0]);
+        })),
+        Some(TrapKind::TableOutOfBounds)
+    );
+    assert_eq!(
+        run(&|expr| {
+            expr.write_const_instruction(1i32);
+            expr.write_single_leb_instruction(Opcode::RefFunc, 0);
+            expr.write_const_instruction(2i32);
+            expr.write_misc_instruction(MiscOpcode::TableFill, &[0]);
+            get_all(expr);
+        })
+        .unwrap(),
+        [3u32.into(), func(0), func(0), func(0)]
+    );
+
+    // table.init copies from the element segment, and table.copy within the table
+    assert_eq!(
+        run(&|expr| {
+            expr.write_const_instruction(1i32);
+            expr.write_const_instruction(0i32);
+            expr.write_const_instruction(2i32);
+            expr.write_misc_instruction(MiscOpcode::TableInit, &[0, 0]);
+            expr.write_const_instruction(0i32);
+            expr.write_const_instruction(1i32);
+            expr.write_const_instruction(1i32);
+            expr.write_misc_instruction(MiscOpcode::TableCopy, &[0, 0]);
+            get_all(expr);
+        })
+        .unwrap(),
+        [3u32.into(), func(1), func(1), func(0)]
+    );
+    assert_eq!(
+        trap_kind(run(&|expr| {
+            expr.write_const_instruction(0i32);
+            expr.write_const_instruction(1i32);
+            expr.write_const_instruction(2i32);
+            expr.write_misc_instruction(MiscOpcode::TableInit, &[0, 0]);
+        })),
+        Some(TrapKind::TableOutOfBounds)
+    );
+
+    // Once the segment is dropped, only empty copies from it are allowed
+    let init = |length: u32| {
+        move |expr: &mut ExpressionWriter| {
+            expr.write_const_instruction(0i32);
+            expr.write_const_instruction(0i32);
+            expr.write_const_instruction(length);
+            expr.write_misc_instruction(MiscOpcode::TableInit, &[0, 0]);
+        }
+    };
+    assert!(run(&|expr| expr.write_misc_instruction(MiscOpcode::ElemDrop, &[0])).is_ok());
+    assert_eq!(trap_kind(run(&init(1))), Some(TrapKind::TableOutOfBounds));
+    assert!(run(&init(0)).is_ok());
+
+    // A table of functions can't hold anything else
+    assert!(run(&|expr| {
+        expr.write_const_instruction(0i32);
+        expr.write_single_leb_instruction(Opcode::RefNull, ValueType::ExternRef as u64);
+        expr.write_single_leb_instruction(Opcode::TableSet, 0);
+    })
+    .is_err());
+}
+
+fn do_atomic_op(
+    data_store: &mut TestDataStore,
+    opcode: AtomicOpcode,
+    offset: u32,
+    args: &[StackEntry],
+) -> anyhow::Result<Vec<StackEntry>> {
+    let mut stack = Stack::new();
+    let function_store = TestFunctionStore::new();
+
+    let mut expr = make_expression_writer();
+    for arg in args {
+        expr.write_const_instruction(*arg);
+    }
+    expr.write_atomic_instruction(opcode, offset);
+
+    execute_expression(&expr, &mut stack, &function_store, data_store)?;
+    let count = stack.working_count();
+    Ok(stack.working_top(count).to_vec())
+}
+
+#[test]
+fn test_atomic_ops() {
+    let (_, mut data_store) = make_test_store();
+    data_store.enable_memory();
+
+    let mut run = |opcode, offset, args: &[StackEntry]| {
+        do_atomic_op(&mut data_store, opcode, offset, args).map_err(|e| WasmError::trap_kind(&e))
+    };
+
+    // Stores and loads, including the narrow forms which only touch part of the value
+    assert_eq!(
+        run(
+            AtomicOpcode::I64AtomicStore,
+            8,
+            &[0u32.into(), 0x1122334455667788u64.into()]
+        ),
+        Ok(vec![])
+    );
+    assert_eq!(
+        run(AtomicOpcode::I64AtomicLoad, 0, &[8u32.into()]),
+        Ok(vec![0x1122334455667788u64.into()])
+    );
+    assert_eq!(
+        run(AtomicOpcode::I32AtomicLoad16U, 10, &[0u32.into()]),
+        Ok(vec![0x5566u32.into()])
+    );
+    assert_eq!(
+        run(
+            AtomicOpcode::I32AtomicStore8,
+            0,
+            &[8u32.into(), 0x1FFu32.into()]
+        ),
+        Ok(vec![])
+    );
+    assert_eq!(
+        run(AtomicOpcode::I64AtomicLoad32U, 0, &[8u32.into()]),
+        Ok(vec![0x556677FFu64.into()])
+    );
+
+    // Read-modify-write returns the original value, and wraps within the access size
+    assert_eq!(
+        run(
+            AtomicOpcode::I32AtomicRmwAdd,
+            0,
+            &[16u32.into(), 5u32.into()]
+        ),
+        Ok(vec![0u32.into()])
+    );
+    assert_eq!(
+        run(
+            AtomicOpcode::I32AtomicRmw8SubU,
+            0,
+            &[16u32.into(), 6u32.into()]
+        ),
+        Ok(vec![5u32.into()])
+    );
+    assert_eq!(
+        run(AtomicOpcode::I32AtomicLoad, 0, &[16u32.into()]),
+        Ok(vec![0xFFu32.into()])
+    );
+    assert_eq!(
+        run(
+            AtomicOpcode::I64AtomicRmwXchg,
+            0,
+            &[16u32.into(), 7u64.into()]
+        ),
+        Ok(vec![0xFFu64.into()])
+    );
+
+    // Compare and exchange only writes when the expected value matches
+    assert_eq!(
+        run(
+            AtomicOpcode::I32AtomicRmwCmpxchg,
+            0,
+            &[16u32.into(), 6u32.into(), 9u32.into()]
+        ),
+        Ok(vec![7u32.into()])
+    );
+    assert_eq!(
+        run(
+            AtomicOpcode::I32AtomicRmwCmpxchg,
+            0,
+            &[16u32.into(), 7u32.into(), 9u32.into()]
+        ),
+        Ok(vec![7u32.into()])
+    );
+    assert_eq!(
+        run(AtomicOpcode::I32AtomicLoad, 0, &[16u32.into()]),
+        Ok(vec![9u32.into()])
+    );
+
+    // Atomic accesses must be naturally aligned
+    assert_eq!(
+        run(AtomicOpcode::I32AtomicLoad, 2, &[16u32.into()]),
+        Err(Some(TrapKind::UnalignedAtomic))
+    );
+    assert_eq!(
+        run(AtomicOpcode::I32AtomicLoad8U, 0, &[65536u32.into()]),
+        Err(Some(TrapKind::MemoryOutOfBounds))
+    );
+
+    // Nothing can wait on a memory which isn't shared, so there is nothing to notify
+    assert_eq!(run(AtomicOpcode::AtomicFence, 0, &[]), Ok(vec![]));
+    assert_eq!(
+        run(
+            AtomicOpcode::MemoryAtomicNotify,
+            0,
+            &[16u32.into(), 1u32.into()]
+        ),
+        Ok(vec![0u32.into()])
+    );
+    assert_eq!(
+        run(
+            AtomicOpcode::MemoryAtomicWait32,
+            0,
+            &[16u32.into(), 9u32.into(), (-1i64).into()]
+        ),
+        Err(Some(TrapKind::ExpectedSharedMemory))
+    );
+}
+
+#[test]
+fn test_atomic_wait_on_shared_memory() {
+    let (_, mut data_store) = make_test_store();
+    let shared = SharedMemory::new(1, 1);
+    data_store.add_shared_memory(shared);
+
+    let mut wait = |expected: u32, timeout: i64| {
+        let mut expr = make_expression_writer();
+        expr.write_const_instruction(0u32);
+        expr.write_const_instruction(expected);
+        expr.write_const_instruction(timeout);
+        expr.write_atomic_instruction(AtomicOpcode::MemoryAtomicWait32, 0);
+
+        let mut stack = Stack::new();
+        execute_expression(
+            &expr,
+            &mut stack,
+            &TestFunctionStore::new(),
+            &mut data_store,
+        )
+        .unwrap();
+        stack.working_top(1)[0]
+    };
+
+    // A value which doesn't match returns straight away, and one which does waits until
+    // the timeout because nothing notifies it
+    assert_eq!(wait(1, -1), 1u32.into());
+    assert_eq!(wait(0, 1000), 2u32.into());
+}
@@ -0,0 +1,188 @@
+// Every possible opcode is decoded here, so that the parser, the compiler and the executor
+// can't drift apart as proposals are added. A new opcode has to be given a category and
+// an executor arm, and a reserved one has to be rejected without a panic.
+
+use super::super::execute_core::execute_expression;
+use super::test_store::*;
+use crate::core::{compiled::CompiledFunction, Stack};
+use crate::parser::{
+    lookup_opcode, AtomicOpcode, InstructionCategory, InstructionSource, InvalidOpcode, MiscOpcode,
+    Opcode,
+};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+// The single byte opcodes which aren't in the spec, or which belong to proposals the
+// interpreter can't decode. 0xFD is the SIMD prefix.
+const RESERVED_OPCODES: &[(u8, u8)] = &[
+    (0x06, 0x0A),
+    (0x14, 0x19),
+    (0x1D, 0x1F),
+    (0x27, 0x27),
+    (0xC5, 0xCF),
+    (0xD3, 0xFB),
+    (0xFD, 0xFD),
+];
+
+// Enough of each prefixed space to go well past the last opcode in it
+const SUB_OPCODES: std::ops::Range<u32> = 0..0x100;
+
+fn is_reserved(byte: u8) -> bool {
+    RESERVED_OPCODES
+        .iter()
+        .any(|(first, last)| (*first..=*last).contains(&byte))
+}
+
+// Whether the catalog of opcodes, which describes modules for tools, thinks the
+// interpreter can decode the opcode. It only lists the opcodes of proposals, so it knows
+// nothing about the MVP.
+fn catalog_supports(opcode: u8, sub_opcode: Option<u32>) -> Option<bool> {
+    lookup_opcode(opcode, sub_opcode).map(|info| info.supported)
+}
+
+// A prefix byte followed by the sub opcode as a LEB
+fn prefixed(prefix: u8, sub_opcode: u32) -> Vec<u8> {
+    match sub_opcode {
+        0..=0x7F => vec![prefix, sub_opcode as u8],
+        _ => vec![
+            prefix,
+            0x80 | (sub_opcode & 0x7F) as u8,
+            (sub_opcode >> 7) as u8,
+        ],
+    }
+}
+
+// The bytes of an instruction with the category, followed by an end, using immediates
+// which any function can compile. The opcode includes the prefix of a prefixed instruction.
+fn encode(opcode: &[u8], cat: InstructionCategory, alignment: u32) -> Vec<u8> {
+    let mut bytes = opcode.to_vec();
+    match cat {
+        InstructionCategory::SingleByte | InstructionCategory::Else | InstructionCategory::End => {}
+        InstructionCategory::SingleLebInteger => bytes.push(0x00),
+        InstructionCategory::SingleFloat => bytes.extend_from_slice(&[0x00; 4]),
+        InstructionCategory::SingleDouble => bytes.extend_from_slice(&[0x00; 8]),
+        // A typed select has to have exactly one type
+        InstructionCategory::TwoLebInteger if opcode == [u8::from(Opcode::SelectTyped)] => {
+            bytes.extend_from_slice(&[0x01, 0x7F])
+        }
+        InstructionCategory::TwoLebInteger => bytes.extend_from_slice(&[0x00, 0x00]),
+        InstructionCategory::MemArg => bytes.extend_from_slice(&[alignment as u8, 0x00]),
+        InstructionCategory::BranchTable => bytes.extend_from_slice(&[0x00, 0x00]),
+        InstructionCategory::Block(_) => bytes.extend_from_slice(&[0x40, 0x0B]),
+        InstructionCategory::MiscPrefixed
+        | InstructionCategory::AtomicPrefixed
+        | InstructionCategory::ExtensionPrefixed => {
+            panic!("{:?} is only the category of a prefix byte", cat)
+        }
+    }
+    bytes.push(0x0B);
+    bytes
+}
+
+// Compile the instruction, and run it on an empty stack. Most instructions fail for want
+// of operands, which is fine, but none of them may panic.
+fn check_compiles_and_executes(expr: &[u8], name: &str) {
+    let compiled = CompiledFunction::new(expr, &[])
+        .unwrap_or_else(|e| panic!("{} doesn't compile: {:#}", name, e));
+    assert_eq!(
+        u8::from(compiled.instructions()[0].opcode()),
+        expr[0],
+        "{} compiles to the wrong opcode",
+        name
+    );
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let mut stack = Stack::new();
+        let (function_store, mut data_store) = make_test_store();
+        stack.push_test_frame(0).unwrap();
+        execute_expression(expr, &mut stack, &function_store, &mut data_store).map(|_| ())
+    }));
+    assert!(result.is_ok(), "{} panics in the executor", name);
+}
+
+#[test]
+fn test_single_byte_opcodes() {
+    for byte in 0..=u8::MAX {
+        let opcode = Opcode::from_byte(byte);
+        let category = InstructionCategory::from_lead_byte(byte, 0);
+        if is_reserved(byte) {
+            assert!(opcode.is_err(), "0x{:02x} is reserved", byte);
+            let error = category.unwrap_err().downcast::<InvalidOpcode>().unwrap();
+            assert_eq!(error, InvalidOpcode::new(byte, 0));
+            assert_ne!(catalog_supports(byte, None), Some(true));
+            continue;
+        }
+
+        let opcode = opcode.unwrap_or_else(|_| panic!("0x{:02x} isn't reserved", byte));
+        assert_eq!(u8::from(opcode), byte);
+        assert_eq!(
+            category.unwrap(),
+            InstructionCategory::from_opcode(opcode),
+            "{:?}",
+            opcode
+        );
+        assert_ne!(catalog_supports(byte, None), Some(false), "{:?}", opcode);
+
+        let cat = InstructionCategory::from_opcode(opcode);
+        match cat {
+            // The ends of blocks only make sense as part of a block, which is covered by
+            // the block instructions themselves
+            InstructionCategory::Else | InstructionCategory::End => {}
+            // The prefixes are covered along with the instructions behind them
+            InstructionCategory::MiscPrefixed
+            | InstructionCategory::AtomicPrefixed
+            | InstructionCategory::ExtensionPrefixed => {}
+            _ => {
+                let alignment = opcode.natural_alignment().unwrap_or(0);
+                let expr = encode(&[byte], cat, alignment);
+                check_compiles_and_executes(&expr, &format!("{:?}", opcode));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_misc_opcodes() {
+    let prefix = u8::from(Opcode::MiscPrefix);
+    for sub_opcode in SUB_OPCODES {
+        let opcode = match MiscOpcode::from_u32(sub_opcode) {
+            Ok(opcode) => opcode,
+            Err(_) => {
+                assert_ne!(catalog_supports(prefix, Some(sub_opcode)), Some(true));
+                let mut expr = prefixed(prefix, sub_opcode);
+                expr.push(0x0B);
+                assert!(InstructionSource::iter(&expr).next().unwrap().is_err());
+                continue;
+            }
+        };
+        assert_eq!(u32::from(opcode), sub_opcode);
+        assert_eq!(catalog_supports(prefix, Some(sub_opcode)), Some(true));
+
+        let cat = InstructionCategory::from_misc_opcode(opcode);
+        let expr = encode(&prefixed(prefix, sub_opcode), cat, 0);
+        check_compiles_and_executes(&expr, &format!("{:?}", opcode));
+    }
+}
+
+#[test]
+fn test_atomic_opcodes() {
+    let prefix = u8::from(Opcode::AtomicPrefix);
+    for sub_opcode in SUB_OPCODES {
+        let opcode = match AtomicOpcode::from_u32(sub_opcode) {
+            Ok(opcode) => opcode,
+            Err(_) => {
+                assert_ne!(catalog_supports(prefix, Some(sub_opcode)), Some(true));
+                let mut expr = prefixed(prefix, sub_opcode);
+                expr.push(0x0B);
+                assert!(InstructionSource::iter(&expr).next().unwrap().is_err());
+                continue;
+            }
+        };
+        assert_eq!(u32::from(opcode), sub_opcode);
+        assert_eq!(catalog_supports(prefix, Some(sub_opcode)), Some(true));
+
+        let cat = InstructionCategory::from_atomic_opcode(opcode);
+        let alignment = opcode.natural_alignment().unwrap_or(0);
+        let expr = encode(&prefixed(prefix, sub_opcode), cat, alignment);
+        check_compiles_and_executes(&expr, &format!("{:?}", opcode));
+    }
+}
@@ -0,0 +1,389 @@
+//! The executor as it was before blocks moved onto an explicit control stack. Each nested
+//! block recursed into `execute_block_expression`, and branches unwound the Rust stack until
+//! they reached their target. It is kept as the reference engine for differential tests.
+
+use std::convert::TryFrom;
+
+use crate::core::{BlockType, FuncType, Stack};
+use crate::parser::{Instruction, InstructionSource, Opcode};
+use anyhow::Result;
+
+use crate::core::compiled::CompiledInstruction;
+
+use super::super::execute_core::{
+    execute_single_instruction, InstructionResult, SingleInstructionResult,
+};
+use super::super::stack_ops::get_stack_top;
+use super::super::store_access::{DataStore, FunctionStore};
+
+enum BranchControl {
+    NoBranch,
+    // The label is counted outwards from the innermost, and the count is of the labels in
+    // between, which all go along with the target's when the branch reaches it
+    Branch { label_idx: usize, label_cnt: usize },
+    Return,
+}
+
+impl BranchControl {
+    fn no_branch() -> Self {
+        BranchControl::NoBranch
+    }
+
+    fn branch_target(label_idx: usize) -> Self {
+        BranchControl::Branch {
+            label_idx,
+            label_cnt: label_idx,
+        }
+    }
+
+    fn do_return() -> Self {
+        BranchControl::Return
+    }
+
+    fn is_branch(&self) -> bool {
+        match self {
+            BranchControl::NoBranch => false,
+            _ => true,
+        }
+    }
+}
+
+// Blocks and branch tables can only be compiled as part of a whole function, but all that
+// matters here is that they are control instructions
+fn uncompiled_control_instruction(opcode: Opcode) -> Option<InstructionResult> {
+    match opcode {
+        Opcode::Block => Some(InstructionResult::Block),
+        Opcode::Loop => Some(InstructionResult::Loop),
+        Opcode::If => Some(InstructionResult::If),
+        Opcode::BrTable => Some(InstructionResult::BrTable),
+        _ => None,
+    }
+}
+
+fn execute_inner_loop<'a>(
+    iter: &'_ mut impl Iterator<Item = Result<Instruction<'a>>>,
+    stack: &'_ mut Stack,
+    function_store: &'_ impl FunctionStore,
+    data_store: &'_ mut impl DataStore,
+) -> Option<Result<(InstructionResult, Instruction<'a>)>> {
+    loop {
+        let instruction = match iter.next()? {
+            Ok(instruction) => instruction,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if let Err(e) = stack.consume_fuel().and_then(|_| stack.check_interrupt()) {
+            return Some(Err(e));
+        }
+
+        if let Some(ir) = uncompiled_control_instruction(instruction.opcode()) {
+            return Some(Ok((ir, instruction)));
+        }
+
+        let result = CompiledInstruction::decode(&instruction).and_then(|compiled| {
+            execute_single_instruction(&compiled, stack, function_store, data_store)
+        });
+        match result {
+            Ok(SingleInstructionResult::Done) => {}
+            Ok(SingleInstructionResult::ControlInstruction(ir)) => {
+                return Some(Ok((ir, instruction)));
+            }
+            Err(e) => {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+fn execute_block_expression(
+    block_type: BlockType,
+    is_loop: bool,
+    expr: &(impl InstructionSource + ?Sized),
+    types: &[FuncType],
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    // A branch to a loop starts it again, so it carries the loop's parameters, where a
+    // branch to anything else leaves it with the block's results
+    let func_type = block_type.func_type(types)?;
+    let param_count = func_type.arg_types().len();
+    let result_count = func_type.return_types().len();
+    let label_arity = if is_loop { param_count } else { result_count };
+
+    loop {
+        // Push a label on to the stack, beneath the block's parameters. The rust stack
+        // handles the actual branching, and the label keeps hold of how many values the
+        // branch carries.
+        stack.push_label(param_count, label_arity)?;
+
+        // Now execute the expression
+        let branch_control =
+            execute_expression_internal(expr, types, stack, function_store, data_store)?;
+        match branch_control {
+            BranchControl::Return => {
+                // For returns, leave the stack alone to be cleaned up when we get back to the call frame
+                return Ok(BranchControl::do_return());
+            }
+
+            BranchControl::Branch {
+                label_idx,
+                label_cnt,
+            } if label_idx > 0 => {
+                // We're part way through the branch, so decrement the label index and propagate
+                // the branch up
+                return Ok(BranchControl::Branch {
+                    label_idx: label_idx - 1,
+                    label_cnt,
+                });
+            }
+
+            BranchControl::Branch { label_cnt, .. } => {
+                // Walk all of the labels back off the stack, keeping the values the branch
+                // carries. We add one to account for the label we're going to.
+                stack.pop_n_labels(label_cnt + 1)?;
+
+                // Loops go around again, with the values carried as their parameters
+                if !is_loop {
+                    return Ok(BranchControl::no_branch());
+                }
+            }
+
+            BranchControl::NoBranch => {
+                // Reaching the end keeps the block's results, which aren't the values a
+                // branch to a loop would carry
+                stack.end_label(result_count)?;
+                return Ok(BranchControl::no_branch());
+            }
+        }
+    }
+}
+
+fn execute_if<'a>(
+    instruction: &'a Instruction<'a>,
+    types: &[FuncType],
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    let condition = u32::try_from(get_stack_top(stack, 1)?[0])?;
+    stack.pop();
+
+    if condition != 0 {
+        execute_block_expression(
+            instruction.get_block_type(),
+            false,
+            instruction.get_block(),
+            types,
+            stack,
+            function_store,
+            data_store,
+        )
+    } else if instruction.has_else_block() {
+        execute_block_expression(
+            instruction.get_block_type(),
+            false,
+            instruction.get_else_block(),
+            types,
+            stack,
+            function_store,
+            data_store,
+        )
+    } else {
+        // Without an else, the parameters are left where they are as the results
+        Ok(BranchControl::no_branch())
+    }
+}
+
+fn execute_block<'a>(
+    instruction: &'a Instruction<'a>,
+    types: &[FuncType],
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    execute_block_expression(
+        instruction.get_block_type(),
+        instruction.opcode() == Opcode::Loop,
+        instruction.get_block(),
+        types,
+        stack,
+        function_store,
+        data_store,
+    )
+}
+
+fn execute_br(
+    label: usize,
+    _stack: &mut Stack,
+    _function_store: &impl FunctionStore,
+    _data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    // Taking the branch is pretty easy
+    Ok(BranchControl::branch_target(label))
+}
+
+fn execute_br_if(
+    label: usize,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    let condition = u32::try_from(get_stack_top(stack, 1)?[0])?;
+    stack.pop();
+
+    if condition != 0 {
+        Ok(execute_br(label, stack, function_store, data_store)?)
+    } else {
+        Ok(BranchControl::no_branch())
+    }
+}
+
+fn execute_br_table(
+    labels: &[usize],
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    assert!(labels.len() > 0);
+
+    let index = u32::try_from(get_stack_top(stack, 1)?[0])?;
+    let index = usize::try_from(index).unwrap();
+    stack.pop();
+
+    let index = std::cmp::min(index, labels.len() - 1);
+    execute_br(labels[index], stack, function_store, data_store)
+}
+
+fn execute_call(
+    idx: usize,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    function_store.execute_function(idx, stack, data_store)?;
+    Ok(BranchControl::no_branch())
+}
+
+fn execute_call_indirect<'a>(
+    instruction: &'a Instruction<'a>,
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    let (func_type_idx, table_idx) = instruction.get_pair_u32_as_usize_arg();
+
+    let elem_idx = u32::try_from(get_stack_top(stack, 1)?[0])? as usize;
+    stack.pop();
+
+    function_store.execute_indirect_function(
+        func_type_idx,
+        table_idx,
+        elem_idx,
+        stack,
+        data_store,
+    )?;
+    Ok(BranchControl::no_branch())
+}
+
+fn execute_return(
+    _stack: &mut Stack,
+    _function_store: &impl FunctionStore,
+    _data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    Ok(BranchControl::do_return())
+}
+
+fn execute_expression_internal(
+    expr: &(impl InstructionSource + ?Sized),
+    types: &[FuncType],
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<BranchControl> {
+    let mut iter = expr.iter();
+    loop {
+        let branch_control = match execute_inner_loop(&mut iter, stack, function_store, data_store)
+        {
+            None => {
+                return Ok(BranchControl::no_branch());
+            }
+            Some(Err(e)) => {
+                return Err(e);
+            }
+
+            Some(Ok((InstructionResult::If, instruction))) => {
+                execute_if(&instruction, types, stack, function_store, data_store)?
+            }
+            Some(Ok((InstructionResult::Block, instruction)))
+            | Some(Ok((InstructionResult::Loop, instruction))) => {
+                execute_block(&instruction, types, stack, function_store, data_store)?
+            }
+
+            Some(Ok((InstructionResult::Br, instruction))) => execute_br(
+                instruction.get_single_u32_as_usize_arg(),
+                stack,
+                function_store,
+                data_store,
+            )?,
+            Some(Ok((InstructionResult::BrIf, instruction))) => execute_br_if(
+                instruction.get_single_u32_as_usize_arg(),
+                stack,
+                function_store,
+                data_store,
+            )?,
+            Some(Ok((InstructionResult::BrTable, instruction))) => execute_br_table(
+                &instruction.get_block_table_targets(),
+                stack,
+                function_store,
+                data_store,
+            )?,
+
+            Some(Ok((InstructionResult::Call, instruction))) => execute_call(
+                instruction.get_single_u32_as_usize_arg(),
+                stack,
+                function_store,
+                data_store,
+            )?,
+            Some(Ok((InstructionResult::CallIndirect, instruction))) => {
+                execute_call_indirect(&instruction, stack, function_store, data_store)?
+            }
+            Some(Ok((InstructionResult::Return, _))) => {
+                execute_return(stack, function_store, data_store)?
+            }
+
+            // Tail calls only differ from a call followed by a return in how much stack
+            // they use, which doesn't matter here
+            Some(Ok((InstructionResult::ReturnCall, instruction))) => {
+                execute_call(
+                    instruction.get_single_u32_as_usize_arg(),
+                    stack,
+                    function_store,
+                    data_store,
+                )?;
+                execute_return(stack, function_store, data_store)?
+            }
+            Some(Ok((InstructionResult::ReturnCallIndirect, instruction))) => {
+                execute_call_indirect(&instruction, stack, function_store, data_store)?;
+                execute_return(stack, function_store, data_store)?
+            }
+        };
+
+        // If we're branching, then propagate the branch to the caller
+        if branch_control.is_branch() {
+            return Ok(branch_control);
+        }
+    }
+}
+
+/// Execute an expression. The types are for blocks whose type is a type index.
+pub fn execute_expression_recursive(
+    expr: &(impl InstructionSource + ?Sized),
+    types: &[FuncType],
+    stack: &mut Stack,
+    function_store: &impl FunctionStore,
+    data_store: &mut impl DataStore,
+) -> Result<()> {
+    execute_expression_internal(expr, types, stack, function_store, data_store)?;
+    Ok(())
+}
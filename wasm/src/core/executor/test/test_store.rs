@@ -2,76 +2,220 @@ use anyhow::{anyhow, Result};
 
 use super::super::{ConstantDataStore, DataStore, FunctionStore};
 use crate::core::{
-    stack_entry::StackEntry, Callable, FuncType, Locals, Memory, Stack, Table, WasmExprCallable,
+    handle, memory_page::WASM_PAGE_SIZE_IN_BYTES, stack_entry::StackEntry, Callable, FuncType,
+    Handle, LEByteConvert, Locals, Lock, Memory, Shared, SharedMemory, Table, TableElement,
+    WasmExprCallable,
 };
 use crate::parser::InstructionSource;
+use std::time::Duration;
 
 pub struct TestDataStore {
-    memory: Memory,
-    memory_enabled: bool,
+    memories: Vec<Memory>,
+    data: Vec<Vec<u8>>,
+    globals: Vec<StackEntry>,
 }
 
 impl TestDataStore {
     pub fn new() -> Self {
         Self {
-            memory: Memory::new_from_bounds(1, Some(3)),
-            memory_enabled: false,
+            memories: Vec::new(),
+            data: Vec::new(),
+            globals: Vec::new(),
         }
     }
 
     pub fn enable_memory(&mut self) {
-        self.memory_enabled = true;
+        if self.memories.is_empty() {
+            self.add_memory();
+        }
+    }
+
+    /// Add another memory, for instructions which take a memory index
+    pub fn add_memory(&mut self) -> usize {
+        self.memories.push(Memory::new_from_bounds(1, Some(3)));
+        self.memories.len() - 1
+    }
+
+    pub fn add_shared_memory(&mut self, shared: SharedMemory) -> usize {
+        self.memories.push(Memory::from_shared(shared));
+        self.memories.len() - 1
+    }
+
+    fn memory(&self, mem_idx: usize) -> Result<&Memory> {
+        self.memories
+            .get(mem_idx)
+            .ok_or_else(|| anyhow!("Memory index out of range"))
+    }
+
+    fn memory_mut(&mut self, mem_idx: usize) -> Result<&mut Memory> {
+        self.memories
+            .get_mut(mem_idx)
+            .ok_or_else(|| anyhow!("Memory index out of range"))
+    }
+
+    pub fn add_data_segment(&mut self, data: &[u8]) {
+        self.data.push(data.to_vec());
+    }
+
+    pub fn add_global(&mut self, value: StackEntry) -> usize {
+        self.globals.push(value);
+        self.globals.len() - 1
+    }
+
+    pub fn globals(&self) -> &[StackEntry] {
+        &self.globals
+    }
+
+    /// A copy of the whole of the first memory, or nothing if memory isn't enabled
+    pub fn memory_contents(&self) -> Vec<u8> {
+        let mut contents = Vec::new();
+        if let Some(memory) = self.memories.first() {
+            contents.resize(memory.current_size() * WASM_PAGE_SIZE_IN_BYTES, 0);
+            memory.get_data(0, &mut contents).unwrap();
+        }
+        contents
     }
 }
 
 impl ConstantDataStore for TestDataStore {
-    fn get_global_value(&self, _idx: usize) -> Result<StackEntry> {
-        Err(anyhow!("Global value not present in test store"))
+    fn get_global_value(&self, idx: usize) -> Result<StackEntry> {
+        self.globals
+            .get(idx)
+            .copied()
+            .ok_or_else(|| anyhow!("Global value not present in test store"))
     }
 }
 
 impl DataStore for TestDataStore {
-    fn set_global_value(&mut self, _idx: usize, _value: StackEntry) -> Result<()> {
-        Err(anyhow!("Global value not present in test store"))
+    fn set_global_value(&mut self, idx: usize, value: StackEntry) -> Result<()> {
+        match self.globals.get_mut(idx) {
+            Some(global) => {
+                *global = value;
+                Ok(())
+            }
+            None => Err(anyhow!("Global value not present in test store")),
+        }
     }
 
     fn read_data(&self, mem_idx: usize, offset: usize, data: &mut [u8]) -> Result<()> {
-        if self.memory_enabled && mem_idx == 0 {
-            self.memory.get_data(offset, data)
-        } else {
-            Err(anyhow!("Memory index out of range"))
-        }
+        self.memory(mem_idx)?.get_data(offset, data)
     }
 
     fn write_data(&mut self, mem_idx: usize, offset: usize, data: &[u8]) -> Result<()> {
-        if self.memory_enabled && mem_idx == 0 {
-            self.memory.set_data(offset, data)
+        self.memory_mut(mem_idx)?.set_data(offset, data)
+    }
+
+    fn load_data<T: LEByteConvert>(&self, mem_idx: usize, offset: usize) -> Result<T> {
+        self.memory(mem_idx)?.load(offset)
+    }
+
+    fn store_data<T: LEByteConvert>(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: T,
+    ) -> Result<()> {
+        self.memory_mut(mem_idx)?.store(offset, value)
+    }
+
+    fn get_memory_size(&self, mem_idx: usize) -> Result<usize> {
+        Ok(self.memory(mem_idx)?.current_size())
+    }
+
+    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<bool> {
+        Ok(self.memory_mut(mem_idx)?.grow_by(grow_by).is_ok())
+    }
+
+    fn copy_memory(
+        &mut self,
+        dst_mem_idx: usize,
+        dst_offset: usize,
+        src_mem_idx: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        if dst_mem_idx == src_mem_idx {
+            self.memory_mut(dst_mem_idx)?
+                .copy_within(dst_offset, src_offset, length)
         } else {
-            Err(anyhow!("Memory index out of range"))
+            let mut buf = vec![0; length];
+            self.memory(src_mem_idx)?.get_data(src_offset, &mut buf)?;
+            self.memory_mut(dst_mem_idx)?.set_data(dst_offset, &buf)
         }
     }
 
-    fn get_memory_size(&self, mem_idx: usize) -> Result<usize> {
-        if self.memory_enabled && mem_idx == 0 {
-            Ok(self.memory.current_size())
+    fn fill_memory(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: u8,
+        length: usize,
+    ) -> Result<()> {
+        self.memory_mut(mem_idx)?.fill(offset, value, length)
+    }
+
+    fn init_memory(
+        &mut self,
+        mem_idx: usize,
+        data_idx: usize,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        self.memory(mem_idx)?;
+        if data_idx >= self.data.len() {
+            Err(anyhow!("Data segment index out of range"))
         } else {
-            Err(anyhow!("Memory index out of range"))
+            let data = &self.data[data_idx];
+            match src_offset.checked_add(length) {
+                Some(end) if end <= data.len() => {
+                    self.memories[mem_idx].set_data(dst_offset, &data[src_offset..end])
+                }
+                _ => Err(anyhow!("Attempting to access outside data segment")),
+            }
         }
     }
 
-    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<()> {
-        if self.memory_enabled && mem_idx == 0 {
-            self.memory.grow_by(grow_by)
+    fn drop_data(&mut self, data_idx: usize) -> Result<()> {
+        if data_idx < self.data.len() {
+            self.data[data_idx] = Vec::new();
+            Ok(())
         } else {
-            Err(anyhow!("Memory index out of range"))
+            Err(anyhow!("Data segment index out of range"))
         }
     }
+
+    fn update_data(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        length: usize,
+        update: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<()> {
+        self.memory_mut(mem_idx)?
+            .update_data(offset, length, update)
+    }
+
+    fn wait_memory(
+        &self,
+        mem_idx: usize,
+        offset: usize,
+        expected: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<u32> {
+        self.memory(mem_idx)?.wait(offset, expected, timeout)
+    }
+
+    fn notify_memory(&self, mem_idx: usize, offset: usize, count: u32) -> Result<u32> {
+        self.memory(mem_idx)?.notify(offset, count)
+    }
 }
 
 pub struct TestFunctionStore {
-    functions: Vec<Callable>,
+    functions: Vec<Handle<Callable>>,
     func_types: Vec<FuncType>,
-    table: Option<Table>,
+    table: Option<Handle<Table>>,
+    elements: Lock<Vec<Vec<TableElement>>>,
 }
 
 impl TestFunctionStore {
@@ -80,6 +224,7 @@ impl TestFunctionStore {
             functions: Vec::new(),
             func_types: Vec::new(),
             table: None,
+            elements: Lock::new(Vec::new()),
         }
     }
 
@@ -89,10 +234,9 @@ impl TestFunctionStore {
         func_type: FuncType,
         locals: Vec<Locals>,
     ) -> usize {
-        self.functions.push(WasmExprCallable::new_base(
-            func_type,
-            locals,
-            expr.as_expr(),
+        self.functions.push(handle(
+            WasmExprCallable::new_base(func_type, locals, &expr.as_expr(), &self.func_types)
+                .expect("Test function bodies should compile"),
         ));
         self.functions.len() - 1
     }
@@ -102,46 +246,86 @@ impl TestFunctionStore {
     }
 
     pub fn set_table(&mut self, table: Table) {
-        self.table = Some(table);
+        self.table = Some(handle(table));
+    }
+
+    pub fn add_element_segment(&mut self, func_indices: &[usize]) -> usize {
+        let segment = func_indices
+            .iter()
+            .map(|idx| TableElement::FuncRef(Some(self.functions[*idx].clone())))
+            .collect();
+        let mut elements = self.elements.borrow_mut();
+        elements.push(segment);
+        elements.len() - 1
     }
 }
 
 impl FunctionStore for TestFunctionStore {
-    fn execute_function(
-        &self,
-        idx: usize,
-        stack: &mut Stack,
-        data_store: &mut impl DataStore,
-    ) -> Result<()> {
-        if idx < self.functions.len() {
-            let callable = &self.functions[idx];
-            callable.call(stack, self, data_store)
-        } else {
-            Err(anyhow!("Callable index out of range"))
-        }
+    fn get_function(&self, idx: usize) -> Result<Handle<Callable>> {
+        self.functions
+            .get(idx)
+            .cloned()
+            .ok_or_else(|| anyhow!("Callable index out of range"))
     }
 
-    fn execute_indirect_function(
+    fn get_indirect_function(
         &self,
         func_type_idx: usize,
         table_idx: usize,
         elem_idx: usize,
-        stack: &mut Stack,
-        data_store: &mut impl DataStore,
-    ) -> Result<()> {
+    ) -> Result<Handle<Callable>> {
         if func_type_idx >= self.func_types.len() {
             Err(anyhow!("FuncType index out of range"))
         } else if table_idx != 0 || self.table.is_none() {
             Err(anyhow!("Table index out of range"))
         } else {
-            let callable = self.table.as_ref().unwrap().get_entry(elem_idx)?;
-            let callable = callable.borrow();
+            let callable = self.table.as_ref().unwrap().borrow().get_entry(elem_idx)?;
 
-            if *callable.func_type() != self.func_types[func_type_idx] {
+            if *callable.borrow().func_type() != self.func_types[func_type_idx] {
                 Err(anyhow!("Indirect function call type does not match"))
             } else {
-                callable.call(stack, self, data_store)
+                Ok(callable)
+            }
+        }
+    }
+
+    fn get_function_index(&self, callable: &Handle<Callable>) -> Option<usize> {
+        self.functions
+            .iter()
+            .position(|f| Shared::ptr_eq(f, callable))
+    }
+
+    fn get_table(&self, table_idx: usize) -> Result<Handle<Table>> {
+        match &self.table {
+            Some(table) if table_idx == 0 => Ok(table.clone()),
+            _ => Err(anyhow!("Table index out of range")),
+        }
+    }
+
+    fn init_table(
+        &self,
+        table_idx: usize,
+        elem_idx: usize,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        let elements = self.elements.borrow();
+        let segment = elements
+            .get(elem_idx)
+            .ok_or_else(|| anyhow!("Element segment index out of range"))?;
+        self.get_table(table_idx)?
+            .borrow_mut()
+            .init(dst_offset, segment, src_offset, length)
+    }
+
+    fn drop_elements(&self, elem_idx: usize) -> Result<()> {
+        match self.elements.borrow_mut().get_mut(elem_idx) {
+            Some(segment) => {
+                *segment = Vec::new();
+                Ok(())
             }
+            None => Err(anyhow!("Element segment index out of range")),
         }
     }
 }
@@ -1,4 +1,4 @@
-use crate::core::{stack_entry::StackEntry, GlobalType, ValueType};
+use crate::core::{stack_entry::StackEntry, GlobalType, MutableType, Value, ValueType};
 use anyhow::{anyhow, Result};
 
 #[derive(Debug)]
@@ -8,23 +8,14 @@ pub struct Global {
 }
 
 fn check_value_type(global_type: &GlobalType, value: StackEntry) -> Result<StackEntry> {
-    match global_type.value_type() {
-        ValueType::I32 => match value {
-            StackEntry::I32Entry(i) => Ok(StackEntry::I32Entry(i)),
-            _ => Err(anyhow!("Global value type mismatch")),
-        },
-        ValueType::I64 => match value {
-            StackEntry::I64Entry(i) => Ok(StackEntry::I64Entry(i)),
-            _ => Err(anyhow!("Global value type mismatch")),
-        },
-        ValueType::F32 => match value {
-            StackEntry::F32Entry(i) => Ok(StackEntry::F32Entry(i)),
-            _ => Err(anyhow!("Global value type mismatch")),
-        },
-        ValueType::F64 => match value {
-            StackEntry::F64Entry(i) => Ok(StackEntry::F64Entry(i)),
-            _ => Err(anyhow!("Global value type mismatch")),
-        },
+    if value.value_type() == *global_type.value_type() {
+        Ok(value)
+    } else {
+        Err(anyhow!(
+            "Global of type {:?} given {:?}",
+            global_type.value_type(),
+            value.value_type()
+        ))
     }
 }
 
@@ -35,6 +26,14 @@ impl Global {
         Ok(Global { global_type, value })
     }
 
+    /// Make a global for the host to provide as an import, with the type of its value
+    pub fn from_value(value: Value, mutable_type: MutableType) -> Self {
+        Global {
+            global_type: GlobalType::new(value.value_type(), mutable_type),
+            value: value.into(),
+        }
+    }
+
     pub fn global_type(&self) -> &GlobalType {
         &self.global_type
     }
@@ -43,7 +42,6 @@ impl Global {
         self.global_type.is_mutable()
     }
 
-    #[allow(dead_code)]
     pub fn value_type(&self) -> &ValueType {
         self.global_type.value_type()
     }
@@ -57,7 +55,10 @@ impl Global {
             self.value = check_value_type(self.global_type(), value)?;
             Ok(())
         } else {
-            Err(anyhow!("Cannot mutate constant value"))
+            Err(anyhow!(
+                "Cannot mutate constant global of type {:?}",
+                self.value_type()
+            ))
         }
     }
 }
@@ -0,0 +1,108 @@
+//! How functions, tables, memories and globals are shared between a module, its imports
+//! and its exports. By default they are held in `Rc<RefCell<_>>`, which is cheap, but
+//! ties an instance to the thread which created it. With the `sync` feature they are held
+//! in `Arc`s around a read-write lock instead, so that instances can be sent to other
+//! threads, at the cost of taking a lock wherever a `RefCell` would have been borrowed.
+//!
+//! The lock has the same methods as `RefCell`, so the rest of the crate is written once
+//! against these names. The one difference is that borrowing something mutably which is
+//! already borrowed on the same thread deadlocks rather than panicking.
+
+#[cfg(not(feature = "sync"))]
+mod imp {
+    pub use std::cell::{Ref as LockRef, RefCell as Lock, RefMut as LockRefMut};
+    pub use std::rc::Rc as Shared;
+
+    /// Implemented by everything. With the `sync` feature, only by types which are `Send`
+    /// and `Sync`.
+    pub trait MaybeSendSync {}
+
+    impl<T: ?Sized> MaybeSendSync for T {}
+}
+
+#[cfg(feature = "sync")]
+mod imp {
+    use std::fmt;
+    use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    pub use std::sync::Arc as Shared;
+    pub type LockRef<'a, T> = RwLockReadGuard<'a, T>;
+    pub type LockRefMut<'a, T> = RwLockWriteGuard<'a, T>;
+
+    /// Implemented by everything. With the `sync` feature, only by types which are `Send`
+    /// and `Sync`.
+    pub trait MaybeSendSync: Send + Sync {}
+
+    impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
+
+    /// A read-write lock with the methods of `RefCell`. A panic while the lock is held
+    /// leaves whatever was being changed as it was, in the same way as unwinding through a
+    /// `RefCell` borrow would, so poisoning is ignored.
+    #[derive(Default)]
+    pub struct Lock<T: ?Sized>(RwLock<T>);
+
+    #[derive(Debug)]
+    pub struct BorrowError;
+
+    impl<T> Lock<T> {
+        pub fn new(value: T) -> Self {
+            Self(RwLock::new(value))
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn replace(&self, value: T) -> T {
+            std::mem::replace(&mut *self.borrow_mut(), value)
+        }
+    }
+
+    impl<T: ?Sized> Lock<T> {
+        pub fn borrow(&self) -> LockRef<'_, T> {
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn borrow_mut(&self) -> LockRefMut<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn try_borrow(&self) -> Result<LockRef<'_, T>, BorrowError> {
+            match self.0.try_read() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(poisoned)) => Ok(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+
+        pub fn try_borrow_mut(&self) -> Result<LockRefMut<'_, T>, BorrowError> {
+            match self.0.try_write() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(poisoned)) => Ok(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    impl<T: ?Sized + fmt::Debug> fmt::Debug for Lock<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.try_borrow() {
+                Ok(value) => f.debug_tuple("Lock").field(&&*value).finish(),
+                Err(_) => f.write_str("Lock(<locked>)"),
+            }
+        }
+    }
+}
+
+pub use imp::*;
+
+/// Something shared between a module and the host, or between modules
+pub type Handle<T> = Shared<Lock<T>>;
+
+pub fn handle<T>(value: T) -> Handle<T> {
+    Shared::new(Lock::new(value))
+}
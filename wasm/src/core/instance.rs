@@ -0,0 +1,2009 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use crate::core::stack::SavedStack;
+use crate::core::{
+    callable::{complete_host_call, CallEntry},
+    executor::execute_core::{CallStack, RunStatus, SavedCallStack},
+    handle,
+    module::{DataModule, FunctionModule},
+    observer::SharedObserver,
+    profiler::{Profile, Profiler},
+    Callable, DataStore, ExecutionObserver, ExportInfo, ExportValue, Exports, FuncType, Global,
+    Handle, InstanceId, InterruptHandle, LoadedModule, Lock, MemoryMonitor, MemoryView,
+    ReadOnlyDataStore, Stack, StackLimits, Value,
+};
+
+/// Per-call settings for `Instance::invoke_with_config`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionConfig {
+    /// The number of instructions the call may execute before it traps with
+    /// `TrapKind::OutOfFuel`, or `None` for no limit. Calls made with
+    /// `Instance::invoke_resumable_with_config` are suspended instead.
+    pub fuel: Option<u64>,
+}
+
+/// An instantiated module, which an embedder can call into by export name.
+#[derive(Debug)]
+pub struct Instance {
+    function_module: FunctionModule,
+    data_module: Lock<DataModule>,
+    exports: Exports,
+    stack_limits: StackLimits,
+    interrupt: InterruptHandle,
+    memory_monitor: MemoryMonitor,
+    observer: Option<SharedObserver>,
+    profiler: Option<Handle<Profiler>>,
+}
+
+impl Instance {
+    pub fn new(module: LoadedModule) -> Self {
+        let (function_module, data_module, exports) = module;
+        Self {
+            function_module,
+            data_module: Lock::new(data_module),
+            exports,
+            stack_limits: StackLimits::default(),
+            interrupt: InterruptHandle::new(),
+            memory_monitor: MemoryMonitor::new(),
+            observer: None,
+            profiler: None,
+        }
+    }
+
+    /// Identifies the instance in errors about functions shared between instances
+    pub fn id(&self) -> InstanceId {
+        self.function_module.instance_id()
+    }
+
+    /// Set the limits applied to every subsequent call into the instance
+    pub fn set_stack_limits(&mut self, stack_limits: StackLimits) {
+        self.stack_limits = stack_limits;
+    }
+
+    /// Report what every subsequent call into the instance executes to `observer`
+    pub fn set_observer(&mut self, observer: Option<Handle<dyn ExecutionObserver>>) {
+        self.observer = observer.map(SharedObserver::new);
+        self.profiler = None;
+    }
+
+    /// Count the instructions executed and the time spent in each function from now on,
+    /// until the profile is taken with `take_profile`. The profiler is the instance's
+    /// observer, so setting another observer stops profiling.
+    pub fn enable_profiling(&mut self) {
+        let profiler = handle(Profiler::default());
+        self.set_observer(Some(profiler.clone()));
+        self.profiler = Some(profiler);
+    }
+
+    /// What has been executed since profiling was enabled or the profile was last taken,
+    /// or `None` if profiling isn't enabled
+    pub fn take_profile(&self) -> Option<Profile> {
+        self.profiler
+            .as_ref()
+            .map(|profiler| profiler.borrow_mut().take_profile())
+    }
+
+    /// A handle which another thread can use to stop whatever this instance is executing
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// A handle which another thread can use to copy the memories of this instance
+    pub fn memory_monitor(&self) -> MemoryMonitor {
+        self.memory_monitor.clone()
+    }
+
+    /// Copy memory for any monitoring threads which are waiting. Calls do this as they
+    /// run, so this is only needed to answer them while the instance is idle.
+    pub fn serve_memory_requests(&self) {
+        self.memory_monitor.serve(&*self.data_module.borrow());
+    }
+
+    /// Run the start function, when the module was instantiated without running it. Does
+    /// nothing if there isn't one waiting, so it is only ever run once.
+    pub fn run_start(&self) -> Result<()> {
+        match self.function_module.take_pending_start() {
+            Some(start) => start.borrow().call(
+                &mut self.make_stack(),
+                &self.function_module,
+                &mut *self.data_module.borrow_mut(),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn function_module(&self) -> &FunctionModule {
+        &self.function_module
+    }
+
+    pub(crate) fn data_module(&self) -> &Lock<DataModule> {
+        &self.data_module
+    }
+
+    pub(crate) fn make_stack(&self) -> Stack {
+        let mut stack = Stack::with_limits(self.stack_limits.clone());
+        stack.set_interrupt_handle(Some(self.interrupt.clone()));
+        stack.set_memory_monitor(Some(self.memory_monitor.clone()));
+        stack.set_shared_observer(self.observer.clone());
+        if let Some(profiler) = &self.profiler {
+            profiler.borrow_mut().pause();
+        }
+        stack
+    }
+
+    /// A view of one of the memories of the instance. Host functions can't get one while
+    /// the instance is running, so take it beforehand; it stays usable between calls.
+    pub fn memory(&self, idx: usize) -> Result<MemoryView> {
+        self.data_module.borrow().memory(idx)
+    }
+
+    pub fn get_export(&self, name: &str) -> Option<&ExportValue> {
+        self.exports.get(name)
+    }
+
+    fn exported_global(&self, name: &str) -> Result<&Handle<Global>> {
+        match self.get_export(name) {
+            Some(ExportValue::Global(global)) => Ok(global),
+            Some(_) => Err(anyhow!("Export {} is not a global", name)),
+            None => Err(anyhow!("No export named {}", name)),
+        }
+    }
+
+    /// The current value of the exported global `name`
+    pub fn get_global(&self, name: &str) -> Result<Value> {
+        Ok(Value::from(
+            *self.exported_global(name)?.borrow().get_value(),
+        ))
+    }
+
+    /// Change the value of the exported global `name`, which must be mutable and have the
+    /// same type as the value
+    pub fn set_global(&self, name: &str, value: Value) -> Result<()> {
+        let mut global = self.exported_global(name)?.borrow_mut();
+        if !global.is_mutable() {
+            return Err(anyhow!("Global {} is immutable", name));
+        }
+        if value.value_type() != *global.value_type() {
+            return Err(anyhow!(
+                "Global {} is {:?} but {:?} was supplied",
+                name,
+                global.value_type(),
+                value.value_type()
+            ));
+        }
+        global.set_value(value.into())
+    }
+
+    /// Describe the exports of the instance, in the order the module declares them
+    pub fn exports(&self) -> impl Iterator<Item = ExportInfo<'_>> {
+        self.exports.iter().map(|(name, export)| {
+            let export_type = export.export_type();
+            ExportInfo {
+                name,
+                kind: export_type.kind(),
+                export_type,
+            }
+        })
+    }
+
+    // In name order, so that anything reported about them comes out the same way each time
+    pub(crate) fn export_values(&self) -> impl Iterator<Item = (&String, &ExportValue)> {
+        self.exports.iter_by_name()
+    }
+
+    /// Call the exported function `name`. The arguments must match the parameter types
+    /// of the function exactly, and the results are returned in order.
+    pub fn invoke(&self, name: &str, args: &[Value]) -> Result<Vec<Value>> {
+        self.invoke_with_config(name, args, &mut ExecutionConfig::default())
+    }
+
+    /// Call the exported function `name` with the given settings. Whether or not the call
+    /// succeeds, `config.fuel` is updated to the fuel that remains, so a host can meter a
+    /// sequence of calls against a single budget.
+    pub fn invoke_with_config(
+        &self,
+        name: &str,
+        args: &[Value],
+        config: &mut ExecutionConfig,
+    ) -> Result<Vec<Value>> {
+        let mut stack = self.make_stack();
+        stack.set_fuel(config.fuel);
+        let result =
+            self.invoke_with_store(name, args, &mut stack, &mut *self.data_module.borrow_mut());
+        config.fuel = stack.remaining_fuel();
+        result
+    }
+
+    /// Call the exported function `name` without allowing it to modify the memories or
+    /// globals of the instance. Any instruction which would do so fails the call instead.
+    pub fn invoke_read_only(&self, name: &str, args: &[Value]) -> Result<Vec<Value>> {
+        self.invoke_with_store(
+            name,
+            args,
+            &mut self.make_stack(),
+            &mut ReadOnlyDataStore::new(&*self.data_module.borrow()),
+        )
+    }
+
+    /// Call the exported function `name`, allowing host functions it calls to return
+    /// `HostFuncError::Pending`. When one does, the call is suspended and handed back as an
+    /// `Execution`, which carries on once the host supplies the results. The instance can
+    /// be used as normal while a call is suspended.
+    pub fn invoke_resumable(&self, name: &str, args: &[Value]) -> Result<Invocation<'_>> {
+        self.invoke_resumable_with_config(name, args, &ExecutionConfig::default())
+    }
+
+    /// Like `invoke_resumable`, except that running out of fuel suspends the call rather
+    /// than trapping. It carries on from the same instruction when `Execution::resume`
+    /// gives it more, so a host can share its time between many calls by handing each of
+    /// them a little fuel at a time.
+    pub fn invoke_resumable_with_config(
+        &self,
+        name: &str,
+        args: &[Value],
+        config: &ExecutionConfig,
+    ) -> Result<Invocation<'_>> {
+        let mut stack = self.make_stack();
+        stack.set_step_limit(config.fuel);
+        let callable = self.push_args(name, args, &mut stack)?;
+        let callable = callable.borrow();
+        let result_count = callable.func_type().return_types().len();
+
+        let (call_stack, status) = match callable.enter(&mut stack)? {
+            CallEntry::Body(body, func_idx) => {
+                let mut call_stack = CallStack::new(body, func_idx);
+                let status = call_stack.run(
+                    &mut stack,
+                    &self.function_module,
+                    &mut *self.data_module.borrow_mut(),
+                )?;
+                (Some(call_stack), status)
+            }
+            CallEntry::Complete => (None, RunStatus::Complete),
+            CallEntry::Pending(func_type) => (None, RunStatus::Pending(func_type)),
+        };
+
+        Execution::settle(self, stack, call_stack, result_count, status)
+    }
+
+    fn invoke_with_store(
+        &self,
+        name: &str,
+        args: &[Value],
+        stack: &mut Stack,
+        data_store: &mut impl DataStore,
+    ) -> Result<Vec<Value>> {
+        let callable = self.push_args(name, args, stack)?;
+        let callable = callable.borrow();
+
+        callable.call(stack, &self.function_module, data_store)?;
+
+        Ok(read_results(
+            stack,
+            callable.func_type().return_types().len(),
+        ))
+    }
+
+    /// Check the arguments to the exported function `name` and push them onto the stack
+    pub(crate) fn push_args(
+        &self,
+        name: &str,
+        args: &[Value],
+        stack: &mut Stack,
+    ) -> Result<Handle<Callable>> {
+        let callable = match self.get_export(name) {
+            Some(ExportValue::Function(callable)) => callable.clone(),
+            Some(_) => return Err(anyhow!("Export {} is not a function", name)),
+            None => return Err(anyhow!("No export named {}", name)),
+        };
+
+        let func_type = callable.borrow().func_type().clone();
+        if args.len() != func_type.arg_types().len() {
+            return Err(anyhow!(
+                "Function {} expects {} arguments but {} were supplied",
+                name,
+                func_type.arg_types().len(),
+                args.len()
+            ));
+        }
+        for (idx, (arg, arg_type)) in args.iter().zip(func_type.arg_types()).enumerate() {
+            if arg.value_type() != *arg_type {
+                return Err(anyhow!(
+                    "Argument {} to function {} is {:?} but {:?} was expected",
+                    idx,
+                    name,
+                    arg.value_type(),
+                    arg_type
+                ));
+            }
+        }
+
+        for arg in args {
+            stack.push((*arg).into());
+        }
+
+        Ok(callable)
+    }
+}
+
+pub(crate) fn read_results(stack: &Stack, result_count: usize) -> Vec<Value> {
+    stack
+        .working_top(result_count)
+        .iter()
+        .map(|entry| Value::from(*entry))
+        .collect()
+}
+
+/// The outcome of `Instance::invoke_resumable`, `Execution::resume_with` or
+/// `Execution::resume`
+// There is only ever one of these for each call, so boxing the execution saves nothing
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Invocation<'a> {
+    /// The call has finished, with these results
+    Complete(Vec<Value>),
+    /// A host function is waiting for its results
+    Pending(Execution<'a>),
+    /// The call has used up its fuel
+    OutOfFuel(Execution<'a>),
+}
+
+/// A call into an instance which is suspended, either while a host function waits for its
+/// results or until it is given more fuel. Dropping it abandons the call.
+pub struct Execution<'a> {
+    instance: &'a Instance,
+    stack: Stack,
+    /// The wasm functions being executed, or `None` if the exported function is the host
+    /// function that is waiting
+    call_stack: Option<CallStack>,
+    /// The type of the host function that is waiting, if there is one
+    pending: Option<FuncType>,
+    result_count: usize,
+}
+
+/// A suspended call which doesn't borrow anything from the instance, so it can be kept
+/// or sent somewhere else, and carried on with later by `Execution::restore`. It holds the
+/// stack and the position reached in each function. Memories, tables and globals stay
+/// with the instance, and aren't part of it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SavedExecution {
+    stack: SavedStack,
+    call_stack: Option<SavedCallStack>,
+    pending: Option<FuncType>,
+    result_count: usize,
+}
+
+impl<'a> Execution<'a> {
+    /// The type of the host function that is waiting for its results, or `None` if the
+    /// call is waiting for fuel
+    pub fn pending_func_type(&self) -> Option<&FuncType> {
+        self.pending.as_ref()
+    }
+
+    /// Carry on with the call, using `results` as the results of the waiting host function.
+    /// They must match its declared result types.
+    pub fn resume_with(mut self, results: Vec<Value>) -> Result<Invocation<'a>> {
+        let pending = self
+            .pending
+            .take()
+            .ok_or_else(|| anyhow!("The call isn't waiting for a host function"))?;
+        let status = match &mut self.call_stack {
+            Some(call_stack) => call_stack.resume_with(
+                &pending,
+                results,
+                &mut self.stack,
+                &self.instance.function_module,
+                &mut *self.instance.data_module.borrow_mut(),
+            )?,
+            None => {
+                complete_host_call(&pending, results, &mut self.stack)?;
+                RunStatus::Complete
+            }
+        };
+
+        Self::settle(
+            self.instance,
+            self.stack,
+            self.call_stack,
+            self.result_count,
+            status,
+        )
+    }
+
+    /// Carry on with a call which ran out of fuel, with `fuel` more, or `None` to let it
+    /// run to the end
+    pub fn resume(mut self, fuel: Option<u64>) -> Result<Invocation<'a>> {
+        let call_stack = match (&self.pending, &mut self.call_stack) {
+            (None, Some(call_stack)) => call_stack,
+            _ => return Err(anyhow!("The call is waiting for a host function")),
+        };
+
+        self.stack.set_step_limit(fuel);
+        let status = call_stack.run(
+            &mut self.stack,
+            &self.instance.function_module,
+            &mut *self.instance.data_module.borrow_mut(),
+        )?;
+
+        Self::settle(
+            self.instance,
+            self.stack,
+            self.call_stack,
+            self.result_count,
+            status,
+        )
+    }
+
+    fn settle(
+        instance: &'a Instance,
+        mut stack: Stack,
+        call_stack: Option<CallStack>,
+        result_count: usize,
+        status: RunStatus,
+    ) -> Result<Invocation<'a>> {
+        match status {
+            RunStatus::Complete => {
+                // The exported function's frame was pushed when it was entered, unless it
+                // was a host function, which never has one
+                if call_stack.is_some() {
+                    stack.pop_typed_frame()?;
+                }
+                Ok(Invocation::Complete(read_results(&stack, result_count)))
+            }
+            RunStatus::Pending(pending) => Ok(Invocation::Pending(Self {
+                instance,
+                stack,
+                call_stack,
+                pending: Some(pending),
+                result_count,
+            })),
+            RunStatus::Paused => Ok(Invocation::OutOfFuel(Self {
+                instance,
+                stack,
+                call_stack,
+                pending: None,
+                result_count,
+            })),
+        }
+    }
+
+    /// Copy out what the call needs to carry on. This fails if the call is part way
+    /// through a wasm function from another instance, since that can't be found again by
+    /// its index.
+    pub fn save(&self) -> Result<SavedExecution> {
+        let call_stack = match &self.call_stack {
+            Some(call_stack) => Some(call_stack.save(&self.instance.function_module)?),
+            None => None,
+        };
+        Ok(SavedExecution {
+            stack: self.stack.save(),
+            call_stack,
+            pending: self.pending.clone(),
+            result_count: self.result_count,
+        })
+    }
+
+    /// Carry on with a saved call in `instance`, which should be the instance it was saved
+    /// from or another instance of the same module. The saved call only refers to
+    /// functions by index, so restoring it into any other module gives meaningless results.
+    pub fn restore(instance: &'a Instance, saved: SavedExecution) -> Result<Self> {
+        let depth = saved.call_stack.as_ref().map_or(0, SavedCallStack::depth);
+        if saved.stack.frame_count() != depth
+            || (saved.call_stack.is_none() && saved.pending.is_none())
+        {
+            return Err(anyhow!("The saved call doesn't fit together"));
+        }
+
+        let mut stack = instance.make_stack();
+        stack.restore(saved.stack)?;
+        let call_stack = match saved.call_stack {
+            Some(call_stack) => {
+                let call_stack = CallStack::restore(call_stack, &instance.function_module)?;
+                call_stack.check_stack(&stack, &instance.function_module)?;
+                Some(call_stack)
+            }
+            None => None,
+        };
+
+        // The results are read off the stack when the call finishes, so there have to be as
+        // many as the function which was called returns
+        let pending = saved.pending;
+        let return_count = stack
+            .outermost_return_count()
+            .or_else(|| pending.as_ref().map(|p| p.return_types().len()));
+        if return_count != Some(saved.result_count) {
+            return Err(anyhow!(
+                "The saved call expects {} results from a function which returns {:?}",
+                saved.result_count,
+                return_count
+            ));
+        }
+
+        Ok(Self {
+            instance,
+            stack,
+            call_stack,
+            pending,
+            result_count: saved.result_count,
+        })
+    }
+}
+
+impl fmt::Debug for Execution<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Execution")
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        compiled::CompiledInstruction, resolve_raw_module, test_module::TestModule,
+        validate_raw_module, Callable, Data, ElemType, Element, EmptyResolver, ExportDesc,
+        ExportKind, ExportType, Expr, Func, FuncType, Global, GlobalType, HostFuncCallable,
+        HostFuncError, HostFuncResult, HostObjectResolver, ImportDesc, InstantiateConfig, Limits,
+        Locals, MemType, Memory, MemorySnapshot, MutableType, Profile, RawModule, Resolver,
+        SharedMemory, StartFunction, Table, TableType, TrapKind, ValueType, WasmError,
+    };
+    use crate::reader::ParserConfig;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn make_instance() -> Instance {
+        let module = TestModule::new()
+            .func(
+                FuncType::new(vec![ValueType::I32, ValueType::I32], vec![ValueType::I32]),
+                &[0x20, 0x00, 0x20, 0x01, 0x6A, 0x0B],
+            )
+            .export("add", ExportDesc::Func(0))
+            .build();
+
+        Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap())
+    }
+
+    #[test]
+    fn test_invoke() {
+        let instance = make_instance();
+
+        assert_eq!(
+            instance
+                .invoke("add", &[Value::I32(40), Value::I32(2)])
+                .unwrap(),
+            vec![Value::I32(42)]
+        );
+        assert_eq!(
+            instance
+                .invoke("add", &[Value::I32(-1), Value::I32(-1)])
+                .unwrap(),
+            vec![Value::I32(-2)]
+        );
+    }
+
+    // Records the target and level of every event
+    #[derive(Default)]
+    struct TargetSubscriber {
+        events: Arc<Mutex<Vec<(String, tracing::Level)>>>,
+    }
+
+    impl tracing::Subscriber for TargetSubscriber {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            let metadata = event.metadata();
+            self.events
+                .lock()
+                .unwrap()
+                .push((metadata.target().to_string(), *metadata.level()));
+        }
+        fn enter(&self, _span: &tracing::span::Id) {}
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[test]
+    fn test_trace_events() {
+        let subscriber = TargetSubscriber::default();
+        let events = subscriber.events.clone();
+        tracing::subscriber::with_default(subscriber, || {
+            make_instance()
+                .invoke("add", &[Value::I32(1), Value::I32(2)])
+                .unwrap();
+        });
+
+        let events = events.lock().unwrap();
+        let count = |target: &str, level| {
+            events
+                .iter()
+                .filter(|event| (event.0.as_str(), event.1) == (target, level))
+                .count()
+        };
+        // Validating, initializing, and one event for each instruction apart from the end
+        assert_eq!(count("wasm_instantiate", tracing::Level::DEBUG), 2);
+        assert_eq!(count("wasm_execute", tracing::Level::TRACE), 3);
+    }
+
+    // Writes down everything it is told
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Vec<String>,
+    }
+
+    impl ExecutionObserver for RecordingObserver {
+        fn before_instruction(
+            &mut self,
+            func_idx: Option<usize>,
+            offset: usize,
+            instruction: &CompiledInstruction,
+            stack_depth: usize,
+        ) {
+            self.events.push(format!(
+                "{:?}:{} {} {}",
+                func_idx,
+                offset,
+                instruction.name(),
+                stack_depth
+            ));
+        }
+        fn on_call(&mut self, func_idx: Option<usize>) {
+            self.events.push(format!("call {:?}", func_idx));
+        }
+        fn on_return(&mut self, func_idx: Option<usize>) {
+            self.events.push(format!("return {:?}", func_idx));
+        }
+        fn on_memory_grow(&mut self, mem_idx: usize, old_pages: usize, new_pages: usize) {
+            self.events
+                .push(format!("grow {} {}->{}", mem_idx, old_pages, new_pages));
+        }
+    }
+
+    #[test]
+    fn test_execution_observer() {
+        let returns_i32 = FuncType::new(vec![], vec![ValueType::I32]);
+        let module = TestModule::new()
+            // call 1, i32.const 1, memory.grow, i32.add
+            .func(
+                returns_i32.clone(),
+                &[0x10, 0x01, 0x41, 0x01, 0x40, 0x00, 0x6A, 0x0B],
+            )
+            // i32.const 5
+            .func(returns_i32, &[0x41, 0x05, 0x0B])
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .export("run", ExportDesc::Func(0))
+            .build();
+        let mut instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        let observer = handle(RecordingObserver::default());
+        instance.set_observer(Some(observer.clone()));
+        assert_eq!(instance.invoke("run", &[]).unwrap(), vec![Value::I32(6)]);
+        assert_eq!(
+            observer.borrow().events,
+            vec![
+                "call Some(0)",
+                "Some(0):0 Call 0",
+                "call Some(1)",
+                "Some(1):0 I32Const 0",
+                "return Some(1)",
+                "Some(0):1 I32Const 1",
+                "Some(0):2 MemoryGrow 2",
+                "grow 0 1->2",
+                "Some(0):3 I32Add 2",
+                "return Some(0)",
+            ]
+        );
+
+        // Without the observer, nothing more is recorded
+        instance.set_observer(None);
+        assert_eq!(instance.invoke("run", &[]).unwrap(), vec![Value::I32(7)]);
+        assert_eq!(observer.borrow().events.len(), 10);
+    }
+
+    #[test]
+    fn test_take_profile() {
+        let mut instance = make_instance();
+        assert_eq!(instance.take_profile(), None);
+
+        instance.enable_profiling();
+        for _ in 0..2 {
+            instance
+                .invoke("add", &[Value::I32(1), Value::I32(2)])
+                .unwrap();
+        }
+        let profile = instance.take_profile().unwrap();
+        assert_eq!(profile.functions.len(), 1);
+        assert_eq!(profile.functions[0].func_idx, Some(0));
+        assert_eq!(profile.functions[0].calls, 2);
+        assert_eq!(profile.total_instructions(), 6);
+
+        assert_eq!(instance.take_profile(), Some(Profile::default()));
+    }
+
+    #[test]
+    fn test_invoke_rejects_bad_calls() {
+        let instance = make_instance();
+
+        assert!(instance.invoke("missing", &[]).is_err());
+        assert!(instance.invoke("add", &[Value::I32(1)]).is_err());
+        assert!(instance
+            .invoke("add", &[Value::I32(1), Value::I64(2)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_invoke_read_only() {
+        let nullary = FuncType::new(vec![], vec![]);
+        let module = TestModule::new()
+            // i32.const 0, i32.load, global.get 0, i32.add
+            .func(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                &[0x41, 0x00, 0x28, 0x00, 0x00, 0x23, 0x00, 0x6A, 0x0B],
+            )
+            // i32.const 0, i32.const 5, i32.store
+            .func(
+                nullary.clone(),
+                &[0x41, 0x00, 0x41, 0x05, 0x36, 0x00, 0x00, 0x0B],
+            )
+            // i32.const 1, memory.grow, drop
+            .func(nullary.clone(), &[0x41, 0x01, 0x40, 0x00, 0x1A, 0x0B])
+            // i32.const 7, global.set 0
+            .func(nullary, &[0x41, 0x07, 0x24, 0x00, 0x0B])
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .global(
+                GlobalType::new(ValueType::I32, MutableType::Var),
+                &[0x41, 0x00, 0x0B],
+            )
+            .export("read", ExportDesc::Func(0))
+            .export("store", ExportDesc::Func(1))
+            .export("grow", ExportDesc::Func(2))
+            .export("set_global", ExportDesc::Func(3))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        instance.invoke("store", &[]).unwrap();
+        instance.invoke("set_global", &[]).unwrap();
+        assert_eq!(
+            instance.invoke_read_only("read", &[]).unwrap(),
+            vec![Value::I32(12)]
+        );
+
+        for name in &["store", "grow", "set_global"] {
+            let err = instance.invoke_read_only(name, &[]).unwrap_err();
+            assert!(format!("{}", err).contains("read-only"));
+        }
+
+        // The same functions still work normally outside of read-only mode
+        instance.invoke("grow", &[]).unwrap();
+    }
+
+    #[test]
+    fn test_memory_view() {
+        let module = TestModule::new()
+            // i32.const 4, i32.load
+            .func(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                &[0x41, 0x04, 0x28, 0x02, 0x00, 0x0B],
+            )
+            // i32.const 0, i32.const 5, i32.store
+            .func(
+                FuncType::new(vec![], vec![]),
+                &[0x41, 0x00, 0x41, 0x05, 0x36, 0x02, 0x00, 0x0B],
+            )
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .export("read", ExportDesc::Func(0))
+            .export("store", ExportDesc::Func(1))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+        let memory = instance.memory(0).unwrap();
+        assert!(instance.memory(1).is_err());
+
+        memory.set(4, 42i32).unwrap();
+        assert_eq!(instance.invoke("read", &[]).unwrap(), vec![Value::I32(42)]);
+
+        instance.invoke("store", &[]).unwrap();
+        assert_eq!(memory.read(0, 4).unwrap(), vec![5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_exports() {
+        let module = TestModule::new()
+            // local.get 0, memory.grow
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+                &[0x20, 0x00, 0x40, 0x00, 0x0B],
+            )
+            .memory(MemType::new(Limits::Bounded(1, 4)))
+            .table(TableType::new(ElemType::ExternRef, Limits::Bounded(2, 8)))
+            .global(
+                GlobalType::new(ValueType::F64, MutableType::Const),
+                &[0x44, 0, 0, 0, 0, 0, 0, 0, 0, 0x0B],
+            )
+            .export("grow", ExportDesc::Func(0))
+            .export("memory", ExportDesc::Mem(0))
+            .export("answer", ExportDesc::Global(0))
+            .export("refs", ExportDesc::Table(0))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        let exports: Vec<ExportInfo> = instance.exports().collect();
+        let names: Vec<&str> = exports.iter().map(|export| export.name).collect();
+        assert_eq!(names, vec!["grow", "memory", "answer", "refs"]);
+        let kinds: Vec<ExportKind> = exports.iter().map(|export| export.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ExportKind::Func,
+                ExportKind::Memory,
+                ExportKind::Global,
+                ExportKind::Table
+            ]
+        );
+        assert_eq!(
+            exports[0].export_type,
+            ExportType::Func(FuncType::new(vec![ValueType::I32], vec![ValueType::I32]))
+        );
+        assert_eq!(
+            exports[2].export_type,
+            ExportType::Global(GlobalType::new(ValueType::F64, MutableType::Const))
+        );
+        assert_eq!(
+            exports[3].export_type,
+            ExportType::Table(TableType::new(ElemType::ExternRef, Limits::Bounded(2, 8)))
+        );
+
+        // A memory which has grown reports its current size
+        instance.invoke("grow", &[Value::I32(2)]).unwrap();
+        let memory = instance.exports().nth(1).unwrap();
+        assert_eq!(
+            memory.export_type,
+            ExportType::Memory(MemType::new(Limits::Bounded(3, 4)))
+        );
+    }
+
+    #[test]
+    fn test_global_access() {
+        let global_type = |mutability| GlobalType::new(ValueType::I32, mutability);
+        let module = TestModule::new()
+            // global.get 0
+            .func(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                &[0x23, 0x00, 0x0B],
+            )
+            .global(global_type(MutableType::Var), &[0x41, 0x03, 0x0B])
+            .global(global_type(MutableType::Const), &[0x41, 0x09, 0x0B])
+            .export("limit", ExportDesc::Global(0))
+            .export("version", ExportDesc::Global(1))
+            .export("get_limit", ExportDesc::Func(0))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        assert_eq!(instance.get_global("limit").unwrap(), Value::I32(3));
+        assert_eq!(instance.get_global("version").unwrap(), Value::I32(9));
+        instance.set_global("limit", Value::I32(-8)).unwrap();
+        assert_eq!(
+            instance.invoke("get_limit", &[]).unwrap(),
+            vec![Value::I32(-8)]
+        );
+
+        assert!(instance.set_global("version", Value::I32(10)).is_err());
+        assert!(instance.set_global("limit", Value::I64(10)).is_err());
+        assert!(instance.get_global("get_limit").is_err());
+        assert!(instance.get_global("missing").is_err());
+        assert_eq!(instance.get_global("limit").unwrap(), Value::I32(-8));
+    }
+
+    #[test]
+    fn test_start_function_override() {
+        let nullary = FuncType::new(vec![], vec![]);
+        let make_module = || {
+            TestModule::new()
+                // i32.const 1, global.set 0
+                .func(nullary.clone(), &[0x41, 0x01, 0x24, 0x00, 0x0B])
+                // i32.const 2, global.set 0
+                .func(nullary.clone(), &[0x41, 0x02, 0x24, 0x00, 0x0B])
+                // global.get 0
+                .func(
+                    FuncType::new(vec![], vec![ValueType::I32]),
+                    &[0x23, 0x00, 0x0B],
+                )
+                .global(
+                    GlobalType::new(ValueType::I32, MutableType::Var),
+                    &[0x41, 0x00, 0x0B],
+                )
+                .start(0)
+                .export("setup", ExportDesc::Func(1))
+                .export("get", ExportDesc::Func(2))
+                .build()
+        };
+        let instantiate = |start: StartFunction| {
+            validate_raw_module(make_module(), EmptyResolver::instance())
+                .unwrap()
+                .instantiate_with_start(&start)
+                .map(Instance::new)
+        };
+
+        for (start, expected) in vec![
+            (StartFunction::Declared, 1),
+            (StartFunction::Export("setup".to_string()), 2),
+            (StartFunction::Suppressed, 0),
+        ] {
+            let instance = instantiate(start).unwrap();
+            assert_eq!(
+                instance.invoke("get", &[]).unwrap(),
+                vec![Value::I32(expected)]
+            );
+        }
+
+        // Only exported functions of the right type can stand in for the start function
+        assert!(instantiate(StartFunction::Export("get".to_string())).is_err());
+        assert!(instantiate(StartFunction::Export("missing".to_string())).is_err());
+
+        // The host can run the start function itself once it is ready
+        let instance = Instance::new(
+            validate_raw_module(make_module(), EmptyResolver::instance())
+                .unwrap()
+                .instantiate()
+                .unwrap(),
+        );
+        assert_eq!(instance.invoke("get", &[]).unwrap(), vec![Value::I32(0)]);
+        instance.run_start().unwrap();
+        assert_eq!(instance.invoke("get", &[]).unwrap(), vec![Value::I32(1)]);
+
+        // It only runs once, so running it again doesn't undo what has happened since
+        instance.invoke("setup", &[]).unwrap();
+        instance.run_start().unwrap();
+        assert_eq!(instance.invoke("get", &[]).unwrap(), vec![Value::I32(2)]);
+
+        let instance = Instance::new(
+            validate_raw_module(make_module(), EmptyResolver::instance())
+                .unwrap()
+                .instantiate_with_config(&InstantiateConfig {
+                    start: StartFunction::Export("setup".to_string()),
+                    run_start: false,
+                })
+                .unwrap(),
+        );
+        assert_eq!(instance.invoke("get", &[]).unwrap(), vec![Value::I32(0)]);
+        instance.run_start().unwrap();
+        assert_eq!(instance.invoke("get", &[]).unwrap(), vec![Value::I32(2)]);
+    }
+
+    #[test]
+    fn test_memory_indices_are_validated() {
+        let load_from = |body: &[u8]| {
+            TestModule::new()
+                .func(FuncType::new(vec![], vec![ValueType::I32]), body)
+                .memory(MemType::new(Limits::Unbounded(1)))
+                .export("load", ExportDesc::Func(0))
+                .build()
+        };
+
+        // i32.const 0, i32.load with an explicit memory index of 0
+        let module = load_from(&[0x41, 0x00, 0x28, 0x42, 0x00, 0x00, 0x0B]);
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+        assert_eq!(instance.invoke("load", &[]).unwrap(), vec![Value::I32(0)]);
+
+        // The same load from memory 1, which the module doesn't have
+        let module = load_from(&[0x41, 0x00, 0x28, 0x42, 0x01, 0x00, 0x0B]);
+        let error = resolve_raw_module(module, EmptyResolver::instance())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<WasmError>(),
+            Some(WasmError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_multiple_memories() {
+        let returns_i32 = FuncType::new(vec![], vec![ValueType::I32]);
+        let module = TestModule::new()
+            .import(
+                "env",
+                "memory",
+                ImportDesc::MemType(MemType::new(Limits::Bounded(1, 1))),
+            )
+            // i32.const 8, i32.load from memory 1
+            .func(
+                returns_i32.clone(),
+                &[0x41, 0x08, 0x28, 0x42, 0x01, 0x00, 0x0B],
+            )
+            // i32.const 0, i32.const 0, i32.const 4, memory.copy from memory 0 to 1,
+            // i32.const 4, i32.const 0, i32.load from memory 1, i32.store to memory 0,
+            // i32.const 4, i32.load from memory 0
+            .func(
+                returns_i32.clone(),
+                &[
+                    0x41, 0x00, 0x41, 0x00, 0x41, 0x04, 0xFC, 0x0A, 0x01, 0x00, 0x41, 0x04, 0x41,
+                    0x00, 0x28, 0x42, 0x01, 0x00, 0x36, 0x02, 0x00, 0x41, 0x04, 0x28, 0x02, 0x00,
+                    0x0B,
+                ],
+            )
+            // i32.const 1, memory.grow memory 1, memory.size memory 1, i32.add,
+            // memory.size memory 0, i32.add
+            .func(
+                returns_i32,
+                &[
+                    0x41, 0x01, 0x40, 0x01, 0x3F, 0x01, 0x6A, 0x3F, 0x00, 0x6A, 0x0B,
+                ],
+            )
+            // local.get 0, i32.const 0, local.get 1, memory.copy from memory 0 to 1
+            .func(
+                FuncType::new(vec![ValueType::I32, ValueType::I32], vec![]),
+                &[
+                    0x20, 0x00, 0x41, 0x00, 0x20, 0x01, 0xFC, 0x0A, 0x01, 0x00, 0x0B,
+                ],
+            )
+            .memory(MemType::new(Limits::Unbounded(1)))
+            // The local memory is memory 1, after the imported one
+            .data(Data::new(
+                1,
+                Expr::new(vec![0x41, 0x08, 0x0B]),
+                vec![7, 0, 0, 0],
+            ))
+            .export("load", ExportDesc::Func(0))
+            .export("copy", ExportDesc::Func(1))
+            .export("grow", ExportDesc::Func(2))
+            .export("copy_to", ExportDesc::Func(3))
+            .build();
+
+        let mut resolver = HostObjectResolver::new(EmptyResolver::instance());
+        let imported = resolver.define_memory("env", "memory", Memory::new_from_bounds(1, Some(1)));
+        imported.borrow_mut().store(0, 0x1234_5678_u32).unwrap();
+        let instance = Instance::new(resolve_raw_module(module, &resolver).unwrap());
+
+        assert_eq!(instance.invoke("load", &[]).unwrap(), vec![Value::I32(7)]);
+        assert_eq!(
+            instance.invoke("copy", &[]).unwrap(),
+            vec![Value::I32(0x1234_5678)]
+        );
+        assert_eq!(imported.borrow().load::<u32>(4).unwrap(), 0x1234_5678);
+
+        // Growing memory 1 leaves memory 0 alone: 1 + 2 + 1
+        assert_eq!(instance.invoke("grow", &[]).unwrap(), vec![Value::I32(4)]);
+        assert_eq!(imported.borrow().current_size(), 1);
+
+        // Copies which run off the end of either memory trap before anything is copied,
+        // including one far bigger than both
+        for (dst, length) in [(0, -1), (0, 0x1_0001), (0x3_FFFE, 4)] {
+            let error = instance
+                .invoke("copy_to", &[Value::I32(dst), Value::I32(length)])
+                .unwrap_err();
+            assert_eq!(
+                WasmError::trap_kind(&error),
+                Some(TrapKind::MemoryOutOfBounds)
+            );
+        }
+        assert_eq!(instance.invoke("load", &[]).unwrap(), vec![Value::I32(7)]);
+    }
+
+    #[test]
+    fn test_passive_element_segments() {
+        let returns_i32 = FuncType::new(vec![], vec![ValueType::I32]);
+        let module = TestModule::new()
+            .func(returns_i32.clone(), &[0x41, 0x07, 0x0B])
+            .func(returns_i32, &[0x41, 0x09, 0x0B])
+            // table.init 0 0 copying both functions to the start of the table, then
+            // elem.drop 0
+            .func(
+                FuncType::new(vec![], vec![]),
+                &[
+                    0x41, 0x00, 0x41, 0x00, 0x41, 0x02, 0xFC, 0x0C, 0x00, 0x00, 0xFC, 0x0D, 0x00,
+                    0x0B,
+                ],
+            )
+            // local.get 0, call_indirect 0 0
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+                &[0x20, 0x00, 0x11, 0x00, 0x00, 0x0B],
+            )
+            .table(TableType::new(ElemType::FuncRef, Limits::Unbounded(2)))
+            .elem(Element::new_passive(vec![0, 1]))
+            .elem(Element::new_declarative(vec![1]))
+            .export("init", ExportDesc::Func(2))
+            .export("call", ExportDesc::Func(3))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        // Passive segments aren't copied into the table when the module is instantiated
+        let error = instance.invoke("call", &[Value::I32(0)]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::UninitializedElement)
+        );
+
+        instance.invoke("init", &[]).unwrap();
+        assert_eq!(
+            instance.invoke("call", &[Value::I32(0)]).unwrap(),
+            vec![Value::I32(7)]
+        );
+        assert_eq!(
+            instance.invoke("call", &[Value::I32(1)]).unwrap(),
+            vec![Value::I32(9)]
+        );
+
+        // The segment was dropped by the first call, so there is nothing left to copy
+        let error = instance.invoke("init", &[]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::TableOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn test_multiple_tables() {
+        let returns_i32 = FuncType::new(vec![], vec![ValueType::I32]);
+        let module = TestModule::new()
+            .func(returns_i32.clone(), &[0x41, 0x07, 0x0B])
+            .func(returns_i32, &[0x41, 0x09, 0x0B])
+            // i32.const 0, i32.const 0, local.get 0, table.copy from table 0 to 1
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![]),
+                &[
+                    0x41, 0x00, 0x41, 0x00, 0x20, 0x00, 0xFC, 0x0E, 0x01, 0x00, 0x0B,
+                ],
+            )
+            // local.get 0, call_indirect 0 1
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+                &[0x20, 0x00, 0x11, 0x00, 0x01, 0x0B],
+            )
+            .table(TableType::new(ElemType::FuncRef, Limits::Unbounded(2)))
+            .table(TableType::new(ElemType::FuncRef, Limits::Unbounded(3)))
+            .elem(Element::new(
+                0,
+                Expr::new(vec![0x41, 0x00, 0x0B]),
+                vec![0, 1],
+            ))
+            .export("copy", ExportDesc::Func(2))
+            .export("call", ExportDesc::Func(3))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        // The element segment only filled in table 0
+        let error = instance.invoke("call", &[Value::I32(0)]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::UninitializedElement)
+        );
+
+        instance.invoke("copy", &[Value::I32(2)]).unwrap();
+        assert_eq!(
+            instance.invoke("call", &[Value::I32(0)]).unwrap(),
+            vec![Value::I32(7)]
+        );
+        assert_eq!(
+            instance.invoke("call", &[Value::I32(1)]).unwrap(),
+            vec![Value::I32(9)]
+        );
+        let error = instance.invoke("call", &[Value::I32(2)]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::UninitializedElement)
+        );
+
+        // Table 1 has room for three entries, but table 0 only has two to copy
+        let error = instance.invoke("copy", &[Value::I32(3)]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::TableOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn test_indirect_call_mismatch_names_the_entry() {
+        let module = TestModule::new()
+            .func(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                &[0x41, 0x07, 0x0B],
+            )
+            // local.get 0, call_indirect 1 0
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+                &[0x20, 0x00, 0x11, 0x01, 0x00, 0x0B],
+            )
+            .table(TableType::new(ElemType::FuncRef, Limits::Unbounded(1)))
+            .elem(Element::new(0, Expr::new(vec![0x41, 0x00, 0x0B]), vec![0]))
+            .export("call", ExportDesc::Func(1))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        let error = instance.invoke("call", &[Value::I32(0)]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::IndirectCallTypeMismatch)
+        );
+        let message = format!("{:#}", error);
+        assert!(message.contains(&format!("table entry 0 is function 0 of {}", instance.id())));
+    }
+
+    #[test]
+    fn test_invoke_with_fuel() {
+        let instance = make_instance();
+        let args = [Value::I32(40), Value::I32(2)];
+
+        // The function body is three instructions long
+        let mut config = ExecutionConfig { fuel: Some(10) };
+        assert_eq!(
+            instance
+                .invoke_with_config("add", &args, &mut config)
+                .unwrap(),
+            vec![Value::I32(42)]
+        );
+        assert_eq!(config.fuel, Some(7));
+
+        let mut config = ExecutionConfig { fuel: Some(2) };
+        let error = instance
+            .invoke_with_config("add", &args, &mut config)
+            .unwrap_err();
+        assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::OutOfFuel));
+        assert_eq!(config.fuel, Some(0));
+
+        let mut config = ExecutionConfig::default();
+        assert!(instance
+            .invoke_with_config("add", &args, &mut config)
+            .is_ok());
+        assert_eq!(config.fuel, None);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let module = TestModule::new()
+            // loop (result i32) br 0 end
+            .func(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                &[0x03, 0x7F, 0x0C, 0x00, 0x0B, 0x0B],
+            )
+            .export("spin", ExportDesc::Func(0))
+            .build();
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+        let handle = instance.interrupt_handle();
+
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.interrupt();
+        });
+        let error = instance.invoke("spin", &[]).unwrap_err();
+        interrupter.join().unwrap();
+        assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Interrupted));
+
+        // The trap uses up the interrupt, so a later call runs normally
+        assert!(!instance.interrupt_handle().is_interrupted());
+        let mut config = ExecutionConfig { fuel: Some(100) };
+        let error = instance
+            .invoke_with_config("spin", &[], &mut config)
+            .unwrap_err();
+        assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::OutOfFuel));
+
+        // An interrupt with nothing running stops the next call
+        instance.interrupt_handle().interrupt();
+        let error = instance.invoke("spin", &[]).unwrap_err();
+        assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Interrupted));
+    }
+
+    fn make_counter_instance() -> Instance {
+        let module = TestModule::new()
+            // loop, add one to the i32 at address 0, br 0, end
+            .func(
+                FuncType::new(vec![], vec![]),
+                &[
+                    0x03, 0x40, 0x41, 0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x41, 0x01, 0x6A, 0x36,
+                    0x02, 0x00, 0x0C, 0x00, 0x0B, 0x0B,
+                ],
+            )
+            .memory(MemType::new(Limits::Unbounded(2)))
+            .export("count", ExportDesc::Func(0))
+            .build();
+        Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap())
+    }
+
+    #[test]
+    fn test_memory_monitor() {
+        let instance = make_counter_instance();
+        let monitor = instance.memory_monitor();
+        let interrupt = instance.interrupt_handle();
+
+        let observer = thread::spawn(move || {
+            let counter = |snapshot: &MemorySnapshot| {
+                u32::from_le_bytes([
+                    snapshot.bytes[0],
+                    snapshot.bytes[1],
+                    snapshot.bytes[2],
+                    snapshot.bytes[3],
+                ])
+            };
+            let first = monitor.snapshot(0, Duration::from_secs(10));
+            let second = monitor.snapshot_pages(0, 0..1, Duration::from_secs(10));
+            interrupt.interrupt();
+
+            let (first, second) = (first.unwrap(), second.unwrap());
+            assert_eq!(first.page_count, 2);
+            assert_eq!(first.pages(), 0..2);
+            assert_eq!(second.pages(), 0..1);
+            // The guest ran on between the two copies
+            assert!(counter(&second) > counter(&first));
+        });
+
+        let error = instance.invoke("count", &[]).unwrap_err();
+        observer.join().unwrap();
+        assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Interrupted));
+    }
+
+    #[test]
+    fn test_memory_monitor_while_idle() {
+        let instance = make_counter_instance();
+        instance.memory(0).unwrap().set(0, 7u32).unwrap();
+
+        // Nothing answers while the instance is idle and not serving
+        let monitor = instance.memory_monitor();
+        assert!(monitor.snapshot(0, Duration::from_millis(10)).is_err());
+
+        let observer = thread::spawn(move || {
+            let snapshot = monitor.snapshot_pages(0, 1..5, Duration::from_secs(10))?;
+            assert_eq!(snapshot.pages(), 1..2);
+            assert!(monitor.snapshot(1, Duration::from_secs(10)).is_err());
+            monitor.snapshot(0, Duration::from_secs(10))
+        });
+        while !observer.is_finished() {
+            instance.serve_memory_requests();
+            thread::yield_now();
+        }
+        let snapshot = observer.join().unwrap().unwrap();
+        assert_eq!(&snapshot.bytes[..4], &[7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let module = TestModule::new()
+            // call 0
+            .func(FuncType::new(vec![], vec![]), &[0x10, 0x00, 0x0B])
+            // local.get 0, i32.eqz, br_if 0, local.get 0, i32.const 1, i32.sub, call 1
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![]),
+                &[
+                    0x20, 0x00, 0x45, 0x0D, 0x00, 0x20, 0x00, 0x41, 0x01, 0x6B, 0x10, 0x01, 0x0B,
+                ],
+            )
+            .export("forever", ExportDesc::Func(0))
+            .export("countdown", ExportDesc::Func(1))
+            .build();
+        let mut instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        // Unbounded recursion traps cleanly with the default limits
+        let error = instance.invoke("forever", &[]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::CallStackExhausted)
+        );
+
+        // The outermost call counts towards the depth too
+        instance.set_stack_limits(StackLimits {
+            max_call_depth: 10,
+            ..StackLimits::default()
+        });
+        assert!(instance.invoke("countdown", &[Value::I32(9)]).is_ok());
+        let error = instance.invoke("countdown", &[Value::I32(10)]).unwrap_err();
+        assert_eq!(
+            WasmError::trap_kind(&error),
+            Some(TrapKind::CallStackExhausted)
+        );
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let module = TestModule::new()
+            // local.get 1, local.get 0, i32.eqz, br_if 0, drop,
+            // local.get 0, i32.const 1, i32.sub, local.get 1, local.get 0, i32.add,
+            // return_call 0
+            .func(
+                FuncType::new(vec![ValueType::I32, ValueType::I32], vec![ValueType::I32]),
+                &[
+                    0x20, 0x01, 0x20, 0x00, 0x45, 0x0D, 0x00, 0x1A, 0x20, 0x00, 0x41, 0x01, 0x6B,
+                    0x20, 0x01, 0x20, 0x00, 0x6A, 0x12, 0x00, 0x0B,
+                ],
+            )
+            // i32.const 1, i32.const 0, return_call 0
+            .func(
+                FuncType::new(vec![], vec![]),
+                &[0x41, 0x01, 0x41, 0x00, 0x12, 0x00, 0x0B],
+            )
+            .export("sum", ExportDesc::Func(0))
+            .export("mismatch", ExportDesc::Func(1))
+            .build();
+        let mut instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+
+        // Each tail call replaces the frame of its caller, so the depth never grows
+        instance.set_stack_limits(StackLimits {
+            max_call_depth: 2,
+            ..StackLimits::default()
+        });
+        assert_eq!(
+            instance
+                .invoke("sum", &[Value::I32(1000), Value::I32(0)])
+                .unwrap(),
+            vec![Value::I32(500500)]
+        );
+
+        // The callee has to return what the caller would have
+        assert!(instance.invoke("mismatch", &[]).is_err());
+    }
+
+    // Resolves env:double to a host function of whatever type the test asks for
+    struct HostResolver {
+        func_type: FuncType,
+        results: fn(&[Value]) -> HostFuncResult,
+    }
+
+    impl Resolver for HostResolver {
+        fn resolve_function(
+            &self,
+            mod_name: &str,
+            name: &str,
+            _func_type: &FuncType,
+        ) -> Result<Handle<Callable>> {
+            assert_eq!((mod_name, name), ("env", "double"));
+            let results = self.results;
+            Ok(handle(Callable::from(HostFuncCallable::new(
+                self.func_type.clone(),
+                results,
+            ))))
+        }
+        fn resolve_table(
+            &self,
+            _mod_name: &str,
+            _name: &str,
+            _table_type: &TableType,
+        ) -> Result<Handle<Table>> {
+            unreachable!()
+        }
+        fn resolve_memory(
+            &self,
+            _mod_name: &str,
+            _name: &str,
+            _mem_type: &MemType,
+        ) -> Result<Handle<Memory>> {
+            unreachable!()
+        }
+        fn resolve_global(
+            &self,
+            _mod_name: &str,
+            _name: &str,
+            _global_type: &GlobalType,
+        ) -> Result<Handle<Global>> {
+            unreachable!()
+        }
+    }
+
+    fn double(args: &[Value]) -> HostFuncResult {
+        match args {
+            [Value::I32(v)] => Ok(vec![Value::I32(v * 2)]),
+            _ => panic!("Unexpected arguments {:?}", args),
+        }
+    }
+
+    fn make_host_instance(resolver: &HostResolver) -> Result<Instance> {
+        let unary_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+
+        // (func (param i32) (result i32) local.get 0 call $double i32.const 1 i32.add)
+        let module = TestModule::new()
+            .import_func("env", "double", unary_i32.clone())
+            .func(unary_i32, &[0x20, 0x00, 0x10, 0x00, 0x41, 0x01, 0x6A, 0x0B])
+            .export("double_plus_one", ExportDesc::Func(1))
+            .build();
+
+        Ok(Instance::new(resolve_raw_module(module, resolver)?))
+    }
+
+    #[test]
+    fn test_invoke_host_function() {
+        let instance = make_host_instance(&HostResolver {
+            func_type: FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            results: double,
+        })
+        .unwrap();
+
+        assert_eq!(
+            instance
+                .invoke("double_plus_one", &[Value::I32(20)])
+                .unwrap(),
+            vec![Value::I32(41)]
+        );
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_instance_moves_between_threads() {
+        let instance = make_host_instance(&HostResolver {
+            func_type: FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            results: double,
+        })
+        .unwrap();
+
+        let results = std::thread::spawn(move || {
+            instance
+                .invoke("double_plus_one", &[Value::I32(20)])
+                .unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(results, vec![Value::I32(41)]);
+    }
+
+    #[test]
+    fn test_tail_call_to_host_function() {
+        let unary_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+
+        // (func (param i32) (result i32) local.get 0 return_call $double)
+        let module = TestModule::new()
+            .import_func("env", "double", unary_i32.clone())
+            .func(unary_i32.clone(), &[0x20, 0x00, 0x12, 0x00, 0x0B])
+            .export("double", ExportDesc::Func(1))
+            .build();
+        let resolver = HostResolver {
+            func_type: unary_i32,
+            results: double,
+        };
+        let instance = Instance::new(resolve_raw_module(module, &resolver).unwrap());
+
+        assert_eq!(
+            instance.invoke("double", &[Value::I32(21)]).unwrap(),
+            vec![Value::I32(42)]
+        );
+    }
+
+    #[test]
+    fn test_host_function_signature_is_checked() {
+        // The host function doesn't have the type the module imports it with
+        let error = make_host_instance(&HostResolver {
+            func_type: FuncType::new(vec![ValueType::I64], vec![ValueType::I32]),
+            results: double,
+        })
+        .err()
+        .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<WasmError>(),
+            Some(WasmError::LinkError(_))
+        ));
+
+        // The host function returns something other than its declared results
+        let instance = make_host_instance(&HostResolver {
+            func_type: FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            results: |_| Ok(vec![Value::I64(0)]),
+        })
+        .unwrap();
+        assert!(instance
+            .invoke("double_plus_one", &[Value::I32(20)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_host_function_with_too_few_operands() {
+        let unary_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+
+        // (func (result i32) call $double) with nothing on the stack for its argument
+        let module = TestModule::new()
+            .import_func("env", "double", unary_i32.clone())
+            .func(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                &[0x10, 0x00, 0x0B],
+            )
+            .export("empty", ExportDesc::Func(1))
+            .build();
+        let resolver = HostResolver {
+            func_type: unary_i32,
+            results: double,
+        };
+        let instance = Instance::new(resolve_raw_module(module, &resolver).unwrap());
+
+        let error = instance.invoke("empty", &[]).unwrap_err();
+        assert!(format!("{:#}", error).contains("Not enough arguments"));
+    }
+
+    #[test]
+    fn test_host_trap_and_host_error_are_distinct() {
+        let unary_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+
+        let instance = make_host_instance(&HostResolver {
+            func_type: unary_i32.clone(),
+            results: |_| Err(HostFuncError::Trap(TrapKind::Unreachable)),
+        })
+        .unwrap();
+        let error = instance
+            .invoke("double_plus_one", &[Value::I32(20)])
+            .unwrap_err();
+        assert_eq!(WasmError::trap_kind(&error), Some(TrapKind::Unreachable));
+        assert!(!WasmError::is_host_error(&error));
+
+        let instance = make_host_instance(&HostResolver {
+            func_type: unary_i32,
+            results: |_| Err(anyhow!("host is out of widgets").into()),
+        })
+        .unwrap();
+        let error = instance
+            .invoke("double_plus_one", &[Value::I32(20)])
+            .unwrap_err();
+        assert!(WasmError::is_host_error(&error));
+        assert_eq!(WasmError::trap_kind(&error), None);
+        assert!(format!("{:#}", error).contains("host is out of widgets"));
+    }
+
+    #[test]
+    fn test_resume_pending_host_function() {
+        let unary_i32 = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+        let instance = make_host_instance(&HostResolver {
+            func_type: unary_i32.clone(),
+            results: |_| Err(HostFuncError::Pending),
+        })
+        .unwrap();
+
+        // The host has to be able to resume the call
+        assert!(instance
+            .invoke("double_plus_one", &[Value::I32(20)])
+            .is_err());
+
+        let execution = match instance
+            .invoke_resumable("double_plus_one", &[Value::I32(20)])
+            .unwrap()
+        {
+            Invocation::Pending(execution) => execution,
+            other => panic!("Call didn't wait for the host: {:?}", other),
+        };
+        assert_eq!(execution.pending_func_type(), Some(&unary_i32));
+
+        // The instance is still usable while the call is suspended
+        assert!(instance
+            .invoke_resumable("double_plus_one", &[Value::I32(1)])
+            .is_ok());
+
+        match execution.resume_with(vec![Value::I32(40)]).unwrap() {
+            Invocation::Complete(results) => assert_eq!(results, vec![Value::I32(41)]),
+            other => panic!("Call didn't complete: {:?}", other),
+        }
+
+        // The results still have to match the host function's signature
+        let execution = match instance
+            .invoke_resumable("double_plus_one", &[Value::I32(20)])
+            .unwrap()
+        {
+            Invocation::Pending(execution) => execution,
+            other => panic!("Call didn't wait for the host: {:?}", other),
+        };
+        assert!(execution.resume_with(vec![Value::I64(40)]).is_err());
+    }
+
+    // Counts a local up to ten, which takes 72 instructions
+    fn make_counting_instance() -> Instance {
+        let count = vec![
+            0x03, 0x40, 0x20, 0x00, 0x41, 0x01, 0x6A, 0x22, 0x00, 0x41, 0x0A, 0x48, 0x0D, 0x00,
+            0x0B, 0x20, 0x00, 0x0B,
+        ];
+        let module = TestModule::new()
+            .func_with_locals(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                vec![Locals::new(1, ValueType::I32)],
+                &count,
+            )
+            .export("count", ExportDesc::Func(0))
+            .build();
+        Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap())
+    }
+
+    #[test]
+    fn test_suspend_when_out_of_fuel() {
+        let instance = make_counting_instance();
+        let config = ExecutionConfig { fuel: Some(10) };
+        let mut invocation = instance
+            .invoke_resumable_with_config("count", &[], &config)
+            .unwrap();
+
+        let mut suspensions = 0;
+        let results = loop {
+            match invocation {
+                Invocation::OutOfFuel(execution) => {
+                    assert_eq!(execution.pending_func_type(), None);
+                    suspensions += 1;
+                    invocation = execution.resume(Some(10)).unwrap();
+                }
+                Invocation::Complete(results) => break results,
+                Invocation::Pending(_) => panic!("Nothing waits for the host"),
+            }
+        };
+        assert_eq!(results, vec![Value::I32(10)]);
+        assert_eq!(suspensions, 7);
+    }
+
+    #[test]
+    fn test_save_and_restore_execution() {
+        let instance = make_counting_instance();
+        let config = ExecutionConfig { fuel: Some(30) };
+        let execution = match instance
+            .invoke_resumable_with_config("count", &[], &config)
+            .unwrap()
+        {
+            Invocation::OutOfFuel(execution) => execution,
+            other => panic!("Call didn't run out of fuel: {:?}", other),
+        };
+        assert!(execution.resume_with(vec![]).is_err());
+
+        let execution = match instance
+            .invoke_resumable_with_config("count", &[], &config)
+            .unwrap()
+        {
+            Invocation::OutOfFuel(execution) => execution,
+            other => panic!("Call didn't run out of fuel: {:?}", other),
+        };
+        let saved = execution.save().unwrap();
+        drop(execution);
+        #[cfg(feature = "serde")]
+        let saved: SavedExecution =
+            serde_json::from_str(&serde_json::to_string(&saved).unwrap()).unwrap();
+
+        // The call carries on in another instance of the module, with the count it reached
+        let other = make_counting_instance();
+        match Execution::restore(&other, saved.clone())
+            .unwrap()
+            .resume(None)
+            .unwrap()
+        {
+            Invocation::Complete(results) => assert_eq!(results, vec![Value::I32(10)]),
+            other => panic!("Call didn't complete: {:?}", other),
+        }
+
+        // A call waiting for a host function can be saved as well
+        let instance = make_host_instance(&HostResolver {
+            func_type: FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            results: |_| Err(HostFuncError::Pending),
+        })
+        .unwrap();
+        let saved = match instance
+            .invoke_resumable("double_plus_one", &[Value::I32(20)])
+            .unwrap()
+        {
+            Invocation::Pending(execution) => execution.save().unwrap(),
+            other => panic!("Call didn't wait for the host: {:?}", other),
+        };
+        let execution = Execution::restore(&instance, saved.clone()).unwrap();
+        assert!(execution.resume(Some(10)).is_err());
+        let execution = Execution::restore(&instance, saved).unwrap();
+        match execution.resume_with(vec![Value::I32(40)]).unwrap() {
+            Invocation::Complete(results) => assert_eq!(results, vec![Value::I32(41)]),
+            other => panic!("Call didn't complete: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_restore_checks_the_saved_call() {
+        let instance = make_counting_instance();
+        let config = ExecutionConfig { fuel: Some(30) };
+        let saved = match instance
+            .invoke_resumable_with_config("count", &[], &config)
+            .unwrap()
+        {
+            Invocation::OutOfFuel(execution) => execution.save().unwrap(),
+            other => panic!("Call didn't run out of fuel: {:?}", other),
+        };
+
+        let mut wrong_results = saved.clone();
+        wrong_results.result_count = 1000;
+        let error = Execution::restore(&instance, wrong_results).unwrap_err();
+        assert!(error.to_string().contains("expects 1000 results"));
+
+        // Modules whose function 0 is long enough to have got as far, but isn't the one
+        // which was running, so the frame doesn't fit it. The body is some nops and an
+        // i32.const 0, in a block (result i32) if there is one.
+        let restore_into = |local_type, in_block: bool| {
+            let mut body = Vec::new();
+            if in_block {
+                body.extend_from_slice(&[0x02, 0x7F]);
+            }
+            body.extend_from_slice(&[0x01; 40]);
+            body.extend_from_slice(&[0x41, 0x00, 0x0B]);
+            if in_block {
+                body.push(0x0B);
+            }
+            let module = TestModule::new()
+                .func_with_locals(
+                    FuncType::new(vec![], vec![ValueType::I32]),
+                    vec![Locals::new(1, local_type)],
+                    &body,
+                )
+                .export("count", ExportDesc::Func(0))
+                .build();
+            let other =
+                Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+            Execution::restore(&other, saved.clone())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            restore_into(ValueType::I64, false),
+            "Local 0 of saved frame 0 is I32 but should be I64"
+        );
+        // The call was saved inside a loop without parameters, whose label has no arity
+        for in_block in [false, true] {
+            assert_eq!(
+                restore_into(ValueType::I32, in_block),
+                "Saved frame 0 doesn't have the labels of the blocks it is in"
+            );
+        }
+    }
+
+    // Gives every memory import its own view of the same shared memory
+    struct SharedMemoryResolver {
+        shared: SharedMemory,
+    }
+
+    impl Resolver for SharedMemoryResolver {
+        fn resolve_function(
+            &self,
+            mod_name: &str,
+            name: &str,
+            func_type: &FuncType,
+        ) -> Result<Handle<Callable>> {
+            EmptyResolver::instance().resolve_function(mod_name, name, func_type)
+        }
+        fn resolve_table(
+            &self,
+            mod_name: &str,
+            name: &str,
+            table_type: &TableType,
+        ) -> Result<Handle<Table>> {
+            EmptyResolver::instance().resolve_table(mod_name, name, table_type)
+        }
+        fn resolve_memory(
+            &self,
+            _mod_name: &str,
+            _name: &str,
+            _mem_type: &MemType,
+        ) -> Result<Handle<Memory>> {
+            Ok(handle(Memory::from_shared(self.shared.clone())))
+        }
+        fn resolve_global(
+            &self,
+            mod_name: &str,
+            name: &str,
+            global_type: &GlobalType,
+        ) -> Result<Handle<Global>> {
+            EmptyResolver::instance().resolve_global(mod_name, name, global_type)
+        }
+    }
+
+    fn make_shared_memory_instance(shared: SharedMemory) -> Instance {
+        let returns_i32 = FuncType::new(vec![], vec![ValueType::I32]);
+        let module = TestModule::new()
+            .import(
+                "env",
+                "memory",
+                ImportDesc::MemType(MemType::new_shared(Limits::Bounded(1, 1))),
+            )
+            // i32.const 0, i32.const 0, i64.const -1, memory.atomic.wait32
+            .func(
+                returns_i32.clone(),
+                &[
+                    0x41, 0x00, 0x41, 0x00, 0x42, 0x7F, 0xFE, 0x01, 0x02, 0x00, 0x0B,
+                ],
+            )
+            // i32.const 4, i32.const 42, i32.atomic.store,
+            // i32.const 0, i32.const 1, memory.atomic.notify
+            .func(
+                returns_i32.clone(),
+                &[
+                    0x41, 0x04, 0x41, 0x2A, 0xFE, 0x17, 0x02, 0x00, 0x41, 0x00, 0x41, 0x01, 0xFE,
+                    0x00, 0x02, 0x00, 0x0B,
+                ],
+            )
+            // i32.const 4, i32.atomic.load
+            .func(returns_i32, &[0x41, 0x04, 0xFE, 0x10, 0x02, 0x00, 0x0B])
+            .export("wait", ExportDesc::Func(0))
+            .export("notify", ExportDesc::Func(1))
+            .export("load", ExportDesc::Func(2))
+            .build();
+
+        Instance::new(resolve_raw_module(module, &SharedMemoryResolver { shared }).unwrap())
+    }
+
+    #[test]
+    fn test_shared_memory_between_threads() {
+        let shared = SharedMemory::new(1, 1);
+
+        let waiter_memory = shared.clone();
+        let waiter = thread::spawn(move || {
+            let instance = make_shared_memory_instance(waiter_memory);
+            let woken = instance.invoke("wait", &[]).unwrap();
+            (woken, instance.invoke("load", &[]).unwrap())
+        });
+
+        // Keep notifying until the other thread is waiting to be woken
+        let instance = make_shared_memory_instance(shared);
+        while instance.invoke("notify", &[]).unwrap() != vec![Value::I32(1)] {
+            thread::yield_now();
+        }
+
+        assert_eq!(
+            waiter.join().unwrap(),
+            (vec![Value::I32(0)], vec![Value::I32(42)])
+        );
+    }
+
+    #[test]
+    fn test_shared_memory_import_must_be_shared() {
+        let module = |mem_type| {
+            TestModule::new()
+                .import("env", "memory", ImportDesc::MemType(mem_type))
+                .build()
+        };
+        let resolver = SharedMemoryResolver {
+            shared: SharedMemory::new(1, 1),
+        };
+
+        assert!(resolve_raw_module(
+            module(MemType::new_shared(Limits::Bounded(1, 1))),
+            &resolver
+        )
+        .is_ok());
+        assert!(
+            resolve_raw_module(module(MemType::new(Limits::Bounded(1, 1))), &resolver).is_err()
+        );
+    }
+
+    #[test]
+    fn test_lazy_function_bodies() {
+        let returns_i32 = FuncType::new(vec![], vec![ValueType::I32]);
+        let module = TestModule::new()
+            // i32.const 5
+            .func(returns_i32.clone(), &[0x41, 0x05, 0x0B])
+            // i32.const with an over-long immediate
+            .func(
+                returns_i32,
+                &[0x41, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00, 0x0B],
+            )
+            .export("good", ExportDesc::Func(0))
+            .export("bad", ExportDesc::Func(1))
+            .build();
+        let mut bytes = Vec::new();
+        module.write(&mut bytes).unwrap();
+
+        // Read normally, the bad body stops the module from being instantiated at all
+        let read = |lazy_function_bodies| {
+            let config = ParserConfig {
+                lazy_function_bodies,
+                ..ParserConfig::default()
+            };
+            RawModule::read_with_config(&mut bytes.as_slice(), &config).unwrap()
+        };
+        assert!(resolve_raw_module(read(false), EmptyResolver::instance()).is_err());
+
+        // Read lazily, only calling the bad function fails
+        let module = read(true);
+        assert!(module.funcs().iter().all(Func::is_lazy));
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+        assert_eq!(instance.invoke("good", &[]).unwrap(), vec![Value::I32(5)]);
+        for _ in 0..2 {
+            let error = instance.invoke("bad", &[]).unwrap_err();
+            assert!(format!("{:#}", error).contains("In function 1 of"));
+        }
+        assert_eq!(instance.invoke("good", &[]).unwrap(), vec![Value::I32(5)]);
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Lets another thread stop execution in an instance. Interrupting makes the call that is
+/// running trap with `TrapKind::Interrupted` shortly afterwards, or the next call if none is
+/// running. The request is used up by the trap, so the instance can be called again.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    // Clears the request, returning whether there was one
+    pub(crate) fn take_interrupt(&self) -> bool {
+        self.interrupted.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for InterruptHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.interrupted, &other.interrupted)
+    }
+}
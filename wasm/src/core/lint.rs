@@ -0,0 +1,451 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::core::{
+    memory_page::WASM_PAGE_SIZE_IN_BYTES, DataMode, ElementItems, ElementMode, ExportDesc, Expr,
+    FuncType,
+};
+use crate::core::{ImportDesc, Limits, RawModule};
+use crate::parser::{InstructionIterator, InstructionSource, Opcode};
+use crate::reader::{ScanLocation, TypeReader};
+
+// Functions with more locals than this are probably generated code gone wrong, and each
+// call zeroes every one of them
+const LARGE_LOCALS_COUNT: u64 = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LintKind {
+    UnusedImport,
+    UnreferencedFunction,
+    OversizedMemory,
+    MutableExportedGlobal,
+    SuspiciousStartFunction,
+    LargeLocals,
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LintKind::UnusedImport => "unused-import",
+            LintKind::UnreferencedFunction => "unreferenced-function",
+            LintKind::OversizedMemory => "oversized-memory",
+            LintKind::MutableExportedGlobal => "mutable-exported-global",
+            LintKind::SuspiciousStartFunction => "suspicious-start-function",
+            LintKind::LargeLocals => "large-locals",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something in a module which is valid, but probably isn't what the author intended
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LintFinding {
+    pub kind: LintKind,
+    pub location: ScanLocation,
+    pub message: String,
+}
+
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.kind, self.message)
+    }
+}
+
+// Everything that refers to a function or global from somewhere other than its definition
+#[derive(Default)]
+struct References {
+    functions: HashSet<usize>,
+    globals: HashSet<usize>,
+}
+
+impl References {
+    fn collect(module: &RawModule) -> Result<Self> {
+        let mut references = Self::default();
+
+        for func in module.funcs() {
+            references.add_expression(func.expr())?;
+        }
+        for global in module.globals() {
+            references.add_expression(global.init_expr())?;
+        }
+        for elem in module.elem() {
+            if let ElementMode::Active(_, expr) = elem.mode() {
+                references.add_expression(expr)?;
+            }
+            match elem.items() {
+                ElementItems::Functions(indices) => {
+                    references.functions.extend(indices.iter().copied())
+                }
+                ElementItems::Expressions(_, exprs) => {
+                    for expr in exprs {
+                        references.add_expression(expr)?;
+                    }
+                }
+            }
+        }
+        for data in module.data() {
+            if let DataMode::Active(_, expr) = data.mode() {
+                references.add_expression(expr)?;
+            }
+        }
+        for export in module.exports() {
+            match export.d {
+                ExportDesc::Func(idx) => references.functions.insert(idx),
+                ExportDesc::Global(idx) => references.globals.insert(idx),
+                ExportDesc::Table(_) | ExportDesc::Mem(_) => false,
+            };
+        }
+        references.functions.extend(module.start());
+
+        Ok(references)
+    }
+
+    fn add_expression(&mut self, expr: &Expr) -> Result<()> {
+        // Walk nested blocks with a work list, so that deep nesting can't overflow
+        let mut pending = vec![expr.get_instruction_bytes()];
+        while let Some(bytes) = pending.pop() {
+            for instr in InstructionIterator::new(bytes) {
+                let instr = instr?;
+                match instr.opcode() {
+                    Opcode::Call | Opcode::ReturnCall | Opcode::RefFunc => {
+                        self.functions.insert(instr.get_single_u32_as_usize_arg());
+                    }
+                    Opcode::GlobalGet | Opcode::GlobalSet => {
+                        self.globals.insert(instr.get_single_u32_as_usize_arg());
+                    }
+                    _ => {}
+                }
+
+                pending.extend(instr.try_get_block());
+                pending.extend(instr.try_get_else_block());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The offset of an active data segment, if it is simple enough to work out without
+// instantiating the module
+fn constant_offset(expr: &Expr) -> Option<usize> {
+    let mut instrs = expr.iter();
+    let instr = instrs.next()?.ok()?;
+    if instr.opcode() != Opcode::I32Const || instrs.next().is_some() {
+        return None;
+    }
+    Some(instr.get_single_u32_arg() as usize)
+}
+
+struct Linter<'a> {
+    module: &'a RawModule,
+    references: References,
+    findings: Vec<LintFinding>,
+}
+
+impl<'a> Linter<'a> {
+    fn record(&mut self, kind: LintKind, location: ScanLocation, message: String) {
+        self.findings.push(LintFinding {
+            kind,
+            location,
+            message,
+        });
+    }
+
+    fn imported_count(&self, is_kind: fn(&ImportDesc) -> bool) -> usize {
+        self.module
+            .imports()
+            .iter()
+            .filter(|import| is_kind(import.desc()))
+            .count()
+    }
+
+    fn imported_function_count(&self) -> usize {
+        self.imported_count(|desc| matches!(desc, ImportDesc::TypeIdx(_)))
+    }
+
+    fn function_type(&self, idx: usize) -> Option<&'a FuncType> {
+        let module = self.module;
+        let mut imported_functions =
+            module
+                .imports()
+                .iter()
+                .filter_map(|import| match import.desc() {
+                    ImportDesc::TypeIdx(type_idx) => Some(*type_idx),
+                    _ => None,
+                });
+        let import_count = imported_functions.clone().count();
+
+        let type_idx = if idx < import_count {
+            imported_functions.nth(idx)
+        } else {
+            module.typeidx().get(idx - import_count).copied()
+        }?;
+        module.types().get(type_idx)
+    }
+
+    fn check_imports(&mut self) {
+        let (mut function_idx, mut global_idx) = (0, 0);
+        for (idx, import) in self.module.imports().iter().enumerate() {
+            let used = match import.desc() {
+                ImportDesc::TypeIdx(_) => {
+                    let used = self.references.functions.contains(&function_idx);
+                    function_idx += 1;
+                    used
+                }
+                ImportDesc::GlobalType(_) => {
+                    let used = self.references.globals.contains(&global_idx);
+                    global_idx += 1;
+                    used
+                }
+                // Tables and memories are used implicitly by too many instructions to be
+                // worth tracking
+                ImportDesc::TableType(_) | ImportDesc::MemType(_) => true,
+            };
+
+            if !used {
+                let message = format!(
+                    "{}.{} is imported but never used",
+                    import.mod_name(),
+                    import.name()
+                );
+                self.record(LintKind::UnusedImport, ScanLocation::Import(idx), message);
+            }
+        }
+    }
+
+    fn check_functions(&mut self) {
+        let import_count = self.imported_function_count();
+
+        for (idx, func) in self.module.funcs().iter().enumerate() {
+            let location = ScanLocation::Function(import_count + idx);
+            if !self.references.functions.contains(&(import_count + idx)) {
+                let message = "is never called, exported or placed in a table".to_string();
+                self.record(LintKind::UnreferencedFunction, location.clone(), message);
+            }
+
+            let local_count: u64 = func
+                .locals()
+                .iter()
+                .map(|locals| u64::from(locals.count()))
+                .sum();
+            if local_count > LARGE_LOCALS_COUNT {
+                let message = format!(
+                    "declares {} locals, all of which are zeroed on every call",
+                    local_count
+                );
+                self.record(LintKind::LargeLocals, location, message);
+            }
+        }
+    }
+
+    fn check_memories(&mut self) {
+        let import_count = self.imported_count(|desc| matches!(desc, ImportDesc::MemType(_)));
+
+        for (idx, mem) in self.module.mems().iter().enumerate() {
+            let memory_idx = import_count + idx;
+            let minimum_pages = match mem.limits() {
+                Limits::Unbounded(minimum) | Limits::Bounded(minimum, _) => *minimum,
+            };
+
+            // Only segments with a known offset say anything about how much memory is needed
+            let mut highest_end = Some(0);
+            for data in self.module.data() {
+                if let DataMode::Active(data_memory_idx, expr) = data.mode() {
+                    if *data_memory_idx == memory_idx {
+                        highest_end = match (highest_end, constant_offset(expr)) {
+                            (Some(end), Some(offset)) => Some(end.max(offset + data.bytes().len())),
+                            _ => None,
+                        };
+                    }
+                }
+            }
+
+            // A memory nothing is written to up front is presumably used as a heap
+            let needed_pages = match highest_end {
+                Some(0) | None => continue,
+                Some(end) => end.div_ceil(WASM_PAGE_SIZE_IN_BYTES),
+            };
+            if minimum_pages > needed_pages {
+                let message = format!(
+                    "starts with {} pages, but data segments only reach into {}",
+                    minimum_pages, needed_pages
+                );
+                self.record(
+                    LintKind::OversizedMemory,
+                    ScanLocation::Memory(memory_idx),
+                    message,
+                );
+            }
+        }
+    }
+
+    fn check_exported_globals(&mut self) {
+        let mut global_types: Vec<_> = self
+            .module
+            .imports()
+            .iter()
+            .filter_map(|import| match import.desc() {
+                ImportDesc::GlobalType(global_type) => Some(global_type),
+                _ => None,
+            })
+            .collect();
+        global_types.extend(self.module.globals().iter().map(|g| g.global_type()));
+
+        for export in self.module.exports() {
+            if let ExportDesc::Global(idx) = export.d {
+                if matches!(global_types.get(idx), Some(g) if g.is_mutable()) {
+                    let message = format!(
+                        "is exported as \"{}\" while mutable, so the host can change it",
+                        export.nm
+                    );
+                    self.record(
+                        LintKind::MutableExportedGlobal,
+                        ScanLocation::Global(idx),
+                        message,
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_start_function(&mut self) {
+        let start = match self.module.start() {
+            Some(start) => start,
+            None => return,
+        };
+        let location = ScanLocation::Function(start);
+
+        let import_count = self.imported_function_count();
+        if start < import_count {
+            let message =
+                "the start function is imported, so instantiating calls into the host".to_string();
+            self.record(LintKind::SuspiciousStartFunction, location.clone(), message);
+        }
+
+        let exported = self
+            .module
+            .exports()
+            .iter()
+            .any(|export| matches!(export.d, ExportDesc::Func(idx) if idx == start));
+        if exported {
+            let message =
+                "the start function is also exported, so it may run more than once".to_string();
+            self.record(LintKind::SuspiciousStartFunction, location.clone(), message);
+        }
+
+        if let Some(func_type) = self.function_type(start) {
+            if !func_type.arg_types().is_empty() || !func_type.return_types().is_empty() {
+                let message =
+                    "the start function should take no arguments and return nothing".to_string();
+                self.record(LintKind::SuspiciousStartFunction, location, message);
+            }
+        }
+    }
+}
+
+/// Look for things in a module which are allowed, but which are probably mistakes or waste.
+/// None of these stop the module from loading. Findings are grouped by kind.
+pub fn lint_module(module: &RawModule) -> Result<Vec<LintFinding>> {
+    let mut linter = Linter {
+        module,
+        references: References::collect(module)?,
+        findings: Vec::new(),
+    };
+
+    linter.check_imports();
+    linter.check_functions();
+    linter.check_memories();
+    linter.check_exported_globals();
+    linter.check_start_function();
+    linter.findings.sort_by_key(|finding| finding.kind);
+    Ok(linter.findings)
+}
+
+pub fn lint_module_from_path(file: &str) -> Result<Vec<LintFinding>> {
+    let mut buf = BufReader::new(File::open(file)?);
+    lint_module(&RawModule::read(&mut buf)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_module::TestModule;
+    use crate::core::{Data, GlobalType, Locals, MemType, MutableType, ValueType};
+
+    fn kinds_and_locations(module: &RawModule) -> Vec<(LintKind, ScanLocation)> {
+        lint_module(module)
+            .unwrap()
+            .into_iter()
+            .map(|finding| (finding.kind, finding.location))
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_clean_module() {
+        let module = TestModule::new()
+            .import_func("env", "log", FuncType::new(vec![], vec![]))
+            .func(FuncType::new(vec![], vec![]), &[0x10, 0x00, 0x0B])
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .data(Data::new(0, Expr::new(vec![0x41, 0x10, 0x0B]), vec![1, 2]))
+            .export("main", ExportDesc::Func(1))
+            .build();
+
+        assert_eq!(kinds_and_locations(&module), vec![]);
+    }
+
+    #[test]
+    fn test_lint_findings() {
+        let module = TestModule::new()
+            .import_func("env", "used", FuncType::new(vec![], vec![]))
+            .import_func("env", "unused", FuncType::new(vec![], vec![]))
+            .import(
+                "env",
+                "flag",
+                ImportDesc::GlobalType(GlobalType::new(ValueType::I32, MutableType::Const)),
+            )
+            // A call to the first import, nested inside a block
+            .func(
+                FuncType::new(vec![], vec![]),
+                &[0x02, 0x00, 0x10, 0x00, 0x0B, 0x0B],
+            )
+            .func_with_locals(
+                FuncType::new(vec![], vec![]),
+                vec![
+                    Locals::new(1000, ValueType::I32),
+                    Locals::new(25, ValueType::F64),
+                ],
+                &[0x0B],
+            )
+            .func(FuncType::new(vec![ValueType::I32], vec![]), &[0x0B])
+            .memory(MemType::new(Limits::Unbounded(4)))
+            .global(
+                GlobalType::new(ValueType::I32, MutableType::Var),
+                &[0x41, 0x00, 0x0B],
+            )
+            .data(Data::new(0, Expr::new(vec![0x41, 0x10, 0x0B]), vec![1, 2]))
+            .start(4)
+            .export("main", ExportDesc::Func(2))
+            .export("init", ExportDesc::Func(4))
+            .export("counter", ExportDesc::Global(1))
+            .build();
+
+        assert_eq!(
+            kinds_and_locations(&module),
+            vec![
+                (LintKind::UnusedImport, ScanLocation::Import(1)),
+                (LintKind::UnusedImport, ScanLocation::Import(2)),
+                (LintKind::UnreferencedFunction, ScanLocation::Function(3)),
+                (LintKind::OversizedMemory, ScanLocation::Memory(0)),
+                (LintKind::MutableExportedGlobal, ScanLocation::Global(1)),
+                (LintKind::SuspiciousStartFunction, ScanLocation::Function(4)),
+                (LintKind::SuspiciousStartFunction, ScanLocation::Function(4)),
+                (LintKind::LargeLocals, ScanLocation::Function(3)),
+            ]
+        );
+    }
+}
@@ -1,42 +1,172 @@
 use std::{
     cmp::min,
-    ops::{Index, IndexMut},
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
 
-use crate::core::{memory_page::*, Limits, MemType};
+use crate::core::{
+    executor::memory_access::LEByteConvert, memory_page::WASM_PAGE_SIZE_IN_BYTES, trap,
+    BoundsCheck, CachedLengthBoundsCheck, Limits, MemType, PageLookupBoundsCheck, TrapKind,
+};
 use anyhow::{anyhow, Result};
+use generic_array::{typenum::Unsigned, GenericArray};
+
+// The results of memory.atomic.wait
+pub const WAIT_OK: u32 = 0;
+pub const WAIT_NOT_EQUAL: u32 = 1;
+pub const WAIT_TIMED_OUT: u32 = 2;
+
+#[derive(Debug, Default)]
+struct SharedState {
+    bytes: Vec<u8>,
+    // For each address with threads waiting on it, how many are waiting and how many of
+    // those have been woken but haven't noticed yet
+    waiters: HashMap<usize, (usize, usize)>,
+}
+
+#[derive(Debug, Default)]
+struct SharedContents {
+    state: Mutex<SharedState>,
+    woken: Condvar,
+}
+
+/// The contents of a shared memory, which can be sent to other threads and wrapped in a
+/// `Memory` of their own with `Memory::from_shared`, so that instances on different
+/// threads can import the same memory. Every access locks the contents for its duration,
+/// which makes each access atomic as far as the other threads are concerned.
+#[derive(Debug, Clone)]
+pub struct SharedMemory {
+    contents: Arc<SharedContents>,
+    minimum_pages: usize,
+    maximum_pages: usize,
+}
+
+impl SharedMemory {
+    /// Shared memories have to have a maximum size, because another thread could be using
+    /// the memory when it grows
+    pub fn new(minimum_pages: usize, maximum_pages: usize) -> Self {
+        let state = SharedState {
+            bytes: allocate_pages(minimum_pages),
+            waiters: HashMap::new(),
+        };
+        Self {
+            contents: Arc::new(SharedContents {
+                state: Mutex::new(state),
+                woken: Condvar::new(),
+            }),
+            minimum_pages,
+            maximum_pages,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SharedState> {
+        // A thread which panicked can't have left the bytes half updated, because every
+        // update is a plain copy of bytes
+        self.contents
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn ptr_eq(&self, other: &SharedMemory) -> bool {
+        Arc::ptr_eq(&self.contents, &other.contents)
+    }
+}
+
+// The bytes of a memory are kept in one block, which is always a whole number of pages
+// long, so that an access is a bounds check and a single copy however it lines up with
+// the pages
+fn allocate_pages(page_count: usize) -> Vec<u8> {
+    accounted!(Memory, vec![0; page_count * WASM_PAGE_SIZE_IN_BYTES])
+}
+
+fn resize_pages(bytes: &mut Vec<u8>, page_count: usize) {
+    accounted!(
+        Memory,
+        bytes.resize(page_count * WASM_PAGE_SIZE_IN_BYTES, 0)
+    )
+}
+
+fn page_count(bytes: &[u8]) -> usize {
+    bytes.len() / WASM_PAGE_SIZE_IN_BYTES
+}
+
+enum Contents {
+    Local(Vec<u8>),
+    Shared(SharedMemory),
+}
+
+impl fmt::Debug for Contents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Contents::Local(bytes) => write!(f, "Local({} pages)", page_count(bytes)),
+            Contents::Shared(_) => write!(f, "Shared"),
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct Memory {
+pub struct Memory<Check: BoundsCheck = CachedLengthBoundsCheck> {
     minimum_pages: usize,
     maximum_pages: Option<usize>,
-    pages: Vec<MemoryPage>,
+    contents: Contents,
+    bounds_check: Check,
 }
 
-impl Memory {
+impl<Check: BoundsCheck> Memory<Check> {
     pub fn new(mem_type: MemType) -> Self {
         let (minimum_pages, maximum_pages): (usize, Option<usize>) = match mem_type.limits() {
             Limits::Bounded(minimum_pages, maximum_pages) => (*minimum_pages, Some(*maximum_pages)),
             Limits::Unbounded(minimum_pages) => (*minimum_pages, None),
         };
 
-        Self::new_from_bounds(minimum_pages, maximum_pages)
+        match maximum_pages {
+            Some(maximum_pages) if mem_type.is_shared() => {
+                Self::from_shared(SharedMemory::new(minimum_pages, maximum_pages))
+            }
+            _ => Self::new_from_bounds(minimum_pages, maximum_pages),
+        }
     }
 
     pub fn new_from_bounds(minimum_pages: usize, maximum_pages: Option<usize>) -> Self {
-        let mut pages = Vec::with_capacity(minimum_pages);
-        for _ in 0..minimum_pages {
-            pages.push(MemoryPage::new())
-        }
+        let mut bounds_check = Check::default();
+        bounds_check.set_page_count(minimum_pages);
 
         // Make the memory object
         Memory {
             minimum_pages,
             maximum_pages,
-            pages,
+            contents: Contents::Local(allocate_pages(minimum_pages)),
+            bounds_check,
         }
     }
 
+    /// Make a memory which accesses the same contents as every other memory made from
+    /// `shared`
+    pub fn from_shared(shared: SharedMemory) -> Self {
+        Memory {
+            minimum_pages: shared.minimum_pages,
+            maximum_pages: Some(shared.maximum_pages),
+            contents: Contents::Shared(shared),
+            bounds_check: Check::default(),
+        }
+    }
+
+    /// The shared contents of the memory, for use on another thread, or `None` if the
+    /// memory isn't shared
+    pub fn shared(&self) -> Option<SharedMemory> {
+        match &self.contents {
+            Contents::Local(_) => None,
+            Contents::Shared(shared) => Some(shared.clone()),
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        matches!(self.contents, Contents::Shared(_))
+    }
+
     #[allow(dead_code)]
     pub fn min_size(&self) -> usize {
         self.minimum_pages
@@ -49,102 +179,353 @@ impl Memory {
 
     #[allow(dead_code)]
     pub fn current_size(&self) -> usize {
-        self.pages.len()
+        match &self.contents {
+            Contents::Local(bytes) => page_count(bytes),
+            Contents::Shared(shared) => page_count(&shared.lock().bytes),
+        }
+    }
+
+    /// Whether the `length` bytes from `offset` are all in the memory. Memories never
+    /// shrink, so this stays true even if another thread grows a shared memory. Host
+    /// functions use this to check a buffer the guest gave them before allocating anything
+    /// the size of it.
+    pub fn contains(&self, offset: usize, length: usize) -> bool {
+        let size = self.current_size() * WASM_PAGE_SIZE_IN_BYTES;
+        matches!(offset.checked_add(length), Some(end) if end <= size)
+    }
+
+    /// Every byte of a memory which isn't shared, for code which wants to do its own
+    /// accesses after checking the bounds itself. The slice is only valid until the
+    /// memory grows. Shared memories are behind a lock, so they can't be borrowed like
+    /// this and give `None`.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match &self.contents {
+            Contents::Local(bytes) => Some(bytes),
+            Contents::Shared(_) => None,
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        match &mut self.contents {
+            Contents::Local(bytes) => Some(bytes),
+            Contents::Shared(_) => None,
+        }
     }
 
     pub fn grow_by(&mut self, grow_by: usize) -> Result<()> {
-        match self.current_size().checked_add(grow_by) {
-            Some(new_size) if new_size <= self.max_size().unwrap_or(new_size) => {
-                for _ in 0..grow_by {
-                    self.pages.push(MemoryPage::new())
+        let max_size = self.max_size();
+        let grow = |bytes: &mut Vec<u8>| match page_count(bytes).checked_add(grow_by) {
+            Some(new_size) if new_size <= max_size.unwrap_or(new_size) => {
+                // Check the byte length fits before asking for it
+                if new_size.checked_mul(WASM_PAGE_SIZE_IN_BYTES).is_none() {
+                    return Err(anyhow!("New memory is too big"));
                 }
-
-                Ok(())
+                resize_pages(bytes, new_size);
+                Ok(new_size)
             }
 
             _ => Err(anyhow!("New memory is too big")),
+        };
+
+        let new_size = match &mut self.contents {
+            Contents::Local(bytes) => grow(bytes)?,
+            Contents::Shared(shared) => grow(&mut shared.lock().bytes)?,
+        };
+        self.bounds_check.set_page_count(new_size);
+        Ok(())
+    }
+
+    // Run an operation on the bytes of the access once it has been checked. Another thread
+    // can grow a shared memory at any time, which the cached bounds wouldn't know about, so
+    // shared memories are always checked against the size they have now.
+    fn with_bytes<R>(
+        &self,
+        offset: usize,
+        length: usize,
+        operation: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R> {
+        match &self.contents {
+            Contents::Local(bytes) => {
+                self.bounds_check.check(page_count(bytes), offset, length)?;
+                Ok(operation(&bytes[offset..offset + length]))
+            }
+            Contents::Shared(shared) => {
+                let state = shared.lock();
+                let bytes = &state.bytes;
+                PageLookupBoundsCheck::default().check(page_count(bytes), offset, length)?;
+                Ok(operation(&bytes[offset..offset + length]))
+            }
         }
     }
 
-    pub fn set_data(&mut self, offset: usize, data: &[u8]) -> Result<()> {
-        self.check_bounds(offset, data.len())?;
+    fn with_bytes_mut<R>(
+        &mut self,
+        offset: usize,
+        length: usize,
+        operation: impl FnOnce(&mut [u8]) -> R,
+    ) -> Result<R> {
+        match &mut self.contents {
+            Contents::Local(bytes) => {
+                self.bounds_check.check(page_count(bytes), offset, length)?;
+                Ok(operation(&mut bytes[offset..offset + length]))
+            }
+            Contents::Shared(shared) => {
+                let mut state = shared.lock();
+                let bytes = &mut state.bytes;
+                PageLookupBoundsCheck::default().check(page_count(bytes), offset, length)?;
+                Ok(operation(&mut bytes[offset..offset + length]))
+            }
+        }
+    }
 
-        let (mut current_page, mut current_page_offset) = split_page_from_address(offset);
-        let mut data_start = 0;
-        let mut data_remaining = data.len();
+    pub fn set_data(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.with_bytes_mut(offset, data.len(), |bytes| bytes.copy_from_slice(data))
+    }
 
-        while data_remaining > 0 {
-            let bytes_to_copy = min(
-                data_remaining,
-                WASM_PAGE_SIZE_IN_BYTES - current_page_offset,
-            );
-            let page = &mut self.pages[current_page];
+    pub fn get_data(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        self.with_bytes(offset, data.len(), |bytes| data.copy_from_slice(bytes))
+    }
 
-            page[current_page_offset..current_page_offset + bytes_to_copy]
-                .copy_from_slice(&data[data_start..data_start + bytes_to_copy]);
+    /// Read a little endian value straight out of the memory, as a load instruction would
+    pub fn load<T: LEByteConvert>(&self, offset: usize) -> Result<T> {
+        self.with_bytes(offset, T::ArrayLength::USIZE, |bytes| {
+            T::from_bytes(GenericArray::clone_from_slice(bytes))
+        })
+    }
 
-            data_start += bytes_to_copy;
-            data_remaining -= bytes_to_copy;
-            current_page += 1;
-            current_page_offset = 0;
-        }
+    /// Write a little endian value straight into the memory, as a store instruction would
+    pub fn store<T: LEByteConvert>(&mut self, offset: usize, value: T) -> Result<()> {
+        self.with_bytes_mut(offset, T::ArrayLength::USIZE, |bytes| {
+            bytes.copy_from_slice(&value.to_bytes())
+        })
+    }
 
-        Ok(())
+    /// Let `update` change `length` bytes in place. Nothing else can touch a shared
+    /// memory in between, which is what makes the atomic read-modify-write instructions
+    /// atomic.
+    pub fn update_data(
+        &mut self,
+        offset: usize,
+        length: usize,
+        update: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<()> {
+        self.with_bytes_mut(offset, length, |bytes| update(bytes))
     }
 
-    pub fn get_data(&self, offset: usize, data: &mut [u8]) -> Result<()> {
-        self.check_bounds(offset, data.len())?;
+    /// Wait until another thread calls `notify` for the same address, as long as the
+    /// memory there holds `expected` to start with. Waits forever if there is no timeout.
+    pub fn wait(&self, offset: usize, expected: &[u8], timeout: Option<Duration>) -> Result<u32> {
+        let shared = match &self.contents {
+            Contents::Shared(shared) => shared,
+            Contents::Local(_) => {
+                return Err(trap(TrapKind::ExpectedSharedMemory)
+                    .context("Only shared memories can be waited on"))
+            }
+        };
 
-        let (mut current_page, mut current_page_offset) = split_page_from_address(offset);
-        let mut data_start = 0;
-        let mut data_remaining = data.len();
+        let mut state = shared.lock();
+        PageLookupBoundsCheck::default().check(page_count(&state.bytes), offset, expected.len())?;
+        if state.bytes[offset..offset + expected.len()] != *expected {
+            return Ok(WAIT_NOT_EQUAL);
+        }
 
-        while data_remaining > 0 {
-            let bytes_to_copy = min(
-                data_remaining,
-                WASM_PAGE_SIZE_IN_BYTES - current_page_offset,
-            );
-            let page = &self.pages[current_page];
+        state.waiters.entry(offset).or_insert((0, 0)).0 += 1;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            state = match deadline {
+                None => shared
+                    .contents
+                    .woken
+                    .wait(state)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    shared
+                        .contents
+                        .woken
+                        .wait_timeout(state, remaining)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .0
+                }
+            };
 
-            data[data_start..data_start + bytes_to_copy]
-                .copy_from_slice(&page[current_page_offset..current_page_offset + bytes_to_copy]);
+            let waiters = state.waiters.get_mut(&offset).unwrap();
+            let result = if waiters.1 > 0 {
+                waiters.1 -= 1;
+                WAIT_OK
+            } else if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                WAIT_TIMED_OUT
+            } else {
+                continue;
+            };
 
-            data_start += bytes_to_copy;
-            data_remaining -= bytes_to_copy;
-            current_page += 1;
-            current_page_offset = 0;
+            waiters.0 -= 1;
+            if waiters.0 == 0 {
+                state.waiters.remove(&offset);
+            }
+            return Ok(result);
         }
+    }
+
+    /// Wake up to `count` of the threads waiting on the address, and return how many
+    /// were woken. Nothing can wait on a memory which isn't shared, so that always
+    /// returns zero.
+    pub fn notify(&self, offset: usize, count: u32) -> Result<u32> {
+        match &self.contents {
+            Contents::Local(bytes) => {
+                self.bounds_check.check(page_count(bytes), offset, 4)?;
+                Ok(0)
+            }
+            Contents::Shared(shared) => {
+                let mut state = shared.lock();
+                PageLookupBoundsCheck::default().check(page_count(&state.bytes), offset, 4)?;
+                let woken = match state.waiters.get_mut(&offset) {
+                    Some((waiting, woken)) => {
+                        let newly_woken = min(count as usize, *waiting - *woken);
+                        *woken += newly_woken;
+                        newly_woken
+                    }
+                    None => 0,
+                };
+                if woken > 0 {
+                    shared.contents.woken.notify_all();
+                }
+                Ok(woken as u32)
+            }
+        }
+    }
 
+    pub fn copy_within(
+        &mut self,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        // Both ranges are checked before anything is copied, and copy_within copes with
+        // them overlapping
+        self.with_bytes(dst_offset, length, |_| ())?;
+        self.with_bytes(src_offset, length, |_| ())?;
+        let copy =
+            |bytes: &mut Vec<u8>| bytes.copy_within(src_offset..src_offset + length, dst_offset);
+        match &mut self.contents {
+            Contents::Local(bytes) => copy(bytes),
+            Contents::Shared(shared) => copy(&mut shared.lock().bytes),
+        }
         Ok(())
     }
 
-    fn check_bounds(&self, offset: usize, length: usize) -> Result<()> {
-        match offset.checked_add(length) {
-            None => Err(anyhow!("Length overflow when accessing memory")),
-            Some(end) if end > self.current_size() * WASM_PAGE_SIZE_IN_BYTES => {
-                Err(anyhow!("Attempting to access outside allocated memory"))
+    pub fn fill(&mut self, offset: usize, value: u8, length: usize) -> Result<()> {
+        self.with_bytes_mut(offset, length, |bytes| bytes.fill(value))
+    }
+
+    /// Every byte of the memory
+    pub(crate) fn contents(&self) -> Result<Vec<u8>> {
+        Ok(match &self.contents {
+            Contents::Local(bytes) => bytes.clone(),
+            Contents::Shared(shared) => shared.lock().bytes.clone(),
+        })
+    }
+
+    /// Put back contents taken with `contents`. The memory shrinks again if it has grown
+    /// since then.
+    pub(crate) fn restore_contents(&mut self, contents: &[u8]) -> Result<()> {
+        let page_count = contents.len() / WASM_PAGE_SIZE_IN_BYTES;
+        let resize = |bytes: &mut Vec<u8>| resize_pages(bytes, page_count);
+        match &mut self.contents {
+            Contents::Local(bytes) => resize(bytes),
+            Contents::Shared(shared) => resize(&mut shared.lock().bytes),
+        }
+        self.bounds_check.set_page_count(page_count);
+        self.set_data(0, contents)
+    }
+
+    pub fn copy_from(&mut self, source: &Memory<Check>) -> Result<()> {
+        // Memories made from the same shared memory already have the same contents
+        if let (Contents::Shared(target), Contents::Shared(source)) =
+            (&self.contents, &source.contents)
+        {
+            if target.ptr_eq(source) {
+                return Ok(());
             }
-            _ => Ok(()),
         }
+
+        // Make sure we have at least as many pages as the source, then copy them over.
+        // Any pages beyond the size of the source are left alone.
+        let source_size = source.current_size();
+        if source_size > self.current_size() {
+            self.grow_by(source_size - self.current_size())?;
+        }
+
+        self.set_data(0, &source.contents()?)
     }
 }
 
-impl Index<usize> for Memory {
-    type Output = u8;
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_contiguous_access() {
+        let mut memory: Memory = Memory::new_from_bounds(1, Some(2));
+        memory.grow_by(1).unwrap();
+
+        // Accesses which cross into the second page are a single copy
+        let boundary = WASM_PAGE_SIZE_IN_BYTES;
+        memory.set_data(boundary - 2, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(
+            &memory.as_slice().unwrap()[boundary - 2..boundary + 2],
+            &[1, 2, 3, 4]
+        );
 
-    fn index(&self, address: usize) -> &Self::Output {
-        let (page, offset) = split_page_from_address(address);
+        memory.copy_within(boundary - 1, boundary - 2, 4).unwrap();
+        memory.fill(boundary + 3, 9, 2).unwrap();
+        let mut buf = [0; 7];
+        memory.get_data(boundary - 2, &mut buf).unwrap();
+        assert_eq!(buf, [1, 1, 2, 3, 4, 9, 9]);
 
-        let page = &self.pages[page];
-        &page[offset]
+        memory.as_mut_slice().unwrap()[2 * boundary - 1] = 5;
+        assert_eq!(memory.load::<u8>(2 * boundary - 1).unwrap(), 5);
+        assert!(memory.get_data(2 * boundary - 1, &mut buf).is_err());
+        assert!(memory.grow_by(1).is_err());
+        assert_eq!(memory.as_slice().unwrap().len(), 2 * boundary);
+        assert!(memory.contains(0, 2 * boundary));
+        assert!(!memory.contains(1, 2 * boundary));
+        assert!(!memory.contains(usize::MAX, 2));
     }
-}
 
-impl IndexMut<usize> for Memory {
-    fn index_mut(&mut self, address: usize) -> &mut Self::Output {
-        let (page, offset) = split_page_from_address(address);
+    #[test]
+    fn test_typed_access() {
+        let mut memory: Memory = Memory::new_from_bounds(1, None);
+        let last_word = WASM_PAGE_SIZE_IN_BYTES - 4;
+
+        memory.store(last_word, 0x0403_0201_u32).unwrap();
+        assert_eq!(memory.load::<u32>(last_word).unwrap(), 0x0403_0201);
+        assert_eq!(memory.load::<u16>(last_word + 1).unwrap(), 0x0302);
+        assert_eq!(memory.load::<i8>(last_word + 3).unwrap(), 4);
+        assert_eq!(
+            &memory.as_slice().unwrap()[last_word..],
+            &[0x01, 0x02, 0x03, 0x04]
+        );
+
+        // An access which runs off the end fails without writing anything
+        assert!(memory.load::<u64>(last_word).is_err());
+        assert!(memory.store(last_word, 1.5_f64).is_err());
+        assert_eq!(memory.load::<u32>(last_word).unwrap(), 0x0403_0201);
+    }
+
+    #[test]
+    fn test_shared_access() {
+        let shared = SharedMemory::new(1, 1);
+        let mut first: Memory = Memory::from_shared(shared.clone());
+        let second: Memory = Memory::from_shared(shared);
+
+        first.store(8, 0x0201_u16).unwrap();
+        assert_eq!(second.load::<u8>(8).unwrap(), 1);
+        assert_eq!(second.load::<u8>(9).unwrap(), 2);
+        assert!(second.load::<u8>(WASM_PAGE_SIZE_IN_BYTES).is_err());
 
-        let page = &mut self.pages[page];
-        &mut page[offset]
+        // The contents are behind a lock, so they can't be borrowed
+        assert!(first.as_mut_slice().is_none());
+        assert!(second.as_slice().is_none());
     }
 }
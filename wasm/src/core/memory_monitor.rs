@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Result};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::core::{memory_page::WASM_PAGE_SIZE_IN_BYTES, DataStore};
+
+/// A copy of some of the pages of a memory, all taken at the same point in execution
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemorySnapshot {
+    pub memory: usize,
+    /// The size of the whole memory in pages when the copy was taken
+    pub page_count: usize,
+    pub first_page: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl MemorySnapshot {
+    /// The pages which were copied. Any that were asked for beyond the end of the memory
+    /// are left out.
+    pub fn pages(&self) -> Range<usize> {
+        self.first_page..self.first_page + self.bytes.len() / WASM_PAGE_SIZE_IN_BYTES
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    id: u64,
+    memory: usize,
+    pages: Range<usize>,
+}
+
+#[derive(Debug, Default)]
+struct MonitorState {
+    next_id: u64,
+    pending: Vec<Request>,
+    completed: Vec<(u64, Result<MemorySnapshot>)>,
+}
+
+#[derive(Debug, Default)]
+struct MonitorShared {
+    requested: AtomicBool,
+    state: Mutex<MonitorState>,
+    served: Condvar,
+}
+
+/// Lets another thread copy the memory of an instance while it runs, such as to show a
+/// live view of a long running guest. The interpreter makes the copy itself between
+/// instructions, so nothing the guest does can tear it. While no call is running the
+/// copy waits for the next call, or for `Instance::serve_memory_requests`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMonitor {
+    shared: Arc<MonitorShared>,
+}
+
+impl MemoryMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy the whole of a memory, waiting up to `timeout` for the instance to do it
+    pub fn snapshot(&self, memory: usize, timeout: Duration) -> Result<MemorySnapshot> {
+        self.snapshot_pages(memory, 0..usize::MAX, timeout)
+    }
+
+    /// Copy some of the pages of a memory, which keeps the pause in the guest short when
+    /// only part of a large memory is of interest
+    pub fn snapshot_pages(
+        &self,
+        memory: usize,
+        pages: Range<usize>,
+        timeout: Duration,
+    ) -> Result<MemorySnapshot> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.push(Request { id, memory, pages });
+        self.shared.requested.store(true, Ordering::Relaxed);
+
+        loop {
+            if let Some(position) = state.completed.iter().position(|(done, _)| *done == id) {
+                return state.completed.swap_remove(position).1;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::default() {
+                state.pending.retain(|request| request.id != id);
+                return Err(anyhow!(
+                    "The instance did not copy memory {} within {:?}",
+                    memory,
+                    timeout
+                ));
+            }
+            state = self.shared.served.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+
+    // Cheap enough for the interpreter to call between instructions
+    pub(crate) fn is_requested(&self) -> bool {
+        self.shared.requested.load(Ordering::Relaxed)
+    }
+
+    /// Copy the memory for every request that is waiting
+    pub(crate) fn serve(&self, data_store: &impl DataStore) {
+        if !self.shared.requested.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        let requests = std::mem::take(&mut state.pending);
+        for request in requests {
+            let snapshot = copy_pages(data_store, request.memory, request.pages);
+            state.completed.push((request.id, snapshot));
+        }
+        self.shared.served.notify_all();
+    }
+}
+
+fn copy_pages(
+    data_store: &impl DataStore,
+    memory: usize,
+    pages: Range<usize>,
+) -> Result<MemorySnapshot> {
+    let page_count = data_store.get_memory_size(memory)?;
+    let first_page = pages.start.min(page_count);
+    let end_page = pages.end.min(page_count).max(first_page);
+
+    let mut bytes = vec![0; (end_page - first_page) * WASM_PAGE_SIZE_IN_BYTES];
+    data_store.read_data(memory, first_page * WASM_PAGE_SIZE_IN_BYTES, &mut bytes)?;
+    Ok(MemorySnapshot {
+        memory,
+        page_count,
+        first_page,
+        bytes,
+    })
+}
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+
+use crate::core::{executor::memory_access::LEByteConvert, memory_page, Handle, Memory};
+
+// How much of a string read_cstr looks at in one go while searching for its end
+const CSTR_CHUNK_SIZE: usize = 64;
+
+/// A handle on one of the linear memories of an instance, for host code which has to read
+/// and write what the module keeps there. Every access is bounds checked, and one which
+/// is out of bounds fails instead of panicking. The view shares the memory with the
+/// instance, so it sees any changes made by later calls, and can be kept between them.
+#[derive(Debug, Clone)]
+pub struct MemoryView {
+    memory: Handle<Memory>,
+}
+
+impl MemoryView {
+    pub fn new(memory: Handle<Memory>) -> Self {
+        Self { memory }
+    }
+
+    /// The current size of the memory in bytes
+    pub fn len(&self) -> usize {
+        self.memory.borrow().current_size() * memory_page::WASM_PAGE_SIZE_IN_BYTES
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn read(&self, offset: usize, length: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; length];
+        self.read_into(offset, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_into(&self, offset: usize, bytes: &mut [u8]) -> Result<()> {
+        self.memory.borrow().get_data(offset, bytes)
+    }
+
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Result<()> {
+        self.memory.borrow_mut().set_data(offset, bytes)
+    }
+
+    /// The string which starts at `offset` and ends at the next zero byte. Fails if there
+    /// is no zero byte before the end of the memory, or the string isn't valid UTF-8.
+    pub fn read_cstr(&self, offset: usize) -> Result<String> {
+        let memory_len = self.len();
+        let mut bytes = Vec::new();
+        let mut position = offset;
+        while position < memory_len {
+            let mut chunk = [0; CSTR_CHUNK_SIZE];
+            let chunk = &mut chunk[..CSTR_CHUNK_SIZE.min(memory_len - position)];
+            self.read_into(position, chunk)?;
+
+            if let Some(end) = chunk.iter().position(|byte| *byte == 0) {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Ok(String::from_utf8(bytes)?);
+            }
+            bytes.extend_from_slice(chunk);
+            position += chunk.len();
+        }
+
+        Err(anyhow!(
+            "String at offset {} is not terminated before the end of memory",
+            offset
+        ))
+    }
+
+    /// Read a little endian value, as a load instruction would
+    pub fn get<T: LEByteConvert>(&self, offset: usize) -> Result<T> {
+        self.memory.borrow().load(offset)
+    }
+
+    /// Write a little endian value, as a store instruction would
+    pub fn set<T: LEByteConvert>(&self, offset: usize, value: T) -> Result<()> {
+        self.memory.borrow_mut().store(offset, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::handle;
+
+    fn make_view(pages: usize) -> MemoryView {
+        MemoryView::new(handle(Memory::new_from_bounds(pages, Some(pages))))
+    }
+
+    #[test]
+    fn test_memory_view() {
+        let view = make_view(1);
+        assert_eq!(view.len(), memory_page::WASM_PAGE_SIZE_IN_BYTES);
+
+        view.write(100, b"hello\0world").unwrap();
+        assert_eq!(view.read(100, 5).unwrap(), b"hello");
+        assert_eq!(view.read_cstr(100).unwrap(), "hello");
+        assert_eq!(view.read_cstr(105).unwrap(), "");
+
+        view.set(200, 0x1234_5678u32).unwrap();
+        assert_eq!(view.read(200, 4).unwrap(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(view.get::<u16>(202).unwrap(), 0x1234);
+        view.set(208, -1.5f64).unwrap();
+        assert_eq!(view.get::<f64>(208).unwrap(), -1.5);
+
+        // A clone of the view shares the memory
+        view.clone().write(300, &[1, 2, 3]).unwrap();
+        assert_eq!(view.read(300, 3).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_memory_view_bounds() {
+        let view = make_view(1);
+        let end = view.len();
+
+        assert!(view.read(end - 4, 4).is_ok());
+        assert!(view.read(end - 3, 4).is_err());
+        assert!(view.write(end, &[0]).is_err());
+        assert!(view.get::<u64>(end - 4).is_err());
+        assert!(view.set(usize::MAX, 0u8).is_err());
+
+        // A string running off the end of memory, spanning more than one chunk
+        view.write(end - 100, &[b'a'; 100]).unwrap();
+        assert!(view.read_cstr(end - 100).is_err());
+        assert!(view.read_cstr(end).is_err());
+        view.set(end - 1, 0u8).unwrap();
+        assert_eq!(view.read_cstr(end - 100).unwrap(), "a".repeat(99));
+
+        assert!(make_view(0).is_empty());
+    }
+}
@@ -1,18 +1,87 @@
-use anyhow::{anyhow, Result};
-use std::cell::RefCell;
-use std::collections::HashMap;
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
-use std::rc::Rc;
+use std::io::Write;
+use std::ops::Range;
+use std::time::Duration;
+use tracing::{event, Level};
 
 use crate::core::{
-    self, evaluate_constant_expression, stack_entry::StackEntry, Callable, ConstantDataStore,
-    DataStore, FuncType, FunctionStore, Global, Memory, Stack, Table,
+    self, evaluate_constant_expression, handle, stack_entry::StackEntry, trap, Callable,
+    ConstantDataStore, DataStore, FuncType, FunctionStore, Global, Handle, LEByteConvert, Lock,
+    Memory, MemoryView, ParseError, Shared, Stack, Table, TableElement, TrapKind, WasmError,
 };
 use crate::parser::InstructionSource;
-use crate::reader::{ModuleBuilder, ReaderUtil, ScopedReader, TypeReader};
+use crate::reader::{
+    check_module_header, find_section, ModuleBuilder, ParserConfig, PositionReader, ReaderUtil,
+    ScopedReader, TypeReader, MODULE_HEADER_LENGTH, SUPPORTED_WASM_VERSION, WASM_MAGIC,
+};
+use crate::writer::{TypeWriter, WriterUtil};
+
+// How much of a memory.copy between two memories is buffered at once
+const COPY_CHUNK_SIZE: usize = 0x1_0000;
+
+// An imported memory or table matches its import if it is at least as big as the import's
+// minimum, and can never grow beyond the import's maximum
+fn check_import_limits(
+    import: &core::Import,
+    limits: &core::Limits,
+    current_size: usize,
+    max_size: Option<usize>,
+) -> Result<()> {
+    if current_size < limits.minimum() {
+        return Err(anyhow!(
+            "Import {}:{} has size {} but at least {} is required",
+            import.mod_name(),
+            import.name(),
+            current_size,
+            limits.minimum()
+        ));
+    }
+
+    match (limits.maximum(), max_size) {
+        (Some(required), Some(max_size)) if max_size > required => Err(anyhow!(
+            "Import {}:{} can grow to {} but at most {} is allowed",
+            import.mod_name(),
+            import.name(),
+            max_size,
+            required
+        )),
+        (Some(required), None) => Err(anyhow!(
+            "Import {}:{} has no maximum size but at most {} is allowed",
+            import.mod_name(),
+            import.name(),
+            required
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Table entries can come from other instances, so a mismatch says where the entry was from
+fn check_indirect_call_type(
+    function: &Callable,
+    elem_idx: usize,
+    expected: &FuncType,
+) -> Result<()> {
+    if function.func_type() == expected {
+        return Ok(());
+    }
+
+    let origin = match function.provenance() {
+        Some(provenance) => provenance.to_string(),
+        None => "a function of unknown origin".to_string(),
+    };
+    Err(trap(TrapKind::IndirectCallTypeMismatch).context(format!(
+        "Indirect function call type does not match: table entry {} is {} of type {:?} but {:?} was expected",
+        elem_idx,
+        origin,
+        function.func_type(),
+        expected
+    )))
+}
 
 fn is_data_import(import: &core::Import) -> bool {
     match import.desc() {
@@ -35,6 +104,20 @@ struct RawModuleMetadata {
     types: Vec<core::FuncType>,
 }
 
+// Sections with nothing in them are left out, as they would be by a compiler
+fn write_section<T: Write, V: TypeWriter>(
+    writer: &mut T,
+    section_type: core::SectionType,
+    items: &[V],
+) -> Result<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    writer.write_u8(section_type as u8)?;
+    writer.write_sized(|section| section.write_vec(items, |w, item| item.write(w)))
+}
+
 #[derive(Debug)]
 pub struct RawModule {
     metadata: RawModuleMetadata,
@@ -48,79 +131,156 @@ pub struct RawModule {
     start: Option<usize>,
     imports: Vec<core::Import>,
     exports: Vec<core::Export>,
+    data_count: Option<usize>,
 }
 
 impl TypeReader for core::RawModule {
     fn read<T: Read>(reader: &mut T) -> Result<Self> {
-        const HEADER_LENGTH: usize = 8;
-        const EXPECTED_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        Self::read_with_config(reader, &ParserConfig::default())
+    }
+}
 
-        let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+impl RawModule {
+    /// Read a module, with any failure reported as a `WasmError::ParseError`
+    pub fn read_with_config<T: Read>(reader: &mut T, config: &ParserConfig) -> Result<Self> {
+        accounted!(Module, Self::read_sections(reader, config, None))
+    }
 
-        // Read in the header
-        reader.read_exact(&mut header)?;
+    /// Read a module which is already in memory. Function bodies are always read lazily,
+    /// and point into `bytes` rather than being copied out of it.
+    pub fn read_in_place(
+        bytes: Shared<dyn core::SharedBytes>,
+        config: &ParserConfig,
+    ) -> Result<Self> {
+        let config = ParserConfig {
+            lazy_function_bodies: true,
+            ..config.clone()
+        };
+        let code_section = find_section(bytes.bytes(), core::SectionType::CodeSection)
+            .map(|section| (bytes.clone(), section));
+        accounted!(
+            Module,
+            Self::read_sections(&mut bytes.bytes(), &config, code_section)
+        )
+    }
 
-        if header != EXPECTED_HEADER {
-            Err(anyhow!("Invalid module header"))
-        } else {
-            let mut current_section_type: Option<core::SectionType> =
-                Some(core::SectionType::TypeSection);
-            let mut module_builder = ModuleBuilder::new();
-
-            loop {
-                if let Ok(section_type) = ModuleBuilder::read_next_section_header(reader) {
-                    // Read the section length
-                    let section_length = usize::try_from(reader.read_leb_u32()?).unwrap();
-                    // And make a scoped reader for the section
-                    let mut section_reader = ScopedReader::new(reader, section_length);
-
-                    // Always skip custom sections wherever they appear
-                    if section_type == core::SectionType::CustomSection {
-                        // Read the section name
-                        let section_name = section_reader.read_name()?;
-                        let _section_body = section_reader.read_bytes_to_end()?;
-
-                        println!("Skipping custom section \"{}\"", section_name);
-                    } else {
-                        while let Some(expected_section_type) = current_section_type {
-                            if expected_section_type == section_type {
-                                // This is the correct section type so we process it and move on
-                                module_builder
-                                    .process_section(section_type, &mut section_reader)?;
-
-                                // And the next section type is the same as this one
-                                current_section_type = Some(expected_section_type);
-                                break;
-                            } else {
-                                // The section type doesn't match, so we move on to see if it
-                                // is the next valid section
-                                current_section_type =
-                                    ModuleBuilder::get_next_section_type(expected_section_type);
-                            }
-                        }
+    // Any failure is reported as a `WasmError::ParseError` which says how far through the
+    // module, and which section, the reader had got to
+    fn read_sections<T: Read>(
+        reader: &mut T,
+        config: &ParserConfig,
+        code_in_place: Option<(Shared<dyn core::SharedBytes>, Range<usize>)>,
+    ) -> Result<Self> {
+        let mut reader = PositionReader::new(reader);
+        let mut section = None;
+        Self::read_sections_from(&mut reader, &mut section, config, code_in_place).map_err(|e| {
+            if e.downcast_ref::<WasmError>().is_some() {
+                e
+            } else {
+                WasmError::ParseError(ParseError {
+                    section,
+                    offset: reader.position(),
+                    message: format!("{:#}", e),
+                })
+                .into()
+            }
+        })
+    }
 
-                        if current_section_type == None {
-                            assert!(false, "Sections are in unexpected order");
-                            return Err(anyhow!("Invalid section order"));
-                        }
-                    }
+    fn read_sections_from<T: Read>(
+        reader: &mut T,
+        section: &mut Option<core::SectionType>,
+        config: &ParserConfig,
+        code_in_place: Option<(Shared<dyn core::SharedBytes>, Range<usize>)>,
+    ) -> Result<Self> {
+        // Read in the header. A short read is reported by the header check rather than as
+        // an IO error because it usually means the file isn't a module at all
+        let mut header = Vec::with_capacity(MODULE_HEADER_LENGTH);
+        reader
+            .by_ref()
+            .take(MODULE_HEADER_LENGTH as u64)
+            .read_to_end(&mut header)?;
+
+        check_module_header(&header)?;
+        let mut module_size = MODULE_HEADER_LENGTH as u64;
+
+        let mut module_builder = ModuleBuilder::with_config(config);
+        if let Some((buffer, section)) = code_in_place {
+            module_builder.set_code_in_place(buffer, section);
+        }
 
-                    if !section_reader.is_at_end() {
-                        assert!(false, "Failed to read whole section");
-                        return Err(anyhow!("Failed to read whole section"));
-                    }
-                } else {
-                    // End of file, so we can break out of the loop
-                    break;
-                }
+        while let Some(section_type) = ModuleBuilder::read_next_section_header(reader)? {
+            // Read the section length
+            let section_length = config.check_section_size(module_size, reader.read_leb_u32()?)?;
+            module_size += section_length as u64;
+            // And make a scoped reader for the section
+            let mut section_reader = ScopedReader::new(reader, section_length);
+            *section = Some(section_type);
+
+            // Always skip custom sections wherever they appear. Only the name is
+            // wanted, so the body is never buffered.
+            if section_type == core::SectionType::CustomSection {
+                config.check_custom_section_size(section_length)?;
+                let section_name = section_reader.read_name()?;
+                section_reader.skip_to_end()?;
+
+                event!(
+                    target: "wasm_reader",
+                    Level::DEBUG,
+                    "Skipping custom section \"{}\"",
+                    section_name
+                );
+            } else {
+                event!(
+                    target: "wasm_reader",
+                    Level::DEBUG,
+                    "Reading {:?} of {} bytes",
+                    section_type,
+                    section_length
+                );
+                module_builder.process_section(section_type, &mut section_reader)?;
             }
 
-            module_builder.make_module()
+            if !section_reader.is_at_end() {
+                return Err(anyhow!("Failed to read whole section"));
+            }
+            *section = None;
         }
+
+        module_builder.make_module()
+    }
+
+    /// Write the module in the binary format. Custom sections are dropped when a module
+    /// is read, so they are not written.
+    pub fn write<T: Write>(&self, writer: &mut T) -> Result<()> {
+        writer.write_bytes(&WASM_MAGIC)?;
+        writer.write_bytes(&SUPPORTED_WASM_VERSION.to_le_bytes())?;
+
+        write_section(writer, core::SectionType::TypeSection, self.types())?;
+        write_section(writer, core::SectionType::ImportSection, &self.imports)?;
+        if !self.typeidx.is_empty() {
+            writer.write_u8(core::SectionType::FunctionSection as u8)?;
+            writer.write_sized(|section| {
+                section.write_vec(&self.typeidx, |w, idx| w.write_leb_usize(*idx))
+            })?;
+        }
+        write_section(writer, core::SectionType::TableSection, &self.tables)?;
+        write_section(writer, core::SectionType::MemorySection, &self.mems)?;
+        write_section(writer, core::SectionType::GlobalSection, &self.globals)?;
+        write_section(writer, core::SectionType::ExportSection, &self.exports)?;
+        if let Some(start) = self.start {
+            writer.write_u8(core::SectionType::StartSection as u8)?;
+            writer.write_sized(|section| section.write_leb_usize(start))?;
+        }
+        write_section(writer, core::SectionType::ElementSection, &self.elem)?;
+        if let Some(data_count) = self.data_count {
+            writer.write_u8(core::SectionType::DataCountSection as u8)?;
+            writer.write_sized(|section| section.write_leb_usize(data_count))?;
+        }
+        write_section(writer, core::SectionType::CodeSection, &self.funcs)?;
+        write_section(writer, core::SectionType::DataSection, &self.data)
     }
-}
 
-impl RawModule {
     pub fn new(
         types: Vec<core::FuncType>,
         typeidx: Vec<usize>,
@@ -146,44 +306,286 @@ impl RawModule {
             start,
             imports,
             exports,
+            data_count: None,
         }
     }
+
+    pub fn types(&self) -> &[core::FuncType] {
+        &self.metadata.types
+    }
+
+    /// The type index of each function defined in the module, in the same order as `funcs`
+    pub fn typeidx(&self) -> &[usize] {
+        &self.typeidx
+    }
+
+    pub fn funcs(&self) -> &[core::Func] {
+        &self.funcs
+    }
+
+    pub fn tables(&self) -> &[core::TableType] {
+        &self.tables
+    }
+
+    pub fn mems(&self) -> &[core::MemType] {
+        &self.mems
+    }
+
+    pub fn globals(&self) -> &[core::GlobalDef] {
+        &self.globals
+    }
+
+    pub fn elem(&self) -> &[core::Element] {
+        &self.elem
+    }
+
+    pub fn data(&self) -> &[core::Data] {
+        &self.data
+    }
+
+    pub fn start(&self) -> Option<usize> {
+        self.start
+    }
+
+    pub fn imports(&self) -> &[core::Import] {
+        &self.imports
+    }
+
+    pub fn exports(&self) -> &[core::Export] {
+        &self.exports
+    }
+
+    /// How many data segments the module's data count section says there are, if it has one
+    pub fn data_count(&self) -> Option<usize> {
+        self.data_count
+    }
+
+    pub(crate) fn set_data_count(&mut self, data_count: Option<usize>) {
+        self.data_count = data_count;
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ExportValue {
-    Function(Rc<RefCell<Callable>>),
-    Table(Rc<RefCell<Table>>),
-    Memory(Rc<RefCell<Memory>>),
-    Global(Rc<RefCell<Global>>),
+    Function(Handle<Callable>),
+    Table(Handle<Table>),
+    Memory(Handle<Memory>),
+    Global(Handle<Global>),
+}
+
+fn current_limits(current_size: usize, max_size: Option<usize>) -> core::Limits {
+    match max_size {
+        Some(max_size) => core::Limits::Bounded(current_size, max_size),
+        None => core::Limits::Unbounded(current_size),
+    }
+}
+
+impl ExportValue {
+    /// The type of the export as it is now. Tables and memories can have grown since the
+    /// module was instantiated, so their minimum is their current size.
+    pub fn export_type(&self) -> ExportType {
+        match self {
+            ExportValue::Function(callable) => {
+                ExportType::Func(callable.borrow().func_type().clone())
+            }
+            ExportValue::Table(table) => {
+                let table = table.borrow();
+                ExportType::Table(core::TableType::new(
+                    table.elem_type().clone(),
+                    current_limits(table.current_size(), table.max_size()),
+                ))
+            }
+            ExportValue::Memory(memory) => {
+                let memory = memory.borrow();
+                let limits = current_limits(memory.current_size(), memory.max_size());
+                ExportType::Memory(if memory.is_shared() {
+                    core::MemType::new_shared(limits)
+                } else {
+                    core::MemType::new(limits)
+                })
+            }
+            ExportValue::Global(global) => {
+                ExportType::Global(global.borrow().global_type().clone())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExportKind {
+    Func,
+    Table,
+    Memory,
+    Global,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExportType {
+    Func(FuncType),
+    Table(core::TableType),
+    Memory(core::MemType),
+    Global(core::GlobalType),
+}
+
+impl ExportType {
+    pub fn kind(&self) -> ExportKind {
+        match self {
+            ExportType::Func(_) => ExportKind::Func,
+            ExportType::Table(_) => ExportKind::Table,
+            ExportType::Memory(_) => ExportKind::Memory,
+            ExportType::Global(_) => ExportKind::Global,
+        }
+    }
+}
+
+/// An export of an instance, described without handing out the export itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportInfo<'a> {
+    pub name: &'a str,
+    pub kind: ExportKind,
+    pub export_type: ExportType,
+}
+
+/// The exports of a module, which can be looked up by name like a map, or listed in the
+/// order the module declares them
+#[derive(Debug, Clone, Default)]
+pub struct Exports {
+    values: BTreeMap<String, ExportValue>,
+    order: Vec<String>,
+}
+
+impl Exports {
+    fn insert(&mut self, name: String, value: ExportValue) {
+        if self.values.insert(name.clone(), value).is_none() {
+            self.order.push(name);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ExportValue> {
+        self.values.get(name)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// The exports in module order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &ExportValue)> {
+        self.order
+            .iter()
+            .map(move |name| (name, &self.values[name]))
+    }
+
+    /// The exports in name order
+    pub fn iter_by_name(&self) -> impl Iterator<Item = (&String, &ExportValue)> {
+        self.values.iter()
+    }
+}
+
+impl std::ops::Index<&str> for Exports {
+    type Output = ExportValue;
+
+    fn index(&self, name: &str) -> &ExportValue {
+        &self.values[name]
+    }
+}
+
+/// The functions, tables, memories or globals of an instance, in index order. Imports come
+/// first, in the order the module imports them, and the module's own definitions follow,
+/// so every index a module uses means the same thing whichever section it appears in.
+#[derive(Debug, Clone)]
+pub struct IndexSpace<T> {
+    items: Vec<T>,
+    imported: usize,
+}
+
+impl<T> IndexSpace<T> {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            imported: 0,
+        }
+    }
+
+    /// Add an import, which must come before anything the module defines
+    pub fn import(&mut self, item: T) -> Result<usize> {
+        if self.imported != self.items.len() {
+            return Err(anyhow!("Imports must be added before definitions"));
+        }
+        self.imported += 1;
+        Ok(self.define(item))
+    }
+
+    /// Add something the module defines itself, after every import
+    pub fn define(&mut self, item: T) -> usize {
+        self.items.push(item);
+        self.items.len() - 1
+    }
+
+    pub fn imported_count(&self) -> usize {
+        self.imported
+    }
+
+    pub fn is_imported(&self, idx: usize) -> bool {
+        idx < self.imported
+    }
+
+    pub fn definitions(&self) -> &[T] {
+        &self.items[self.imported..]
+    }
+}
+
+impl<T> Default for IndexSpace<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> std::ops::Deref for IndexSpace<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items
+    }
 }
 
 #[derive(Debug)]
 pub struct DataModule {
-    pub memories: Vec<Rc<RefCell<Memory>>>,
-    pub globals: Vec<Rc<RefCell<Global>>>,
+    pub memories: IndexSpace<Handle<Memory>>,
+    pub globals: IndexSpace<Handle<Global>>,
+    data: Vec<Vec<u8>>,
 }
 
 impl DataModule {
     pub fn new() -> Self {
         Self {
-            memories: Vec::new(),
-            globals: Vec::new(),
+            memories: IndexSpace::new(),
+            globals: IndexSpace::new(),
+            data: Vec::new(),
         }
     }
 
-    fn pre_execute_validate(&self) -> Result<()> {
-        if self.memories.len() > 1 {
-            Err(anyhow!("Too many memories"))
-        } else {
-            Ok(())
-        }
+    /// A view of the memory with the index, whether the module defines or imports it
+    pub fn memory(&self, idx: usize) -> Result<MemoryView> {
+        self.memories
+            .get(idx)
+            .map(|memory| MemoryView::new(memory.clone()))
+            .ok_or_else(|| anyhow!("Memory index {} out of range", idx))
     }
 
     fn add_memories<Iter: Iterator<Item = core::MemType>>(&mut self, memories: Iter) -> Result<()> {
         for memory in memories {
-            self.memories
-                .push(Rc::new(RefCell::new(Memory::new(memory))));
+            self.memories.define(handle(Memory::new(memory)));
         }
 
         Ok(())
@@ -194,10 +596,11 @@ impl DataModule {
             let global_type = global.global_type().clone();
             let init_expr = global.init_expr();
 
-            let results = evaluate_constant_expression(init_expr, self, 1)?;
+            let results = evaluate_constant_expression(init_expr, self, 1)
+                .with_context(|| format!("In the initializer of global {}", self.globals.len()))?;
             let global = Global::new(global_type, results[0])?;
 
-            self.globals.push(Rc::new(RefCell::new(global)));
+            self.globals.define(handle(global));
         }
 
         Ok(())
@@ -212,14 +615,12 @@ impl DataModule {
         }
     }
 
-    fn initialize_memory_data(&self, data: core::Data) -> Result<()> {
-        if data.mem_idx() >= self.memories.len() {
+    fn initialize_memory_data(&self, mem_idx: usize, expr: &core::Expr, data: &[u8]) -> Result<()> {
+        if mem_idx >= self.memories.len() {
             Err(anyhow!("Memory initializer mem idx out of range"))
         } else {
-            let memory = &self.memories[data.mem_idx()];
-            let offset = self.evaluate_offset_expression(data.expr())?;
-
-            let data = data.bytes();
+            let memory = &self.memories[mem_idx];
+            let offset = self.evaluate_offset_expression(expr)?;
 
             memory.borrow_mut().set_data(offset, data)?;
 
@@ -227,9 +628,17 @@ impl DataModule {
         }
     }
 
-    fn initialize_memory<Iter: Iterator<Item = core::Data>>(&self, iter: Iter) -> Result<()> {
+    fn initialize_memory<Iter: Iterator<Item = core::Data>>(&mut self, iter: Iter) -> Result<()> {
         for data in iter {
-            self.initialize_memory_data(data)?;
+            // Active segments are dropped once they have been copied into memory, so only
+            // passive segments keep their contents for use by memory.init
+            match data.mode() {
+                core::DataMode::Active(mem_idx, expr) => {
+                    self.initialize_memory_data(*mem_idx, expr, data.bytes())?;
+                    self.data.push(Vec::new());
+                }
+                core::DataMode::Passive => self.data.push(data.into_bytes()),
+            }
         }
 
         Ok(())
@@ -244,12 +653,40 @@ impl DataModule {
             core::ImportDesc::MemType(mem_type) => {
                 let resolved_memory =
                     resolver.resolve_memory(import.mod_name(), import.name(), mem_type)?;
-                self.memories.push(resolved_memory);
+                {
+                    let memory = resolved_memory.borrow();
+                    check_import_limits(
+                        &import,
+                        mem_type.limits(),
+                        memory.current_size(),
+                        memory.max_size(),
+                    )?;
+                    if memory.is_shared() != mem_type.is_shared() {
+                        return Err(anyhow!(
+                            "Import {}:{} must {}be a shared memory",
+                            import.mod_name(),
+                            import.name(),
+                            if mem_type.is_shared() { "" } else { "not " }
+                        ));
+                    }
+                }
+                self.memories.import(resolved_memory)?;
             }
             core::ImportDesc::GlobalType(global_type) => {
                 let resolved_global =
                     resolver.resolve_global(import.mod_name(), import.name(), global_type)?;
-                self.globals.push(resolved_global);
+                // The mutability has to match as well as the value type, because a module
+                // which imports a constant may assume that nothing else can change it
+                if resolved_global.borrow().global_type() != global_type {
+                    return Err(anyhow!(
+                        "Imported global {}:{} has type {:?} but {:?} was expected",
+                        import.mod_name(),
+                        import.name(),
+                        resolved_global.borrow().global_type(),
+                        global_type
+                    ));
+                }
+                self.globals.import(resolved_global)?;
             }
 
             _ => panic!("Not a data import"),
@@ -288,17 +725,28 @@ impl ConstantDataStore for DataModule {
             Err(anyhow!("Global index out of range"))
         }
     }
+
+    fn is_constant_global(&self, idx: usize) -> bool {
+        self.globals.is_imported(idx) && !self.globals[idx].borrow().is_mutable()
+    }
 }
 
 impl DataStore for DataModule {
     fn set_global_value(&mut self, idx: usize, value: StackEntry) -> Result<()> {
         if idx < self.globals.len() {
-            self.globals[idx].borrow_mut().set_value(value)
+            self.globals[idx]
+                .borrow_mut()
+                .set_value(value)
+                .with_context(|| format!("In GlobalSet of global {}", idx))
         } else {
             Err(anyhow!("Global index out of range"))
         }
     }
 
+    fn cached_memory(&self) -> Option<&Handle<Memory>> {
+        self.memories.first()
+    }
+
     fn read_data(&self, mem_idx: usize, offset: usize, data: &mut [u8]) -> Result<()> {
         if mem_idx < self.memories.len() {
             self.memories[mem_idx].borrow().get_data(offset, data)
@@ -315,6 +763,25 @@ impl DataStore for DataModule {
         }
     }
 
+    fn load_data<T: LEByteConvert>(&self, mem_idx: usize, offset: usize) -> Result<T> {
+        match self.memories.get(mem_idx) {
+            Some(memory) => memory.borrow().load(offset),
+            None => Err(anyhow!("Memory index out of range")),
+        }
+    }
+
+    fn store_data<T: LEByteConvert>(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: T,
+    ) -> Result<()> {
+        match self.memories.get(mem_idx) {
+            Some(memory) => memory.borrow_mut().store(offset, value),
+            None => Err(anyhow!("Memory index out of range")),
+        }
+    }
+
     fn get_memory_size(&self, mem_idx: usize) -> Result<usize> {
         if mem_idx < self.memories.len() {
             Ok(self.memories[mem_idx].borrow().current_size())
@@ -323,61 +790,272 @@ impl DataStore for DataModule {
         }
     }
 
-    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<()> {
+    fn grow_memory_by(&mut self, mem_idx: usize, grow_by: usize) -> Result<bool> {
+        if mem_idx < self.memories.len() {
+            Ok(self.memories[mem_idx].borrow_mut().grow_by(grow_by).is_ok())
+        } else {
+            Err(anyhow!("Memory index out of range"))
+        }
+    }
+
+    fn copy_memory(
+        &mut self,
+        dst_mem_idx: usize,
+        dst_offset: usize,
+        src_mem_idx: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        if dst_mem_idx >= self.memories.len() || src_mem_idx >= self.memories.len() {
+            return Err(anyhow!("Memory index out of range"));
+        }
+        let (src, dst) = (&self.memories[src_mem_idx], &self.memories[dst_mem_idx]);
+        if Shared::ptr_eq(src, dst) {
+            return dst.borrow_mut().copy_within(dst_offset, src_offset, length);
+        }
+
+        // The length comes from the guest, so both ranges are checked before anything is
+        // copied, and the copy goes a chunk at a time rather than through a buffer as long
+        // as the guest asks for
+        if !src.borrow().contains(src_offset, length) || !dst.borrow().contains(dst_offset, length)
+        {
+            return Err(trap(TrapKind::MemoryOutOfBounds)
+                .context("Attempting to copy outside allocated memory"));
+        }
+        let mut buf = vec![0; length.min(COPY_CHUNK_SIZE)];
+        for copied in (0..length).step_by(COPY_CHUNK_SIZE) {
+            let chunk = &mut buf[..(length - copied).min(COPY_CHUNK_SIZE)];
+            src.borrow().get_data(src_offset + copied, chunk)?;
+            dst.borrow_mut().set_data(dst_offset + copied, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn fill_memory(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        value: u8,
+        length: usize,
+    ) -> Result<()> {
+        if mem_idx < self.memories.len() {
+            self.memories[mem_idx]
+                .borrow_mut()
+                .fill(offset, value, length)
+        } else {
+            Err(anyhow!("Memory index out of range"))
+        }
+    }
+
+    fn init_memory(
+        &mut self,
+        mem_idx: usize,
+        data_idx: usize,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        if data_idx >= self.data.len() {
+            Err(anyhow!("Data segment index out of range"))
+        } else if mem_idx >= self.memories.len() {
+            Err(anyhow!("Memory index out of range"))
+        } else {
+            let data = &self.data[data_idx];
+            match src_offset.checked_add(length) {
+                Some(end) if end <= data.len() => self.memories[mem_idx]
+                    .borrow_mut()
+                    .set_data(dst_offset, &data[src_offset..end]),
+                _ => Err(trap(TrapKind::MemoryOutOfBounds)
+                    .context("Attempting to access outside data segment")),
+            }
+        }
+    }
+
+    fn drop_data(&mut self, data_idx: usize) -> Result<()> {
+        if data_idx < self.data.len() {
+            self.data[data_idx] = Vec::new();
+            Ok(())
+        } else {
+            Err(anyhow!("Data segment index out of range"))
+        }
+    }
+
+    fn update_data(
+        &mut self,
+        mem_idx: usize,
+        offset: usize,
+        length: usize,
+        update: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<()> {
         if mem_idx < self.memories.len() {
-            self.memories[mem_idx].borrow_mut().grow_by(grow_by)
+            self.memories[mem_idx]
+                .borrow_mut()
+                .update_data(offset, length, update)
         } else {
             Err(anyhow!("Memory index out of range"))
         }
     }
+
+    fn wait_memory(
+        &self,
+        mem_idx: usize,
+        offset: usize,
+        expected: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<u32> {
+        if mem_idx < self.memories.len() {
+            self.memories[mem_idx]
+                .borrow()
+                .wait(offset, expected, timeout)
+        } else {
+            Err(anyhow!("Memory index out of range"))
+        }
+    }
+
+    fn notify_memory(&self, mem_idx: usize, offset: usize, count: u32) -> Result<u32> {
+        if mem_idx < self.memories.len() {
+            self.memories[mem_idx].borrow().notify(offset, count)
+        } else {
+            Err(anyhow!("Memory index out of range"))
+        }
+    }
+}
+
+/// The parts of an instance which executing it can change, so that they can be put back
+/// as they were. Imported memories, tables and globals are included, so putting them back
+/// affects every instance which shares them.
+#[derive(Debug, Clone)]
+pub(crate) struct InstanceState {
+    memories: Vec<Vec<u8>>,
+    globals: Vec<StackEntry>,
+    data: Vec<Vec<u8>>,
+    tables: Vec<Vec<TableElement>>,
+    elements: Vec<Vec<TableElement>>,
+}
+
+impl InstanceState {
+    pub(crate) fn capture(
+        function_module: &FunctionModule,
+        data_module: &DataModule,
+    ) -> Result<Self> {
+        Ok(Self {
+            memories: data_module
+                .memories
+                .iter()
+                .map(|memory| memory.borrow().contents())
+                .collect::<Result<_>>()?,
+            globals: data_module
+                .globals
+                .iter()
+                .map(|global| *global.borrow().get_value())
+                .collect(),
+            data: data_module.data.clone(),
+            tables: function_module
+                .tables
+                .iter()
+                .map(|table| table.borrow().entries().to_vec())
+                .collect(),
+            elements: function_module.elements.borrow().clone(),
+        })
+    }
+
+    pub(crate) fn restore(
+        &self,
+        function_module: &FunctionModule,
+        data_module: &mut DataModule,
+    ) -> Result<()> {
+        for (memory, contents) in data_module.memories.iter().zip(&self.memories) {
+            memory.borrow_mut().restore_contents(contents)?;
+        }
+        // Constant globals can't have changed
+        for (global, value) in data_module.globals.iter().zip(&self.globals) {
+            let mut global = global.borrow_mut();
+            if global.is_mutable() {
+                global.set_value(*value)?;
+            }
+        }
+        data_module.data = self.data.clone();
+        for (table, entries) in function_module.tables.iter().zip(&self.tables) {
+            table.borrow_mut().restore_entries(entries);
+        }
+        *function_module.elements.borrow_mut() = self.elements.clone();
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct FunctionModule {
-    pub functions: Vec<Rc<RefCell<Callable>>>,
-    pub tables: Vec<Rc<RefCell<Table>>>,
+    instance_id: core::InstanceId,
+    pub functions: IndexSpace<Handle<Callable>>,
+    pub tables: IndexSpace<Handle<Table>>,
     func_types: Vec<FuncType>,
+    // Element segments, which can be dropped while the module is executing
+    elements: Lock<Vec<Vec<TableElement>>>,
+    // The start function, when instantiation left it for the host to run
+    pending_start: Lock<Option<Handle<Callable>>>,
 }
 
 impl FunctionModule {
     fn new() -> Self {
         Self {
-            functions: Vec::new(),
-            tables: Vec::new(),
+            instance_id: core::InstanceId::next(),
+            functions: IndexSpace::new(),
+            tables: IndexSpace::new(),
             func_types: Vec::new(),
+            elements: Lock::new(Vec::new()),
+            pending_start: Lock::new(None),
         }
     }
 
-    fn pre_execute_validate(&self) -> Result<()> {
-        if self.tables.len() > 1 {
-            Err(anyhow!("Too many tables"))
-        } else {
-            Ok(())
-        }
+    /// Take the start function which is waiting to be run, if there is one
+    pub(crate) fn take_pending_start(&self) -> Option<Handle<Callable>> {
+        self.pending_start.borrow_mut().take()
+    }
+
+    pub fn instance_id(&self) -> core::InstanceId {
+        self.instance_id
     }
 
     fn add_functions<Iter: Iterator<Item = (usize, core::Func)>>(
         &mut self,
         functions: Iter,
         metadata: &RawModuleMetadata,
+        context: core::compiled::ModuleContext,
     ) -> Result<()> {
+        let context = core::Shared::new(context);
+        let types: core::Shared<[FuncType]> = metadata.types.clone().into();
         for (type_idx, func) in functions {
             if type_idx >= metadata.types.len() {
                 return Err(anyhow!("Function has invalid type index"));
             }
 
-            self.functions
-                .push(Rc::new(RefCell::new(core::WasmExprCallable::new(
-                    metadata.types[type_idx].clone(),
-                    func.clone(),
-                ))));
+            let func_type = metadata.types[type_idx].clone();
+            let lazy = func.is_lazy();
+            let mut callable = if lazy {
+                core::WasmExprCallable::new_lazy(func_type, func, types.clone(), context.clone())?
+            } else {
+                core::WasmExprCallable::new(func_type, func, &metadata.types)
+                    .with_context(|| format!("In function {}", self.functions.len()))?
+            };
+            callable.record_provenance(core::Provenance::new(
+                self.instance_id,
+                self.functions.len(),
+            ));
+            if !lazy {
+                if let Some(body) = callable.compiled_body()? {
+                    body.check_module_references(&context, callable.func_type(), callable.locals())
+                        .with_context(|| format!("In function {}", self.functions.len()))?;
+                }
+            }
+
+            self.functions.define(handle(callable));
         }
         Ok(())
     }
 
     fn add_tables<Iter: Iterator<Item = core::TableType>>(&mut self, tables: Iter) -> Result<()> {
         for table in tables {
-            self.tables.push(Rc::new(RefCell::new(Table::new(table))));
+            self.tables.define(handle(Table::new(table)));
         }
 
         Ok(())
@@ -388,33 +1066,75 @@ impl FunctionModule {
         Ok(())
     }
 
+    // Imported functions which have come from the host have nowhere else to record where
+    // they came from, so this instance is recorded as their source
+    fn resolve_element_function(&self, idx: usize) -> Result<TableElement> {
+        let function = self
+            .functions
+            .get(idx)
+            .cloned()
+            .ok_or_else(|| anyhow!("Function index out of range"))?;
+        function
+            .borrow_mut()
+            .record_provenance(core::Provenance::new(self.instance_id, idx));
+        Ok(TableElement::FuncRef(Some(function)))
+    }
+
+    fn resolve_element_items(
+        &self,
+        element: &core::Element,
+        data_module: &DataModule,
+    ) -> Result<Vec<TableElement>> {
+        match element.items() {
+            core::ElementItems::Functions(indices) => indices
+                .iter()
+                .map(|idx| self.resolve_element_function(*idx))
+                .collect(),
+            core::ElementItems::Expressions(elem_type, exprs) => exprs
+                .iter()
+                .map(|expr| {
+                    let entry = match evaluate_constant_expression(expr, data_module, 1)?[0] {
+                        StackEntry::FuncRefEntry(Some(idx)) => {
+                            self.resolve_element_function(idx as usize)?
+                        }
+                        StackEntry::FuncRefEntry(None) => TableElement::FuncRef(None),
+                        StackEntry::ExternRefEntry(extern_ref) => {
+                            TableElement::ExternRef(extern_ref)
+                        }
+                        entry => {
+                            return Err(anyhow!(
+                                "Element expression gives {:?}, which is not a reference",
+                                entry.value_type()
+                            ))
+                        }
+                    };
+                    if entry.elem_type() != *elem_type {
+                        return Err(anyhow!(
+                            "Element expression gives {:?} in a segment of {:?}",
+                            entry.elem_type(),
+                            elem_type
+                        ));
+                    }
+                    Ok(entry)
+                })
+                .collect(),
+        }
+    }
+
     fn initialize_table_element(
         &self,
-        element: core::Element,
+        table_idx: usize,
+        expr: &core::Expr,
+        entries: &[TableElement],
         data_module: &DataModule,
     ) -> Result<()> {
-        if element.table_idx() >= self.tables.len() {
+        if table_idx >= self.tables.len() {
             Err(anyhow!("Table initializer table idx out of range"))
         } else {
-            let table = &self.tables[element.table_idx()];
-            let offset = data_module.evaluate_offset_expression(element.expr())?;
-
-            let functions = element.func_indices();
-            let functions: Result<Vec<_>> = functions
-                .into_iter()
-                .map(|idx| {
-                    if *idx < self.functions.len() {
-                        Ok(self.functions[*idx].clone())
-                    } else {
-                        Err(anyhow!("Function index out of range"))
-                    }
-                })
-                .collect();
-            let functions = functions?;
-
-            table.borrow_mut().set_entries(offset, &functions);
-
-            Ok(())
+            let offset = data_module.evaluate_offset_expression(expr)?;
+            self.tables[table_idx]
+                .borrow_mut()
+                .init(offset, entries, 0, entries.len())
         }
     }
 
@@ -424,7 +1144,20 @@ impl FunctionModule {
         data_module: &DataModule,
     ) -> Result<()> {
         for element in iter {
-            self.initialize_table_element(element, data_module)?;
+            let entries = self.resolve_element_items(&element, data_module)?;
+
+            // Active segments are dropped once they have been copied into their table, and
+            // declarative ones are dropped straight away, so table.init can only use them
+            // to copy nothing. Passive segments keep their entries until elem.drop.
+            let segment = match element.mode() {
+                core::ElementMode::Active(table_idx, expr) => {
+                    self.initialize_table_element(*table_idx, expr, &entries, data_module)?;
+                    Vec::new()
+                }
+                core::ElementMode::Passive => entries,
+                core::ElementMode::Declarative => Vec::new(),
+            };
+            self.elements.borrow_mut().push(segment);
         }
 
         Ok(())
@@ -446,17 +1179,42 @@ impl FunctionModule {
                     ));
                 }
 
-                let resolved_function = resolver.resolve_function(
-                    import.mod_name(),
-                    import.name(),
-                    &metadata.types[*type_index],
-                )?;
-                self.functions.push(resolved_function);
+                let func_type = &metadata.types[*type_index];
+                let resolved_function =
+                    resolver.resolve_function(import.mod_name(), import.name(), func_type)?;
+                if resolved_function.borrow().func_type() != func_type {
+                    return Err(anyhow!(
+                        "Imported function {}:{} has type {:?} but {:?} was expected",
+                        import.mod_name(),
+                        import.name(),
+                        resolved_function.borrow().func_type(),
+                        func_type
+                    ));
+                }
+                self.functions.import(resolved_function)?;
             }
             core::ImportDesc::TableType(table_type) => {
                 let resolved_table =
                     resolver.resolve_table(import.mod_name(), import.name(), table_type)?;
-                self.tables.push(resolved_table);
+                {
+                    let table = resolved_table.borrow();
+                    if table.elem_type() != table_type.elem_type() {
+                        return Err(anyhow!(
+                            "Import {}:{} holds {:?} but {:?} is required",
+                            import.mod_name(),
+                            import.name(),
+                            table.elem_type(),
+                            table_type.elem_type()
+                        ));
+                    }
+                    check_import_limits(
+                        &import,
+                        table_type.limits(),
+                        table.current_size(),
+                        table.max_size(),
+                    )?;
+                }
+                self.tables.import(resolved_table)?;
             }
 
             _ => panic!("Not a function import"),
@@ -488,42 +1246,72 @@ impl FunctionModule {
 }
 
 impl FunctionStore for FunctionModule {
-    fn execute_function(
-        &self,
-        idx: usize,
-        stack: &mut Stack,
-        data_store: &mut impl DataStore,
-    ) -> Result<()> {
-        if idx < self.functions.len() {
-            let callable = self.functions[idx].borrow();
-            callable.call(stack, self, data_store)
-        } else {
-            Err(anyhow!("Callable index out of range"))
-        }
+    fn get_function(&self, idx: usize) -> Result<Handle<Callable>> {
+        self.functions
+            .get(idx)
+            .cloned()
+            .ok_or_else(|| anyhow!("Callable index out of range"))
     }
 
-    fn execute_indirect_function(
+    fn get_indirect_function(
         &self,
         func_type_idx: usize,
         table_idx: usize,
         elem_idx: usize,
-        stack: &mut Stack,
-        data_store: &mut impl DataStore,
-    ) -> Result<()> {
+    ) -> Result<Handle<Callable>> {
         if func_type_idx >= self.func_types.len() {
             Err(anyhow!("FuncType index out of range"))
         } else if table_idx >= self.tables.len() {
             Err(anyhow!("Table index out of range"))
         } else {
-            let table = self.tables[table_idx].borrow();
-            let callable = table.get_entry(elem_idx)?;
-            let callable = callable.borrow();
+            let callable = self.tables[table_idx].borrow().get_entry(elem_idx)?;
+
+            check_indirect_call_type(
+                &callable.borrow(),
+                elem_idx,
+                &self.func_types[func_type_idx],
+            )?;
+            Ok(callable)
+        }
+    }
 
-            if *callable.func_type() != self.func_types[func_type_idx] {
-                Err(anyhow!("Indirect function call type does not match"))
-            } else {
-                callable.call(stack, self, data_store)
+    fn get_function_index(&self, callable: &Handle<Callable>) -> Option<usize> {
+        self.functions
+            .iter()
+            .position(|f| Shared::ptr_eq(f, callable))
+    }
+
+    fn get_table(&self, table_idx: usize) -> Result<Handle<Table>> {
+        self.tables
+            .get(table_idx)
+            .cloned()
+            .ok_or_else(|| anyhow!("Table index out of range"))
+    }
+
+    fn init_table(
+        &self,
+        table_idx: usize,
+        elem_idx: usize,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        let elements = self.elements.borrow();
+        let segment = elements
+            .get(elem_idx)
+            .ok_or_else(|| anyhow!("Element segment index out of range"))?;
+        self.get_table(table_idx)?
+            .borrow_mut()
+            .init(dst_offset, segment, src_offset, length)
+    }
+
+    fn drop_elements(&self, elem_idx: usize) -> Result<()> {
+        match self.elements.borrow_mut().get_mut(elem_idx) {
+            Some(segment) => {
+                *segment = Vec::new();
+                Ok(())
             }
+            None => Err(anyhow!("Element segment index out of range")),
         }
     }
 }
@@ -536,6 +1324,13 @@ fn resolve_imports<Iter: Iterator<Item = core::Import>, Resolver: core::Resolver
     resolver: &Resolver,
 ) -> Result<()> {
     for import in imports {
+        event!(
+            target: "wasm_instantiate",
+            Level::DEBUG,
+            "Resolving import {}:{}",
+            import.mod_name(),
+            import.name()
+        );
         if is_data_import(&import) {
             data_module.resolve_import(import, resolver)?;
         } else {
@@ -550,8 +1345,8 @@ fn collect_exports<Iter: Iterator<Item = core::Export>>(
     function_module: &FunctionModule,
     data_module: &DataModule,
     exports: Iter,
-) -> Result<HashMap<String, ExportValue>> {
-    let mut ret = HashMap::new();
+) -> Result<Exports> {
+    let mut ret = Exports::default();
 
     for core::Export { nm, d } in exports {
         if is_data_export(&d) {
@@ -564,53 +1359,724 @@ fn collect_exports<Iter: Iterator<Item = core::Export>>(
     Ok(ret)
 }
 
-type LoadedModule = (FunctionModule, DataModule, HashMap<String, ExportValue>);
+pub type LoadedModule = (FunctionModule, DataModule, Exports);
+
+/// The function which is run once a module's tables and memories have been initialized.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum StartFunction {
+    /// The start function declared by the module, if it has one
+    #[default]
+    Declared,
+    /// The exported function with this name, in place of the declared one. It must take no
+    /// arguments and return no results, just as a declared start function must.
+    Export(String),
+    /// No function at all, so that the host can run any setup itself
+    Suppressed,
+}
 
-pub fn resolve_raw_module<Resolver: core::Resolver>(
-    module: RawModule,
+/// How a validated module is instantiated
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstantiateConfig {
+    pub start: StartFunction,
+    /// Whether to run the start function as part of instantiating the module. When this
+    /// is false, the start function waits for `Instance::run_start`, so that the host can
+    /// set up its own state or fill in memory first.
+    pub run_start: bool,
+}
+
+impl Default for InstantiateConfig {
+    fn default() -> Self {
+        Self {
+            start: StartFunction::Declared,
+            run_start: true,
+        }
+    }
+}
+
+/// A module whose imports have been resolved and whose contents have been checked, but
+/// which has not yet had its tables and memories initialized or its start function run.
+#[derive(Debug)]
+pub struct ValidatedModule {
+    function_module: FunctionModule,
+    data_module: DataModule,
+    exports: Exports,
+    elem: Vec<core::Element>,
+    data: Vec<core::Data>,
+    start: Option<usize>,
+}
+
+impl ValidatedModule {
+    /// Instantiate the module without running its start function, which is left for
+    /// `Instance::run_start`
+    pub fn instantiate(self) -> Result<LoadedModule> {
+        self.instantiate_with_config(&InstantiateConfig {
+            run_start: false,
+            ..InstantiateConfig::default()
+        })
+    }
+
+    /// Instantiate the module, running `start` in place of the start function the module
+    /// declares.
+    pub fn instantiate_with_start(self, start: &StartFunction) -> Result<LoadedModule> {
+        self.instantiate_with_config(&InstantiateConfig {
+            start: start.clone(),
+            run_start: true,
+        })
+    }
+
+    pub fn instantiate_with_config(self, config: &InstantiateConfig) -> Result<LoadedModule> {
+        let start = match &config.start {
+            StartFunction::Declared => self.start.map(|idx| self.function_module.get_function(idx)),
+            StartFunction::Export(name) => Some(find_start_export(&self.exports, name)),
+            StartFunction::Suppressed => None,
+        }
+        .transpose()?;
+
+        let ValidatedModule {
+            function_module,
+            mut data_module,
+            exports,
+            elem,
+            data,
+            ..
+        } = self;
+
+        // The next step is to initialize the tables and memories.
+        event!(
+            target: "wasm_instantiate",
+            Level::DEBUG,
+            "Initializing {} element segments and {} data segments",
+            elem.len(),
+            data.len()
+        );
+        function_module.initialize_table_elements(elem.into_iter(), &data_module)?;
+        data_module.initialize_memory(data.into_iter())?;
+
+        // Finally, if there is a start function specified then execute it.
+        match start {
+            Some(start) if config.run_start => {
+                event!(target: "wasm_instantiate", Level::DEBUG, "Running start function");
+                let mut stack = Stack::new();
+                start
+                    .borrow()
+                    .call(&mut stack, &function_module, &mut data_module)?;
+            }
+            start => *function_module.pending_start.borrow_mut() = start,
+        }
+
+        Ok((function_module, data_module, exports))
+    }
+}
+
+fn find_start_export(exports: &Exports, name: &str) -> Result<Handle<Callable>> {
+    let callable = match exports.get(name) {
+        Some(ExportValue::Function(callable)) => callable,
+        _ => {
+            return Err(anyhow!(
+                "No function {} is exported to use as the start function",
+                name
+            ))
+        }
+    };
+
+    let func_type = callable.borrow().func_type().clone();
+    if func_type != FuncType::new(vec![], vec![]) {
+        return Err(anyhow!(
+            "Start function {} has type {:?} but must take no arguments and return no results",
+            name,
+            func_type
+        ));
+    }
+    Ok(callable.clone())
+}
+
+pub fn validate_raw_module<Resolver: core::Resolver>(
+    mut module: RawModule,
     resolver: &Resolver,
-) -> Result<LoadedModule> {
+) -> Result<ValidatedModule> {
     let mut data_module = DataModule::new();
     let mut function_module = FunctionModule::new();
 
     resolve_imports(
         &mut function_module,
         &mut data_module,
-        module.imports.into_iter(),
+        module.imports.drain(..),
         &module.metadata,
         resolver,
-    )?;
+    )
+    .map_err(|e| WasmError::classify(e, WasmError::LinkError))?;
+
+    validate_definitions(function_module, data_module, module)
+        .map_err(|e| WasmError::classify(e, WasmError::ValidationError))
+}
+
+fn validate_definitions(
+    mut function_module: FunctionModule,
+    mut data_module: DataModule,
+    module: RawModule,
+) -> Result<ValidatedModule> {
+    // The memories and globals have to be known before the functions, so that the memory
+    // and global indices in the function bodies can be checked. Global initializers can
+    // only read imported globals, so they don't need the functions.
+    data_module.add_memories(module.mems.into_iter())?;
+    data_module.add_globals(module.globals.into_iter())?;
+    let context = core::compiled::ModuleContext::new(
+        data_module.memories.len(),
+        data_module
+            .globals
+            .iter()
+            .map(|global| global.borrow().global_type().clone())
+            .collect(),
+    );
     function_module.add_functions(
         module.typeidx.into_iter().zip(module.funcs.into_iter()),
         &module.metadata,
+        context,
     )?;
     function_module.add_tables(module.tables.into_iter())?;
-    data_module.add_memories(module.mems.into_iter())?;
-    data_module.add_globals(module.globals.into_iter())?;
     let exports = collect_exports(&function_module, &data_module, module.exports.into_iter())?;
     function_module.add_func_types(module.metadata.types)?;
+    event!(
+        target: "wasm_instantiate",
+        Level::DEBUG,
+        "Validated {} functions, {} tables, {} memories and {} globals",
+        function_module.functions.len(),
+        function_module.tables.len(),
+        data_module.memories.len(),
+        data_module.globals.len()
+    );
+
+    Ok(ValidatedModule {
+        function_module,
+        data_module,
+        exports,
+        elem: module.elem,
+        data: module.data,
+        start: module.start,
+    })
+}
 
-    // Everything prior to this point is setting up the environment so that we
-    // can start executing things, so make sure that everything is sane once we're
-    // at that point.
-    data_module.pre_execute_validate()?;
-    function_module.pre_execute_validate()?;
+pub fn resolve_raw_module<Resolver: core::Resolver>(
+    module: RawModule,
+    resolver: &Resolver,
+) -> Result<LoadedModule> {
+    accounted!(Module, validate_raw_module(module, resolver))?
+        .instantiate_with_config(&InstantiateConfig::default())
+}
 
-    // The next step is to initialize the tables and memories.
-    function_module.initialize_table_elements(module.elem.into_iter(), &data_module)?;
-    data_module.initialize_memory(module.data.into_iter())?;
+pub fn load_module_from_path(file: &str, resolver: &impl core::Resolver) -> Result<LoadedModule> {
+    load_module_from_reader(BufReader::new(File::open(file)?), resolver)
+}
 
-    // Finally, if there is a start function specified then execute it.
-    if let Some(start) = module.start {
-        let mut stack = Stack::new();
-        function_module.execute_function(start, &mut stack, &mut data_module)?;
-    }
+/// Load a module from a stream, such as a socket. The module is read in one pass from
+/// start to finish, so the stream doesn't need to be able to seek, and sections which are
+/// skipped are read and thrown away rather than held in memory.
+pub fn load_module_from_reader(
+    mut reader: impl Read,
+    resolver: &impl core::Resolver,
+) -> Result<LoadedModule> {
+    let raw_module = core::RawModule::read(&mut reader)?;
+    resolve_raw_module(raw_module, resolver)
+}
 
-    Ok((function_module, data_module, exports))
+pub fn load_module_from_bytes(
+    bytes: &[u8],
+    resolver: &impl core::Resolver,
+) -> Result<LoadedModule> {
+    load_module_from_reader(bytes, resolver)
 }
 
-pub fn load_module_from_path(file: &str, resolver: &impl core::Resolver) -> Result<LoadedModule> {
-    let mut buf = BufReader::new(File::open(file)?);
-    let raw_module = core::RawModule::read(&mut buf)?;
+/// Load a module by memory mapping the file. Function bodies are compiled from the
+/// mapping the first time they are called, so they are never copied into memory.
+///
+/// # Safety
+///
+/// The file must not be written to or truncated, by this process or any other, while the
+/// module or any instance made from it is alive. The mapping is read as ordinary memory,
+/// so a change to the file is undefined behavior rather than an error.
+#[cfg(feature = "mmap")]
+pub unsafe fn load_module_from_mmap(
+    file: &str,
+    resolver: &impl core::Resolver,
+) -> Result<LoadedModule> {
+    // SAFETY: the caller promises that the file doesn't change while the mapping is in use
+    let map = unsafe { memmap2::Mmap::map(&File::open(file)?)? };
+    let raw_module = core::RawModule::read_in_place(Shared::new(map), &ParserConfig::default())?;
     resolve_raw_module(raw_module, resolver)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        test_module::TestModule, ElemType, Element, EmptyResolver, ExportDesc, Expr, GlobalType,
+        HostFuncCallable, HostObjectResolver, ImportDesc, Instance, Limits, Locals, MutableType,
+        TableType, Value, ValueType,
+    };
+
+    #[test]
+    fn test_index_space() {
+        let mut space = IndexSpace::new();
+        assert_eq!(space.import('a').unwrap(), 0);
+        assert_eq!(space.import('b').unwrap(), 1);
+        assert_eq!(space.define('c'), 2);
+        assert!(space.import('d').is_err());
+
+        assert_eq!(&*space, &['a', 'b', 'c']);
+        assert_eq!(space.imported_count(), 2);
+        assert!(space.is_imported(1));
+        assert!(!space.is_imported(2));
+        assert_eq!(space.definitions(), &['c']);
+    }
+
+    fn parse_error(bytes: &[u8]) -> ParseError {
+        match RawModule::read(&mut &bytes[..])
+            .unwrap_err()
+            .downcast_ref::<WasmError>()
+        {
+            Some(WasmError::ParseError(error)) => error.clone(),
+            other => panic!("Expected a parse error but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_offsets() {
+        let mut module = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+        let error = parse_error(&module[..5]);
+        assert_eq!(error.section, None);
+        assert_eq!(error.offset, 5);
+        assert!(error.message.contains("truncated"));
+
+        // A type section holding one type, whose form byte at offset 11 is wrong
+        module.extend_from_slice(&[0x01, 0x04, 0x01, 0x50, 0x00, 0x00]);
+        let error = parse_error(&module);
+        assert_eq!(error.section, Some(core::SectionType::TypeSection));
+        assert_eq!(error.offset, 12);
+        assert!(format!("{}", error).ends_with("(at offset 0xc in the TypeSection)"));
+
+        // A section which says it is longer than what is in it
+        module[11] = 0x60;
+        module[9] = 0x05;
+        module.push(0x00);
+        let error = parse_error(&module);
+        assert_eq!(error.section, Some(core::SectionType::TypeSection));
+        assert_eq!(error.message, "Failed to read whole section");
+
+        // A function whose body has a byte which isn't an opcode, inside a block
+        let mut module = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        module.extend_from_slice(&[0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+        module.extend_from_slice(&[0x03, 0x02, 0x01, 0x00]);
+        module.extend_from_slice(&[0x0A, 0x08, 0x01, 0x06, 0x00, 0x02, 0x40, 0x06, 0x0B, 0x0B]);
+        let error = parse_error(&module);
+        assert_eq!(error.section, Some(core::SectionType::CodeSection));
+        assert!(error.message.contains("In function 0"));
+        assert!(error
+            .message
+            .contains("Invalid opcode byte 0x06 at offset 2"));
+
+        // A function body with bytes after its final end, which is reported at the first
+        // of them
+        module.truncate(18);
+        module.extend_from_slice(&[0x0A, 0x06, 0x01, 0x04, 0x00, 0x0B, 0x01, 0x01]);
+        let error = parse_error(&module);
+        assert_eq!(error.section, Some(core::SectionType::CodeSection));
+        assert_eq!(error.offset, 24);
+        assert!(error.message.contains("In function 0"));
+        assert!(error
+            .message
+            .contains("Function body has 2 bytes after its end"));
+    }
+
+    #[test]
+    fn test_function_numbering_in_errors() {
+        // A module which imports env:f, and defines one function with the given body
+        let module = |body: &[u8]| {
+            let mut module = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+            module.extend_from_slice(&[0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+            module.extend_from_slice(&[0x02, 0x09, 0x01, 0x03, b'e', b'n', b'v', 0x01, b'f']);
+            module.extend_from_slice(&[0x00, 0x00]);
+            module.extend_from_slice(&[0x03, 0x02, 0x01, 0x00]);
+            module.extend_from_slice(&[0x0A, body.len() as u8 + 2, 0x01, body.len() as u8]);
+            module.extend_from_slice(body);
+            module
+        };
+
+        // Both when it is parsed and when it is loaded, the defined function is function
+        // 1, because the imported function comes first
+        let error = parse_error(&module(&[0x00, 0x06, 0x0B]));
+        assert!(
+            error.message.starts_with("In function 1: "),
+            "{}",
+            error.message
+        );
+
+        let raw_module =
+            RawModule::read(&mut &module(&[0x00, 0x23, 0x05, 0x1A, 0x0B])[..]).unwrap();
+        let resolver = core::StubResolver::new(EmptyResolver::instance());
+        let error = resolve_raw_module(raw_module, &resolver).err().unwrap();
+        assert!(
+            format!("{:#}", error).starts_with("In function 1: "),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_section_order() {
+        let header = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        let custom = [0x00, 0x03, 0x02, b'h', b'i'];
+        let empty_types = [0x01, 0x01, 0x00];
+        let empty_tables = [0x04, 0x01, 0x00];
+        let empty_memories = [0x05, 0x01, 0x00];
+        let message = |sections: &[&[u8]]| {
+            let mut module = header.to_vec();
+            for section in sections {
+                module.extend_from_slice(section);
+            }
+            parse_error(&module).message
+        };
+
+        assert_eq!(
+            message(&[&empty_types, &empty_types]),
+            "Duplicate TypeSection"
+        );
+        assert_eq!(
+            message(&[&empty_types, &custom, &empty_types]),
+            "Duplicate TypeSection"
+        );
+        assert_eq!(
+            message(&[&empty_memories, &empty_tables]),
+            "Invalid section order: TableSection after MemorySection"
+        );
+        assert_eq!(
+            message(&[&empty_tables, &custom, &empty_types]),
+            "Invalid section order: TypeSection after TableSection"
+        );
+        assert_eq!(message(&[&[0x0D, 0x00]]), "Unknown section type");
+
+        // Custom sections can go anywhere, as often as they like
+        let module = TestModule::new()
+            .func(FuncType::new(vec![], vec![]), &[0x0B])
+            .build();
+        let mut bytes = Vec::new();
+        module.write(&mut bytes).unwrap();
+        let functions = find_section(&bytes, core::SectionType::FunctionSection).unwrap();
+        bytes.splice(functions.end..functions.end, custom);
+        bytes.splice(MODULE_HEADER_LENGTH..MODULE_HEADER_LENGTH, custom);
+        bytes.extend_from_slice(&custom);
+        bytes.extend_from_slice(&custom);
+        assert_eq!(RawModule::read(&mut &bytes[..]).unwrap().funcs().len(), 1);
+    }
+
+    #[test]
+    fn test_data_count_section() {
+        let write = |data_idx: u8, data_count, segments: usize| {
+            let mut module = TestModule::new()
+                // i32.const 0, i32.const 0, i32.const 2, memory.init, data.drop
+                .func(
+                    FuncType::new(vec![], vec![]),
+                    &[
+                        0x41, 0x00, 0x41, 0x00, 0x41, 0x02, 0xFC, 0x08, data_idx, 0x00, 0xFC, 0x09,
+                        data_idx, 0x0B,
+                    ],
+                )
+                .memory(core::MemType::new(Limits::Unbounded(1)))
+                .export("init", ExportDesc::Func(0));
+            for _ in 0..segments {
+                module = module.data(core::Data::new_passive(b"hi".to_vec()));
+            }
+            let mut module = module.build();
+            module.set_data_count(data_count);
+            let mut bytes = Vec::new();
+            module.write(&mut bytes).unwrap();
+            bytes
+        };
+
+        let bytes = write(0, Some(1), 1);
+        let module = RawModule::read(&mut &bytes[..]).unwrap();
+        assert_eq!(module.data_count(), Some(1));
+        let instance =
+            Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap());
+        instance.invoke("init", &[]).unwrap();
+
+        assert_eq!(
+            parse_error(&write(0, None, 1)).message,
+            "In function 0: MemoryInit needs a data count section"
+        );
+        let error = parse_error(&write(1, Some(1), 1));
+        assert_eq!(error.section, Some(core::SectionType::CodeSection));
+        assert!(error.message.contains("Data segment index 1 out of range"));
+        assert_eq!(
+            parse_error(&write(0, Some(2), 1)).message,
+            "Data count section says there are 2 data segments but there are 1"
+        );
+    }
+
+    #[test]
+    fn test_element_expressions() {
+        let func_type = FuncType::new(vec![], vec![ValueType::I32]);
+        let ref_func = |idx| Expr::new(vec![0xD2, idx, 0x0B]);
+        let ref_null = || Expr::new(vec![0xD0, 0x70, 0x0B]);
+        let module = |segment_type| {
+            TestModule::new()
+                // i32.const 7
+                .func(func_type.clone(), &[0x41, 0x07, 0x0B])
+                // i32.const 1, call_indirect 0 0
+                .func(func_type.clone(), &[0x41, 0x01, 0x11, 0x00, 0x00, 0x0B])
+                // table.init 1 0 copying one entry to 2, then call_indirect 0 0 on it
+                .func(
+                    func_type.clone(),
+                    &[
+                        0x41, 0x02, 0x41, 0x00, 0x41, 0x01, 0xFC, 0x0C, 0x01, 0x00, 0x41, 0x02,
+                        0x11, 0x00, 0x00, 0x0B,
+                    ],
+                )
+                .table(TableType::new(ElemType::FuncRef, Limits::Unbounded(3)))
+                .elem(Element::with_items(
+                    core::ElementMode::Active(0, Expr::new(vec![0x41, 0x00, 0x0B])),
+                    core::ElementItems::Expressions(
+                        ElemType::FuncRef,
+                        vec![ref_null(), ref_func(0)],
+                    ),
+                ))
+                .elem(Element::with_items(
+                    core::ElementMode::Passive,
+                    core::ElementItems::Expressions(segment_type, vec![ref_func(0)]),
+                ))
+                .elem(Element::with_items(
+                    core::ElementMode::Declarative,
+                    core::ElementItems::Expressions(ElemType::FuncRef, vec![ref_func(1)]),
+                ))
+                .export("indirect", ExportDesc::Func(1))
+                .export("init", ExportDesc::Func(2))
+                .build()
+        };
+
+        let loaded =
+            resolve_raw_module(module(ElemType::FuncRef), EmptyResolver::instance()).unwrap();
+        {
+            let table = loaded.0.tables[0].borrow();
+            assert!(table[0].is_null());
+            assert!(Shared::ptr_eq(
+                table[1].func_ref().unwrap(),
+                &loaded.0.functions[0]
+            ));
+        }
+        let instance = Instance::new(loaded);
+        assert_eq!(
+            instance.invoke("indirect", &[]).unwrap(),
+            vec![Value::I32(7)]
+        );
+        assert_eq!(instance.invoke("init", &[]).unwrap(), vec![Value::I32(7)]);
+
+        // Every entry has to give the type of reference the segment holds
+        let error =
+            resolve_raw_module(module(ElemType::ExternRef), EmptyResolver::instance()).unwrap_err();
+        assert!(format!("{:#}", error)
+            .contains("Element expression gives FuncRef in a segment of ExternRef"));
+    }
+
+    #[test]
+    fn test_global_initializers() {
+        let mut resolver = HostObjectResolver::new(EmptyResolver::instance());
+        resolver.define_global(
+            "env",
+            "constant",
+            Global::from_value(Value::I32(10), MutableType::Const),
+        );
+        resolver.define_global(
+            "env",
+            "variable",
+            Global::from_value(Value::I32(20), MutableType::Var),
+        );
+
+        // Each module imports the constant as global 0 and the variable as global 1, and
+        // then defines globals which read the given global
+        let load = |reads: &[u8]| {
+            let import_type = |mutable_type| {
+                ImportDesc::GlobalType(GlobalType::new(ValueType::I32, mutable_type))
+            };
+            let mut module = TestModule::new()
+                .import("env", "constant", import_type(MutableType::Const))
+                .import("env", "variable", import_type(MutableType::Var))
+                .func(FuncType::new(vec![], vec![]), &[0x0B]);
+            for idx in reads {
+                module = module.global(
+                    GlobalType::new(ValueType::I32, MutableType::Const),
+                    &[0x23, *idx, 0x0B],
+                );
+            }
+            let module = module.build();
+            resolve_raw_module(module, &resolver).map_err(|e| format!("{:#}", e))
+        };
+
+        let (_, data_module, _) = load(&[0, 0]).unwrap();
+        assert_eq!(
+            *data_module.globals[3].borrow().get_value(),
+            StackEntry::I32Entry(10)
+        );
+
+        // Not the imported variable, nor any global the module defines, whether it comes
+        // before or after the one being initialized
+        let error = load(&[1]).unwrap_err();
+        assert!(error.contains("In the initializer of global 2"));
+        assert!(error.contains("not global 1"));
+        assert!(load(&[0, 2]).unwrap_err().contains("not global 2"));
+        assert!(load(&[3, 0]).unwrap_err().contains("not global 3"));
+    }
+
+    #[test]
+    fn test_global_set_validation() {
+        // Global 0 is a constant and global 1 a variable, and the function sets the given
+        // global to the operand. Local 0 is an f32 parameter, locals 1 and 2 are i64s and
+        // local 3 is an i32.
+        let load = |operand: &[u8], global_idx: u8| {
+            let global_type = |mutable_type| GlobalType::new(ValueType::I32, mutable_type);
+            let module = TestModule::new()
+                .func_with_locals(
+                    FuncType::new(vec![ValueType::F32], vec![]),
+                    vec![
+                        Locals::new(2, ValueType::I64),
+                        Locals::new(1, ValueType::I32),
+                    ],
+                    &[operand, &[0x24, global_idx, 0x0B]].concat(),
+                )
+                .global(global_type(MutableType::Const), &[0x41, 0x00, 0x0B])
+                .global(global_type(MutableType::Var), &[0x41, 0x00, 0x0B])
+                .build();
+            resolve_raw_module(module, EmptyResolver::instance()).map_err(|e| format!("{:#}", e))
+        };
+
+        let i32_const = [0x41, 0x01];
+        let (_, mut data_module, _) = load(&i32_const, 1).unwrap();
+
+        let error = load(&i32_const, 0).unwrap_err();
+        assert!(error.contains("In function 0"));
+        assert!(error.contains("GlobalSet of global 0 of type I32, which is immutable"));
+        assert!(load(&i32_const, 2)
+            .unwrap_err()
+            .contains("GlobalSet uses global 2 but there are only 2 globals"));
+
+        // The operand has to be of the global's type, whether it is a constant or another
+        // global
+        assert!(load(&[0x23, 0x00], 1).is_ok());
+        let error = load(&[0x42, 0x01], 1).unwrap_err();
+        assert!(error.contains("In function 0"));
+        assert!(
+            error.contains("GlobalSet of global 1 of type I32 given an operand of type I64"),
+            "{}",
+            error
+        );
+        assert!(load(&[0x43, 0x00, 0x00, 0x80, 0x3F], 1)
+            .unwrap_err()
+            .contains("given an operand of type F32"));
+
+        // Or a local, either got or teed
+        assert!(load(&[0x20, 0x03], 1).is_ok());
+        assert!(load(&[0x41, 0x01, 0x22, 0x03], 1).is_ok());
+        assert!(load(&[0x20, 0x00], 1)
+            .unwrap_err()
+            .contains("GlobalSet of global 1 of type I32 given an operand of type F32"));
+        assert!(load(&[0x42, 0x01, 0x22, 0x02], 1)
+            .unwrap_err()
+            .contains("given an operand of type I64"));
+
+        // A value of the wrong type is refused when it is set, as well
+        let error = data_module
+            .set_global_value(1, StackEntry::I64Entry(1))
+            .map_err(|e| format!("{:#}", e))
+            .unwrap_err();
+        assert!(error.contains("In GlobalSet of global 1"));
+        assert!(error.contains("Global of type I32 given I64"));
+    }
+
+    #[test]
+    fn test_imports_come_before_definitions() {
+        let returns_i32 = FuncType::new(vec![], vec![ValueType::I32]);
+        let global_type = |mutable_type| GlobalType::new(ValueType::I32, mutable_type);
+
+        // The definitions are listed before the imports, but every index below assumes
+        // that the imported function and global come first
+        let module = TestModule::new()
+            // call 0, global.get 1, i32.add
+            .func(returns_i32.clone(), &[0x10, 0x00, 0x23, 0x01, 0x6A, 0x0B])
+            // global.get 0
+            .func(returns_i32.clone(), &[0x23, 0x00, 0x0B])
+            // i32.const 1, call_indirect 0 0
+            .func(returns_i32.clone(), &[0x41, 0x01, 0x11, 0x00, 0x00, 0x0B])
+            // call 0, global.set 2
+            .func(
+                FuncType::new(vec![], vec![]),
+                &[0x10, 0x00, 0x24, 0x02, 0x0B],
+            )
+            .table(TableType::new(ElemType::FuncRef, Limits::Unbounded(2)))
+            .global(global_type(MutableType::Const), &[0x41, 20, 0x0B])
+            .global(global_type(MutableType::Var), &[0x41, 0x00, 0x0B])
+            .elem(Element::new(
+                0,
+                Expr::new(vec![0x41, 0x00, 0x0B]),
+                vec![0, 1],
+            ))
+            .start(4)
+            .import_func("env", "five", returns_i32)
+            .import(
+                "env",
+                "base",
+                ImportDesc::GlobalType(global_type(MutableType::Const)),
+            )
+            .export("five", ExportDesc::Func(0))
+            .export("sum", ExportDesc::Func(1))
+            .export("base", ExportDesc::Func(2))
+            .export("indirect", ExportDesc::Func(3))
+            .export("started", ExportDesc::Global(2))
+            .build();
+
+        let mut resolver = HostObjectResolver::new(EmptyResolver::instance());
+        let five = resolver.define_function(
+            "env",
+            "five",
+            HostFuncCallable::new(FuncType::new(vec![], vec![ValueType::I32]), |_| {
+                Ok(vec![Value::I32(5)])
+            })
+            .into(),
+        );
+        resolver.define_global(
+            "env",
+            "base",
+            Global::from_value(Value::I32(100), MutableType::Const),
+        );
+
+        let loaded = resolve_raw_module(module, &resolver).unwrap();
+        let (function_module, data_module, _) = &loaded;
+        assert_eq!(function_module.functions.imported_count(), 1);
+        assert_eq!(function_module.functions.definitions().len(), 4);
+        assert!(Shared::ptr_eq(&function_module.functions[0], &five));
+        assert_eq!(data_module.globals.imported_count(), 1);
+        assert_eq!(data_module.globals.definitions().len(), 2);
+
+        // The table holds the imported function and then the first defined one
+        let table = function_module.tables[0].borrow();
+        assert!(Shared::ptr_eq(&table.get_entry(0).unwrap(), &five));
+        assert!(Shared::ptr_eq(
+            &table.get_entry(1).unwrap(),
+            &function_module.functions[1]
+        ));
+        drop(table);
+
+        // The start function stored the imported function's result in the last global
+        assert_eq!(
+            *data_module.globals[2].borrow().get_value(),
+            StackEntry::I32Entry(5)
+        );
+
+        let instance = Instance::new(loaded);
+        assert_eq!(instance.invoke("five", &[]).unwrap(), vec![Value::I32(5)]);
+        assert_eq!(instance.invoke("sum", &[]).unwrap(), vec![Value::I32(25)]);
+        assert_eq!(instance.invoke("base", &[]).unwrap(), vec![Value::I32(100)]);
+        assert_eq!(
+            instance.invoke("indirect", &[]).unwrap(),
+            vec![Value::I32(25)]
+        );
+    }
+}
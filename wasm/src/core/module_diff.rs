@@ -0,0 +1,506 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::core::{Data, DataMode, ExportDesc, Expr, Func, FuncType, ImportDesc, RawModule};
+use crate::parser::{disassemble, InstructionSource};
+use crate::reader::{import_signature, ScanLocation, TypeReader};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Changed => "changed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One difference between two versions of a module. Removed things are located by their
+/// index in the old module, and everything else by its index in the new one.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModuleChange {
+    pub kind: ChangeKind,
+    pub location: ScanLocation,
+    pub message: String,
+}
+
+impl fmt::Display for ModuleChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.kind, self.message)
+    }
+}
+
+fn describe_func_type(func_type: Option<&FuncType>) -> String {
+    match func_type {
+        Some(func_type) => format!(
+            "{:?} -> {:?}",
+            func_type.arg_types(),
+            func_type.return_types()
+        ),
+        None => "an unknown type".to_string(),
+    }
+}
+
+fn describe_export(desc: &ExportDesc) -> String {
+    match desc {
+        ExportDesc::Func(idx) => format!("func {}", idx),
+        ExportDesc::Table(idx) => format!("table {}", idx),
+        ExportDesc::Mem(idx) => format!("memory {}", idx),
+        ExportDesc::Global(idx) => format!("global {}", idx),
+    }
+}
+
+fn describe_locals(func: &Func) -> Vec<String> {
+    func.locals()
+        .iter()
+        .map(|locals| format!("{} x {:?}", locals.count(), locals.value_type()))
+        .collect()
+}
+
+fn describe_data_mode(mode: &DataMode) -> String {
+    match mode {
+        DataMode::Active(memory, offset) => match disassemble(offset) {
+            Ok(lines) => format!("memory {} at {}", memory, lines.join(", ")),
+            Err(_) => format!("memory {} at an offset that cannot be read", memory),
+        },
+        DataMode::Passive => "passive".to_string(),
+    }
+}
+
+fn describe_data(data: &Data) -> String {
+    let length = data.bytes().len();
+    format!(
+        "{} byte{}, {}",
+        length,
+        if length == 1 { "" } else { "s" },
+        describe_data_mode(data.mode())
+    )
+}
+
+// The first instruction that differs, when the instructions can be decoded, since that is
+// what someone auditing the change will want to look at
+fn describe_body_change(old: &Expr, new: &Expr) -> Option<String> {
+    if old.get_instruction_bytes() == new.get_instruction_bytes() {
+        return None;
+    }
+
+    let (old_lines, new_lines) = match (disassemble(old), disassemble(new)) {
+        (Ok(old_lines), Ok(new_lines)) => (old_lines, new_lines),
+        _ => return Some("the body changed and cannot be disassembled".to_string()),
+    };
+
+    let first_difference = old_lines
+        .iter()
+        .zip(new_lines.iter())
+        .position(|(old_line, new_line)| old_line != new_line)
+        .unwrap_or_else(|| old_lines.len().min(new_lines.len()));
+    let describe_line = |line: Option<&String>| match line {
+        Some(line) => format!("`{}`", line.trim()),
+        None => "the end".to_string(),
+    };
+
+    Some(format!(
+        "the body changed from {} to {} instructions, first at instruction {} where {} became {}",
+        old_lines.len(),
+        new_lines.len(),
+        first_difference,
+        describe_line(old_lines.get(first_difference)),
+        describe_line(new_lines.get(first_difference))
+    ))
+}
+
+struct ModuleDiffer<'a> {
+    old: &'a RawModule,
+    new: &'a RawModule,
+    changes: Vec<ModuleChange>,
+}
+
+impl ModuleDiffer<'_> {
+    fn record(&mut self, kind: ChangeKind, location: ScanLocation, message: String) {
+        self.changes.push(ModuleChange {
+            kind,
+            location,
+            message,
+        });
+    }
+
+    fn imported_function_count(module: &RawModule) -> usize {
+        module
+            .imports()
+            .iter()
+            .filter(|import| matches!(import.desc(), ImportDesc::TypeIdx(_)))
+            .count()
+    }
+
+    fn diff_types(&mut self) {
+        let (old, new) = (self.old.types(), self.new.types());
+        for idx in 0..old.len().max(new.len()) {
+            match (old.get(idx), new.get(idx)) {
+                (Some(old_type), Some(new_type)) if old_type != new_type => self.record(
+                    ChangeKind::Changed,
+                    ScanLocation::Type(idx),
+                    format!(
+                        "{} became {}",
+                        describe_func_type(Some(old_type)),
+                        describe_func_type(Some(new_type))
+                    ),
+                ),
+                (Some(old_type), None) => self.record(
+                    ChangeKind::Removed,
+                    ScanLocation::Type(idx),
+                    describe_func_type(Some(old_type)),
+                ),
+                (None, Some(new_type)) => self.record(
+                    ChangeKind::Added,
+                    ScanLocation::Type(idx),
+                    describe_func_type(Some(new_type)),
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    // Imports are matched by name rather than position, so that adding one doesn't make
+    // every import after it look changed
+    fn diff_imports(&mut self) {
+        let describe = |module: &RawModule| -> Vec<(String, String)> {
+            module
+                .imports()
+                .iter()
+                .map(|import| {
+                    (
+                        format!("{}.{}", import.mod_name(), import.name()),
+                        import_signature(module, import.desc()),
+                    )
+                })
+                .collect()
+        };
+        let (old, new) = (describe(self.old), describe(self.new));
+        let mut new_matched = vec![false; new.len()];
+        let mut old_matched = vec![false; old.len()];
+
+        // Identical imports first, so that a duplicated name is paired with its own type
+        for (old_idx, old_import) in old.iter().enumerate() {
+            if let Some(new_idx) = (0..new.len())
+                .find(|new_idx| !new_matched[*new_idx] && new[*new_idx] == *old_import)
+            {
+                new_matched[new_idx] = true;
+                old_matched[old_idx] = true;
+            }
+        }
+
+        for (old_idx, (old_name, old_signature)) in old.iter().enumerate() {
+            if old_matched[old_idx] {
+                continue;
+            }
+            match (0..new.len())
+                .find(|new_idx| !new_matched[*new_idx] && new[*new_idx].0 == *old_name)
+            {
+                Some(new_idx) => {
+                    new_matched[new_idx] = true;
+                    let message = format!(
+                        "{} was {} and is now {}",
+                        old_name, old_signature, new[new_idx].1
+                    );
+                    self.record(ChangeKind::Changed, ScanLocation::Import(new_idx), message);
+                }
+                None => {
+                    let message = format!("{} {}", old_name, old_signature);
+                    self.record(ChangeKind::Removed, ScanLocation::Import(old_idx), message);
+                }
+            }
+        }
+
+        for (new_idx, (new_name, new_signature)) in new.iter().enumerate() {
+            if !new_matched[new_idx] {
+                let message = format!("{} {}", new_name, new_signature);
+                self.record(ChangeKind::Added, ScanLocation::Import(new_idx), message);
+            }
+        }
+    }
+
+    fn diff_functions(&mut self) {
+        let old_imported = Self::imported_function_count(self.old);
+        let new_imported = Self::imported_function_count(self.new);
+        let func_type = |module: &RawModule, idx: usize| {
+            describe_func_type(
+                module
+                    .typeidx()
+                    .get(idx)
+                    .and_then(|type_idx| module.types().get(*type_idx)),
+            )
+        };
+
+        let (old, new) = (self.old.funcs(), self.new.funcs());
+        for idx in 0..old.len().max(new.len()) {
+            match (old.get(idx), new.get(idx)) {
+                (Some(old_func), Some(new_func)) => {
+                    let location = ScanLocation::Function(new_imported + idx);
+                    let (old_type, new_type) = (func_type(self.old, idx), func_type(self.new, idx));
+                    if old_type != new_type {
+                        let message = format!("the type {} became {}", old_type, new_type);
+                        self.record(ChangeKind::Changed, location.clone(), message);
+                    }
+
+                    let (old_locals, new_locals) =
+                        (describe_locals(old_func), describe_locals(new_func));
+                    if old_locals != new_locals {
+                        let message = format!(
+                            "the locals [{}] became [{}]",
+                            old_locals.join(", "),
+                            new_locals.join(", ")
+                        );
+                        self.record(ChangeKind::Changed, location.clone(), message);
+                    }
+
+                    if let Some(message) = describe_body_change(old_func.expr(), new_func.expr()) {
+                        self.record(ChangeKind::Changed, location, message);
+                    }
+                }
+                (Some(_), None) => self.record(
+                    ChangeKind::Removed,
+                    ScanLocation::Function(old_imported + idx),
+                    func_type(self.old, idx),
+                ),
+                (None, Some(_)) => self.record(
+                    ChangeKind::Added,
+                    ScanLocation::Function(new_imported + idx),
+                    func_type(self.new, idx),
+                ),
+                (None, None) => {}
+            }
+        }
+    }
+
+    fn diff_exports(&mut self) {
+        let describe = |module: &RawModule| -> BTreeMap<String, (usize, String)> {
+            module
+                .exports()
+                .iter()
+                .enumerate()
+                .map(|(idx, export)| (export.nm.clone(), (idx, describe_export(&export.d))))
+                .collect()
+        };
+        let (old, new) = (describe(self.old), describe(self.new));
+
+        for (name, (old_idx, old_desc)) in old.iter() {
+            match new.get(name) {
+                Some((new_idx, new_desc)) if new_desc != old_desc => {
+                    let message = format!("{} was {} and is now {}", name, old_desc, new_desc);
+                    self.record(ChangeKind::Changed, ScanLocation::Export(*new_idx), message);
+                }
+                Some(_) => {}
+                None => {
+                    let message = format!("{} {}", name, old_desc);
+                    self.record(ChangeKind::Removed, ScanLocation::Export(*old_idx), message);
+                }
+            }
+        }
+        for (name, (new_idx, new_desc)) in new.iter() {
+            if !old.contains_key(name) {
+                let message = format!("{} {}", name, new_desc);
+                self.record(ChangeKind::Added, ScanLocation::Export(*new_idx), message);
+            }
+        }
+    }
+
+    fn diff_data(&mut self) {
+        let (old, new) = (self.old.data(), self.new.data());
+        for idx in 0..old.len().max(new.len()) {
+            let location = ScanLocation::Data(idx);
+            match (old.get(idx), new.get(idx)) {
+                (Some(old_data), Some(new_data)) => {
+                    let (old_mode, new_mode) = (
+                        describe_data_mode(old_data.mode()),
+                        describe_data_mode(new_data.mode()),
+                    );
+                    if old_mode != new_mode {
+                        let message =
+                            format!("the segment moved from {} to {}", old_mode, new_mode);
+                        self.record(ChangeKind::Changed, location.clone(), message);
+                    }
+
+                    let (old_bytes, new_bytes) = (old_data.bytes(), new_data.bytes());
+                    if old_bytes != new_bytes {
+                        let first_difference = old_bytes
+                            .iter()
+                            .zip(new_bytes.iter())
+                            .position(|(old_byte, new_byte)| old_byte != new_byte)
+                            .unwrap_or_else(|| old_bytes.len().min(new_bytes.len()));
+                        let message = format!(
+                            "the contents changed from {} to {} bytes, first at byte {}",
+                            old_bytes.len(),
+                            new_bytes.len(),
+                            first_difference
+                        );
+                        self.record(ChangeKind::Changed, location, message);
+                    }
+                }
+                (Some(old_data), None) => {
+                    let message = describe_data(old_data);
+                    self.record(ChangeKind::Removed, location, message);
+                }
+                (None, Some(new_data)) => {
+                    let message = describe_data(new_data);
+                    self.record(ChangeKind::Added, location, message);
+                }
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+/// Compare two versions of a module structure by structure: the types, imports, function
+/// signatures and bodies, exports and data segments. Function bodies are compared
+/// instruction by instruction, so a change is reported where the code differs rather
+/// than where its bytes first do. The modules don't have to be valid.
+pub fn diff_modules(old: &RawModule, new: &RawModule) -> Vec<ModuleChange> {
+    let mut differ = ModuleDiffer {
+        old,
+        new,
+        changes: Vec::new(),
+    };
+
+    differ.diff_types();
+    differ.diff_imports();
+    differ.diff_functions();
+    differ.diff_exports();
+    differ.diff_data();
+    differ.changes
+}
+
+pub fn diff_modules_from_paths(old: &str, new: &str) -> Result<Vec<ModuleChange>> {
+    let old = RawModule::read(&mut BufReader::new(File::open(old)?))?;
+    let new = RawModule::read(&mut BufReader::new(File::open(new)?))?;
+    Ok(diff_modules(&old, &new))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{Export, Import, Limits, Locals, MemType, ValueType};
+
+    fn make_module(
+        types: Vec<FuncType>,
+        funcs: Vec<(usize, Func)>,
+        data: Vec<Data>,
+        imports: Vec<Import>,
+        exports: Vec<Export>,
+    ) -> RawModule {
+        let (typeidx, funcs) = funcs.into_iter().unzip();
+        RawModule::new(
+            types,
+            typeidx,
+            funcs,
+            vec![],
+            vec![MemType::new(Limits::Unbounded(1))],
+            vec![],
+            vec![],
+            data,
+            None,
+            imports,
+            exports,
+        )
+    }
+
+    fn describe(changes: Vec<ModuleChange>) -> Vec<String> {
+        changes.iter().map(ModuleChange::to_string).collect()
+    }
+
+    #[test]
+    fn test_diff_identical_modules() {
+        let make = || {
+            make_module(
+                vec![FuncType::new(vec![], vec![ValueType::I32])],
+                vec![(0, Func::new(vec![], Expr::new(vec![0x41, 0x01, 0x0B])))],
+                vec![Data::new(0, Expr::new(vec![0x41, 0x00, 0x0B]), vec![1, 2])],
+                vec![],
+                vec![Export::new("f".to_string(), ExportDesc::Func(0))],
+            )
+        };
+        assert_eq!(diff_modules(&make(), &make()), vec![]);
+    }
+
+    #[test]
+    fn test_diff_modules() {
+        let import = |name: &str, type_idx| {
+            Import::new(
+                "env".to_string(),
+                name.to_string(),
+                ImportDesc::TypeIdx(type_idx),
+            )
+        };
+        let export = |name: &str, idx| Export::new(name.to_string(), ExportDesc::Func(idx));
+        let func = |locals: Vec<Locals>, body: &[u8]| Func::new(locals, Expr::new(body.to_vec()));
+        let nullary = FuncType::new(vec![], vec![]);
+        let unary = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+
+        let old = make_module(
+            vec![nullary.clone(), unary.clone()],
+            vec![
+                // i32.const 1, i32.const 2, i32.add, drop
+                (0, func(vec![], &[0x41, 0x01, 0x41, 0x02, 0x6A, 0x1A, 0x0B])),
+                (1, func(vec![], &[0x20, 0x00, 0x0B])),
+                (0, func(vec![], &[0x0B])),
+            ],
+            vec![
+                Data::new(0, Expr::new(vec![0x41, 0x00, 0x0B]), vec![1, 2, 3]),
+                Data::new_passive(vec![4]),
+            ],
+            vec![import("log", 1), import("exit", 0)],
+            vec![export("main", 1), export("helper", 3)],
+        );
+        let new = make_module(
+            vec![nullary, unary, FuncType::new(vec![], vec![ValueType::F64])],
+            vec![
+                // i32.const 1, i32.const 3, i32.add, drop
+                (0, func(vec![], &[0x41, 0x01, 0x41, 0x03, 0x6A, 0x1A, 0x0B])),
+                (
+                    1,
+                    func(vec![Locals::new(2, ValueType::I64)], &[0x20, 0x00, 0x0B]),
+                ),
+            ],
+            vec![Data::new(
+                0,
+                Expr::new(vec![0x41, 0x10, 0x0B]),
+                vec![1, 2, 3, 4],
+            )],
+            vec![import("log", 0), import("now", 2)],
+            vec![export("main", 2), export("start", 3)],
+        );
+
+        assert_eq!(
+            describe(diff_modules(&old, &new)),
+            vec![
+                "type[2]: added: [] -> [F64]",
+                "import[0]: changed: env.log was func [I32] -> [I32] and is now func [] -> []",
+                "import[1]: removed: env.exit func [] -> []",
+                "import[1]: added: env.now func [] -> [F64]",
+                "func[2]: changed: the body changed from 4 to 4 instructions, first at \
+                 instruction 1 where `I32Const 2` became `I32Const 3`",
+                "func[3]: changed: the locals [] became [2 x I64]",
+                "func[4]: removed: [] -> []",
+                "export[1]: removed: helper func 3",
+                "export[0]: changed: main was func 1 and is now func 2",
+                "export[1]: added: start func 3",
+                "data[0]: changed: the segment moved from memory 0 at I32Const 0 to memory 0 \
+                 at I32Const 16",
+                "data[0]: changed: the contents changed from 3 to 4 bytes, first at byte 3",
+                "data[1]: removed: 1 byte, passive",
+            ]
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+use crate::core::{compiled::CompiledInstruction, Handle, MaybeSendSync};
+
+/// Watches a call as it executes, for building debuggers and profilers. Every method does
+/// nothing by default, so an observer only implements what it is interested in.
+///
+/// Functions are identified by their index in the instance which defined them. Functions
+/// from the host don't have one unless an instance has put them in a table, so their index
+/// is `None`.
+pub trait ExecutionObserver: MaybeSendSync {
+    /// Called before each instruction is executed. The offset is the position of the
+    /// instruction in the compiled function, where the ends of blocks count as
+    /// instructions, and the stack depth is the number of values the function has on the
+    /// stack, not counting its locals.
+    fn before_instruction(
+        &mut self,
+        _func_idx: Option<usize>,
+        _offset: usize,
+        _instruction: &CompiledInstruction,
+        _stack_depth: usize,
+    ) {
+    }
+
+    /// Called as a function is entered, whether it is defined in wasm or by the host
+    fn on_call(&mut self, _func_idx: Option<usize>) {}
+
+    /// Called as a function returns. A trap abandons the functions being executed without
+    /// returning from them.
+    fn on_return(&mut self, _func_idx: Option<usize>) {}
+
+    /// Called when `memory.grow` succeeds, with the old and new sizes in pages
+    fn on_memory_grow(&mut self, _mem_idx: usize, _old_pages: usize, _new_pages: usize) {}
+}
+
+/// The observer a stack reports to
+#[derive(Clone)]
+pub(crate) struct SharedObserver(Handle<dyn ExecutionObserver>);
+
+impl SharedObserver {
+    pub(crate) fn new(observer: Handle<dyn ExecutionObserver>) -> Self {
+        Self(observer)
+    }
+
+    pub(crate) fn before_instruction(
+        &self,
+        func_idx: Option<usize>,
+        offset: usize,
+        instruction: &CompiledInstruction,
+        stack_depth: usize,
+    ) {
+        self.0
+            .borrow_mut()
+            .before_instruction(func_idx, offset, instruction, stack_depth);
+    }
+
+    pub(crate) fn on_call(&self, func_idx: Option<usize>) {
+        self.0.borrow_mut().on_call(func_idx);
+    }
+
+    pub(crate) fn on_return(&self, func_idx: Option<usize>) {
+        self.0.borrow_mut().on_return(func_idx);
+    }
+
+    pub(crate) fn on_memory_grow(&self, mem_idx: usize, old_pages: usize, new_pages: usize) {
+        self.0
+            .borrow_mut()
+            .on_memory_grow(mem_idx, old_pages, new_pages);
+    }
+}
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ExecutionObserver")
+    }
+}
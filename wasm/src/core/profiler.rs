@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::core::{compiled::CompiledInstruction, ExecutionObserver};
+
+/// What one function cost while it was being profiled
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FunctionProfile {
+    /// The function's index in the instance which defined it, or `None` for host functions
+    /// which no instance has put in a table
+    pub func_idx: Option<usize>,
+    pub calls: u64,
+    pub instructions: u64,
+    /// The time spent in the function itself, not counting the wasm functions it called.
+    /// Time spent in host functions is counted against them rather than their callers.
+    pub time: Duration,
+}
+
+/// The functions executed since profiling started or the profile was last taken, with the
+/// ones which executed the most instructions first
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Profile {
+    pub functions: Vec<FunctionProfile>,
+}
+
+impl Profile {
+    pub fn total_instructions(&self) -> u64 {
+        self.functions
+            .iter()
+            .map(|function| function.instructions)
+            .sum()
+    }
+}
+
+/// Counts the instructions and time of each function. Time is measured between events, and
+/// each gap is charged to the function which was running at the start of it.
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    functions: BTreeMap<Option<usize>, FunctionProfile>,
+    running: Option<(Option<usize>, Instant)>,
+}
+
+impl Profiler {
+    /// Stop charging time to whatever was running. Called between calls into the instance,
+    /// so that neither the time in between, nor a call which trapped, is counted.
+    pub(crate) fn pause(&mut self) {
+        self.running = None;
+    }
+
+    pub(crate) fn take_profile(&mut self) -> Profile {
+        let mut functions: Vec<_> = std::mem::take(&mut self.functions).into_values().collect();
+        functions.sort_by_key(|function| std::cmp::Reverse(function.instructions));
+        Profile { functions }
+    }
+
+    fn function(&mut self, func_idx: Option<usize>) -> &mut FunctionProfile {
+        self.functions
+            .entry(func_idx)
+            .or_insert_with(|| FunctionProfile {
+                func_idx,
+                ..FunctionProfile::default()
+            })
+    }
+
+    // Charge the time since the last event, and start timing `next`
+    fn switch_to(&mut self, next: Option<Option<usize>>) {
+        let now = Instant::now();
+        if let Some((func_idx, since)) = self.running.take() {
+            self.function(func_idx).time += now - since;
+        }
+        self.running = next.map(|func_idx| (func_idx, now));
+    }
+}
+
+impl ExecutionObserver for Profiler {
+    fn before_instruction(
+        &mut self,
+        func_idx: Option<usize>,
+        _offset: usize,
+        _instruction: &CompiledInstruction,
+        _stack_depth: usize,
+    ) {
+        self.switch_to(Some(func_idx));
+        self.function(func_idx).instructions += 1;
+    }
+
+    fn on_call(&mut self, func_idx: Option<usize>) {
+        self.switch_to(Some(func_idx));
+        self.function(func_idx).calls += 1;
+    }
+
+    fn on_return(&mut self, _func_idx: Option<usize>) {
+        self.switch_to(None);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::compiled::CompiledFunction;
+    use crate::core::Expr;
+
+    #[test]
+    fn test_profiler() {
+        let function = CompiledFunction::new(&Expr::new(vec![0x01, 0x0B]), &[]).unwrap();
+        let nop = &function.instructions()[0];
+        let mut profiler = Profiler::default();
+
+        profiler.on_call(Some(0));
+        profiler.before_instruction(Some(0), 0, nop, 0);
+        profiler.on_call(Some(2));
+        profiler.before_instruction(Some(2), 0, nop, 0);
+        profiler.before_instruction(Some(2), 1, nop, 0);
+        profiler.on_return(Some(2));
+        profiler.on_call(None);
+        profiler.on_return(None);
+        profiler.before_instruction(Some(0), 1, nop, 0);
+        std::thread::sleep(Duration::from_millis(2));
+        profiler.on_return(Some(0));
+
+        // Time after a pause isn't charged to anything
+        profiler.on_call(Some(1));
+        profiler.pause();
+        std::thread::sleep(Duration::from_millis(2));
+
+        let profile = profiler.take_profile();
+        let summary: Vec<_> = profile
+            .functions
+            .iter()
+            .map(|function| (function.func_idx, function.calls, function.instructions))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(0), 1, 2),
+                (Some(2), 1, 2),
+                (None, 1, 0),
+                (Some(1), 1, 0)
+            ]
+        );
+        assert_eq!(profile.total_instructions(), 4);
+        assert!(profile.functions[0].time >= Duration::from_millis(2));
+        assert_eq!(profile.functions[3].time, Duration::ZERO);
+
+        assert_eq!(profiler.take_profile(), Profile::default());
+    }
+}
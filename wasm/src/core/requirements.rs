@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Result};
+
+use crate::core::{FuncType, ImportDesc, Limits, RawModule};
+
+/// A memory or table which the host has to supply. Sizes are in pages for memories and
+/// elements for tables.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ImportedResource {
+    pub mod_name: String,
+    pub name: String,
+    pub minimum: usize,
+    pub maximum: Option<usize>,
+}
+
+/// What a module needs from its host before it can be instantiated, worked out without
+/// resolving any imports. Orchestration layers can use this to pick a host with enough
+/// capacity rather than finding out when instantiation fails.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResourceRequirements {
+    pub memories: Vec<ImportedResource>,
+    pub tables: Vec<ImportedResource>,
+    /// How many host functions of each signature are imported, in order of first use
+    pub host_functions: Vec<(FuncType, usize)>,
+    /// Pages allocated up front for the memories the module defines itself
+    pub defined_memory_pages: usize,
+    /// Elements allocated up front for the tables the module defines itself
+    pub defined_table_elements: usize,
+}
+
+impl ResourceRequirements {
+    /// The least memory, in pages, that instantiating the module will use
+    pub fn total_memory_pages(&self) -> usize {
+        self.defined_memory_pages
+            + self
+                .memories
+                .iter()
+                .map(|memory| memory.minimum)
+                .sum::<usize>()
+    }
+}
+
+fn limits_range(limits: &Limits) -> (usize, Option<usize>) {
+    match limits {
+        Limits::Unbounded(minimum) => (*minimum, None),
+        Limits::Bounded(minimum, maximum) => (*minimum, Some(*maximum)),
+    }
+}
+
+fn imported_resource(mod_name: &str, name: &str, limits: &Limits) -> ImportedResource {
+    let (minimum, maximum) = limits_range(limits);
+    ImportedResource {
+        mod_name: mod_name.to_string(),
+        name: name.to_string(),
+        minimum,
+        maximum,
+    }
+}
+
+pub fn required_resources(module: &RawModule) -> Result<ResourceRequirements> {
+    let mut requirements = ResourceRequirements::default();
+
+    for import in module.imports() {
+        match import.desc() {
+            ImportDesc::TypeIdx(type_idx) => {
+                let func_type = module.types().get(*type_idx).ok_or_else(|| {
+                    anyhow!(
+                        "Import {}.{} has invalid type index {}",
+                        import.mod_name(),
+                        import.name(),
+                        type_idx
+                    )
+                })?;
+
+                match requirements
+                    .host_functions
+                    .iter_mut()
+                    .find(|(existing, _)| existing == func_type)
+                {
+                    Some((_, count)) => *count += 1,
+                    None => requirements.host_functions.push((func_type.clone(), 1)),
+                }
+            }
+            ImportDesc::TableType(table_type) => requirements.tables.push(imported_resource(
+                import.mod_name(),
+                import.name(),
+                table_type.limits(),
+            )),
+            ImportDesc::MemType(mem_type) => requirements.memories.push(imported_resource(
+                import.mod_name(),
+                import.name(),
+                mem_type.limits(),
+            )),
+            ImportDesc::GlobalType(_) => {}
+        }
+    }
+
+    requirements.defined_memory_pages = module
+        .mems()
+        .iter()
+        .map(|mem| limits_range(mem.limits()).0)
+        .sum();
+    requirements.defined_table_elements = module
+        .tables()
+        .iter()
+        .map(|table| limits_range(table.limits()).0)
+        .sum();
+
+    Ok(requirements)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{test_module::TestModule, ElemType, MemType, TableType, ValueType};
+
+    #[test]
+    fn test_required_resources() {
+        let unary = FuncType::new(vec![ValueType::I32], vec![ValueType::I32]);
+        let nullary = FuncType::new(vec![], vec![]);
+        let module = TestModule::new()
+            .import_func("env", "double", unary.clone())
+            .import(
+                "env",
+                "memory",
+                ImportDesc::MemType(MemType::new(Limits::Unbounded(16))),
+            )
+            .import_func("env", "tick", nullary.clone())
+            .import(
+                "env",
+                "table",
+                ImportDesc::TableType(TableType::new(ElemType::FuncRef, Limits::Bounded(1, 10))),
+            )
+            .import_func("env", "negate", unary.clone())
+            .table(TableType::new(ElemType::FuncRef, Limits::Unbounded(8)))
+            .memory(MemType::new(Limits::Bounded(2, 4)))
+            .build();
+
+        let requirements = required_resources(&module).unwrap();
+        assert_eq!(
+            requirements.memories,
+            vec![ImportedResource {
+                mod_name: "env".to_string(),
+                name: "memory".to_string(),
+                minimum: 16,
+                maximum: None,
+            }]
+        );
+        assert_eq!(
+            requirements.tables,
+            vec![ImportedResource {
+                mod_name: "env".to_string(),
+                name: "table".to_string(),
+                minimum: 1,
+                maximum: Some(10),
+            }]
+        );
+        assert_eq!(requirements.host_functions, vec![(unary, 2), (nullary, 1)]);
+        assert_eq!(requirements.defined_memory_pages, 2);
+        assert_eq!(requirements.defined_table_elements, 8);
+        assert_eq!(requirements.total_memory_pages(), 18);
+    }
+}
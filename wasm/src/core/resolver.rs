@@ -1,8 +1,11 @@
-use anyhow::{anyhow, Result};
-use std::cell::RefCell;
-use std::rc::Rc;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
 
-use crate::core::{Callable, FuncType, Global, GlobalType, MemType, Memory, Table, TableType};
+use crate::core::{
+    handle, stack_entry::StackEntry, Callable, FuncType, Global, GlobalType, Handle, Lock, MemType,
+    Memory, StubCallable, Table, TableElement, TableType,
+};
 
 pub trait Resolver {
     fn resolve_function(
@@ -10,27 +13,65 @@ pub trait Resolver {
         mod_name: &str,
         name: &str,
         func_type: &FuncType,
-    ) -> Result<Rc<RefCell<Callable>>>;
+    ) -> Result<Handle<Callable>>;
     fn resolve_table(
         &self,
         mod_name: &str,
         name: &str,
         table_type: &TableType,
-    ) -> Result<Rc<RefCell<Table>>>;
+    ) -> Result<Handle<Table>>;
     fn resolve_memory(
         &self,
         mod_name: &str,
         name: &str,
         mem_type: &MemType,
-    ) -> Result<Rc<RefCell<Memory>>>;
+    ) -> Result<Handle<Memory>>;
     fn resolve_global(
         &self,
         mod_name: &str,
         name: &str,
         global_type: &GlobalType,
-    ) -> Result<Rc<RefCell<Global>>>;
+    ) -> Result<Handle<Global>>;
 }
 
+/// The error a resolver gives when it has nothing at all for an import. Any other error,
+/// such as one for an import of the wrong type, means the import was found but is unusable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportNotFound {
+    kind: &'static str,
+    mod_name: String,
+    name: String,
+}
+
+impl ImportNotFound {
+    /// The kind is what was being imported, such as "function" or "memory"
+    pub fn new(kind: &'static str, mod_name: &str, name: &str) -> Self {
+        Self {
+            kind,
+            mod_name: mod_name.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    /// Whether the error, or anything it was given as context for, says an import wasn't
+    /// found
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<ImportNotFound>().is_some()
+    }
+}
+
+impl fmt::Display for ImportNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Imported {} {}:{} not found",
+            self.kind, self.mod_name, self.name
+        )
+    }
+}
+
+impl std::error::Error for ImportNotFound {}
+
 pub struct EmptyResolver {}
 
 impl Resolver for EmptyResolver {
@@ -39,32 +80,32 @@ impl Resolver for EmptyResolver {
         mod_name: &str,
         name: &str,
         _func_type: &FuncType,
-    ) -> Result<Rc<RefCell<Callable>>> {
-        Err(anyhow!("Imported function {}:{} not found", mod_name, name))
+    ) -> Result<Handle<Callable>> {
+        Err(ImportNotFound::new("function", mod_name, name).into())
     }
     fn resolve_table(
         &self,
         mod_name: &str,
         name: &str,
         _table_type: &TableType,
-    ) -> Result<Rc<RefCell<Table>>> {
-        Err(anyhow!("Imported table {}:{} not found", mod_name, name))
+    ) -> Result<Handle<Table>> {
+        Err(ImportNotFound::new("table", mod_name, name).into())
     }
     fn resolve_memory(
         &self,
         mod_name: &str,
         name: &str,
         _mem_type: &MemType,
-    ) -> Result<Rc<RefCell<Memory>>> {
-        Err(anyhow!("Imported memory {}:{} not found", mod_name, name))
+    ) -> Result<Handle<Memory>> {
+        Err(ImportNotFound::new("memory", mod_name, name).into())
     }
     fn resolve_global(
         &self,
         mod_name: &str,
         name: &str,
         _global_type: &GlobalType,
-    ) -> Result<Rc<RefCell<Global>>> {
-        Err(anyhow!("Imported global {}:{} not found", mod_name, name))
+    ) -> Result<Handle<Global>> {
+        Err(ImportNotFound::new("global", mod_name, name).into())
     }
 }
 
@@ -75,3 +116,677 @@ impl EmptyResolver {
         &EMPTY_RESOLVER_INSTANCE
     }
 }
+
+/// A resolver which satisfies any import the inner resolver cannot provide with a stand in.
+/// Functions are replaced by stubs which fail when they are called, tables and memories are
+/// created at their minimum size, and globals are initialized to zero. This allows modules
+/// to be instantiated and partially exercised before all of their imports are implemented.
+/// Only imports the inner resolver reports as `ImportNotFound` are stood in for, so an
+/// import which is there but unusable still fails.
+pub struct StubResolver<'a, Inner: Resolver> {
+    inner: &'a Inner,
+    stubbed_imports: Lock<Vec<String>>,
+}
+
+impl<'a, Inner: Resolver> StubResolver<'a, Inner> {
+    pub fn new(inner: &'a Inner) -> Self {
+        Self {
+            inner,
+            stubbed_imports: Lock::new(Vec::new()),
+        }
+    }
+
+    /// The names of the imports that have been stubbed out so far, as "module:name"
+    pub fn stubbed_imports(&self) -> Vec<String> {
+        self.stubbed_imports.borrow().clone()
+    }
+
+    // Record that the import is going to be stubbed out, unless the error is for some
+    // reason other than the import not being there, in which case it is passed on
+    fn stub_if_not_found(&self, error: anyhow::Error, mod_name: &str, name: &str) -> Result<()> {
+        if !ImportNotFound::is_cause_of(&error) {
+            return Err(error);
+        }
+        self.stubbed_imports
+            .borrow_mut()
+            .push(format!("{}:{}", mod_name, name));
+        Ok(())
+    }
+}
+
+impl<'a, Inner: Resolver> Resolver for StubResolver<'a, Inner> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Handle<Callable>> {
+        self.inner
+            .resolve_function(mod_name, name, func_type)
+            .or_else(|error| {
+                self.stub_if_not_found(error, mod_name, name)?;
+                Ok(handle(Callable::from(StubCallable::new(
+                    func_type.clone(),
+                    mod_name,
+                    name,
+                ))))
+            })
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Handle<Table>> {
+        self.inner
+            .resolve_table(mod_name, name, table_type)
+            .or_else(|error| {
+                self.stub_if_not_found(error, mod_name, name)?;
+                Ok(handle(Table::new(table_type.clone())))
+            })
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Handle<Memory>> {
+        self.inner
+            .resolve_memory(mod_name, name, mem_type)
+            .or_else(|error| {
+                self.stub_if_not_found(error, mod_name, name)?;
+                Ok(handle(Memory::new(mem_type.clone())))
+            })
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Handle<Global>> {
+        self.inner
+            .resolve_global(mod_name, name, global_type)
+            .or_else(|error| {
+                self.stub_if_not_found(error, mod_name, name)?;
+                let value = StackEntry::zero_value(global_type.value_type().clone());
+                Ok(handle(Global::new(global_type.clone(), value)?))
+            })
+    }
+}
+
+/// A resolver which grows the memories and tables provided by the inner resolver when they
+/// are smaller than the minimum the importing module asks for. Instantiation fails when an
+/// import is too small, so this saves the embedder from working out how big a memory or
+/// table must be before handing it over. A memory or table whose maximum doesn't allow it to
+/// grow far enough is passed on unchanged, and is rejected as usual.
+pub struct GrowingResolver<'a, Inner: Resolver> {
+    inner: &'a Inner,
+}
+
+impl<'a, Inner: Resolver> GrowingResolver<'a, Inner> {
+    pub fn new(inner: &'a Inner) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, Inner: Resolver> Resolver for GrowingResolver<'a, Inner> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Handle<Callable>> {
+        self.inner.resolve_function(mod_name, name, func_type)
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Handle<Table>> {
+        let table = self.inner.resolve_table(mod_name, name, table_type)?;
+        {
+            let mut table = table.borrow_mut();
+            let minimum = table_type.limits().minimum();
+            if table.current_size() < minimum {
+                let grow_by = minimum - table.current_size();
+                // Failing to grow leaves the table as it was
+                let _ = table.grow_by(grow_by, TableElement::null(table_type.elem_type()));
+            }
+        }
+        Ok(table)
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Handle<Memory>> {
+        let memory = self.inner.resolve_memory(mod_name, name, mem_type)?;
+        {
+            let mut memory = memory.borrow_mut();
+            let minimum = mem_type.limits().minimum();
+            if memory.current_size() < minimum {
+                let grow_by = minimum - memory.current_size();
+                let _ = memory.grow_by(grow_by);
+            }
+        }
+        Ok(memory)
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Handle<Global>> {
+        self.inner.resolve_global(mod_name, name, global_type)
+    }
+}
+
+type ImportName = (String, String);
+
+fn import_name(mod_name: &str, name: &str) -> ImportName {
+    (mod_name.to_string(), name.to_string())
+}
+
+/// A resolver for functions, tables, memories and globals which the host has made itself,
+/// rather than taken from another module. The host keeps a handle to each object, so it
+/// can fill in a memory or table before the module that imports it is instantiated, and
+/// look at it again afterwards. Imports which haven't been defined are passed on to the
+/// inner resolver. Whether an object suits the import is checked when the module is
+/// instantiated, as for any other resolver.
+pub struct HostObjectResolver<'a, Inner: Resolver> {
+    inner: &'a Inner,
+    functions: BTreeMap<ImportName, Handle<Callable>>,
+    tables: BTreeMap<ImportName, Handle<Table>>,
+    memories: BTreeMap<ImportName, Handle<Memory>>,
+    globals: BTreeMap<ImportName, Handle<Global>>,
+}
+
+impl<'a, Inner: Resolver> HostObjectResolver<'a, Inner> {
+    pub fn new(inner: &'a Inner) -> Self {
+        Self {
+            inner,
+            functions: BTreeMap::new(),
+            tables: BTreeMap::new(),
+            memories: BTreeMap::new(),
+            globals: BTreeMap::new(),
+        }
+    }
+
+    pub fn define_function(
+        &mut self,
+        mod_name: &str,
+        name: &str,
+        function: Callable,
+    ) -> Handle<Callable> {
+        let function = handle(function);
+        self.functions
+            .insert(import_name(mod_name, name), function.clone());
+        function
+    }
+
+    pub fn define_table(&mut self, mod_name: &str, name: &str, table: Table) -> Handle<Table> {
+        let table = handle(table);
+        self.tables
+            .insert(import_name(mod_name, name), table.clone());
+        table
+    }
+
+    pub fn define_memory(&mut self, mod_name: &str, name: &str, memory: Memory) -> Handle<Memory> {
+        let memory = handle(memory);
+        self.memories
+            .insert(import_name(mod_name, name), memory.clone());
+        memory
+    }
+
+    pub fn define_global(&mut self, mod_name: &str, name: &str, global: Global) -> Handle<Global> {
+        let global = handle(global);
+        self.globals
+            .insert(import_name(mod_name, name), global.clone());
+        global
+    }
+}
+
+impl<'a, Inner: Resolver> Resolver for HostObjectResolver<'a, Inner> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Handle<Callable>> {
+        match self.functions.get(&import_name(mod_name, name)) {
+            Some(function) => Ok(function.clone()),
+            None => self.inner.resolve_function(mod_name, name, func_type),
+        }
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Handle<Table>> {
+        match self.tables.get(&import_name(mod_name, name)) {
+            Some(table) => Ok(table.clone()),
+            None => self.inner.resolve_table(mod_name, name, table_type),
+        }
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Handle<Memory>> {
+        match self.memories.get(&import_name(mod_name, name)) {
+            Some(memory) => Ok(memory.clone()),
+            None => self.inner.resolve_memory(mod_name, name, mem_type),
+        }
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Handle<Global>> {
+        match self.globals.get(&import_name(mod_name, name)) {
+            Some(global) => Ok(global.clone()),
+            None => self.inner.resolve_global(mod_name, name, global_type),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        resolve_raw_module, stack_entry::StackEntry, test_module::TestModule, DataStore, ElemType,
+        FunctionStore, HostFuncCallable, ImportDesc, Limits, MutableType, RawModule, Shared, Stack,
+        Value, ValueType, WasmError,
+    };
+
+    fn make_module_with_imports() -> RawModule {
+        TestModule::new()
+            .import_func(
+                "env",
+                "log",
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+            )
+            .import(
+                "env",
+                "table",
+                ImportDesc::TableType(TableType::new(ElemType::FuncRef, Limits::Unbounded(2))),
+            )
+            .import(
+                "env",
+                "memory",
+                ImportDesc::MemType(MemType::new(Limits::Unbounded(1))),
+            )
+            .import(
+                "env",
+                "counter",
+                ImportDesc::GlobalType(GlobalType::new(ValueType::I64, MutableType::Var)),
+            )
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+                &[0x20, 0x00, 0x0B],
+            )
+            .build()
+    }
+
+    #[test]
+    fn test_missing_imports_fail_without_stubs() {
+        assert!(resolve_raw_module(make_module_with_imports(), EmptyResolver::instance()).is_err());
+    }
+
+    #[test]
+    fn test_stub_resolver() {
+        let resolver = StubResolver::new(EmptyResolver::instance());
+        let (function_module, mut data_module, _) =
+            resolve_raw_module(make_module_with_imports(), &resolver).unwrap();
+
+        assert_eq!(
+            resolver.stubbed_imports(),
+            vec!["env:log", "env:table", "env:memory", "env:counter"]
+        );
+        assert_eq!(data_module.memories[0].borrow().current_size(), 1);
+        assert_eq!(function_module.tables[0].borrow().current_size(), 2);
+        assert_eq!(
+            *data_module.globals[0].borrow().get_value(),
+            StackEntry::I64Entry(0)
+        );
+
+        // The module's own function still works, but the stub fails with a useful message
+        let mut stack = Stack::new();
+        stack.push(7u32.into());
+        assert!(function_module
+            .execute_function(1, &mut stack, &mut data_module)
+            .is_ok());
+        assert_eq!(stack.working_top(1)[0], 7u32.into());
+
+        let err = function_module
+            .execute_function(0, &mut stack, &mut data_module)
+            .unwrap_err();
+        assert!(format!("{}", err).contains("env:log"));
+    }
+
+    // Provides the same memory for every memory import, and nothing else
+    struct MemoryResolver {
+        memory: Handle<Memory>,
+    }
+
+    impl Resolver for MemoryResolver {
+        fn resolve_function(
+            &self,
+            mod_name: &str,
+            name: &str,
+            func_type: &FuncType,
+        ) -> Result<Handle<Callable>> {
+            EmptyResolver::instance().resolve_function(mod_name, name, func_type)
+        }
+        fn resolve_table(
+            &self,
+            mod_name: &str,
+            name: &str,
+            table_type: &TableType,
+        ) -> Result<Handle<Table>> {
+            EmptyResolver::instance().resolve_table(mod_name, name, table_type)
+        }
+        fn resolve_memory(
+            &self,
+            _mod_name: &str,
+            _name: &str,
+            _mem_type: &MemType,
+        ) -> Result<Handle<Memory>> {
+            Ok(self.memory.clone())
+        }
+        fn resolve_global(
+            &self,
+            mod_name: &str,
+            name: &str,
+            global_type: &GlobalType,
+        ) -> Result<Handle<Global>> {
+            EmptyResolver::instance().resolve_global(mod_name, name, global_type)
+        }
+    }
+
+    fn make_module_importing_memory(limits: Limits) -> RawModule {
+        TestModule::new()
+            .import("env", "memory", ImportDesc::MemType(MemType::new(limits)))
+            .build()
+    }
+
+    #[test]
+    fn test_imported_memory_limits() {
+        let resolver = |minimum_pages, maximum_pages| MemoryResolver {
+            memory: handle(Memory::new_from_bounds(minimum_pages, maximum_pages)),
+        };
+        let is_link_error = |result: Result<_>| {
+            matches!(
+                result.err().unwrap().downcast_ref::<WasmError>(),
+                Some(WasmError::LinkError(_))
+            )
+        };
+
+        assert!(resolve_raw_module(
+            make_module_importing_memory(Limits::Unbounded(1)),
+            &resolver(2, None)
+        )
+        .is_ok());
+
+        // Too small, or able to grow beyond what the module allows
+        assert!(is_link_error(resolve_raw_module(
+            make_module_importing_memory(Limits::Unbounded(2)),
+            &resolver(1, None)
+        )));
+        assert!(is_link_error(resolve_raw_module(
+            make_module_importing_memory(Limits::Bounded(1, 2)),
+            &resolver(1, Some(3))
+        )));
+        assert!(is_link_error(resolve_raw_module(
+            make_module_importing_memory(Limits::Bounded(1, 2)),
+            &resolver(1, None)
+        )));
+    }
+
+    // Provides the same global for every global import, and nothing else
+    struct GlobalResolver {
+        global: Handle<Global>,
+    }
+
+    impl Resolver for GlobalResolver {
+        fn resolve_function(
+            &self,
+            mod_name: &str,
+            name: &str,
+            func_type: &FuncType,
+        ) -> Result<Handle<Callable>> {
+            EmptyResolver::instance().resolve_function(mod_name, name, func_type)
+        }
+        fn resolve_table(
+            &self,
+            mod_name: &str,
+            name: &str,
+            table_type: &TableType,
+        ) -> Result<Handle<Table>> {
+            EmptyResolver::instance().resolve_table(mod_name, name, table_type)
+        }
+        fn resolve_memory(
+            &self,
+            mod_name: &str,
+            name: &str,
+            mem_type: &MemType,
+        ) -> Result<Handle<Memory>> {
+            EmptyResolver::instance().resolve_memory(mod_name, name, mem_type)
+        }
+        fn resolve_global(
+            &self,
+            _mod_name: &str,
+            _name: &str,
+            _global_type: &GlobalType,
+        ) -> Result<Handle<Global>> {
+            Ok(self.global.clone())
+        }
+    }
+
+    #[test]
+    fn test_imported_global_type() {
+        let resolver = GlobalResolver {
+            global: handle(
+                Global::new(
+                    GlobalType::new(ValueType::I32, MutableType::Const),
+                    StackEntry::I32Entry(5),
+                )
+                .unwrap(),
+            ),
+        };
+        let import_global = |value_type, mutable_type| {
+            TestModule::new()
+                .import(
+                    "env",
+                    "global",
+                    ImportDesc::GlobalType(GlobalType::new(value_type, mutable_type)),
+                )
+                .build()
+        };
+        let is_link_error = |result: Result<_>| {
+            matches!(
+                result.err().unwrap().downcast_ref::<WasmError>(),
+                Some(WasmError::LinkError(_))
+            )
+        };
+
+        assert!(
+            resolve_raw_module(import_global(ValueType::I32, MutableType::Const), &resolver)
+                .is_ok()
+        );
+        assert!(is_link_error(resolve_raw_module(
+            import_global(ValueType::I32, MutableType::Var),
+            &resolver
+        )));
+        assert!(is_link_error(resolve_raw_module(
+            import_global(ValueType::I64, MutableType::Const),
+            &resolver
+        )));
+    }
+
+    // Has every global, but none of them are the type the module asks for
+    struct MismatchedGlobalResolver {}
+
+    impl Resolver for MismatchedGlobalResolver {
+        fn resolve_function(
+            &self,
+            mod_name: &str,
+            name: &str,
+            func_type: &FuncType,
+        ) -> Result<Handle<Callable>> {
+            EmptyResolver::instance().resolve_function(mod_name, name, func_type)
+        }
+        fn resolve_table(
+            &self,
+            mod_name: &str,
+            name: &str,
+            table_type: &TableType,
+        ) -> Result<Handle<Table>> {
+            EmptyResolver::instance().resolve_table(mod_name, name, table_type)
+        }
+        fn resolve_memory(
+            &self,
+            mod_name: &str,
+            name: &str,
+            mem_type: &MemType,
+        ) -> Result<Handle<Memory>> {
+            EmptyResolver::instance().resolve_memory(mod_name, name, mem_type)
+        }
+        fn resolve_global(
+            &self,
+            mod_name: &str,
+            name: &str,
+            global_type: &GlobalType,
+        ) -> Result<Handle<Global>> {
+            Err(anyhow::anyhow!(
+                "Global {}:{} is not of type {:?}",
+                mod_name,
+                name,
+                global_type
+            ))
+        }
+    }
+
+    #[test]
+    fn test_stub_resolver_keeps_mismatched_imports() {
+        // The inner resolver has the global, but it is an i32 constant rather than the i64
+        // variable the module wants, so it fails to link instead of being stubbed
+        let inner = GlobalResolver {
+            global: handle(
+                Global::new(
+                    GlobalType::new(ValueType::I32, MutableType::Const),
+                    StackEntry::I32Entry(5),
+                )
+                .unwrap(),
+            ),
+        };
+        let resolver = StubResolver::new(&inner);
+        let error = resolve_raw_module(make_module_with_imports(), &resolver).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<WasmError>(),
+            Some(WasmError::LinkError(_))
+        ));
+        assert_eq!(
+            resolver.stubbed_imports(),
+            vec!["env:log", "env:table", "env:memory"]
+        );
+
+        // The same goes for a mismatch which the inner resolver reports itself
+        let inner = MismatchedGlobalResolver {};
+        let resolver = StubResolver::new(&inner);
+        let error = resolve_raw_module(make_module_with_imports(), &resolver).unwrap_err();
+        assert!(format!("{:#}", error).contains("Global env:counter is not of type"));
+        assert!(!resolver
+            .stubbed_imports()
+            .contains(&"env:counter".to_string()));
+    }
+
+    #[test]
+    fn test_growing_resolver() {
+        let inner = MemoryResolver {
+            memory: handle(Memory::new_from_bounds(1, Some(3))),
+        };
+        let resolver = GrowingResolver::new(&inner);
+
+        let (_, data_module, _) = resolve_raw_module(
+            make_module_importing_memory(Limits::Unbounded(3)),
+            &resolver,
+        )
+        .unwrap();
+        assert!(Shared::ptr_eq(&data_module.memories[0], &inner.memory));
+        assert_eq!(inner.memory.borrow().current_size(), 3);
+
+        // The memory's maximum won't let it grow far enough, so it is left alone
+        assert!(resolve_raw_module(
+            make_module_importing_memory(Limits::Unbounded(4)),
+            &resolver
+        )
+        .is_err());
+        assert_eq!(inner.memory.borrow().current_size(), 3);
+    }
+
+    #[test]
+    fn test_host_object_resolver() {
+        let mut resolver = HostObjectResolver::new(EmptyResolver::instance());
+        let memory = resolver.define_memory("env", "memory", Memory::new_from_bounds(1, None));
+        memory.borrow_mut().set_data(16, b"hello").unwrap();
+        let counter = resolver.define_global(
+            "env",
+            "counter",
+            Global::from_value(Value::I64(42), MutableType::Var),
+        );
+        let table = resolver.define_table(
+            "env",
+            "table",
+            Table::new(TableType::new(ElemType::FuncRef, Limits::Unbounded(2))),
+        );
+        let log = resolver.define_function(
+            "env",
+            "log",
+            HostFuncCallable::new(
+                FuncType::new(vec![ValueType::I32], vec![ValueType::I32]),
+                |args| Ok(args.to_vec()),
+            )
+            .into(),
+        );
+
+        let (function_module, mut data_module, _) =
+            resolve_raw_module(make_module_with_imports(), &resolver).unwrap();
+        assert!(Shared::ptr_eq(
+            &function_module.get_function(0).unwrap(),
+            &log
+        ));
+        assert!(Shared::ptr_eq(&function_module.tables[0], &table));
+        assert!(Shared::ptr_eq(&data_module.globals[0], &counter));
+
+        // The module sees what the host put in the memory, and the host what the module
+        // writes
+        let mut bytes = [0; 5];
+        data_module.read_data(0, 16, &mut bytes).unwrap();
+        assert_eq!(&bytes, b"hello");
+        data_module.write_data(0, 32, b"world").unwrap();
+        memory.borrow().get_data(32, &mut bytes).unwrap();
+        assert_eq!(&bytes, b"world");
+
+        let mut stack = Stack::new();
+        stack.push(7u32.into());
+        function_module
+            .execute_function(0, &mut stack, &mut data_module)
+            .unwrap();
+        assert_eq!(stack.working_top(1)[0], 7u32.into());
+
+        // An object which doesn't suit the import is still rejected
+        let mut resolver = HostObjectResolver::new(&resolver);
+        resolver.define_global(
+            "env",
+            "counter",
+            Global::from_value(Value::I32(42), MutableType::Var),
+        );
+        assert!(resolve_raw_module(make_module_with_imports(), &resolver).is_err());
+    }
+}
@@ -3,7 +3,8 @@ use anyhow::{anyhow, Result};
 use num_enum::TryFromPrimitive;
 use std::io::Read;
 
-#[derive(Debug, PartialEq, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum SectionType {
     CustomSection,
@@ -18,6 +19,7 @@ pub enum SectionType {
     ElementSection,
     CodeSection,
     DataSection,
+    DataCountSection,
 }
 
 impl TypeReader for SectionType {
@@ -1,5 +1,20 @@
-use crate::core::{stack_entry::StackEntry, FuncType, Locals, ValueType};
+use crate::core::{
+    observer::SharedObserver, stack_entry::StackEntry, trap, ExecutionObserver, FuncType, Handle,
+    InterruptHandle, Locals, MemoryMonitor, TrapKind, Value, ValueType, WasmError,
+};
 use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+
+// How many instructions run between looks at the interrupt handle and memory monitor
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+
+// With the stack-canaries feature, an extra entry holding a known value goes between the
+// locals of each frame and its working stack, and beneath the parameters of each block.
+// They are checked whenever a frame or a label is popped, so a mistake in the arithmetic
+// which moves entries around fails straight away instead of quietly corrupting a value.
+const CANARIES: bool = cfg!(feature = "stack-canaries");
+const CANARY_SLOTS: usize = CANARIES as usize;
+const CANARY: StackEntry = StackEntry::I64Entry(0xCA4A_12E5_CA4A_12E5);
 
 struct LocalsFlatteningIterator<'a, T: Iterator<Item = &'a Locals>> {
     iter: T,
@@ -52,13 +67,15 @@ fn flatten_locals<'a, T: Iterator<Item = &'a Locals>>(iter: T) -> LocalsFlatteni
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct StackLabel {
     sp: usize,
     arity: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StackFrame {
     sp: usize,
     parameter_count: usize,
@@ -114,7 +131,7 @@ impl StackFrame {
     pub fn working_base(&self) -> usize {
         match self.label_stack.last() {
             Some(label) => label.sp,
-            _ => self.local_limit(),
+            _ => self.local_limit() + CANARY_SLOTS,
         }
     }
 
@@ -135,6 +152,10 @@ impl StackFrame {
         (sp, arity)
     }
 
+    pub fn label_count(&self) -> usize {
+        self.label_stack.len()
+    }
+
     #[allow(dead_code)]
     pub fn label_arity(&self) -> usize {
         match self.label_stack.last() {
@@ -144,21 +165,297 @@ impl StackFrame {
     }
 }
 
-#[derive(Debug)]
+/// Limits on how deep execution may go, so that runaway or adversarial modules fail with
+/// an error instead of exhausting the host's memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackLimits {
+    /// The deepest nesting of blocks, loops and ifs allowed within a single function
+    pub max_control_depth: usize,
+    /// The deepest chain of calls allowed. Calls between wasm functions don't use the host's
+    /// stack, so this can be raised as far as memory allows.
+    pub max_call_depth: usize,
+    /// The number of entries the stack keeps room for once calls return to the outermost
+    /// frame. Anything beyond this, left over from deep recursion or a large working set,
+    /// is handed back to the allocator.
+    pub retained_capacity: usize,
+}
+
+impl Default for StackLimits {
+    fn default() -> Self {
+        Self {
+            max_control_depth: 1024,
+            max_call_depth: 256,
+            retained_capacity: 64 * 1024,
+        }
+    }
+}
+
+/// What a stack holds part way through a call, without its limits or anything else which
+/// comes from the instance
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct SavedStack {
+    frames: Vec<StackFrame>,
+    entries: Vec<Value>,
+    steps: Option<u64>,
+}
+
+impl SavedStack {
+    pub(crate) fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+/// How many frames there were, and how many labels the innermost of them had
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct StackMark {
+    frame_count: usize,
+    label_count: usize,
+}
+
+#[derive(Debug, Clone)]
 pub struct Stack {
     frames: Vec<StackFrame>,
     entries: Vec<StackEntry>,
+    limits: StackLimits,
+    fuel: Option<u64>,
+    steps: Option<u64>,
+    interrupt: Option<InterruptHandle>,
+    memory_monitor: Option<MemoryMonitor>,
+    observer: Option<SharedObserver>,
+    breakpoints: BTreeSet<(usize, usize)>,
+    instructions_until_interrupt_check: u32,
 }
 
 impl Stack {
     #[allow(dead_code)]
     pub fn new() -> Self {
+        Self::with_limits(StackLimits::default())
+    }
+
+    pub fn with_limits(limits: StackLimits) -> Self {
         Stack {
             frames: Vec::new(),
             entries: Vec::new(),
+            limits,
+            fuel: None,
+            steps: None,
+            interrupt: None,
+            memory_monitor: None,
+            observer: None,
+            breakpoints: BTreeSet::new(),
+            instructions_until_interrupt_check: 0,
+        }
+    }
+
+    /// Limit the number of instructions that can be executed with this stack. `None` means
+    /// that execution is unmetered.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Pay for a single instruction, trapping if there is no fuel left
+    pub fn consume_fuel(&mut self) -> Result<()> {
+        match &mut self.fuel {
+            Some(0) => Err(trap(TrapKind::OutOfFuel)),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Pause execution once this many more instructions have been executed. Unlike running
+    /// out of fuel, pausing isn't a trap, and execution can carry on from where it stopped.
+    pub(crate) fn set_step_limit(&mut self, steps: Option<u64>) {
+        self.steps = steps;
+    }
+
+    pub(crate) fn remaining_steps(&self) -> Option<u64> {
+        self.steps
+    }
+
+    /// Returns false, without taking a step, if execution should pause
+    pub(crate) fn take_step(&mut self) -> bool {
+        match &mut self.steps {
+            Some(0) => false,
+            Some(steps) => {
+                *steps -= 1;
+                true
+            }
+            None => true,
         }
     }
 
+    pub fn set_interrupt_handle(&mut self, interrupt: Option<InterruptHandle>) {
+        self.interrupt = interrupt;
+        self.instructions_until_interrupt_check = 0;
+    }
+
+    pub fn set_memory_monitor(&mut self, memory_monitor: Option<MemoryMonitor>) {
+        self.memory_monitor = memory_monitor;
+        self.instructions_until_interrupt_check = 0;
+    }
+
+    /// Report what is executed with this stack to `observer`
+    pub fn set_observer(&mut self, observer: Option<Handle<dyn ExecutionObserver>>) {
+        self.set_shared_observer(observer.map(SharedObserver::new));
+    }
+
+    pub(crate) fn set_shared_observer(&mut self, observer: Option<SharedObserver>) {
+        self.observer = observer;
+    }
+
+    pub(crate) fn observer(&self) -> Option<&SharedObserver> {
+        self.observer.as_ref()
+    }
+
+    /// The function indices and byte offsets of the instructions to pause before. Like
+    /// reaching the step limit, the instruction is executed when execution carries on.
+    pub(crate) fn breakpoints_mut(&mut self) -> &mut BTreeSet<(usize, usize)> {
+        &mut self.breakpoints
+    }
+
+    pub(crate) fn is_breakpoint(&self, func_idx: Option<usize>, byte_offset: usize) -> bool {
+        match func_idx {
+            Some(func_idx) => self.breakpoints.contains(&(func_idx, byte_offset)),
+            None => false,
+        }
+    }
+
+    pub(crate) fn has_breakpoints(&self) -> bool {
+        !self.breakpoints.is_empty()
+    }
+
+    pub(crate) fn save(&self) -> SavedStack {
+        SavedStack {
+            frames: self.frames.clone(),
+            entries: self.entries.iter().copied().map(Value::from).collect(),
+            steps: self.steps,
+        }
+    }
+
+    /// Put back what was saved. It may have come from outside the process, so the frames
+    /// and labels are checked to be in order and to fit within the entries, which is enough
+    /// to keep execution from indexing outside the stack.
+    pub(crate) fn restore(&mut self, saved: SavedStack) -> Result<()> {
+        if saved.frames.len() > self.limits.max_call_depth {
+            return Err(anyhow!(
+                "The saved stack is deeper than the call depth limit"
+            ));
+        }
+        let mut floor = 0;
+        for frame in &saved.frames {
+            let working_base = frame
+                .sp
+                .checked_add(frame.parameter_count)
+                .and_then(|limit| limit.checked_add(frame.local_count))
+                .and_then(|limit| limit.checked_add(CANARY_SLOTS))
+                .filter(|working_base| frame.sp >= floor && *working_base <= saved.entries.len())
+                .ok_or_else(|| anyhow!("The saved stack has a frame out of place"))?;
+            floor = working_base;
+            for label in &frame.label_stack {
+                if label.sp < floor || label.sp > saved.entries.len() {
+                    return Err(anyhow!("The saved stack has a label out of place"));
+                }
+                floor = label.sp;
+            }
+        }
+
+        self.frames = saved.frames;
+        self.entries = saved.entries.into_iter().map(StackEntry::from).collect();
+        self.steps = saved.steps;
+        Ok(())
+    }
+
+    /// How many values the function in the outermost frame returns
+    pub(crate) fn outermost_return_count(&self) -> Option<usize> {
+        self.frames.first().map(|frame| frame.return_types.len())
+    }
+
+    /// Check a frame which has been restored against the function it belongs to. Its
+    /// parameters and locals have to be of the function's types, and its labels have to
+    /// have the arities of the blocks which enclose where the function had got to.
+    pub(crate) fn check_frame(
+        &self,
+        frame_idx: usize,
+        func_type: &FuncType,
+        locals: &[Locals],
+        label_arities: &[usize],
+    ) -> Result<()> {
+        let frame = &self.frames[frame_idx];
+        if &frame.return_types != func_type.return_types()
+            || frame.parameter_count != func_type.arg_types().len()
+            || frame.local_count != Locals::total_count(locals)? as usize
+        {
+            return Err(anyhow!(
+                "Saved frame {} doesn't have the type and locals of its function",
+                frame_idx
+            ));
+        }
+
+        // With the counts the same, there is exactly one entry for each parameter and local
+        let local_types = func_type
+            .arg_types()
+            .iter()
+            .cloned()
+            .chain(flatten_locals(locals.iter()).map(|l| l.value_type()));
+        let entries = &self.entries[frame.parameter_base()..frame.local_limit()];
+        for (idx, (entry, local_type)) in entries.iter().zip(local_types).enumerate() {
+            if entry.value_type() != local_type {
+                return Err(anyhow!(
+                    "Local {} of saved frame {} is {:?} but should be {:?}",
+                    idx,
+                    frame_idx,
+                    entry.value_type(),
+                    local_type
+                ));
+            }
+        }
+
+        let arities: Vec<_> = frame.label_stack.iter().map(|label| label.arity).collect();
+        if arities != label_arities {
+            return Err(anyhow!(
+                "Saved frame {} doesn't have the labels of the blocks it is in",
+                frame_idx
+            ));
+        }
+        Ok(())
+    }
+
+    /// Called for every instruction, but only looks at the interrupt handle and memory
+    /// monitor every so often because they are shared with other threads
+    pub fn check_interrupt(&mut self) -> Result<()> {
+        if self.interrupt.is_none() && self.memory_monitor.is_none() {
+            return Ok(());
+        }
+
+        if self.instructions_until_interrupt_check == 0 {
+            self.instructions_until_interrupt_check = INTERRUPT_CHECK_INTERVAL;
+            if let Some(interrupt) = &self.interrupt {
+                if interrupt.take_interrupt() {
+                    return Err(trap(TrapKind::Interrupted));
+                }
+            }
+        }
+        self.instructions_until_interrupt_check -= 1;
+        Ok(())
+    }
+
+    /// The memory monitor, if `check_interrupt` has just looked at it and found that
+    /// another thread is waiting for a copy
+    pub(crate) fn take_memory_request(&self) -> Option<&MemoryMonitor> {
+        let just_checked = self.instructions_until_interrupt_check == INTERRUPT_CHECK_INTERVAL - 1;
+        self.memory_monitor
+            .as_ref()
+            .filter(|memory_monitor| just_checked && memory_monitor.is_requested())
+    }
+
     #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
@@ -263,12 +560,17 @@ impl Stack {
 
     #[allow(dead_code)]
     pub fn push(&mut self, entry: StackEntry) {
-        self.entries.push(entry);
+        // Only pushes which grow the stack allocate
+        if self.entries.len() < self.entries.capacity() {
+            self.entries.push(entry);
+        } else {
+            accounted!(Stack, self.entries.push(entry));
+        }
     }
 
     #[allow(dead_code)]
     pub fn push_from_slice(&mut self, entries: &[StackEntry]) {
-        self.entries.extend_from_slice(entries);
+        accounted!(Stack, self.entries.extend_from_slice(entries));
     }
 
     #[allow(dead_code)]
@@ -285,6 +587,31 @@ impl Stack {
         self.entries.truncate(self.entries.len() - n);
     }
 
+    // Panic if any of the canaries for the current frame, or for its labels from
+    // `first_label` on, has been overwritten or dropped
+    fn check_canaries(&self, first_label: usize) {
+        if !CANARIES {
+            return;
+        }
+
+        let frame = self.frames.last().unwrap();
+        let frame_depth = self.frames.len() - 1;
+        let check = |position: usize, what: String| match self.entries.get(position) {
+            Some(entry) if *entry == CANARY => (),
+            entry => panic!(
+                "Stack canary {} of frame {} at entry {} is {:?}",
+                what, frame_depth, position, entry
+            ),
+        };
+
+        if first_label == 0 {
+            check(frame.local_limit(), "after the locals".to_string());
+        }
+        for (label_idx, label) in frame.label_stack.iter().enumerate().skip(first_label) {
+            check(label.sp - 1, format!("beneath label {}", label_idx));
+        }
+    }
+
     pub fn drop_entries(&mut self, to_drop: usize, arity: usize) {
         assert!(self.working_count() >= to_drop + arity);
 
@@ -304,13 +631,21 @@ impl Stack {
     pub fn push_test_frame(&mut self, local_count: u32) -> Result<()> {
         let func_type = FuncType::new(vec![], vec![]);
         let locals = vec![Locals::new(local_count, ValueType::I32)];
-        self.push_typed_frame(&func_type, &locals)
+        self.push_typed_frame(&func_type, &locals, local_count as usize)
     }
 
-    pub fn push_typed_frame(&mut self, func_type: &FuncType, locals: &Vec<Locals>) -> Result<()> {
+    /// The local count is the total of the counts of the locals, which the caller works
+    /// out once rather than on every call
+    pub fn push_typed_frame(
+        &mut self,
+        func_type: &FuncType,
+        locals: &Vec<Locals>,
+        local_count: usize,
+    ) -> Result<()> {
         let arg_count = func_type.arg_types().len();
-        let local_count = locals.iter().map(|l| l.count() as usize).sum();
-        if arg_count > self.working_count() {
+        if self.frames.len() >= self.limits.max_call_depth {
+            Err(trap(TrapKind::CallStackExhausted))
+        } else if arg_count > self.working_count() {
             Err(anyhow!("Not enough arguments on working stack"))
         } else {
             let working_params = self.working_top(arg_count);
@@ -324,7 +659,9 @@ impl Stack {
                         (_, ValueType::I32, StackEntry::I32Entry(_))
                         | (_, ValueType::I64, StackEntry::I64Entry(_))
                         | (_, ValueType::F32, StackEntry::F32Entry(_))
-                        | (_, ValueType::F64, StackEntry::F64Entry(_)) => Ok(()),
+                        | (_, ValueType::F64, StackEntry::F64Entry(_))
+                        | (_, ValueType::FuncRef, StackEntry::FuncRefEntry(_))
+                        | (_, ValueType::ExternRef, StackEntry::ExternRefEntry(_)) => Ok(()),
                         (idx, ..) => Err(anyhow!("Argument {} type does not match", idx)),
                     }
                 })
@@ -343,16 +680,14 @@ impl Stack {
                     // Push on zeroed out entries for the locals
                     for (_, l) in flatten_locals(locals.iter()).enumerate() {
                         debug_assert!(l.count() == 1);
-                        self.push(match l.value_type() {
-                            ValueType::I32 => StackEntry::I32Entry(0),
-                            ValueType::I64 => StackEntry::I64Entry(0),
-                            ValueType::F32 => StackEntry::F32Entry(0.0),
-                            ValueType::F64 => StackEntry::F64Entry(0.0),
-                        });
+                        self.push(StackEntry::zero_value(l.value_type()));
+                    }
+                    if CANARIES {
+                        self.push(CANARY);
                     }
 
                     // Now push the frame
-                    self.frames.push(frame);
+                    accounted!(Stack, self.frames.push(frame));
 
                     Ok(())
                 }
@@ -361,6 +696,7 @@ impl Stack {
     }
 
     pub fn pop_typed_frame(&mut self) -> Result<()> {
+        self.check_canaries(0);
         let last_frame = self.frames.last().unwrap();
         let return_types = &last_frame.return_types;
 
@@ -377,7 +713,9 @@ impl Stack {
                         (_, ValueType::I32, StackEntry::I32Entry(_))
                         | (_, ValueType::I64, StackEntry::I64Entry(_))
                         | (_, ValueType::F32, StackEntry::F32Entry(_))
-                        | (_, ValueType::F64, StackEntry::F64Entry(_)) => Ok(()),
+                        | (_, ValueType::F64, StackEntry::F64Entry(_))
+                        | (_, ValueType::FuncRef, StackEntry::FuncRefEntry(_))
+                        | (_, ValueType::ExternRef, StackEntry::ExternRefEntry(_)) => Ok(()),
                         (idx, ..) => Err(anyhow!("Argument {} type does not match", idx)),
                     }
                 })
@@ -400,6 +738,7 @@ impl Stack {
                     }
 
                     self.entries.truncate(new_len);
+                    self.release_excess_capacity();
 
                     Ok(())
                 }
@@ -407,16 +746,131 @@ impl Stack {
         }
     }
 
-    pub fn push_label(&mut self, arity: usize) {
-        let sp = self.height();
-        self.frames.last_mut().unwrap().push_label(sp, arity);
+    /// Replace the current frame ahead of a tail call to a function of type `func_type`. The
+    /// arguments on top of the working stack are moved down to where the frame began, ready
+    /// for the callee's frame to be pushed in its place.
+    pub fn pop_frame_for_tail_call(&mut self, func_type: &FuncType) -> Result<()> {
+        let arg_count = func_type.arg_types().len();
+        let last_frame = self.frames.last().unwrap();
+        if &last_frame.return_types != func_type.return_types() {
+            Err(anyhow!(
+                "Tail call to a function returning {:?} from a function returning {:?}",
+                func_type.return_types(),
+                last_frame.return_types
+            ))
+        } else if arg_count > self.working_count() {
+            Err(anyhow!("Not enough arguments on working stack"))
+        } else {
+            self.check_canaries(0);
+            let old_arg_base = self.working_limit() - arg_count;
+            let new_arg_base = self.frame_base();
+
+            self.frames.pop();
+
+            for i in 0..arg_count {
+                self.entries[new_arg_base + i] = self.entries[old_arg_base + i];
+            }
+            self.entries.truncate(new_arg_base + arg_count);
+
+            Ok(())
+        }
+    }
+
+    /// Where execution is, so that a trap can get back to it with `unwind_to`
+    pub(crate) fn mark(&self) -> StackMark {
+        StackMark {
+            frame_count: self.frames.len(),
+            label_count: self.last_frame(|f| f.label_count()),
+        }
+    }
+
+    /// Throw away the frames and labels pushed since the mark was taken, after a trap has
+    /// abandoned the functions and blocks they belong to. Everything they hold goes with
+    /// them, including the arguments of the calls and the parameters of the blocks, so the
+    /// stack is left as it was just before the first of them was entered and can be used
+    /// for another call.
+    pub(crate) fn unwind_to(&mut self, mark: StackMark) {
+        if let Some(frame) = self.frames.get(mark.frame_count) {
+            let frame_base = frame.frame_base();
+            self.frames.truncate(mark.frame_count);
+            self.entries.truncate(frame_base);
+        }
+
+        if let Some(frame) = self.frames.last_mut() {
+            if frame.label_count() > mark.label_count {
+                let label_count = frame.label_count();
+                let (sp, _) = frame.pop_n_labels(label_count - mark.label_count);
+                self.entries.truncate(sp - CANARY_SLOTS);
+            }
+        }
+        self.release_excess_capacity();
+    }
+
+    // Only shrink once execution is back at the outermost frame, because anything deeper is
+    // likely to need the space again before long
+    fn release_excess_capacity(&mut self) {
+        let retained_capacity = self.limits.retained_capacity.max(self.entries.len());
+        if self.frames.len() <= 1 && self.entries.capacity() > retained_capacity {
+            self.entries.shrink_to(retained_capacity);
+        }
+    }
+
+    /// Enter a block. The block's parameters are already on the stack, and become part of
+    /// the block, so the label goes beneath them.
+    pub fn push_label(&mut self, param_count: usize, arity: usize) -> Result<()> {
+        if param_count > self.working_count() {
+            return Err(anyhow!("Not enough block parameters on working stack"));
+        }
+
+        let sp = self.height() - param_count;
+        let frame = self.frames.last_mut().unwrap();
+        if frame.label_count() >= self.limits.max_control_depth {
+            return Err(WasmError::ExhaustionError(format!(
+                "Blocks are nested more than {} deep",
+                self.limits.max_control_depth
+            ))
+            .into());
+        }
+
+        accounted!(Stack, frame.push_label(sp + CANARY_SLOTS, arity));
+        if CANARIES {
+            accounted!(Stack, self.entries.insert(sp, CANARY));
+        }
+        Ok(())
     }
 
-    pub fn pop_n_labels(&mut self, count: usize) {
+    pub fn pop_n_labels(&mut self, count: usize) -> Result<()> {
         // We ask the frame to drop the labels and tell us how to fix up the
         // stack
+        let label_count = self.frames.last().unwrap().label_count();
+        self.check_canaries(label_count.saturating_sub(count));
         let (sp, arity) = self.frames.last_mut().unwrap().pop_n_labels(count);
-        self.drop_entries((self.height() - sp) - arity, arity);
+        self.drop_block_entries(sp, arity)
+    }
+
+    /// Leave a block by reaching its end, keeping its results. These aren't necessarily the
+    /// values a branch to the label would keep, because a branch to a loop carries the
+    /// loop's parameters.
+    pub fn end_label(&mut self, result_count: usize) -> Result<()> {
+        let label_count = self.frames.last().unwrap().label_count();
+        self.check_canaries(label_count.saturating_sub(1));
+        let (sp, _) = self.frames.last_mut().unwrap().pop_n_labels(1);
+        self.drop_block_entries(sp, result_count)
+    }
+
+    // Drop everything a block left above its label apart from the values it keeps, along
+    // with the label's canary. Validation makes sure the values are there, so this only
+    // fails for unvalidated bodies.
+    fn drop_block_entries(&mut self, sp: usize, keep: usize) -> Result<()> {
+        let count = self.height() - sp;
+        if count < keep {
+            return Err(trap(TrapKind::StackUnderflow).context(format!(
+                "The block should leave {} values but has {}",
+                keep, count
+            )));
+        }
+        self.drop_entries(count - keep + CANARY_SLOTS, keep);
+        Ok(())
     }
 }
 
@@ -436,9 +890,11 @@ mod test {
         let func_type = FuncType::new(params, ret_types.iter().map(|x| x.clone()).collect());
         let locals = vec![Locals::new(local_count, ValueType::I32)];
 
-        stack.push_typed_frame(&func_type, &locals)
+        stack.push_typed_frame(&func_type, &locals, local_count as usize)
     }
 
+    // The canaries of the current frame and its labels are checked, and aren't counted as
+    // hidden entries, so that the ranges are the same with or without them
     fn check_stack_ranges(stack: &Stack) -> (usize, usize, usize, usize) {
         assert_eq!(stack.frame_base(), stack.parameter_base());
 
@@ -452,7 +908,14 @@ mod test {
         let local_count = stack.local_count();
         assert_eq!(stack.local_limit(), stack.local_base() + local_count);
 
-        let hidden_working_count = stack.working_base() - stack.local_limit();
+        let canary_count = match stack.frames.last() {
+            Some(frame) => {
+                stack.check_canaries(0);
+                (1 + frame.label_count()) * CANARY_SLOTS
+            }
+            None => 0,
+        };
+        let hidden_working_count = stack.working_base() - stack.local_limit() - canary_count;
 
         let working_count = stack.working_count();
         assert_eq!(stack.working_limit(), stack.working_base() + working_count);
@@ -485,9 +948,10 @@ mod test {
         assert_eq!(stack.frame_base(), 0);
         assert_eq!(check_stack_ranges(&stack), (0, 4, 0, 0));
 
-        // Validate that the locals are all currently "Unused"
-        assert_eq!(stack.frame().len(), 4);
-        assert_eq!(stack.frame_mut().len(), 4);
+        // Validate that the locals are all currently "Unused". The frame goes on to the
+        // canary after them.
+        assert_eq!(stack.frame().len(), 4 + CANARY_SLOTS);
+        assert_eq!(stack.frame_mut().len(), 4 + CANARY_SLOTS);
         assert_eq!(stack.local().len(), 4);
         assert_eq!(stack.local_mut().len(), 4);
 
@@ -526,7 +990,10 @@ mod test {
 
         // Verify the new entries are all correct
         for i in 4..8 {
-            assert_eq!(stack.frame()[i], u32::try_from(i).unwrap().into());
+            assert_eq!(
+                stack.frame()[i + CANARY_SLOTS],
+                u32::try_from(i).unwrap().into()
+            );
         }
 
         // Now pop an entry
@@ -542,7 +1009,10 @@ mod test {
         }
 
         for i in 4..7 {
-            assert_eq!(stack.frame()[i], u32::try_from(i).unwrap().into());
+            assert_eq!(
+                stack.frame()[i + CANARY_SLOTS],
+                u32::try_from(i).unwrap().into()
+            );
         }
 
         // Now push another entry
@@ -552,7 +1022,7 @@ mod test {
         assert_eq!(stack.frame_base(), 0);
         assert_eq!(check_stack_ranges(&stack), (0, 4, 0, 4));
 
-        assert_eq!(stack.frame()[7], 32.0f32.into());
+        assert_eq!(stack.frame()[7 + CANARY_SLOTS], 32.0f32.into());
 
         // Now pop n entries
         stack.pop_n(2);
@@ -562,7 +1032,10 @@ mod test {
         assert_eq!(check_stack_ranges(&stack), (0, 4, 0, 2));
 
         for i in 4..6 {
-            assert_eq!(stack.frame()[i], u32::try_from(i).unwrap().into());
+            assert_eq!(
+                stack.frame()[i + CANARY_SLOTS],
+                u32::try_from(i).unwrap().into()
+            );
         }
 
         // Push a "result" entry
@@ -579,13 +1052,13 @@ mod test {
         assert_eq!(stack.frame_base(), 0);
         assert_eq!(check_stack_ranges(&stack), (0, 4, 0, 1));
 
-        assert_eq!(stack.frame()[4], 32.0f64.into());
+        assert_eq!(stack.frame()[4 + CANARY_SLOTS], 32.0f64.into());
 
         // Now push another frame, this time taking one parameter
         assert!(push_test_frame(&mut stack, &[ValueType::F64], 4, &[ValueType::F64]).is_ok());
 
         assert_eq!(stack.is_empty(), false);
-        assert_eq!(stack.frame_base(), 4);
+        assert_eq!(stack.frame_base(), 4 + CANARY_SLOTS);
         assert_eq!(check_stack_ranges(&stack), (1, 4, 0, 0));
         assert_eq!(stack.local()[0], 32f64.into());
 
@@ -593,9 +1066,9 @@ mod test {
         stack.push(42f64.into());
 
         assert_eq!(stack.is_empty(), false);
-        assert_eq!(stack.frame_base(), 4);
+        assert_eq!(stack.frame_base(), 4 + CANARY_SLOTS);
         assert_eq!(check_stack_ranges(&stack), (1, 4, 0, 1));
-        assert_eq!(stack.frame()[5], 42f64.into());
+        assert_eq!(stack.frame()[5 + CANARY_SLOTS], 42f64.into());
 
         // Now pop the frame
         assert!(stack.pop_typed_frame().is_ok());
@@ -603,7 +1076,7 @@ mod test {
         assert_eq!(stack.is_empty(), false);
         assert_eq!(stack.frame_base(), 0);
         assert_eq!(check_stack_ranges(&stack), (0, 4, 0, 1));
-        assert_eq!(stack.frame()[4], 42f64.into());
+        assert_eq!(stack.frame()[4 + CANARY_SLOTS], 42f64.into());
 
         // Now push some constants
         stack.push(42f64.into());
@@ -612,7 +1085,7 @@ mod test {
         assert_eq!(check_stack_ranges(&stack), (0, 4, 0, 4));
 
         // Now push a label with arity of 2
-        stack.push_label(2);
+        assert!(stack.push_label(0, 2).is_ok());
         assert_eq!(check_stack_ranges(&stack), (0, 4, 4, 0));
 
         // Locals should be unchanged
@@ -632,7 +1105,7 @@ mod test {
         );
 
         // Now pop the label
-        stack.pop_n_labels(1);
+        assert!(stack.pop_n_labels(1).is_ok());
         assert_eq!(check_stack_ranges(&stack), (0, 4, 0, 6));
 
         assert_eq!(
@@ -656,14 +1129,14 @@ mod test {
 
         // This will fail because there are not enough arguments on the stack
         assert_eq!(check_stack_ranges(&stack), (0, 0, 0, 0));
-        assert!(stack.push_typed_frame(&func_type, &locals).is_err());
+        assert!(stack.push_typed_frame(&func_type, &locals, 5).is_err());
         assert_eq!(check_stack_ranges(&stack), (0, 0, 0, 0));
 
         stack.push(0_u32.into());
         stack.push(0_u32.into());
         // This will fail because the parameters are the wrong types
         assert_eq!(check_stack_ranges(&stack), (0, 0, 0, 2));
-        assert!(stack.push_typed_frame(&func_type, &locals).is_err());
+        assert!(stack.push_typed_frame(&func_type, &locals, 5).is_err());
         assert_eq!(check_stack_ranges(&stack), (0, 0, 0, 2));
 
         stack.pop_n(2);
@@ -671,7 +1144,7 @@ mod test {
         stack.push(18_f32.into());
         // Now it should be OK
         assert_eq!(check_stack_ranges(&stack), (0, 0, 0, 2));
-        assert!(stack.push_typed_frame(&func_type, &locals).is_ok());
+        assert!(stack.push_typed_frame(&func_type, &locals, 5).is_ok());
         assert_eq!(check_stack_ranges(&stack), (2, 5, 0, 0));
 
         // Check the locals have been initialized correctly
@@ -702,4 +1175,103 @@ mod test {
         assert_eq!(stack.working_top(2)[0], 26.0_f64.into());
         assert_eq!(stack.working_top(2)[1], 52.0_f64.into());
     }
+
+    #[test]
+    fn test_retained_capacity() {
+        let mut stack = Stack::with_limits(StackLimits {
+            retained_capacity: 100,
+            ..StackLimits::default()
+        });
+
+        assert!(push_test_frame(&mut stack, &[], 0, &[]).is_ok());
+        assert!(push_test_frame(&mut stack, &[], 1000, &[ValueType::I32]).is_ok());
+        assert!(stack.push_label(0, 0).is_ok());
+        for _ in 0..1000 {
+            stack.push(0_u32.into());
+        }
+        assert!(stack.pop_n_labels(1).is_ok());
+        stack.push(42_u32.into());
+        assert!(stack.entries.capacity() >= 2000);
+
+        // Returning to the outermost frame gives back what the deep call needed
+        assert!(stack.pop_typed_frame().is_ok());
+        assert_eq!(check_stack_ranges(&stack), (0, 0, 0, 1));
+        assert_eq!(stack.working_top(1)[0], 42_u32.into());
+        assert!(stack.entries.capacity() <= 100);
+    }
+
+    #[test]
+    fn test_restore_checks_frames() {
+        let mut stack = Stack::new();
+        stack.push(1_u32.into());
+        assert!(push_test_frame(&mut stack, &[ValueType::I32], 2, &[ValueType::I32]).is_ok());
+        assert!(stack.push_label(0, 1).is_ok());
+        let saved = stack.save();
+
+        let mut restored = Stack::new();
+        assert!(restored.restore(saved.clone()).is_ok());
+        assert_eq!(restored.save(), saved);
+
+        // Counts so big that the end of the frame can't be worked out
+        for huge in 0..3 {
+            let mut saved = saved.clone();
+            let frame = &mut saved.frames[0];
+            match huge {
+                0 => frame.sp = usize::MAX,
+                1 => frame.parameter_count = usize::MAX,
+                _ => frame.local_count = usize::MAX - 1,
+            }
+            let error = Stack::new().restore(saved).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "The saved stack has a frame out of place"
+            );
+        }
+    }
+
+    #[cfg(feature = "stack-canaries")]
+    fn push_canary_frame() -> Stack {
+        let mut stack = Stack::new();
+        stack.push(1_u32.into());
+        assert!(push_test_frame(&mut stack, &[ValueType::I32], 2, &[ValueType::I32]).is_ok());
+        stack.push(2_u32.into());
+        assert!(stack.push_label(1, 1).is_ok());
+        stack
+    }
+
+    #[test]
+    #[cfg(feature = "stack-canaries")]
+    fn test_canaries() {
+        let mut stack = push_canary_frame();
+
+        // The canaries are hidden from the frame, and go away with their labels and frames
+        assert_eq!(check_stack_ranges(&stack), (1, 2, 0, 1));
+        assert_eq!(stack.working_base() - stack.local_limit(), 2);
+        assert_eq!(stack.local().len(), 3);
+        stack.push(3_u32.into());
+        assert!(stack.end_label(1).is_ok());
+        assert_eq!(check_stack_ranges(&stack), (1, 2, 0, 1));
+        assert_eq!(stack.working_base() - stack.local_limit(), 1);
+        assert_eq!(stack.working_top(1)[0], 3_u32.into());
+        assert!(stack.pop_typed_frame().is_ok());
+        assert_eq!(stack.entries, vec![3_u32.into()]);
+    }
+
+    #[test]
+    #[cfg(feature = "stack-canaries")]
+    #[should_panic(expected = "Stack canary after the locals of frame 0 at entry 3")]
+    fn test_overwritten_frame_canary() {
+        let mut stack = push_canary_frame();
+        stack.entries[3] = 0_u32.into();
+        let _ = stack.pop_n_labels(1);
+    }
+
+    #[test]
+    #[cfg(feature = "stack-canaries")]
+    #[should_panic(expected = "Stack canary beneath label 0 of frame 0 at entry 4")]
+    fn test_overwritten_label_canary() {
+        let mut stack = push_canary_frame();
+        stack.entries[4] = 0_u32.into();
+        let _ = stack.end_label(1);
+    }
 }
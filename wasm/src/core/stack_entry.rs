@@ -1,6 +1,8 @@
 use anyhow::{anyhow, Error};
 use std::convert::{From, TryFrom};
 
+use crate::core::ValueType;
+
 static INVALID_CONVERSION_MESSAGE: &'static str = "Cannot convert stack entry";
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -9,15 +11,52 @@ pub enum StackEntry {
     I64Entry(u64),
     F32Entry(f32),
     F64Entry(f64),
+    /// A reference to a function, as an index into the function index space of the module
+    /// that is executing, or `None` for the null reference
+    FuncRefEntry(Option<u32>),
+    /// A reference to something owned by the host. The interpreter never looks inside it.
+    ExternRefEntry(Option<u32>),
 }
 
 impl StackEntry {
+    pub fn zero_value(value_type: ValueType) -> StackEntry {
+        match value_type {
+            ValueType::I32 => StackEntry::I32Entry(0),
+            ValueType::I64 => StackEntry::I64Entry(0),
+            ValueType::F32 => StackEntry::F32Entry(0.0),
+            ValueType::F64 => StackEntry::F64Entry(0.0),
+            ValueType::FuncRef => StackEntry::FuncRefEntry(None),
+            ValueType::ExternRef => StackEntry::ExternRefEntry(None),
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            StackEntry::I32Entry(_) => ValueType::I32,
+            StackEntry::I64Entry(_) => ValueType::I64,
+            StackEntry::F32Entry(_) => ValueType::F32,
+            StackEntry::F64Entry(_) => ValueType::F64,
+            StackEntry::FuncRefEntry(_) => ValueType::FuncRef,
+            StackEntry::ExternRefEntry(_) => ValueType::ExternRef,
+        }
+    }
+
+    /// Whether this is a reference, and if it is, whether it is null
+    pub fn is_null_ref(&self) -> Option<bool> {
+        match self {
+            StackEntry::FuncRefEntry(r) | StackEntry::ExternRefEntry(r) => Some(r.is_none()),
+            _ => None,
+        }
+    }
+
     pub fn is_same_type(&self, other: &StackEntry) -> bool {
         match (self, other) {
             (StackEntry::I32Entry(_), StackEntry::I32Entry(_))
             | (StackEntry::I64Entry(_), StackEntry::I64Entry(_))
             | (StackEntry::F32Entry(_), StackEntry::F32Entry(_))
-            | (StackEntry::F64Entry(_), StackEntry::F64Entry(_)) => true,
+            | (StackEntry::F64Entry(_), StackEntry::F64Entry(_))
+            | (StackEntry::FuncRefEntry(_), StackEntry::FuncRefEntry(_))
+            | (StackEntry::ExternRefEntry(_), StackEntry::ExternRefEntry(_)) => true,
             _ => false,
         }
     }
@@ -0,0 +1,408 @@
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::core::{self, resolve_raw_module, ExportValue, GlobalType, Instance, RawModule, Shared};
+
+/// A single reason why a replacement module cannot take over the state of the
+/// module it is replacing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Incompatibility {
+    MissingExport {
+        name: String,
+    },
+    ExportKindMismatch {
+        name: String,
+    },
+    MemoryTooSmall {
+        name: String,
+        required_pages: usize,
+        maximum_pages: usize,
+    },
+    GlobalTypeMismatch {
+        name: String,
+        old_type: GlobalType,
+        new_type: GlobalType,
+    },
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::MissingExport { name } => {
+                write!(f, "export \"{}\" is missing from the new module", name)
+            }
+            Incompatibility::ExportKindMismatch { name } => {
+                write!(f, "export \"{}\" has changed kind", name)
+            }
+            Incompatibility::MemoryTooSmall {
+                name,
+                required_pages,
+                maximum_pages,
+            } => write!(
+                f,
+                "memory \"{}\" needs {} pages but the new module allows at most {}",
+                name, required_pages, maximum_pages
+            ),
+            Incompatibility::GlobalTypeMismatch {
+                name,
+                old_type,
+                new_type,
+            } => write!(
+                f,
+                "global \"{}\" has changed type from {:?} to {:?}",
+                name, old_type, new_type
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReloadError {
+    UnknownModule(String),
+    Instantiation(anyhow::Error),
+    Incompatible(Vec<Incompatibility>),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::UnknownModule(name) => write!(f, "No module named \"{}\"", name),
+            ReloadError::Instantiation(e) => write!(f, "Failed to instantiate module: {}", e),
+            ReloadError::Incompatible(incompatibilities) => {
+                write!(f, "Module is incompatible with the running instance")?;
+                for incompatibility in incompatibilities {
+                    write!(f, "; {}", incompatibility)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+/// A collection of named module instances. Instances in the store can be replaced
+/// with a new version of the module while keeping their exported state.
+#[derive(Debug, Default)]
+pub struct Store {
+    modules: BTreeMap<String, Instance>,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self {
+            modules: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, instance: Instance) -> anyhow::Result<()> {
+        if self.modules.contains_key(name) {
+            Err(anyhow!("Module \"{}\" is already registered", name))
+        } else {
+            self.modules.insert(name.to_string(), instance);
+            Ok(())
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Instance> {
+        self.modules.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Instance> {
+        self.modules.remove(name)
+    }
+
+    /// The names of the registered modules, in order
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.modules.keys()
+    }
+
+    /// Replace the instance registered under `name` with an instance of `module`.
+    ///
+    /// Exported memories and mutable globals of the running instance are copied into
+    /// the exports of the same name in the new instance. If any of them cannot be carried
+    /// over then nothing is replaced and every problem found is reported. Note that the new
+    /// instance is fully instantiated, including running its start function, before its
+    /// state is overwritten.
+    pub fn reload<Resolver: core::Resolver>(
+        &mut self,
+        name: &str,
+        module: RawModule,
+        resolver: &Resolver,
+    ) -> Result<(), ReloadError> {
+        let old_module = self
+            .modules
+            .get(name)
+            .ok_or_else(|| ReloadError::UnknownModule(name.to_string()))?;
+
+        let new_module = resolve_raw_module(module, resolver)
+            .map(Instance::new)
+            .map_err(ReloadError::Instantiation)?;
+
+        let incompatibilities = check_compatibility(old_module, &new_module);
+        if !incompatibilities.is_empty() {
+            return Err(ReloadError::Incompatible(incompatibilities));
+        }
+
+        migrate_state(old_module, &new_module).map_err(ReloadError::Instantiation)?;
+
+        self.modules.insert(name.to_string(), new_module);
+        Ok(())
+    }
+}
+
+fn check_compatibility(old_module: &Instance, new_module: &Instance) -> Vec<Incompatibility> {
+    let mut incompatibilities = Vec::new();
+
+    for (name, old_export) in old_module.export_values() {
+        match (old_export, new_module.get_export(name)) {
+            (ExportValue::Memory(_), None) => {
+                incompatibilities.push(Incompatibility::MissingExport { name: name.clone() })
+            }
+            (ExportValue::Global(old_global), None) if old_global.borrow().is_mutable() => {
+                incompatibilities.push(Incompatibility::MissingExport { name: name.clone() })
+            }
+
+            (ExportValue::Memory(old_memory), Some(ExportValue::Memory(new_memory))) => {
+                let required_pages = old_memory.borrow().current_size();
+                match new_memory.borrow().max_size() {
+                    Some(maximum_pages) if required_pages > maximum_pages => incompatibilities
+                        .push(Incompatibility::MemoryTooSmall {
+                            name: name.clone(),
+                            required_pages,
+                            maximum_pages,
+                        }),
+                    _ => {}
+                }
+            }
+            (ExportValue::Global(old_global), Some(ExportValue::Global(new_global)))
+                if old_global.borrow().is_mutable() =>
+            {
+                let old_type = old_global.borrow().global_type().clone();
+                let new_type = new_global.borrow().global_type().clone();
+                if old_type != new_type {
+                    incompatibilities.push(Incompatibility::GlobalTypeMismatch {
+                        name: name.clone(),
+                        old_type,
+                        new_type,
+                    });
+                }
+            }
+
+            (ExportValue::Memory(_), Some(_)) => {
+                incompatibilities.push(Incompatibility::ExportKindMismatch { name: name.clone() })
+            }
+            (ExportValue::Global(old_global), Some(_)) if old_global.borrow().is_mutable() => {
+                incompatibilities.push(Incompatibility::ExportKindMismatch { name: name.clone() })
+            }
+
+            // Functions, tables and constant globals carry no state that needs migrating
+            _ => {}
+        }
+    }
+
+    incompatibilities
+}
+
+fn migrate_state(old_module: &Instance, new_module: &Instance) -> anyhow::Result<()> {
+    for (name, old_export) in old_module.export_values() {
+        match (old_export, new_module.get_export(name)) {
+            // If both modules import the same object there is nothing to copy
+            (ExportValue::Memory(old_memory), Some(ExportValue::Memory(new_memory)))
+                if !Shared::ptr_eq(old_memory, new_memory) =>
+            {
+                new_memory.borrow_mut().copy_from(&old_memory.borrow())?;
+            }
+            (ExportValue::Global(old_global), Some(ExportValue::Global(new_global)))
+                if old_global.borrow().is_mutable() && !Shared::ptr_eq(old_global, new_global) =>
+            {
+                let value = *old_global.borrow().get_value();
+                new_global.borrow_mut().set_value(value)?;
+            }
+
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        stack_entry::StackEntry, test_module::TestModule, EmptyResolver, ExportDesc, Limits,
+        MemType, MutableType, ValueType,
+    };
+
+    fn make_module(memory_limits: Limits, counter_type: ValueType, version: u8) -> RawModule {
+        assert!(version < 0x40);
+        let counter_init: &[u8] = match counter_type {
+            ValueType::I64 => &[0x42, 0x00, 0x0B],
+            _ => &[0x41, 0x00, 0x0B],
+        };
+
+        TestModule::new()
+            .func(core::FuncType::new(vec![], vec![]), &[0x0B])
+            .memory(MemType::new(memory_limits))
+            .global(
+                GlobalType::new(counter_type, MutableType::Var),
+                counter_init,
+            )
+            .global(
+                GlobalType::new(ValueType::I32, MutableType::Const),
+                &[0x41, version, 0x0B],
+            )
+            .export("memory", ExportDesc::Mem(0))
+            .export("counter", ExportDesc::Global(0))
+            .export("version", ExportDesc::Global(1))
+            .build()
+    }
+
+    fn load(module: RawModule) -> Instance {
+        Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap())
+    }
+
+    fn get_global(store: &Store, name: &str) -> StackEntry {
+        match store.get("test").unwrap().get_export(name).unwrap() {
+            ExportValue::Global(g) => *g.borrow().get_value(),
+            _ => panic!("Unexpected export type"),
+        }
+    }
+
+    fn with_memory<R>(store: &Store, func: impl FnOnce(&mut core::Memory) -> R) -> R {
+        match store.get("test").unwrap().get_export("memory").unwrap() {
+            ExportValue::Memory(m) => func(&mut m.borrow_mut()),
+            _ => panic!("Unexpected export type"),
+        }
+    }
+
+    fn make_store_with_state() -> Store {
+        let mut store = Store::new();
+        store
+            .register(
+                "test",
+                load(make_module(Limits::Unbounded(1), ValueType::I32, 1)),
+            )
+            .unwrap();
+
+        // Give the running instance some state that should survive the reload
+        with_memory(&store, |m| {
+            m.grow_by(1).unwrap();
+            m.set_data(65535, &[1, 2, 3, 4]).unwrap();
+        });
+        match store.get("test").unwrap().get_export("counter").unwrap() {
+            ExportValue::Global(g) => g.borrow_mut().set_value(42u32.into()).unwrap(),
+            _ => panic!("Unexpected export type"),
+        }
+
+        store
+    }
+
+    #[test]
+    fn test_reload_preserves_state() {
+        let mut store = make_store_with_state();
+
+        assert!(store
+            .reload(
+                "test",
+                make_module(Limits::Unbounded(1), ValueType::I32, 2),
+                EmptyResolver::instance()
+            )
+            .is_ok());
+
+        assert_eq!(get_global(&store, "counter"), 42u32.into());
+        assert_eq!(get_global(&store, "version"), 2u32.into());
+
+        let mut buf = [0u8; 4];
+        with_memory(&store, |m| {
+            assert_eq!(m.current_size(), 2);
+            m.get_data(65535, &mut buf).unwrap();
+        });
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_reload_rejects_incompatible_module() {
+        let mut store = make_store_with_state();
+
+        match store.reload(
+            "test",
+            make_module(Limits::Bounded(1, 1), ValueType::I64, 2),
+            EmptyResolver::instance(),
+        ) {
+            Err(error @ ReloadError::Incompatible(_)) => {
+                // Problems are reported in export name order, so the message is stable
+                assert_eq!(
+                    error.to_string(),
+                    "Module is incompatible with the running instance; \
+                     global \"counter\" has changed type from \
+                     GlobalType { t: I32, m: Var } to GlobalType { t: I64, m: Var }; \
+                     memory \"memory\" needs 2 pages but the new module allows at most 1"
+                );
+                let incompatibilities = match error {
+                    ReloadError::Incompatible(incompatibilities) => incompatibilities,
+                    _ => unreachable!(),
+                };
+                assert_eq!(
+                    incompatibilities,
+                    vec![
+                        Incompatibility::GlobalTypeMismatch {
+                            name: "counter".to_string(),
+                            old_type: GlobalType::new(ValueType::I32, MutableType::Var),
+                            new_type: GlobalType::new(ValueType::I64, MutableType::Var),
+                        },
+                        Incompatibility::MemoryTooSmall {
+                            name: "memory".to_string(),
+                            required_pages: 2,
+                            maximum_pages: 1,
+                        },
+                    ]
+                );
+            }
+            other => panic!("Unexpected reload result {:?}", other),
+        }
+
+        // The original instance should still be in place
+        assert_eq!(get_global(&store, "counter"), 42u32.into());
+        assert_eq!(get_global(&store, "version"), 1u32.into());
+    }
+
+    #[test]
+    fn test_names_are_in_order() {
+        let mut store = Store::new();
+        for name in &["zeta", "alpha", "mu"] {
+            store
+                .register(
+                    name,
+                    load(make_module(Limits::Unbounded(1), ValueType::I32, 1)),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(store.names().collect::<Vec<_>>(), ["alpha", "mu", "zeta"]);
+        assert_eq!(
+            store
+                .get("zeta")
+                .unwrap()
+                .exports()
+                .map(|export| export.name)
+                .collect::<Vec<_>>(),
+            ["memory", "counter", "version"]
+        );
+    }
+
+    #[test]
+    fn test_reload_unknown_module() {
+        let mut store = Store::new();
+        match store.reload(
+            "missing",
+            make_module(Limits::Unbounded(1), ValueType::I32, 1),
+            EmptyResolver::instance(),
+        ) {
+            Err(ReloadError::UnknownModule(name)) => assert_eq!(name, "missing"),
+            other => panic!("Unexpected reload result {:?}", other),
+        }
+    }
+}
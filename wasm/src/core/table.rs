@@ -1,27 +1,67 @@
 use anyhow::{anyhow, Result};
 use std::{
-    cell::RefCell,
     ops::{Index, IndexMut},
-    rc::Rc,
     slice::SliceIndex,
 };
 
-use crate::core::{Callable, ElemType, Limits, TableType};
+use crate::core::{trap, Callable, ElemType, Handle, Limits, TableType, TrapKind};
 
-type RefCallable = Rc<RefCell<Callable>>;
-type OptRefCallable = Option<RefCallable>;
+type RefCallable = Handle<Callable>;
+
+/// The value of one entry of a table. A table only holds the kind of reference its
+/// element type says, and anything else put in it is an error.
+#[derive(Debug, Clone)]
+pub enum TableElement {
+    FuncRef(Option<RefCallable>),
+    /// A reference owned by the host, which the interpreter never looks inside
+    ExternRef(Option<u32>),
+}
+
+impl TableElement {
+    pub fn null(elem_type: &ElemType) -> Self {
+        match elem_type {
+            ElemType::FuncRef => TableElement::FuncRef(None),
+            ElemType::ExternRef => TableElement::ExternRef(None),
+        }
+    }
+
+    pub fn elem_type(&self) -> ElemType {
+        match self {
+            TableElement::FuncRef(_) => ElemType::FuncRef,
+            TableElement::ExternRef(_) => ElemType::ExternRef,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(
+            self,
+            TableElement::FuncRef(None) | TableElement::ExternRef(None)
+        )
+    }
+
+    /// The function this entry refers to, if it refers to one
+    pub fn func_ref(&self) -> Option<&RefCallable> {
+        match self {
+            TableElement::FuncRef(callable) => callable.as_ref(),
+            TableElement::ExternRef(_) => None,
+        }
+    }
+}
+
+// Tables without a maximum can't grow beyond this, so a guest can't make the host allocate
+// an unreasonable amount of memory
+const MAX_TABLE_ENTRIES: usize = 10_000_000;
 
 #[derive(Debug)]
 pub struct Table {
+    elem_type: ElemType,
     minimum_entries: usize,
     maximum_entries: Option<usize>,
-    entries: Vec<OptRefCallable>,
+    entries: Vec<TableElement>,
 }
 
 impl Table {
     pub fn new(table_type: TableType) -> Self {
-        assert!(*table_type.elem_type() == ElemType::FuncRef);
-
         let (minimum_entries, maximum_entries): (usize, Option<usize>) = match table_type.limits() {
             Limits::Bounded(minimum_entries, maximum_entries) => {
                 (*minimum_entries, Some(*maximum_entries))
@@ -29,34 +69,44 @@ impl Table {
             Limits::Unbounded(minimum_entries) => (*minimum_entries, None),
         };
 
-        Self::new_from_bounds(minimum_entries, maximum_entries)
+        Self::with_elem_type(
+            table_type.elem_type().clone(),
+            minimum_entries,
+            maximum_entries,
+        )
     }
 
+    /// Make a table of functions
     pub fn new_from_bounds(minimum_entries: usize, maximum_entries: Option<usize>) -> Self {
-        let mut entries = Vec::with_capacity(minimum_entries);
-        for _ in 0..minimum_entries {
-            entries.push(None)
-        }
+        Self::with_elem_type(ElemType::FuncRef, minimum_entries, maximum_entries)
+    }
 
-        // Make the memory object
+    fn with_elem_type(
+        elem_type: ElemType,
+        minimum_entries: usize,
+        maximum_entries: Option<usize>,
+    ) -> Self {
         Table {
+            entries: vec![TableElement::null(&elem_type); minimum_entries],
+            elem_type,
             minimum_entries,
             maximum_entries,
-            entries,
         }
     }
 
+    pub fn elem_type(&self) -> &ElemType {
+        &self.elem_type
+    }
+
     #[allow(dead_code)]
     pub fn min_size(&self) -> usize {
         self.minimum_entries
     }
 
-    #[allow(dead_code)]
     pub fn max_size(&self) -> Option<usize> {
         self.maximum_entries
     }
 
-    #[allow(dead_code)]
     pub fn current_size(&self) -> usize {
         self.entries.len()
     }
@@ -64,22 +114,147 @@ impl Table {
     pub fn get_entry(&self, idx: usize) -> Result<RefCallable> {
         if idx < self.entries.len() {
             match &self.entries[idx] {
-                Some(callable) => Ok(callable.clone()),
-                _ => Err(anyhow!("Table entry {} is not defined", idx)),
+                TableElement::FuncRef(Some(callable)) => Ok(callable.clone()),
+                TableElement::FuncRef(None) => Err(trap(TrapKind::UninitializedElement)
+                    .context(format!("Table entry {} is not defined", idx))),
+                TableElement::ExternRef(_) => Err(anyhow!("Table does not hold functions")),
             }
         } else {
-            Err(anyhow!("Table index {} is out of range", idx))
+            Err(trap(TrapKind::UndefinedElement)
+                .context(format!("Table index {} is out of range", idx)))
+        }
+    }
+
+    fn check_range(&self, offset: usize, length: usize) -> Result<()> {
+        match offset.checked_add(length) {
+            Some(end) if end <= self.entries.len() => Ok(()),
+            _ => Err(trap(TrapKind::TableOutOfBounds).context(format!(
+                "Table access of {} entries at {} is out of range",
+                length, offset
+            ))),
+        }
+    }
+
+    fn check_elem_type(&self, elem_type: ElemType) -> Result<()> {
+        if elem_type == self.elem_type {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Cannot store {:?} in a table of {:?}",
+                elem_type,
+                self.elem_type
+            ))
         }
     }
 
-    pub fn set_entries(&mut self, offset: usize, functions: &[RefCallable]) {
+    pub(crate) fn entries(&self) -> &[TableElement] {
+        &self.entries
+    }
+
+    /// Put back entries taken with `entries`, which shrinks the table again if it has
+    /// grown since then
+    pub(crate) fn restore_entries(&mut self, entries: &[TableElement]) {
+        self.entries = entries.to_vec();
+    }
+
+    /// Read an entry for `table.get`. Unlike `get_entry`, a null entry is not an error.
+    pub fn get(&self, idx: usize) -> Result<TableElement> {
+        self.check_range(idx, 1)?;
+        Ok(self.entries[idx].clone())
+    }
+
+    pub fn set(&mut self, idx: usize, value: TableElement) -> Result<()> {
+        self.check_elem_type(value.elem_type())?;
+        self.check_range(idx, 1)?;
+        self.entries[idx] = value;
+        Ok(())
+    }
+
+    pub fn set_entries(&mut self, offset: usize, functions: &[RefCallable]) -> Result<()> {
+        self.check_elem_type(ElemType::FuncRef)?;
+        self.check_range(offset, functions.len())?;
         for (idx, value) in functions.iter().enumerate() {
-            self.entries[offset + idx] = Some(value.clone());
+            self.entries[offset + idx] = TableElement::FuncRef(Some(value.clone()));
+        }
+        Ok(())
+    }
+
+    /// Copy entries from an element segment
+    pub fn init(
+        &mut self,
+        dst_offset: usize,
+        segment: &[TableElement],
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        match src_offset.checked_add(length) {
+            Some(end) if end <= segment.len() => {
+                let entries = &segment[src_offset..end];
+                for entry in entries {
+                    self.check_elem_type(entry.elem_type())?;
+                }
+                self.check_range(dst_offset, length)?;
+                self.entries[dst_offset..dst_offset + length].clone_from_slice(entries);
+                Ok(())
+            }
+            _ => Err(trap(TrapKind::TableOutOfBounds)
+                .context("Attempting to access outside element segment")),
+        }
+    }
+
+    pub fn grow_by(&mut self, grow_by: usize, value: TableElement) -> Result<()> {
+        self.check_elem_type(value.elem_type())?;
+        let max_size = self.max_size().unwrap_or(MAX_TABLE_ENTRIES);
+        match self.current_size().checked_add(grow_by) {
+            Some(new_size) if new_size <= max_size => {
+                self.entries.resize(new_size, value);
+                Ok(())
+            }
+            _ => Err(anyhow!("New table is too big")),
+        }
+    }
+
+    pub fn fill(&mut self, offset: usize, value: TableElement, length: usize) -> Result<()> {
+        self.check_elem_type(value.elem_type())?;
+        self.check_range(offset, length)?;
+        for entry in &mut self.entries[offset..offset + length] {
+            *entry = value.clone();
         }
+        Ok(())
+    }
+
+    /// Copy entries within the table. The ranges may overlap.
+    pub fn copy_within(
+        &mut self,
+        dst_offset: usize,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        self.check_range(dst_offset, length)?;
+        self.check_range(src_offset, length)?;
+        let entries: Vec<_> = self.entries[src_offset..src_offset + length].to_vec();
+        self.entries[dst_offset..dst_offset + length].clone_from_slice(&entries);
+        Ok(())
+    }
+
+    /// Copy entries from another table
+    pub fn copy_from(
+        &mut self,
+        dst_offset: usize,
+        src: &Table,
+        src_offset: usize,
+        length: usize,
+    ) -> Result<()> {
+        self.check_elem_type(src.elem_type.clone())?;
+        self.check_range(dst_offset, length)?;
+        src.check_range(src_offset, length)?;
+        self.entries[dst_offset..dst_offset + length]
+            .clone_from_slice(&src.entries[src_offset..src_offset + length]);
+        Ok(())
     }
 }
 
-impl<I: SliceIndex<[OptRefCallable]>> Index<I> for Table {
+impl<I: SliceIndex<[TableElement]>> Index<I> for Table {
     type Output = I::Output;
 
     fn index(&self, idx: I) -> &Self::Output {
@@ -87,8 +262,53 @@ impl<I: SliceIndex<[OptRefCallable]>> Index<I> for Table {
     }
 }
 
-impl<I: SliceIndex<[OptRefCallable]>> IndexMut<I> for Table {
+impl<I: SliceIndex<[TableElement]>> IndexMut<I> for Table {
     fn index_mut(&mut self, idx: I) -> &mut Self::Output {
         &mut self.entries[idx]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_externref_table() {
+        assert_eq!(ElemType::from_byte(0x6F).unwrap(), ElemType::ExternRef);
+        let mut table = Table::new(TableType::new(ElemType::ExternRef, Limits::Bounded(2, 4)));
+        assert_eq!(*table.elem_type(), ElemType::ExternRef);
+        assert!(matches!(
+            table.get(1).unwrap(),
+            TableElement::ExternRef(None)
+        ));
+
+        table.set(1, TableElement::ExternRef(Some(7))).unwrap();
+        table.grow_by(1, TableElement::ExternRef(Some(8))).unwrap();
+        table.fill(0, TableElement::ExternRef(Some(9)), 1).unwrap();
+        let values = table
+            .entries()
+            .iter()
+            .map(|entry| match entry {
+                TableElement::ExternRef(value) => *value,
+                entry => panic!("Unexpected entry {:?}", entry),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![Some(9), Some(7), Some(8)]);
+
+        // Functions can't go in, and the table can't be called through
+        assert!(table.set(0, TableElement::FuncRef(None)).is_err());
+        assert!(table.grow_by(1, TableElement::FuncRef(None)).is_err());
+        assert!(table.fill(0, TableElement::FuncRef(None), 1).is_err());
+        assert!(table.set_entries(0, &[]).is_err());
+        assert!(table.get_entry(0).is_err());
+        assert!(table
+            .copy_from(0, &Table::new_from_bounds(1, None), 0, 1)
+            .is_err());
+        assert_eq!(table.current_size(), 3);
+
+        // Nor can host references go in a table of functions
+        let mut table = Table::new_from_bounds(1, None);
+        assert!(table.set(0, TableElement::ExternRef(Some(1))).is_err());
+        assert!(table.set(0, TableElement::FuncRef(None)).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::core::{
+    Data, Element, Export, ExportDesc, Expr, Func, FuncType, GlobalDef, GlobalType, Import,
+    ImportDesc, Locals, MemType, RawModule, TableType,
+};
+
+/// Builds a module for a test out of only the parts the test cares about. A function's
+/// type is added to the module the first time something uses it, so types are numbered in
+/// the order they are first used, unless `func_type` adds them up front.
+#[derive(Debug, Default)]
+pub struct TestModule {
+    types: Vec<FuncType>,
+    typeidx: Vec<usize>,
+    funcs: Vec<Func>,
+    tables: Vec<TableType>,
+    mems: Vec<MemType>,
+    globals: Vec<GlobalDef>,
+    elem: Vec<Element>,
+    data: Vec<Data>,
+    start: Option<usize>,
+    imports: Vec<Import>,
+    exports: Vec<Export>,
+}
+
+impl TestModule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a type without a function, for bodies which refer to it by index
+    pub fn func_type(mut self, func_type: FuncType) -> Self {
+        self.type_idx(func_type);
+        self
+    }
+
+    /// Add a function without any locals. The body has to include its final end.
+    pub fn func(self, func_type: FuncType, body: &[u8]) -> Self {
+        self.func_with_locals(func_type, vec![], body)
+    }
+
+    pub fn func_with_locals(
+        mut self,
+        func_type: FuncType,
+        locals: Vec<Locals>,
+        body: &[u8],
+    ) -> Self {
+        let type_idx = self.type_idx(func_type);
+        self.typeidx.push(type_idx);
+        self.funcs.push(Func::new(locals, Expr::new(body.to_vec())));
+        self
+    }
+
+    pub fn import_func(mut self, mod_name: &str, name: &str, func_type: FuncType) -> Self {
+        let type_idx = self.type_idx(func_type);
+        self.import(mod_name, name, ImportDesc::TypeIdx(type_idx))
+    }
+
+    pub fn import(mut self, mod_name: &str, name: &str, desc: ImportDesc) -> Self {
+        self.imports
+            .push(Import::new(mod_name.to_string(), name.to_string(), desc));
+        self
+    }
+
+    pub fn table(mut self, table_type: TableType) -> Self {
+        self.tables.push(table_type);
+        self
+    }
+
+    pub fn memory(mut self, mem_type: MemType) -> Self {
+        self.mems.push(mem_type);
+        self
+    }
+
+    /// Add a global, initialized by a constant expression which includes its final end
+    pub fn global(mut self, global_type: GlobalType, init: &[u8]) -> Self {
+        self.globals
+            .push(GlobalDef::new(global_type, Expr::new(init.to_vec())));
+        self
+    }
+
+    pub fn elem(mut self, element: Element) -> Self {
+        self.elem.push(element);
+        self
+    }
+
+    pub fn data(mut self, data: Data) -> Self {
+        self.data.push(data);
+        self
+    }
+
+    pub fn start(mut self, func_idx: usize) -> Self {
+        self.start = Some(func_idx);
+        self
+    }
+
+    pub fn export(mut self, name: &str, desc: ExportDesc) -> Self {
+        self.exports.push(Export::new(name.to_string(), desc));
+        self
+    }
+
+    pub fn build(self) -> RawModule {
+        RawModule::new(
+            self.types,
+            self.typeidx,
+            self.funcs,
+            self.tables,
+            self.mems,
+            self.globals,
+            self.elem,
+            self.data,
+            self.start,
+            self.imports,
+            self.exports,
+        )
+    }
+
+    fn type_idx(&mut self, func_type: FuncType) -> usize {
+        match self.types.iter().position(|t| *t == func_type) {
+            Some(type_idx) => type_idx,
+            None => {
+                self.types.push(func_type);
+                self.types.len() - 1
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Debugging by stepping backwards as well as forwards. A session runs a call a few
+//! instructions at a time, and every so often takes a snapshot of everything execution can
+//! change. Going back puts back the closest snapshot before the target, and executes
+//! forwards again from there to reach it.
+//!
+//! Execution has to be deterministic for this to work. Host functions are called again
+//! when their calls are executed again, so they should give the same results each time.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use crate::core::{
+    callable::CallEntry,
+    executor::execute_core::{CallStack, RunStatus},
+    instance::read_results,
+    module::InstanceState,
+    Instance, Stack, Value,
+};
+
+/// Settings for `Instance::time_travel`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeTravelConfig {
+    /// The number of instructions executed between snapshots. Stepping backwards executes
+    /// up to this many instructions again, and each snapshot holds a copy of every memory.
+    pub snapshot_interval: u64,
+}
+
+impl Default for TimeTravelConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_interval: 10_000,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Snapshot {
+    position: u64,
+    stack: Stack,
+    call_stack: Option<CallStack>,
+    results: Option<Vec<Value>>,
+    state: InstanceState,
+}
+
+/// A call into an instance which can be moved backwards and forwards an instruction at a
+/// time. The position is the number of instructions executed so far. The instance can be
+/// inspected between steps, but shouldn't be used for anything else until the session is
+/// finished with, because going back overwrites its memories, tables and globals.
+pub struct TimeTravelSession<'a> {
+    instance: &'a Instance,
+    snapshot_interval: u64,
+    position: u64,
+    stack: Stack,
+    /// The wasm functions being executed, or `None` once the call has finished
+    call_stack: Option<CallStack>,
+    result_count: usize,
+    results: Option<Vec<Value>>,
+    /// In order of position, starting with the start of the call
+    snapshots: Vec<Snapshot>,
+}
+
+impl Instance {
+    /// Start calling the exported function `name`, without executing any of it yet
+    pub fn time_travel(
+        &self,
+        name: &str,
+        args: &[Value],
+        config: TimeTravelConfig,
+    ) -> Result<TimeTravelSession<'_>> {
+        if config.snapshot_interval == 0 {
+            return Err(anyhow!(
+                "The snapshot interval must be at least one instruction"
+            ));
+        }
+
+        let mut stack = self.make_stack();
+        let callable = self.push_args(name, args, &mut stack)?;
+        let callable = callable.borrow();
+        let result_count = callable.func_type().return_types().len();
+
+        let (call_stack, results) = match callable.enter(&mut stack)? {
+            CallEntry::Body(body, func_idx) => (Some(CallStack::new(body, func_idx)), None),
+            CallEntry::Complete => (None, Some(read_results(&stack, result_count))),
+            CallEntry::Pending(_) => return Err(pending_while_debugging()),
+        };
+
+        let mut session = TimeTravelSession {
+            instance: self,
+            snapshot_interval: config.snapshot_interval,
+            position: 0,
+            stack,
+            call_stack,
+            result_count,
+            results,
+            snapshots: Vec::new(),
+        };
+        session.take_snapshot()?;
+        Ok(session)
+    }
+}
+
+fn pending_while_debugging() -> anyhow::Error {
+    anyhow!("Host functions cannot wait for their results in a time travel session")
+}
+
+impl<'a> TimeTravelSession<'a> {
+    pub fn instance(&self) -> &'a Instance {
+        self.instance
+    }
+
+    /// The number of instructions executed so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.results.is_some()
+    }
+
+    /// The results of the call, once it has finished
+    pub fn results(&self) -> Option<&[Value]> {
+        self.results.as_deref()
+    }
+
+    /// The number of wasm functions being executed, which is zero once the call has
+    /// finished
+    pub fn call_depth(&self) -> usize {
+        self.call_stack.as_ref().map_or(0, CallStack::depth)
+    }
+
+    /// The parameters and locals of the innermost function being executed
+    pub fn locals(&self) -> Vec<Value> {
+        match self.call_stack {
+            Some(_) => self
+                .stack
+                .local()
+                .iter()
+                .copied()
+                .map(Value::from)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The values the innermost function is working on, with the top of the stack last
+    pub fn operand_stack(&self) -> Vec<Value> {
+        match self.call_stack {
+            Some(_) => self
+                .stack
+                .working_top(self.stack.working_count())
+                .iter()
+                .copied()
+                .map(Value::from)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Execute the next instruction. Returns false if the call has already finished.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.is_complete() {
+            return Ok(false);
+        }
+        self.run_forwards_to(self.position + 1)?;
+        Ok(true)
+    }
+
+    /// Go back to before the last instruction. Returns false at the start of the call.
+    pub fn step_back(&mut self) -> Result<bool> {
+        if self.position == 0 {
+            return Ok(false);
+        }
+        self.seek(self.position - 1)?;
+        Ok(true)
+    }
+
+    /// Step backwards until `condition` holds, or until the start of the call. Returns
+    /// whether it was found. This is the way to find where a value went wrong: run
+    /// forwards until it is wrong, then step back until it was last right.
+    pub fn step_back_until(
+        &mut self,
+        mut condition: impl FnMut(&Self) -> Result<bool>,
+    ) -> Result<bool> {
+        while self.step_back()? {
+            if condition(self)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Go to the point where `position` instructions have been executed, or as close as
+    /// possible if the call finishes before then
+    pub fn seek(&mut self, position: u64) -> Result<()> {
+        if position < self.position {
+            self.restore_before(position)?;
+        }
+        self.run_forwards_to(position)
+    }
+
+    /// Execute the rest of the call, and return its results
+    pub fn run(&mut self) -> Result<Vec<Value>> {
+        self.run_forwards_to(u64::MAX)?;
+        Ok(self.results.clone().unwrap_or_default())
+    }
+
+    fn take_snapshot(&mut self) -> Result<()> {
+        let state = InstanceState::capture(
+            self.instance.function_module(),
+            &self.instance.data_module().borrow(),
+        )?;
+        self.snapshots.push(Snapshot {
+            position: self.position,
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            results: self.results.clone(),
+            state,
+        });
+        Ok(())
+    }
+
+    // Put back the last snapshot taken at or before the position
+    fn restore_before(&mut self, position: u64) -> Result<()> {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.position <= position)
+            .expect("There is always a snapshot of the start of the call")
+            .clone();
+
+        snapshot.state.restore(
+            self.instance.function_module(),
+            &mut self.instance.data_module().borrow_mut(),
+        )?;
+        self.position = snapshot.position;
+        self.stack = snapshot.stack;
+        self.call_stack = snapshot.call_stack;
+        self.results = snapshot.results;
+        Ok(())
+    }
+
+    // Snapshots are taken whenever the position reaches a multiple of the interval for
+    // the first time
+    fn run_forwards_to(&mut self, target: u64) -> Result<()> {
+        while self.position < target && !self.is_complete() {
+            let next_snapshot =
+                (self.position / self.snapshot_interval + 1).saturating_mul(self.snapshot_interval);
+            self.advance(target.min(next_snapshot) - self.position)?;
+
+            let last_snapshot = self.snapshots.last().map(|snapshot| snapshot.position);
+            if self.position == next_snapshot
+                && !self.is_complete()
+                && last_snapshot < Some(self.position)
+            {
+                self.take_snapshot()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, steps: u64) -> Result<()> {
+        let call_stack = match &mut self.call_stack {
+            Some(call_stack) => call_stack,
+            None => return Ok(()),
+        };
+
+        self.stack.set_step_limit(Some(steps));
+        let status = call_stack.run(
+            &mut self.stack,
+            self.instance.function_module(),
+            &mut *self.instance.data_module().borrow_mut(),
+        );
+        let taken = steps - self.stack.remaining_steps().unwrap_or(0);
+        self.stack.set_step_limit(None);
+
+        let error = match status {
+            Ok(RunStatus::Paused) => {
+                self.position += taken;
+                return Ok(());
+            }
+            Ok(RunStatus::Complete) => {
+                self.position += taken;
+                self.stack.pop_typed_frame()?;
+                self.results = Some(read_results(&self.stack, self.result_count));
+                self.call_stack = None;
+                return Ok(());
+            }
+            Ok(RunStatus::Pending(_)) => pending_while_debugging(),
+            Err(e) => e,
+        };
+
+        // The instruction which failed was counted, but didn't finish. Go back to just
+        // before it, so that the session can still be used to find out why it failed.
+        let failed_at = self.position + taken.saturating_sub(1);
+        self.restore_before(failed_at)?;
+        self.run_forwards_to(failed_at)?;
+        Err(error)
+    }
+}
+
+impl fmt::Debug for TimeTravelSession<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimeTravelSession")
+            .field("position", &self.position)
+            .field("results", &self.results)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        resolve_raw_module, test_module::TestModule, EmptyResolver, ExportDesc, ExportValue,
+        FuncType, GlobalType, Limits, Locals, MemType, MutableType, ValueType,
+    };
+
+    fn make_instance() -> Instance {
+        // Counts a local up to ten, setting the global to it each time round the loop
+        let count = vec![
+            0x03, 0x40, 0x20, 0x00, 0x41, 0x01, 0x6A, 0x22, 0x00, 0x24, 0x00, 0x20, 0x00, 0x41,
+            0x0A, 0x48, 0x0D, 0x00, 0x0B, 0x23, 0x00, 0x0B,
+        ];
+        // Grows the memory and stores 42 at the start of it
+        let grow = vec![
+            0x41, 0x01, 0x40, 0x00, 0x1A, 0x41, 0x00, 0x41, 0x2A, 0x36, 0x02, 0x00, 0x0B,
+        ];
+        let divide_by_zero = vec![0x41, 0x01, 0x41, 0x00, 0x6D, 0x0B];
+
+        let module = TestModule::new()
+            .func_with_locals(
+                FuncType::new(vec![], vec![ValueType::I32]),
+                vec![Locals::new(1, ValueType::I32)],
+                &count,
+            )
+            .func(FuncType::new(vec![], vec![]), &grow)
+            .func(FuncType::new(vec![], vec![ValueType::I32]), &divide_by_zero)
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .global(
+                GlobalType::new(ValueType::I32, MutableType::Var),
+                &[0x41, 0x00, 0x0B],
+            )
+            .export("count", ExportDesc::Func(0))
+            .export("grow", ExportDesc::Func(1))
+            .export("divide_by_zero", ExportDesc::Func(2))
+            .export("memory", ExportDesc::Mem(0))
+            .export("global", ExportDesc::Global(0))
+            .build();
+
+        Instance::new(resolve_raw_module(module, EmptyResolver::instance()).unwrap())
+    }
+
+    fn global_value(instance: &Instance) -> Value {
+        match instance.get_export("global") {
+            Some(ExportValue::Global(global)) => Value::from(*global.borrow().get_value()),
+            _ => panic!("The global is exported"),
+        }
+    }
+
+    #[test]
+    fn test_step_backwards() {
+        let instance = make_instance();
+        let config = TimeTravelConfig {
+            snapshot_interval: 7,
+        };
+        let mut session = instance.time_travel("count", &[], config).unwrap();
+
+        // The loop instruction, nine instructions for each time round, and the global.get
+        assert_eq!(session.run().unwrap(), vec![Value::I32(10)]);
+        assert_eq!(session.position(), 92);
+        assert!(!session.step().unwrap());
+
+        assert!(session.step_back().unwrap());
+        assert_eq!(session.position(), 91);
+        assert!(session.results().is_none());
+        assert_eq!(session.call_depth(), 1);
+
+        // Find the last point at which the global hadn't reached ten
+        assert!(session
+            .step_back_until(|session| Ok(global_value(session.instance()) != Value::I32(10)))
+            .unwrap());
+        assert_eq!(session.position(), 86);
+        assert_eq!(global_value(&instance), Value::I32(9));
+        assert_eq!(session.locals(), vec![Value::I32(10)]);
+        assert_eq!(session.operand_stack(), vec![Value::I32(10)]);
+
+        session.seek(5).unwrap();
+        assert_eq!(global_value(&instance), Value::I32(0));
+        session.step().unwrap();
+        assert_eq!(global_value(&instance), Value::I32(1));
+
+        session.seek(0).unwrap();
+        assert!(!session.step_back().unwrap());
+        assert_eq!(session.locals(), vec![Value::I32(0)]);
+        assert_eq!(session.run().unwrap(), vec![Value::I32(10)]);
+    }
+
+    #[test]
+    fn test_memory_is_restored() {
+        let instance = make_instance();
+        let mut session = instance
+            .time_travel("grow", &[], TimeTravelConfig::default())
+            .unwrap();
+        let memory = match instance.get_export("memory") {
+            Some(ExportValue::Memory(memory)) => memory.clone(),
+            _ => panic!("The memory is exported"),
+        };
+
+        session.run().unwrap();
+        assert_eq!(memory.borrow().current_size(), 2);
+        assert_eq!(memory.borrow().load::<u8>(0).unwrap(), 42);
+
+        session.seek(2).unwrap();
+        assert_eq!(memory.borrow().current_size(), 2);
+        assert_eq!(memory.borrow().load::<u8>(0).unwrap(), 0);
+
+        session.seek(0).unwrap();
+        assert_eq!(memory.borrow().current_size(), 1);
+    }
+
+    #[test]
+    fn test_trap_leaves_session_before_failure() {
+        let instance = make_instance();
+        let mut session = instance
+            .time_travel("divide_by_zero", &[], TimeTravelConfig::default())
+            .unwrap();
+
+        assert!(session.run().is_err());
+        assert_eq!(session.position(), 2);
+        assert_eq!(session.operand_stack(), vec![Value::I32(1), Value::I32(0)]);
+        assert!(session.step().is_err());
+        assert_eq!(session.position(), 2);
+    }
+}
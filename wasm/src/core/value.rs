@@ -0,0 +1,98 @@
+use crate::core::{stack_entry::StackEntry, ValueType};
+
+/// A WebAssembly value as seen by an embedder. Unlike `StackEntry`, integers are stored
+/// signed, as that is how they are most commonly used from Rust.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", content = "value", rename_all = "lowercase")
+)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    /// A function, by its index in the instance's function index space
+    FuncRef(Option<u32>),
+    ExternRef(Option<u32>),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+            Value::FuncRef(_) => ValueType::FuncRef,
+            Value::ExternRef(_) => ValueType::ExternRef,
+        }
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Value::I32(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::I64(v)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Self {
+        Value::F32(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::F64(v)
+    }
+}
+
+impl From<Value> for StackEntry {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::I32(i) => StackEntry::I32Entry(i as u32),
+            Value::I64(i) => StackEntry::I64Entry(i as u64),
+            Value::F32(f) => StackEntry::F32Entry(f),
+            Value::F64(f) => StackEntry::F64Entry(f),
+            Value::FuncRef(r) => StackEntry::FuncRefEntry(r),
+            Value::ExternRef(r) => StackEntry::ExternRefEntry(r),
+        }
+    }
+}
+
+impl From<StackEntry> for Value {
+    fn from(entry: StackEntry) -> Self {
+        match entry {
+            StackEntry::I32Entry(i) => Value::I32(i as i32),
+            StackEntry::I64Entry(i) => Value::I64(i as i64),
+            StackEntry::F32Entry(f) => Value::F32(f),
+            StackEntry::F64Entry(f) => Value::F64(f),
+            StackEntry::FuncRefEntry(r) => Value::FuncRef(r),
+            StackEntry::ExternRefEntry(r) => Value::ExternRef(r),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_value_json_round_trip() {
+        let values = vec![Value::I32(-1), Value::I64(1 << 40), Value::F64(0.5)];
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"type":"i32","value":-1},{"type":"i64","value":1099511627776},{"type":"f64","value":0.5}]"#
+        );
+        assert_eq!(serde_json::from_str::<Vec<Value>>(&json).unwrap(), values);
+    }
+}
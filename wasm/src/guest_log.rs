@@ -0,0 +1,314 @@
+//! A small import module that gives guests a way to log through the host's `tracing`
+//! subscriber without needing the whole of WASI. A guest imports it like this:
+//!
+//! ```text
+//! (import "wasm_interp" "log" (func $log (param i32 i32 i32)))
+//! (data (i32.const 16) "hello from the guest")
+//!
+//! (func $say_hello
+//!     (call $log (i32.const 2) (i32.const 16) (i32.const 20)))
+//! ```
+//!
+//! The first argument is the level, from 0 for error through to 4 for trace, and the other
+//! two are the address and length of a UTF-8 message in the memory exported as "memory".
+//! Events are emitted with the target `wasm_guest`.
+
+use anyhow::{anyhow, Result};
+use tracing::{event, Level};
+
+use crate::core::{
+    handle, trap, Callable, ExportValue, FuncType, Global, GlobalType, Handle, HostFuncCallable,
+    Instance, Lock, MemType, Memory, Resolver, Table, TableType, TrapKind, Value, ValueType,
+};
+use crate::output_capture::{OutputCapture, OutputStream};
+
+pub const LOG_MODULE_NAME: &str = "wasm_interp";
+pub const LOG_FUNCTION_NAME: &str = "log";
+
+fn log_message(level: i32, message: &str) {
+    // Anything more verbose than trace is still logged rather than lost
+    match level {
+        0 => event!(target: "wasm_guest", Level::ERROR, "{}", message),
+        1 => event!(target: "wasm_guest", Level::WARN, "{}", message),
+        2 => event!(target: "wasm_guest", Level::INFO, "{}", message),
+        3 => event!(target: "wasm_guest", Level::DEBUG, "{}", message),
+        _ => event!(target: "wasm_guest", Level::TRACE, "{}", message),
+    }
+}
+
+fn level_name(level: i32) -> &'static str {
+    match level {
+        0 => "ERROR",
+        1 => "WARN",
+        2 => "INFO",
+        3 => "DEBUG",
+        _ => "TRACE",
+    }
+}
+
+fn log(
+    memory: &Lock<Option<Handle<Memory>>>,
+    capture: &Lock<Option<OutputCapture>>,
+    args: &[Value],
+) -> Result<Vec<Value>> {
+    let (level, ptr, len) = match args {
+        [Value::I32(level), Value::I32(ptr), Value::I32(len)] => {
+            (*level, *ptr as u32 as usize, *len as u32 as usize)
+        }
+        _ => panic!("Log function called with the wrong signature"),
+    };
+
+    let memory = memory
+        .borrow()
+        .clone()
+        .ok_or_else(|| anyhow!("Log function called before the module memory was attached"))?;
+    let memory = memory.borrow();
+
+    // The length comes from the guest, so it is checked before anything that size is
+    // allocated
+    if !memory.contains(ptr, len) {
+        return Err(trap(TrapKind::MemoryOutOfBounds)
+            .context("The log message is outside of the module memory"));
+    }
+    let mut bytes = vec![0u8; len];
+    memory.get_data(ptr, &mut bytes)?;
+
+    let message = String::from_utf8_lossy(&bytes);
+    match &*capture.borrow() {
+        Some(capture) => {
+            let line = format!("{} {}\n", level_name(level), message);
+            capture.write(OutputStream::Log, line.as_bytes());
+        }
+        None => log_message(level, &message),
+    }
+    Ok(Vec::new())
+}
+
+/// A resolver which provides `wasm_interp:log`, and passes any other imports on to an
+/// inner resolver. Like WASI, the function reads from the module's own memory, so
+/// `attach_memory` must be called once the module has been instantiated.
+pub struct LogResolver<'a, Inner: Resolver> {
+    inner: &'a Inner,
+    memory: Handle<Option<Handle<Memory>>>,
+    capture: Handle<Option<OutputCapture>>,
+}
+
+impl<'a, Inner: Resolver> LogResolver<'a, Inner> {
+    pub fn new(inner: &'a Inner) -> Self {
+        Self {
+            inner,
+            memory: handle(None),
+            capture: handle(None),
+        }
+    }
+
+    pub fn attach_memory(&self, memory: Handle<Memory>) {
+        *self.memory.borrow_mut() = Some(memory);
+    }
+
+    /// Send the messages the module logs to `capture`, instead of to the tracing
+    /// subscriber
+    pub fn capture_output(&self, capture: OutputCapture) {
+        *self.capture.borrow_mut() = Some(capture);
+    }
+
+    pub fn attach_instance(&self, instance: &Instance) -> Result<()> {
+        match instance.get_export("memory") {
+            Some(ExportValue::Memory(memory)) => {
+                self.attach_memory(memory.clone());
+                Ok(())
+            }
+            _ => Err(anyhow!(
+                "Modules which import {}:{} must export their memory as \"memory\"",
+                LOG_MODULE_NAME,
+                LOG_FUNCTION_NAME
+            )),
+        }
+    }
+}
+
+impl<'a, Inner: Resolver> Resolver for LogResolver<'a, Inner> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Handle<Callable>> {
+        if (mod_name, name) != (LOG_MODULE_NAME, LOG_FUNCTION_NAME) {
+            return self.inner.resolve_function(mod_name, name, func_type);
+        }
+
+        let (memory, capture) = (self.memory.clone(), self.capture.clone());
+        Ok(handle(Callable::from(HostFuncCallable::new(
+            FuncType::new(vec![ValueType::I32, ValueType::I32, ValueType::I32], vec![]),
+            move |args| Ok(log(&memory, &capture, args)?),
+        ))))
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Handle<Table>> {
+        self.inner.resolve_table(mod_name, name, table_type)
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Handle<Memory>> {
+        self.inner.resolve_memory(mod_name, name, mem_type)
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Handle<Global>> {
+        self.inner.resolve_global(mod_name, name, global_type)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        resolve_raw_module, test_module::TestModule, Data, EmptyResolver, ExportDesc, Expr, Limits,
+        WasmError,
+    };
+    use std::sync::{Arc, Mutex};
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    // Just enough of a subscriber to collect the messages of the guest's events
+    #[derive(Default)]
+    struct CollectingSubscriber {
+        events: Arc<Mutex<Vec<(Level, String)>>>,
+    }
+
+    struct MessageVisitor(String);
+
+    impl Visit for MessageVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl Subscriber for CollectingSubscriber {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "wasm_guest"
+        }
+        fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut visitor = MessageVisitor(String::new());
+            event.record(&mut visitor);
+            self.events
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), visitor.0));
+        }
+        fn enter(&self, _span: &span::Id) {}
+        fn exit(&self, _span: &span::Id) {}
+    }
+
+    fn make_instance(resolver: &LogResolver<EmptyResolver>) -> Instance {
+        let message = b"hello from the guest";
+        let module = TestModule::new()
+            .import_func(
+                LOG_MODULE_NAME,
+                LOG_FUNCTION_NAME,
+                FuncType::new(vec![ValueType::I32, ValueType::I32, ValueType::I32], vec![]),
+            )
+            .func(
+                FuncType::new(vec![ValueType::I32], vec![]),
+                &[0x20, 0x00, 0x41, 0x10, 0x41, 0x14, 0x10, 0x00, 0x0B],
+            )
+            // Forwards all of its arguments to the log function
+            .func(
+                FuncType::new(vec![ValueType::I32, ValueType::I32, ValueType::I32], vec![]),
+                &[0x20, 0x00, 0x20, 0x01, 0x20, 0x02, 0x10, 0x00, 0x0B],
+            )
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .data(Data::new(
+                0,
+                Expr::new(vec![0x41, 0x10, 0x0B]),
+                message.to_vec(),
+            ))
+            .export("say_hello", ExportDesc::Func(1))
+            .export("log", ExportDesc::Func(2))
+            .export("memory", ExportDesc::Mem(0))
+            .build();
+
+        let instance = Instance::new(resolve_raw_module(module, resolver).unwrap());
+        resolver.attach_instance(&instance).unwrap();
+        instance
+    }
+
+    #[test]
+    fn test_guest_log() {
+        let resolver = LogResolver::new(EmptyResolver::instance());
+        let instance = make_instance(&resolver);
+
+        let subscriber = CollectingSubscriber::default();
+        let events = subscriber.events.clone();
+        tracing::subscriber::with_default(subscriber, || {
+            instance.invoke("say_hello", &[Value::I32(1)]).unwrap();
+            instance.invoke("say_hello", &[Value::I32(9)]).unwrap();
+        });
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (Level::WARN, "hello from the guest".to_string()),
+                (Level::TRACE, "hello from the guest".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_guest_log_capture() {
+        let resolver = LogResolver::new(EmptyResolver::instance());
+        let capture = OutputCapture::new(1024);
+        resolver.capture_output(capture.clone());
+        let instance = make_instance(&resolver);
+
+        let subscriber = CollectingSubscriber::default();
+        let events = subscriber.events.clone();
+        tracing::subscriber::with_default(subscriber, || {
+            instance.invoke("say_hello", &[Value::I32(0)]).unwrap();
+            instance.invoke("say_hello", &[Value::I32(3)]).unwrap();
+        });
+
+        assert!(events.lock().unwrap().is_empty());
+        assert_eq!(
+            String::from_utf8(capture.take().log).unwrap(),
+            "ERROR hello from the guest\nDEBUG hello from the guest\n"
+        );
+    }
+
+    #[test]
+    fn test_guest_log_outside_memory() {
+        let resolver = LogResolver::new(EmptyResolver::instance());
+        let instance = make_instance(&resolver);
+
+        // A message running off the end of the memory traps without being allocated
+        for (ptr, len) in [(16, -1), (65530, 7)] {
+            let error = instance
+                .invoke("log", &[Value::I32(2), Value::I32(ptr), Value::I32(len)])
+                .unwrap_err();
+            assert_eq!(
+                WasmError::trap_kind(&error),
+                Some(TrapKind::MemoryOutOfBounds)
+            );
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Decoding of the LEB128 integers used throughout the binary format. Every width is
+//! decoded by the same function, which checks the encoding is no longer than the width
+//! needs, and that the unused bits of the last byte are zero, or copies of the sign bit
+//! for signed integers.
+
+use anyhow::{anyhow, Result};
+
+/// The most bytes a LEB128 encoding of an integer with this many bits can take
+pub const fn max_length(bits: u32) -> usize {
+    bits.div_ceil(7) as usize
+}
+
+/// Decode a LEB128 integer of `BITS` bits, taking its bytes one at a time from `next_byte`,
+/// and return it along with the number of bytes it took. Signed integers are sign extended
+/// to 64 bits, so casting the result to the signed type of the right width gives the value.
+pub fn decode<const BITS: u32, const SIGNED: bool>(
+    mut next_byte: impl FnMut() -> Result<u8>,
+) -> Result<(u64, usize)> {
+    let mut result: u64 = 0;
+    let mut shift: u32 = 0;
+    let mut length: usize = 0;
+
+    loop {
+        let byte = next_byte()?;
+        let bits = byte & 0x7f;
+        length += 1;
+
+        if length == max_length(BITS) {
+            if byte & 0x80 != 0 {
+                return Err(anyhow!(
+                    "Integer representation too long: LEB128 value is longer than {} bits allow",
+                    BITS
+                ));
+            }
+
+            // The last byte only has room for the top bits of the value. For a signed value
+            // the rest must match the sign bit, which is the highest bit that is used.
+            let used = BITS - shift;
+            let unused = if SIGNED {
+                let high = bits >> (used - 1);
+                high != 0 && high != (0x7f >> (used - 1))
+            } else {
+                bits >> used != 0
+            };
+            if unused {
+                return Err(anyhow!(
+                    "Integer too large: LEB128 value does not fit in {} bits",
+                    BITS
+                ));
+            }
+        }
+
+        result |= u64::from(bits) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            if SIGNED && shift < 64 && bits & 0x40 != 0 {
+                result |= !0 << shift;
+            }
+            return Ok((result, length));
+        }
+    }
+}
+
+/// Decode a LEB128 integer from the start of `bytes`
+pub fn decode_slice<const BITS: u32, const SIGNED: bool>(bytes: &[u8]) -> Result<(u64, usize)> {
+    let mut bytes = bytes.iter();
+    decode::<BITS, SIGNED>(|| {
+        bytes
+            .next()
+            .copied()
+            .ok_or_else(|| anyhow!("LEB128 value is truncated"))
+    })
+}
+
+pub fn read_u32(bytes: &[u8]) -> Result<(u32, usize)> {
+    decode_slice::<32, false>(bytes).map(|(value, length)| (value as u32, length))
+}
+
+pub fn read_i32(bytes: &[u8]) -> Result<(i32, usize)> {
+    decode_slice::<32, true>(bytes).map(|(value, length)| (value as i32, length))
+}
+
+pub fn read_u64(bytes: &[u8]) -> Result<(u64, usize)> {
+    decode_slice::<64, false>(bytes)
+}
+
+pub fn read_i64(bytes: &[u8]) -> Result<(i64, usize)> {
+    decode_slice::<64, true>(bytes).map(|(value, length)| (value as i64, length))
+}
+
+/// Block types are signed 33 bit integers, so that every type index is positive
+pub fn read_s33(bytes: &[u8]) -> Result<(i64, usize)> {
+    decode_slice::<33, true>(bytes).map(|(value, length)| (value as i64, length))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unsigned() {
+        assert_eq!(read_u32(&[0x00]).unwrap(), (0, 1));
+        assert_eq!(read_u32(&[0xE5, 0x8E, 0x26]).unwrap(), (624485, 3));
+        assert_eq!(read_u32(&[0x80, 0x80, 0x00]).unwrap(), (0, 3));
+        assert_eq!(
+            read_u32(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]).unwrap(),
+            (u32::MAX, 5)
+        );
+        assert!(read_u32(&[0xFF, 0xFF, 0xFF, 0xFF, 0x1F]).is_err());
+        assert!(read_u32(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]).is_err());
+        assert!(read_u32(&[0x80, 0x80]).is_err());
+
+        let max = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        assert_eq!(read_u64(&max).unwrap(), (u64::MAX, 10));
+        let mut too_large = max;
+        too_large[9] = 0x02;
+        assert!(read_u64(&too_large).is_err());
+    }
+
+    #[test]
+    fn test_signed() {
+        assert_eq!(read_i32(&[0x7F]).unwrap(), (-1, 1));
+        assert_eq!(read_i32(&[0xC0, 0xBB, 0x78]).unwrap(), (-123456, 3));
+        assert_eq!(read_i32(&[0xFF, 0x7F]).unwrap(), (-1, 2));
+        assert_eq!(
+            read_i32(&[0x80, 0x80, 0x80, 0x80, 0x78]).unwrap(),
+            (i32::MIN, 5)
+        );
+        assert_eq!(
+            read_i32(&[0xFF, 0xFF, 0xFF, 0xFF, 0x07]).unwrap(),
+            (i32::MAX, 5)
+        );
+        // The unused bits of the last byte must all match the sign bit
+        assert!(read_i32(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]).is_err());
+        assert!(read_i32(&[0x80, 0x80, 0x80, 0x80, 0x70]).is_err());
+
+        assert_eq!(
+            read_i64(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7F]).unwrap(),
+            (i64::MIN, 10)
+        );
+        assert!(read_i64(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]).is_err());
+
+        assert_eq!(read_s33(&[0x40]).unwrap(), (-64, 1));
+        assert_eq!(
+            read_s33(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]).unwrap(),
+            (u32::MAX as i64, 5)
+        );
+        assert!(read_s33(&[0xFF, 0xFF, 0xFF, 0xFF, 0x1F]).is_err());
+    }
+}
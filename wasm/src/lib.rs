@@ -1,3 +1,24 @@
+/// Charge the allocations made by an expression to a category, when allocation accounting
+/// is enabled
+macro_rules! accounted {
+    ($category:ident, $body:expr) => {{
+        #[cfg(feature = "alloc-accounting")]
+        let _scope = crate::alloc_accounting::AllocScope::enter(
+            crate::alloc_accounting::AllocCategory::$category,
+        );
+        $body
+    }};
+}
+
+#[cfg(feature = "alloc-accounting")]
+pub mod alloc_accounting;
 pub mod core;
+pub mod guest_log;
+pub mod leb;
+pub mod output_capture;
 pub mod parser;
 pub mod reader;
+#[cfg(feature = "wast")]
+pub mod spec_test;
+pub mod wasi;
+pub mod writer;
@@ -1,19 +1,685 @@
-mod core;
-mod parser;
-mod reader;
-
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
 use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::{Duration, Instant};
+use wasm::{core, parser, parser::Feature, reader, reader::TypeReader, wasi};
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+fn print_usage() {
+    println!("wasm [--stub-imports] [mod_name]");
+    println!("wasm scan [--unsupported] [mod_name]");
+    println!("wasm analyze [--lint] [--resources] [mod_name]");
+    println!("wasm inspect [--disassemble] [mod_name]");
+    println!("wasm diff [old_mod_name] [new_mod_name]");
+    println!("wasm invoke [--stub-imports] [--time] [--profile] [--fuel=N]");
+    println!("            [--start=NAME | --no-start] [mod_name] [func_name] [args...]");
+    println!("wasm run [--stub-imports] [--time] [mod_name] [args...]");
+    if cfg!(feature = "wast") {
+        println!("wasm wast [script...]");
+    }
+}
+
+fn find_mod_name(args: &[String]) -> Option<&String> {
+    args.iter().find(|arg| !arg.starts_with("--"))
+}
+
+fn parse_value(text: &str, value_type: &core::ValueType) -> Result<core::Value> {
+    let value = match value_type {
+        core::ValueType::I32 => text.parse::<i32>().map(core::Value::from).ok(),
+        core::ValueType::I64 => text.parse::<i64>().map(core::Value::from).ok(),
+        core::ValueType::F32 => text.parse::<f32>().map(core::Value::from).ok(),
+        core::ValueType::F64 => text.parse::<f64>().map(core::Value::from).ok(),
+        core::ValueType::FuncRef => parse_ref(text).map(core::Value::FuncRef),
+        core::ValueType::ExternRef => parse_ref(text).map(core::Value::ExternRef),
+    };
+
+    value.ok_or_else(|| anyhow!("Cannot parse \"{}\" as {:?}", text, value_type))
+}
+
+// References are given as an index, or as "null"
+fn parse_ref(text: &str) -> Option<Option<u32>> {
+    match text {
+        "null" => Some(None),
+        _ => text.parse::<u32>().map(Some).ok(),
+    }
+}
+
+fn time<R>(total: &mut Duration, func: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let result = func();
+    *total += start.elapsed();
+    result
+}
+
+// How long each stage of running a module took, for --time
+#[derive(Default)]
+struct StageTimes {
+    load: Duration,
+    validate: Duration,
+    instantiate: Duration,
+    execute: Duration,
+}
+
+impl StageTimes {
+    fn print(&self) {
+        println!("load:        {:?}", self.load);
+        println!("validate:    {:?}", self.validate);
+        println!("instantiate: {:?}", self.instantiate);
+        println!("execute:     {:?}", self.execute);
+    }
+}
+
+fn instantiate_timed(
+    mod_name: &str,
+    resolver: &impl core::Resolver,
+    start: &core::StartFunction,
+    times: &mut StageTimes,
+) -> Result<core::Instance> {
+    let raw_module = time(&mut times.load, || -> Result<core::RawModule> {
+        let mut buf = BufReader::new(File::open(mod_name)?);
+        core::RawModule::read(&mut buf)
+    })
+    .with_context(|| format!("Failed to read module from {}", mod_name))?;
+    let validated_module = time(&mut times.validate, || {
+        core::validate_raw_module(raw_module, resolver)
+    })?;
+    Ok(core::Instance::new(time(&mut times.instantiate, || {
+        validated_module.instantiate_with_start(start)
+    })?))
+}
+
+fn invoke_with_resolver(
+    args: &[&String],
+    resolver: &impl core::Resolver,
+    print_times: bool,
+    profile: bool,
+    fuel: Option<u64>,
+    start: &core::StartFunction,
+) -> Result<()> {
+    let (mod_name, func_name, func_args) = match args {
+        [mod_name, func_name, func_args @ ..] => (mod_name, func_name, func_args),
+        _ => {
+            print_usage();
+            return Ok(());
+        }
+    };
+
+    let mut times = StageTimes::default();
+    let mut instance = instantiate_timed(mod_name, resolver, start, &mut times)?;
+    if profile {
+        instance.enable_profiling();
+    }
+
+    let arg_types = match instance.get_export(func_name) {
+        Some(core::ExportValue::Function(callable)) => {
+            callable.borrow().func_type().arg_types().clone()
+        }
+        _ => {
+            let functions: Vec<&str> = instance
+                .exports()
+                .filter(|export| export.kind == core::ExportKind::Func)
+                .map(|export| export.name)
+                .collect();
+            return Err(anyhow!(
+                "{} does not export a function {}. The functions it exports are: {}",
+                mod_name,
+                func_name,
+                functions.join(", ")
+            ));
+        }
+    };
+    if arg_types.len() != func_args.len() {
+        return Err(anyhow!(
+            "{} takes {} arguments but {} were supplied",
+            func_name,
+            arg_types.len(),
+            func_args.len()
+        ));
+    }
+    let values = func_args
+        .iter()
+        .zip(arg_types.iter())
+        .map(|(text, value_type)| parse_value(text, value_type))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut config = core::ExecutionConfig { fuel };
+    let results = time(&mut times.execute, || {
+        instance.invoke_with_config(func_name, &values, &mut config)
+    });
+    if let (Some(fuel), Some(remaining)) = (fuel, config.fuel) {
+        println!("fuel used: {}", fuel - remaining);
+    }
+    for result in results? {
+        println!("{:?}", result);
+    }
+
+    if let Some(profile) = instance.take_profile() {
+        println!("function     calls  instructions  time");
+        for function in profile.functions {
+            let name = match function.func_idx {
+                Some(func_idx) => func_idx.to_string(),
+                None => "host".to_string(),
+            };
+            println!(
+                "{:<8} {:>9} {:>13}  {:?}",
+                name, function.calls, function.instructions, function.time
+            );
+        }
+    }
+
+    if print_times {
+        times.print();
+    }
+
+    Ok(())
+}
+
+fn invoke(args: &[String]) -> Result<()> {
+    let stub_imports = args.iter().any(|arg| arg == "--stub-imports");
+    let print_times = args.iter().any(|arg| arg == "--time");
+    let profile = args.iter().any(|arg| arg == "--profile");
+    let fuel = match args.iter().find_map(|arg| arg.strip_prefix("--fuel=")) {
+        Some(fuel) => Some(
+            fuel.parse::<u64>()
+                .with_context(|| format!("Invalid fuel \"{}\"", fuel))?,
+        ),
+        None => None,
+    };
+    let start = match args.iter().find_map(|arg| arg.strip_prefix("--start=")) {
+        Some(name) => core::StartFunction::Export(name.to_string()),
+        None if args.iter().any(|arg| arg == "--no-start") => core::StartFunction::Suppressed,
+        None => core::StartFunction::Declared,
+    };
+    let positional: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
 
-    if args.len() < 2 {
-        println!("wasm [mod_name]");
+    if stub_imports {
+        let resolver = core::StubResolver::new(core::EmptyResolver::instance());
+        invoke_with_resolver(&positional, &resolver, print_times, profile, fuel, &start)
     } else {
-        core::load_module_from_path(&args[1], core::EmptyResolver::instance())
-            .with_context(|| format!("Failed to read module from {}", &args[1]))?;
+        invoke_with_resolver(
+            &positional,
+            core::EmptyResolver::instance(),
+            print_times,
+            profile,
+            fuel,
+            &start,
+        )
+    }
+}
+
+fn load(args: &[String]) -> Result<()> {
+    let stub_imports = args.iter().any(|arg| arg == "--stub-imports");
+
+    match find_mod_name(args) {
+        None => print_usage(),
+        Some(mod_name) if stub_imports => {
+            let resolver = core::StubResolver::new(core::EmptyResolver::instance());
+            core::load_module_from_path(mod_name, &resolver)
+                .with_context(|| format!("Failed to read module from {}", mod_name))?;
+
+            for import in resolver.stubbed_imports() {
+                println!("Stubbed missing import {}", import);
+            }
+        }
+        Some(mod_name) => {
+            core::load_module_from_path(mod_name, core::EmptyResolver::instance())
+                .with_context(|| format!("Failed to read module from {}", mod_name))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn scan(args: &[String]) -> Result<()> {
+    let unsupported_only = args.iter().any(|arg| arg == "--unsupported");
+
+    let mod_name = match find_mod_name(args) {
+        Some(mod_name) => mod_name,
+        None => {
+            print_usage();
+            return Ok(());
+        }
+    };
+
+    let usages = reader::scan_module_from_path(mod_name)
+        .with_context(|| format!("Failed to scan module {}", mod_name))?;
+
+    // Group the usages by what was used, keeping the locations in module order
+    let mut grouped: BTreeMap<(Feature, String), (bool, usize, Vec<String>)> = BTreeMap::new();
+    for usage in usages {
+        if unsupported_only && usage.supported {
+            continue;
+        }
+
+        let (_, count, locations) =
+            grouped
+                .entry((usage.feature, usage.name))
+                .or_insert((usage.supported, 0, Vec::new()));
+        let location = usage.location.to_string();
+        *count += 1;
+        if !locations.contains(&location) {
+            locations.push(location);
+        }
+    }
+
+    if grouped.is_empty() {
+        println!(
+            "{} uses no features beyond the MVP that need reporting",
+            mod_name
+        );
+        return Ok(());
+    }
+
+    let mut current_feature = None;
+    for ((feature, name), (supported, count, locations)) in grouped {
+        if current_feature != Some(feature) {
+            println!("{}", feature);
+            current_feature = Some(feature);
+        }
+
+        println!(
+            "  {}{}: {} use{} in {}",
+            name,
+            if supported { "" } else { " (unsupported)" },
+            count,
+            if count == 1 { "" } else { "s" },
+            locations.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn print_resources(mod_name: &str) -> Result<()> {
+    let mut buf = BufReader::new(File::open(mod_name)?);
+    let requirements = core::required_resources(&core::RawModule::read(&mut buf)?)?;
+
+    let describe_range = |minimum: usize, maximum: Option<usize>| match maximum {
+        Some(maximum) => format!("{} to {}", minimum, maximum),
+        None => format!("at least {}", minimum),
+    };
+    for memory in requirements.memories.iter() {
+        println!(
+            "memory {}.{}: {} pages",
+            memory.mod_name,
+            memory.name,
+            describe_range(memory.minimum, memory.maximum)
+        );
+    }
+    for table in requirements.tables.iter() {
+        println!(
+            "table {}.{}: {} elements",
+            table.mod_name,
+            table.name,
+            describe_range(table.minimum, table.maximum)
+        );
+    }
+    for (func_type, count) in requirements.host_functions.iter() {
+        println!(
+            "{} host function{} of type {:?} -> {:?}",
+            count,
+            if *count == 1 { "" } else { "s" },
+            func_type.arg_types(),
+            func_type.return_types()
+        );
+    }
+    println!(
+        "{} pages of memory and {} table elements in total",
+        requirements.total_memory_pages(),
+        requirements.defined_table_elements
+            + requirements
+                .tables
+                .iter()
+                .map(|table| table.minimum)
+                .sum::<usize>()
+    );
+
+    Ok(())
+}
+
+fn print_lint_findings(mod_name: &str) -> Result<()> {
+    let findings = core::lint_module_from_path(mod_name)?;
+
+    if findings.is_empty() {
+        println!("{} has nothing to report", mod_name);
+    }
+    for finding in findings {
+        println!("{}", finding);
+    }
+
+    Ok(())
+}
+
+fn analyze(args: &[String]) -> Result<()> {
+    let lint = args.iter().any(|arg| arg == "--lint");
+    let resources = args.iter().any(|arg| arg == "--resources");
+
+    let mod_name = match find_mod_name(args) {
+        Some(mod_name) if lint || resources => mod_name,
+        _ => {
+            print_usage();
+            return Ok(());
+        }
+    };
+
+    if resources {
+        print_resources(mod_name)
+            .with_context(|| format!("Failed to read module from {}", mod_name))?;
+    }
+    if lint {
+        print_lint_findings(mod_name)
+            .with_context(|| format!("Failed to lint module {}", mod_name))?;
+    }
+
+    Ok(())
+}
+
+fn describe_limits(limits: &core::Limits) -> String {
+    match limits.maximum() {
+        Some(maximum) => format!("{} to {}", limits.minimum(), maximum),
+        None => format!("at least {}", limits.minimum()),
+    }
+}
+
+fn describe_func_type(func_type: &core::FuncType) -> String {
+    format!(
+        "{:?} -> {:?}",
+        func_type.arg_types(),
+        func_type.return_types()
+    )
+}
+
+fn describe_global_type(global_type: &core::GlobalType) -> String {
+    format!(
+        "{}{:?}",
+        if global_type.is_mutable() { "mut " } else { "" },
+        global_type.value_type()
+    )
+}
+
+fn describe_mem_type(mem_type: &core::MemType) -> String {
+    format!(
+        "{} pages{}",
+        describe_limits(mem_type.limits()),
+        if mem_type.is_shared() { ", shared" } else { "" }
+    )
+}
+
+fn describe_table_type(table_type: &core::TableType) -> String {
+    format!(
+        "{:?}, {} elements",
+        table_type.elem_type(),
+        describe_limits(table_type.limits())
+    )
+}
+
+fn print_disassembly(expr: &core::Expr) {
+    match parser::disassemble(expr) {
+        Ok(lines) => {
+            for line in lines {
+                println!("    {}", line);
+            }
+        }
+        Err(e) => println!("    cannot disassemble: {:#}", e),
+    }
+}
+
+// Print the sections as they were read, without validating them, so that modules which
+// fail to load can be looked at as well
+fn print_module(module: &core::RawModule, disassemble: bool) {
+    let func_type = |type_idx: usize| match module.types().get(type_idx) {
+        Some(func_type) => describe_func_type(func_type),
+        None => format!("unknown type {}", type_idx),
+    };
+
+    println!("types:");
+    for (idx, func_type) in module.types().iter().enumerate() {
+        println!("  {}: {}", idx, describe_func_type(func_type));
+    }
+
+    // Imports come first in each index space, so they are numbered along with the
+    // definitions that follow them
+    println!("imports:");
+    let (mut imported_funcs, mut imported_tables, mut imported_mems, mut imported_globals) =
+        (0, 0, 0, 0);
+    for import in module.imports() {
+        let desc = match import.desc() {
+            core::ImportDesc::TypeIdx(type_idx) => {
+                imported_funcs += 1;
+                format!("func {}: {}", imported_funcs - 1, func_type(*type_idx))
+            }
+            core::ImportDesc::TableType(table_type) => {
+                imported_tables += 1;
+                format!(
+                    "table {}: {}",
+                    imported_tables - 1,
+                    describe_table_type(table_type)
+                )
+            }
+            core::ImportDesc::MemType(mem_type) => {
+                imported_mems += 1;
+                format!(
+                    "memory {}: {}",
+                    imported_mems - 1,
+                    describe_mem_type(mem_type)
+                )
+            }
+            core::ImportDesc::GlobalType(global_type) => {
+                imported_globals += 1;
+                format!(
+                    "global {}: {}",
+                    imported_globals - 1,
+                    describe_global_type(global_type)
+                )
+            }
+        };
+        println!("  {}.{}: {}", import.mod_name(), import.name(), desc);
+    }
+
+    println!("functions:");
+    for (idx, (type_idx, func)) in module.typeidx().iter().zip(module.funcs()).enumerate() {
+        println!("  {}: {}", imported_funcs + idx, func_type(*type_idx));
+        if !func.locals().is_empty() {
+            let locals: Vec<String> = func
+                .locals()
+                .iter()
+                .map(|locals| format!("{} x {:?}", locals.count(), locals.value_type()))
+                .collect();
+            println!("    locals: {}", locals.join(", "));
+        }
+        if disassemble {
+            print_disassembly(func.expr());
+        }
+    }
+
+    println!("tables:");
+    for (idx, table_type) in module.tables().iter().enumerate() {
+        println!(
+            "  {}: {}",
+            imported_tables + idx,
+            describe_table_type(table_type)
+        );
+    }
+
+    println!("memories:");
+    for (idx, mem_type) in module.mems().iter().enumerate() {
+        println!("  {}: {}", imported_mems + idx, describe_mem_type(mem_type));
+    }
+
+    println!("globals:");
+    for (idx, global) in module.globals().iter().enumerate() {
+        println!(
+            "  {}: {}",
+            imported_globals + idx,
+            describe_global_type(global.global_type())
+        );
+        if disassemble {
+            print_disassembly(global.init_expr());
+        }
+    }
+
+    println!("exports:");
+    for export in module.exports() {
+        let desc = match export.d {
+            core::ExportDesc::Func(idx) => format!("func {}", idx),
+            core::ExportDesc::Table(idx) => format!("table {}", idx),
+            core::ExportDesc::Mem(idx) => format!("memory {}", idx),
+            core::ExportDesc::Global(idx) => format!("global {}", idx),
+        };
+        println!("  {}: {}", export.nm, desc);
     }
 
+    if let Some(start) = module.start() {
+        println!("start: func {}", start);
+    }
+    println!("element segments: {}", module.elem().len());
+    println!("data segments: {}", module.data().len());
+}
+
+fn inspect(args: &[String]) -> Result<()> {
+    let disassemble = args.iter().any(|arg| arg == "--disassemble");
+
+    let mod_name = match find_mod_name(args) {
+        Some(mod_name) => mod_name,
+        None => {
+            print_usage();
+            return Ok(());
+        }
+    };
+
+    let mut buf = BufReader::new(
+        File::open(mod_name).with_context(|| format!("Failed to open module {}", mod_name))?,
+    );
+    let module = core::RawModule::read(&mut buf)
+        .with_context(|| format!("Failed to read module from {}", mod_name))?;
+    print_module(&module, disassemble);
+
     Ok(())
 }
+
+fn diff(args: &[String]) -> Result<()> {
+    let (old, new) = match args {
+        [old, new] => (old, new),
+        _ => {
+            print_usage();
+            return Ok(());
+        }
+    };
+
+    let changes = core::diff_modules_from_paths(old, new)
+        .with_context(|| format!("Failed to compare {} with {}", old, new))?;
+    if changes.is_empty() {
+        println!("{} and {} have the same structure", old, new);
+    }
+    for change in changes {
+        println!("{}", change);
+    }
+
+    Ok(())
+}
+
+fn run_with_resolver(
+    mod_name: &str,
+    program_args: &[String],
+    inner: &impl core::Resolver,
+    print_times: bool,
+) -> Result<()> {
+    // The program sees its own name as the first argument, just like a native one would
+    let mut wasi_args = vec![mod_name.to_string()];
+    wasi_args.extend_from_slice(program_args);
+    let resolver = wasi::WasiResolver::new(inner, wasi_args, Vec::new());
+
+    let mut times = StageTimes::default();
+    let instance = instantiate_timed(
+        mod_name,
+        &resolver,
+        &core::StartFunction::Declared,
+        &mut times,
+    )?;
+    resolver.attach_instance(&instance)?;
+
+    let result = time(&mut times.execute, || instance.invoke("_start", &[]));
+    // The times are still wanted when the program exits through proc_exit
+    if print_times {
+        times.print();
+    }
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => match e.downcast_ref::<wasi::ProcExit>() {
+            Some(wasi::ProcExit(code)) => std::process::exit(*code),
+            None => Err(e),
+        },
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    // Everything after the module name belongs to the program being run
+    let (options, mod_name, program_args) = match args.iter().position(|arg| !arg.starts_with("--"))
+    {
+        Some(idx) => (&args[0..idx], &args[idx], &args[idx + 1..]),
+        None => {
+            print_usage();
+            return Ok(());
+        }
+    };
+
+    let print_times = options.iter().any(|arg| arg == "--time");
+    if options.iter().any(|arg| arg == "--stub-imports") {
+        let resolver = core::StubResolver::new(core::EmptyResolver::instance());
+        run_with_resolver(mod_name, program_args, &resolver, print_times)
+    } else {
+        run_with_resolver(
+            mod_name,
+            program_args,
+            core::EmptyResolver::instance(),
+            print_times,
+        )
+    }
+}
+
+#[cfg(feature = "wast")]
+fn wast(scripts: &[String]) -> Result<()> {
+    if scripts.is_empty() {
+        print_usage();
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for script in scripts {
+        let report = wasm::spec_test::run_script_from_path(script)?;
+        for failure in &report.failures {
+            println!("{}: {}", script, failure);
+        }
+        println!(
+            "{}: {} passed, {} failed, {} skipped",
+            script,
+            report.passed,
+            report.failures.len(),
+            report.skipped
+        );
+        failed += report.failures.len();
+    }
+
+    if failed > 0 {
+        Err(anyhow!("{} commands failed", failed))
+    } else {
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("scan") => scan(&args[1..]),
+        Some("analyze") => analyze(&args[1..]),
+        Some("inspect") => inspect(&args[1..]),
+        Some("diff") => diff(&args[1..]),
+        Some("invoke") => invoke(&args[1..]),
+        Some("run") => run(&args[1..]),
+        #[cfg(feature = "wast")]
+        Some("wast") => wast(&args[1..]),
+        _ => load(&args),
+    }
+}
@@ -0,0 +1,120 @@
+//! Collects what a guest writes, through WASI or the `wasm_interp:log` import, in memory
+//! rather than passing it on to the host's stdio and tracing subscriber. A service can
+//! then send a guest's output back in its response:
+//!
+//! ```ignore
+//! let capture = OutputCapture::new(64 * 1024);
+//! let resolver = WasiResolver::new(EmptyResolver::instance(), args, env);
+//! resolver.capture_output(capture.clone());
+//! // ... instantiate the module and call it ...
+//! let output = capture.take();
+//! ```
+//!
+//! Each instance should have its own capture, since it can't tell apart the output of
+//! instances that share one.
+
+use crate::core::{handle, Handle};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+    /// Messages from `wasm_interp:log`, one line each, starting with the level
+    Log,
+}
+
+/// Everything captured since the last `OutputCapture::take`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CapturedOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub log: Vec<u8>,
+    /// How many bytes were thrown away because the limit had been reached
+    pub dropped_bytes: usize,
+}
+
+impl CapturedOutput {
+    pub fn is_truncated(&self) -> bool {
+        self.dropped_bytes > 0
+    }
+
+    fn len(&self) -> usize {
+        self.stdout.len() + self.stderr.len() + self.log.len()
+    }
+}
+
+#[derive(Debug)]
+struct CaptureBuffer {
+    limit: usize,
+    output: CapturedOutput,
+}
+
+/// A buffer for the output of one instance, which clones share. The limit is on the bytes
+/// held across all the streams, and anything written once it is reached is dropped, so a
+/// guest can't use up the host's memory by writing in a loop. The guest is still told
+/// that its writes succeeded.
+#[derive(Debug, Clone)]
+pub struct OutputCapture {
+    buffer: Handle<CaptureBuffer>,
+}
+
+impl OutputCapture {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            buffer: handle(CaptureBuffer {
+                limit,
+                output: CapturedOutput::default(),
+            }),
+        }
+    }
+
+    /// Keep as much of `bytes` as fits under the limit. Host functions provided by the
+    /// embedder can write here too, so that all of a guest's output ends up together.
+    pub fn write(&self, stream: OutputStream, bytes: &[u8]) {
+        let mut buffer = self.buffer.borrow_mut();
+        let room = buffer.limit.saturating_sub(buffer.output.len());
+        let kept = bytes.len().min(room);
+
+        let output = &mut buffer.output;
+        output.dropped_bytes += bytes.len() - kept;
+        let destination = match stream {
+            OutputStream::Stdout => &mut output.stdout,
+            OutputStream::Stderr => &mut output.stderr,
+            OutputStream::Log => &mut output.log,
+        };
+        destination.extend_from_slice(&bytes[..kept]);
+    }
+
+    /// Hand over what has been captured so far and start again with an empty buffer and
+    /// the whole of the limit
+    pub fn take(&self) -> CapturedOutput {
+        std::mem::take(&mut self.buffer.borrow_mut().output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_capture_limit() {
+        let capture = OutputCapture::new(10);
+        capture.clone().write(OutputStream::Stdout, b"hello");
+        capture.write(OutputStream::Stderr, b"oops");
+        capture.write(OutputStream::Stdout, b" world");
+
+        let output = capture.take();
+        assert_eq!(output.stdout, b"hello ");
+        assert_eq!(output.stderr, b"oops");
+        assert_eq!(output.dropped_bytes, 5);
+        assert!(output.is_truncated());
+
+        // Taking the output frees up the limit again
+        capture.write(OutputStream::Log, b"INFO done\n");
+        let output = capture.take();
+        assert_eq!(output.log, b"INFO done\n");
+        assert!(!output.is_truncated());
+        assert_eq!(capture.take(), CapturedOutput::default());
+    }
+}
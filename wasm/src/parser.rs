@@ -1,13 +1,26 @@
+mod disassembler;
 mod expression_reader;
+mod extension;
 mod instruction_accumulator;
 mod instruction_category;
 mod instruction_iterator;
 mod opcode;
+mod opcode_catalog;
 
-pub use expression_reader::read_expression_bytes;
+pub use disassembler::disassemble;
+pub use expression_reader::{read_expression_bytes, read_limited_expression_bytes};
+pub use extension::{
+    extension_category, lookup_extension, register_extension, unregister_extension,
+    InstructionExtension,
+};
 pub use instruction_accumulator::{
     make_slice_accumulator, InstructionAccumulator, SliceInstructionAccumulator,
 };
-pub use instruction_category::{InstructionCategory, InstructionData};
-pub use instruction_iterator::{Instruction, InstructionSource};
-pub use opcode::Opcode;
+pub use instruction_category::{BranchTableTargets, InstructionCategory, InstructionData, MemArg};
+pub use instruction_iterator::{
+    Instruction, InstructionImmediates, InstructionIterator, InstructionSource,
+};
+pub use opcode::{AtomicOpcode, InvalidOpcode, MiscOpcode, Opcode};
+pub use opcode_catalog::{
+    is_prefix_byte, lookup_opcode, Feature, Immediates, OpcodeInfo, EXTENSION_PREFIX,
+};
@@ -0,0 +1,128 @@
+use anyhow::Result;
+
+use crate::core::BlockType;
+use crate::parser::{
+    lookup_extension, Instruction, InstructionImmediates, InstructionSource, Opcode,
+};
+
+const INDENT: &str = "  ";
+
+fn instruction_name(instruction: &Instruction) -> String {
+    if let Some(misc_opcode) = instruction.try_misc_opcode() {
+        format!("{:?}", misc_opcode)
+    } else if let Some(atomic_opcode) = instruction.try_atomic_opcode() {
+        format!("{:?}", atomic_opcode)
+    } else if let Some(sub_opcode) = instruction.try_extension_opcode() {
+        match lookup_extension(sub_opcode) {
+            Ok(extension) => extension.name(sub_opcode),
+            Err(_) => format!("Extension 0x{:X}", sub_opcode),
+        }
+    } else {
+        format!("{:?}", instruction.opcode())
+    }
+}
+
+fn describe_immediates(immediates: &InstructionImmediates) -> Option<String> {
+    match immediates {
+        InstructionImmediates::None => None,
+        InstructionImmediates::Index(index) => Some(index.to_string()),
+        InstructionImmediates::I32(value) => Some(value.to_string()),
+        InstructionImmediates::I64(value) => Some(value.to_string()),
+        InstructionImmediates::F32(value) => Some(value.to_string()),
+        InstructionImmediates::F64(value) => Some(value.to_string()),
+        InstructionImmediates::Pair(first, second) => Some(format!("{} {}", first, second)),
+        InstructionImmediates::MemArg(mem_arg) if mem_arg.memory() != 0 => Some(format!(
+            "memory={} offset={} align={}",
+            mem_arg.memory(),
+            mem_arg.offset(),
+            1 << mem_arg.align()
+        )),
+        InstructionImmediates::MemArg(mem_arg) => Some(format!(
+            "offset={} align={}",
+            mem_arg.offset(),
+            1 << mem_arg.align()
+        )),
+        InstructionImmediates::BranchTable(targets) => Some(
+            targets
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        InstructionImmediates::Block {
+            block_type: BlockType::None,
+            ..
+        } => None,
+        InstructionImmediates::Block { block_type, .. } => Some(format!("{:?}", block_type)),
+    }
+}
+
+fn disassemble_into(bytes: &[u8], depth: usize, lines: &mut Vec<String>) -> Result<()> {
+    for instruction in InstructionSource::iter(bytes) {
+        let instruction = instruction?;
+        let immediates = instruction.immediates();
+
+        let mut line = INDENT.repeat(depth) + &instruction_name(&instruction);
+        if let Some(description) = describe_immediates(&immediates) {
+            line.push(' ');
+            line.push_str(&description);
+        }
+        lines.push(line);
+
+        if let InstructionImmediates::Block {
+            block, else_block, ..
+        } = immediates
+        {
+            disassemble_into(block, depth + 1, lines)?;
+            if let Some(else_block) = else_block {
+                lines.push(INDENT.repeat(depth) + &format!("{:?}", Opcode::Else));
+                disassemble_into(else_block, depth + 1, lines)?;
+            }
+            lines.push(INDENT.repeat(depth) + &format!("{:?}", Opcode::End));
+        }
+    }
+    Ok(())
+}
+
+/// One line for each instruction in an expression, with the instructions inside blocks
+/// indented beneath them. The end of the expression itself isn't included.
+pub fn disassemble(expr: &impl InstructionSource) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    disassemble_into(expr.get_instruction_bytes(), 0, &mut lines)?;
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let expr: &[u8] = &[
+            0x41, 0x7B, // i32.const -5
+            0x04, 0x7F, 0x41, 0x01, 0x05, 0x28, 0x02, 0x08, 0x0B, // if i32 ... else ... end
+            0x02, 0x40, 0x0E, 0x01, 0x00, 0x01, 0x0B, // block br_table 0 1 end
+            0xFC, 0x0A, 0x00, 0x00, // memory.copy 0 0
+            0x0B,
+        ];
+
+        assert_eq!(
+            disassemble(&expr).unwrap(),
+            vec![
+                "I32Const -5",
+                "If I32",
+                "  I32Const 1",
+                "Else",
+                "  I32Load offset=8 align=4",
+                "End",
+                "Block",
+                "  BrTable 0 1",
+                "End",
+                "MemoryCopy 0 0",
+            ]
+        );
+
+        // A truncated instruction is an error rather than a panic
+        assert!(disassemble(&[0x41u8]).is_err());
+    }
+}
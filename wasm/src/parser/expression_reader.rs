@@ -1,4 +1,4 @@
-use crate::parser::{InstructionAccumulator, InstructionCategory};
+use crate::parser::{InstructionAccumulator, InstructionCategory, InvalidOpcode};
 use anyhow;
 use std::io;
 use std::io::prelude::*;
@@ -7,17 +7,19 @@ struct ReaderInstructionAccumulator<'a, T: Read> {
     reader: &'a mut T, // Where we get the instructions from
     buf: Vec<u8>,      // We accumulate the instructions in here
     next_inst: usize,  // The position of the next instruction byte in the buffer
+    max_branch_table_targets: u32,
 }
 
 impl<'a, T> ReaderInstructionAccumulator<'a, T>
 where
     T: Read,
 {
-    pub fn new(reader: &'a mut T) -> Self {
+    pub fn new(reader: &'a mut T, max_branch_table_targets: u32) -> Self {
         Self {
             reader: reader,
             buf: Vec::new(),
             next_inst: 0,
+            max_branch_table_targets,
         }
     }
 
@@ -29,8 +31,11 @@ where
         self.ensure_bytes(1)?;
 
         let lead_byte = self.get_byte(0);
-        let instruction_category = InstructionCategory::from_lead_byte(lead_byte)?;
-        instruction_category.ensure_instruction(self, 0)?;
+        // Offsets in the instruction count from its start, so count them from the start of
+        // the expression instead
+        let instruction_category = InstructionCategory::from_lead_byte(lead_byte, 0)
+            .and_then(|category| category.ensure_instruction(self, 0).map(|_| category))
+            .map_err(|e| InvalidOpcode::rebase(e, self.next_inst))?;
 
         Ok(instruction_category != InstructionCategory::End)
     }
@@ -68,10 +73,23 @@ where
 
         &self.buf[self.next_inst + idx..self.next_inst + idx + length]
     }
+
+    fn max_branch_table_targets(&self) -> u32 {
+        self.max_branch_table_targets
+    }
 }
 
 pub fn read_expression_bytes<T: Read>(reader: &mut T) -> anyhow::Result<Vec<u8>> {
-    let mut acc = ReaderInstructionAccumulator::new(reader);
+    read_limited_expression_bytes(reader, u32::MAX)
+}
+
+/// Read an expression, failing as soon as a br_table has more than
+/// `max_branch_table_targets` targets
+pub fn read_limited_expression_bytes<T: Read>(
+    reader: &mut T,
+    max_branch_table_targets: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let mut acc = ReaderInstructionAccumulator::new(reader, max_branch_table_targets);
 
     while acc.move_to_next()? {
         // Nothing in here - we're just accumulating the instructions
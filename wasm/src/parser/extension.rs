@@ -0,0 +1,232 @@
+//! Instructions defined by the embedder rather than by any proposal, so that new ones can
+//! be tried out without changing the interpreter. They all start with the 0xFF prefix,
+//! which no proposal uses, followed by a LEB sub opcode. An extension claims a range of
+//! sub opcodes, and is asked how to decode, check and execute each instruction in it.
+//!
+//! The registry is shared by the whole process, because modules can be read and run on
+//! any thread. Register extensions before reading the modules that use them.
+
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, RwLock};
+
+use crate::core::{compiled::CompiledImmediates, DataStore, Stack};
+use crate::parser::InstructionCategory;
+
+pub trait InstructionExtension: Send + Sync {
+    /// The name of the instruction, which the scanner reports
+    fn name(&self, sub_opcode: u32) -> String;
+
+    /// The immediates which follow the sub opcode. Blocks, branch tables and prefixes are
+    /// not allowed, so extension instructions can't affect control flow.
+    fn immediates(&self, sub_opcode: u32) -> InstructionCategory;
+
+    /// Check an instruction when the function containing it is loaded
+    fn validate(&self, _sub_opcode: u32, _immediates: &CompiledImmediates) -> Result<()> {
+        Ok(())
+    }
+
+    fn execute(
+        &self,
+        sub_opcode: u32,
+        immediates: &CompiledImmediates,
+        stack: &mut Stack,
+        store: &mut dyn DataStore,
+    ) -> Result<()>;
+}
+
+struct Registration {
+    last_sub_opcode: u32,
+    extension: Arc<dyn InstructionExtension>,
+}
+
+// Keyed by the first sub opcode of each range
+static EXTENSIONS: RwLock<BTreeMap<u32, Registration>> = RwLock::new(BTreeMap::new());
+
+/// Handle the sub opcodes in the range with the extension. The range must not overlap one
+/// which is already registered.
+pub fn register_extension(
+    sub_opcodes: RangeInclusive<u32>,
+    extension: Arc<dyn InstructionExtension>,
+) -> Result<()> {
+    let (first, last) = sub_opcodes.into_inner();
+    if first > last {
+        return Err(anyhow!("Extension sub opcode range is empty"));
+    }
+
+    let mut extensions = EXTENSIONS.write().unwrap();
+    let overlapping = extensions
+        .range(..=last)
+        .next_back()
+        .filter(|(_, registration)| registration.last_sub_opcode >= first);
+    if let Some((existing_first, existing)) = overlapping {
+        return Err(anyhow!(
+            "Extension sub opcodes 0x{:X}..=0x{:X} overlap 0x{:X}..=0x{:X}, which are already registered",
+            first,
+            last,
+            existing_first,
+            existing.last_sub_opcode
+        ));
+    }
+
+    extensions.insert(
+        first,
+        Registration {
+            last_sub_opcode: last,
+            extension,
+        },
+    );
+    Ok(())
+}
+
+/// Remove the extension whose range starts at the sub opcode. Returns false if there
+/// wasn't one.
+pub fn unregister_extension(first_sub_opcode: u32) -> bool {
+    EXTENSIONS
+        .write()
+        .unwrap()
+        .remove(&first_sub_opcode)
+        .is_some()
+}
+
+pub fn lookup_extension(sub_opcode: u32) -> Result<Arc<dyn InstructionExtension>> {
+    EXTENSIONS
+        .read()
+        .unwrap()
+        .range(..=sub_opcode)
+        .next_back()
+        .filter(|(_, registration)| registration.last_sub_opcode >= sub_opcode)
+        .map(|(_, registration)| registration.extension.clone())
+        .ok_or_else(|| {
+            anyhow!(
+                "No extension is registered for instruction 0xFF 0x{:X}",
+                sub_opcode
+            )
+        })
+}
+
+/// The category describing the immediates of an extension instruction
+pub fn extension_category(sub_opcode: u32) -> Result<InstructionCategory> {
+    let extension = lookup_extension(sub_opcode)?;
+    match extension.immediates(sub_opcode) {
+        category @ InstructionCategory::SingleByte
+        | category @ InstructionCategory::SingleLebInteger
+        | category @ InstructionCategory::SingleFloat
+        | category @ InstructionCategory::SingleDouble
+        | category @ InstructionCategory::TwoLebInteger
+        | category @ InstructionCategory::MemArg => Ok(category),
+        category => Err(anyhow!(
+            "Extension instruction {} cannot have immediates of category {:?}",
+            extension.name(sub_opcode),
+            category
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        resolve_raw_module, stack_entry::StackEntry, test_module::TestModule, EmptyResolver,
+        ExportDesc, FuncType, Instance, RawModule, Value, ValueType,
+    };
+    use crate::reader::scan_module;
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+
+    // The test registers its own range, because the registry is shared with every other
+    // test running at the same time
+    const FIRST_SUB_OPCODE: u32 = 0x1000;
+
+    // 0x1000 pushes its immediate, and 0x1001 multiplies the top of the stack by three
+    struct TestExtension;
+
+    impl InstructionExtension for TestExtension {
+        fn name(&self, sub_opcode: u32) -> String {
+            match sub_opcode - FIRST_SUB_OPCODE {
+                0 => "test.const".to_string(),
+                _ => "test.triple".to_string(),
+            }
+        }
+
+        fn immediates(&self, sub_opcode: u32) -> InstructionCategory {
+            match sub_opcode - FIRST_SUB_OPCODE {
+                0 => InstructionCategory::SingleLebInteger,
+                _ => InstructionCategory::SingleByte,
+            }
+        }
+
+        fn validate(&self, _sub_opcode: u32, immediates: &CompiledImmediates) -> Result<()> {
+            match immediates {
+                CompiledImmediates::Index(value) if *value > 1000 => {
+                    Err(anyhow!("test.const is limited to 1000"))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn execute(
+            &self,
+            sub_opcode: u32,
+            immediates: &CompiledImmediates,
+            stack: &mut Stack,
+            _store: &mut dyn DataStore,
+        ) -> Result<()> {
+            match (sub_opcode - FIRST_SUB_OPCODE, immediates) {
+                (0, CompiledImmediates::Index(value)) => stack.push((*value).into()),
+                _ => {
+                    let value = u32::try_from(stack.working_top(1)[0])?;
+                    stack.pop();
+                    stack.push(StackEntry::from(value * 3));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn make_module(body: &[u8]) -> RawModule {
+        TestModule::new()
+            .func(FuncType::new(vec![], vec![ValueType::I32]), body)
+            .export("run", ExportDesc::Func(0))
+            .build()
+    }
+
+    #[test]
+    fn test_extension_instructions() {
+        // test.const 7, test.triple, end. The sub opcodes are 0x1000 and 0x1001 as LEBs.
+        let body = [0xFF, 0x80, 0x20, 0x07, 0xFF, 0x81, 0x20, 0x0B];
+        assert!(resolve_raw_module(make_module(&body), EmptyResolver::instance()).is_err());
+
+        register_extension(
+            FIRST_SUB_OPCODE..=FIRST_SUB_OPCODE + 1,
+            Arc::new(TestExtension),
+        )
+        .unwrap();
+        assert!(register_extension(0x1001..=0x1001, Arc::new(TestExtension)).is_err());
+
+        let instance = Instance::new(
+            resolve_raw_module(make_module(&body), EmptyResolver::instance()).unwrap(),
+        );
+        assert_eq!(instance.invoke("run", &[]).unwrap(), vec![Value::I32(21)]);
+
+        // The extension gets to reject instructions when the module is loaded
+        let too_big = [0xFF, 0x80, 0x20, 0xE9, 0x07, 0x0B];
+        assert!(resolve_raw_module(make_module(&too_big), EmptyResolver::instance()).is_err());
+
+        // A module starting with the type section, then a function section, then a code
+        // section holding the body
+        let mut module = vec![
+            0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01,
+            0x7F, 0x03, 0x02, 0x01, 0x00, 0x0A, 0x0B, 0x01, 0x09, 0x00,
+        ];
+        module.extend_from_slice(&body);
+        let usages = scan_module(&mut Cursor::new(module)).unwrap();
+        let names: Vec<&str> = usages.iter().map(|usage| usage.name.as_str()).collect();
+        assert_eq!(names, vec!["test.const", "test.triple"]);
+        assert!(usages.iter().all(|usage| usage.supported));
+
+        assert!(unregister_extension(FIRST_SUB_OPCODE));
+        assert!(resolve_raw_module(make_module(&body), EmptyResolver::instance()).is_err());
+    }
+}
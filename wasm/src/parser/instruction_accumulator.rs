@@ -1,3 +1,4 @@
+use crate::leb;
 use anyhow::{anyhow, Result};
 use std::convert::TryFrom;
 
@@ -9,6 +10,15 @@ pub trait InstructionAccumulator {
         self.get_bytes(offset, 1)[0]
     }
 
+    /// The most targets a br_table may have, not counting the default. Tables are
+    /// checked against it as they are read, before their targets are walked.
+    fn max_branch_table_targets(&self) -> u32 {
+        u32::MAX
+    }
+
+    /// Make sure all of the LEB at the offset is present, and return its length. Only the
+    /// length is checked here, against the longest an integer can take, because what the
+    /// integer holds depends on the instruction. The getters check the rest.
     fn ensure_leb_at(&mut self, offset: usize) -> Result<usize> {
         let mut number_length: usize = 1;
         loop {
@@ -18,6 +28,11 @@ pub trait InstructionAccumulator {
                 return Ok(number_length);
             }
 
+            if number_length == leb::max_length(64) {
+                return Err(anyhow!(
+                    "Integer representation too long: LEB128 value is longer than 64 bits allow"
+                ));
+            }
             number_length += 1;
         }
     }
@@ -33,144 +48,42 @@ pub trait InstructionAccumulator {
         }
     }
 
-    fn get_leb_u32_at(&self, offset: usize) -> u32 {
-        // To encode a 32 bit number in LEB form can use a maximum of 5 chunks, of which
-        // the highest must only use 4 bits
-        static HIGHEST_CHUNK: usize = 4;
-        static HIGHEST_CHUNK_MASK: u8 = 0x0F;
-
-        let mut pos: usize = offset;
-        let mut result: u32 = 0;
-        let mut shift = 0;
-
-        loop {
-            // It is impossible to go past this point because of the mask check.
-            assert!(pos <= (offset + HIGHEST_CHUNK));
-
+    fn get_leb_at<const BITS: u32, const SIGNED: bool>(&self, offset: usize) -> Result<u64> {
+        let mut pos = offset;
+        leb::decode::<BITS, SIGNED>(|| {
             let byte = self.get_byte(pos);
-
-            if pos == (offset + HIGHEST_CHUNK) && (byte & HIGHEST_CHUNK_MASK) != byte {
-                panic!("LEB integer is too big");
-            }
-
             pos += 1;
-            result |= u32::from(byte & 0x7f) << shift;
-            if (byte & 0x80) == 0 {
-                return result;
-            }
-            shift += 7;
-        }
+            Ok(byte)
+        })
+        .map(|(value, _)| value)
     }
 
-    fn get_leb_i32_at(&self, offset: usize) -> i32 {
-        // To encode a 32 bit number in LEB form can use a maximum of 5 chunks, of which
-        // the highest must only use 4 bits
-        static HIGHEST_CHUNK: usize = 4;
-        static HIGHEST_CHUNK_MASK: u8 = 0x0F;
-
-        let mut pos: usize = offset;
-        let mut result: u32 = 0;
-        let mut shift = 0;
-
-        loop {
-            // It is impossible to go past this point because of the mask check.
-            assert!(pos <= (offset + HIGHEST_CHUNK));
-
-            let byte = self.get_byte(pos);
-
-            if pos == (offset + HIGHEST_CHUNK) && (byte & HIGHEST_CHUNK_MASK) != byte {
-                panic!("LEB integer is too big");
-            }
-
-            pos += 1;
-            result |= u32::from(byte & 0x7f) << shift;
-            shift += 7;
-
-            if (byte & 0x80) == 0 {
-                // At this point we have a shift bit unsigned number, so we need to sign extend it.
-                // This ought to work.
-                let mut result = unsafe { std::mem::transmute(result) };
-
-                if shift < 32 {
-                    result = result << (32 - shift);
-                    result = result >> (32 - shift);
-                }
-
-                return result;
-            }
-        }
+    fn get_leb_u32_at(&self, offset: usize) -> Result<u32> {
+        self.get_leb_at::<32, false>(offset)
+            .map(|value| value as u32)
     }
 
-    fn get_leb_u64_at(&self, offset: usize) -> u64 {
-        // To encode a 64 bit number in LEB form can use a maximum of 10 chunks, of which
-        // the highest must only use 1 bits
-        static HIGHEST_CHUNK: usize = 9;
-        static HIGHEST_CHUNK_MASK: u8 = 0x01;
-
-        let mut pos: usize = offset;
-        let mut result: u64 = 0;
-        let mut shift = 0;
-
-        loop {
-            // It is impossible to go past this point because of the mask check.
-            assert!(pos <= (offset + HIGHEST_CHUNK));
-
-            let byte = self.get_byte(pos);
-
-            if pos == (offset + HIGHEST_CHUNK) && (byte & HIGHEST_CHUNK_MASK) != byte {
-                panic!("LEB integer is too big");
-            }
-
-            pos += 1;
-            result |= u64::from(byte & 0x7f) << shift;
-            if (byte & 0x80) == 0 {
-                return result;
-            }
-            shift += 7;
-        }
+    fn get_leb_i32_at(&self, offset: usize) -> Result<i32> {
+        self.get_leb_at::<32, true>(offset)
+            .map(|value| value as i32)
     }
 
-    fn get_leb_i64_at(&self, offset: usize) -> i64 {
-        // To encode a 64 bit number in LEB form can use a maximum of 10 chunks, of which
-        // the highest must only use 1 bits
-        static HIGHEST_CHUNK: usize = 9;
-        static HIGHEST_CHUNK_MASK: u8 = 0x01;
-
-        let mut pos: usize = offset;
-        let mut result: u64 = 0;
-        let mut shift = 0;
-
-        loop {
-            // It is impossible to go past this point because of the mask check.
-            assert!(pos <= (offset + HIGHEST_CHUNK));
-
-            let byte = self.get_byte(pos);
-
-            if pos == (offset + HIGHEST_CHUNK) && (byte & HIGHEST_CHUNK_MASK) != byte {
-                panic!("LEB integer is too big");
-            }
-
-            pos += 1;
-            result |= u64::from(byte & 0x7f) << shift;
-            shift += 7;
-
-            if (byte & 0x80) == 0 {
-                // At this point we have a shift bit unsigned number, so we need to sign extend it.
-                // This ought to work.
-                let mut result = unsafe { std::mem::transmute(result) };
+    fn get_leb_u64_at(&self, offset: usize) -> Result<u64> {
+        self.get_leb_at::<64, false>(offset)
+    }
 
-                if shift < 64 {
-                    result = result << (64 - shift);
-                    result = result >> (64 - shift);
-                }
+    fn get_leb_i64_at(&self, offset: usize) -> Result<i64> {
+        self.get_leb_at::<64, true>(offset)
+            .map(|value| value as i64)
+    }
 
-                return result;
-            }
-        }
+    fn get_leb_s33_at(&self, offset: usize) -> Result<i64> {
+        self.get_leb_at::<33, true>(offset)
+            .map(|value| value as i64)
     }
 
-    fn get_leb_usize_at(&self, offset: usize) -> usize {
-        usize::try_from(self.get_leb_u32_at(offset)).unwrap()
+    fn get_leb_usize_at(&self, offset: usize) -> Result<usize> {
+        Ok(usize::try_from(self.get_leb_u32_at(offset)?)?)
     }
 
     fn get_f32_at(&self, offset: usize) -> f32 {
@@ -1,21 +1,71 @@
 use crate::{
     core::BlockType,
-    parser::{InstructionAccumulator, Opcode},
+    parser::{
+        extension_category, AtomicOpcode, InstructionAccumulator, InvalidOpcode, MiscOpcode, Opcode,
+    },
 };
 use anyhow::{anyhow, Result};
 use std::convert::{TryFrom, TryInto};
+use std::ops::Range;
+
+// Make sure the block type at the offset is present and valid, and return its length
+fn ensure_block_type<T: InstructionAccumulator>(acc: &mut T, offset: usize) -> Result<usize> {
+    let length = acc.ensure_leb_at(offset)?;
+    BlockType::from_s33(acc.get_leb_s33_at(offset)?)?;
+    Ok(length)
+}
+
+// With multi-memory, this bit of the alignment says that a memory index follows it. The
+// alignment itself is the power of two in the bits below.
+const MEM_ARG_HAS_MEMORY_INDEX: u32 = 0x40;
+
+/// The immediates of a load or a store
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemArg {
+    align: u32,
+    memory: u32,
+    offset: u32,
+}
+
+impl MemArg {
+    pub fn new(align: u32, memory: u32, offset: u32) -> Self {
+        Self {
+            align,
+            memory,
+            offset,
+        }
+    }
+
+    /// The alignment hint, as a power of two
+    pub fn align(&self) -> u32 {
+        self.align
+    }
+
+    /// The memory accessed, which is always 0 in modules without multi-memory
+    pub fn memory(&self) -> u32 {
+        self.memory
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum InstructionCategory {
-    SingleByte,       // No arguments
-    SingleLebInteger, // Single argument, can be I32 or I64
-    SingleFloat,      // Single argument of type F32
-    SingleDouble,     // Single argument of type F64
-    Block(bool),      // One or two sub expressions
-    Else,             // No arguments
-    End,              // No arguments
-    TwoLebInteger,    // Two I32 arguments
-    BranchTable,      // Vector of I32 arguments containing at least one entry
+    SingleByte,        // No arguments
+    SingleLebInteger,  // Single argument, can be I32 or I64
+    SingleFloat,       // Single argument of type F32
+    SingleDouble,      // Single argument of type F64
+    Block(bool),       // One or two sub expressions
+    Else,              // No arguments
+    End,               // No arguments
+    TwoLebInteger,     // Two I32 arguments
+    MemArg,            // Alignment, an optional memory index, then an offset
+    BranchTable,       // Vector of I32 arguments containing at least one entry
+    MiscPrefixed,      // 0xFC prefix followed by a sub opcode, then the sub opcode arguments
+    AtomicPrefixed,    // 0xFE prefix followed by a sub opcode, then the sub opcode arguments
+    ExtensionPrefixed, // 0xFF prefix followed by a sub opcode, then whatever the extension says
 }
 
 #[derive(Debug)]
@@ -58,8 +108,13 @@ impl InstructionData {
 }
 
 impl InstructionCategory {
-    pub fn from_lead_byte(lead_byte: u8) -> Result<InstructionCategory> {
-        Ok(Self::from_opcode(Opcode::from_byte(lead_byte)?))
+    /// The category of the instruction which starts with the byte, which is at the offset
+    /// given, so that an invalid byte can be reported with where it is
+    pub fn from_lead_byte(lead_byte: u8, offset: usize) -> Result<InstructionCategory> {
+        match Opcode::from_byte(lead_byte) {
+            Ok(opcode) => Ok(Self::from_opcode(opcode)),
+            Err(_) => Err(InvalidOpcode::new(lead_byte, offset).into()),
+        }
     }
 
     pub fn from_opcode(opcode: Opcode) -> InstructionCategory {
@@ -72,13 +127,18 @@ impl InstructionCategory {
             Opcode::End => InstructionCategory::End,
             Opcode::Br | Opcode::BrIf => InstructionCategory::SingleLebInteger,
             Opcode::BrTable => InstructionCategory::BranchTable,
-            Opcode::Call => InstructionCategory::SingleLebInteger,
-            Opcode::CallIndirect => InstructionCategory::TwoLebInteger,
+            Opcode::Call | Opcode::ReturnCall => InstructionCategory::SingleLebInteger,
+            Opcode::CallIndirect | Opcode::ReturnCallIndirect => InstructionCategory::TwoLebInteger,
             Opcode::LocalGet
             | Opcode::LocalSet
             | Opcode::LocalTee
             | Opcode::GlobalGet
             | Opcode::GlobalSet => InstructionCategory::SingleLebInteger,
+            Opcode::TableGet | Opcode::TableSet => InstructionCategory::SingleLebInteger,
+            // Typed select has a vector of value types, which always holds exactly one. Value
+            // types are single bytes, which read the same as a LEB, so the vector reads the
+            // same as its length followed by the type.
+            Opcode::SelectTyped => InstructionCategory::TwoLebInteger,
             Opcode::I32Load
             | Opcode::I64Load
             | Opcode::F32Load
@@ -101,16 +161,55 @@ impl InstructionCategory {
             | Opcode::I32Store16
             | Opcode::I64Store8
             | Opcode::I64Store16
-            | Opcode::I64Store32 => InstructionCategory::TwoLebInteger,
+            | Opcode::I64Store32 => InstructionCategory::MemArg,
             Opcode::MemorySize | Opcode::MemoryGrow => InstructionCategory::SingleLebInteger,
             Opcode::I32Const | Opcode::I64Const => InstructionCategory::SingleLebInteger,
             Opcode::F32Const => InstructionCategory::SingleFloat,
             Opcode::F64Const => InstructionCategory::SingleDouble,
+            // The reference type of ref.null is a single byte, which reads the same as a LEB
+            Opcode::RefNull | Opcode::RefFunc => InstructionCategory::SingleLebInteger,
+            Opcode::MiscPrefix => InstructionCategory::MiscPrefixed,
+            Opcode::AtomicPrefix => InstructionCategory::AtomicPrefixed,
+            Opcode::ExtensionPrefix => InstructionCategory::ExtensionPrefixed,
 
             _ => InstructionCategory::SingleByte,
         }
     }
 
+    // The category of a prefixed instruction describes the arguments which follow the
+    // sub opcode
+    pub fn from_misc_opcode(opcode: MiscOpcode) -> InstructionCategory {
+        match opcode {
+            MiscOpcode::I32TruncSatF32S
+            | MiscOpcode::I32TruncSatF32U
+            | MiscOpcode::I32TruncSatF64S
+            | MiscOpcode::I32TruncSatF64U
+            | MiscOpcode::I64TruncSatF32S
+            | MiscOpcode::I64TruncSatF32U
+            | MiscOpcode::I64TruncSatF64S
+            | MiscOpcode::I64TruncSatF64U => InstructionCategory::SingleByte,
+
+            MiscOpcode::DataDrop
+            | MiscOpcode::MemoryFill
+            | MiscOpcode::ElemDrop
+            | MiscOpcode::TableGrow
+            | MiscOpcode::TableSize
+            | MiscOpcode::TableFill => InstructionCategory::SingleLebInteger,
+            MiscOpcode::MemoryInit
+            | MiscOpcode::MemoryCopy
+            | MiscOpcode::TableInit
+            | MiscOpcode::TableCopy => InstructionCategory::TwoLebInteger,
+        }
+    }
+
+    pub fn from_atomic_opcode(opcode: AtomicOpcode) -> InstructionCategory {
+        match opcode {
+            // The fence takes a single reserved zero byte, which reads the same as a LEB
+            AtomicOpcode::AtomicFence => InstructionCategory::SingleLebInteger,
+            _ => InstructionCategory::MemArg,
+        }
+    }
+
     pub fn ensure_instruction<T: InstructionAccumulator>(
         &self,
         acc: &mut T,
@@ -135,19 +234,65 @@ impl InstructionCategory {
                 self.ensure_block_instruction(*allow_else, acc, offset)
             }
             InstructionCategory::TwoLebInteger => self.ensure_two_leb_integer(acc, offset),
+            InstructionCategory::MemArg => self.ensure_mem_arg(acc, offset),
             InstructionCategory::BranchTable => self.ensure_branch_table(acc, offset),
+            InstructionCategory::MiscPrefixed => {
+                self.ensure_prefixed_instruction(acc, offset, |sub_opcode| {
+                    Ok(Self::from_misc_opcode(MiscOpcode::from_u32(sub_opcode)?))
+                })
+            }
+            InstructionCategory::AtomicPrefixed => {
+                self.ensure_prefixed_instruction(acc, offset, |sub_opcode| {
+                    Ok(Self::from_atomic_opcode(AtomicOpcode::from_u32(
+                        sub_opcode,
+                    )?))
+                })
+            }
+            InstructionCategory::ExtensionPrefixed => {
+                self.ensure_prefixed_instruction(acc, offset, extension_category)
+            }
         }
     }
 
+    fn ensure_prefixed_instruction<T: InstructionAccumulator>(
+        &self,
+        acc: &mut T,
+        offset: usize,
+        category: impl FnOnce(u32) -> Result<InstructionCategory>,
+    ) -> Result<InstructionData> {
+        let opcode_size = acc.ensure_leb_at(offset + 1)?;
+        let sub_category = category(acc.get_leb_u32_at(offset + 1)?)?;
+
+        // The arguments start after the sub opcode, so we can treat the last byte of the
+        // sub opcode as if it were the lead byte of an ordinary instruction
+        let instr_data = sub_category.ensure_instruction(acc, offset + opcode_size)?;
+
+        Ok(simple_instruction_data(opcode_size + instr_data.length()))
+    }
+
     fn ensure_two_leb_integer<T: InstructionAccumulator>(
         &self,
         acc: &mut T,
         offset: usize,
     ) -> Result<InstructionData> {
-        let align_size = acc.ensure_leb_at(offset + 1)?;
-        let offset_size = acc.ensure_leb_at(offset + 1 + align_size)?;
+        let first_size = acc.ensure_leb_at(offset + 1)?;
+        let second_size = acc.ensure_leb_at(offset + 1 + first_size)?;
+
+        Ok(simple_instruction_data(1 + first_size + second_size))
+    }
+
+    fn ensure_mem_arg<T: InstructionAccumulator>(
+        &self,
+        acc: &mut T,
+        offset: usize,
+    ) -> Result<InstructionData> {
+        let mut instr_size = 1 + acc.ensure_leb_at(offset + 1)?;
+        if acc.get_leb_u32_at(offset + 1)? & MEM_ARG_HAS_MEMORY_INDEX != 0 {
+            instr_size += acc.ensure_leb_at(offset + instr_size)?;
+        }
+        instr_size += acc.ensure_leb_at(offset + instr_size)?;
 
-        Ok(simple_instruction_data(1 + align_size + offset_size))
+        Ok(simple_instruction_data(instr_size))
     }
 
     fn ensure_block_instruction<T: InstructionAccumulator>(
@@ -156,27 +301,48 @@ impl InstructionCategory {
         acc: &mut T,
         offset: usize,
     ) -> Result<InstructionData> {
-        // Validate the block type
-        acc.ensure_bytes(offset + 2)?;
-        BlockType::try_from(acc.get_byte(offset + 1))?;
-
-        // Child instruction offset always starts at 2 because all blocks begin with a block type
-        let mut next_child_offset = offset + 2;
+        // Validate the block type. The child instructions start straight after it.
+        let block_type_length = ensure_block_type(acc, offset + 1)?;
+        let mut next_child_offset = offset + 1 + block_type_length;
         let mut range_start = next_child_offset;
         let mut block_range: Option<BlockRange> = None;
 
+        // Nested blocks are walked in this same loop rather than recursively, so that deeply
+        // nested expressions can't exhaust the host stack. Each entry records whether that
+        // nested block may still have an else.
+        let mut nested_blocks: Vec<bool> = Vec::new();
+
         loop {
             // Make sure that we have the lead byte of the next instruction
             acc.ensure_bytes(next_child_offset + 1)?;
 
             // Now get the lead byte
             let child_lead_byte = acc.get_byte(next_child_offset);
-            let child_instr_cat = InstructionCategory::from_lead_byte(child_lead_byte)?;
+            let child_instr_cat =
+                InstructionCategory::from_lead_byte(child_lead_byte, next_child_offset)?;
+
+            if let InstructionCategory::Block(nested_allow_else) = child_instr_cat {
+                // Validate the block type and step into the nested block
+                let block_type_length = ensure_block_type(acc, next_child_offset + 1)?;
+                nested_blocks.push(nested_allow_else);
+                next_child_offset += 1 + block_type_length;
+                continue;
+            }
 
             // Now ensure that we have that instruction
             let child_instr_size = child_instr_cat.ensure_instruction(acc, next_child_offset)?;
 
-            if child_instr_cat == InstructionCategory::Else {
+            if let Some(nested_allow_else) = nested_blocks.last_mut() {
+                if child_instr_cat == InstructionCategory::Else {
+                    if !*nested_allow_else {
+                        return Err(anyhow!("Unexpected else in block"));
+                    }
+                    *nested_allow_else = false;
+                } else if child_instr_cat == InstructionCategory::End {
+                    nested_blocks.pop();
+                }
+                next_child_offset += child_instr_size.length();
+            } else if child_instr_cat == InstructionCategory::Else {
                 if !block_range.is_none() || !allow_else {
                     return Err(anyhow!("Unexpected else in block"));
                 }
@@ -230,7 +396,14 @@ impl InstructionCategory {
         let mut instr_size: usize = 1 + acc.ensure_leb_at(offset + 1)?;
 
         // Now we read the vector length
-        let vector_length = acc.get_leb_u32_at(offset + 1);
+        let vector_length = acc.get_leb_u32_at(offset + 1)?;
+        if vector_length > acc.max_branch_table_targets() {
+            return Err(anyhow!(
+                "Module too large: br_table with {} targets exceeds the limit of {}",
+                vector_length,
+                acc.max_branch_table_targets()
+            ));
+        }
 
         for _ in 0..vector_length {
             // Add on the length of the integer from the vector
@@ -243,14 +416,22 @@ impl InstructionCategory {
         Ok(simple_instruction_data(instr_size))
     }
 
-    pub fn get_single_u32_arg<T: InstructionAccumulator>(&self, acc: &T, offset: usize) -> u32 {
+    pub fn get_single_u32_arg<T: InstructionAccumulator>(
+        &self,
+        acc: &T,
+        offset: usize,
+    ) -> Result<u32> {
         match self {
             InstructionCategory::SingleLebInteger => acc.get_leb_u32_at(offset + 1),
             _ => panic!("Not valid for instruction type"),
         }
     }
 
-    pub fn get_single_i32_arg<T: InstructionAccumulator>(&self, acc: &T, offset: usize) -> i32 {
+    pub fn get_single_i32_arg<T: InstructionAccumulator>(
+        &self,
+        acc: &T,
+        offset: usize,
+    ) -> Result<i32> {
         match self {
             InstructionCategory::SingleLebInteger => acc.get_leb_i32_at(offset + 1),
             _ => panic!("Not valid for instruction type"),
@@ -261,18 +442,26 @@ impl InstructionCategory {
         &self,
         acc: &T,
         offset: usize,
-    ) -> usize {
-        usize::try_from(self.get_single_u32_arg(acc, offset)).unwrap()
+    ) -> Result<usize> {
+        Ok(usize::try_from(self.get_single_u32_arg(acc, offset)?)?)
     }
 
-    pub fn get_single_u64_arg<T: InstructionAccumulator>(&self, acc: &T, offset: usize) -> u64 {
+    pub fn get_single_u64_arg<T: InstructionAccumulator>(
+        &self,
+        acc: &T,
+        offset: usize,
+    ) -> Result<u64> {
         match self {
             InstructionCategory::SingleLebInteger => acc.get_leb_u64_at(offset + 1),
             _ => panic!("Not valid for instruction type"),
         }
     }
 
-    pub fn get_single_i64_arg<T: InstructionAccumulator>(&self, acc: &T, offset: usize) -> i64 {
+    pub fn get_single_i64_arg<T: InstructionAccumulator>(
+        &self,
+        acc: &T,
+        offset: usize,
+    ) -> Result<i64> {
         match self {
             InstructionCategory::SingleLebInteger => acc.get_leb_i64_at(offset + 1),
             _ => panic!("Not valid for instruction type"),
@@ -297,15 +486,13 @@ impl InstructionCategory {
         &self,
         acc: &T,
         offset: usize,
-    ) -> (u32, u32) {
+    ) -> Result<(u32, u32)> {
         match self {
             InstructionCategory::TwoLebInteger => {
-                // TODOTODOTODO - this can be optimized - there is no need to measure the number then read it.
-                // I'll do that later as part of a wider overhaul of the leb reading
                 let first_arg_size = acc.get_leb_size_at(offset + 1);
-                let arg1 = acc.get_leb_u32_at(offset + 1);
-                let arg2 = acc.get_leb_u32_at(offset + first_arg_size + 1);
-                (arg1, arg2)
+                let arg1 = acc.get_leb_u32_at(offset + 1)?;
+                let arg2 = acc.get_leb_u32_at(offset + first_arg_size + 1)?;
+                Ok((arg1, arg2))
             }
             _ => panic!("Not valid for this instruction type"),
         }
@@ -315,16 +502,43 @@ impl InstructionCategory {
         &self,
         acc: &impl InstructionAccumulator,
         offset: usize,
-    ) -> (usize, usize) {
-        let (a1, a2) = self.get_pair_u32_arg(acc, offset);
-        (a1.try_into().unwrap(), a2.try_into().unwrap())
+    ) -> Result<(usize, usize)> {
+        let (a1, a2) = self.get_pair_u32_arg(acc, offset)?;
+        Ok((a1.try_into()?, a2.try_into()?))
     }
 
-    pub fn get_block_type(&self, acc: &impl InstructionAccumulator, offset: usize) -> BlockType {
+    pub fn get_mem_arg(&self, acc: &impl InstructionAccumulator, offset: usize) -> Result<MemArg> {
         match self {
-            InstructionCategory::Block(_) => {
-                BlockType::from_byte(acc.get_byte(offset + 1)).unwrap()
+            InstructionCategory::MemArg => {
+                let mut arg_offset = offset + 1;
+                let flags = acc.get_leb_u32_at(arg_offset)?;
+                arg_offset += acc.get_leb_size_at(arg_offset);
+
+                let memory = if flags & MEM_ARG_HAS_MEMORY_INDEX != 0 {
+                    let memory = acc.get_leb_u32_at(arg_offset)?;
+                    arg_offset += acc.get_leb_size_at(arg_offset);
+                    memory
+                } else {
+                    0
+                };
+
+                Ok(MemArg::new(
+                    flags & !MEM_ARG_HAS_MEMORY_INDEX,
+                    memory,
+                    acc.get_leb_u32_at(arg_offset)?,
+                ))
             }
+            _ => panic!("Not valid for this instruction type"),
+        }
+    }
+
+    pub fn get_block_type(
+        &self,
+        acc: &impl InstructionAccumulator,
+        offset: usize,
+    ) -> Result<BlockType> {
+        match self {
+            InstructionCategory::Block(_) => BlockType::from_s33(acc.get_leb_s33_at(offset + 1)?),
 
             _ => panic!(
                 "No block result type for instructions of category {:?}",
@@ -348,58 +562,101 @@ impl InstructionCategory {
         }
     }
 
-    pub fn get_block<'a>(
-        &'_ self,
-        acc: &'a impl InstructionAccumulator,
-        offset: usize,
-        data: &InstructionData,
-    ) -> &'a [u8] {
+    pub fn get_block_range(&self, offset: usize, data: &InstructionData) -> Range<usize> {
         match data {
             InstructionData {
                 block_range: Some(block_range),
                 ..
-            } => acc.get_bytes(
-                offset + block_range.start,
-                block_range.end - block_range.start,
-            ),
-            _ => panic!("No else block"),
+            } => offset + block_range.start..offset + block_range.end,
+            _ => panic!("No block"),
         }
     }
 
-    pub fn get_else_block<'a>(
-        &'_ self,
-        acc: &'a impl InstructionAccumulator,
-        offset: usize,
-        data: &InstructionData,
-    ) -> &'a [u8] {
+    pub fn get_else_block_range(&self, offset: usize, data: &InstructionData) -> Range<usize> {
         match data {
             InstructionData {
                 else_range: Some(block_range),
                 ..
-            } => acc.get_bytes(
-                offset + block_range.start,
-                block_range.end - block_range.start,
-            ),
+            } => offset + block_range.start..offset + block_range.end,
             _ => panic!("No else block"),
         }
     }
 
+    /// The targets of a branch table, with the default target last, checking each of them
     pub fn get_block_table_targets(
         &self,
         acc: &impl InstructionAccumulator,
         offset: usize,
-    ) -> Vec<usize> {
-        // There have got to be better ways to parse this.
-        let mut instr_size: usize = 1 + acc.get_leb_size_at(offset + 1);
-        let vector_length = acc.get_leb_usize_at(offset + 1);
-        let mut ret = Vec::with_capacity(vector_length + 1);
-
-        for _ in 0..(vector_length + 1) {
-            let number_size = acc.get_leb_size_at(offset + instr_size);
-            ret.push(acc.get_leb_u32_at(instr_size).try_into().unwrap());
-            instr_size += number_size;
+    ) -> Result<Vec<usize>> {
+        match self {
+            InstructionCategory::BranchTable => {
+                let mut position = offset + 1 + acc.get_leb_size_at(offset + 1);
+                (0..=acc.get_leb_u32_at(offset + 1)?)
+                    .map(|_| {
+                        let target = acc.get_leb_usize_at(position)?;
+                        position += acc.get_leb_size_at(position);
+                        Ok(target)
+                    })
+                    .collect()
+            }
+            _ => panic!("Not valid for this instruction type"),
+        }
+    }
+
+    /// The targets of a branch table, decoded as they are iterated over rather than all
+    /// at once, with the default target last. They must have been checked already, which
+    /// `Instruction` does as it is created.
+    pub fn block_table_targets<'a, Acc: InstructionAccumulator>(
+        &self,
+        acc: &'a Acc,
+        offset: usize,
+    ) -> BranchTableTargets<'a, Acc> {
+        match self {
+            InstructionCategory::BranchTable => BranchTableTargets {
+                acc,
+                position: offset + 1 + acc.get_leb_size_at(offset + 1),
+                remaining: acc.get_leb_usize_at(offset + 1).expect(CHECKED) + 1,
+            },
+            _ => panic!("Not valid for this instruction type"),
+        }
+    }
+}
+
+const CHECKED: &str = "Branch table targets are checked before they are iterated over";
+
+pub struct BranchTableTargets<'a, Acc: InstructionAccumulator> {
+    acc: &'a Acc,
+    position: usize,
+    remaining: usize,
+}
+
+impl<'a, Acc: InstructionAccumulator> Iterator for BranchTableTargets<'a, Acc> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.remaining == 0 {
+            return None;
         }
 
-        ret
+        let target = self.acc.get_leb_usize_at(self.position).expect(CHECKED);
+        self.position += self.acc.get_leb_size_at(self.position);
+        self.remaining -= 1;
+        Some(target)
+    }
+
+    // Picking a target only needs the length of the ones before it, not their values
+    fn nth(&mut self, n: usize) -> Option<usize> {
+        let skipped = n.min(self.remaining);
+        for _ in 0..skipped {
+            self.position += self.acc.get_leb_size_at(self.position);
+        }
+        self.remaining -= skipped;
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
+
+impl<'a, Acc: InstructionAccumulator> ExactSizeIterator for BranchTableTargets<'a, Acc> {}
@@ -4,32 +4,87 @@ use crate::{
 };
 use anyhow::{anyhow, Result};
 
+// Every immediate is decoded as the instruction is created, so the accessors don't fail
+const CHECKED: &str = "Immediates are checked when the instruction is read";
+
 #[derive(Debug)]
 pub struct Instruction<'a> {
     bytes: &'a [u8],
     opcode: parser::Opcode,
+    misc_opcode: Option<parser::MiscOpcode>,
+    atomic_opcode: Option<parser::AtomicOpcode>,
+    extension_opcode: Option<u32>,
     cat: parser::InstructionCategory,
+    arg_offset: usize,
     acc: parser::SliceInstructionAccumulator<'a>,
     data: parser::InstructionData,
 }
 
 impl<'a> Instruction<'a> {
-    fn new(bytes: &'a [u8], data: InstructionData) -> Self {
+    fn new(bytes: &'a [u8], data: InstructionData) -> Result<Self> {
         // All instructions are at least one byte long, and we depend heavily on that assumption
-        assert!(bytes.len() > 0);
-
-        let opcode = parser::Opcode::from_byte(bytes[0]).unwrap();
-        let cat = parser::InstructionCategory::from_opcode(opcode.clone());
+        let lead_byte = *bytes
+            .first()
+            .ok_or_else(|| anyhow!("An instruction must have at least one byte"))?;
+        let opcode = parser::Opcode::from_byte(lead_byte)
+            .map_err(|_| parser::InvalidOpcode::new(lead_byte, 0))?;
         let mut acc = parser::make_slice_accumulator(bytes);
-        assert!(cat.ensure_instruction(&mut acc, 0).is_ok());
 
-        Self {
+        // For prefixed instructions, the category describes the arguments following the sub
+        // opcode, so we offset all of the argument accesses by the size of the sub opcode
+        let mut misc_opcode = None;
+        let mut atomic_opcode = None;
+        let mut extension_opcode = None;
+        let (cat, arg_offset) = match opcode {
+            parser::Opcode::MiscPrefix => {
+                let sub_opcode = parser::MiscOpcode::from_u32(acc.get_leb_u32_at(1)?)?;
+                misc_opcode = Some(sub_opcode);
+                (
+                    parser::InstructionCategory::from_misc_opcode(sub_opcode),
+                    acc.get_leb_size_at(1),
+                )
+            }
+            parser::Opcode::AtomicPrefix => {
+                let sub_opcode = parser::AtomicOpcode::from_u32(acc.get_leb_u32_at(1)?)?;
+                atomic_opcode = Some(sub_opcode);
+                (
+                    parser::InstructionCategory::from_atomic_opcode(sub_opcode),
+                    acc.get_leb_size_at(1),
+                )
+            }
+            parser::Opcode::ExtensionPrefix => {
+                let sub_opcode = acc.get_leb_u32_at(1)?;
+                extension_opcode = Some(sub_opcode);
+                (
+                    parser::extension_category(sub_opcode)?,
+                    acc.get_leb_size_at(1),
+                )
+            }
+            _ => (parser::InstructionCategory::from_opcode(opcode), 0),
+        };
+        cat.ensure_instruction(&mut acc, arg_offset)?;
+
+        let instruction = Self {
             bytes,
             opcode,
+            misc_opcode,
+            atomic_opcode,
+            extension_opcode,
             cat,
+            arg_offset,
             acc,
             data,
-        }
+        };
+
+        // Decoding the immediates once here reports any which are malformed as the
+        // instruction is read, so the accessors below can't fail
+        instruction.try_immediates()?;
+        Ok(instruction)
+    }
+
+    /// The whole of the instruction, including any block it contains
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
     }
 
     #[allow(dead_code)]
@@ -42,12 +97,32 @@ impl<'a> Instruction<'a> {
         self.opcode.clone()
     }
 
+    pub fn misc_opcode(&self) -> parser::MiscOpcode {
+        match self.misc_opcode {
+            Some(misc_opcode) => misc_opcode,
+            _ => panic!("Not a prefixed instruction"),
+        }
+    }
+
+    pub fn atomic_opcode(&self) -> parser::AtomicOpcode {
+        match self.atomic_opcode {
+            Some(atomic_opcode) => atomic_opcode,
+            _ => panic!("Not an atomic instruction"),
+        }
+    }
+
+    pub fn extension_opcode(&self) -> u32 {
+        match self.extension_opcode {
+            Some(extension_opcode) => extension_opcode,
+            _ => panic!("Not an extension instruction"),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn category(&self) -> &parser::InstructionCategory {
         &self.cat
     }
 
-    #[allow(dead_code)]
     fn is_block_start(&self) -> bool {
         match self.cat {
             parser::InstructionCategory::Block(_) => true,
@@ -61,64 +136,243 @@ impl<'a> Instruction<'a> {
 
     #[allow(dead_code)]
     pub fn get_single_u32_arg(&self) -> u32 {
-        self.cat.get_single_u32_arg(&self.acc, 0)
+        self.cat
+            .get_single_u32_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     pub fn get_single_i32_arg(&self) -> i32 {
-        self.cat.get_single_i32_arg(&self.acc, 0)
+        self.cat
+            .get_single_i32_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     #[allow(dead_code)]
     pub fn get_single_u64_arg(&self) -> u64 {
-        self.cat.get_single_u64_arg(&self.acc, 0)
+        self.cat
+            .get_single_u64_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     pub fn get_single_i64_arg(&self) -> i64 {
-        self.cat.get_single_i64_arg(&self.acc, 0)
+        self.cat
+            .get_single_i64_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     pub fn get_single_u32_as_usize_arg(&self) -> usize {
-        self.cat.get_single_u32_as_usize_arg(&self.acc, 0)
+        self.cat
+            .get_single_u32_as_usize_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     pub fn get_single_f32_arg(&self) -> f32 {
-        self.cat.get_single_f32_arg(&self.acc, 0)
+        self.cat.get_single_f32_arg(&self.acc, self.arg_offset)
     }
 
     pub fn get_single_f64_arg(&self) -> f64 {
-        self.cat.get_single_f64_arg(&self.acc, 0)
+        self.cat.get_single_f64_arg(&self.acc, self.arg_offset)
     }
 
     #[allow(dead_code)]
     pub fn get_pair_u32_arg(&self) -> (u32, u32) {
-        self.cat.get_pair_u32_arg(&self.acc, 0)
+        self.cat
+            .get_pair_u32_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     pub fn get_pair_u32_as_usize_arg(&self) -> (usize, usize) {
-        self.cat.get_pair_u32_as_usize_arg(&self.acc, 0)
+        self.cat
+            .get_pair_u32_as_usize_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
+    }
+
+    pub fn get_mem_arg(&self) -> parser::MemArg {
+        self.cat
+            .get_mem_arg(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     pub fn get_block_type(&self) -> BlockType {
-        self.cat.get_block_type(&self.acc, 0)
+        self.cat
+            .get_block_type(&self.acc, self.arg_offset)
+            .expect(CHECKED)
     }
 
     pub fn has_else_block(&self) -> bool {
-        self.cat.has_else_block(&self.acc, 0, &self.data)
+        self.cat
+            .has_else_block(&self.acc, self.arg_offset, &self.data)
     }
 
-    pub fn get_block<'b>(&'b self) -> &'b [u8] {
-        self.cat.get_block(&self.acc, 0, &self.data)
+    // Blocks borrow from the expression rather than the instruction, so the executor can
+    // keep hold of them after the instruction has gone
+    pub fn get_block(&self) -> &'a [u8] {
+        &self.bytes[self.cat.get_block_range(self.arg_offset, &self.data)]
     }
 
-    pub fn get_else_block<'b>(&'b self) -> &'b [u8] {
-        self.cat.get_else_block(&self.acc, 0, &self.data)
+    pub fn get_else_block(&self) -> &'a [u8] {
+        &self.bytes[self.cat.get_else_block_range(self.arg_offset, &self.data)]
     }
 
     pub fn get_block_table_targets(&self) -> Vec<usize> {
-        self.cat.get_block_table_targets(&self.acc, 0)
+        self.cat
+            .get_block_table_targets(&self.acc, self.arg_offset)
+            .expect(CHECKED)
+    }
+
+    /// The same targets as `get_block_table_targets`, without collecting them
+    pub fn block_table_targets(
+        &self,
+    ) -> parser::BranchTableTargets<'_, parser::SliceInstructionAccumulator<'a>> {
+        self.cat.block_table_targets(&self.acc, self.arg_offset)
+    }
+
+    // The get_* accessors above panic if the instruction has the wrong category, which is
+    // fine for the executor because it only asks for what the opcode implies. The try_*
+    // versions are for tools which look at arbitrary instructions.
+
+    pub fn try_misc_opcode(&self) -> Option<parser::MiscOpcode> {
+        self.misc_opcode
+    }
+
+    pub fn try_atomic_opcode(&self) -> Option<parser::AtomicOpcode> {
+        self.atomic_opcode
+    }
+
+    pub fn try_extension_opcode(&self) -> Option<u32> {
+        self.extension_opcode
+    }
+
+    pub fn try_get_single_u32_arg(&self) -> Option<u32> {
+        self.has_category(parser::InstructionCategory::SingleLebInteger)
+            .then(|| self.get_single_u32_arg())
+    }
+
+    pub fn try_get_single_i32_arg(&self) -> Option<i32> {
+        self.has_category(parser::InstructionCategory::SingleLebInteger)
+            .then(|| self.get_single_i32_arg())
+    }
+
+    pub fn try_get_single_u64_arg(&self) -> Option<u64> {
+        self.has_category(parser::InstructionCategory::SingleLebInteger)
+            .then(|| self.get_single_u64_arg())
+    }
+
+    pub fn try_get_single_i64_arg(&self) -> Option<i64> {
+        self.has_category(parser::InstructionCategory::SingleLebInteger)
+            .then(|| self.get_single_i64_arg())
+    }
+
+    pub fn try_get_single_f32_arg(&self) -> Option<f32> {
+        self.has_category(parser::InstructionCategory::SingleFloat)
+            .then(|| self.get_single_f32_arg())
+    }
+
+    pub fn try_get_single_f64_arg(&self) -> Option<f64> {
+        self.has_category(parser::InstructionCategory::SingleDouble)
+            .then(|| self.get_single_f64_arg())
+    }
+
+    pub fn try_get_pair_u32_arg(&self) -> Option<(u32, u32)> {
+        self.has_category(parser::InstructionCategory::TwoLebInteger)
+            .then(|| self.get_pair_u32_arg())
+    }
+
+    pub fn try_get_mem_arg(&self) -> Option<parser::MemArg> {
+        self.has_category(parser::InstructionCategory::MemArg)
+            .then(|| self.get_mem_arg())
+    }
+
+    pub fn try_get_block_type(&self) -> Option<BlockType> {
+        self.is_block_start().then(|| self.get_block_type())
+    }
+
+    pub fn try_get_block(&self) -> Option<&'a [u8]> {
+        self.is_block_start().then(|| self.get_block())
+    }
+
+    pub fn try_get_else_block(&self) -> Option<&'a [u8]> {
+        (self.is_block_start() && self.has_else_block()).then(|| self.get_else_block())
+    }
+
+    pub fn try_get_block_table_targets(&self) -> Option<Vec<usize>> {
+        self.has_category(parser::InstructionCategory::BranchTable)
+            .then(|| self.get_block_table_targets())
+    }
+
+    /// Decode whatever immediates the instruction has, whatever its category
+    pub fn immediates(&self) -> InstructionImmediates<'a> {
+        self.try_immediates().expect(CHECKED)
+    }
+
+    fn try_immediates(&self) -> Result<InstructionImmediates<'a>> {
+        let (cat, acc, offset) = (&self.cat, &self.acc, self.arg_offset);
+        Ok(match cat {
+            parser::InstructionCategory::SingleByte
+            | parser::InstructionCategory::Else
+            | parser::InstructionCategory::End
+            | parser::InstructionCategory::MiscPrefixed
+            | parser::InstructionCategory::AtomicPrefixed
+            | parser::InstructionCategory::ExtensionPrefixed => InstructionImmediates::None,
+            parser::InstructionCategory::SingleLebInteger => match self.opcode {
+                parser::Opcode::I32Const => {
+                    InstructionImmediates::I32(cat.get_single_i32_arg(acc, offset)?)
+                }
+                parser::Opcode::I64Const => {
+                    InstructionImmediates::I64(cat.get_single_i64_arg(acc, offset)?)
+                }
+                _ => InstructionImmediates::Index(cat.get_single_u32_arg(acc, offset)?),
+            },
+            parser::InstructionCategory::SingleFloat => {
+                InstructionImmediates::F32(self.get_single_f32_arg())
+            }
+            parser::InstructionCategory::SingleDouble => {
+                InstructionImmediates::F64(self.get_single_f64_arg())
+            }
+            parser::InstructionCategory::TwoLebInteger => {
+                let (first, second) = cat.get_pair_u32_arg(acc, offset)?;
+                InstructionImmediates::Pair(first, second)
+            }
+            parser::InstructionCategory::MemArg => {
+                InstructionImmediates::MemArg(cat.get_mem_arg(acc, offset)?)
+            }
+            parser::InstructionCategory::BranchTable => {
+                InstructionImmediates::BranchTable(cat.get_block_table_targets(acc, offset)?)
+            }
+            parser::InstructionCategory::Block(_) => InstructionImmediates::Block {
+                block_type: cat.get_block_type(acc, offset)?,
+                block: self.get_block(),
+                else_block: self.try_get_else_block(),
+            },
+        })
+    }
+
+    fn has_category(&self, cat: parser::InstructionCategory) -> bool {
+        self.cat == cat
     }
 }
 
+/// The immediates of an instruction, decoded according to its category. Integer immediates
+/// are signed for the const instructions and unsigned indices for everything else.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstructionImmediates<'a> {
+    None,
+    Index(u32),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Pair(u32, u32),
+    MemArg(parser::MemArg),
+    /// The branch targets, with the default target last
+    BranchTable(Vec<usize>),
+    Block {
+        block_type: BlockType,
+        block: &'a [u8],
+        else_block: Option<&'a [u8]>,
+    },
+}
+
 pub struct InstructionIterator<'a, Source: InstructionSource + ?Sized> {
     source: &'a Source,
     current_instr_start: usize,
@@ -138,20 +392,25 @@ impl<'a, Source: InstructionSource + ?Sized> InstructionIterator<'a, Source> {
         // So, we can forget about any previous instruction now and move on
         self.current_instr_start = self.current_instr_end;
 
-        // There must be at least one byte beyond the end of the current instruction, otherwise
-        // we shouldn't have got here
-        assert!(self.source.get_instruction_bytes().len() > self.current_instr_end);
+        // Offsets in the instruction count from its start, so count them from the start of
+        // the expression instead
+        let start = self.current_instr_start;
+        self.decode_next()
+            .map_err(|e| parser::InvalidOpcode::rebase(e, start))
+    }
 
+    fn decode_next(&mut self) -> Result<Instruction<'a>> {
+        self.ensure_bytes(1)?;
         let lead_byte = self.get_byte(0);
-        let lead_byte = parser::InstructionCategory::from_lead_byte(lead_byte)?;
+        let lead_byte = parser::InstructionCategory::from_lead_byte(lead_byte, 0)?;
         let instr_data = lead_byte.ensure_instruction(self, 0)?;
 
         self.current_instr_end += instr_data.length();
 
-        Ok(Instruction::new(
+        Instruction::new(
             &self.source.get_instruction_bytes()[self.current_instr_start..self.current_instr_end],
             instr_data,
-        ))
+        )
     }
 }
 
@@ -163,12 +422,20 @@ impl<'a, Source: InstructionSource + ?Sized> Iterator for InstructionIterator<'a
             match self.next_internal() {
                 Ok(instr) => {
                     if instr.is_block_end() {
-                        // This is the "end" instruction - we don't return this, but we check that it should
-                        // be at the end of the expression
-                        assert!(
-                            self.current_instr_end == self.source.get_instruction_bytes().len()
-                        );
-                        None
+                        // This is the "end" instruction - we don't return this, but it has to be
+                        // at the end of the expression
+                        let length = self.source.get_instruction_bytes().len();
+                        if self.current_instr_end == length {
+                            None
+                        } else {
+                            // Don't go any further once the expression is known to be bad
+                            let offset = self.current_instr_start;
+                            self.current_instr_end = length;
+                            Some(Err(anyhow!(
+                                "End at offset {} comes before the end of the expression",
+                                offset
+                            )))
+                        }
                     } else {
                         Some(Ok(instr))
                     }
@@ -227,3 +494,133 @@ impl<T: AsRef<[u8]> + ?Sized> InstructionSource for T {
         self.as_ref()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_immediates() {
+        let expr: &[u8] = &[
+            0x41, 0x7B, // i32.const -5
+            0x20, 0x03, // local.get 3
+            0x43, 0x00, 0x00, 0xC0, 0x3F, // f32.const 1.5
+            0x28, 0x02, 0x08, // i32.load 2 8
+            0x36, 0x42, 0x01, 0x04, // i32.store 2 (memory 1) 4
+            0x0E, 0x01, 0x00, 0x01, // br_table 0 1
+            0x04, 0x7F, 0x41, 0x01, 0x05, 0x41, 0x02, 0x0B, // if i32 ... else ... end
+            0x01, // nop
+            0x0B,
+        ];
+        let instructions: Vec<_> = InstructionSource::iter(expr).map(|i| i.unwrap()).collect();
+
+        let immediates: Vec<_> = instructions.iter().map(|i| i.immediates()).collect();
+        assert_eq!(
+            immediates,
+            vec![
+                InstructionImmediates::I32(-5),
+                InstructionImmediates::Index(3),
+                InstructionImmediates::F32(1.5),
+                InstructionImmediates::MemArg(parser::MemArg::new(2, 0, 8)),
+                InstructionImmediates::MemArg(parser::MemArg::new(2, 1, 4)),
+                InstructionImmediates::BranchTable(vec![0, 1]),
+                InstructionImmediates::Block {
+                    block_type: BlockType::I32,
+                    block: &[0x41, 0x01],
+                    else_block: Some(&[0x41, 0x02]),
+                },
+                InstructionImmediates::None,
+            ]
+        );
+
+        // Asking for the wrong kind of immediate gives nothing back instead of panicking
+        let (local_get, f32_const, nop) = (&instructions[1], &instructions[2], &instructions[7]);
+        assert_eq!(local_get.try_get_single_u32_arg(), Some(3));
+        assert_eq!(local_get.try_get_single_f32_arg(), None);
+        assert_eq!(local_get.try_get_block_type(), None);
+        assert_eq!(f32_const.try_get_single_u32_arg(), None);
+        assert_eq!(nop.try_get_pair_u32_arg(), None);
+        assert_eq!(nop.try_get_block_table_targets(), None);
+        assert_eq!(nop.try_misc_opcode(), None);
+        assert_eq!(nop.try_get_mem_arg(), None);
+        assert_eq!(
+            instructions[6].try_get_else_block(),
+            Some(&[0x41, 0x02][..])
+        );
+    }
+
+    #[test]
+    fn test_branch_table_targets() {
+        // br_table 1 200 0, where 200 takes two bytes
+        let expr: &[u8] = &[0x0E, 0x02, 0x01, 0xC8, 0x01, 0x00, 0x0B];
+        let instruction = InstructionSource::iter(expr).next().unwrap().unwrap();
+
+        let targets = instruction.block_table_targets();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets.collect::<Vec<_>>(), vec![1, 200, 0]);
+        assert_eq!(instruction.block_table_targets().nth(2), Some(0));
+        assert_eq!(instruction.block_table_targets().nth(3), None);
+        assert_eq!(instruction.get_block_table_targets(), vec![1, 200, 0]);
+    }
+
+    #[test]
+    fn test_invalid_opcodes() {
+        let invalid_opcode = |expr: &[u8]| {
+            InstructionSource::iter(expr)
+                .find_map(Result::err)
+                .unwrap()
+                .downcast::<parser::InvalidOpcode>()
+                .unwrap()
+        };
+
+        // The offset counts from the start of the expression, wherever the byte is
+        assert_eq!(
+            invalid_opcode(&[0x01, 0x41, 0x00, 0x06, 0x0B]),
+            parser::InvalidOpcode::new(0x06, 3)
+        );
+        assert_eq!(
+            invalid_opcode(&[0x01, 0x02, 0x40, 0x03, 0x40, 0x01, 0xD3, 0x0B, 0x0B, 0x0B]),
+            parser::InvalidOpcode::new(0xD3, 6)
+        );
+        assert_eq!(
+            invalid_opcode(&[0x04, 0x40, 0x05, 0x0A, 0x0B, 0x0B]),
+            parser::InvalidOpcode::new(0x0A, 3)
+        );
+        assert_eq!(
+            format!("{}", invalid_opcode(&[0xFD, 0x0B])),
+            "Invalid opcode byte 0xfd at offset 0"
+        );
+
+        // The same goes for expressions read from a module
+        let error = parser::read_expression_bytes(&mut &[0x01, 0x02, 0x40, 0x1E, 0x0B, 0x0B][..])
+            .unwrap_err();
+        assert_eq!(
+            error.downcast::<parser::InvalidOpcode>().unwrap(),
+            parser::InvalidOpcode::new(0x1E, 3)
+        );
+
+        // An end before the end of the expression is an error rather than a panic
+        let expr: &[u8] = &[0x01, 0x0B, 0x01, 0x0B];
+        let error = InstructionSource::iter(expr).find_map(Result::err).unwrap();
+        assert!(format!("{}", error).contains("End at offset 1"));
+    }
+
+    #[test]
+    fn test_block_types() {
+        let expr: &[u8] = &[
+            0x02, 0x40, 0x01, 0x0B, // block (empty) nop end
+            0x03, 0x80, 0x01, 0x01, 0x0B, // loop (type 128) nop end
+            0x0B,
+        ];
+        let instructions: Vec<_> = InstructionSource::iter(expr).map(|i| i.unwrap()).collect();
+
+        assert_eq!(instructions[0].get_block_type(), BlockType::None);
+        assert_eq!(instructions[0].get_block(), &[0x01]);
+        assert_eq!(instructions[1].get_block_type(), BlockType::TypeIdx(128));
+        assert_eq!(instructions[1].get_block(), &[0x01]);
+
+        // Negative values other than the single byte forms aren't block types
+        let expr: &[u8] = &[0x02, 0x50, 0x0B, 0x0B];
+        assert!(InstructionSource::iter(expr).next().unwrap().is_err());
+    }
+}
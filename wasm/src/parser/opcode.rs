@@ -1,5 +1,6 @@
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::convert::TryInto;
+use std::fmt;
 use std::io::{Error, ErrorKind, Result};
 
 #[derive(Debug, Copy, Clone, PartialEq, TryFromPrimitive, IntoPrimitive)]
@@ -19,19 +20,25 @@ pub enum Opcode {
     Return = 0x0F,
     Call = 0x10,
     CallIndirect = 0x11,
+    ReturnCall = 0x12,
+    ReturnCallIndirect = 0x13,
 
-    // 0x12 ..= 0x19 are not listed in the spec
+    // 0x14 ..= 0x19 are not listed in the spec
     Drop = 0x1A,
     Select = 0x1B,
+    SelectTyped = 0x1C,
 
-    // 0x1C ..= 0x1F are not listed in the spec
+    // 0x1D ..= 0x1F are not listed in the spec
     LocalGet = 0x20,
     LocalSet = 0x21,
     LocalTee = 0x22,
     GlobalGet = 0x23,
     GlobalSet = 0x24,
 
-    // 0x25 ..= 0x27 are not listed in the spec
+    TableGet = 0x25,
+    TableSet = 0x26,
+
+    // 0x27 is not listed in the spec
     I32Load = 0x28,
     I64Load = 0x29,
     F32Load = 0x2A,
@@ -194,9 +201,68 @@ pub enum Opcode {
     I64ReinterpretF64 = 0xBD,
     F32ReinterpretI32 = 0xBE,
     F64ReinterpretI64 = 0xBF,
-    // 0xC0 ..= 0xFF are not listed in the spec
+    I32Extend8S = 0xC0,
+    I32Extend16S = 0xC1,
+    I64Extend8S = 0xC2,
+    I64Extend16S = 0xC3,
+    I64Extend32S = 0xC4,
+
+    // 0xC5 ..= 0xCF are not listed in the spec
+    RefNull = 0xD0,
+    RefIsNull = 0xD1,
+    RefFunc = 0xD2,
+
+    // 0xD3 ..= 0xFB are not listed in the spec
+    MiscPrefix = 0xFC,
+    // 0xFD is the SIMD prefix, which we don't support
+    AtomicPrefix = 0xFE,
+    // 0xFF is not listed in the spec, so we use it for instructions defined by the embedder
+    ExtensionPrefix = 0xFF,
+}
+
+/// A byte which doesn't start any instruction, along with where it was found, counting
+/// from the start of the instructions being decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidOpcode {
+    byte: u8,
+    offset: usize,
+}
+
+impl InvalidOpcode {
+    pub fn new(byte: u8, offset: usize) -> Self {
+        Self { byte, offset }
+    }
+
+    pub fn byte(&self) -> u8 {
+        self.byte
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Count the offset of an invalid opcode in the error from `base` bytes earlier, for
+    /// when the instruction it was found in started there. Other errors are unchanged.
+    pub(crate) fn rebase(error: anyhow::Error, base: usize) -> anyhow::Error {
+        match error.downcast::<InvalidOpcode>() {
+            Ok(invalid) => InvalidOpcode::new(invalid.byte, base + invalid.offset).into(),
+            Err(error) => error,
+        }
+    }
+}
+
+impl fmt::Display for InvalidOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid opcode byte 0x{:02x} at offset {}",
+            self.byte, self.offset
+        )
+    }
 }
 
+impl std::error::Error for InvalidOpcode {}
+
 impl Opcode {
     pub fn from_byte(byte: u8) -> Result<Opcode> {
         match byte.try_into() {
@@ -207,4 +273,179 @@ impl Opcode {
             )),
         }
     }
+
+    /// The largest alignment hint a load or store may have, as a power of two, which is
+    /// the number of bytes it accesses
+    pub fn natural_alignment(self) -> Option<u32> {
+        match self {
+            Opcode::I32Load8S
+            | Opcode::I32Load8U
+            | Opcode::I64Load8S
+            | Opcode::I64Load8U
+            | Opcode::I32Store8
+            | Opcode::I64Store8 => Some(0),
+            Opcode::I32Load16S
+            | Opcode::I32Load16U
+            | Opcode::I64Load16S
+            | Opcode::I64Load16U
+            | Opcode::I32Store16
+            | Opcode::I64Store16 => Some(1),
+            Opcode::I32Load
+            | Opcode::F32Load
+            | Opcode::I64Load32S
+            | Opcode::I64Load32U
+            | Opcode::I32Store
+            | Opcode::F32Store
+            | Opcode::I64Store32 => Some(2),
+            Opcode::I64Load | Opcode::F64Load | Opcode::I64Store | Opcode::F64Store => Some(3),
+            _ => None,
+        }
+    }
+}
+
+// Instructions introduced after the MVP live behind the 0xFC prefix byte, and are identified
+// by a LEB encoded u32 following the prefix
+#[derive(Debug, Copy, Clone, PartialEq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum MiscOpcode {
+    I32TruncSatF32S = 0x00,
+    I32TruncSatF32U = 0x01,
+    I32TruncSatF64S = 0x02,
+    I32TruncSatF64U = 0x03,
+    I64TruncSatF32S = 0x04,
+    I64TruncSatF32U = 0x05,
+    I64TruncSatF64S = 0x06,
+    I64TruncSatF64U = 0x07,
+    MemoryInit = 0x08,
+    DataDrop = 0x09,
+    MemoryCopy = 0x0A,
+    MemoryFill = 0x0B,
+    TableInit = 0x0C,
+    ElemDrop = 0x0D,
+    TableCopy = 0x0E,
+    TableGrow = 0x0F,
+    TableSize = 0x10,
+    TableFill = 0x11,
+}
+
+impl MiscOpcode {
+    pub fn from_u32(value: u32) -> Result<MiscOpcode> {
+        match value.try_into() {
+            Ok(v) => Ok(v),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid 0xFC prefixed opcode 0x{:02x}", value),
+            )),
+        }
+    }
+}
+
+// The threads proposal puts its instructions behind the 0xFE prefix byte, in the same way
+// as the 0xFC instructions
+#[derive(Debug, Copy, Clone, PartialEq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum AtomicOpcode {
+    MemoryAtomicNotify = 0x00,
+    MemoryAtomicWait32 = 0x01,
+    MemoryAtomicWait64 = 0x02,
+    AtomicFence = 0x03,
+
+    I32AtomicLoad = 0x10,
+    I64AtomicLoad = 0x11,
+    I32AtomicLoad8U = 0x12,
+    I32AtomicLoad16U = 0x13,
+    I64AtomicLoad8U = 0x14,
+    I64AtomicLoad16U = 0x15,
+    I64AtomicLoad32U = 0x16,
+    I32AtomicStore = 0x17,
+    I64AtomicStore = 0x18,
+    I32AtomicStore8 = 0x19,
+    I32AtomicStore16 = 0x1A,
+    I64AtomicStore8 = 0x1B,
+    I64AtomicStore16 = 0x1C,
+    I64AtomicStore32 = 0x1D,
+
+    I32AtomicRmwAdd = 0x1E,
+    I64AtomicRmwAdd = 0x1F,
+    I32AtomicRmw8AddU = 0x20,
+    I32AtomicRmw16AddU = 0x21,
+    I64AtomicRmw8AddU = 0x22,
+    I64AtomicRmw16AddU = 0x23,
+    I64AtomicRmw32AddU = 0x24,
+
+    I32AtomicRmwSub = 0x25,
+    I64AtomicRmwSub = 0x26,
+    I32AtomicRmw8SubU = 0x27,
+    I32AtomicRmw16SubU = 0x28,
+    I64AtomicRmw8SubU = 0x29,
+    I64AtomicRmw16SubU = 0x2A,
+    I64AtomicRmw32SubU = 0x2B,
+
+    I32AtomicRmwAnd = 0x2C,
+    I64AtomicRmwAnd = 0x2D,
+    I32AtomicRmw8AndU = 0x2E,
+    I32AtomicRmw16AndU = 0x2F,
+    I64AtomicRmw8AndU = 0x30,
+    I64AtomicRmw16AndU = 0x31,
+    I64AtomicRmw32AndU = 0x32,
+
+    I32AtomicRmwOr = 0x33,
+    I64AtomicRmwOr = 0x34,
+    I32AtomicRmw8OrU = 0x35,
+    I32AtomicRmw16OrU = 0x36,
+    I64AtomicRmw8OrU = 0x37,
+    I64AtomicRmw16OrU = 0x38,
+    I64AtomicRmw32OrU = 0x39,
+
+    I32AtomicRmwXor = 0x3A,
+    I64AtomicRmwXor = 0x3B,
+    I32AtomicRmw8XorU = 0x3C,
+    I32AtomicRmw16XorU = 0x3D,
+    I64AtomicRmw8XorU = 0x3E,
+    I64AtomicRmw16XorU = 0x3F,
+    I64AtomicRmw32XorU = 0x40,
+
+    I32AtomicRmwXchg = 0x41,
+    I64AtomicRmwXchg = 0x42,
+    I32AtomicRmw8XchgU = 0x43,
+    I32AtomicRmw16XchgU = 0x44,
+    I64AtomicRmw8XchgU = 0x45,
+    I64AtomicRmw16XchgU = 0x46,
+    I64AtomicRmw32XchgU = 0x47,
+
+    I32AtomicRmwCmpxchg = 0x48,
+    I64AtomicRmwCmpxchg = 0x49,
+    I32AtomicRmw8CmpxchgU = 0x4A,
+    I32AtomicRmw16CmpxchgU = 0x4B,
+    I64AtomicRmw8CmpxchgU = 0x4C,
+    I64AtomicRmw16CmpxchgU = 0x4D,
+    I64AtomicRmw32CmpxchgU = 0x4E,
+}
+
+impl AtomicOpcode {
+    pub fn from_u32(value: u32) -> Result<AtomicOpcode> {
+        match value.try_into() {
+            Ok(v) => Ok(v),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid 0xFE prefixed opcode 0x{:02x}", value),
+            )),
+        }
+    }
+
+    /// The alignment of the memory access, as a power of two, or `None` for the fence
+    pub fn natural_alignment(self) -> Option<u32> {
+        // From the first load on, the instructions come in groups of seven with the same
+        // run of access sizes
+        const GROUP_ALIGNMENTS: [u32; 7] = [2, 3, 0, 1, 0, 1, 2];
+        match self {
+            AtomicOpcode::MemoryAtomicNotify | AtomicOpcode::MemoryAtomicWait32 => Some(2),
+            AtomicOpcode::MemoryAtomicWait64 => Some(3),
+            AtomicOpcode::AtomicFence => None,
+            _ => {
+                let index = u32::from(self) - AtomicOpcode::I32AtomicLoad as u32;
+                Some(GROUP_ALIGNMENTS[(index % 7) as usize])
+            }
+        }
+    }
 }
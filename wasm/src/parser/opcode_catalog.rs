@@ -0,0 +1,250 @@
+use std::fmt;
+
+use crate::parser::{AtomicOpcode, MiscOpcode, Opcode};
+
+pub const MISC_PREFIX: u8 = 0xFC;
+pub const SIMD_PREFIX: u8 = 0xFD;
+pub const ATOMIC_PREFIX: u8 = 0xFE;
+pub const EXTENSION_PREFIX: u8 = 0xFF;
+
+// Proposals layered on top of the MVP that we know how to recognise in a module, whether
+// or not the interpreter can execute them yet
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Feature {
+    SignExtension,
+    SaturatingFloatToInt,
+    MultiValue,
+    BulkMemory,
+    ReferenceTypes,
+    TailCall,
+    ExceptionHandling,
+    Simd,
+    Threads,
+    Memory64,
+    /// An instruction from an extension registered by the embedder
+    Extension,
+    Unknown,
+}
+
+impl Feature {
+    /// Every proposal, which is everything apart from `Extension` and `Unknown`
+    pub const PROPOSALS: [Feature; 10] = [
+        Feature::SignExtension,
+        Feature::SaturatingFloatToInt,
+        Feature::MultiValue,
+        Feature::BulkMemory,
+        Feature::ReferenceTypes,
+        Feature::TailCall,
+        Feature::ExceptionHandling,
+        Feature::Simd,
+        Feature::Threads,
+        Feature::Memory64,
+    ];
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Feature::SignExtension => "sign-extension",
+            Feature::SaturatingFloatToInt => "saturating-float-to-int",
+            Feature::MultiValue => "multi-value",
+            Feature::BulkMemory => "bulk-memory",
+            Feature::ReferenceTypes => "reference-types",
+            Feature::TailCall => "tail-call",
+            Feature::ExceptionHandling => "exception-handling",
+            Feature::Simd => "simd",
+            Feature::Threads => "threads",
+            Feature::Memory64 => "memory64",
+            Feature::Extension => "extension",
+            Feature::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// The shape of the immediate arguments that follow an opcode. This is only as detailed
+// as it needs to be to step over an instruction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Immediates {
+    None,
+    BlockType,
+    Leb,
+    TwoLeb,
+    ValueTypes,
+    Byte,
+    Bytes16,
+    MemArgLane,
+    TryTable,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpcodeInfo {
+    pub name: String,
+    pub feature: Feature,
+    pub immediates: Immediates,
+    pub supported: bool,
+}
+
+pub fn is_prefix_byte(byte: u8) -> bool {
+    byte == MISC_PREFIX || byte == SIMD_PREFIX || byte == ATOMIC_PREFIX || byte == EXTENSION_PREFIX
+}
+
+// Whether the interpreter itself knows how to decode and execute the opcode
+fn is_supported(opcode: u8, sub_opcode: Option<u32>) -> bool {
+    match (Opcode::from_byte(opcode), sub_opcode) {
+        (Ok(Opcode::MiscPrefix), Some(sub_opcode)) => MiscOpcode::from_u32(sub_opcode).is_ok(),
+        (Ok(Opcode::AtomicPrefix), Some(sub_opcode)) => AtomicOpcode::from_u32(sub_opcode).is_ok(),
+        (Ok(_), None) => true,
+        _ => false,
+    }
+}
+
+fn named(
+    name: &str,
+    feature: Feature,
+    immediates: Immediates,
+) -> Option<(String, Feature, Immediates)> {
+    Some((name.to_string(), feature, immediates))
+}
+
+fn lookup_single_byte(opcode: u8) -> Option<(String, Feature, Immediates)> {
+    match opcode {
+        0x06 => named("try", Feature::ExceptionHandling, Immediates::BlockType),
+        0x07 => named("catch", Feature::ExceptionHandling, Immediates::Leb),
+        0x08 => named("throw", Feature::ExceptionHandling, Immediates::Leb),
+        0x09 => named("rethrow", Feature::ExceptionHandling, Immediates::Leb),
+        0x0A => named("throw_ref", Feature::ExceptionHandling, Immediates::None),
+        0x12 => named("return_call", Feature::TailCall, Immediates::Leb),
+        0x13 => named(
+            "return_call_indirect",
+            Feature::TailCall,
+            Immediates::TwoLeb,
+        ),
+        0x18 => named("delegate", Feature::ExceptionHandling, Immediates::Leb),
+        0x19 => named("catch_all", Feature::ExceptionHandling, Immediates::None),
+        0x1C => named("select t", Feature::ReferenceTypes, Immediates::ValueTypes),
+        0x1F => named(
+            "try_table",
+            Feature::ExceptionHandling,
+            Immediates::TryTable,
+        ),
+        0x25 => named("table.get", Feature::ReferenceTypes, Immediates::Leb),
+        0x26 => named("table.set", Feature::ReferenceTypes, Immediates::Leb),
+        0xC0 => named("i32.extend8_s", Feature::SignExtension, Immediates::None),
+        0xC1 => named("i32.extend16_s", Feature::SignExtension, Immediates::None),
+        0xC2 => named("i64.extend8_s", Feature::SignExtension, Immediates::None),
+        0xC3 => named("i64.extend16_s", Feature::SignExtension, Immediates::None),
+        0xC4 => named("i64.extend32_s", Feature::SignExtension, Immediates::None),
+        0xD0 => named("ref.null", Feature::ReferenceTypes, Immediates::Leb),
+        0xD1 => named("ref.is_null", Feature::ReferenceTypes, Immediates::None),
+        0xD2 => named("ref.func", Feature::ReferenceTypes, Immediates::Leb),
+        _ => None,
+    }
+}
+
+fn lookup_misc(sub_opcode: u32) -> Option<(String, Feature, Immediates)> {
+    match sub_opcode {
+        0x00 => named(
+            "i32.trunc_sat_f32_s",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x01 => named(
+            "i32.trunc_sat_f32_u",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x02 => named(
+            "i32.trunc_sat_f64_s",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x03 => named(
+            "i32.trunc_sat_f64_u",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x04 => named(
+            "i64.trunc_sat_f32_s",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x05 => named(
+            "i64.trunc_sat_f32_u",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x06 => named(
+            "i64.trunc_sat_f64_s",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x07 => named(
+            "i64.trunc_sat_f64_u",
+            Feature::SaturatingFloatToInt,
+            Immediates::None,
+        ),
+        0x08 => named("memory.init", Feature::BulkMemory, Immediates::TwoLeb),
+        0x09 => named("data.drop", Feature::BulkMemory, Immediates::Leb),
+        0x0A => named("memory.copy", Feature::BulkMemory, Immediates::TwoLeb),
+        0x0B => named("memory.fill", Feature::BulkMemory, Immediates::Leb),
+        0x0C => named("table.init", Feature::BulkMemory, Immediates::TwoLeb),
+        0x0D => named("elem.drop", Feature::BulkMemory, Immediates::Leb),
+        0x0E => named("table.copy", Feature::BulkMemory, Immediates::TwoLeb),
+        0x0F => named("table.grow", Feature::ReferenceTypes, Immediates::Leb),
+        0x10 => named("table.size", Feature::ReferenceTypes, Immediates::Leb),
+        0x11 => named("table.fill", Feature::ReferenceTypes, Immediates::Leb),
+        _ => None,
+    }
+}
+
+// There are far too many SIMD and atomic instructions to be worth naming individually, so
+// we only track enough to step over them
+fn lookup_simd(sub_opcode: u32) -> Option<(String, Feature, Immediates)> {
+    let immediates = match sub_opcode {
+        0x00..=0x0B | 0x5C | 0x5D => Immediates::TwoLeb,
+        0x0C | 0x0D => Immediates::Bytes16,
+        0x15..=0x22 => Immediates::Byte,
+        0x54..=0x5B => Immediates::MemArgLane,
+        0x0E..=0x14 | 0x23..=0x53 | 0x5E..=0x113 => Immediates::None,
+        _ => return None,
+    };
+    Some((
+        format!("simd 0x{:02X}", sub_opcode),
+        Feature::Simd,
+        immediates,
+    ))
+}
+
+fn lookup_atomic(sub_opcode: u32) -> Option<(String, Feature, Immediates)> {
+    let immediates = match sub_opcode {
+        0x03 => Immediates::Byte,
+        0x00..=0x02 | 0x10..=0x4E => Immediates::TwoLeb,
+        _ => return None,
+    };
+    Some((
+        format!("atomic 0x{:02X}", sub_opcode),
+        Feature::Threads,
+        immediates,
+    ))
+}
+
+/// Look up an opcode which is not part of the MVP instruction set. The sub opcode must be
+/// supplied for the prefix bytes. Returns None for MVP opcodes and for anything unknown.
+pub fn lookup_opcode(opcode: u8, sub_opcode: Option<u32>) -> Option<OpcodeInfo> {
+    let (name, feature, immediates) = match (opcode, sub_opcode) {
+        (MISC_PREFIX, Some(sub_opcode)) => lookup_misc(sub_opcode),
+        (SIMD_PREFIX, Some(sub_opcode)) => lookup_simd(sub_opcode),
+        (ATOMIC_PREFIX, Some(sub_opcode)) => lookup_atomic(sub_opcode),
+        (opcode, None) => lookup_single_byte(opcode),
+        _ => None,
+    }?;
+
+    Some(OpcodeInfo {
+        name,
+        feature,
+        immediates,
+        supported: is_supported(opcode, sub_opcode),
+    })
+}
@@ -1,9 +1,26 @@
+mod conformance;
+mod fingerprint;
 mod module_reader;
+mod module_scanner;
+mod parser_config;
+mod position_reader;
 mod reader_util;
 mod scoped_reader;
+mod sha256;
+#[cfg(test)]
+mod test_module;
 mod type_reader;
 
+pub use conformance::{
+    conformance_report, ConformanceReport, NonInstructionSupport, OpcodeSupport, ProposalSupport,
+    SupportStatus,
+};
+pub(crate) use fingerprint::import_signature;
+pub use fingerprint::{fingerprint_module, ImportSignature, ModuleFingerprint};
 pub use module_reader::*;
+pub use module_scanner::{scan_module, scan_module_from_path, FeatureUsage, ScanLocation};
+pub use parser_config::{checked_size, ParserConfig};
+pub use position_reader::*;
 pub use reader_util::*;
 pub use scoped_reader::*;
 pub use type_reader::*;
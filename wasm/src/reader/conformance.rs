@@ -0,0 +1,289 @@
+//! A report of what the interpreter supports, worked out from the opcode catalog and the
+//! scanner's feature gates so that it can't drift from the code. `CONFORMANCE.md` at the
+//! root of the crate is generated from it, and a test checks that it is current.
+
+use std::fmt;
+use std::fmt::Write;
+
+use crate::parser::{is_prefix_byte, lookup_opcode, AtomicOpcode, Feature, MiscOpcode, Opcode};
+use crate::reader::module_scanner::NON_INSTRUCTION_SUPPORT;
+
+// No prefix has sub opcodes beyond this
+const MAX_SUB_OPCODE: u32 = 0x1FF;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SupportStatus {
+    Supported,
+    Partial,
+    Unsupported,
+}
+
+impl fmt::Display for SupportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SupportStatus::Supported => "supported",
+            SupportStatus::Partial => "partial",
+            SupportStatus::Unsupported => "unsupported",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// An opcode, which belongs to the MVP when it has no feature
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OpcodeSupport {
+    /// The lead byte, and the sub opcode for prefixed instructions, in hex
+    pub encoding: String,
+    /// The interpreter's name for the opcode, or the catalog's if it can't decode it
+    pub name: String,
+    pub feature: Option<Feature>,
+    pub supported: bool,
+}
+
+/// Part of a proposal which isn't an instruction, such as a new kind of type
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NonInstructionSupport {
+    pub feature: Feature,
+    pub name: String,
+    pub supported: bool,
+}
+
+/// How much of a proposal is supported, counting its opcodes and everything else it adds
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProposalSupport {
+    pub feature: Option<Feature>,
+    pub status: SupportStatus,
+    pub supported: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConformanceReport {
+    /// The MVP first, then each proposal
+    pub proposals: Vec<ProposalSupport>,
+    pub non_instructions: Vec<NonInstructionSupport>,
+    /// In encoding order
+    pub opcodes: Vec<OpcodeSupport>,
+}
+
+fn feature_name(feature: Option<Feature>) -> String {
+    match feature {
+        Some(feature) => feature.to_string(),
+        None => "mvp".to_string(),
+    }
+}
+
+fn yes_no(supported: bool) -> &'static str {
+    if supported {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+impl ConformanceReport {
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        self.write_markdown(&mut out).unwrap();
+        out
+    }
+
+    fn write_markdown(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "# Conformance")?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "Generated by `wasm::reader::conformance_report`. Don't edit this by hand; run the \
+             tests with `UPDATE_CONFORMANCE=1` to regenerate it."
+        )?;
+
+        writeln!(out)?;
+        writeln!(out, "## Proposals")?;
+        writeln!(out)?;
+        writeln!(out, "| Proposal | Status | Supported |")?;
+        writeln!(out, "| --- | --- | --- |")?;
+        for proposal in &self.proposals {
+            writeln!(
+                out,
+                "| {} | {} | {}/{} |",
+                feature_name(proposal.feature),
+                proposal.status,
+                proposal.supported,
+                proposal.total
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "## Other features")?;
+        writeln!(out)?;
+        writeln!(out, "| Proposal | Feature | Supported |")?;
+        writeln!(out, "| --- | --- | --- |")?;
+        for item in &self.non_instructions {
+            writeln!(
+                out,
+                "| {} | {} | {} |",
+                item.feature,
+                item.name,
+                yes_no(item.supported)
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "## Opcodes")?;
+        writeln!(out)?;
+        writeln!(out, "| Encoding | Name | Proposal | Supported |")?;
+        writeln!(out, "| --- | --- | --- | --- |")?;
+        for opcode in &self.opcodes {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} |",
+                opcode.encoding,
+                opcode.name,
+                feature_name(opcode.feature),
+                yes_no(opcode.supported)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_opcode(opcode: u8, sub_opcode: Option<u32>) -> Option<OpcodeSupport> {
+    let decoded = match (Opcode::from_byte(opcode), sub_opcode) {
+        (Ok(Opcode::MiscPrefix), Some(sub_opcode)) => MiscOpcode::from_u32(sub_opcode)
+            .ok()
+            .map(|misc_opcode| format!("{:?}", misc_opcode)),
+        (Ok(Opcode::AtomicPrefix), Some(sub_opcode)) => AtomicOpcode::from_u32(sub_opcode)
+            .ok()
+            .map(|atomic_opcode| format!("{:?}", atomic_opcode)),
+        (Ok(opcode), None) => Some(format!("{:?}", opcode)),
+        _ => None,
+    };
+
+    // Anything the catalog doesn't know about but the interpreter can decode is in the MVP
+    let (name, feature, supported) = match (lookup_opcode(opcode, sub_opcode), decoded) {
+        (Some(info), decoded) => (
+            decoded.unwrap_or(info.name),
+            Some(info.feature),
+            info.supported,
+        ),
+        (None, Some(name)) => (name, None, true),
+        (None, None) => return None,
+    };
+
+    let encoding = match sub_opcode {
+        Some(sub_opcode) => format!("0x{:02X} 0x{:02X}", opcode, sub_opcode),
+        None => format!("0x{:02X}", opcode),
+    };
+    Some(OpcodeSupport {
+        encoding,
+        name,
+        feature,
+        supported,
+    })
+}
+
+fn proposal_support(
+    feature: Option<Feature>,
+    opcodes: &[OpcodeSupport],
+    non_instructions: &[NonInstructionSupport],
+) -> ProposalSupport {
+    let support: Vec<bool> = opcodes
+        .iter()
+        .filter(|opcode| opcode.feature == feature)
+        .map(|opcode| opcode.supported)
+        .chain(
+            non_instructions
+                .iter()
+                .filter(|item| Some(item.feature) == feature)
+                .map(|item| item.supported),
+        )
+        .collect();
+
+    let supported = support.iter().filter(|supported| **supported).count();
+    let status = if supported == support.len() {
+        SupportStatus::Supported
+    } else if supported == 0 {
+        SupportStatus::Unsupported
+    } else {
+        SupportStatus::Partial
+    };
+    ProposalSupport {
+        feature,
+        status,
+        supported,
+        total: support.len(),
+    }
+}
+
+pub fn conformance_report() -> ConformanceReport {
+    let mut opcodes = Vec::new();
+    for opcode in 0..=u8::MAX {
+        if is_prefix_byte(opcode) {
+            opcodes.extend(
+                (0..=MAX_SUB_OPCODE)
+                    .filter_map(|sub_opcode| describe_opcode(opcode, Some(sub_opcode))),
+            );
+        } else {
+            opcodes.extend(describe_opcode(opcode, None));
+        }
+    }
+
+    let non_instructions: Vec<NonInstructionSupport> = NON_INSTRUCTION_SUPPORT
+        .iter()
+        .map(|(feature, name, supported)| NonInstructionSupport {
+            feature: *feature,
+            name: name.to_string(),
+            supported: *supported,
+        })
+        .collect();
+
+    let proposals = std::iter::once(None)
+        .chain(Feature::PROPOSALS.iter().copied().map(Some))
+        .map(|feature| proposal_support(feature, &opcodes, &non_instructions))
+        .collect();
+
+    ConformanceReport {
+        proposals,
+        non_instructions,
+        opcodes,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conformance_report() {
+        let report = conformance_report();
+        let status = |feature| {
+            report
+                .proposals
+                .iter()
+                .find(|proposal| proposal.feature == feature)
+                .unwrap()
+                .status
+        };
+
+        assert_eq!(status(None), SupportStatus::Supported);
+        assert_eq!(status(Some(Feature::Threads)), SupportStatus::Supported);
+        assert_eq!(status(Some(Feature::Simd)), SupportStatus::Unsupported);
+        assert_eq!(
+            status(Some(Feature::ReferenceTypes)),
+            SupportStatus::Supported
+        );
+
+        let i32_add = report
+            .opcodes
+            .iter()
+            .find(|opcode| opcode.encoding == "0x6A")
+            .unwrap();
+        assert_eq!(i32_add.name, "I32Add");
+        assert!(i32_add.feature.is_none() && i32_add.supported);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::io::Cursor;
+
+use crate::core::{ImportDesc, Limits, RawModule};
+use crate::parser::Feature;
+use crate::reader::{scan_module, sha256::sha256, TypeReader};
+use anyhow::Result;
+
+/// One import of a module, with the type it has to be given. The signature is text so
+/// that imports can be compared and ordered, such as `func [I32] -> [I64]`,
+/// `memory 1..4 shared` or `global mut F32`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ImportSignature {
+    pub mod_name: String,
+    pub name: String,
+    pub signature: String,
+}
+
+/// What identifies a module, for caches and for spotting the same module being loaded
+/// more than once. Two modules only have the same fingerprint if their bytes are the
+/// same, and it doesn't change between runs or builds of the interpreter, so it can be
+/// stored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModuleFingerprint {
+    /// The SHA-256 of the module bytes
+    pub content_hash: [u8; 32],
+    /// Every proposal the module uses, whether or not the interpreter supports it
+    pub features: BTreeSet<Feature>,
+    pub imports: BTreeSet<ImportSignature>,
+}
+
+impl ModuleFingerprint {
+    /// The whole fingerprint as a hex string, to use as a key
+    pub fn key(&self) -> String {
+        let mut summary = self.content_hash.to_vec();
+        for feature in self.features.iter() {
+            summary.extend_from_slice(format!("\n{:?}", feature).as_bytes());
+        }
+        for import in self.imports.iter() {
+            summary.extend_from_slice(
+                format!("\n{}.{} {}", import.mod_name, import.name, import.signature).as_bytes(),
+            );
+        }
+
+        sha256(&summary)
+            .iter()
+            .fold(String::new(), |mut key, byte| {
+                let _ = write!(key, "{:02x}", byte);
+                key
+            })
+    }
+}
+
+fn describe_limits(limits: &Limits) -> String {
+    match limits.maximum() {
+        Some(maximum) => format!("{}..{}", limits.minimum(), maximum),
+        None => format!("{}..", limits.minimum()),
+    }
+}
+
+pub(crate) fn import_signature(module: &RawModule, desc: &ImportDesc) -> String {
+    match desc {
+        ImportDesc::TypeIdx(type_idx) => match module.types().get(*type_idx) {
+            Some(func_type) => format!(
+                "func {:?} -> {:?}",
+                func_type.arg_types(),
+                func_type.return_types()
+            ),
+            None => format!("func type {}", type_idx),
+        },
+        ImportDesc::TableType(table_type) => format!(
+            "table {:?} {}",
+            table_type.elem_type(),
+            describe_limits(table_type.limits())
+        ),
+        ImportDesc::MemType(mem_type) if mem_type.is_shared() => {
+            format!("memory {} shared", describe_limits(mem_type.limits()))
+        }
+        ImportDesc::MemType(mem_type) => format!("memory {}", describe_limits(mem_type.limits())),
+        ImportDesc::GlobalType(global_type) => format!(
+            "global {}{:?}",
+            if global_type.is_mutable() { "mut " } else { "" },
+            global_type.value_type()
+        ),
+    }
+}
+
+/// Fingerprint a module from its bytes. The module has to be one the reader accepts.
+pub fn fingerprint_module(bytes: &[u8]) -> Result<ModuleFingerprint> {
+    let module = RawModule::read(&mut Cursor::new(bytes))?;
+    let features = scan_module(&mut Cursor::new(bytes))?
+        .into_iter()
+        .map(|usage| usage.feature)
+        .collect();
+    let imports = module
+        .imports()
+        .iter()
+        .map(|import| ImportSignature {
+            mod_name: import.mod_name().to_string(),
+            name: import.name().to_string(),
+            signature: import_signature(&module, import.desc()),
+        })
+        .collect();
+
+    Ok(ModuleFingerprint {
+        content_hash: sha256(bytes),
+        features,
+        imports,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reader::test_module::make_module;
+
+    // Imports env.f of type (i32) -> i64 and a shared memory, and has one function which
+    // uses a sign extension instruction
+    fn make_test_module(memory_name: u8) -> Vec<u8> {
+        make_module(&[
+            (1, vec![0x01, 0x60, 0x01, 0x7F, 0x01, 0x7E]),
+            (
+                2,
+                vec![
+                    0x02,
+                    0x03,
+                    b'e',
+                    b'n',
+                    b'v',
+                    0x01,
+                    b'f',
+                    0x00,
+                    0x00,
+                    0x03,
+                    b'e',
+                    b'n',
+                    b'v',
+                    0x01,
+                    memory_name,
+                    0x02,
+                    0x03,
+                    0x01,
+                    0x04,
+                ],
+            ),
+            (3, vec![0x01, 0x00]),
+            (10, vec![0x01, 0x06, 0x00, 0x20, 0x00, 0xAC, 0xC4, 0x0B]),
+        ])
+    }
+
+    #[test]
+    fn test_fingerprint_module() {
+        let bytes = make_test_module(b'm');
+        let fingerprint = fingerprint_module(&bytes).unwrap();
+
+        assert_eq!(fingerprint.content_hash, sha256(&bytes));
+        assert_eq!(
+            fingerprint.features.iter().copied().collect::<Vec<_>>(),
+            vec![Feature::SignExtension, Feature::Threads]
+        );
+        let signatures = fingerprint
+            .imports
+            .iter()
+            .map(|import| format!("{}.{} {}", import.mod_name, import.name, import.signature))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            signatures,
+            vec!["env.f func [I32] -> [I64]", "env.m memory 1..4 shared"]
+        );
+
+        // The same bytes always give the same key, and different ones a different key
+        let key = fingerprint.key();
+        assert_eq!(key.len(), 64);
+        assert_eq!(fingerprint_module(&bytes).unwrap().key(), key);
+        let renamed = fingerprint_module(&make_test_module(b'n')).unwrap();
+        assert_ne!(renamed, fingerprint);
+        assert_ne!(renamed.key(), key);
+
+        assert!(fingerprint_module(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -1,9 +1,145 @@
+use std::convert::TryFrom;
+use std::io;
 use std::io::prelude::*;
+use std::ops::Range;
 
-use crate::core;
-use crate::reader::{ReaderUtil, TypeReader};
-use anyhow::{anyhow, Result};
-use std::convert::TryFrom;
+use crate::parser::{InstructionIterator, InstructionSource, MiscOpcode};
+use crate::reader::{read_func, read_lazy_funcs, ParserConfig, ReaderUtil, TypeReader};
+use crate::{core, leb};
+use anyhow::{anyhow, Context, Result};
+
+pub const MODULE_HEADER_LENGTH: usize = 8;
+pub const WASM_MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6d];
+pub const SUPPORTED_WASM_VERSION: u32 = 1;
+
+// A text format module will usually open with "(module" or a comment, possibly after some
+// whitespace, which is enough to give the user a hint
+fn looks_like_text_format(header: &[u8]) -> bool {
+    match header.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'(') | Some(b';') => header
+            .iter()
+            .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace()),
+        _ => false,
+    }
+}
+
+/// Check the magic number and version at the start of a binary module, giving a specific
+/// error for each of the ways it can be wrong.
+pub fn check_module_header(header: &[u8]) -> Result<()> {
+    if looks_like_text_format(header) {
+        return Err(anyhow!(
+            "This looks like a WebAssembly text format (.wat) file. Only binary modules can be \
+             loaded, so convert it with a WAT assembler such as wat2wasm first"
+        ));
+    }
+
+    if header.len() < WASM_MAGIC.len() || header[0..WASM_MAGIC.len()] != WASM_MAGIC {
+        return Err(anyhow!(
+            "Not a WebAssembly module: expected magic number {:02X?} but found {:02X?}",
+            WASM_MAGIC,
+            &header[0..header.len().min(WASM_MAGIC.len())]
+        ));
+    }
+
+    if header.len() < MODULE_HEADER_LENGTH {
+        return Err(anyhow!(
+            "Module header is truncated: expected {} bytes but found {}",
+            MODULE_HEADER_LENGTH,
+            header.len()
+        ));
+    }
+
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != SUPPORTED_WASM_VERSION {
+        return Err(anyhow!(
+            "Unsupported WebAssembly version {}, only version {} is supported",
+            version,
+            SUPPORTED_WASM_VERSION
+        ));
+    }
+
+    Ok(())
+}
+
+/// Find where the contents of the first section of a type are in a module's bytes, without
+/// reading any of the sections. Nothing is checked, so a malformed module may give `None`
+/// or a range which isn't really a section, which reading it properly will report.
+pub fn find_section(module: &[u8], section_type: core::SectionType) -> Option<Range<usize>> {
+    let id = section_type as u8;
+    let mut position = MODULE_HEADER_LENGTH;
+    while position < module.len() {
+        let (length, length_size) = leb::read_u32(&module[position + 1..]).ok()?;
+        let start = position + 1 + length_size;
+        let end = start.checked_add(usize::try_from(length).ok()?)?;
+        if module[position] == id {
+            return Some(start..end);
+        }
+        position = end;
+    }
+    None
+}
+
+// The order the spec requires the sections other than custom sections to come in. Each
+// of them may appear at most once.
+const SECTION_ORDER: [core::SectionType; 12] = [
+    core::SectionType::TypeSection,
+    core::SectionType::ImportSection,
+    core::SectionType::FunctionSection,
+    core::SectionType::TableSection,
+    core::SectionType::MemorySection,
+    core::SectionType::GlobalSection,
+    core::SectionType::ExportSection,
+    core::SectionType::StartSection,
+    core::SectionType::ElementSection,
+    core::SectionType::DataCountSection,
+    core::SectionType::CodeSection,
+    core::SectionType::DataSection,
+];
+
+// Where the section comes in the order, or `None` for a custom section, which can appear
+// anywhere
+fn section_position(section_type: core::SectionType) -> Option<usize> {
+    SECTION_ORDER.iter().position(|s| *s == section_type)
+}
+
+// memory.init and data.drop can only be used when the module has a data count section,
+// so that the data segments they refer to can be checked before the data section is read
+fn check_data_indices(func: &core::Func, data_count: Option<usize>) -> Result<()> {
+    // Walk nested blocks with a work list, so that deep nesting can't overflow
+    let mut pending = vec![func.expr().get_instruction_bytes()];
+    while let Some(bytes) = pending.pop() {
+        // A body which can't be decoded is reported when it is compiled, so the walk just
+        // stops there
+        for instr in InstructionIterator::new(bytes).map_while(Result::ok) {
+            let data_idx = match instr.try_misc_opcode() {
+                Some(MiscOpcode::MemoryInit) => instr.get_pair_u32_as_usize_arg().0,
+                Some(MiscOpcode::DataDrop) => instr.get_single_u32_as_usize_arg(),
+                _ => {
+                    pending.extend(instr.try_get_block());
+                    pending.extend(instr.try_get_else_block());
+                    continue;
+                }
+            };
+            match data_count {
+                None => {
+                    return Err(anyhow!(
+                        "{:?} needs a data count section",
+                        instr.misc_opcode()
+                    ))
+                }
+                Some(data_count) if data_idx >= data_count => {
+                    return Err(anyhow!(
+                        "Data segment index {} out of range, there are only {} segments",
+                        data_idx,
+                        data_count
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(())
+}
 
 fn append_to_vector<R>(target: &mut Vec<R>, mut extra: Vec<R>) {
     target.append(&mut extra);
@@ -22,10 +158,20 @@ pub struct ModuleBuilder {
     start: Option<usize>,
     imports: Vec<core::Import>,
     exports: Vec<core::Export>,
+    config: ParserConfig,
+    code_in_place: Option<(core::Shared<dyn core::SharedBytes>, Range<usize>)>,
+    // The last section which was read, other than custom sections
+    last_section: Option<core::SectionType>,
+    data_count: Option<usize>,
 }
 
 impl ModuleBuilder {
     pub fn new() -> Self {
+        Self::with_config(&ParserConfig::default())
+    }
+
+    /// A builder which applies the limits in the config to what it reads
+    pub fn with_config(config: &ParserConfig) -> Self {
         ModuleBuilder {
             types: Vec::new(),
             typeidx: Vec::new(),
@@ -38,15 +184,36 @@ impl ModuleBuilder {
             start: None,
             imports: Vec::new(),
             exports: Vec::new(),
+            config: config.clone(),
+            code_in_place: None,
+            last_section: None,
+            data_count: None,
         }
     }
 
+    /// Say where the code section already is in memory, so that reading it lazily leaves
+    /// the function bodies there instead of copying them
+    pub fn set_code_in_place(
+        &mut self,
+        buffer: core::Shared<dyn core::SharedBytes>,
+        section: Range<usize>,
+    ) {
+        self.code_in_place = Some((buffer, section));
+    }
+
     pub fn process_section<T: Read>(
         &mut self,
         section_type: core::SectionType,
         reader: &mut T,
     ) -> anyhow::Result<()> {
+        self.check_section_order(section_type)?;
         match section_type {
+            // Nothing in a custom section is kept, but the name still has to be valid
+            core::SectionType::CustomSection => {
+                reader.read_name()?;
+                io::copy(reader, &mut io::sink())?;
+                Ok(())
+            }
             core::SectionType::TypeSection => Ok(append_to_vector(
                 &mut self.types,
                 reader.read_vec(core::FuncType::read)?,
@@ -75,44 +242,82 @@ impl ModuleBuilder {
                 &mut self.exports,
                 reader.read_vec(core::Export::read)?,
             )),
-            core::SectionType::StartSection => {
-                self.update_start(usize::try_from(reader.read_leb_u32()?).unwrap())
-            }
+            core::SectionType::StartSection => self.update_start(reader.read_leb_usize()?),
             core::SectionType::ElementSection => Ok(append_to_vector(
                 &mut self.elem,
                 reader.read_vec(core::Element::read)?,
             )),
-            core::SectionType::CodeSection => Ok(append_to_vector(
-                &mut self.funcs,
-                reader.read_vec(core::Func::read)?,
-            )),
+            core::SectionType::CodeSection if self.config.lazy_function_bodies => {
+                let mut funcs = match self.code_in_place.take() {
+                    Some((buffer, section)) => {
+                        io::copy(reader, &mut io::sink())?;
+                        read_lazy_funcs(buffer, section)?
+                    }
+                    None => {
+                        let mut code = Vec::new();
+                        reader.read_to_end(&mut code)?;
+                        let section = 0..code.len();
+                        read_lazy_funcs(core::Shared::new(code), section)?
+                    }
+                };
+                self.funcs.append(&mut funcs);
+                Ok(())
+            }
+            core::SectionType::CodeSection => {
+                // Functions are numbered the way the rest of the module refers to them,
+                // after the imported ones
+                let first_idx = self.imported_function_count();
+                let next_idx = std::cell::Cell::new(first_idx);
+                let funcs = reader.read_vec(|reader| {
+                    let idx = next_idx.replace(next_idx.get() + 1);
+                    read_func(reader, &self.config).with_context(|| format!("In function {}", idx))
+                })?;
+                for (idx, func) in funcs.iter().enumerate() {
+                    check_data_indices(func, self.data_count)
+                        .with_context(|| format!("In function {}", first_idx + idx))?;
+                }
+                Ok(append_to_vector(&mut self.funcs, funcs))
+            }
+            core::SectionType::DataCountSection => {
+                self.data_count = Some(reader.read_leb_usize()?);
+                Ok(())
+            }
             core::SectionType::DataSection => Ok(append_to_vector(
                 &mut self.data,
                 reader.read_vec(core::Data::read)?,
             )),
-
-            _ => panic!("Cannot read unknown or custom sections"),
         }
     }
 
-    pub fn get_next_section_type(
-        current_section_type: core::SectionType,
-    ) -> Option<core::SectionType> {
-        match current_section_type {
-            core::SectionType::TypeSection => Some(core::SectionType::ImportSection),
-            core::SectionType::ImportSection => Some(core::SectionType::FunctionSection),
-            core::SectionType::FunctionSection => Some(core::SectionType::TableSection),
-            core::SectionType::TableSection => Some(core::SectionType::MemorySection),
-            core::SectionType::MemorySection => Some(core::SectionType::GlobalSection),
-            core::SectionType::GlobalSection => Some(core::SectionType::ExportSection),
-            core::SectionType::ExportSection => Some(core::SectionType::StartSection),
-            core::SectionType::StartSection => Some(core::SectionType::ElementSection),
-            core::SectionType::ElementSection => Some(core::SectionType::CodeSection),
-            core::SectionType::CodeSection => Some(core::SectionType::DataSection),
-            core::SectionType::DataSection => None,
-
-            _ => panic!("Cannot read unknown or custom sections"),
+    fn imported_function_count(&self) -> usize {
+        self.imports
+            .iter()
+            .filter(|import| matches!(import.desc(), core::ImportDesc::TypeIdx(_)))
+            .count()
+    }
+
+    // Sections other than custom sections have to come in the order the spec gives, and
+    // none of them can be repeated
+    fn check_section_order(&mut self, section_type: core::SectionType) -> Result<()> {
+        let position = match section_position(section_type) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        if let Some(last_section) = self.last_section {
+            if last_section == section_type {
+                return Err(anyhow!("Duplicate {:?}", section_type));
+            }
+            if section_position(last_section).is_some_and(|last_position| position < last_position)
+            {
+                return Err(anyhow!(
+                    "Invalid section order: {:?} after {:?}",
+                    section_type,
+                    last_section
+                ));
+            }
         }
+        self.last_section = Some(section_type);
+        Ok(())
     }
 
     pub fn make_module(self) -> Result<core::RawModule> {
@@ -120,8 +325,17 @@ impl ModuleBuilder {
             Err(anyhow!("No functions found"))
         } else if self.typeidx.len() != self.funcs.len() {
             Err(anyhow!("TypeIdx and code tables do not match sizes"))
+        } else if self
+            .data_count
+            .is_some_and(|count| count != self.data.len())
+        {
+            Err(anyhow!(
+                "Data count section says there are {} data segments but there are {}",
+                self.data_count.unwrap(),
+                self.data.len()
+            ))
         } else {
-            Ok(core::RawModule::new(
+            let mut module = core::RawModule::new(
                 self.types,
                 self.typeidx,
                 self.funcs,
@@ -133,7 +347,9 @@ impl ModuleBuilder {
                 self.start,
                 self.imports,
                 self.exports,
-            ))
+            );
+            module.set_data_count(self.data_count);
+            Ok(module)
         }
     }
 
@@ -146,7 +362,80 @@ impl ModuleBuilder {
         }
     }
 
-    pub fn read_next_section_header<T: Read>(reader: &mut T) -> Result<core::SectionType> {
-        core::SectionType::read(reader)
+    /// Read the id of the next section, or `None` at the end of the module
+    pub fn read_next_section_header<T: Read>(reader: &mut T) -> Result<Option<core::SectionType>> {
+        let mut id = [0];
+        if reader.read(&mut id)? == 0 {
+            return Ok(None);
+        }
+        core::SectionType::read(&mut &id[..]).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header_error(header: &[u8]) -> String {
+        format!("{}", check_module_header(header).unwrap_err())
+    }
+
+    #[test]
+    fn test_check_module_header() {
+        assert!(check_module_header(&[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]).is_ok());
+
+        assert!(header_error(b"(module ").contains("text format"));
+        assert!(header_error(b"  ;; a c").contains("text format"));
+        assert!(header_error(b"\x7fELF\x02\x01\x01\x00").contains("Not a WebAssembly module"));
+        assert!(header_error(&[0x00, 0x61]).contains("Not a WebAssembly module"));
+        assert!(header_error(&[0x00, 0x61, 0x73, 0x6d, 0x01]).contains("truncated"));
+        assert_eq!(
+            header_error(&[0x00, 0x61, 0x73, 0x6d, 0x0d, 0x00, 0x01, 0x00]),
+            "Unsupported WebAssembly version 65549, only version 1 is supported"
+        );
+    }
+    #[test]
+    fn test_process_custom_section() {
+        let mut builder = ModuleBuilder::new();
+        let type_section = [0x01, 0x60, 0x00, 0x00];
+        builder
+            .process_section(core::SectionType::TypeSection, &mut &type_section[..])
+            .unwrap();
+
+        // A custom section is skipped wherever it comes, and doesn't affect the order of
+        // the others
+        let custom_section = [0x04, b'n', b'a', b'm', b'e', 0x01, 0x02, 0x03];
+        builder
+            .process_section(core::SectionType::CustomSection, &mut &custom_section[..])
+            .unwrap();
+        builder
+            .process_section(core::SectionType::CustomSection, &mut &custom_section[..])
+            .unwrap();
+        let error = builder
+            .process_section(core::SectionType::TypeSection, &mut &type_section[..])
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Duplicate TypeSection");
+        assert_eq!(builder.types.len(), 1);
+
+        // But its name has to be valid
+        let bad_name = [0x02, 0xFF, 0xFE];
+        assert!(builder
+            .process_section(core::SectionType::CustomSection, &mut &bad_name[..])
+            .is_err());
+    }
+
+    #[test]
+    fn test_local_count_overflow() {
+        let read_func = |bytes: &[u8]| core::Func::read(&mut std::io::Cursor::new(bytes));
+
+        // Two runs of locals, u32::MAX i32s and then one i64, followed by an empty body
+        let too_many = [
+            0x0A, 0x02, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x7F, 0x01, 0x7E, 0x0B,
+        ];
+        let error = read_func(&too_many).unwrap_err();
+        assert!(format!("{}", error).contains("more than 4294967295 locals"));
+
+        let func = read_func(&[0x06, 0x02, 0x03, 0x7F, 0x04, 0x7E, 0x0B]).unwrap();
+        assert_eq!(func.local_count(), 7);
     }
 }
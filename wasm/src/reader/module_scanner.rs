@@ -0,0 +1,746 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+
+use crate::core::{ElemType, SectionType, ValueType};
+use crate::parser::{
+    extension_category, is_prefix_byte, lookup_extension, lookup_opcode, Feature, Immediates,
+    InstructionCategory, Opcode, EXTENSION_PREFIX,
+};
+use crate::reader::{check_module_header, ReaderUtil, MODULE_HEADER_LENGTH};
+use anyhow::{anyhow, Result};
+use num_enum::TryFromPrimitive;
+
+// Things the interpreter cannot handle yet that aren't described by the opcode catalog.
+// These need updating as support is added.
+const MULTI_VALUE_SUPPORTED: bool = true;
+const SHARED_MEMORY_SUPPORTED: bool = true;
+const MEMORY_64_SUPPORTED: bool = false;
+
+// The same things as the scanner reports them, for the conformance report
+pub(crate) const NON_INSTRUCTION_SUPPORT: [(Feature, &str, bool); 4] = [
+    (
+        Feature::MultiValue,
+        "multiple results",
+        MULTI_VALUE_SUPPORTED,
+    ),
+    (
+        Feature::MultiValue,
+        "block type index",
+        MULTI_VALUE_SUPPORTED,
+    ),
+    (Feature::Threads, "shared memory", SHARED_MEMORY_SUPPORTED),
+    (Feature::Memory64, "64-bit limits", MEMORY_64_SUPPORTED),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScanLocation {
+    Section(u8),
+    Type(usize),
+    Import(usize),
+    Table(usize),
+    Memory(usize),
+    Global(usize),
+    Element(usize),
+    Data(usize),
+    Function(usize),
+    Export(usize),
+}
+
+impl fmt::Display for ScanLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanLocation::Section(id) => write!(f, "section {}", id),
+            ScanLocation::Type(idx) => write!(f, "type[{}]", idx),
+            ScanLocation::Import(idx) => write!(f, "import[{}]", idx),
+            ScanLocation::Table(idx) => write!(f, "table[{}]", idx),
+            ScanLocation::Memory(idx) => write!(f, "memory[{}]", idx),
+            ScanLocation::Global(idx) => write!(f, "global[{}]", idx),
+            ScanLocation::Element(idx) => write!(f, "elem[{}]", idx),
+            ScanLocation::Data(idx) => write!(f, "data[{}]", idx),
+            ScanLocation::Function(idx) => write!(f, "func[{}]", idx),
+            ScanLocation::Export(idx) => write!(f, "export[{}]", idx),
+        }
+    }
+}
+
+/// A single use of something outside the MVP spec, found by scanning a module
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FeatureUsage {
+    pub feature: Feature,
+    pub name: String,
+    pub supported: bool,
+    pub location: ScanLocation,
+}
+
+// The scanner walks the module using only the binary format, rather than the module
+// reader, so that it can step over everything the reader would reject
+struct ModuleScanner {
+    usages: Vec<FeatureUsage>,
+    imported_functions: usize,
+    imported_tables: usize,
+    imported_memories: usize,
+    imported_globals: usize,
+}
+
+fn read_byte(cursor: &mut Cursor<&[u8]>) -> Result<u8> {
+    cursor.read_u8()
+}
+
+// Unlike read_leb_u32, this copes with LEB values of any size
+fn skip_leb(cursor: &mut Cursor<&[u8]>) -> Result<()> {
+    while read_byte(cursor)? & 0x80 != 0 {}
+    Ok(())
+}
+
+fn skip_bytes(cursor: &mut Cursor<&[u8]>, count: usize) -> Result<()> {
+    let mut buf = vec![0; count];
+    cursor.read_exact(&mut buf)?;
+    Ok(())
+}
+
+fn is_at_end(cursor: &Cursor<&[u8]>) -> bool {
+    cursor.position() as usize >= cursor.get_ref().len()
+}
+
+impl ModuleScanner {
+    fn new() -> Self {
+        Self {
+            usages: Vec::new(),
+            imported_functions: 0,
+            imported_tables: 0,
+            imported_memories: 0,
+            imported_globals: 0,
+        }
+    }
+
+    fn record(&mut self, feature: Feature, name: String, supported: bool, location: &ScanLocation) {
+        self.usages.push(FeatureUsage {
+            feature,
+            name,
+            supported,
+            location: location.clone(),
+        });
+    }
+
+    fn scan(&mut self, bytes: &[u8]) -> Result<()> {
+        check_module_header(&bytes[0..bytes.len().min(MODULE_HEADER_LENGTH)])?;
+
+        let mut cursor = Cursor::new(&bytes[MODULE_HEADER_LENGTH..]);
+        while !is_at_end(&cursor) {
+            let section_id = read_byte(&mut cursor)?;
+            let section_length = cursor.read_leb_usize()?;
+            let section_start = cursor.position() as usize;
+            let section_end = section_start
+                .checked_add(section_length)
+                .filter(|end| *end <= cursor.get_ref().len())
+                .ok_or_else(|| anyhow!("Section {} extends past end of module", section_id))?;
+
+            let mut section = Cursor::new(&cursor.get_ref()[section_start..section_end]);
+            self.scan_section(section_id, &mut section)?;
+            cursor.set_position(section_end as u64);
+        }
+
+        Ok(())
+    }
+
+    fn scan_section(&mut self, section_id: u8, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let location = ScanLocation::Section(section_id);
+        let supported = SectionType::try_from_primitive(section_id).is_ok();
+
+        match section_id {
+            1 => self.scan_type_section(section),
+            2 => self.scan_import_section(section),
+            4 => self.scan_table_section(section),
+            5 => self.scan_memory_section(section),
+            6 => self.scan_global_section(section),
+            9 => self.scan_element_section(section),
+            10 => self.scan_code_section(section),
+            11 => self.scan_data_section(section),
+
+            // Custom, function, export and start sections can't contain anything interesting
+            0 | 3 | 7 | 8 => Ok(()),
+
+            12 => {
+                let name = "data count section".to_string();
+                self.record(Feature::BulkMemory, name, supported, &location);
+                Ok(())
+            }
+            13 => {
+                let name = "tag section".to_string();
+                self.record(Feature::ExceptionHandling, name, supported, &location);
+                Ok(())
+            }
+            _ => {
+                let name = format!("section {}", section_id);
+                self.record(Feature::Unknown, name, supported, &location);
+                Ok(())
+            }
+        }
+    }
+
+    fn scan_value_type(&mut self, byte: u8, location: &ScanLocation) {
+        let feature = match byte {
+            0x7C..=0x7F => return,
+            0x7B => Feature::Simd,
+            0x70 | 0x6F => Feature::ReferenceTypes,
+            _ => Feature::Unknown,
+        };
+        let supported = ValueType::from_byte(byte).is_ok();
+        self.record(
+            feature,
+            format!("value type 0x{:02X}", byte),
+            supported,
+            location,
+        );
+    }
+
+    fn scan_value_types(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        location: &ScanLocation,
+    ) -> Result<usize> {
+        let types = cursor.read_vec(read_byte)?;
+        for value_type in types.iter() {
+            self.scan_value_type(*value_type, location);
+        }
+        Ok(types.len())
+    }
+
+    fn scan_limits(&mut self, cursor: &mut Cursor<&[u8]>, location: &ScanLocation) -> Result<()> {
+        let flags = read_byte(cursor)?;
+        match flags {
+            0x00 | 0x01 => {}
+            // Shared memories without a maximum size aren't valid
+            0x02 | 0x03 => self.record(
+                Feature::Threads,
+                "shared memory".to_string(),
+                SHARED_MEMORY_SUPPORTED && flags == 0x03,
+                location,
+            ),
+            0x04..=0x07 => self.record(
+                Feature::Memory64,
+                "64-bit limits".to_string(),
+                MEMORY_64_SUPPORTED,
+                location,
+            ),
+            _ => return Err(anyhow!("Unknown Limits tag")),
+        }
+
+        skip_leb(cursor)?;
+        if flags & 0x01 != 0 {
+            skip_leb(cursor)?;
+        }
+        Ok(())
+    }
+
+    fn scan_table_type(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        location: &ScanLocation,
+    ) -> Result<()> {
+        let elem_type = read_byte(cursor)?;
+        // Tables of anything but functions need reference types
+        if elem_type != ElemType::FuncRef as u8 {
+            self.scan_value_type(elem_type, location);
+        }
+        self.scan_limits(cursor, location)
+    }
+
+    fn scan_global_type(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        location: &ScanLocation,
+    ) -> Result<()> {
+        let value_type = read_byte(cursor)?;
+        self.scan_value_type(value_type, location);
+        read_byte(cursor)?;
+        Ok(())
+    }
+
+    fn scan_block_type(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        location: &ScanLocation,
+    ) -> Result<()> {
+        let lead_byte = read_byte(cursor)?;
+        match lead_byte {
+            0x40 => {}
+            0x6F..=0x7F => self.scan_value_type(lead_byte, location),
+            _ => {
+                // Anything else is the start of a signed LEB type index
+                if lead_byte & 0x80 != 0 {
+                    skip_leb(cursor)?;
+                }
+                self.record(
+                    Feature::MultiValue,
+                    "block type index".to_string(),
+                    MULTI_VALUE_SUPPORTED,
+                    location,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_type_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Type(idx);
+            if read_byte(section)? != 0x60 {
+                return Err(anyhow!("Invalid func type header"));
+            }
+
+            self.scan_value_types(section, &location)?;
+            if self.scan_value_types(section, &location)? > 1 {
+                self.record(
+                    Feature::MultiValue,
+                    "multiple results".to_string(),
+                    MULTI_VALUE_SUPPORTED,
+                    &location,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_import_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Import(idx);
+            section.read_name()?;
+            section.read_name()?;
+
+            match read_byte(section)? {
+                0x00 => {
+                    skip_leb(section)?;
+                    self.imported_functions += 1;
+                }
+                0x01 => {
+                    self.scan_table_type(section, &location)?;
+                    self.imported_tables += 1;
+                }
+                0x02 => {
+                    self.scan_limits(section, &location)?;
+                    self.imported_memories += 1;
+                }
+                0x03 => {
+                    self.scan_global_type(section, &location)?;
+                    self.imported_globals += 1;
+                }
+                0x04 => {
+                    self.record(
+                        Feature::ExceptionHandling,
+                        "tag import".to_string(),
+                        false,
+                        &location,
+                    );
+                    read_byte(section)?;
+                    skip_leb(section)?;
+                }
+                _ => return Err(anyhow!("Unknown ImportDesc tag")),
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_table_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Table(self.imported_tables + idx);
+            self.scan_table_type(section, &location)?;
+        }
+        Ok(())
+    }
+
+    fn scan_memory_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Memory(self.imported_memories + idx);
+            self.scan_limits(section, &location)?;
+        }
+        Ok(())
+    }
+
+    fn scan_global_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Global(self.imported_globals + idx);
+            self.scan_global_type(section, &location)?;
+            self.scan_constant_expression(section, &location)?;
+        }
+        Ok(())
+    }
+
+    fn scan_element_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Element(idx);
+            let flags = section.read_leb_u32()?;
+            if flags > 7 {
+                return Err(anyhow!("Invalid element segment flags {}", flags));
+            }
+
+            if flags != 0 {
+                let feature = if flags & 0x04 != 0 {
+                    Feature::ReferenceTypes
+                } else {
+                    Feature::BulkMemory
+                };
+                let name = format!("element segment flags {}", flags);
+                self.record(feature, name, true, &location);
+            }
+
+            // Bit 0 marks passive or declarative segments, which have no offset. Bit 1
+            // adds an explicit table index to active segments, and bit 2 switches from
+            // function indices to expressions
+            if flags & 0x01 == 0 {
+                if flags & 0x02 != 0 {
+                    skip_leb(section)?;
+                }
+                self.scan_constant_expression(section, &location)?;
+            }
+            if flags & 0x03 != 0 {
+                let elem_type = read_byte(section)?;
+                if flags & 0x04 != 0 {
+                    self.scan_value_type(elem_type, &location);
+                }
+            }
+
+            let elem_count = section.read_leb_usize()?;
+            for _ in 0..elem_count {
+                if flags & 0x04 != 0 {
+                    self.scan_constant_expression(section, &location)?;
+                } else {
+                    skip_leb(section)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_data_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Data(idx);
+            match section.read_leb_u32()? {
+                0 => self.scan_constant_expression(section, &location)?,
+                1 => self.record(
+                    Feature::BulkMemory,
+                    "passive data segment".to_string(),
+                    true,
+                    &location,
+                ),
+                2 => {
+                    self.record(
+                        Feature::BulkMemory,
+                        "data segment memory index".to_string(),
+                        true,
+                        &location,
+                    );
+                    skip_leb(section)?;
+                    self.scan_constant_expression(section, &location)?;
+                }
+                flags => return Err(anyhow!("Invalid data segment flags {}", flags)),
+            }
+
+            let length = section.read_leb_usize()?;
+            skip_bytes(section, length)?;
+        }
+        Ok(())
+    }
+
+    fn scan_code_section(&mut self, section: &mut Cursor<&[u8]>) -> Result<()> {
+        let count = section.read_leb_usize()?;
+        for idx in 0..count {
+            let location = ScanLocation::Function(self.imported_functions + idx);
+            let body_length = section.read_leb_usize()?;
+            let body_start = section.position() as usize;
+            let body_end = body_start
+                .checked_add(body_length)
+                .filter(|end| *end <= section.get_ref().len())
+                .ok_or_else(|| anyhow!("Function body extends past end of section"))?;
+
+            let mut body = Cursor::new(&section.get_ref()[body_start..body_end]);
+            let local_count = body.read_leb_usize()?;
+            for _ in 0..local_count {
+                skip_leb(&mut body)?;
+                let value_type = read_byte(&mut body)?;
+                self.scan_value_type(value_type, &location);
+            }
+
+            while !is_at_end(&body) {
+                if !self.scan_instruction(&mut body, &location)? {
+                    break;
+                }
+            }
+
+            section.set_position(body_end as u64);
+        }
+        Ok(())
+    }
+
+    fn scan_constant_expression(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        location: &ScanLocation,
+    ) -> Result<()> {
+        // Constant expressions can't contain blocks, so the first end is the end of the
+        // expression
+        loop {
+            let position = cursor.position();
+            let is_end = read_byte(cursor)? == u8::from(Opcode::End);
+            cursor.set_position(position);
+
+            if !self.scan_instruction(cursor, location)? {
+                return Err(anyhow!(
+                    "Cannot find end of constant expression in {}",
+                    location
+                ));
+            }
+            if is_end {
+                return Ok(());
+            }
+        }
+    }
+
+    // Step over a single instruction, recording it if it is from outside the MVP. Returns
+    // false if the instruction is not recognised, in which case the rest of the expression
+    // cannot be decoded
+    fn scan_instruction(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        location: &ScanLocation,
+    ) -> Result<bool> {
+        let opcode = read_byte(cursor)?;
+        let sub_opcode = if is_prefix_byte(opcode) {
+            Some(cursor.read_leb_u32()?)
+        } else {
+            None
+        };
+
+        // Extensions aren't in the catalog, because they are only known once registered
+        if let (EXTENSION_PREFIX, Some(sub_opcode)) = (opcode, sub_opcode) {
+            if let Ok(category) = extension_category(sub_opcode) {
+                let name = lookup_extension(sub_opcode)?.name(sub_opcode);
+                self.record(Feature::Extension, name, true, location);
+                self.skip_category(cursor, category, location)?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(info) = lookup_opcode(opcode, sub_opcode) {
+            self.record(info.feature, info.name, info.supported, location);
+            self.skip_immediates(cursor, info.immediates, location)?;
+            return Ok(true);
+        }
+
+        match (Opcode::from_byte(opcode), sub_opcode) {
+            (Ok(opcode), None) => {
+                self.skip_category(cursor, InstructionCategory::from_opcode(opcode), location)?;
+                Ok(true)
+            }
+            _ => {
+                let name = match sub_opcode {
+                    Some(sub_opcode) => format!("opcode 0x{:02X} 0x{:02X}", opcode, sub_opcode),
+                    None => format!("opcode 0x{:02X}", opcode),
+                };
+                self.record(Feature::Unknown, name, false, location);
+                Ok(false)
+            }
+        }
+    }
+
+    fn skip_immediates(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        immediates: Immediates,
+        location: &ScanLocation,
+    ) -> Result<()> {
+        match immediates {
+            Immediates::None => Ok(()),
+            Immediates::BlockType => self.scan_block_type(cursor, location),
+            Immediates::Leb => skip_leb(cursor),
+            Immediates::TwoLeb => {
+                skip_leb(cursor)?;
+                skip_leb(cursor)
+            }
+            Immediates::ValueTypes => self.scan_value_types(cursor, location).map(|_| ()),
+            Immediates::Byte => skip_bytes(cursor, 1),
+            Immediates::Bytes16 => skip_bytes(cursor, 16),
+            Immediates::MemArgLane => {
+                skip_leb(cursor)?;
+                skip_leb(cursor)?;
+                skip_bytes(cursor, 1)
+            }
+            Immediates::TryTable => {
+                self.scan_block_type(cursor, location)?;
+                let catch_count = cursor.read_leb_usize()?;
+                for _ in 0..catch_count {
+                    // catch and catch_ref have a tag index before the label
+                    if read_byte(cursor)? < 2 {
+                        skip_leb(cursor)?;
+                    }
+                    skip_leb(cursor)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn skip_category(
+        &mut self,
+        cursor: &mut Cursor<&[u8]>,
+        category: InstructionCategory,
+        location: &ScanLocation,
+    ) -> Result<()> {
+        match category {
+            InstructionCategory::SingleByte
+            | InstructionCategory::Else
+            | InstructionCategory::End => Ok(()),
+            InstructionCategory::SingleLebInteger => skip_leb(cursor),
+            InstructionCategory::SingleFloat => skip_bytes(cursor, 4),
+            InstructionCategory::SingleDouble => skip_bytes(cursor, 8),
+            InstructionCategory::Block(_) => self.scan_block_type(cursor, location),
+            InstructionCategory::TwoLebInteger => {
+                skip_leb(cursor)?;
+                skip_leb(cursor)
+            }
+            InstructionCategory::MemArg => {
+                // A multi-memory memory index sits between the alignment and the offset
+                if cursor.read_leb_u32()? & 0x40 != 0 {
+                    skip_leb(cursor)?;
+                }
+                skip_leb(cursor)
+            }
+            InstructionCategory::BranchTable => {
+                let target_count = cursor.read_leb_usize()?;
+                for _ in 0..=target_count {
+                    skip_leb(cursor)?;
+                }
+                Ok(())
+            }
+            InstructionCategory::MiscPrefixed
+            | InstructionCategory::AtomicPrefixed
+            | InstructionCategory::ExtensionPrefixed => {
+                panic!("Prefixed instructions should be found in the opcode catalog")
+            }
+        }
+    }
+}
+
+/// Decode the whole module and list every use of instructions, types and sections from
+/// outside the MVP spec, along with whether the interpreter supports them.
+pub fn scan_module<T: Read>(reader: &mut T) -> Result<Vec<FeatureUsage>> {
+    let bytes = reader.read_bytes_to_end()?;
+    let mut scanner = ModuleScanner::new();
+    scanner.scan(&bytes)?;
+    Ok(scanner.usages)
+}
+
+pub fn scan_module_from_path(file: &str) -> Result<Vec<FeatureUsage>> {
+    let mut buf = BufReader::new(File::open(file)?);
+    scan_module(&mut buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reader::test_module::make_module;
+
+    fn scan(bytes: &[u8]) -> Vec<(Feature, String, bool, ScanLocation)> {
+        scan_module(&mut Cursor::new(bytes))
+            .unwrap()
+            .into_iter()
+            .map(|u| (u.feature, u.name, u.supported, u.location))
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_mvp_module() {
+        let module = make_module(&[
+            (1, vec![0x01, 0x60, 0x01, 0x7F, 0x01, 0x7F]),
+            (3, vec![0x01, 0x00]),
+            (
+                10,
+                vec![0x01, 0x07, 0x00, 0x20, 0x00, 0x41, 0x7F, 0x6A, 0x0B],
+            ),
+        ]);
+        assert_eq!(scan(&module), vec![]);
+    }
+
+    #[test]
+    fn test_scan_unsupported_features() {
+        let module = make_module(&[
+            (1, vec![0x01, 0x60, 0x00, 0x02, 0x7F, 0x7F]),
+            (3, vec![0x02, 0x00, 0x00]),
+            (5, vec![0x01, 0x03, 0x01, 0x02]),
+            (12, vec![0x01]),
+            (
+                10,
+                vec![
+                    0x02, // Two functions
+                    0x10, 0x00, // First function has no locals
+                    0x41, 0x00, 0x41, 0x00, 0x41, 0x00, // Three constants
+                    0xFC, 0x0A, 0x00, 0x00, // memory.copy
+                    0xC0, // i32.extend8_s
+                    0x02, 0x00, 0x0B, // Block with a type index
+                    0x0B, //
+                    0x04, 0x00, // Second function
+                    0xFD, 0xFF, 0x7F, // Unknown SIMD opcode, which stops decoding
+                ],
+            ),
+        ]);
+
+        let results = scan(&module);
+        assert_eq!(
+            results,
+            vec![
+                (
+                    Feature::MultiValue,
+                    "multiple results".to_string(),
+                    true,
+                    ScanLocation::Type(0)
+                ),
+                (
+                    Feature::Threads,
+                    "shared memory".to_string(),
+                    true,
+                    ScanLocation::Memory(0)
+                ),
+                (
+                    Feature::BulkMemory,
+                    "data count section".to_string(),
+                    true,
+                    ScanLocation::Section(12)
+                ),
+                (
+                    Feature::BulkMemory,
+                    "memory.copy".to_string(),
+                    true,
+                    ScanLocation::Function(0)
+                ),
+                (
+                    Feature::SignExtension,
+                    "i32.extend8_s".to_string(),
+                    true,
+                    ScanLocation::Function(0)
+                ),
+                (
+                    Feature::MultiValue,
+                    "block type index".to_string(),
+                    true,
+                    ScanLocation::Function(0)
+                ),
+                (
+                    Feature::Unknown,
+                    "opcode 0xFD 0x3FFF".to_string(),
+                    false,
+                    ScanLocation::Function(1)
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+
+/// Limits applied while reading a binary module. The defaults allow anything the binary
+/// format can describe, which is capped at 4GiB by the u32 section lengths, apart from
+/// br_table instructions, which are held to the size that web engines accept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParserConfig {
+    /// The limit on the module header plus the contents of every section. The section ids
+    /// and lengths themselves are not counted.
+    pub max_module_size: u64,
+    pub max_section_size: u64,
+    /// The limit on each custom section, which is checked before any of it is read.
+    pub max_custom_section_size: u64,
+    /// The most targets a br_table may have, not counting the default. A table is
+    /// rejected when its length is read, before any of its targets.
+    pub max_branch_table_targets: u32,
+    /// Keep the code section as it was read instead of decoding each function body, and
+    /// compile each function the first time it is called. Instantiation is quicker, but a
+    /// malformed body isn't reported until the function it belongs to is called.
+    pub lazy_function_bodies: bool,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            max_module_size: u64::from(u32::MAX),
+            max_section_size: u64::from(u32::MAX),
+            max_custom_section_size: u64::from(u32::MAX),
+            max_branch_table_targets: 65520,
+            lazy_function_bodies: false,
+        }
+    }
+}
+
+impl ParserConfig {
+    /// Check the length of a section against the limits, given the number of bytes of the
+    /// module that have been read before it, and convert it to a usize.
+    pub fn check_section_size(
+        &self,
+        module_size_so_far: u64,
+        section_length: u32,
+    ) -> Result<usize> {
+        let section_length = u64::from(section_length);
+        if section_length > self.max_section_size {
+            return Err(anyhow!(
+                "Module too large: section of {} bytes exceeds the limit of {} bytes",
+                section_length,
+                self.max_section_size
+            ));
+        }
+
+        let module_size = module_size_so_far
+            .checked_add(section_length)
+            .filter(|size| *size <= self.max_module_size)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Module too large: exceeds the limit of {} bytes",
+                    self.max_module_size
+                )
+            })?;
+
+        checked_size(module_size)?;
+        checked_size(section_length)
+    }
+
+    /// Check the length of a custom section, which has already passed
+    /// `check_section_size`, against the tighter limit for custom sections.
+    pub fn check_custom_section_size(&self, section_length: usize) -> Result<()> {
+        if section_length as u64 > self.max_custom_section_size {
+            Err(anyhow!(
+                "Module too large: custom section of {} bytes exceeds the limit of {} bytes",
+                section_length,
+                self.max_custom_section_size
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Convert a size read from a module to a usize, which can fail on 32-bit hosts.
+pub fn checked_size(size: impl Into<u64>) -> Result<usize> {
+    let size = size.into();
+    usize::try_from(size)
+        .map_err(|_| anyhow!("Module too large: size {} does not fit in memory", size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::RawModule;
+    use crate::reader::ReaderUtil;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_section_size_limits() {
+        let config = ParserConfig {
+            max_module_size: 100,
+            max_section_size: 50,
+            ..ParserConfig::default()
+        };
+
+        assert_eq!(config.check_section_size(8, 50).unwrap(), 50);
+        assert!(config.check_section_size(8, 51).is_err());
+        assert!(config.check_section_size(60, 41).is_err());
+        assert!(ParserConfig::default()
+            .check_section_size(u64::from(u32::MAX), 1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_with_config() {
+        // A module with a single empty function. The sections are 4, 2 and 4 bytes long
+        let module = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x02, 0x01, 0x00, 0x0A, 0x04, 0x01, 0x02, 0x00, 0x0B,
+        ];
+        let read = |max_module_size, max_section_size| {
+            RawModule::read_with_config(
+                &mut Cursor::new(&module[..]),
+                &ParserConfig {
+                    max_module_size,
+                    max_section_size,
+                    ..ParserConfig::default()
+                },
+            )
+        };
+
+        assert!(read(18, 4).is_ok());
+        for (max_module_size, max_section_size) in [(17, 4), (18, 3)].iter() {
+            let err = read(*max_module_size, *max_section_size).unwrap_err();
+            assert!(format!("{}", err).starts_with("Module too large"));
+        }
+    }
+
+    #[test]
+    fn test_custom_section_limits() {
+        // A module with a single empty function, followed by a custom section named "ab"
+        // which holds three bytes
+        let module = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x02, 0x01, 0x00, 0x0A, 0x04, 0x01, 0x02, 0x00, 0x0B, 0x00, 0x06, 0x02, 0x61,
+            0x62, 0x01, 0x02, 0x03,
+        ];
+        let read = |bytes: &[u8], max_custom_section_size| {
+            RawModule::read_with_config(
+                &mut Cursor::new(bytes),
+                &ParserConfig {
+                    max_custom_section_size,
+                    ..ParserConfig::default()
+                },
+            )
+        };
+
+        assert!(read(&module, 6).is_ok());
+        let err = read(&module, 5).unwrap_err();
+        assert!(format!("{}", err).starts_with("Module too large"));
+
+        // A body cut short is an error rather than a partial read
+        assert!(read(&module[..module.len() - 1], 6).is_err());
+    }
+
+    #[test]
+    fn test_branch_table_limit() {
+        // A function whose body is a br_table with three targets plus the default, inside
+        // a block. The sections are 4, 2 and 15 bytes long
+        let module = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x02, 0x01, 0x00, 0x0A, 0x0F, 0x01, 0x0D, 0x00, 0x02, 0x40, 0x41, 0x00, 0x0E,
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x0B, 0x0B,
+        ];
+        let read = |max_branch_table_targets| {
+            RawModule::read_with_config(
+                &mut Cursor::new(&module[..]),
+                &ParserConfig {
+                    max_branch_table_targets,
+                    ..ParserConfig::default()
+                },
+            )
+        };
+
+        assert!(read(3).is_ok());
+        let err = format!("{}", read(2).unwrap_err());
+        assert!(err.starts_with("In function 0: Module too large"));
+    }
+
+    #[test]
+    fn test_oversized_leb_is_an_error() {
+        let mut reader = Cursor::new([0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+        assert_eq!(reader.read_leb_u32().unwrap(), u32::MAX);
+
+        let mut reader = Cursor::new([0xFF, 0xFF, 0xFF, 0xFF, 0x1F]);
+        assert!(reader.read_leb_u32().is_err());
+        let mut reader = Cursor::new([0x80, 0x80, 0x80, 0x80, 0x80, 0x00]);
+        assert!(reader.read_leb_u32().is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use std::io;
+use std::io::prelude::*;
+
+/// Counts the bytes which have been read through it, so that a failure can say how far
+/// into its source it happened
+pub struct PositionReader<I: io::Read> {
+    src: I,
+    position: u64,
+}
+
+impl<I> PositionReader<I>
+where
+    I: Read,
+{
+    pub fn new(src: I) -> Self {
+        Self { src, position: 0 }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<I> Read for PositionReader<I>
+where
+    I: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.src.read(buf)?;
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
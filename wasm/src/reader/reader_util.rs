@@ -1,7 +1,12 @@
 use anyhow::{anyhow, Result};
-use std::convert::TryFrom;
 use std::io;
 
+use crate::{leb, reader::checked_size};
+
+// Don't trust a vector length read from the module to size an allocation, because a
+// corrupt length could be up to 4G elements. Beyond this the vector grows as it is read
+const MAX_VEC_PREALLOCATION: usize = 1024;
+
 pub trait ReaderUtil {
     fn read_u8(&mut self) -> Result<u8>;
     fn read_leb_u32(&mut self) -> Result<u32>;
@@ -24,26 +29,16 @@ where
     }
 
     fn read_leb_u32(&mut self) -> Result<u32> {
-        let mut result: u32 = 0;
-        let mut shift = 0;
-
-        loop {
-            let byte = self.read_u8()?;
-            result |= u32::from(byte & 0x7f) << shift;
-            if (byte & 0x80) == 0 {
-                return Ok(result);
-            }
-            shift += 7;
-        }
+        leb::decode::<32, false>(|| self.read_u8()).map(|(value, _)| value as u32)
     }
 
     fn read_leb_usize(&mut self) -> Result<usize> {
-        Ok(usize::try_from(self.read_leb_u32()?).unwrap())
+        checked_size(self.read_leb_u32()?)
     }
 
     fn read_vec<R, T2: Fn(&mut Self) -> Result<R>>(&mut self, read_fn: T2) -> Result<Vec<R>> {
         let vector_length = self.read_leb_u32()?;
-        let mut ret = Vec::with_capacity(usize::try_from(vector_length).unwrap());
+        let mut ret = Vec::with_capacity(checked_size(vector_length)?.min(MAX_VEC_PREALLOCATION));
 
         for _ in 0..vector_length {
             ret.push(read_fn(self)?);
@@ -1,5 +1,7 @@
+use std::convert::TryFrom;
 use std::io;
 use std::io::prelude::*;
+use std::io::SeekFrom;
 
 pub struct ScopedReader<'a, I: io::Read> {
     src: &'a mut I,
@@ -22,6 +24,40 @@ where
     pub fn is_at_end(&self) -> bool {
         self.offset == self.size
     }
+
+    /// How many bytes of the scope haven't been read yet
+    pub fn remaining(&self) -> usize {
+        self.size - self.offset
+    }
+
+    /// Read and discard the rest of the scope a block at a time, so that the size the
+    /// scope was given never decides how much is buffered. Fails if the source ends first.
+    pub fn skip_to_end(&mut self) -> io::Result<()> {
+        let remaining = self.remaining() as u64;
+        let skipped = io::copy(&mut self.by_ref().take(remaining), &mut io::sink())?;
+        if skipped < remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Source ended before the end of the scope",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, I> ScopedReader<'a, I>
+where
+    I: Read + Seek,
+{
+    /// Skip the rest of the scope without reading it. Seeking beyond the end of the source
+    /// is not an error, so a truncated source is only noticed by the next read.
+    pub fn seek_to_end(&mut self) -> io::Result<()> {
+        let remaining = i64::try_from(self.remaining())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.src.seek(SeekFrom::Current(remaining))?;
+        self.offset = self.size;
+        Ok(())
+    }
 }
 
 impl<'a, I> Read for ScopedReader<'a, I>
@@ -46,3 +82,27 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_skip_to_end() {
+        let mut source = Cursor::new([1u8, 2, 3, 4, 5]);
+        let mut scope = ScopedReader::new(&mut source, 3);
+        scope.skip_to_end().unwrap();
+        assert!(scope.is_at_end());
+        assert_eq!(source.position(), 3);
+
+        let mut source = Cursor::new([1u8, 2, 3, 4, 5]);
+        let mut scope = ScopedReader::new(&mut source, 3);
+        scope.seek_to_end().unwrap();
+        assert!(scope.is_at_end());
+        assert_eq!(source.position(), 3);
+
+        let mut source = Cursor::new([1u8, 2]);
+        assert!(ScopedReader::new(&mut source, 3).skip_to_end().is_err());
+    }
+}
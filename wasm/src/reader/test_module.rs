@@ -0,0 +1,16 @@
+use crate::reader::{SUPPORTED_WASM_VERSION, WASM_MAGIC};
+
+/// The bytes of a module made of the given sections, as pairs of the section id and its
+/// contents. Every section has to be shorter than 128 bytes, so that its length is a
+/// single byte.
+pub fn make_module(sections: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = WASM_MAGIC.to_vec();
+    bytes.extend_from_slice(&SUPPORTED_WASM_VERSION.to_le_bytes());
+    for (id, contents) in sections {
+        assert!(contents.len() < 0x80, "Section {} is too long", id);
+        bytes.push(*id);
+        bytes.push(contents.len() as u8);
+        bytes.extend_from_slice(contents);
+    }
+    bytes
+}
@@ -1,11 +1,11 @@
+use std::convert::TryFrom;
 use std::io;
 use std::io::prelude::*;
-
-use std::convert::TryFrom;
+use std::ops::Range;
 
 use crate::core;
 use crate::parser;
-use crate::reader::{ReaderUtil, ScopedReader};
+use crate::reader::{ParserConfig, ReaderUtil, ScopedReader};
 use anyhow::anyhow;
 
 pub trait TypeReader
@@ -60,7 +60,25 @@ impl TypeReader for core::TableType {
 
 impl TypeReader for core::MemType {
     fn read<T: io::Read>(reader: &mut T) -> anyhow::Result<Self> {
-        Ok(Self::new(core::Limits::read(reader)?))
+        // Memories use the same limits as tables, except that they can also be shared
+        match reader.read_u8()? {
+            0x00 => Ok(Self::new(core::Limits::Unbounded(reader.read_leb_usize()?))),
+            0x01 => {
+                let min = reader.read_leb_usize()?;
+                let max = reader.read_leb_usize()?;
+
+                Ok(Self::new(core::Limits::Bounded(min, max)))
+            }
+            0x02 => Err(anyhow!("Shared memories must have a maximum size")),
+            0x03 => {
+                let min = reader.read_leb_usize()?;
+                let max = reader.read_leb_usize()?;
+
+                Ok(Self::new_shared(core::Limits::Bounded(min, max)))
+            }
+
+            _ => Err(anyhow!("Unknown Limits tag")),
+        }
     }
 }
 
@@ -147,13 +165,53 @@ impl TypeReader for core::Export {
     }
 }
 
+// Segments other than the MVP form give the kind of element they hold, and functions are
+// the only kind there is
+fn read_elem_kind<T: io::Read>(reader: &mut T) -> anyhow::Result<()> {
+    match reader.read_u8()? {
+        0x00 => Ok(()),
+        kind => Err(anyhow!("Invalid element kind {}", kind)),
+    }
+}
+
 impl TypeReader for core::Element {
     fn read<T: io::Read>(reader: &mut T) -> anyhow::Result<Self> {
-        let x = reader.read_leb_usize()?;
-        let e = core::Expr::read(reader)?;
-        let y = reader.read_vec(T::read_leb_usize)?;
+        // Bit 0 of the flags is set for passive and declarative segments, which have
+        // neither a table nor an offset, and bit 1 then picks declarative. For active
+        // segments bit 1 means there is an explicit table index, and an element kind or
+        // type, where otherwise the table is zero and the entries are functions. Bit 2
+        // means the entries are expressions rather than function indices.
+        let flags = reader.read_leb_u32()?;
+        if flags > 7 {
+            return Err(anyhow!("Invalid element segment flags {}", flags));
+        }
 
-        Ok(Self::new(x, e, y))
+        let mode = match flags & 3 {
+            0 => core::ElementMode::Active(0, core::Expr::read(reader)?),
+            2 => {
+                let x = reader.read_leb_usize()?;
+                core::ElementMode::Active(x, core::Expr::read(reader)?)
+            }
+            1 => core::ElementMode::Passive,
+            _ => core::ElementMode::Declarative,
+        };
+        let explicit_type = flags & 3 != 0;
+
+        let items = if flags & 4 == 0 {
+            if explicit_type {
+                read_elem_kind(reader)?;
+            }
+            core::ElementItems::Functions(reader.read_vec(T::read_leb_usize)?)
+        } else {
+            let elem_type = if explicit_type {
+                core::ElemType::read(reader)?
+            } else {
+                core::ElemType::FuncRef
+            };
+            core::ElementItems::Expressions(elem_type, reader.read_vec(core::Expr::read)?)
+        };
+
+        Ok(Self::with_items(mode, items))
     }
 }
 
@@ -168,26 +226,87 @@ impl TypeReader for core::Locals {
 
 impl TypeReader for core::Func {
     fn read<T: io::Read>(reader: &mut T) -> anyhow::Result<Self> {
-        let size = reader.read_leb_u32()?;
+        read_func(reader, &ParserConfig::default())
+    }
+}
 
-        // Use a subset reader to only read the code part
-        let mut payload_reader = ScopedReader::new(reader, usize::try_from(size).unwrap());
+/// Read a function body from the code section, applying the limits on its instructions
+pub fn read_func<T: io::Read>(reader: &mut T, config: &ParserConfig) -> anyhow::Result<core::Func> {
+    let size = reader.read_leb_usize()?;
 
-        let locals = payload_reader.read_vec(core::Locals::read)?;
-        let e = core::Expr::read(&mut payload_reader)?;
+    // Use a subset reader to only read the code part
+    let mut payload_reader = ScopedReader::new(reader, size);
 
-        assert!(payload_reader.is_at_end());
+    let locals = payload_reader.read_vec(core::Locals::read)?;
+    let e = core::Expr::new(parser::read_limited_expression_bytes(
+        &mut payload_reader,
+        config.max_branch_table_targets,
+    )?);
 
-        Ok(Self::new(locals, e))
+    if !payload_reader.is_at_end() {
+        return Err(anyhow!(
+            "Function body has {} bytes after its end",
+            payload_reader.remaining()
+        ));
     }
+
+    core::Func::try_new(locals, e)
+}
+
+/// Read the function bodies of a code section without decoding their instructions. Only
+/// the locals are read, and each body's expression is left where it is in the buffer,
+/// which all of the bodies share. The section is the range of the buffer given.
+pub fn read_lazy_funcs(
+    buffer: core::Shared<dyn core::SharedBytes>,
+    section: Range<usize>,
+) -> anyhow::Result<Vec<core::Func>> {
+    let code = buffer
+        .bytes()
+        .get(..section.end)
+        .ok_or_else(|| anyhow!("Code section is outside of its buffer"))?;
+    let mut reader = io::Cursor::new(code);
+    reader.set_position(u64::try_from(section.start)?);
+
+    let funcs = reader.read_vec(|reader| {
+        let size = reader.read_leb_usize()?;
+        let end = usize::try_from(reader.position())?
+            .checked_add(size)
+            .filter(|end| *end <= code.len())
+            .ok_or_else(|| anyhow!("Function body is longer than the code section"))?;
+
+        let locals = ScopedReader::new(reader, size).read_vec(core::Locals::read)?;
+        let start = usize::try_from(reader.position())?;
+        reader.set_position(u64::try_from(end)?);
+
+        core::Func::try_new_lazy(locals, core::Expr::from_shared(buffer.clone(), start..end)?)
+    })?;
+
+    if usize::try_from(reader.position())? != code.len() {
+        return Err(anyhow!(
+            "Code section has bytes after its last function body"
+        ));
+    }
+    Ok(funcs)
 }
 
 impl TypeReader for core::Data {
     fn read<T: io::Read>(reader: &mut T) -> anyhow::Result<Self> {
-        let x = reader.read_leb_usize()?;
-        let e = core::Expr::read(reader)?;
-        let b = reader.read_vec(T::read_u8)?;
-
-        Ok(Self::new(x, e, b))
+        // Passive segments have no memory or offset. Active segments either use memory
+        // zero, or have an explicit memory index
+        match reader.read_leb_u32()? {
+            0 => {
+                let e = core::Expr::read(reader)?;
+                let b = reader.read_vec(T::read_u8)?;
+                Ok(Self::new(0, e, b))
+            }
+            1 => Ok(Self::new_passive(reader.read_vec(T::read_u8)?)),
+            2 => {
+                let x = reader.read_leb_usize()?;
+                let e = core::Expr::read(reader)?;
+                let b = reader.read_vec(T::read_u8)?;
+                Ok(Self::new(x, e, b))
+            }
+            _ => Err(anyhow!("Invalid data segment flags")),
+        }
     }
 }
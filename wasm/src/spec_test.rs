@@ -0,0 +1,11 @@
+//! Runs the `.wast` scripts which make up the WebAssembly spec test suite. The `wast` crate
+//! parses the scripts and assembles their modules, and everything else is done by the
+//! interpreter. This is only built with the `wast` feature.
+
+mod registry;
+mod script;
+mod values;
+
+pub use registry::ModuleRegistry;
+pub use script::{run_script, run_script_from_path, ScriptFailure, ScriptReport};
+pub use values::{check_results, to_value};
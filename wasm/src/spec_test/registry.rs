@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::core::{
+    handle, Callable, ElemType, ExportValue, FuncType, Global, GlobalType, Handle,
+    HostFuncCallable, ImportNotFound, Instance, Limits, MemType, Memory, MutableType, Resolver,
+    Table, TableType, ValueType,
+};
+
+/// The instances a script creates. Scripts can name an instance so that later commands can
+/// refer to it, and register one under a module name so that later modules can import
+/// from it. The `spectest` module, which the test suite imports from, is always registered.
+pub struct ModuleRegistry {
+    current: Option<Rc<Instance>>,
+    named: BTreeMap<String, Rc<Instance>>,
+    registered: BTreeMap<String, BTreeMap<String, ExportValue>>,
+}
+
+fn print_function(arg_types: Vec<ValueType>) -> ExportValue {
+    let callable = HostFuncCallable::new(FuncType::new(arg_types, vec![]), |args| {
+        println!("spectest print: {:?}", args);
+        Ok(vec![])
+    });
+    ExportValue::Function(handle(callable.into()))
+}
+
+fn const_global(value_type: ValueType, value: crate::core::Value) -> Result<ExportValue> {
+    let global = Global::new(
+        GlobalType::new(value_type, MutableType::Const),
+        value.into(),
+    )?;
+    Ok(ExportValue::Global(handle(global)))
+}
+
+// The host module described by the spec interpreter
+fn spectest_exports() -> Result<BTreeMap<String, ExportValue>> {
+    let mut exports = BTreeMap::new();
+
+    let prints = [
+        ("print", vec![]),
+        ("print_i32", vec![ValueType::I32]),
+        ("print_i64", vec![ValueType::I64]),
+        ("print_f32", vec![ValueType::F32]),
+        ("print_f64", vec![ValueType::F64]),
+        ("print_i32_f32", vec![ValueType::I32, ValueType::F32]),
+        ("print_f64_f64", vec![ValueType::F64, ValueType::F64]),
+    ];
+    for (name, arg_types) in prints {
+        exports.insert(name.to_string(), print_function(arg_types));
+    }
+
+    exports.insert(
+        "global_i32".to_string(),
+        const_global(ValueType::I32, 666.into())?,
+    );
+    exports.insert(
+        "global_i64".to_string(),
+        const_global(ValueType::I64, 666_i64.into())?,
+    );
+    exports.insert(
+        "global_f32".to_string(),
+        const_global(ValueType::F32, 666.6_f32.into())?,
+    );
+    exports.insert(
+        "global_f64".to_string(),
+        const_global(ValueType::F64, 666.6_f64.into())?,
+    );
+
+    let table = Table::new(TableType::new(ElemType::FuncRef, Limits::Bounded(10, 20)));
+    exports.insert("table".to_string(), ExportValue::Table(handle(table)));
+    let memory = Memory::new(MemType::new(Limits::Bounded(1, 2)));
+    exports.insert("memory".to_string(), ExportValue::Memory(handle(memory)));
+
+    Ok(exports)
+}
+
+impl ModuleRegistry {
+    pub fn new() -> Result<Self> {
+        let mut registered = BTreeMap::new();
+        registered.insert("spectest".to_string(), spectest_exports()?);
+
+        Ok(Self {
+            current: None,
+            named: BTreeMap::new(),
+            registered,
+        })
+    }
+
+    /// Make the instance the one which commands without a module name refer to
+    pub fn add_instance(&mut self, name: Option<&str>, instance: Instance) {
+        let instance = Rc::new(instance);
+        if let Some(name) = name {
+            self.named.insert(name.to_string(), instance.clone());
+        }
+        self.current = Some(instance);
+    }
+
+    /// Forget the current instance, after a module fails to instantiate, so that commands
+    /// meant for it don't run against an earlier one
+    pub fn clear_current(&mut self) {
+        self.current = None;
+    }
+
+    pub fn instance(&self, name: Option<&str>) -> Result<&Rc<Instance>> {
+        match name {
+            Some(name) => self
+                .named
+                .get(name)
+                .ok_or_else(|| anyhow!("There is no module named {}", name)),
+            None => self
+                .current
+                .as_ref()
+                .ok_or_else(|| anyhow!("No module has been instantiated")),
+        }
+    }
+
+    /// Allow later modules to import the exports of an instance from `as_name`
+    pub fn register(&mut self, as_name: &str, name: Option<&str>) -> Result<()> {
+        let exports = self
+            .instance(name)?
+            .export_values()
+            .map(|(export_name, export)| (export_name.clone(), export.clone()))
+            .collect();
+        self.registered.insert(as_name.to_string(), exports);
+        Ok(())
+    }
+
+    fn lookup(&self, mod_name: &str, name: &str) -> Option<&ExportValue> {
+        self.registered
+            .get(mod_name)
+            .and_then(|exports| exports.get(name))
+    }
+}
+
+// The types of what is found are checked when the imports are resolved
+impl Resolver for ModuleRegistry {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _func_type: &FuncType,
+    ) -> Result<Handle<Callable>> {
+        match self.lookup(mod_name, name) {
+            Some(ExportValue::Function(callable)) => Ok(callable.clone()),
+            Some(_) => Err(anyhow!("Import {}:{} is not a function", mod_name, name)),
+            None => Err(ImportNotFound::new("function", mod_name, name).into()),
+        }
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _table_type: &TableType,
+    ) -> Result<Handle<Table>> {
+        match self.lookup(mod_name, name) {
+            Some(ExportValue::Table(table)) => Ok(table.clone()),
+            Some(_) => Err(anyhow!("Import {}:{} is not a table", mod_name, name)),
+            None => Err(ImportNotFound::new("table", mod_name, name).into()),
+        }
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _mem_type: &MemType,
+    ) -> Result<Handle<Memory>> {
+        match self.lookup(mod_name, name) {
+            Some(ExportValue::Memory(memory)) => Ok(memory.clone()),
+            Some(_) => Err(anyhow!("Import {}:{} is not a memory", mod_name, name)),
+            None => Err(ImportNotFound::new("memory", mod_name, name).into()),
+        }
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        _global_type: &GlobalType,
+    ) -> Result<Handle<Global>> {
+        match self.lookup(mod_name, name) {
+            Some(ExportValue::Global(global)) => Ok(global.clone()),
+            Some(_) => Err(anyhow!("Import {}:{} is not a global", mod_name, name)),
+            None => Err(ImportNotFound::new("global", mod_name, name).into()),
+        }
+    }
+}
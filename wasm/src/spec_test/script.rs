@@ -0,0 +1,283 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Cursor;
+use wast::parser::{self, ParseBuffer};
+use wast::{QuoteWat, Wast, WastDirective, WastExecute, WastInvoke, Wat};
+
+use super::{check_results, to_value, ModuleRegistry};
+use crate::core::{
+    resolve_raw_module, validate_raw_module, Instance, RawModule, TrapKind, Value, WasmError,
+};
+use crate::reader::TypeReader;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptFailure {
+    /// Counting from one
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// What happened when a script was run. Commands for features the interpreter doesn't
+/// have, such as threads and exceptions, are skipped rather than failed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScriptReport {
+    pub passed: usize,
+    pub skipped: usize,
+    pub failures: Vec<ScriptFailure>,
+}
+
+impl ScriptReport {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+enum Outcome {
+    Passed,
+    Skipped,
+}
+
+// The modules a script has defined without instantiating them, by name. The most recent
+// is also kept without a name.
+type Definitions = BTreeMap<Option<String>, Vec<u8>>;
+
+fn encode_module(module: &mut QuoteWat) -> Result<Vec<u8>> {
+    Ok(module.encode()?)
+}
+
+fn instantiate(bytes: &[u8], registry: &ModuleRegistry) -> Result<Instance> {
+    let module = RawModule::read(&mut Cursor::new(bytes))?;
+    Ok(Instance::new(resolve_raw_module(module, registry)?))
+}
+
+// Read and validate a module without instantiating it, to find out whether it is rejected
+fn load_error(bytes: &[u8], registry: &ModuleRegistry) -> Option<anyhow::Error> {
+    RawModule::read(&mut Cursor::new(bytes))
+        .and_then(|module| validate_raw_module(module, registry))
+        .err()
+}
+
+fn invoke(registry: &ModuleRegistry, call: &WastInvoke) -> Result<Vec<Value>> {
+    let instance = registry.instance(call.module.map(|id| id.name()))?;
+    let args = call.args.iter().map(to_value).collect::<Result<Vec<_>>>()?;
+    instance.invoke(call.name, &args)
+}
+
+fn execute(registry: &ModuleRegistry, exec: &mut WastExecute) -> Result<Vec<Value>> {
+    match exec {
+        WastExecute::Invoke(call) => invoke(registry, call),
+        WastExecute::Get { module, global, .. } => {
+            let instance = registry.instance(module.map(|id| id.name()))?;
+            match instance.get_export(global) {
+                Some(crate::core::ExportValue::Global(value)) => {
+                    Ok(vec![Value::from(*value.borrow().get_value())])
+                }
+                _ => Err(anyhow!("There is no global named {}", global)),
+            }
+        }
+        // Modules are instantiated to see whether it traps, and then thrown away
+        WastExecute::Wat(module) => {
+            instantiate(&module.encode()?, registry)?;
+            Ok(vec![])
+        }
+    }
+}
+
+// The spec's messages can have more detail after the ones the interpreter uses
+fn check_trap(error: anyhow::Error, message: &str) -> Result<Outcome> {
+    match WasmError::trap_kind(&error) {
+        Some(kind) if message.starts_with(&kind.to_string()) => Ok(Outcome::Passed),
+        Some(kind) => Err(anyhow!(
+            "Trapped with \"{}\" but \"{}\" was expected",
+            kind,
+            message
+        )),
+        None => Err(error.context(format!("Expected trap \"{}\"", message))),
+    }
+}
+
+fn is_link_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<WasmError>(),
+        Some(WasmError::LinkError(_))
+    )
+}
+
+fn is_component(module: &QuoteWat) -> bool {
+    matches!(
+        module,
+        QuoteWat::Wat(Wat::Component(_)) | QuoteWat::QuoteComponent(..)
+    )
+}
+
+// The interpreter's error messages aren't the same as the reference interpreter's, so
+// modules which should be rejected only have to be rejected, for any reason
+fn run_directive(
+    registry: &mut ModuleRegistry,
+    definitions: &mut Definitions,
+    directive: WastDirective,
+) -> Result<Outcome> {
+    match directive {
+        WastDirective::Module(module) | WastDirective::ModuleDefinition(module)
+            if is_component(&module) =>
+        {
+            Ok(Outcome::Skipped)
+        }
+        WastDirective::Module(mut module) => {
+            let name = module.name().map(|id| id.name());
+            match instantiate(&encode_module(&mut module)?, registry) {
+                Ok(instance) => {
+                    registry.add_instance(name, instance);
+                    Ok(Outcome::Passed)
+                }
+                Err(e) => {
+                    registry.clear_current();
+                    Err(e)
+                }
+            }
+        }
+        WastDirective::ModuleDefinition(mut module) => {
+            let name = module.name().map(|id| id.name().to_string());
+            let bytes = encode_module(&mut module)?;
+            definitions.insert(None, bytes.clone());
+            definitions.insert(name, bytes);
+            Ok(Outcome::Passed)
+        }
+        WastDirective::ModuleInstance {
+            instance, module, ..
+        } => {
+            let definition = module.map(|id| id.name().to_string());
+            let bytes = definitions
+                .get(&definition)
+                .ok_or_else(|| anyhow!("There is no module definition {:?}", definition))?;
+            let name = instance.map(|id| id.name());
+            registry.add_instance(name, instantiate(bytes, registry)?);
+            Ok(Outcome::Passed)
+        }
+        WastDirective::AssertMalformed {
+            mut module,
+            message,
+            ..
+        } => {
+            // Modules in text form may be malformed text, which the assembler rejects
+            let bytes = match module.encode() {
+                Ok(bytes) => bytes,
+                Err(_) => return Ok(Outcome::Passed),
+            };
+            match RawModule::read(&mut Cursor::new(&bytes)) {
+                Err(_) => Ok(Outcome::Passed),
+                Ok(_) => Err(anyhow!(
+                    "Malformed module was read, but \"{}\" was expected",
+                    message
+                )),
+            }
+        }
+        WastDirective::AssertInvalid {
+            mut module,
+            message,
+            ..
+        } => match load_error(&encode_module(&mut module)?, registry) {
+            Some(e) if is_link_error(&e) => {
+                Err(e.context(format!("Expected invalid module \"{}\"", message)))
+            }
+            Some(_) => Ok(Outcome::Passed),
+            None => Err(anyhow!(
+                "Invalid module was accepted, but \"{}\" was expected",
+                message
+            )),
+        },
+        WastDirective::AssertUnlinkable {
+            mut module,
+            message,
+            ..
+        } => match instantiate(&module.encode()?, registry) {
+            Err(_) => Ok(Outcome::Passed),
+            Ok(_) => Err(anyhow!(
+                "Module was linked, but \"{}\" was expected",
+                message
+            )),
+        },
+        WastDirective::Register { name, module, .. } => {
+            registry.register(name, module.map(|id| id.name()))?;
+            Ok(Outcome::Passed)
+        }
+        WastDirective::Invoke(call) => {
+            invoke(registry, &call)?;
+            Ok(Outcome::Passed)
+        }
+        WastDirective::AssertReturn {
+            mut exec, results, ..
+        } => {
+            check_results(&execute(registry, &mut exec)?, &results)?;
+            Ok(Outcome::Passed)
+        }
+        WastDirective::AssertTrap {
+            mut exec, message, ..
+        } => match execute(registry, &mut exec) {
+            Ok(results) => Err(anyhow!(
+                "Returned {:?}, but trap \"{}\" was expected",
+                results,
+                message
+            )),
+            Err(e) => check_trap(e, message),
+        },
+        WastDirective::AssertExhaustion { call, message, .. } => match invoke(registry, &call) {
+            Ok(results) => Err(anyhow!(
+                "Returned {:?}, but \"{}\" was expected",
+                results,
+                message
+            )),
+            Err(e) => match e.downcast_ref::<WasmError>() {
+                Some(WasmError::Trap(TrapKind::CallStackExhausted))
+                | Some(WasmError::ExhaustionError(_)) => Ok(Outcome::Passed),
+                _ => Err(e.context(format!("Expected exhaustion \"{}\"", message))),
+            },
+        },
+        WastDirective::AssertException { .. }
+        | WastDirective::AssertSuspension { .. }
+        | WastDirective::Thread(_)
+        | WastDirective::Wait { .. } => Ok(Outcome::Skipped),
+    }
+}
+
+/// Run every command in a script. A command failing doesn't stop the script, but a script
+/// which can't be parsed is an error.
+pub fn run_script(text: &str) -> Result<ScriptReport> {
+    let with_text = |mut e: wast::Error| {
+        e.set_text(text);
+        anyhow!("{}", e)
+    };
+    let buffer = ParseBuffer::new(text).map_err(with_text)?;
+    let script = parser::parse::<Wast>(&buffer).map_err(with_text)?;
+
+    let mut registry = ModuleRegistry::new()?;
+    let mut definitions = Definitions::new();
+    let mut report = ScriptReport::default();
+
+    for directive in script.directives {
+        let (line, _) = directive.span().linecol_in(text);
+        match run_directive(&mut registry, &mut definitions, directive) {
+            Ok(Outcome::Passed) => report.passed += 1,
+            Ok(Outcome::Skipped) => report.skipped += 1,
+            Err(e) => report.failures.push(ScriptFailure {
+                line: line + 1,
+                message: format!("{:#}", e),
+            }),
+        }
+    }
+
+    Ok(report)
+}
+
+pub fn run_script_from_path(path: &str) -> Result<ScriptReport> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read script from {}", path))?;
+    run_script(&text).with_context(|| format!("Failed to run script {}", path))
+}
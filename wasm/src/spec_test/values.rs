@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Result};
+use wast::core::{AbstractHeapType, HeapType, NanPattern, WastArgCore, WastRetCore};
+use wast::token::{F32, F64};
+use wast::{WastArg, WastRet};
+
+use crate::core::Value;
+
+fn is_func_heap_type(heap_type: &HeapType) -> Option<bool> {
+    match heap_type {
+        HeapType::Abstract {
+            ty: AbstractHeapType::Func,
+            ..
+        } => Some(true),
+        HeapType::Abstract {
+            ty: AbstractHeapType::Extern,
+            ..
+        } => Some(false),
+        _ => None,
+    }
+}
+
+/// Convert an argument to an invocation
+pub fn to_value(arg: &WastArg) -> Result<Value> {
+    let value = match arg {
+        WastArg::Core(WastArgCore::I32(i)) => Value::I32(*i),
+        WastArg::Core(WastArgCore::I64(i)) => Value::I64(*i),
+        WastArg::Core(WastArgCore::F32(f)) => Value::F32(f32::from_bits(f.bits)),
+        WastArg::Core(WastArgCore::F64(f)) => Value::F64(f64::from_bits(f.bits)),
+        WastArg::Core(WastArgCore::RefNull(heap_type)) => match is_func_heap_type(heap_type) {
+            Some(true) => Value::FuncRef(None),
+            Some(false) => Value::ExternRef(None),
+            None => return Err(anyhow!("Unsupported null reference type {:?}", heap_type)),
+        },
+        WastArg::Core(WastArgCore::RefExtern(r)) => Value::ExternRef(Some(*r)),
+        _ => return Err(anyhow!("Unsupported argument {:?}", arg)),
+    };
+    Ok(value)
+}
+
+// A canonical NaN has only the top bit of the mantissa set, and an arithmetic NaN has at
+// least that bit set. Either sign is allowed.
+fn f32_matches(actual: f32, expected: &NanPattern<F32>) -> bool {
+    let bits = actual.to_bits();
+    match expected {
+        NanPattern::CanonicalNan => bits & 0x7fff_ffff == 0x7fc0_0000,
+        NanPattern::ArithmeticNan => actual.is_nan() && bits & 0x0040_0000 != 0,
+        NanPattern::Value(expected) => bits == expected.bits,
+    }
+}
+
+fn f64_matches(actual: f64, expected: &NanPattern<F64>) -> bool {
+    let bits = actual.to_bits();
+    match expected {
+        NanPattern::CanonicalNan => bits & 0x7fff_ffff_ffff_ffff == 0x7ff8_0000_0000_0000,
+        NanPattern::ArithmeticNan => actual.is_nan() && bits & 0x0008_0000_0000_0000 != 0,
+        NanPattern::Value(expected) => bits == expected.bits,
+    }
+}
+
+fn matches_result(actual: &Value, expected: &WastRetCore) -> Result<bool> {
+    let matched = match (actual, expected) {
+        (Value::I32(a), WastRetCore::I32(e)) => a == e,
+        (Value::I64(a), WastRetCore::I64(e)) => a == e,
+        (Value::F32(a), WastRetCore::F32(e)) => f32_matches(*a, e),
+        (Value::F64(a), WastRetCore::F64(e)) => f64_matches(*a, e),
+        (Value::FuncRef(None), WastRetCore::RefNull(heap_type))
+        | (Value::ExternRef(None), WastRetCore::RefNull(heap_type)) => {
+            match heap_type.as_ref().map(is_func_heap_type) {
+                None => true,
+                Some(Some(is_func)) => is_func == matches!(actual, Value::FuncRef(_)),
+                Some(None) => {
+                    return Err(anyhow!("Unsupported null reference type {:?}", heap_type))
+                }
+            }
+        }
+        (Value::ExternRef(Some(a)), WastRetCore::RefExtern(e)) => match e {
+            Some(e) => a == e,
+            None => true,
+        },
+        (Value::FuncRef(Some(_)), WastRetCore::RefFunc(_)) => true,
+        (_, WastRetCore::Either(cases)) => {
+            for case in cases {
+                if matches_result(actual, case)? {
+                    return Ok(true);
+                }
+            }
+            false
+        }
+        (
+            _,
+            WastRetCore::I32(_)
+            | WastRetCore::I64(_)
+            | WastRetCore::F32(_)
+            | WastRetCore::F64(_)
+            | WastRetCore::RefNull(_)
+            | WastRetCore::RefExtern(_)
+            | WastRetCore::RefFunc(_),
+        ) => false,
+        _ => return Err(anyhow!("Unsupported expected result {:?}", expected)),
+    };
+    Ok(matched)
+}
+
+/// Check the results of an invocation against what the script expects. NaNs match by
+/// bit pattern, or by the canonical and arithmetic patterns.
+pub fn check_results(actual: &[Value], expected: &[WastRet]) -> Result<()> {
+    if actual.len() != expected.len() {
+        return Err(anyhow!(
+            "Returned {:?} but {} results were expected",
+            actual,
+            expected.len()
+        ));
+    }
+
+    for (idx, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+        let matched = match expected {
+            WastRet::Core(expected) => matches_result(actual, expected)?,
+            _ => return Err(anyhow!("Unsupported expected result {:?}", expected)),
+        };
+        if !matched {
+            return Err(anyhow!(
+                "Result {} is {:?} but {:?} was expected",
+                idx,
+                actual,
+                expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nan_patterns() {
+        let canonical = NanPattern::CanonicalNan;
+        let arithmetic = NanPattern::ArithmeticNan;
+
+        assert!(f32_matches(f32::from_bits(0x7fc0_0000), &canonical));
+        assert!(f32_matches(f32::from_bits(0xffc0_0000), &canonical));
+        assert!(!f32_matches(f32::from_bits(0x7fc0_0001), &canonical));
+        assert!(f32_matches(f32::from_bits(0x7fc0_0001), &arithmetic));
+        assert!(!f32_matches(f32::from_bits(0x7f80_0001), &arithmetic));
+        assert!(!f32_matches(f32::INFINITY, &arithmetic));
+
+        assert!(f64_matches(
+            f64::from_bits(0xfff8_0000_0000_0000),
+            &NanPattern::CanonicalNan
+        ));
+        assert!(f64_matches(
+            f64::from_bits(0x7ffc_0000_0000_0000),
+            &NanPattern::ArithmeticNan
+        ));
+        assert!(!f64_matches(
+            f64::from_bits(0x7ff0_0000_0000_0001),
+            &NanPattern::ArithmeticNan
+        ));
+
+        // Values compare by bit pattern, so zeros of different signs don't match
+        let zero = NanPattern::Value(F64 { bits: 0 });
+        assert!(f64_matches(0.0, &zero));
+        assert!(!f64_matches(-0.0, &zero));
+    }
+}
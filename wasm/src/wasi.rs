@@ -0,0 +1,641 @@
+use anyhow::{anyhow, Context, Result};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::core::{
+    handle, Callable, ExportValue, FuncType, Global, GlobalType, Handle, HostFuncCallable,
+    ImportNotFound, Instance, Lock, MemType, Memory, Resolver, Shared, Table, TableType, Value,
+    ValueType,
+};
+use crate::output_capture::{OutputCapture, OutputStream};
+
+pub const WASI_MODULE_NAME: &str = "wasi_snapshot_preview1";
+
+// The subset of the WASI errno values that we return
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_BADF: i32 = 8;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_INVAL: i32 = 28;
+
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const CLOCK_PROCESS_CPUTIME: i32 = 2;
+const CLOCK_THREAD_CPUTIME: i32 = 3;
+
+/// The error returned from a call into a module when it calls `proc_exit`. Embedders can
+/// downcast to this to find the exit code.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProcExit(pub i32);
+
+impl fmt::Display for ProcExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Module exited with code {}", self.0)
+    }
+}
+
+impl std::error::Error for ProcExit {}
+
+#[derive(Debug)]
+struct WasiState {
+    args: Vec<String>,
+    env: Vec<String>,
+    memory: Lock<Option<Handle<Memory>>>,
+    output: Lock<Option<OutputCapture>>,
+    start_time: Instant,
+    /// The state of the generator `random_get` uses in place of the host's secure source of
+    /// randomness, if the embedder has asked for repeatable randomness
+    random_seed: Lock<Option<u64>>,
+}
+
+impl WasiState {
+    fn memory(&self) -> Result<Handle<Memory>> {
+        self.memory
+            .borrow()
+            .clone()
+            .ok_or_else(|| anyhow!("WASI function called before the module memory was attached"))
+    }
+
+    // Fill the buffer from the host's secure source of randomness, unless a seed has been
+    // given, in which case xorshift64* makes the same bytes every time
+    fn fill_random(&self, bytes: &mut [u8]) -> Result<()> {
+        let mut seed = self.random_seed.borrow_mut();
+        let state = match seed.as_mut() {
+            Some(state) => state,
+            None => {
+                return getrandom::getrandom(bytes)
+                    .map_err(|e| anyhow!("Failed to get random bytes from the host: {}", e))
+            }
+        };
+
+        for chunk in bytes.chunks_mut(8) {
+            let mut x = *state;
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            *state = x;
+            let random = x.wrapping_mul(0x2545_F491_4F6C_DD1D).to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+fn read_u32(memory: &Memory, offset: u32) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    memory.get_data(offset as usize, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn write_u32(memory: &mut Memory, offset: u32, value: u32) -> Result<()> {
+    memory.set_data(offset as usize, &value.to_le_bytes())
+}
+
+fn write_u64(memory: &mut Memory, offset: u32, value: u64) -> Result<()> {
+    memory.set_data(offset as usize, &value.to_le_bytes())
+}
+
+fn size_u32(size: usize) -> Result<u32> {
+    u32::try_from(size).map_err(|_| anyhow!("WASI value of {} bytes is too large", size))
+}
+
+// Read the (buf, buf_len) pairs of an iovec array
+fn read_iovecs(memory: &Memory, iovs: u32, iovs_len: u32) -> Result<Vec<(u32, u32)>> {
+    (0..iovs_len)
+        .map(|idx| {
+            let iov = iovs.wrapping_add(idx * 8);
+            Ok((
+                read_u32(memory, iov)?,
+                read_u32(memory, iov.wrapping_add(4))?,
+            ))
+        })
+        .collect()
+}
+
+// The buffers are checked before any of them are used, so that a guest can't have the host
+// allocate more than its memory holds, and a bad buffer fails the call without a partial
+// transfer
+fn iovecs_in_bounds(memory: &Memory, iovecs: &[(u32, u32)]) -> bool {
+    iovecs
+        .iter()
+        .all(|(buf, buf_len)| memory.contains(*buf as usize, *buf_len as usize))
+}
+
+// Lay out a list of strings the way args_get and environ_get return them, as an array of
+// pointers into a buffer of nul terminated strings
+fn write_string_list(
+    memory: &mut Memory,
+    strings: &[String],
+    pointers: u32,
+    mut buffer: u32,
+) -> Result<()> {
+    for (idx, string) in strings.iter().enumerate() {
+        write_u32(memory, pointers.wrapping_add(size_u32(idx * 4)?), buffer)?;
+        memory.set_data(buffer as usize, string.as_bytes())?;
+        memory.set_data(buffer as usize + string.len(), &[0])?;
+        buffer = buffer.wrapping_add(size_u32(string.len() + 1)?);
+    }
+
+    Ok(())
+}
+
+fn write_string_list_sizes(
+    memory: &mut Memory,
+    strings: &[String],
+    count_ptr: u32,
+    size_ptr: u32,
+) -> Result<()> {
+    let buffer_size: usize = strings.iter().map(|s| s.len() + 1).sum();
+    write_u32(memory, count_ptr, size_u32(strings.len())?)?;
+    write_u32(memory, size_ptr, size_u32(buffer_size)?)
+}
+
+fn i32_args<const N: usize>(args: &[Value]) -> [u32; N] {
+    let mut ret = [0; N];
+    for (ret, arg) in ret.iter_mut().zip(args) {
+        *ret = match arg {
+            Value::I32(v) => *v as u32,
+            _ => panic!("WASI function called with the wrong signature"),
+        };
+    }
+    ret
+}
+
+fn errno(errno: i32) -> Result<Vec<Value>> {
+    Ok(vec![Value::I32(errno)])
+}
+
+fn fd_write(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [fd, iovs, iovs_len, nwritten_ptr] = i32_args::<4>(args);
+    let memory = state.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    let stream = match fd {
+        1 => OutputStream::Stdout,
+        2 => OutputStream::Stderr,
+        _ => return errno(ERRNO_BADF),
+    };
+    let capture = state.output.borrow().clone();
+    let mut out: Box<dyn Write> = match stream {
+        OutputStream::Stdout => Box::new(io::stdout()),
+        _ => Box::new(io::stderr()),
+    };
+
+    let iovecs = read_iovecs(&memory, iovs, iovs_len)?;
+    if !iovecs_in_bounds(&memory, &iovecs) {
+        return errno(ERRNO_FAULT);
+    }
+
+    let mut written = 0;
+    for (buf, buf_len) in iovecs {
+        let mut bytes = vec![0u8; buf_len as usize];
+        memory.get_data(buf as usize, &mut bytes)?;
+        match &capture {
+            Some(capture) => capture.write(stream, &bytes),
+            None => out.write_all(&bytes)?,
+        }
+        written += bytes.len();
+    }
+    out.flush()?;
+
+    write_u32(&mut memory, nwritten_ptr, size_u32(written)?)?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn fd_read(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [fd, iovs, iovs_len, nread_ptr] = i32_args::<4>(args);
+    if fd != 0 {
+        return errno(ERRNO_BADF);
+    }
+
+    let memory = state.memory()?;
+    let mut memory = memory.borrow_mut();
+
+    let iovecs = read_iovecs(&memory, iovs, iovs_len)?;
+    if !iovecs_in_bounds(&memory, &iovecs) {
+        return errno(ERRNO_FAULT);
+    }
+
+    let mut read = 0;
+    for (buf, buf_len) in iovecs {
+        let mut bytes = vec![0u8; buf_len as usize];
+        let count = io::stdin().read(&mut bytes)?;
+        memory.set_data(buf as usize, &bytes[0..count])?;
+        read += count;
+
+        // A short read means there is nothing more available right now
+        if count < bytes.len() {
+            break;
+        }
+    }
+
+    write_u32(&mut memory, nread_ptr, size_u32(read)?)?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn fd_close(_state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [fd] = i32_args::<1>(args);
+    errno(if fd <= 2 { ERRNO_SUCCESS } else { ERRNO_BADF })
+}
+
+// There are no preopened directories, which is how a program discovers that
+fn fd_prestat_get(_state: &WasiState, _args: &[Value]) -> Result<Vec<Value>> {
+    errno(ERRNO_BADF)
+}
+
+fn proc_exit(_state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [code] = i32_args::<1>(args);
+    Err(ProcExit(code as i32).into())
+}
+
+fn args_get(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [argv, argv_buf] = i32_args::<2>(args);
+    let memory = state.memory()?;
+    write_string_list(&mut memory.borrow_mut(), &state.args, argv, argv_buf)?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn args_sizes_get(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [argc_ptr, argv_buf_size_ptr] = i32_args::<2>(args);
+    let memory = state.memory()?;
+    write_string_list_sizes(
+        &mut memory.borrow_mut(),
+        &state.args,
+        argc_ptr,
+        argv_buf_size_ptr,
+    )?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn environ_get(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [environ, environ_buf] = i32_args::<2>(args);
+    let memory = state.memory()?;
+    write_string_list(&mut memory.borrow_mut(), &state.env, environ, environ_buf)?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn environ_sizes_get(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [count_ptr, buf_size_ptr] = i32_args::<2>(args);
+    let memory = state.memory()?;
+    write_string_list_sizes(
+        &mut memory.borrow_mut(),
+        &state.env,
+        count_ptr,
+        buf_size_ptr,
+    )?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn clock_time_get(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let (clock_id, time_ptr) = match args {
+        [Value::I32(clock_id), Value::I64(_precision), Value::I32(time_ptr)] => {
+            (*clock_id, *time_ptr as u32)
+        }
+        _ => panic!("WASI function called with the wrong signature"),
+    };
+
+    // We have no way to measure CPU time, so those clocks just use the monotonic time
+    let time = match clock_id {
+        CLOCK_REALTIME => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| anyhow!("System clock is before the epoch"))?,
+        CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME | CLOCK_THREAD_CPUTIME => {
+            state.start_time.elapsed()
+        }
+        _ => return errno(ERRNO_INVAL),
+    };
+
+    let memory = state.memory()?;
+    write_u64(&mut memory.borrow_mut(), time_ptr, time.as_nanos() as u64)?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn random_get(state: &WasiState, args: &[Value]) -> Result<Vec<Value>> {
+    let [buf, buf_len] = i32_args::<2>(args);
+    let memory = state.memory()?;
+    let mut memory = memory.borrow_mut();
+    if !memory.contains(buf as usize, buf_len as usize) {
+        return errno(ERRNO_FAULT);
+    }
+
+    let mut bytes = vec![0; buf_len as usize];
+    state.fill_random(&mut bytes)?;
+    memory.set_data(buf as usize, &bytes)?;
+    errno(ERRNO_SUCCESS)
+}
+
+fn sched_yield(_state: &WasiState, _args: &[Value]) -> Result<Vec<Value>> {
+    errno(ERRNO_SUCCESS)
+}
+
+type WasiFunc = fn(&WasiState, &[Value]) -> Result<Vec<Value>>;
+
+fn lookup_wasi_function(name: &str) -> Option<(WasiFunc, FuncType)> {
+    use ValueType::{I32, I64};
+
+    let (func, arg_types, return_types): (WasiFunc, _, _) = match name {
+        "fd_write" => (fd_write, vec![I32, I32, I32, I32], vec![I32]),
+        "fd_read" => (fd_read, vec![I32, I32, I32, I32], vec![I32]),
+        "fd_close" => (fd_close, vec![I32], vec![I32]),
+        "fd_prestat_get" => (fd_prestat_get, vec![I32, I32], vec![I32]),
+        "proc_exit" => (proc_exit, vec![I32], vec![]),
+        "args_get" => (args_get, vec![I32, I32], vec![I32]),
+        "args_sizes_get" => (args_sizes_get, vec![I32, I32], vec![I32]),
+        "environ_get" => (environ_get, vec![I32, I32], vec![I32]),
+        "environ_sizes_get" => (environ_sizes_get, vec![I32, I32], vec![I32]),
+        "clock_time_get" => (clock_time_get, vec![I32, I64, I32], vec![I32]),
+        "random_get" => (random_get, vec![I32, I32], vec![I32]),
+        "sched_yield" => (sched_yield, vec![], vec![I32]),
+        _ => return None,
+    };
+
+    Some((func, FuncType::new(arg_types, return_types)))
+}
+
+/// A resolver which provides the `wasi_snapshot_preview1` functions, and passes any other
+/// imports on to an inner resolver.
+///
+/// The WASI functions work on the memory exported by the module, which doesn't exist until
+/// the module is instantiated, so `attach_memory` must be called before the module calls
+/// any of them.
+pub struct WasiResolver<'a, Inner: Resolver> {
+    inner: &'a Inner,
+    state: Shared<WasiState>,
+}
+
+impl<'a, Inner: Resolver> WasiResolver<'a, Inner> {
+    pub fn new(inner: &'a Inner, args: Vec<String>, env: Vec<(String, String)>) -> Self {
+        Self {
+            inner,
+            state: Shared::new(WasiState {
+                args,
+                env: env
+                    .into_iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect(),
+                memory: Lock::new(None),
+                output: Lock::new(None),
+                start_time: Instant::now(),
+                random_seed: Lock::new(None),
+            }),
+        }
+    }
+
+    /// Make `random_get` give the same bytes on every run which uses the same seed, rather
+    /// than the secure randomness it gives by default. This is for reproducing a run, or for
+    /// testing, and is not suitable for anything which needs its randomness to be secret.
+    pub fn seed_random(&self, seed: u64) {
+        // xorshift gets stuck on zero
+        *self.state.random_seed.borrow_mut() = Some(seed | 1);
+    }
+
+    pub fn attach_memory(&self, memory: Handle<Memory>) {
+        *self.state.memory.borrow_mut() = Some(memory);
+    }
+
+    /// Send what the module writes to stdout and stderr to `capture`, instead of to the
+    /// host's own
+    pub fn capture_output(&self, capture: OutputCapture) {
+        *self.state.output.borrow_mut() = Some(capture);
+    }
+
+    /// Attach the memory that the instance exports as "memory", which is where WASI
+    /// modules are required to export it.
+    pub fn attach_instance(&self, instance: &Instance) -> Result<()> {
+        match instance.get_export("memory") {
+            Some(ExportValue::Memory(memory)) => {
+                self.attach_memory(memory.clone());
+                Ok(())
+            }
+            _ => Err(anyhow!(
+                "WASI modules must export their memory as \"memory\""
+            )),
+        }
+    }
+}
+
+impl<'a, Inner: Resolver> Resolver for WasiResolver<'a, Inner> {
+    fn resolve_function(
+        &self,
+        mod_name: &str,
+        name: &str,
+        func_type: &FuncType,
+    ) -> Result<Handle<Callable>> {
+        if mod_name != WASI_MODULE_NAME {
+            return self.inner.resolve_function(mod_name, name, func_type);
+        }
+
+        // A function which isn't implemented counts as not found, so that it can be stubbed
+        let (func, wasi_func_type) = lookup_wasi_function(name)
+            .ok_or_else(|| anyhow::Error::from(ImportNotFound::new("function", mod_name, name)))
+            .with_context(|| format!("WASI function {} is not implemented", name))?;
+        let state = self.state.clone();
+        Ok(handle(Callable::from(HostFuncCallable::new(
+            wasi_func_type,
+            move |args| Ok(func(&state, args)?),
+        ))))
+    }
+    fn resolve_table(
+        &self,
+        mod_name: &str,
+        name: &str,
+        table_type: &TableType,
+    ) -> Result<Handle<Table>> {
+        self.inner.resolve_table(mod_name, name, table_type)
+    }
+    fn resolve_memory(
+        &self,
+        mod_name: &str,
+        name: &str,
+        mem_type: &MemType,
+    ) -> Result<Handle<Memory>> {
+        self.inner.resolve_memory(mod_name, name, mem_type)
+    }
+    fn resolve_global(
+        &self,
+        mod_name: &str,
+        name: &str,
+        global_type: &GlobalType,
+    ) -> Result<Handle<Global>> {
+        self.inner.resolve_global(mod_name, name, global_type)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        resolve_raw_module, test_module::TestModule, EmptyResolver, ExportDesc, Limits,
+    };
+
+    fn make_instance(resolver: &WasiResolver<EmptyResolver>) -> Instance {
+        let binary = FuncType::new(vec![ValueType::I32, ValueType::I32], vec![ValueType::I32]);
+        let unary = FuncType::new(vec![ValueType::I32], vec![]);
+
+        // Each import is re-exported by a function which just forwards its arguments
+        let mut module = TestModule::new()
+            .import_func(WASI_MODULE_NAME, "args_sizes_get", binary.clone())
+            .import_func(WASI_MODULE_NAME, "args_get", binary.clone())
+            .import_func(WASI_MODULE_NAME, "random_get", binary.clone())
+            .import_func(WASI_MODULE_NAME, "proc_exit", unary.clone());
+        for func_idx in 0..3 {
+            module = module.func(
+                binary.clone(),
+                &[0x20, 0x00, 0x20, 0x01, 0x10, func_idx, 0x0B],
+            );
+        }
+        let module = module
+            .func(unary, &[0x20, 0x00, 0x10, 0x03, 0x0B])
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .export("args_sizes_get", ExportDesc::Func(4))
+            .export("args_get", ExportDesc::Func(5))
+            .export("random_get", ExportDesc::Func(6))
+            .export("exit", ExportDesc::Func(7))
+            .export("memory", ExportDesc::Mem(0))
+            .build();
+
+        let instance = Instance::new(resolve_raw_module(module, resolver).unwrap());
+        resolver.attach_instance(&instance).unwrap();
+        instance
+    }
+
+    fn read_memory(instance: &Instance, offset: usize, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        match instance.get_export("memory") {
+            Some(ExportValue::Memory(m)) => m.borrow().get_data(offset, &mut buf).unwrap(),
+            _ => panic!("Unexpected export type"),
+        }
+        buf
+    }
+
+    #[test]
+    fn test_wasi_args() {
+        let resolver = WasiResolver::new(
+            EmptyResolver::instance(),
+            vec!["prog".to_string(), "-v".to_string()],
+            vec![],
+        );
+        let instance = make_instance(&resolver);
+
+        let success = vec![Value::I32(ERRNO_SUCCESS)];
+        assert_eq!(
+            instance
+                .invoke("args_sizes_get", &[Value::I32(0), Value::I32(4)])
+                .unwrap(),
+            success
+        );
+        assert_eq!(read_memory(&instance, 0, 8), [2, 0, 0, 0, 8, 0, 0, 0]);
+
+        assert_eq!(
+            instance
+                .invoke("args_get", &[Value::I32(16), Value::I32(32)])
+                .unwrap(),
+            success
+        );
+        assert_eq!(read_memory(&instance, 16, 8), [32, 0, 0, 0, 37, 0, 0, 0]);
+        assert_eq!(read_memory(&instance, 32, 8), b"prog\0-v\0");
+    }
+
+    #[test]
+    fn test_wasi_random_and_exit() {
+        let resolver = WasiResolver::new(EmptyResolver::instance(), vec![], vec![]);
+        let instance = make_instance(&resolver);
+
+        assert_eq!(
+            instance
+                .invoke("random_get", &[Value::I32(100), Value::I32(13)])
+                .unwrap(),
+            vec![Value::I32(ERRNO_SUCCESS)]
+        );
+        assert_ne!(read_memory(&instance, 100, 13), [0; 13]);
+        assert_eq!(read_memory(&instance, 113, 1), [0]);
+
+        // A buffer which doesn't fit in memory is refused before anything is allocated
+        for (buf, buf_len) in [(100, -1), (65535, 2)] {
+            assert_eq!(
+                instance
+                    .invoke("random_get", &[Value::I32(buf), Value::I32(buf_len)])
+                    .unwrap(),
+                vec![Value::I32(ERRNO_FAULT)]
+            );
+        }
+
+        // With a seed, the same bytes come out every time
+        let seeded_bytes = || {
+            let resolver = WasiResolver::new(EmptyResolver::instance(), vec![], vec![]);
+            resolver.seed_random(42);
+            let instance = make_instance(&resolver);
+            instance
+                .invoke("random_get", &[Value::I32(100), Value::I32(13)])
+                .unwrap();
+            read_memory(&instance, 100, 13)
+        };
+        assert_eq!(seeded_bytes(), seeded_bytes());
+        assert_ne!(seeded_bytes(), [0; 13]);
+
+        let err = instance.invoke("exit", &[Value::I32(3)]).unwrap_err();
+        assert_eq!(err.downcast_ref::<ProcExit>(), Some(&ProcExit(3)));
+    }
+
+    #[test]
+    fn test_wasi_output_capture() {
+        let resolver = WasiResolver::new(EmptyResolver::instance(), vec![], vec![]);
+        let capture = OutputCapture::new(8);
+        resolver.capture_output(capture.clone());
+
+        let fd_write_type = FuncType::new(
+            vec![
+                ValueType::I32,
+                ValueType::I32,
+                ValueType::I32,
+                ValueType::I32,
+            ],
+            vec![ValueType::I32],
+        );
+        let module = TestModule::new()
+            .import_func(WASI_MODULE_NAME, "fd_write", fd_write_type.clone())
+            .func(
+                fd_write_type,
+                &[
+                    0x20, 0x00, 0x20, 0x01, 0x20, 0x02, 0x20, 0x03, 0x10, 0x00, 0x0B,
+                ],
+            )
+            .memory(MemType::new(Limits::Unbounded(1)))
+            .export("fd_write", ExportDesc::Func(1))
+            .export("memory", ExportDesc::Mem(0))
+            .build();
+        let instance = Instance::new(resolve_raw_module(module, &resolver).unwrap());
+        resolver.attach_instance(&instance).unwrap();
+
+        // Two iovecs at 0, pointing at "hello " and "world"
+        let memory = instance.memory(0).unwrap();
+        memory.write(32, b"hello world").unwrap();
+        for (offset, value) in [(0, 32u32), (4, 6), (8, 38), (12, 5)] {
+            memory.set(offset, value).unwrap();
+        }
+        let write = |fd| {
+            instance
+                .invoke(
+                    "fd_write",
+                    &[Value::I32(fd), Value::I32(0), Value::I32(2), Value::I32(16)],
+                )
+                .unwrap()
+        };
+
+        assert_eq!(write(1), vec![Value::I32(ERRNO_SUCCESS)]);
+        // The guest is told everything was written, even what didn't fit
+        assert_eq!(memory.get::<u32>(16).unwrap(), 11);
+        assert_eq!(write(2), vec![Value::I32(ERRNO_SUCCESS)]);
+        assert_eq!(write(3), vec![Value::I32(ERRNO_BADF)]);
+
+        // An iovec bigger than the memory is refused without being read
+        memory.set(12, u32::MAX).unwrap();
+        assert_eq!(write(1), vec![Value::I32(ERRNO_FAULT)]);
+
+        let output = capture.take();
+        assert_eq!(output.stdout, b"hello wo");
+        assert_eq!(output.stderr, b"");
+        assert_eq!(output.dropped_bytes, 14);
+    }
+}
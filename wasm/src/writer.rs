@@ -0,0 +1,5 @@
+mod type_writer;
+mod writer_util;
+
+pub use type_writer::*;
+pub use writer_util::*;
@@ -0,0 +1,335 @@
+use std::io::prelude::*;
+
+use crate::core;
+use crate::parser::InstructionSource;
+use crate::writer::WriterUtil;
+
+/// The counterpart of `TypeReader`, which writes a type in the form it is read
+pub trait TypeWriter {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()>;
+}
+
+impl TypeWriter for core::ValueType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(self.clone() as u8)
+    }
+}
+
+impl TypeWriter for core::MutableType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(self.clone() as u8)
+    }
+}
+
+impl TypeWriter for core::ElemType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(self.clone() as u8)
+    }
+}
+
+// Memories set the second bit of the limits tag when they are shared
+fn write_limits<T: Write>(
+    writer: &mut T,
+    limits: &core::Limits,
+    shared: bool,
+) -> anyhow::Result<()> {
+    let shared_flag = if shared { 0x02 } else { 0x00 };
+    match limits {
+        core::Limits::Unbounded(min) => {
+            writer.write_u8(shared_flag)?;
+            writer.write_leb_usize(*min)
+        }
+        core::Limits::Bounded(min, max) => {
+            writer.write_u8(shared_flag | 0x01)?;
+            writer.write_leb_usize(*min)?;
+            writer.write_leb_usize(*max)
+        }
+    }
+}
+
+impl TypeWriter for core::Limits {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        write_limits(writer, self, false)
+    }
+}
+
+impl TypeWriter for core::TableType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        self.elem_type().write(writer)?;
+        self.limits().write(writer)
+    }
+}
+
+impl TypeWriter for core::MemType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        write_limits(writer, self.limits(), self.is_shared())
+    }
+}
+
+impl TypeWriter for core::GlobalType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        self.value_type().write(writer)?;
+        let m = if self.is_mutable() {
+            core::MutableType::Var
+        } else {
+            core::MutableType::Const
+        };
+        m.write(writer)
+    }
+}
+
+impl TypeWriter for core::FuncType {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_u8(0x60)?;
+        writer.write_vec(self.arg_types(), |w, t| t.write(w))?;
+        writer.write_vec(self.return_types(), |w, t| t.write(w))
+    }
+}
+
+impl TypeWriter for core::ImportDesc {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        match self {
+            Self::TypeIdx(idx) => {
+                writer.write_u8(0x00)?;
+                writer.write_leb_usize(*idx)
+            }
+            Self::TableType(table_type) => {
+                writer.write_u8(0x01)?;
+                table_type.write(writer)
+            }
+            Self::MemType(mem_type) => {
+                writer.write_u8(0x02)?;
+                mem_type.write(writer)
+            }
+            Self::GlobalType(global_type) => {
+                writer.write_u8(0x03)?;
+                global_type.write(writer)
+            }
+        }
+    }
+}
+
+impl TypeWriter for core::Import {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_name(self.mod_name())?;
+        writer.write_name(self.name())?;
+        self.desc().write(writer)
+    }
+}
+
+impl TypeWriter for core::Expr {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        // The bytes include the terminating end instruction
+        writer.write_bytes(self.get_instruction_bytes())
+    }
+}
+
+impl TypeWriter for core::GlobalDef {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        self.global_type().write(writer)?;
+        self.init_expr().write(writer)
+    }
+}
+
+impl TypeWriter for core::ExportDesc {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        let (tag, idx) = match self {
+            core::ExportDesc::Func(idx) => (0x00, idx),
+            core::ExportDesc::Table(idx) => (0x01, idx),
+            core::ExportDesc::Mem(idx) => (0x02, idx),
+            core::ExportDesc::Global(idx) => (0x03, idx),
+        };
+        writer.write_u8(tag)?;
+        writer.write_leb_usize(*idx)
+    }
+}
+
+impl TypeWriter for core::Export {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_name(&self.nm)?;
+        self.d.write(writer)
+    }
+}
+
+// The only element kind is functions
+const ELEM_KIND_FUNC: u8 = 0x00;
+
+impl TypeWriter for core::Element {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        // Use the MVP form for active segments of functions on table zero, so that
+        // modules which don't use bulk memory are written as they were read
+        let expressions = match self.items() {
+            core::ElementItems::Functions(_) => 0,
+            core::ElementItems::Expressions(..) => 4,
+        };
+        let explicit_type = match self.mode() {
+            core::ElementMode::Active(0, e) if self.elem_type() == core::ElemType::FuncRef => {
+                writer.write_leb_u32(expressions)?;
+                e.write(writer)?;
+                false
+            }
+            core::ElementMode::Active(x, e) => {
+                writer.write_leb_u32(expressions | 2)?;
+                writer.write_leb_usize(*x)?;
+                e.write(writer)?;
+                true
+            }
+            core::ElementMode::Passive => {
+                writer.write_leb_u32(expressions | 1)?;
+                true
+            }
+            core::ElementMode::Declarative => {
+                writer.write_leb_u32(expressions | 3)?;
+                true
+            }
+        };
+
+        match self.items() {
+            core::ElementItems::Functions(y) => {
+                if explicit_type {
+                    writer.write_u8(ELEM_KIND_FUNC)?;
+                }
+                writer.write_vec(y, |w, idx| w.write_leb_usize(*idx))
+            }
+            core::ElementItems::Expressions(elem_type, exprs) => {
+                if explicit_type {
+                    elem_type.write(writer)?;
+                }
+                writer.write_vec(exprs, |w, expr| expr.write(w))
+            }
+        }
+    }
+}
+
+impl TypeWriter for core::Locals {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_leb_u32(self.count())?;
+        self.value_type().write(writer)
+    }
+}
+
+impl TypeWriter for core::Func {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        writer.write_sized(|body| {
+            body.write_vec(self.locals(), |w, locals| locals.write(w))?;
+            self.expr().write(body)
+        })
+    }
+}
+
+impl TypeWriter for core::Data {
+    fn write<T: Write>(&self, writer: &mut T) -> anyhow::Result<()> {
+        match self.mode() {
+            core::DataMode::Active(0, e) => {
+                writer.write_leb_u32(0)?;
+                e.write(writer)?;
+            }
+            core::DataMode::Active(x, e) => {
+                writer.write_leb_u32(2)?;
+                writer.write_leb_usize(*x)?;
+                e.write(writer)?;
+            }
+            core::DataMode::Passive => writer.write_leb_u32(1)?,
+        }
+        writer.write_vec(self.bytes(), |w, byte| w.write_u8(*byte))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reader::TypeReader;
+    use std::fmt::Debug;
+    use std::io::Cursor;
+
+    fn round_trip<V: TypeWriter + TypeReader + Debug>(value: &V) -> V {
+        let mut bytes = Vec::new();
+        value.write(&mut bytes).unwrap();
+
+        let mut reader = Cursor::new(bytes);
+        let read_back = V::read(&mut reader).unwrap();
+        assert_eq!(reader.position() as usize, reader.get_ref().len());
+        read_back
+    }
+
+    fn encode<V: TypeWriter>(value: &V) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        value.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_type_round_trips() {
+        let func_type = core::FuncType::new(
+            vec![core::ValueType::I32, core::ValueType::F64],
+            vec![core::ValueType::ExternRef],
+        );
+        assert_eq!(round_trip(&func_type), func_type);
+
+        let shared = core::MemType::new_shared(core::Limits::Bounded(1, 300));
+        assert!(round_trip(&shared).is_shared());
+        assert_eq!(encode(&shared), vec![0x03, 0x01, 0xAC, 0x02]);
+
+        let global_type = core::GlobalType::new(core::ValueType::I64, core::MutableType::Var);
+        assert_eq!(round_trip(&global_type), global_type);
+
+        let offset = core::Expr::new(vec![0x41, 0x05, 0x0B]);
+        let active = core::Element::new(0, offset.clone(), vec![1, 2]);
+        assert_eq!(
+            encode(&active),
+            vec![0x00, 0x41, 0x05, 0x0B, 0x02, 0x01, 0x02]
+        );
+        let other_table = core::Element::new(3, offset.clone(), vec![200]);
+        assert_eq!(encode(&round_trip(&other_table)), encode(&other_table));
+        let declarative = core::Element::new_declarative(vec![0]);
+        assert!(matches!(
+            round_trip(&declarative).mode(),
+            core::ElementMode::Declarative
+        ));
+
+        // Segments of expressions use flags 4 to 7, with a reference type unless they are
+        // active segments of functions on table zero
+        let ref_func = || core::Expr::new(vec![0xD2, 0x01, 0x0B]);
+        let ref_null = || core::Expr::new(vec![0xD0, 0x6F, 0x0B]);
+        let functions = core::Element::with_items(
+            core::ElementMode::Active(0, offset.clone()),
+            core::ElementItems::Expressions(core::ElemType::FuncRef, vec![ref_func()]),
+        );
+        assert_eq!(
+            encode(&functions),
+            vec![0x04, 0x41, 0x05, 0x0B, 0x01, 0xD2, 0x01, 0x0B]
+        );
+        let externs = core::Element::with_items(
+            core::ElementMode::Active(0, offset.clone()),
+            core::ElementItems::Expressions(core::ElemType::ExternRef, vec![ref_null()]),
+        );
+        assert_eq!(encode(&externs)[0], 0x06);
+        assert_eq!(round_trip(&externs).elem_type(), core::ElemType::ExternRef);
+        for (mode, flags) in [
+            (core::ElementMode::Passive, 0x05),
+            (core::ElementMode::Declarative, 0x07),
+        ] {
+            let element = core::Element::with_items(
+                mode,
+                core::ElementItems::Expressions(core::ElemType::FuncRef, vec![ref_func()]),
+            );
+            assert_eq!(encode(&element)[..2], [flags, 0x70]);
+            assert_eq!(encode(&round_trip(&element)), encode(&element));
+        }
+
+        let data = core::Data::new(1, offset, b"abc".to_vec());
+        let read_back = round_trip(&data);
+        assert!(matches!(read_back.mode(), core::DataMode::Active(1, _)));
+        assert_eq!(read_back.bytes(), b"abc");
+
+        let func = core::Func::new(
+            vec![core::Locals::new(2, core::ValueType::I32)],
+            core::Expr::new(vec![0x20, 0x00, 0x0B]),
+        );
+        assert_eq!(
+            encode(&func),
+            vec![0x06, 0x01, 0x02, 0x7F, 0x20, 0x00, 0x0B]
+        );
+        assert_eq!(encode(&round_trip(&func)), encode(&func));
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::io;
+
+pub trait WriterUtil {
+    fn write_u8(&mut self, value: u8) -> Result<()>;
+    fn write_leb_u32(&mut self, value: u32) -> Result<()>;
+    fn write_leb_usize(&mut self, value: usize) -> Result<()>;
+
+    fn write_vec<R, T: Fn(&mut Self, &R) -> Result<()>>(
+        &mut self,
+        items: &[R],
+        write_fn: T,
+    ) -> Result<()>;
+
+    fn write_name(&mut self, name: &str) -> Result<()>;
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()>;
+
+    /// Write a section, or a function body, preceded by its size. The contents are built
+    /// up in memory first because the size comes before them.
+    fn write_sized<T: FnOnce(&mut Vec<u8>) -> Result<()>>(&mut self, write_fn: T) -> Result<()>;
+}
+
+impl<T> WriterUtil for T
+where
+    T: io::Write,
+{
+    fn write_u8(&mut self, value: u8) -> Result<()> {
+        self.write_all(&[value])?;
+        Ok(())
+    }
+
+    fn write_leb_u32(&mut self, mut value: u32) -> Result<()> {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                return self.write_u8(byte);
+            }
+            self.write_u8(byte | 0x80)?;
+        }
+    }
+
+    fn write_leb_usize(&mut self, value: usize) -> Result<()> {
+        match u32::try_from(value) {
+            Ok(value) => self.write_leb_u32(value),
+            Err(_) => Err(anyhow!(
+                "Value {} is too large to encode in a module",
+                value
+            )),
+        }
+    }
+
+    fn write_vec<R, T2: Fn(&mut Self, &R) -> Result<()>>(
+        &mut self,
+        items: &[R],
+        write_fn: T2,
+    ) -> Result<()> {
+        self.write_leb_usize(items.len())?;
+
+        for item in items {
+            write_fn(self, item)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_name(&mut self, name: &str) -> Result<()> {
+        self.write_leb_usize(name.len())?;
+        self.write_bytes(name.as_bytes())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write_all(bytes)?;
+        Ok(())
+    }
+
+    fn write_sized<T2: FnOnce(&mut Vec<u8>) -> Result<()>>(&mut self, write_fn: T2) -> Result<()> {
+        let mut contents = Vec::new();
+        write_fn(&mut contents)?;
+
+        self.write_leb_usize(contents.len())?;
+        self.write_bytes(&contents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reader::ReaderUtil;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_leb_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 624485, u32::MAX] {
+            let mut bytes = Vec::new();
+            bytes.write_leb_u32(value).unwrap();
+            assert_eq!(Cursor::new(bytes).read_leb_u32().unwrap(), value);
+        }
+
+        let mut bytes = Vec::new();
+        bytes.write_leb_u32(624485).unwrap();
+        assert_eq!(bytes, vec![0xE5, 0x8E, 0x26]);
+    }
+}
@@ -0,0 +1,48 @@
+#![cfg(feature = "alloc-accounting")]
+
+use std::alloc::System;
+use wasm::alloc_accounting::{self, AccountingAllocator, AllocCategory};
+use wasm::core::{
+    load_module_from_path, memory_page::WASM_PAGE_SIZE_IN_BYTES, EmptyResolver, Instance,
+    StubResolver, Value,
+};
+
+#[global_allocator]
+static ALLOCATOR: AccountingAllocator<System> = AccountingAllocator::new(System);
+
+#[test]
+fn test_allocations_are_charged_to_categories() {
+    let module = load_module_from_path(
+        "../test_app/test.wasm",
+        &StubResolver::new(EmptyResolver::instance()),
+    )
+    .unwrap();
+    let parsed = alloc_accounting::stats(AllocCategory::Module);
+    assert!(parsed.allocations > 0 && parsed.peak_bytes > 0);
+
+    let memory = alloc_accounting::stats(AllocCategory::Memory);
+    assert!(memory.current_bytes >= WASM_PAGE_SIZE_IN_BYTES);
+    // The memory is a single block holding all of its pages
+    assert_eq!(memory.largest_allocation, memory.current_bytes);
+    assert_eq!(memory.largest_allocation % WASM_PAGE_SIZE_IN_BYTES, 0);
+
+    let instance = Instance::new(module);
+    let before = alloc_accounting::stats(AllocCategory::Stack);
+    assert_eq!(
+        instance.invoke("fib", &[Value::I32(10)]).unwrap(),
+        vec![Value::I32(55)]
+    );
+    let after = alloc_accounting::stats(AllocCategory::Stack);
+    assert!(after.allocations > before.allocations);
+    // The stack is gone once the call has returned
+    assert_eq!(after.current_bytes, before.current_bytes);
+
+    drop(instance);
+    let memory = alloc_accounting::stats(AllocCategory::Memory);
+    assert_eq!(memory.current_bytes, 0);
+    assert_eq!(memory.live_allocations(), 0);
+
+    alloc_accounting::reset_peaks();
+    let total = alloc_accounting::total_stats();
+    assert_eq!(total.peak_bytes, total.current_bytes);
+}
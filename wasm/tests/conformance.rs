@@ -0,0 +1,21 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use wasm::reader::conformance_report;
+
+// CONFORMANCE.md is generated from the code, so that it always says what the interpreter
+// actually supports. Run the tests with UPDATE_CONFORMANCE=1 to regenerate it.
+#[test]
+fn conformance_matrix_is_current() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("CONFORMANCE.md");
+    let generated = conformance_report().to_markdown();
+    if env::var_os("UPDATE_CONFORMANCE").is_some() {
+        fs::write(&path, &generated).unwrap();
+    }
+
+    let committed = fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        committed == generated,
+        "CONFORMANCE.md is out of date; run the tests with UPDATE_CONFORMANCE=1 to regenerate it"
+    );
+}
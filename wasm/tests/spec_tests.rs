@@ -0,0 +1,38 @@
+#![cfg(feature = "wast")]
+
+use wasm::spec_test::{run_script, run_script_from_path};
+
+#[test]
+fn test_scripts() {
+    for entry in std::fs::read_dir("tests/wast").unwrap() {
+        let path = entry.unwrap().path();
+        let report = run_script_from_path(path.to_str().unwrap()).unwrap();
+
+        assert!(
+            report.is_success(),
+            "{} failed: {:#?}",
+            path.display(),
+            report.failures
+        );
+        assert!(report.passed > 0);
+    }
+}
+
+#[test]
+fn test_failures_are_reported() {
+    let report = run_script(
+        r#"
+        (module (func (export "one") (result i32) (i32.const 1)))
+        (assert_return (invoke "one") (i32.const 2))
+        (assert_trap (invoke "one") "unreachable")
+        (assert_return (invoke "missing"))
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(report.passed, 1);
+    let lines: Vec<usize> = report.failures.iter().map(|failure| failure.line).collect();
+    assert_eq!(lines, vec![3, 4, 5]);
+
+    assert!(run_script("(module").is_err());
+}
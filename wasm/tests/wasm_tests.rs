@@ -1,20 +1,21 @@
 use anyhow::{anyhow, Result};
-use std::{cell::RefCell, rc::Rc};
 use wasm::core;
 use wasm::core::{
-    Callable, FuncType, Global, GlobalType, MemType, Memory, MutableType, Table, TableType,
-    ValueType,
+    handle, Callable, FuncType, Global, GlobalType, Handle, MemType, Memory, MutableType, Shared,
+    Table, TableType, ValueType,
 };
+use wasm::parser::InstructionSource;
+use wasm::reader::TypeReader;
 
 struct TestResolver {
-    global_zero: Rc<RefCell<Global>>,
+    global_zero: Handle<Global>,
 }
 
 impl TestResolver {
     pub fn new() -> Self {
         let global_zero_type = GlobalType::new(ValueType::I32, MutableType::Const);
         let global_zero = Global::new(global_zero_type, 0u32.into()).unwrap();
-        let global_zero = Rc::new(RefCell::new(global_zero));
+        let global_zero = handle(global_zero);
 
         TestResolver { global_zero }
     }
@@ -26,7 +27,7 @@ impl core::Resolver for TestResolver {
         mod_name: &str,
         name: &str,
         _func_type: &FuncType,
-    ) -> Result<Rc<RefCell<Callable>>> {
+    ) -> Result<Handle<Callable>> {
         Err(anyhow!("Imported function {}:{} not found", mod_name, name))
     }
     fn resolve_table(
@@ -34,7 +35,7 @@ impl core::Resolver for TestResolver {
         mod_name: &str,
         name: &str,
         _table_type: &TableType,
-    ) -> Result<Rc<RefCell<Table>>> {
+    ) -> Result<Handle<Table>> {
         Err(anyhow!("Imported table {}:{} not found", mod_name, name))
     }
     fn resolve_memory(
@@ -42,7 +43,7 @@ impl core::Resolver for TestResolver {
         mod_name: &str,
         name: &str,
         _mem_type: &MemType,
-    ) -> Result<Rc<RefCell<Memory>>> {
+    ) -> Result<Handle<Memory>> {
         Err(anyhow!("Imported memory {}:{} not found", mod_name, name))
     }
     fn resolve_global(
@@ -50,7 +51,7 @@ impl core::Resolver for TestResolver {
         mod_name: &str,
         name: &str,
         global_type: &GlobalType,
-    ) -> Result<Rc<RefCell<Global>>> {
+    ) -> Result<Handle<Global>> {
         if mod_name == "test" && name == "zero" {
             if global_type.clone() == self.global_zero.borrow().global_type().clone() {
                 Ok(self.global_zero.clone())
@@ -125,19 +126,19 @@ fn test_load_module() -> std::result::Result<(), String> {
             assert_eq!(memory.min_size(), 2);
             assert_eq!(memory.max_size(), None);
             assert_eq!(memory.current_size(), 2);
-            assert_eq!(memory[0], 't' as u8);
-            assert_eq!(memory[1], 'e' as u8);
-            assert_eq!(memory[2], 's' as u8);
-            assert_eq!(memory[3], 't' as u8);
+            assert_eq!(memory.load::<u8>(0).unwrap(), 't' as u8);
+            assert_eq!(memory.load::<u8>(1).unwrap(), 'e' as u8);
+            assert_eq!(memory.load::<u8>(2).unwrap(), 's' as u8);
+            assert_eq!(memory.load::<u8>(3).unwrap(), 't' as u8);
 
             let mut buf: [u8; 4] = [0; 4];
             assert!(memory.get_data(0, &mut buf).is_ok());
             assert_eq!(buf, ['t' as u8, 'e' as u8, 's' as u8, 't' as u8]);
 
-            assert_eq!(memory[65534], 's' as u8);
-            assert_eq!(memory[65535], 'p' as u8);
-            assert_eq!(memory[65536], 'a' as u8);
-            assert_eq!(memory[65537], 'n' as u8);
+            assert_eq!(memory.load::<u8>(65534).unwrap(), 's' as u8);
+            assert_eq!(memory.load::<u8>(65535).unwrap(), 'p' as u8);
+            assert_eq!(memory.load::<u8>(65536).unwrap(), 'a' as u8);
+            assert_eq!(memory.load::<u8>(65537).unwrap(), 'n' as u8);
 
             assert!(memory.get_data(65534, &mut buf).is_ok());
             assert_eq!(buf, ['s' as u8, 'p' as u8, 'a' as u8, 'n' as u8]);
@@ -148,12 +149,9 @@ fn test_load_module() -> std::result::Result<(), String> {
             assert_eq!(table.min_size(), 2);
             assert_eq!(table.max_size(), None);
             assert_eq!(table.current_size(), 2);
-            assert!(table[0].is_some());
-            assert!(std::rc::Rc::ptr_eq(
-                &table[0].as_ref().unwrap(),
-                &exported_fn
-            ));
-            assert!(table[1].is_none());
+            assert!(!table[0].is_null());
+            assert!(Shared::ptr_eq(table[0].func_ref().unwrap(), &exported_fn));
+            assert!(table[1].is_null());
         }
         Err(e) => {
             assert!(false, format!("Test file failed to load: {}", e));
@@ -161,3 +159,118 @@ fn test_load_module() -> std::result::Result<(), String> {
     }
     Ok(())
 }
+
+#[test]
+fn test_invoke_exported_function() -> Result<()> {
+    let resolver = TestResolver::new();
+    let instance = core::Instance::new(core::load_module_from_path(
+        "../test_app/test.wasm",
+        &resolver,
+    )?);
+
+    assert_eq!(
+        instance.invoke("fib", &[core::Value::I32(10)])?,
+        vec![core::Value::I32(55)]
+    );
+    assert!(instance.invoke("fib", &[core::Value::I64(10)]).is_err());
+    assert!(instance.invoke("fib7", &[]).is_err());
+
+    Ok(())
+}
+
+// Hands out at most three bytes at a time, and can't seek, like a slow network stream
+struct TrickleReader<'a>(&'a [u8]);
+
+impl std::io::Read for TrickleReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = buf.len().min(self.0.len()).min(3);
+        buf[..length].copy_from_slice(&self.0[..length]);
+        self.0 = &self.0[length..];
+        Ok(length)
+    }
+}
+
+#[test]
+fn test_load_module_from_reader() -> Result<()> {
+    let bytes = std::fs::read("../test_app/test.wasm")?;
+    let resolver = TestResolver::new();
+
+    for module in [
+        core::load_module_from_bytes(&bytes, &resolver)?,
+        core::load_module_from_reader(TrickleReader(&bytes), &resolver)?,
+    ] {
+        let instance = core::Instance::new(module);
+        assert_eq!(
+            instance.invoke("fib", &[core::Value::I32(10)])?,
+            vec![core::Value::I32(55)]
+        );
+    }
+
+    // A stream which ends part way through the module is an error
+    assert!(
+        core::load_module_from_reader(TrickleReader(&bytes[..bytes.len() / 2]), &resolver).is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_read_module_in_place() -> Result<()> {
+    let bytes: Shared<dyn core::SharedBytes> = Shared::new(std::fs::read("../test_app/test.wasm")?);
+    let module = core::RawModule::read_in_place(bytes.clone(), &Default::default())?;
+
+    // Every body is read lazily, straight out of the buffer the module was read from
+    let buffer = bytes.bytes().as_ptr_range();
+    assert!(!module.funcs().is_empty());
+    for func in module.funcs() {
+        assert!(func.is_lazy());
+        assert!(buffer.contains(&func.expr().get_instruction_bytes().as_ptr()));
+    }
+
+    let instance = core::Instance::new(core::resolve_raw_module(module, &TestResolver::new())?);
+    assert_eq!(
+        instance.invoke("fib", &[core::Value::I32(10)])?,
+        vec![core::Value::I32(55)]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_load_module_from_mmap() -> Result<()> {
+    // SAFETY: nothing writes to the test app while the tests run
+    let module =
+        unsafe { core::load_module_from_mmap("../test_app/test.wasm", &TestResolver::new())? };
+    let instance = core::Instance::new(module);
+    assert_eq!(
+        instance.invoke("fib", &[core::Value::I32(10)])?,
+        vec![core::Value::I32(55)]
+    );
+    let missing =
+        unsafe { core::load_module_from_mmap("../test_app/missing.wasm", &TestResolver::new()) };
+    assert!(missing.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_write_module() -> Result<()> {
+    let bytes = std::fs::read("../test_app/test.wasm")?;
+    let module = core::RawModule::read(&mut std::io::Cursor::new(&bytes))?;
+
+    let mut written = Vec::new();
+    module.write(&mut written)?;
+
+    // Writing what was read back gives the same bytes, and the module still works
+    let module = core::RawModule::read(&mut std::io::Cursor::new(&written))?;
+    let mut rewritten = Vec::new();
+    module.write(&mut rewritten)?;
+    assert_eq!(written, rewritten);
+
+    let resolver = TestResolver::new();
+    let instance = core::Instance::new(core::resolve_raw_module(module, &resolver)?);
+    assert_eq!(
+        instance.invoke("fib", &[core::Value::I32(10)])?,
+        vec![core::Value::I32(55)]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+;; Exercises each kind of command the script runner supports
+
+(module $Math
+  (global (export "answer") i32 (i32.const 42))
+  (func (export "add") (param i32 i32) (result i32)
+    (i32.add (local.get 0) (local.get 1)))
+  (func (export "div_s") (param i32 i32) (result i32)
+    (i32.div_s (local.get 0) (local.get 1)))
+  (func (export "nan") (result f32)
+    (f32.div (f32.const 0) (f32.const 0)))
+  (func (export "neg_zero") (result f64) (f64.const -0))
+  (func $loop (export "loop") (call $loop))
+)
+
+(assert_return (invoke "add" (i32.const 1) (i32.const 2)) (i32.const 3))
+(assert_return (invoke "add" (i32.const -1) (i32.const 1)) (i32.const 0))
+(assert_return (invoke "nan") (f32.const nan:canonical))
+(assert_return (invoke "neg_zero") (f64.const -0))
+(assert_return (get "answer") (i32.const 42))
+(assert_trap (invoke "div_s" (i32.const 1) (i32.const 0)) "integer divide by zero")
+(assert_trap (invoke "div_s" (i32.const 0x80000000) (i32.const -1)) "integer overflow")
+(assert_exhaustion (invoke "loop") "call stack exhausted")
+(invoke "add" (i32.const 4) (i32.const 5))
+
+(register "math" $Math)
+
+(module
+  (import "math" "add" (func $add (param i32 i32) (result i32)))
+  (import "spectest" "global_i32" (global $g i32))
+  (import "spectest" "print_i32" (func $print (param i32)))
+  (func (export "add_global") (param i32) (result i32)
+    (call $add (local.get 0) (global.get $g)))
+  (func (export "print") (call $print (i32.const 7)))
+  (func (export "null") (result externref) (ref.null extern))
+  (func (export "identity") (param externref) (result externref) (local.get 0))
+  (func (export "unreachable") (unreachable))
+)
+
+(assert_return (invoke "add_global" (i32.const 1)) (i32.const 667))
+(assert_return (invoke "print"))
+(assert_return (invoke "null") (ref.null extern))
+(assert_return (invoke "identity" (ref.extern 3)) (ref.extern 3))
+(assert_trap (invoke "unreachable") "unreachable")
+(assert_return (invoke $Math "add" (i32.const 2) (i32.const 2)) (i32.const 4))
+
+(assert_invalid
+  (module (func (drop (i32.load (i32.const 0)))))
+  "unknown memory")
+(assert_malformed
+  (module binary "\00asm" "\02\00\00\00")
+  "unknown binary version")
+(assert_malformed
+  (module quote "(func (i32.const))")
+  "unexpected token")
+(assert_unlinkable
+  (module (import "math" "missing" (func)))
+  "unknown import")
+(assert_unlinkable
+  (module (import "math" "add" (func (param i64))))
+  "incompatible import type")
+(assert_trap
+  (module (func $start (unreachable)) (start $start))
+  "unreachable")